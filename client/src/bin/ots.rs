@@ -0,0 +1,336 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+
+use one_time_share_client::{parse_share_link, Client, NewMessage};
+
+const USAGE: &str = "Usage:
+  ots create [options] [FILE]    share FILE (stdin by default) and print the link
+  ots consume [options] LINK     read the message of LINK to stdout, it's gone afterwards
+  ots status [options] LINK      show what is known about the message without reading it
+
+Options:
+  --server URL          the server to create the messages on, or $OTS_SERVER
+  --user-token TOKEN    the user the messages are created for, or $OTS_USER_TOKEN
+  --ca-bundle PATH      the CA certificates to check the server with instead of the built-in
+                        ones, or $OTS_CA_BUNDLE
+  --retention MINUTES   remove the message after this many minutes if nobody reads it
+  --passphrase TEXT     protect the message with a passphrase, or $OTS_PASSPHRASE
+  --filename NAME       the file name the recipient sees, FILE's name by default
+  --content-type TYPE   the content type the recipient sees
+  --encrypt             encrypt the message here, the server never sees the key
+  --output PATH         write the message to PATH instead of stdout
+  -h, --help            show this help";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Create,
+    Consume,
+    Status,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    server: Option<String>,
+    user_token: Option<String>,
+    ca_bundle: Option<String>,
+    retention_minutes: Option<u32>,
+    passphrase: Option<String>,
+    filename: Option<String>,
+    content_type: Option<String>,
+    encrypt: bool,
+    output: Option<String>,
+    // the file to share or the link to read
+    target: Option<String>,
+}
+
+// the environment fills in what the arguments don't have, so the secrets
+// don't have to be on the command line where other users can see them
+fn parse_args(
+    args: &[String],
+    env_var: impl Fn(&str) -> Option<String>,
+) -> Result<(Command, Options), String> {
+    let mut args = args.iter();
+    let command = match args.next().map(String::as_str) {
+        Some("create") => Command::Create,
+        Some("consume") => Command::Consume,
+        Some("status") => Command::Status,
+        Some(command) => return Err(format!("Unknown command: {}", command)),
+        None => return Err("No command given".to_string()),
+    };
+
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{} needs a value", name))
+        };
+        match arg.as_str() {
+            "--server" => options.server = Some(value(arg)?),
+            "--user-token" => options.user_token = Some(value(arg)?),
+            "--ca-bundle" => options.ca_bundle = Some(value(arg)?),
+            "--retention" => {
+                let retention = value(arg)?;
+                options.retention_minutes = Some(
+                    retention
+                        .parse()
+                        .map_err(|_| format!("Invalid retention: {}", retention))?,
+                );
+            }
+            "--passphrase" => options.passphrase = Some(value(arg)?),
+            "--filename" => options.filename = Some(value(arg)?),
+            "--content-type" => options.content_type = Some(value(arg)?),
+            "--encrypt" => options.encrypt = true,
+            "--output" => options.output = Some(value(arg)?),
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(format!("Unknown option: {}", arg))
+            }
+            _ if options.target.is_none() => options.target = Some(arg.clone()),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    options.server = options.server.or_else(|| env_var("OTS_SERVER"));
+    options.user_token = options.user_token.or_else(|| env_var("OTS_USER_TOKEN"));
+    options.ca_bundle = options.ca_bundle.or_else(|| env_var("OTS_CA_BUNDLE"));
+    options.passphrase = options.passphrase.or_else(|| env_var("OTS_PASSPHRASE"));
+
+    if command != Command::Create && options.target.is_none() {
+        return Err("No link given".to_string());
+    }
+    Ok((command, options))
+}
+
+fn make_client(base_url: &str, options: &Options) -> Result<Client, String> {
+    let client = match &options.ca_bundle {
+        Some(ca_bundle) => Client::with_ca_bundle(base_url, ca_bundle),
+        None => Client::new(base_url),
+    }
+    .map_err(|err| err.to_string())?;
+    Ok(match &options.user_token {
+        Some(user_token) => client.with_user_token(user_token.clone()),
+        None => client,
+    })
+}
+
+fn read_input(target: Option<&str>) -> Result<Vec<u8>, String> {
+    match target {
+        None | Some("-") => {
+            let mut data = Vec::new();
+            io::stdin()
+                .read_to_end(&mut data)
+                .map_err(|err| format!("Can't read stdin: {}", err))?;
+            Ok(data)
+        }
+        Some(path) => fs::read(path).map_err(|err| format!("Can't read {}: {}", path, err)),
+    }
+}
+
+async fn create(options: Options) -> Result<(), String> {
+    let server = options
+        .server
+        .as_deref()
+        .ok_or("No server given, use --server or $OTS_SERVER")?;
+    let client = make_client(server, &options)?;
+
+    let target = options.target.as_deref();
+    let mut message = NewMessage::new(read_input(target)?);
+    message.retention_minutes = options.retention_minutes;
+    message.passphrase = options.passphrase.clone();
+    message.content_type = options.content_type.clone();
+    message.filename = options.filename.clone().or_else(|| {
+        target
+            .filter(|path| *path != "-")
+            .and_then(|path| std::path::Path::new(path).file_name())
+            .map(|name| name.to_string_lossy().to_string())
+    });
+
+    let created = if options.encrypt {
+        create_encrypted(&client, &message).await?
+    } else {
+        client
+            .create_message(&message)
+            .await
+            .map_err(|err| err.to_string())?
+    };
+    println!("{}", created.url);
+    Ok(())
+}
+
+#[cfg(feature = "encryption")]
+async fn create_encrypted(
+    client: &Client,
+    message: &NewMessage,
+) -> Result<one_time_share_client::CreatedMessage, String> {
+    client
+        .create_encrypted_message(message)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(not(feature = "encryption"))]
+async fn create_encrypted(
+    _client: &Client,
+    _message: &NewMessage,
+) -> Result<one_time_share_client::CreatedMessage, String> {
+    Err("ots is built without the encryption feature".to_string())
+}
+
+#[cfg(feature = "encryption")]
+async fn consume_encrypted(
+    client: &Client,
+    message_token: &str,
+    key: &str,
+    passphrase: Option<&str>,
+) -> Result<Vec<u8>, String> {
+    client
+        .consume_encrypted(message_token, key, passphrase)
+        .await
+        .map(|message| message.data)
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(not(feature = "encryption"))]
+async fn consume_encrypted(
+    _client: &Client,
+    _message_token: &str,
+    _key: &str,
+    _passphrase: Option<&str>,
+) -> Result<Vec<u8>, String> {
+    Err("The message is encrypted, but ots is built without the encryption feature".to_string())
+}
+
+// a bare token is looked up on the configured server
+fn resolve_link(options: &Options) -> Result<(Client, String, Option<String>), String> {
+    let target = options.target.as_deref().unwrap_or_default();
+    match parse_share_link(target) {
+        Some(link) => Ok((
+            make_client(&link.base_url, options)?,
+            link.message_token,
+            link.key,
+        )),
+        None => {
+            let server = options
+                .server
+                .as_deref()
+                .ok_or_else(|| format!("Not a link: {}", target))?;
+            Ok((make_client(server, options)?, target.to_string(), None))
+        }
+    }
+}
+
+async fn consume(options: Options) -> Result<(), String> {
+    let (client, message_token, key) = resolve_link(&options)?;
+    let passphrase = options.passphrase.as_deref();
+    let data = match key {
+        Some(key) => consume_encrypted(&client, &message_token, &key, passphrase).await?,
+        None => {
+            client
+                .consume(&message_token, passphrase)
+                .await
+                .map_err(|err| err.to_string())?
+                .data
+        }
+    };
+    match options.output.as_deref() {
+        None | Some("-") => io::stdout()
+            .write_all(&data)
+            .and_then(|_| io::stdout().flush())
+            .map_err(|err| format!("Can't write stdout: {}", err)),
+        Some(path) => {
+            fs::write(path, &data).map_err(|err| format!("Can't write {}: {}", path, err))
+        }
+    }
+}
+
+async fn status(options: Options) -> Result<(), String> {
+    let (client, message_token, _) = resolve_link(&options)?;
+    let status = client
+        .status(&message_token)
+        .await
+        .map_err(|err| err.to_string())?;
+    println!("size_bytes: {}", status.size_bytes);
+    println!("expire_timestamp: {}", status.expire_timestamp);
+    println!("passphrase_required: {}", status.passphrase_required);
+    println!("client_encrypted: {}", status.client_encrypted);
+    if let Some(filename) = status.filename {
+        println!("filename: {}", filename);
+    }
+    if let Some(content_type) = status.content_type {
+        println!("content_type: {}", content_type);
+    }
+    Ok(())
+}
+
+#[async_std::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    let (command, options) = match parse_args(&args, |name| env::var(name).ok()) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let result = match command {
+        Command::Create => create(options).await,
+        Command::Consume => consume(options).await,
+        Command::Status => status(options).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("ots: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let (command, options) = parse_args(
+            &args(&["create", "--retention", "60", "--encrypt", "secret.txt"]),
+            |name| (name == "OTS_SERVER").then(|| "https://1ts.dev".to_string()),
+        )
+        .unwrap();
+        assert_eq!(command, Command::Create);
+        assert_eq!(options.server.as_deref(), Some("https://1ts.dev"));
+        assert_eq!(options.retention_minutes, Some(60));
+        assert!(options.encrypt);
+        assert_eq!(options.target.as_deref(), Some("secret.txt"));
+
+        // the arguments win over the environment
+        let (_, options) = parse_args(
+            &args(&[
+                "consume",
+                "--passphrase",
+                "one",
+                "https://1ts.dev/shared/abc",
+            ]),
+            |_| Some("two".to_string()),
+        )
+        .unwrap();
+        assert_eq!(options.passphrase.as_deref(), Some("one"));
+
+        assert!(parse_args(&args(&["consume"]), |_| None).is_err());
+        assert!(parse_args(&args(&["create", "--retention", "soon"]), |_| None).is_err());
+        assert!(parse_args(&args(&["create", "--unknown"]), |_| None).is_err());
+        assert!(parse_args(&args(&["delete"]), |_| None).is_err());
+        assert!(parse_args(&args(&[]), |_| None).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+
+use crate::Error;
+
+// the server puts it into the link of an end-to-end encrypted message
+pub const KEY_PLACEHOLDER: &str = "{key}";
+const NONCE_SIZE_BYTES: usize = 12;
+
+// the same format as the page of the service uses, so either side can read the other's messages
+pub struct EncryptedMessage {
+    // base64 of the nonce followed by the ciphertext
+    pub payload: String,
+    // base64url of the raw AES-256 key, without padding
+    pub key: String,
+}
+
+pub fn encrypt(data: &[u8]) -> Result<EncryptedMessage, Error> {
+    let key = Aes256Gcm::generate_key(&mut OsRng);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = Aes256Gcm::new(&key)
+        .encrypt(&nonce, data)
+        .map_err(|_| Error::Encryption("Can't encrypt message".to_string()))?;
+
+    let mut payload = nonce.to_vec();
+    payload.extend_from_slice(&ciphertext);
+    Ok(EncryptedMessage {
+        payload: STANDARD.encode(payload),
+        key: URL_SAFE_NO_PAD.encode(key),
+    })
+}
+
+pub fn decrypt(payload: &str, key: &str) -> Result<Vec<u8>, Error> {
+    let key = URL_SAFE_NO_PAD
+        .decode(key.trim_end_matches('='))
+        .map_err(|_| Error::Encryption("Key is not valid base64url".to_string()))?;
+    if key.len() != 32 {
+        return Err(Error::Encryption("Key should be 32 bytes long".to_string()));
+    }
+    let payload = STANDARD
+        .decode(payload)
+        .map_err(|_| Error::Encryption("Message is not valid base64".to_string()))?;
+    if payload.len() < NONCE_SIZE_BYTES {
+        return Err(Error::Encryption("Message is too short".to_string()));
+    }
+    let (nonce, ciphertext) = payload.split_at(NONCE_SIZE_BYTES);
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| Error::Encryption("Can't decrypt message, the key is wrong".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let encrypted = encrypt(b"hello").unwrap();
+        assert_eq!(
+            decrypt(&encrypted.payload, &encrypted.key).unwrap(),
+            b"hello"
+        );
+        assert!(!encrypted.key.contains('='));
+
+        let other = encrypt(b"hello").unwrap();
+        assert!(decrypt(&encrypted.payload, &other.key).is_err());
+    }
+}
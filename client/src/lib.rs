@@ -0,0 +1,545 @@
+//! Client for the HTTP API of One Time Share.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), one_time_share_client::Error> {
+//! use one_time_share_client::{Client, NewMessage};
+//!
+//! let client = Client::new("https://1ts.dev")?.with_user_token("my-token");
+//! let created = client
+//!     .create_message(&NewMessage::text("hello").retention_minutes(60))
+//!     .await?;
+//! let message = client.consume(&created.message_token, None).await?;
+//! assert_eq!(message.data, b"hello");
+//! # Ok(())
+//! # }
+//! ```
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use http_types::{Method, Request, Response, Url};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[cfg(feature = "encryption")]
+pub mod encryption;
+mod transport;
+
+use crate::transport::Transport;
+
+// the token the home page of the service uses
+const DEFAULT_USER_TOKEN: &str = "default";
+const SHARED_PATH_SEGMENT: &str = "/shared/";
+
+#[derive(Debug)]
+pub enum Error {
+    // the server couldn't be reached
+    Transport(String),
+    // the server refused the request
+    Api {
+        status: u16,
+        message: String,
+        // the request field that failed the validation
+        field: Option<String>,
+        // set when the request was throttled
+        retry_after_seconds: Option<u64>,
+    },
+    InvalidResponse(String),
+    Encryption(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport(message) => write!(f, "Transport error: {}", message),
+            Error::Api {
+                status, message, ..
+            } => write!(f, "Server answered {}: {}", status, message),
+            Error::InvalidResponse(message) => write!(f, "Invalid response: {}", message),
+            Error::Encryption(message) => write!(f, "Encryption error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// a message to share, the data can be anything
+#[derive(Clone, Debug, Default)]
+pub struct NewMessage {
+    pub data: Vec<u8>,
+    pub retention_minutes: Option<u32>,
+    pub passphrase: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+impl NewMessage {
+    pub fn new(data: impl Into<Vec<u8>>) -> Self {
+        NewMessage {
+            data: data.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn text(text: &str) -> Self {
+        Self::new(text.as_bytes())
+    }
+
+    // the message is removed after this many minutes if nobody reads it
+    pub fn retention_minutes(mut self, retention_minutes: u32) -> Self {
+        self.retention_minutes = Some(retention_minutes);
+        self
+    }
+
+    pub fn passphrase(mut self, passphrase: &str) -> Self {
+        self.passphrase = Some(passphrase.to_string());
+        self
+    }
+
+    pub fn filename(mut self, filename: &str) -> Self {
+        self.filename = Some(filename.to_string());
+        self
+    }
+
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct CreatedMessage {
+    // the link to give to the recipient
+    pub url: String,
+    pub message_token: String,
+    // unix time, 0 if the message doesn't expire
+    pub expire_timestamp: u64,
+    // hex SHA-256 of the data, older servers don't send it
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConsumedMessage {
+    pub data: Vec<u8>,
+    pub expire_timestamp: u64,
+    // set when the message was created from a file
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+// what is known about a message without reading it
+#[derive(Deserialize, Clone, Debug)]
+pub struct MessageStatus {
+    pub size_bytes: u64,
+    pub expire_timestamp: u64,
+    pub passphrase_required: bool,
+    pub client_encrypted: bool,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+// the parts of a shared link, the key is there only for end-to-end encrypted messages
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShareLink {
+    // the server the link points to, a client for it can be made from this
+    pub base_url: String,
+    pub message_token: String,
+    pub key: Option<String>,
+}
+
+pub fn parse_share_link(url: &str) -> Option<ShareLink> {
+    let url = Url::parse(url).ok()?;
+    // the server can be under a path prefix
+    let (base_path, message_token) = url.path().rsplit_once(SHARED_PATH_SEGMENT)?;
+    if message_token.is_empty() || message_token.contains('/') {
+        return None;
+    }
+    let mut base_url = url.clone();
+    base_url.set_path(base_path);
+    base_url.set_query(None);
+    base_url.set_fragment(None);
+    Some(ShareLink {
+        base_url: base_url.to_string(),
+        message_token: message_token.to_string(),
+        key: url
+            .fragment()
+            .filter(|key| !key.is_empty())
+            .map(str::to_string),
+    })
+}
+
+#[derive(Serialize)]
+struct CreateMessageRequest<'a> {
+    user_token: &'a str,
+    message_data: String,
+    retention: Option<u32>,
+    passphrase: Option<&'a str>,
+    end_to_end: Option<bool>,
+    filename: Option<&'a str>,
+    content_type: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct ConsumeMessageRequest<'a> {
+    passphrase: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct ConsumeMessageResponse {
+    message_data: String,
+    expire_timestamp: u64,
+    #[serde(default)]
+    filename: Option<String>,
+    #[serde(default)]
+    content_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+    field: Option<String>,
+    retry_after_seconds: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct Client {
+    base_url: Url,
+    user_token: String,
+    transport: Transport,
+}
+
+impl Client {
+    // TLS connections are checked against the Mozilla roots built into the client
+    pub fn new(base_url: &str) -> Result<Self, Error> {
+        Self::build(base_url, None)
+    }
+
+    pub fn with_ca_bundle(base_url: &str, ca_bundle_path: &str) -> Result<Self, Error> {
+        Self::build(base_url, Some(ca_bundle_path))
+    }
+
+    fn build(base_url: &str, ca_bundle_path: Option<&str>) -> Result<Self, Error> {
+        let base_url = Url::parse(base_url)
+            .map_err(|err| Error::Transport(format!("Invalid base URL {}: {}", base_url, err)))?;
+        let transport = Transport::new(ca_bundle_path, base_url.scheme() == "https")?;
+        Ok(Client {
+            base_url,
+            user_token: DEFAULT_USER_TOKEN.to_string(),
+            transport,
+        })
+    }
+
+    // the user the messages are created for, its limits apply
+    pub fn with_user_token(mut self, user_token: impl Into<String>) -> Self {
+        self.user_token = user_token.into();
+        self
+    }
+
+    fn url(&self, path_segments: &[&str]) -> Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("the base URL is an http(s) URL")
+            .pop_if_empty()
+            .extend(path_segments);
+        url
+    }
+
+    async fn send_json<T: for<'de> Deserialize<'de>>(
+        &self,
+        method: Method,
+        url: Url,
+        body: Option<serde_json::Value>,
+    ) -> Result<T, Error> {
+        let mut req = Request::new(method, url);
+        req.insert_header("Accept", "application/json");
+        if let Some(body) = body {
+            req.set_body(http_types::Body::from_json(&body).map_err(|err| {
+                Error::InvalidResponse(format!("Can't serialize request: {}", err))
+            })?);
+        }
+        let res = self.transport.send(req).await?;
+        parse_response(res).await
+    }
+
+    async fn create(
+        &self,
+        message: &NewMessage,
+        data: String,
+        end_to_end: bool,
+    ) -> Result<CreatedMessage, Error> {
+        let body = serde_json::to_value(CreateMessageRequest {
+            user_token: &self.user_token,
+            message_data: data,
+            retention: message.retention_minutes,
+            passphrase: message.passphrase.as_deref(),
+            end_to_end: end_to_end.then_some(true),
+            filename: message.filename.as_deref(),
+            content_type: message.content_type.as_deref(),
+        })
+        .map_err(|err| Error::InvalidResponse(format!("Can't serialize request: {}", err)))?;
+        self.send_json(
+            Method::Post,
+            self.url(&["api", "v1", "messages"]),
+            Some(body),
+        )
+        .await
+    }
+
+    pub async fn create_message(&self, message: &NewMessage) -> Result<CreatedMessage, Error> {
+        self.create(message, STANDARD.encode(&message.data), false)
+            .await
+    }
+
+    async fn consume_raw(
+        &self,
+        message_token: &str,
+        passphrase: Option<&str>,
+    ) -> Result<ConsumeMessageResponse, Error> {
+        let body = serde_json::to_value(ConsumeMessageRequest { passphrase })
+            .map_err(|err| Error::InvalidResponse(format!("Can't serialize request: {}", err)))?;
+        self.send_json(
+            Method::Post,
+            self.url(&["api", "v1", "messages", message_token, "consume"]),
+            Some(body),
+        )
+        .await
+    }
+
+    // reads the message, it's removed from the server at the same time
+    pub async fn consume(
+        &self,
+        message_token: &str,
+        passphrase: Option<&str>,
+    ) -> Result<ConsumedMessage, Error> {
+        let consumed = self.consume_raw(message_token, passphrase).await?;
+        Ok(ConsumedMessage {
+            data: STANDARD.decode(&consumed.message_data).map_err(|err| {
+                Error::InvalidResponse(format!("Message is not valid base64: {}", err))
+            })?,
+            expire_timestamp: consumed.expire_timestamp,
+            filename: consumed.filename,
+            content_type: consumed.content_type,
+        })
+    }
+
+    // doesn't read the message, so it can be checked before it's consumed
+    pub async fn status(&self, message_token: &str) -> Result<MessageStatus, Error> {
+        self.send_json(
+            Method::Get,
+            self.url(&["api", "v1", "messages", message_token, "meta"]),
+            None,
+        )
+        .await
+    }
+
+    // the server gets only the ciphertext, the key is put to the fragment of the returned link
+    #[cfg(feature = "encryption")]
+    pub async fn create_encrypted_message(
+        &self,
+        message: &NewMessage,
+    ) -> Result<CreatedMessage, Error> {
+        let encrypted = encryption::encrypt(&message.data)?;
+        let mut created = self.create(message, encrypted.payload, true).await?;
+        created.url = created
+            .url
+            .replace(encryption::KEY_PLACEHOLDER, &encrypted.key);
+        Ok(created)
+    }
+
+    // takes the key from the link of an end-to-end encrypted message
+    #[cfg(feature = "encryption")]
+    pub async fn consume_encrypted(
+        &self,
+        message_token: &str,
+        key: &str,
+        passphrase: Option<&str>,
+    ) -> Result<ConsumedMessage, Error> {
+        let consumed = self.consume_raw(message_token, passphrase).await?;
+        Ok(ConsumedMessage {
+            data: encryption::decrypt(&consumed.message_data, key)?,
+            expire_timestamp: consumed.expire_timestamp,
+            filename: consumed.filename,
+            content_type: consumed.content_type,
+        })
+    }
+}
+
+async fn parse_response<T: for<'de> Deserialize<'de>>(mut res: Response) -> Result<T, Error> {
+    let status = res.status();
+    let body = res
+        .body_bytes()
+        .await
+        .map_err(|err| Error::Transport(err.to_string()))?;
+    if status.is_success() {
+        return serde_json::from_slice(&body)
+            .map_err(|err| Error::InvalidResponse(format!("Can't parse the response: {}", err)));
+    }
+    let error_response: Option<ErrorResponse> = serde_json::from_slice(&body).ok();
+    Err(match error_response {
+        Some(error_response) => Error::Api {
+            status: status as u16,
+            message: error_response.error,
+            field: error_response.field,
+            retry_after_seconds: error_response.retry_after_seconds,
+        },
+        None => Error::Api {
+            status: status as u16,
+            message: status.canonical_reason().to_string(),
+            field: None,
+            retry_after_seconds: None,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_share_link() {
+        assert_eq!(
+            parse_share_link("https://1ts.dev/shared/abc-123#c2VjcmV0"),
+            Some(ShareLink {
+                base_url: "https://1ts.dev/".to_string(),
+                message_token: "abc-123".to_string(),
+                key: Some("c2VjcmV0".to_string()),
+            })
+        );
+        assert_eq!(
+            parse_share_link("https://example.com/ots/shared/abc-123"),
+            Some(ShareLink {
+                base_url: "https://example.com/ots".to_string(),
+                message_token: "abc-123".to_string(),
+                key: None,
+            })
+        );
+        assert_eq!(parse_share_link("https://1ts.dev/"), None);
+        assert_eq!(parse_share_link("not a link"), None);
+    }
+
+    // answers the way the v1 API of the server does, the message is "hello"
+    async fn start_server() -> String {
+        let mut app = tide::new();
+        app.at("/api/v1/messages")
+            .post(|mut req: tide::Request<()>| async move {
+                let body: serde_json::Value = req.body_json().await?;
+                if body["user_token"] != "test_token" {
+                    let mut res = tide::Response::new(tide::StatusCode::Forbidden);
+                    res.set_body(serde_json::json!({"error": "Unknown user token"}));
+                    return Ok(res);
+                }
+                let mut res = tide::Response::new(tide::StatusCode::Ok);
+                res.set_body(serde_json::json!({
+                    "url": "http://localhost/shared/abc-123",
+                    "message_token": "abc-123",
+                    "expire_timestamp": 100,
+                    "sha256": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+                }));
+                Ok(res)
+            });
+        app.at("/api/v1/messages/abc-123/consume").post(|_| async {
+            Ok(serde_json::json!({"message_data": "aGVsbG8=", "expire_timestamp": 100}))
+        });
+        app.at("/api/v1/messages/abc-123/meta").get(|_| async {
+            Ok(serde_json::json!({
+                "size_bytes": 5,
+                "expire_timestamp": 100,
+                "passphrase_required": false,
+                "client_encrypted": false,
+                "filename": null,
+                "content_type": null,
+            }))
+        });
+        app.at("/api/v1/messages/busy/meta").get(|_| async {
+            let mut res = tide::Response::new(tide::StatusCode::TooManyRequests);
+            res.set_body(
+                serde_json::json!({"error": "Too many requests", "retry_after_seconds": 5}),
+            );
+            Ok(res)
+        });
+
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let app = app.clone();
+                async_std::task::spawn(async move {
+                    let _ = async_h1::accept(stream, |req| {
+                        let app = app.clone();
+                        async move { app.respond(req).await }
+                    })
+                    .await;
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[async_std::test]
+    async fn test_client() {
+        let base_url = start_server().await;
+        let client = Client::new(&base_url)
+            .unwrap()
+            .with_user_token("test_token");
+
+        let created = client
+            .create_message(&NewMessage::text("hello").retention_minutes(10))
+            .await
+            .unwrap();
+        assert_eq!(created.message_token, "abc-123");
+        assert_eq!(created.expire_timestamp, 100);
+        assert_eq!(
+            created.sha256.as_deref(),
+            Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+        );
+
+        let status = client.status("abc-123").await.unwrap();
+        assert_eq!(status.size_bytes, 5);
+        assert!(!status.passphrase_required);
+
+        let consumed = client.consume("abc-123", None).await.unwrap();
+        assert_eq!(consumed.data, b"hello");
+
+        match client.status("busy").await.unwrap_err() {
+            Error::Api {
+                status,
+                retry_after_seconds,
+                ..
+            } => {
+                assert_eq!(status, 429);
+                assert_eq!(retry_after_seconds, Some(5));
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+
+        let err = Client::new(&base_url)
+            .unwrap()
+            .create_message(&NewMessage::text("hello"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Server answered 403: Unknown user token");
+
+        // not routed, the body isn't JSON
+        match client.status("unknown").await.unwrap_err() {
+            Error::Api { status, .. } => assert_eq!(status, 404),
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_url_keeps_base_path() {
+        let client = Client::new("http://localhost:8080/one-time-share/").unwrap();
+        assert_eq!(
+            client.url(&["api", "v1", "messages"]).as_str(),
+            "http://localhost:8080/one-time-share/api/v1/messages"
+        );
+    }
+    #[test]
+    fn test_tls_without_ca_bundle() {
+        // the built-in roots are used, whatever the system keeps its bundle
+        assert!(Client::new("https://1ts.dev").is_ok());
+        assert!(Client::with_ca_bundle("https://1ts.dev", "/nonexistent/ca.pem").is_err());
+    }
+}
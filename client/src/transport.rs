@@ -0,0 +1,79 @@
+use async_rustls::TlsConnector;
+use async_std::net::TcpStream;
+use http_types::{Request, Response};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use crate::Error;
+
+// one connection per request, the API calls are few and far between
+#[derive(Clone)]
+pub(crate) struct Transport {
+    tls_config: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl Transport {
+    // the CA bundle is read only when there is something to talk TLS to, without one the
+    // Mozilla roots built into the client are used, the system bundle isn't at the same path
+    // everywhere
+    pub(crate) fn new(ca_bundle_path: Option<&str>, needs_tls: bool) -> Result<Self, Error> {
+        if !needs_tls {
+            return Ok(Transport { tls_config: None });
+        }
+        let mut tls_config = rustls::ClientConfig::new();
+        let ca_bundle_path = match ca_bundle_path {
+            Some(ca_bundle_path) => ca_bundle_path,
+            None => {
+                tls_config
+                    .root_store
+                    .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+                return Ok(Transport {
+                    tls_config: Some(Arc::new(tls_config)),
+                });
+            }
+        };
+        let ca_bundle = File::open(ca_bundle_path).map_err(|err| {
+            Error::Transport(format!("Can't open CA bundle {}: {}", ca_bundle_path, err))
+        })?;
+        tls_config
+            .root_store
+            .add_pem_file(&mut BufReader::new(ca_bundle))
+            .map_err(|_| Error::Transport(format!("Can't parse CA bundle {}", ca_bundle_path)))?;
+        Ok(Transport {
+            tls_config: Some(Arc::new(tls_config)),
+        })
+    }
+
+    pub(crate) async fn send(&self, req: Request) -> Result<Response, Error> {
+        let url = req.url().clone();
+        let host = url
+            .host_str()
+            .ok_or_else(|| Error::Transport("URL has no host".to_string()))?
+            .to_string();
+        let port = url.port_or_known_default().unwrap_or(80);
+        let stream = TcpStream::connect((host.as_str(), port))
+            .await
+            .map_err(|err| Error::Transport(format!("Can't connect to {}: {}", host, err)))?;
+
+        let res = match (url.scheme(), &self.tls_config) {
+            ("http", _) => async_h1::connect(stream, req).await,
+            ("https", Some(tls_config)) => {
+                let domain = webpki::DNSNameRef::try_from_ascii_str(&host)
+                    .map_err(|_| Error::Transport(format!("Invalid host name: {}", host)))?;
+                let tls_stream = TlsConnector::from(tls_config.clone())
+                    .connect(domain, stream)
+                    .await
+                    .map_err(|err| Error::Transport(format!("TLS handshake failed: {}", err)))?;
+                async_h1::connect(tls_stream, req).await
+            }
+            (scheme, _) => {
+                return Err(Error::Transport(format!(
+                    "Unsupported URL scheme: {}",
+                    scheme
+                )))
+            }
+        };
+        res.map_err(|err| Error::Transport(err.to_string()))
+    }
+}
@@ -0,0 +1,231 @@
+use async_std::fs::OpenOptions;
+use async_std::io::WriteExt;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tide::{Middleware, Next, Request, StatusCode};
+
+use crate::access_log::mask_message_tokens;
+use crate::proxy::client_addr;
+use crate::rate_limit::{classify_request, client_ip, Budget};
+use crate::request_id::RequestId;
+use crate::time_format::format_rfc3339_millis;
+
+// the names are part of the log format, filters match on them
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AbuseEvent {
+    UnknownUserToken,
+    TokenProbe,
+    RateLimited,
+    Banned,
+}
+
+impl fmt::Display for AbuseEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AbuseEvent::UnknownUserToken => "unknown_user_token",
+            AbuseEvent::TokenProbe => "token_probe",
+            AbuseEvent::RateLimited => "rate_limited",
+            AbuseEvent::Banned => "banned",
+        };
+        f.write_str(name)
+    }
+}
+
+fn classify_response(budget: Option<Budget>, status: StatusCode) -> Option<AbuseEvent> {
+    match (budget, status) {
+        (_, StatusCode::TooManyRequests) => Some(AbuseEvent::RateLimited),
+        (Some(Budget::Create), StatusCode::NotFound) => Some(AbuseEvent::UnknownUserToken),
+        (Some(Budget::Consume), StatusCode::NotFound) => Some(AbuseEvent::TokenProbe),
+        _ => None,
+    }
+}
+
+struct AbuseLogEntry<'a> {
+    timestamp_millis: u64,
+    event: AbuseEvent,
+    ip: &'a str,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    request_id: &'a str,
+}
+
+// one event per line, the address is always the third field:
+// 2024-01-02T03:04:05.678Z event=token_probe ip=203.0.113.7 method=POST path=/shared/:token status=404 request_id=...
+fn format_entry(entry: &AbuseLogEntry) -> String {
+    format!(
+        "{} event={} ip={} method={} path={} status={} request_id={}\n",
+        format_rfc3339_millis(entry.timestamp_millis),
+        entry.event,
+        entry.ip,
+        entry.method,
+        entry.path,
+        entry.status,
+        entry.request_id
+    )
+}
+
+pub struct AbuseLogMiddleware {
+    path: String,
+    // the events are still logged, with "-" for the address
+    privacy_mode: bool,
+}
+
+impl AbuseLogMiddleware {
+    pub fn new(path: &str, privacy_mode: bool) -> Self {
+        AbuseLogMiddleware {
+            path: path.to_string(),
+            privacy_mode,
+        }
+    }
+
+    // the file is opened for every event, so it can be rotated without a restart
+    async fn write(&self, line: &str) -> std::io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AbuseLogMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let method = req.method();
+        let budget = classify_request(&req);
+        let path = req.url().path().to_string();
+        let ip = match client_addr(&req) {
+            Some(remote) if !self.privacy_mode => client_ip(remote),
+            _ => "-".to_string(),
+        };
+        let request_id = req
+            .ext::<RequestId>()
+            .map(|request_id| request_id.0.clone())
+            .unwrap_or_else(|| "-".to_string());
+
+        let res = next.run(req).await;
+
+        // a middleware can attach an event to its response when the status alone doesn't tell it
+        let event = res
+            .ext::<AbuseEvent>()
+            .copied()
+            .or_else(|| classify_response(budget, res.status()));
+        if let Some(event) = event {
+            let line = format_entry(&AbuseLogEntry {
+                timestamp_millis: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|duration| duration.as_millis() as u64)
+                    .unwrap_or(0),
+                event,
+                ip: &ip,
+                method: method.as_ref(),
+                path: &mask_message_tokens(&path),
+                status: res.status() as u16,
+                request_id: &request_id,
+            });
+            if let Err(err) = self.write(&line).await {
+                log::error!("Failed to write to the abuse log {}: {}", self.path, err);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use tide::http::{Method, Url};
+    use tide::Response;
+
+    #[test]
+    fn test_format_entry() {
+        let line = format_entry(&AbuseLogEntry {
+            timestamp_millis: 1_700_000_000_123,
+            event: AbuseEvent::TokenProbe,
+            ip: "203.0.113.7",
+            method: "POST",
+            path: "/shared/:token",
+            status: 404,
+            request_id: "abc",
+        });
+        assert_eq!(
+            line,
+            "2023-11-14T22:13:20.123Z event=token_probe ip=203.0.113.7 method=POST path=/shared/:token status=404 request_id=abc\n"
+        );
+    }
+
+    #[test]
+    fn test_classify_response() {
+        assert_eq!(
+            classify_response(Some(Budget::Create), StatusCode::NotFound),
+            Some(AbuseEvent::UnknownUserToken)
+        );
+        assert_eq!(
+            classify_response(Some(Budget::Consume), StatusCode::NotFound),
+            Some(AbuseEvent::TokenProbe)
+        );
+        assert_eq!(
+            classify_response(None, StatusCode::TooManyRequests),
+            Some(AbuseEvent::RateLimited)
+        );
+        assert_eq!(classify_response(None, StatusCode::NotFound), None);
+        assert_eq!(
+            classify_response(Some(Budget::Consume), StatusCode::Ok),
+            None
+        );
+    }
+
+    #[async_std::test]
+    async fn test_middleware_writes_events() {
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("abuse.log");
+
+        let mut app = tide::new();
+        app.with(AbuseLogMiddleware::new(log_path.to_str().unwrap(), false));
+        app.at("/shared/:token")
+            .post(|_| async { Ok(Response::new(StatusCode::NotFound)) });
+        app.at("/").get(|_| async { Ok("home") });
+
+        for (method, url) in [
+            (Method::Get, "http://localhost/"),
+            (Method::Post, "http://localhost/shared/secret"),
+        ] {
+            let mut req = tide::http::Request::new(method, Url::parse(url).unwrap());
+            req.set_peer_addr(Some("10.0.0.1:1000"));
+            let _: tide::http::Response = app.respond(req).await.unwrap();
+        }
+
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains(
+            " event=token_probe ip=10.0.0.1 method=POST path=/shared/:token status=404 "
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_middleware_hides_client_in_privacy_mode() {
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("abuse.log");
+
+        let mut app = tide::new();
+        app.with(AbuseLogMiddleware::new(log_path.to_str().unwrap(), true));
+        app.at("/shared/:token")
+            .post(|_| async { Ok(Response::new(StatusCode::NotFound)) });
+
+        let mut req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://localhost/shared/secret").unwrap(),
+        );
+        req.set_peer_addr(Some("10.0.0.1:1000"));
+        let _: tide::http::Response = app.respond(req).await.unwrap();
+
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        assert!(log.contains(" event=token_probe ip=- method=POST "));
+        assert!(!log.contains("10.0.0.1"));
+    }
+}
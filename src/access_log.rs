@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tide::{Middleware, Next, Request};
+
+use crate::proxy::client_addr;
+use crate::request_id::RequestId;
+use crate::time_format::format_common_log_date;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Off,
+    Common,
+    // common format with referer and user agent
+    #[default]
+    Combined,
+}
+
+// path segments that are followed by a message token or an upload or download id
+const TOKEN_PARENT_SEGMENTS: [&str; 5] = ["shared", "messages", "files", "uploads", "downloads"];
+const TOKEN_MASK: &str = ":token";
+
+pub struct AccessLogMiddleware {
+    format: AccessLogFormat,
+    log_message_tokens: bool,
+    // the address, referer and user agent are logged as "-"
+    privacy_mode: bool,
+}
+
+impl AccessLogMiddleware {
+    pub fn new(format: AccessLogFormat, log_message_tokens: bool, privacy_mode: bool) -> Self {
+        AccessLogMiddleware {
+            format,
+            log_message_tokens,
+            privacy_mode,
+        }
+    }
+}
+
+// anyone who reads the logs could otherwise consume the messages
+pub(crate) fn mask_message_tokens(path: &str) -> String {
+    let mut previous_segment = "";
+    path.split('/')
+        .map(|segment| {
+            let is_token = !segment.is_empty() && TOKEN_PARENT_SEGMENTS.contains(&previous_segment);
+            previous_segment = segment;
+            if is_token {
+                TOKEN_MASK
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+struct AccessLogEntry<'a> {
+    remote_addr: &'a str,
+    timestamp: u64,
+    method: &'a str,
+    path: &'a str,
+    http_version: &'a str,
+    status: u16,
+    size: Option<usize>,
+    referer: &'a str,
+    user_agent: &'a str,
+    duration_millis: u128,
+    request_id: &'a str,
+}
+
+fn format_entry(entry: &AccessLogEntry, format: AccessLogFormat) -> String {
+    let size = entry
+        .size
+        .map(|size| size.to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let mut line = format!(
+        "{} - - [{}] \"{} {} {}\" {} {}",
+        entry.remote_addr,
+        format_common_log_date(entry.timestamp),
+        entry.method,
+        entry.path,
+        entry.http_version,
+        entry.status,
+        size
+    );
+    if format == AccessLogFormat::Combined {
+        line.push_str(&format!(
+            " \"{}\" \"{}\"",
+            entry.referer.replace('"', "\\\""),
+            entry.user_agent.replace('"', "\\\"")
+        ));
+    }
+    line.push_str(&format!(
+        " {}ms {}",
+        entry.duration_millis, entry.request_id
+    ));
+    line
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AccessLogMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let start = Instant::now();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let method = req.method().to_string();
+        let path = if self.log_message_tokens {
+            req.url().path().to_string()
+        } else {
+            mask_message_tokens(req.url().path())
+        };
+        let http_version = req
+            .version()
+            .map(|version| version.to_string())
+            .unwrap_or_else(|| "HTTP/1.1".to_string());
+        let remote_addr = match client_addr(&req) {
+            Some(remote_addr) if !self.privacy_mode => remote_addr.to_string(),
+            _ => "-".to_string(),
+        };
+        let header_value = |name: &str| {
+            req.header(name)
+                .filter(|_| !self.privacy_mode)
+                .map(|values| values.last().as_str().to_string())
+                .unwrap_or_else(|| "-".to_string())
+        };
+        let referer = header_value("Referer");
+        let user_agent = header_value("User-Agent");
+        let request_id = req
+            .ext::<RequestId>()
+            .map(|request_id| request_id.0.clone())
+            .unwrap_or_else(|| "-".to_string());
+
+        let res = next.run(req).await;
+
+        if let Some(err) = res.error() {
+            if res.status().is_server_error() {
+                log::error!(
+                    "{} {} failed: {} (request {})",
+                    method,
+                    path,
+                    err,
+                    request_id
+                );
+            }
+        }
+
+        if self.format != AccessLogFormat::Off {
+            let entry = AccessLogEntry {
+                remote_addr: &remote_addr,
+                timestamp,
+                method: &method,
+                path: &path,
+                http_version: &http_version,
+                status: res.status() as u16,
+                size: res.len(),
+                referer: &referer,
+                user_agent: &user_agent,
+                duration_millis: start.elapsed().as_millis(),
+                request_id: &request_id,
+            };
+            log::info!(target: "access", "{}", format_entry(&entry, self.format));
+        }
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_message_tokens() {
+        assert_eq!(mask_message_tokens("/shared/abc-123"), "/shared/:token");
+        assert_eq!(
+            mask_message_tokens("/api/v1/messages/abc-123/consume"),
+            "/api/v1/messages/:token/consume"
+        );
+        assert_eq!(
+            mask_message_tokens("/api/v1/files/abc-123/consume"),
+            "/api/v1/files/:token/consume"
+        );
+        assert_eq!(mask_message_tokens("/api/v1/messages"), "/api/v1/messages");
+        assert_eq!(mask_message_tokens("/"), "/");
+    }
+
+    #[test]
+    fn test_format_entry() {
+        let entry = AccessLogEntry {
+            remote_addr: "127.0.0.1:5000",
+            timestamp: 971186136,
+            method: "GET",
+            path: "/shared/:token",
+            http_version: "HTTP/1.1",
+            status: 200,
+            size: Some(2326),
+            referer: "-",
+            user_agent: "curl/8.0",
+            duration_millis: 3,
+            request_id: "req-1",
+        };
+
+        assert_eq!(
+            format_entry(&entry, AccessLogFormat::Common),
+            "127.0.0.1:5000 - - [10/Oct/2000:13:55:36 +0000] \"GET /shared/:token HTTP/1.1\" 200 2326 3ms req-1"
+        );
+        assert_eq!(
+            format_entry(&entry, AccessLogFormat::Combined),
+            "127.0.0.1:5000 - - [10/Oct/2000:13:55:36 +0000] \"GET /shared/:token HTTP/1.1\" 200 2326 \"-\" \"curl/8.0\" 3ms req-1"
+        );
+    }
+}
@@ -0,0 +1,482 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::admin_auth::BasicAuthUser;
+use crate::error::AppError;
+use crate::passkeys::{admin_sessions_enabled, session_token};
+use crate::store::{DeliveryState, SettingsStore, StoreResult, UserLimits, WebhookDelivery};
+use crate::tokens::is_same_token;
+use crate::{make_default_user_limits, Config, StaticData};
+
+// names in global_vars, the values set here override the config
+const RETENTION_LIMIT_MINUTES_VAR: &str = "default_retention_limit_minutes";
+const MAX_MESSAGE_SIZE_BYTES_VAR: &str = "default_max_message_size_bytes";
+const MESSAGE_CREATION_LIMIT_MINUTES_VAR: &str = "default_message_creation_limit_minutes";
+const MESSAGE_CREATION_LIMIT_COUNT_VAR: &str = "default_message_creation_limit_count";
+const DEFAULT_LIMIT_VARS: [&str; 4] = [
+    RETENTION_LIMIT_MINUTES_VAR,
+    MAX_MESSAGE_SIZE_BYTES_VAR,
+    MESSAGE_CREATION_LIMIT_MINUTES_VAR,
+    MESSAGE_CREATION_LIMIT_COUNT_VAR,
+];
+
+// the newest deliveries shown when the request doesn't say how many
+const DEFAULT_WEBHOOK_DELIVERY_LIMIT: usize = 100;
+const MAX_WEBHOOK_DELIVERY_LIMIT: usize = 1000;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct DefaultLimits {
+    pub retention_limit_minutes: Option<u32>,
+    pub max_message_size_bytes: Option<u32>,
+    pub message_creation_limit_minutes: Option<u32>,
+    pub message_creation_limit_count: Option<u32>,
+}
+
+// the default limits from the config with the ones changed through the admin API applied
+pub fn load_default_user_limits<S: SettingsStore + ?Sized>(
+    store: &S,
+    config: &Config,
+) -> StoreResult<UserLimits> {
+    let mut limits = make_default_user_limits(config);
+    let fields = [
+        (
+            RETENTION_LIMIT_MINUTES_VAR,
+            &mut limits.retention_limit_minutes,
+        ),
+        (
+            MAX_MESSAGE_SIZE_BYTES_VAR,
+            &mut limits.max_message_size_bytes,
+        ),
+        (
+            MESSAGE_CREATION_LIMIT_MINUTES_VAR,
+            &mut limits.message_creation_limit_minutes,
+        ),
+        (
+            MESSAGE_CREATION_LIMIT_COUNT_VAR,
+            &mut limits.message_creation_limit_count,
+        ),
+    ];
+    for (name, field) in fields {
+        if let Some(value) = store.get_global_integer(name)? {
+            *field = value as u32;
+        }
+    }
+    Ok(limits)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReencryptResponse {
+    pub reencrypted_messages: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WebhookDeliveriesResponse {
+    pub deliveries: Vec<WebhookDelivery>,
+}
+
+fn to_response(limits: &UserLimits) -> DefaultLimits {
+    DefaultLimits {
+        retention_limit_minutes: Some(limits.retention_limit_minutes),
+        max_message_size_bytes: Some(limits.max_message_size_bytes),
+        message_creation_limit_minutes: Some(limits.message_creation_limit_minutes),
+        message_creation_limit_count: Some(limits.message_creation_limit_count),
+    }
+}
+
+pub(crate) fn bearer_token(req: &Request<Arc<Mutex<StaticData>>>) -> Option<&str> {
+    req.header("Authorization")
+        .and_then(|values| values.last().as_str().strip_prefix("Bearer "))
+}
+
+// without a configured token, passkeys, LDAP or basic auth the admin API doesn't exist, the
+// bearer is either the token or a session from one of the sign-ins, basic auth credentials
+// are checked by AdminAuthMiddleware
+pub(crate) fn check_admin_token(
+    req: &Request<Arc<Mutex<StaticData>>>,
+    data: &StaticData,
+) -> tide::Result<()> {
+    let admin_token = data
+        .config
+        .admin_token
+        .as_deref()
+        .filter(|admin_token| !admin_token.is_empty());
+    let sessions_enabled = admin_sessions_enabled(data);
+    if admin_token.is_none() && !sessions_enabled {
+        return Err(AppError::NotFound("Not found".to_string()).into_error());
+    }
+    if req.ext::<BasicAuthUser>().is_some() {
+        return Ok(());
+    }
+    let is_admin_token = bearer_token(req).is_some_and(|bearer_token| {
+        admin_token.is_some_and(|admin_token| is_same_token(bearer_token, admin_token))
+    });
+    let is_valid = is_admin_token
+        || (sessions_enabled && {
+            let mut sessions = data.admin_sessions.lock().unwrap();
+            session_token(req, &sessions)
+                .is_some_and(|session_token| sessions.touch_session(&session_token, Instant::now()))
+        });
+    if !is_valid {
+        return Err(AppError::BadToken("Invalid admin token".to_string()).into_error());
+    }
+    Ok(())
+}
+
+// reloads the defaults and applies them to the default user and the home page right away
+fn apply_default_limits(data: &mut StaticData) -> tide::Result<Response> {
+    let limits = {
+        let database = data.database.lock().unwrap();
+        let limits = load_default_user_limits(&*database, &data.config)?;
+        database.set_user_limits("default", &limits)?;
+        limits
+    };
+    data.default_user_limits = limits;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&to_response(&data.default_user_limits))?)
+        .build())
+}
+
+pub async fn get_default_limits(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    check_admin_token(&req, &data)?;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&to_response(&data.default_user_limits))?)
+        .build())
+}
+
+// only the given limits are changed
+pub async fn update_default_limits(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let update: Result<DefaultLimits, _> = req.body_json().await;
+
+    let state = req.state().clone();
+    let mut data = state.lock().unwrap();
+    check_admin_token(&req, &data)?;
+    let update = match update {
+        Ok(update) => update,
+        Err(_) => {
+            return Err(AppError::BadRequest("Can't parse request body".to_string()).into_error())
+        }
+    };
+
+    {
+        let database = data.database.lock().unwrap();
+        let values = [
+            (RETENTION_LIMIT_MINUTES_VAR, update.retention_limit_minutes),
+            (MAX_MESSAGE_SIZE_BYTES_VAR, update.max_message_size_bytes),
+            (
+                MESSAGE_CREATION_LIMIT_MINUTES_VAR,
+                update.message_creation_limit_minutes,
+            ),
+            (
+                MESSAGE_CREATION_LIMIT_COUNT_VAR,
+                update.message_creation_limit_count,
+            ),
+        ];
+        for (name, value) in values {
+            if let Some(value) = value {
+                database.set_global_integer(name, value as i64)?;
+            }
+        }
+    }
+    log::info!("Default limits changed through the admin API");
+    apply_default_limits(&mut data)
+}
+
+// goes back to the limits from the config
+pub async fn reset_default_limits(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let mut data = req.state().lock().unwrap();
+    check_admin_token(&req, &data)?;
+    {
+        let database = data.database.lock().unwrap();
+        for name in DEFAULT_LIMIT_VARS {
+            database.remove_global_var(name)?;
+        }
+    }
+    log::info!("Default limits reset to the config through the admin API");
+    apply_default_limits(&mut data)
+}
+
+// after it's done the keys other than the newest one can be removed from the config
+pub async fn reencrypt_messages(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    check_admin_token(&req, &data)?;
+    let reencrypted_messages = data.database.lock().unwrap().reencrypt_messages()?;
+    log::info!(
+        "{} message(s) re-encrypted with the newest key through the admin API",
+        reencrypted_messages
+    );
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&ReencryptResponse {
+            reencrypted_messages,
+        })?)
+        .build())
+}
+
+// e.g. `?state=failed&limit=10`, the newest deliveries first
+pub async fn list_webhook_deliveries(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    check_admin_token(&req, &data)?;
+    let mut state = None;
+    let mut limit = DEFAULT_WEBHOOK_DELIVERY_LIMIT;
+    for (name, value) in req.url().query_pairs() {
+        match name.as_ref() {
+            "state" => {
+                state = Some(DeliveryState::parse(&value).ok_or_else(|| {
+                    AppError::Invalid {
+                        field: "state",
+                        message: "State should be pending, delivered or failed".to_string(),
+                    }
+                    .into_error()
+                })?)
+            }
+            "limit" => {
+                limit = value
+                    .parse::<usize>()
+                    .map_err(|_| {
+                        AppError::Invalid {
+                            field: "limit",
+                            message: "Limit should be a number".to_string(),
+                        }
+                        .into_error()
+                    })?
+                    .min(MAX_WEBHOOK_DELIVERY_LIMIT)
+            }
+            _ => {}
+        }
+    }
+    let deliveries = data
+        .database
+        .lock()
+        .unwrap()
+        .list_webhook_deliveries(state, limit)?;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&WebhookDeliveriesResponse { deliveries })?)
+        .build())
+}
+
+fn find_webhook_delivery(
+    data: &StaticData,
+    req: &Request<Arc<Mutex<StaticData>>>,
+) -> tide::Result<WebhookDelivery> {
+    let id = req.param("id")?.parse::<i64>().ok();
+    let delivery = match id {
+        Some(id) => data.database.lock().unwrap().get_webhook_delivery(id)?,
+        None => None,
+    };
+    delivery.ok_or_else(|| AppError::NotFound("Delivery not found".to_string()).into_error())
+}
+
+pub async fn get_webhook_delivery(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    check_admin_token(&req, &data)?;
+    let delivery = find_webhook_delivery(&data, &req)?;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&delivery)?)
+        .build())
+}
+
+// a failed delivery gets another full set of attempts, starting right away
+pub async fn retry_webhook_delivery(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    check_admin_token(&req, &data)?;
+    let delivery = find_webhook_delivery(&data, &req)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    if !data
+        .database
+        .lock()
+        .unwrap()
+        .retry_webhook_delivery(delivery.id, now)?
+    {
+        return Err(
+            AppError::Conflict("Only failed deliveries can be retried".to_string()).into_error(),
+        );
+    }
+    log::info!(
+        "Webhook delivery {} retried through the admin API",
+        delivery.id
+    );
+    let delivery = find_webhook_delivery(&data, &req)?;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&delivery)?)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::WebhookAttempt;
+    use crate::tests::setup_test_data;
+    use tide::http::{Method, Url};
+
+    fn make_request(method: Method, admin_token: Option<&str>) -> tide::http::Request {
+        let mut req = tide::http::Request::new(
+            method,
+            Url::parse("http://localhost/api/v1/admin/defaults").unwrap(),
+        );
+        if let Some(admin_token) = admin_token {
+            req.insert_header("Authorization", format!("Bearer {}", admin_token));
+        }
+        req
+    }
+
+    #[async_std::test]
+    async fn test_admin_api_is_disabled_without_token() {
+        let app = crate::init_app(setup_test_data());
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Get, Some("")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn test_reencrypt_messages() {
+        let app_data = setup_test_data();
+        app_data.lock().unwrap().config.admin_token = Some("admin".to_string());
+        let app = crate::init_app(app_data);
+
+        let mut req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/admin/reencrypt").unwrap(),
+        );
+        req.insert_header("Authorization", "Bearer wrong");
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        // without encryption keys there is nothing to do
+        let mut req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/admin/reencrypt").unwrap(),
+        );
+        req.insert_header("Authorization", "Bearer admin");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: ReencryptResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.reencrypted_messages, 0);
+    }
+
+    #[async_std::test]
+    async fn test_update_and_reset_default_limits() {
+        let app_data = setup_test_data();
+        app_data.lock().unwrap().config.admin_token = Some("admin".to_string());
+        let app = crate::init_app(app_data.clone());
+
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Get, Some("wrong")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let mut req = make_request(Method::Put, Some("admin"));
+        req.set_body(
+            Body::from_json(&DefaultLimits {
+                max_message_size_bytes: Some(2048),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: DefaultLimits = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.max_message_size_bytes, Some(2048));
+        assert_eq!(body.retention_limit_minutes, Some(60));
+
+        {
+            let data = app_data.lock().unwrap();
+            let database = data.database.lock().unwrap();
+            let limits = database.get_user_limits("default").unwrap().unwrap();
+            assert_eq!(limits.max_message_size_bytes, 2048);
+            assert_eq!(
+                load_default_user_limits(&*database, &data.config)
+                    .unwrap()
+                    .max_message_size_bytes,
+                2048
+            );
+        }
+
+        let mut res: tide::http::Response = app
+            .respond(make_request(Method::Delete, Some("admin")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: DefaultLimits = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.max_message_size_bytes, Some(1024));
+    }
+
+    #[async_std::test]
+    async fn test_webhook_deliveries() {
+        let app_data = setup_test_data();
+        app_data.lock().unwrap().config.admin_token = Some("admin".to_string());
+        let failed_id = {
+            let data = app_data.lock().unwrap();
+            let database = data.database.lock().unwrap();
+            database
+                .enqueue_webhook("https://hooks.example.com/a", "message.consumed", "{}", 100)
+                .unwrap();
+            let failed_id = database
+                .enqueue_webhook("https://hooks.example.com/b", "message.expired", "{}", 100)
+                .unwrap();
+            database
+                .record_webhook_attempt(
+                    failed_id,
+                    &WebhookAttempt {
+                        timestamp: 110,
+                        state: DeliveryState::Failed,
+                        status: Some(500),
+                        error: None,
+                        next_attempt_timestamp: None,
+                    },
+                )
+                .unwrap();
+            failed_id
+        };
+        let app = crate::init_app(app_data);
+        let request = |method: Method, path: &str| {
+            let mut req = tide::http::Request::new(
+                method,
+                Url::parse(&format!("http://localhost/api/v1/admin/webhooks{}", path)).unwrap(),
+            );
+            req.insert_header("Authorization", "Bearer admin");
+            req
+        };
+
+        let mut res: tide::http::Response = app.respond(request(Method::Get, "")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: WebhookDeliveriesResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.deliveries.len(), 2);
+
+        let mut res: tide::http::Response = app
+            .respond(request(Method::Get, "?state=failed"))
+            .await
+            .unwrap();
+        let body: WebhookDeliveriesResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.deliveries.len(), 1);
+        assert_eq!(body.deliveries[0].id, failed_id);
+        assert_eq!(body.deliveries[0].last_status, Some(500));
+
+        let res: tide::http::Response = app
+            .respond(request(Method::Get, "?state=lost"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+
+        let path = format!("/{}/retry", failed_id);
+        let mut res: tide::http::Response =
+            app.respond(request(Method::Post, &path)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let delivery: WebhookDelivery = res.take_body().into_json().await.unwrap();
+        assert_eq!(delivery.state, DeliveryState::Pending);
+        assert_eq!(delivery.attempts, 0);
+
+        // it's pending now
+        let res: tide::http::Response = app.respond(request(Method::Post, &path)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Conflict);
+
+        let res: tide::http::Response = app.respond(request(Method::Get, "/999")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+        let res: tide::http::Response = app.respond(request(Method::Get, "/abc")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+}
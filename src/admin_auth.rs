@@ -0,0 +1,334 @@
+use async_std::task;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tide::{Middleware, Next, Request, Response};
+
+use crate::error::AppError;
+use crate::passkeys::signed_in_response;
+use crate::server::ClientCertificate;
+use crate::StaticData;
+
+pub const BASIC_SIGN_IN_PATH: &str = "/api/v1/admin/sessions/basic";
+const BASIC_AUTH_REALM: &str = "One Time Share admin";
+// checked when the user doesn't exist, so the time doesn't tell which names do
+const UNKNOWN_USER_HASH: &str = "$2y$12$L6Bc/AlTQHyd9liGgGEZyOFLPHNgyxeEPfgYfBCVxJ7JIlwxyVU3u";
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AdminAuthConfig {
+    // user name to bcrypt hash, e.g. the part after the colon of `htpasswd -nbB admin <password>`
+    pub basic_auth_users: HashMap<String, String>,
+    // the admin page and API answer only to TLS clients with a certificate that was verified
+    // against tls.clientCaPath
+    pub require_client_cert: bool,
+}
+
+impl AdminAuthConfig {
+    pub fn basic_auth_enabled(&self) -> bool {
+        !self.basic_auth_users.is_empty()
+    }
+
+    // a hash that can't be parsed would refuse the user on every try
+    pub fn validate(&self) -> Result<(), String> {
+        match self
+            .basic_auth_users
+            .iter()
+            .find(|(_, hash)| hash.parse::<bcrypt::HashParts>().is_err())
+        {
+            Some((username, _)) => Err(format!(
+                "adminAuth.basicAuthUsers has an invalid bcrypt hash for '{}'",
+                username
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+// set by the middleware when the request came with valid basic auth credentials
+#[derive(Clone)]
+pub struct BasicAuthUser(pub String);
+
+fn is_admin_path(path: &str) -> bool {
+    path == "/admin" || path.starts_with("/admin/") || path.starts_with("/api/v1/admin/")
+}
+
+fn parse_basic_auth(authorization: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let credentials = String::from_utf8(STANDARD.decode(credentials.trim()).ok()?).ok()?;
+    let (username, password) = credentials.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+// tells the browser to ask for the user name and password
+fn basic_auth_challenge(message: &str) -> Response {
+    let mut res: Response = AppError::BadToken(message.to_string()).into_error().into();
+    res.insert_header(
+        "WWW-Authenticate",
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", BASIC_AUTH_REALM),
+    );
+    res
+}
+
+// guards the admin page and API with a client certificate and basic auth when configured
+pub struct AdminAuthMiddleware {
+    config: AdminAuthConfig,
+}
+
+impl AdminAuthMiddleware {
+    pub fn new(config: &AdminAuthConfig) -> Self {
+        AdminAuthMiddleware {
+            config: config.clone(),
+        }
+    }
+
+    // bcrypt is slow on purpose, so it doesn't run on the executor threads
+    async fn verify(&self, username: &str, password: String) -> bool {
+        let hash = self.config.basic_auth_users.get(username).cloned();
+        let is_known = hash.is_some();
+        let hash = hash.unwrap_or_else(|| UNKNOWN_USER_HASH.to_string());
+        let is_valid =
+            task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false)).await;
+        is_known && is_valid
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AdminAuthMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let path = req.url().path().to_string();
+        if !is_admin_path(&path) {
+            return Ok(next.run(req).await);
+        }
+        if self.config.require_client_cert && req.ext::<ClientCertificate>().is_none() {
+            return Err(
+                AppError::Forbidden("A client certificate is needed".to_string()).into_error(),
+            );
+        }
+        if !self.config.basic_auth_enabled() {
+            return Ok(next.run(req).await);
+        }
+
+        let credentials = req
+            .header("Authorization")
+            .and_then(|values| parse_basic_auth(values.last().as_str()));
+        match credentials {
+            Some((username, password)) => {
+                if !self.verify(&username, password).await {
+                    log::warn!("Basic auth of '{}' refused", username);
+                    return Ok(basic_auth_challenge("Invalid user name or password"));
+                }
+                req.set_ext(BasicAuthUser(username));
+            }
+            // the API also takes bearer tokens, only the page and the sign-in ask the browser
+            None if path == "/admin" || path == BASIC_SIGN_IN_PATH => {
+                return Ok(basic_auth_challenge("Sign-in needed"));
+            }
+            None => {}
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+// the admin page trades the basic auth credentials for a session like the other sign-ins
+pub async fn sign_in(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    if !data.config.admin_auth.basic_auth_enabled() {
+        return Err(AppError::NotFound("Not found".to_string()).into_error());
+    }
+    let BasicAuthUser(username) = req
+        .ext::<BasicAuthUser>()
+        .ok_or_else(|| AppError::BadToken("Sign-in failed".to_string()).into_error())?;
+    log::info!("Admin {} signed in with basic auth", username);
+    signed_in_response(&req, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passkeys::SignInResponse;
+    use crate::tests::setup_test_data;
+    use tide::http::{Method, Url};
+    use tide::StatusCode;
+
+    fn make_config() -> AdminAuthConfig {
+        AdminAuthConfig {
+            basic_auth_users: HashMap::from([(
+                "alice".to_string(),
+                bcrypt::hash("secret", 4).unwrap(),
+            )]),
+            require_client_cert: false,
+        }
+    }
+
+    fn make_request(method: Method, path: &str, credentials: Option<&str>) -> tide::http::Request {
+        let mut req = tide::http::Request::new(
+            method,
+            Url::parse(&format!("http://localhost{}", path)).unwrap(),
+        );
+        if let Some(credentials) = credentials {
+            req.insert_header(
+                "Authorization",
+                format!("Basic {}", STANDARD.encode(credentials)),
+            );
+        }
+        req
+    }
+
+    fn make_app(config: AdminAuthConfig) -> tide::Server<Arc<Mutex<StaticData>>> {
+        let app_data = setup_test_data();
+        app_data.lock().unwrap().config.admin_auth = config;
+        crate::init_app(app_data)
+    }
+
+    #[test]
+    fn test_parse_basic_auth() {
+        assert_eq!(
+            parse_basic_auth("Basic YWxpY2U6c2VjcmV0"),
+            Some(("alice".to_string(), "secret".to_string()))
+        );
+        // the password can have colons, the user name can't
+        assert_eq!(
+            parse_basic_auth(&format!("basic {}", STANDARD.encode("alice:a:b"))),
+            Some(("alice".to_string(), "a:b".to_string()))
+        );
+        assert_eq!(parse_basic_auth("Bearer YWxpY2U6c2VjcmV0"), None);
+        assert_eq!(parse_basic_auth("Basic not-base64"), None);
+        assert_eq!(
+            parse_basic_auth(&format!("Basic {}", STANDARD.encode("alice"))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(make_config().validate().is_ok());
+        let config = AdminAuthConfig {
+            basic_auth_users: HashMap::from([("bob".to_string(), "plain".to_string())]),
+            require_client_cert: false,
+        };
+        assert!(config.validate().unwrap_err().contains("'bob'"));
+    }
+
+    #[async_std::test]
+    async fn test_admin_page_asks_for_credentials() {
+        let app = make_app(make_config());
+
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Get, "/admin", None))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert!(res["WWW-Authenticate"].as_str().starts_with("Basic "));
+
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Get, "/admin", Some("alice:wrong")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Get, "/admin", Some("mallory:secret")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Get, "/admin", Some("alice:secret")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        // other pages don't need the credentials
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Get, "/", None))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn test_basic_auth_gives_admin_access() {
+        let app = make_app(make_config());
+
+        let res: tide::http::Response = app
+            .respond(make_request(
+                Method::Get,
+                "/api/v1/admin/defaults",
+                Some("alice:secret"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        // without credentials the API answers like before, with no browser prompt
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Get, "/api/v1/admin/defaults", None))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert!(res.header("WWW-Authenticate").is_none());
+
+        let mut res: tide::http::Response = app
+            .respond(make_request(
+                Method::Post,
+                BASIC_SIGN_IN_PATH,
+                Some("alice:secret"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let response: SignInResponse = res.take_body().into_json().await.unwrap();
+
+        let mut req = make_request(Method::Get, "/api/v1/admin/defaults", None);
+        req.insert_header(
+            "Authorization",
+            format!("Bearer {}", response.session_token),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn test_sign_in_is_disabled_by_default() {
+        let app = make_app(AdminAuthConfig::default());
+        let res: tide::http::Response = app
+            .respond(make_request(
+                Method::Post,
+                BASIC_SIGN_IN_PATH,
+                Some("alice:secret"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn test_client_certificate_is_required() {
+        let app = make_app(AdminAuthConfig {
+            require_client_cert: true,
+            ..make_config()
+        });
+
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Get, "/admin", Some("alice:secret")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Forbidden);
+
+        let mut req = make_request(Method::Get, "/admin", Some("alice:secret"));
+        req.ext_mut().insert(ClientCertificate(vec![0x30]));
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Get, "/", None))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+}
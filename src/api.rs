@@ -0,0 +1,2711 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tide::{Body, Request, Response, StatusCode};
+use zeroize::Zeroizing;
+
+use crate::binary_format::accept_quality;
+use crate::downloads::{start_download, FileInfo};
+use crate::email::EmailEvent;
+use crate::error::AppError;
+use crate::passphrase::verify_passphrase;
+use crate::proxy::IpNetwork;
+use crate::qr::QrCode;
+use crate::receipts::{truncate_ip, truncate_user_agent, Reader};
+use crate::store::{MessageState, ReadReceipt};
+use crate::time_format::format_year_month;
+use crate::tokens::hash_token;
+use crate::totp::verify_code;
+use crate::webhooks::{self, WebhookEvent};
+use crate::{
+    get_user_limits, make_bare_share_url, make_qr_url, make_share_url, make_token_share_url,
+    save_new_message, text_share_url, Config, CreatedMessage, MessageForm, StaticData,
+};
+
+const RAW_BYTES_MEDIA_TYPE: &str = "application/octet-stream";
+const SVG_MEDIA_TYPE: &str = "image/svg+xml";
+const PNG_MEDIA_TYPE: &str = "image/png";
+// pixels per module, big enough for a phone camera pointed at a screen
+const QR_PNG_SCALE: usize = 8;
+pub(crate) const MESSAGE_SHA256_HEADER: &str = "Message-Sha256";
+pub(crate) const MESSAGE_DELETE_TOKEN_HEADER: &str = "Message-Delete-Token";
+// seconds until the message expires, left out for a message that doesn't
+pub(crate) const MESSAGE_EXPIRES_IN_HEADER: &str = "Message-Expires-In";
+// three guesses of a 4-digit PIN leave 9997 in 10000 to the owner
+const MAX_PIN_ATTEMPTS: u32 = 3;
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateMessageResponse {
+    pub url: String,
+    // a QR code of the url, not set for end-to-end encrypted messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qr_url: Option<String>,
+    // only for a message with recipients, each link can be opened once
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<RecipientLink>,
+    pub message_token: String,
+    pub expire_timestamp: u64,
+    // when the message can't be read right away, the unix time it can be read from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<u64>,
+    // hex SHA-256 of the message data, sent back in the MESSAGE_SHA256_HEADER on consumption
+    pub sha256: String,
+    // removes the message with DELETE /api/v1/messages/{token}, it's not part of the link
+    // and it's the same for the links of all recipients
+    pub delete_token: String,
+    // for a dual control message, each goes to another person and both read it together
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authorization_tokens: Vec<String>,
+    // a new authenticator secret for the recipient, in base32 and as an otpauth:// link that
+    // authenticator apps take from a QR code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_uri: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RecipientLink {
+    pub name: String,
+    pub url: String,
+    // its status tells whether this recipient read the message
+    pub message_token: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct ConsumeMessageRequest {
+    pub passphrase: Zeroizing<Option<String>>,
+    // both tokens of a dual control message, in any order
+    #[serde(default)]
+    pub authorization_tokens: Vec<Zeroizing<String>>,
+    // the current code of the authenticator the message is bound to
+    #[serde(default)]
+    pub totp_code: Option<String>,
+    #[serde(default)]
+    pub pin: Zeroizing<Option<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConsumeMessageResponse {
+    pub message_data: Zeroizing<String>,
+    pub expire_timestamp: u64,
+    // the seconds left until the message expires, not set for a message that doesn't
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in_seconds: Option<u64>,
+    #[serde(default)]
+    pub filename: Option<String>,
+    #[serde(default)]
+    pub content_type: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MessageMetaResponse {
+    pub size_bytes: usize,
+    pub expire_timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in_seconds: Option<u64>,
+    pub passphrase_required: bool,
+    // two authorization tokens are needed to read it
+    #[serde(default)]
+    pub dual_control: bool,
+    // a code of the authenticator the message is bound to is needed to read it
+    #[serde(default)]
+    pub totp_required: bool,
+    #[serde(default)]
+    pub pin_required: bool,
+    pub client_encrypted: bool,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    // the reads left, 1 for a message that is removed when it's read
+    #[serde(default = "one_view")]
+    pub remaining_views: u32,
+    // the unix time the message can be read from, when it's set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<u64>,
+}
+
+fn one_view() -> u32 {
+    1
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MessageStatusResponse {
+    pub status: MessageState,
+    // not known for the messages stored before it was kept
+    pub created_timestamp: Option<u64>,
+    pub expire_timestamp: u64,
+    // when the recipient read the message
+    pub consumed_timestamp: Option<u64>,
+    // when the message was removed, whatever the reason
+    pub removed_timestamp: Option<u64>,
+    // only when read receipts are turned on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<ReadReceipt>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LimitsResponse {
+    pub max_message_size_bytes: u32,
+    pub retention_limit_minutes: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QuotaRequest {
+    pub user_token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QuotaResponse {
+    // the calendar month the usage is counted for, e.g. "2024-02"
+    pub month: String,
+    pub used_bytes: u64,
+    // not set when the user has no quota
+    pub quota_bytes: Option<u64>,
+    pub remaining_bytes: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: String,
+    // the request field that failed the validation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    // set when the request was refused because of a limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_seconds: Option<u64>,
+    // the unix time a message that isn't unlocked yet can be read from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<u64>,
+    // filled in by the request id middleware
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+pub async fn create_message(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let form: MessageForm = match req.body_json().await {
+        Ok(form) => form,
+        Err(_) => {
+            return Err(AppError::BadRequest("Can't parse request body".to_string()).into_error())
+        }
+    };
+
+    let data = req.state().lock().unwrap();
+    let created = save_new_message(&data, &form)?;
+    let url = make_share_url(&req, &data.config, &created);
+    text_share_url(&data, &form, &url);
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&CreateMessageResponse {
+            url,
+            qr_url: make_qr_url(&req, &data.config, &created),
+            recipients: recipient_links(&req, &data.config, &created),
+            message_token: created.message_token,
+            expire_timestamp: created.expire_timestamp,
+            not_before: created.not_before,
+            sha256: created.checksum,
+            delete_token: created.delete_token,
+            authorization_tokens: created.authorization_tokens,
+            totp_secret: created.totp_secret,
+            totp_uri: created.totp_uri,
+        })?)
+        .build())
+}
+
+pub(crate) fn recipient_links<State>(
+    req: &Request<State>,
+    config: &Config,
+    created: &CreatedMessage,
+) -> Vec<RecipientLink> {
+    created
+        .recipients
+        .iter()
+        .map(|recipient| RecipientLink {
+            name: recipient.name.clone(),
+            url: make_token_share_url(req, config, created, &recipient.message_token),
+            message_token: recipient.message_token.clone(),
+        })
+        .collect()
+}
+
+pub fn parse_consume_request(body: &str) -> tide::Result<ConsumeMessageRequest> {
+    if body.is_empty() {
+        return Ok(ConsumeMessageRequest::default());
+    }
+    serde_json::from_str(body)
+        .map_err(|_| AppError::BadRequest("Can't parse request body".to_string()).into_error())
+}
+
+// the lifetime left of a message, None when it doesn't expire
+pub(crate) fn expires_in_seconds(expire_timestamp: i64) -> Option<u64> {
+    if expire_timestamp == 0 {
+        return None;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as i64);
+    Some((expire_timestamp - now).max(0) as u64)
+}
+
+pub(crate) fn insert_expires_in_header(res: &mut Response, expire_timestamp: i64) {
+    if let Some(expires_in_seconds) = expires_in_seconds(expire_timestamp) {
+        res.insert_header(MESSAGE_EXPIRES_IN_HEADER, expires_in_seconds.to_string());
+    }
+}
+
+// a forged token is answered like a token of a message that doesn't exist, so it counts
+// against the brute force protection just the same
+pub(crate) fn check_token_signature(data: &StaticData, message_token: &str) -> tide::Result<()> {
+    match &data.token_signer {
+        Some(token_signer) if !token_signer.verify(message_token) => {
+            Err(AppError::NotFound("Message not found".to_string()).into_error())
+        }
+        _ => Ok(()),
+    }
+}
+
+// a reader from anywhere else is turned away before anything else is checked, so they use
+// up no attempts and the message is left for the reader it's meant for
+fn check_allowed_ips(data: &StaticData, message_token: &str, reader: &Reader) -> tide::Result<()> {
+    let allowed_ips = data
+        .database
+        .lock()
+        .unwrap()
+        .get_message_allowed_ips(message_token)?;
+    if allowed_ips.is_empty() {
+        return Ok(());
+    }
+    let is_allowed = reader.ip.parse::<IpAddr>().is_ok_and(|ip| {
+        allowed_ips
+            .iter()
+            .filter_map(|allowed_ip| IpNetwork::parse(allowed_ip))
+            .any(|network| network.contains(ip))
+    });
+    if !is_allowed {
+        return Err(
+            AppError::Forbidden("Message can't be read from this address".to_string()).into_error(),
+        );
+    }
+    Ok(())
+}
+
+// a dual control message is read only with both of its tokens, the two hashes are of distinct
+// tokens, so one person with one token can't stand in for two
+fn check_authorization_tokens(
+    data: &StaticData,
+    message_token: &str,
+    authorization_tokens: &[Zeroizing<String>],
+) -> tide::Result<()> {
+    let authorization_hashes = match data
+        .database
+        .lock()
+        .unwrap()
+        .get_message_authorization_hashes(message_token)?
+    {
+        Some(authorization_hashes) => authorization_hashes,
+        None => return Ok(()),
+    };
+    let presented_hashes: Vec<String> = authorization_tokens
+        .iter()
+        .map(|authorization_token| hash_token(authorization_token))
+        .collect();
+    if !authorization_hashes
+        .iter()
+        .all(|authorization_hash| presented_hashes.contains(authorization_hash))
+    {
+        return Err(
+            AppError::BadToken("Both authorization tokens are required".to_string()).into_error(),
+        );
+    }
+    Ok(())
+}
+
+// a wrong code uses up the passphrase attempts, so the million codes can't be tried through,
+// and a code that was accepted once isn't accepted again
+fn check_totp_code(
+    data: &StaticData,
+    message_token: &str,
+    totp_code: Option<&str>,
+) -> tide::Result<()> {
+    let totp_secret = match data
+        .database
+        .lock()
+        .unwrap()
+        .get_message_totp_secret(message_token)?
+    {
+        Some(totp_secret) => totp_secret,
+        None => return Ok(()),
+    };
+    let totp_code = match totp_code {
+        Some(totp_code) if !totp_code.is_empty() => totp_code,
+        _ => return Err(AppError::BadToken("TOTP code required".to_string()).into_error()),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let database = data.database.lock().unwrap();
+    let is_accepted = match verify_code(&totp_secret, totp_code, now) {
+        Some(step) => database.use_message_totp_step(message_token, step)?,
+        None => false,
+    };
+    if !is_accepted {
+        if database.register_failed_passphrase_attempt(
+            message_token,
+            data.config.max_passphrase_attempts,
+        )? {
+            return Err(AppError::Gone(
+                "Too many failed attempts, the message has been destroyed".to_string(),
+            )
+            .into_error());
+        }
+        return Err(AppError::BadToken("Invalid TOTP code".to_string()).into_error());
+    }
+    Ok(())
+}
+
+// unlike the passphrase, the PIN is short by design, so it gets only a few attempts whatever
+// the config allows for passphrases
+fn check_pin(data: &StaticData, message_token: &str, pin: Option<&str>) -> tide::Result<()> {
+    let pin_hash = match data
+        .database
+        .lock()
+        .unwrap()
+        .get_message_pin_hash(message_token)?
+    {
+        Some(pin_hash) => pin_hash,
+        None => return Ok(()),
+    };
+    let pin = match pin {
+        Some(pin) if !pin.is_empty() => pin,
+        _ => return Err(AppError::BadToken("PIN required".to_string()).into_error()),
+    };
+    if !verify_passphrase(pin, &pin_hash) {
+        let is_destroyed = data
+            .database
+            .lock()
+            .unwrap()
+            .register_failed_pin_attempt(message_token, MAX_PIN_ATTEMPTS)?;
+        if is_destroyed {
+            return Err(AppError::Gone(
+                "Too many wrong PINs, the message has been destroyed".to_string(),
+            )
+            .into_error());
+        }
+        return Err(AppError::BadToken(format!(
+            "Invalid PIN, the message is destroyed after {} wrong ones",
+            MAX_PIN_ATTEMPTS
+        ))
+        .into_error());
+    }
+    Ok(())
+}
+
+// checks the passphrase (if the message has one) and removes the message from the database
+pub fn consume_protected_message(
+    data: &StaticData,
+    message_token: &str,
+    consume_request: &ConsumeMessageRequest,
+    reader: &Reader,
+) -> tide::Result<(Zeroizing<Vec<u8>>, i64)> {
+    check_token_signature(data, message_token)?;
+    check_allowed_ips(data, message_token, reader)?;
+    // before the passphrase, so the attempts aren't used up while the message is locked
+    if let Some(not_before) = data
+        .database
+        .lock()
+        .unwrap()
+        .get_message_not_before(message_token)?
+    {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        if now < not_before {
+            return Err(AppError::TooEarly {
+                message: "Message can't be read yet".to_string(),
+                not_before,
+            }
+            .into_error());
+        }
+    }
+    check_authorization_tokens(data, message_token, &consume_request.authorization_tokens)?;
+    check_totp_code(data, message_token, consume_request.totp_code.as_deref())?;
+    check_pin(data, message_token, consume_request.pin.as_deref())?;
+    let passphrase_hash = data
+        .database
+        .lock()
+        .unwrap()
+        .get_message_passphrase_hash(message_token)?;
+
+    if let Some(passphrase_hash) = passphrase_hash {
+        let passphrase = match consume_request.passphrase.as_deref() {
+            Some(passphrase) if !passphrase.is_empty() => passphrase,
+            _ => return Err(AppError::BadToken("Passphrase required".to_string()).into_error()),
+        };
+
+        if !verify_passphrase(passphrase, &passphrase_hash) {
+            let is_destroyed = data
+                .database
+                .lock()
+                .unwrap()
+                .register_failed_passphrase_attempt(
+                    message_token,
+                    data.config.max_passphrase_attempts,
+                )?;
+            if is_destroyed {
+                return Err(AppError::Gone(
+                    "Too many failed passphrase attempts, the message has been destroyed"
+                        .to_string(),
+                )
+                .into_error());
+            }
+            return Err(AppError::BadToken("Invalid passphrase".to_string()).into_error());
+        }
+    }
+
+    // looked up before the message and its webhook are removed
+    let webhook_url = match &data.webhooks {
+        Some(_) => data
+            .database
+            .lock()
+            .unwrap()
+            .get_message_webhook_url(message_token)?,
+        None => None,
+    };
+    let creator_email = match &data.email {
+        Some(_) => data
+            .database
+            .lock()
+            .unwrap()
+            .get_message_creator_email(message_token)?,
+        None => None,
+    };
+
+    // the creator hears about the last view, not the ones before it or in its grace window
+    let is_last_view = data
+        .database
+        .lock()
+        .unwrap()
+        .get_message_remaining_views(message_token)?
+        == Some(1);
+
+    let (message_data, expire_timestamp) = data
+        .database
+        .lock()
+        .unwrap()
+        .try_consume_message(message_token)?;
+
+    let message_data = match message_data {
+        Some(message_data) => message_data,
+        None => return Err(AppError::NotFound("Message not found".to_string()).into_error()),
+    };
+
+    // the message is already removed at this point, so an expired message is gone for good
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    if expire_timestamp != 0 && expire_timestamp <= now {
+        return Err(AppError::Gone("Message has expired".to_string()).into_error());
+    }
+
+    if !is_last_view {
+        return Ok((message_data, expire_timestamp));
+    }
+    if data.config.read_receipts.enabled {
+        save_read_receipt(data, message_token, reader, now)?;
+    }
+    if let Some(webhook_url) = webhook_url {
+        webhooks::enqueue(
+            &*data.database.lock().unwrap(),
+            &webhook_url,
+            &WebhookEvent::consumed(message_token, now),
+        )?;
+    }
+    if let (Some(email), Some(creator_email)) = (&data.email, creator_email) {
+        email.notify(
+            &creator_email,
+            EmailEvent::Consumed,
+            &hash_token(message_token),
+            now,
+        );
+    }
+
+    Ok((message_data, expire_timestamp))
+}
+
+fn save_read_receipt(
+    data: &StaticData,
+    message_token: &str,
+    reader: &Reader,
+    now: i64,
+) -> tide::Result<()> {
+    let record_client = data.config.read_receipts.record_client && !data.config.privacy_mode;
+    let receipt = ReadReceipt {
+        read_timestamp: now,
+        client_ip: record_client.then(|| truncate_ip(&reader.ip)).flatten(),
+        user_agent: record_client
+            .then(|| reader.user_agent.as_deref().map(truncate_user_agent))
+            .flatten(),
+    };
+    data.database
+        .lock()
+        .unwrap()
+        .save_read_receipt(message_token, &receipt)?;
+    // the token is a secret, the start of its hash is enough to find the message in the logs
+    log::info!(
+        target: "audit",
+        "Message read message={} ip={} user_agent={:?}",
+        &hash_token(message_token)[..16],
+        receipt.client_ip.as_deref().unwrap_or("-"),
+        receipt.user_agent.as_deref().unwrap_or("-")
+    );
+    Ok(())
+}
+
+pub async fn consume_message(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let body = Zeroizing::new(req.body_string().await?);
+    let consume_request = parse_consume_request(&body)?;
+
+    let message_token = req.param("token")?;
+    // a client that prefers the raw bytes gets them like a file download
+    let wants_raw_bytes = req.header("Accept").is_some_and(|accept| {
+        let accept = accept.last().as_str();
+        accept_quality(accept, RAW_BYTES_MEDIA_TYPE) > accept_quality(accept, "application/json")
+    });
+    let range_header = req
+        .header("Range")
+        .map(|values| values.last().as_str().to_string());
+
+    let data = req.state().lock().unwrap();
+    // a forged token is turned away before it costs a storage lookup
+    check_token_signature(&data, message_token)?;
+    let message_info = match data
+        .database
+        .lock()
+        .unwrap()
+        .get_message_info(message_token)?
+    {
+        Some(message_info) => message_info,
+        None => return Err(AppError::NotFound("Message not found".to_string()).into_error()),
+    };
+
+    let (message_data, expire_timestamp) =
+        consume_protected_message(&data, message_token, &consume_request, &Reader::of(&req))?;
+
+    let file_info = FileInfo {
+        filename: message_info.filename,
+        content_type: message_info.content_type,
+        checksum: message_info.checksum,
+    };
+    if wants_raw_bytes {
+        let mut res = start_download(&data, message_data, file_info, range_header.as_deref())?;
+        insert_expires_in_header(&mut res, expire_timestamp);
+        return Ok(res);
+    }
+
+    let mut res = Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&ConsumeMessageResponse {
+            message_data: STANDARD.encode(&message_data).into(),
+            expire_timestamp: expire_timestamp as u64,
+            expires_in_seconds: expires_in_seconds(expire_timestamp),
+            filename: file_info.filename,
+            content_type: file_info.content_type,
+        })?)
+        .build();
+    if let Some(checksum) = file_info.checksum {
+        res.insert_header(MESSAGE_SHA256_HEADER, checksum);
+    }
+    insert_expires_in_header(&mut res, expire_timestamp);
+    Ok(res)
+}
+
+// what is known about a message without reading it
+pub fn message_meta_response(data: &StaticData, message_token: &str) -> tide::Result {
+    check_token_signature(data, message_token)?;
+    let message_info = match data
+        .database
+        .lock()
+        .unwrap()
+        .get_message_info(message_token)?
+    {
+        Some(message_info) => message_info,
+        None => return Err(AppError::NotFound("Message not found".to_string()).into_error()),
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    if message_info.expire_timestamp != 0 && message_info.expire_timestamp <= now {
+        return Err(AppError::Gone("Message has expired".to_string()).into_error());
+    }
+
+    let size_bytes = message_info.data.len();
+
+    let mut res = Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&MessageMetaResponse {
+            size_bytes,
+            expire_timestamp: message_info.expire_timestamp as u64,
+            expires_in_seconds: expires_in_seconds(message_info.expire_timestamp),
+            passphrase_required: message_info.has_passphrase,
+            dual_control: message_info.is_dual_control,
+            totp_required: message_info.is_totp_protected,
+            pin_required: message_info.has_pin,
+            client_encrypted: message_info.is_client_encrypted,
+            filename: message_info.filename,
+            content_type: message_info.content_type,
+            remaining_views: message_info.remaining_views,
+            not_before: message_info.not_before.map(|not_before| not_before as u64),
+        })?)
+        .build();
+    insert_expires_in_header(&mut res, message_info.expire_timestamp);
+    Ok(res)
+}
+
+pub async fn message_meta(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let message_token = req.param("token")?;
+
+    let data = req.state().lock().unwrap();
+    message_meta_response(&data, message_token)
+}
+
+// the delete token is sent as a bearer token, like the admin token
+fn delete_token_of<State>(req: &Request<State>) -> &str {
+    req.header("Authorization")
+        .and_then(|values| values.last().as_str().strip_prefix("Bearer "))
+        .unwrap_or_default()
+}
+
+// the creator takes the message back, e.g. after pasting the link in the wrong place; a wrong
+// delete token is answered like an unknown message, so it can't be told apart by probing
+pub async fn delete_message(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let message_token = req.param("token")?;
+    let delete_token = delete_token_of(&req);
+
+    let data = req.state().lock().unwrap();
+    check_token_signature(&data, message_token)?;
+    if delete_token.is_empty()
+        || !data
+            .database
+            .lock()
+            .unwrap()
+            .delete_message(message_token, delete_token)?
+    {
+        return Err(AppError::NotFound("Message not found".to_string()).into_error());
+    }
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+// lets the creator see whether the message was read, with the same delete token; the status
+// of a message that is gone is kept for a while only
+pub async fn message_status(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let message_token = req.param("token")?;
+    let delete_token = delete_token_of(&req);
+
+    let data = req.state().lock().unwrap();
+    check_token_signature(&data, message_token)?;
+    let status = match data
+        .database
+        .lock()
+        .unwrap()
+        .get_message_status(message_token, delete_token)?
+    {
+        Some(status) if !delete_token.is_empty() => status,
+        _ => return Err(AppError::NotFound("Message not found".to_string()).into_error()),
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let is_expired = status.expire_timestamp != 0 && status.expire_timestamp <= now;
+    let state = match status.state {
+        // not cleared away yet
+        MessageState::Pending if is_expired => MessageState::Expired,
+        state => state,
+    };
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&MessageStatusResponse {
+            status: state,
+            created_timestamp: status.created_timestamp.map(|timestamp| timestamp as u64),
+            expire_timestamp: status.expire_timestamp as u64,
+            consumed_timestamp: status
+                .removed_timestamp
+                .filter(|_| state == MessageState::Consumed)
+                .map(|timestamp| timestamp as u64),
+            removed_timestamp: status.removed_timestamp.map(|timestamp| timestamp as u64),
+            receipt: status.receipt,
+        })?)
+        .build())
+}
+
+// a QR code of the link, so it can be opened on a phone without typing it; SVG unless
+// the client asks for PNG with `?format=png` or the Accept header
+pub async fn message_qr(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let message_token = req.param("token")?;
+    let format_param = req
+        .url()
+        .query_pairs()
+        .find(|(name, _)| name == "format")
+        .map(|(_, value)| value.to_ascii_lowercase());
+    let is_png = match format_param.as_deref() {
+        Some("png") => true,
+        Some("svg") => false,
+        Some(_) => {
+            return Err(AppError::Invalid {
+                field: "format",
+                message: "Format should be svg or png".to_string(),
+            }
+            .into_error())
+        }
+        None => req.header("Accept").is_some_and(|accept| {
+            let accept = accept.last().as_str();
+            accept_quality(accept, PNG_MEDIA_TYPE) > accept_quality(accept, SVG_MEDIA_TYPE)
+        }),
+    };
+
+    let data = req.state().lock().unwrap();
+    check_token_signature(&data, message_token)?;
+    // only what the link depends on, the payload isn't read for it
+    let (expire_timestamp, is_client_encrypted) = {
+        let database = data.database.lock().unwrap();
+        match database.get_message_expire_timestamp(message_token)? {
+            Some(expire_timestamp) => (
+                expire_timestamp,
+                database
+                    .is_message_client_encrypted(message_token)?
+                    .unwrap_or(false),
+            ),
+            None => return Err(AppError::NotFound("Message not found".to_string()).into_error()),
+        }
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    if expire_timestamp != 0 && expire_timestamp <= now {
+        return Err(AppError::Gone("Message has expired".to_string()).into_error());
+    }
+    if is_client_encrypted {
+        return Err(AppError::BadRequest(
+            "The link of an end-to-end encrypted message has a key the server doesn't know"
+                .to_string(),
+        )
+        .into_error());
+    }
+
+    let url = make_bare_share_url(&req, &data.config, message_token);
+    let qr = match QrCode::encode(url.as_bytes()) {
+        Some(qr) => qr,
+        None => return Err(AppError::BadRequest("Link is too long".to_string()).into_error()),
+    };
+    let res = if is_png {
+        Response::builder(StatusCode::Ok)
+            .body(qr.to_png(QR_PNG_SCALE))
+            .content_type(PNG_MEDIA_TYPE)
+    } else {
+        Response::builder(StatusCode::Ok)
+            .body(qr.to_svg())
+            .content_type(SVG_MEDIA_TYPE)
+    };
+    Ok(res.build())
+}
+
+pub async fn user_quota(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let quota_request: QuotaRequest = match req.body_json().await {
+        Ok(quota_request) => quota_request,
+        Err(_) => {
+            return Err(AppError::BadRequest("Can't parse request body".to_string()).into_error())
+        }
+    };
+
+    let data = req.state().lock().unwrap();
+    let user_limits = match get_user_limits(&data, &quota_request.user_token)? {
+        Some(user_limits) => user_limits,
+        None => return Err(AppError::NotFound("User not found".to_string()).into_error()),
+    };
+
+    let month = format_year_month(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+    let used_bytes = data
+        .database
+        .lock()
+        .unwrap()
+        .get_monthly_usage(&quota_request.user_token, &month)?;
+    let quota_bytes =
+        (user_limits.monthly_byte_quota > 0).then_some(user_limits.monthly_byte_quota);
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&QuotaResponse {
+            month,
+            used_bytes,
+            quota_bytes,
+            remaining_bytes: quota_bytes.map(|quota_bytes| quota_bytes.saturating_sub(used_bytes)),
+        })?)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::OneTimeShareDb;
+    use crate::encryption::MessageCipher;
+    use crate::store::{MessageOptions, UserLimits};
+    use crate::tests::setup_test_data;
+    use crate::tokens::TokenSigner;
+    use crate::totp::{code_at, decode_base32, STEP_SECONDS};
+    use crate::webhooks::tests::{make_sender, receive_webhook};
+    use tide::http::{Method, Request, Url};
+
+    #[async_std::test]
+    async fn test_create_message() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    retention_limit_minutes: 60,
+                    max_message_size_bytes: 1024,
+                    message_creation_limit_minutes: 5,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("https://localhost/api/v1/messages").unwrap(),
+        );
+        req.set_body(
+            Body::from_json(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                retention: Some(60),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(
+            body.url,
+            format!("https://localhost/shared/{}", body.message_token)
+        );
+        assert!(body.expire_timestamp > 0);
+        assert_eq!(
+            body.sha256,
+            "64ec88ca00b268e5ba1a35678a1b5316d212f4f366b2477232534a8aeca37f3c"
+        );
+
+        // the recipient gets the same checksum with the message
+        let req = Request::new(
+            Method::Post,
+            Url::parse(&format!(
+                "https://localhost/api/v1/messages/{}/consume",
+                body.message_token
+            ))
+            .unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res[MESSAGE_SHA256_HEADER], body.sha256.as_str());
+    }
+
+    #[async_std::test]
+    async fn test_monthly_quota() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    monthly_byte_quota: 15,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let create = |message_data: &str| {
+            let mut req = Request::new(
+                Method::Post,
+                Url::parse("https://localhost/api/v1/messages").unwrap(),
+            );
+            req.set_body(
+                Body::from_json(&MessageForm {
+                    user_token: "test_token".to_string(),
+                    message_data: message_data.to_string().into(),
+                    ..Default::default()
+                })
+                .unwrap(),
+            );
+            app.respond::<_, Response>(req)
+        };
+        let quota = || {
+            let mut req = Request::new(
+                Method::Post,
+                Url::parse("https://localhost/api/v1/quota").unwrap(),
+            );
+            req.set_body(
+                Body::from_json(&QuotaRequest {
+                    user_token: "test_token".to_string(),
+                })
+                .unwrap(),
+            );
+            app.respond::<_, Response>(req)
+        };
+
+        // "Hello world" is 11 bytes
+        assert_eq!(
+            create("SGVsbG8gd29ybGQ=").await.unwrap().status(),
+            StatusCode::Ok
+        );
+        let mut res = quota().await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: QuotaResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.used_bytes, 11);
+        assert_eq!(body.quota_bytes, Some(15));
+        assert_eq!(body.remaining_bytes, Some(4));
+
+        let mut res = create("SGVsbG8gd29ybGQ=").await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let body: ErrorResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(
+            body.error,
+            "Monthly quota exceeded, 4 byte(s) left this month"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_create_message_unknown_user() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages").unwrap(),
+        );
+        req.set_body(
+            Body::from_json(&MessageForm {
+                user_token: "unknown".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                retention: None,
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+        let request_id = res["X-Request-Id"].as_str().to_string();
+        let body: ErrorResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.error, "User not found");
+        assert_eq!(body.request_id, Some(request_id));
+    }
+
+    #[async_std::test]
+    async fn test_incoming_request_id_is_returned() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        let mut req = Request::new(
+            Method::Get,
+            Url::parse("http://localhost/api/v1/messages/unknown/meta").unwrap(),
+        );
+        req.insert_header("X-Request-Id", "client-request-1");
+
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+        assert_eq!(res["X-Request-Id"], "client-request-1");
+        let body: ErrorResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.request_id.as_deref(), Some("client-request-1"));
+    }
+
+    #[async_std::test]
+    async fn test_consume_message() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .save_message(
+                "message_token",
+                0,
+                b"Hello world",
+                &MessageOptions::default(),
+            )
+            .unwrap();
+
+        let url = Url::parse("http://localhost/api/v1/messages/message_token/consume").unwrap();
+
+        let req = Request::new(Method::Post, url.clone());
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(*body.message_data, "SGVsbG8gd29ybGQ=");
+        assert_eq!(body.expire_timestamp, 0);
+
+        let req = Request::new(Method::Post, url);
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn test_unsigned_token_is_refused_before_lookup() {
+        let app_data = setup_test_data();
+        let signer =
+            TokenSigner::from_base64_key("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+        let signed_token = signer.sign("signed_token");
+        app_data.lock().unwrap().token_signer = Some(Arc::new(signer));
+        let app = crate::init_app(app_data.clone());
+
+        for message_token in ["message_token", signed_token.as_str()] {
+            app_data
+                .lock()
+                .unwrap()
+                .database
+                .lock()
+                .unwrap()
+                .save_message(message_token, 0, b"Hello world", &MessageOptions::default())
+                .unwrap();
+        }
+
+        for (method, path) in [(Method::Get, "meta"), (Method::Post, "consume")] {
+            let req = Request::new(
+                method,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/message_token/{}",
+                    path
+                ))
+                .unwrap(),
+            );
+            let res: Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NotFound);
+        }
+        // the message of the unsigned token wasn't touched
+        assert!(app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .get_message_info("message_token")
+            .unwrap()
+            .is_some());
+
+        let req = Request::new(
+            Method::Post,
+            Url::parse(&format!(
+                "http://localhost/api/v1/messages/{}/consume",
+                signed_token
+            ))
+            .unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn test_forged_token_never_reaches_storage() {
+        let app_data = setup_test_data();
+        let signer =
+            TokenSigner::from_base64_key("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+        app_data.lock().unwrap().token_signer = Some(Arc::new(signer));
+        let app = crate::init_app(app_data.clone());
+
+        // any lookup in a poisoned store panics, so getting an answer means nothing was looked up
+        let database = app_data.lock().unwrap().database.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = database.lock().unwrap();
+            panic!("poison the store");
+        })
+        .join();
+
+        for path in ["messages", "files"] {
+            let req = Request::new(
+                Method::Post,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/{}/forged_token/consume",
+                    path
+                ))
+                .unwrap(),
+            );
+            let res: Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NotFound);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_consume_expired_message() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .save_message(
+                "message_token",
+                100,
+                b"Hello world",
+                &MessageOptions::default(),
+            )
+            .unwrap();
+
+        let req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages/message_token/consume").unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Gone);
+    }
+
+    #[async_std::test]
+    async fn test_consume_message_with_file_metadata() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        let options = MessageOptions {
+            filename: Some("hello.txt".to_string()),
+            content_type: Some("text/plain".to_string()),
+            ..Default::default()
+        };
+        for message_token in ["json_token", "raw_token"] {
+            app_data
+                .lock()
+                .unwrap()
+                .database
+                .lock()
+                .unwrap()
+                .save_message(message_token, 0, b"Hello world", &options)
+                .unwrap();
+        }
+
+        let req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages/json_token/consume").unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.filename.as_deref(), Some("hello.txt"));
+        assert_eq!(body.content_type.as_deref(), Some("text/plain"));
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages/raw_token/consume").unwrap(),
+        );
+        req.insert_header("Accept", "application/octet-stream, application/json;q=0.5");
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res["Content-Type"], "text/plain");
+        assert_eq!(
+            res["Content-Disposition"],
+            "attachment; filename=\"hello.txt\""
+        );
+        assert_eq!(res.take_body().into_string().await.unwrap(), "Hello world");
+    }
+
+    #[async_std::test]
+    async fn test_remaining_lifetime() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        {
+            let data = app_data.lock().unwrap();
+            let database = data.database.lock().unwrap();
+            database
+                .save_message(
+                    "expiring",
+                    now + 600,
+                    b"Hello world",
+                    &MessageOptions::default(),
+                )
+                .unwrap();
+            database
+                .save_message("forever", 0, b"Hello world", &MessageOptions::default())
+                .unwrap();
+        }
+        let expires_in =
+            |res: &Response| -> u64 { res[MESSAGE_EXPIRES_IN_HEADER].as_str().parse().unwrap() };
+
+        let mut res: Response = app
+            .respond(Request::new(
+                Method::Get,
+                Url::parse("http://localhost/api/v1/messages/expiring/meta").unwrap(),
+            ))
+            .await
+            .unwrap();
+        assert!((590..=600).contains(&expires_in(&res)));
+        let body: MessageMetaResponse = res.take_body().into_json().await.unwrap();
+        assert!((590..=600).contains(&body.expires_in_seconds.unwrap()));
+
+        let mut res: Response = app
+            .respond(Request::new(
+                Method::Post,
+                Url::parse("http://localhost/api/v1/messages/expiring/consume").unwrap(),
+            ))
+            .await
+            .unwrap();
+        assert!((590..=600).contains(&expires_in(&res)));
+        let body: ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+        assert!((590..=600).contains(&body.expires_in_seconds.unwrap()));
+
+        // nothing to count down
+        let mut res: Response = app
+            .respond(Request::new(
+                Method::Post,
+                Url::parse("http://localhost/api/v1/messages/forever/consume").unwrap(),
+            ))
+            .await
+            .unwrap();
+        assert!(res.header(MESSAGE_EXPIRES_IN_HEADER).is_none());
+        let body: ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+        assert!(body.expires_in_seconds.is_none());
+    }
+
+    #[async_std::test]
+    async fn test_message_meta_does_not_consume() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .save_message(
+                "message_token",
+                0,
+                b"Hello world",
+                &MessageOptions::default(),
+            )
+            .unwrap();
+
+        let req = Request::new(
+            Method::Get,
+            Url::parse("http://localhost/api/v1/messages/message_token/meta").unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: MessageMetaResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.size_bytes, 11);
+        assert_eq!(body.expire_timestamp, 0);
+        assert!(!body.passphrase_required);
+
+        let req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages/message_token/consume").unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn test_message_meta_not_found() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        let req = Request::new(
+            Method::Get,
+            Url::parse("http://localhost/api/v1/messages/unknown/meta").unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn test_delete_message() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    retention_limit_minutes: 60,
+                    max_message_size_bytes: 1024,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("https://localhost/api/v1/messages").unwrap(),
+        );
+        req.set_body(
+            Body::from_json(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                retention: Some(60),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_ne!(created.delete_token, created.message_token);
+        let url = format!(
+            "https://localhost/api/v1/messages/{}",
+            created.message_token
+        );
+
+        for authorization in [None, Some("Bearer wrong"), Some("Bearer ")] {
+            let mut req = Request::new(Method::Delete, Url::parse(&url).unwrap());
+            if let Some(authorization) = authorization {
+                req.insert_header("Authorization", authorization);
+            }
+            let res: Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NotFound);
+        }
+
+        let mut req = Request::new(Method::Delete, Url::parse(&url).unwrap());
+        req.insert_header("Authorization", format!("Bearer {}", created.delete_token));
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NoContent);
+
+        let req = Request::new(
+            Method::Post,
+            Url::parse(&format!("{}/consume", url)).unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn test_message_status() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .save_message(
+                "message_token",
+                0,
+                b"Hello world",
+                &MessageOptions {
+                    delete_token: Some("delete_token".to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let get_status = |delete_token: &str| {
+            let mut req = Request::new(
+                Method::Get,
+                Url::parse("http://localhost/api/v1/messages/message_token/status").unwrap(),
+            );
+            req.insert_header("Authorization", format!("Bearer {}", delete_token));
+            app.respond(req)
+        };
+
+        let res: Response = get_status("wrong").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let mut res: Response = get_status("delete_token").await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let status: MessageStatusResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(status.status, MessageState::Pending);
+        assert!(status.created_timestamp.is_some());
+        assert!(status.consumed_timestamp.is_none());
+
+        let req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages/message_token/consume").unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let mut res: Response = get_status("delete_token").await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let status: MessageStatusResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(status.status, MessageState::Consumed);
+        assert!(status.consumed_timestamp.is_some());
+        assert_eq!(status.consumed_timestamp, status.removed_timestamp);
+    }
+
+    #[async_std::test]
+    async fn test_message_with_several_views() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits("test_token", &UserLimits::default())
+            .unwrap();
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages").unwrap(),
+        );
+        req.set_body(
+            Body::from_json(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                max_views: Some(2),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+
+        let meta = || {
+            app.respond(Request::new(
+                Method::Get,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/{}/meta",
+                    created.message_token
+                ))
+                .unwrap(),
+            ))
+        };
+        let consume = || {
+            app.respond(Request::new(
+                Method::Post,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/{}/consume",
+                    created.message_token
+                ))
+                .unwrap(),
+            ))
+        };
+        let mut res: Response = meta().await.unwrap();
+        let body: MessageMetaResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.remaining_views, 2);
+
+        let res: Response = consume().await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let mut res: Response = meta().await.unwrap();
+        let body: MessageMetaResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.remaining_views, 1);
+
+        let mut res: Response = consume().await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(*body.message_data, "SGVsbG8gd29ybGQ=");
+        let res: Response = consume().await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn test_message_not_before() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        {
+            let data = app_data.lock().unwrap();
+            let database = data.database.lock().unwrap();
+            database
+                .set_user_limits("test_token", &UserLimits::default())
+                .unwrap();
+            for (message_token, not_before) in [("locked", now + 3600), ("unlocked", now - 10)] {
+                database
+                    .save_message(
+                        message_token,
+                        0,
+                        b"Hello world",
+                        &MessageOptions {
+                            not_before: Some(not_before),
+                            ..Default::default()
+                        },
+                    )
+                    .unwrap();
+            }
+        }
+        let consume = |message_token: &str| {
+            app.respond(Request::new(
+                Method::Post,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/{}/consume",
+                    message_token
+                ))
+                .unwrap(),
+            ))
+        };
+
+        let mut res: Response = app
+            .respond(Request::new(
+                Method::Get,
+                Url::parse("http://localhost/api/v1/messages/locked/meta").unwrap(),
+            ))
+            .await
+            .unwrap();
+        let body: MessageMetaResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.not_before, Some((now + 3600) as u64));
+
+        // the locked message stays in place
+        for _ in 0..2 {
+            let mut res: Response = consume("locked").await.unwrap();
+            assert_eq!(res.status(), StatusCode::TooEarly);
+            assert!(res["Retry-After"].as_str().parse::<u64>().unwrap() > 3500);
+            let body: ErrorResponse = res.take_body().into_json().await.unwrap();
+            assert_eq!(body.not_before, Some((now + 3600) as u64));
+        }
+        let res: Response = consume("unlocked").await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        // it would expire before it's unlocked
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages").unwrap(),
+        );
+        req.set_body(
+            Body::from_json(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                retention: Some(60),
+                not_before: Some((now + 2 * 3600) as u64),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let body: ErrorResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.field.as_deref(), Some("not_before"));
+    }
+
+    #[async_std::test]
+    async fn test_delayed_activation() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits("test_token", &UserLimits::default())
+            .unwrap();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages").unwrap(),
+        );
+        req.set_body(
+            Body::from_json(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                retention: Some(30),
+                activate_in_minutes: Some(60),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+        let not_before = created.not_before.unwrap();
+        assert!((now + 3600..now + 3610).contains(&not_before));
+        // the retention starts when the message activates
+        assert_eq!(created.expire_timestamp, not_before + 30 * 60);
+
+        let res: Response = app
+            .respond(Request::new(
+                Method::Post,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/{}/consume",
+                    created.message_token
+                ))
+                .unwrap(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TooEarly);
+    }
+
+    #[async_std::test]
+    async fn test_dual_control_message() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits("test_token", &UserLimits::default())
+            .unwrap();
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages").unwrap(),
+        );
+        req.set_body(
+            Body::from_json(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                dual_control: Some(true),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+        let [first, second] = <[String; 2]>::try_from(created.authorization_tokens).unwrap();
+        assert_ne!(first, second);
+
+        let mut res: Response = app
+            .respond(Request::new(
+                Method::Get,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/{}/meta",
+                    created.message_token
+                ))
+                .unwrap(),
+            ))
+            .await
+            .unwrap();
+        let body: MessageMetaResponse = res.take_body().into_json().await.unwrap();
+        assert!(body.dual_control);
+
+        let consume = |authorization_tokens: &[&String]| {
+            let mut req = Request::new(
+                Method::Post,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/{}/consume",
+                    created.message_token
+                ))
+                .unwrap(),
+            );
+            req.set_body(
+                Body::from_json(&ConsumeMessageRequest {
+                    authorization_tokens: authorization_tokens
+                        .iter()
+                        .map(|token| token.to_string().into())
+                        .collect(),
+                    ..Default::default()
+                })
+                .unwrap(),
+            );
+            app.respond(req)
+        };
+        // one person with one token, however often it's sent, doesn't read it
+        for authorization_tokens in [vec![], vec![&first], vec![&second, &second]] {
+            let res: Response = consume(&authorization_tokens).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+        }
+        let mut res: Response = consume(&[&second, &first]).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(*body.message_data, "SGVsbG8gd29ybGQ=");
+    }
+
+    #[async_std::test]
+    async fn test_totp_protected_message() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits("test_token", &UserLimits::default())
+            .unwrap();
+
+        let create = |form: MessageForm| {
+            let mut req = Request::new(
+                Method::Post,
+                Url::parse("http://localhost/api/v1/messages").unwrap(),
+            );
+            req.set_body(Body::from_json(&form).unwrap());
+            app.respond(req)
+        };
+        let consume = |message_token: &str, totp_code: Option<String>| {
+            let mut req = Request::new(
+                Method::Post,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/{}/consume",
+                    message_token
+                ))
+                .unwrap(),
+            );
+            req.set_body(
+                Body::from_json(&ConsumeMessageRequest {
+                    totp_code,
+                    ..Default::default()
+                })
+                .unwrap(),
+            );
+            app.respond(req)
+        };
+        let current_code = |secret: &str, offset: u32| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let code = code_at(&decode_base32(secret).unwrap(), now / STEP_SECONDS);
+            format!("{:06}", (code + offset) % 1_000_000)
+        };
+
+        // a new secret comes back for the recipient to add to their authenticator
+        let mut res: Response = create(MessageForm {
+            user_token: "test_token".to_string(),
+            message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+            totp: Some(true),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+        let totp_secret = created.totp_secret.unwrap();
+        assert!(created.totp_uri.unwrap().starts_with("otpauth://totp/"));
+
+        let mut res: Response = app
+            .respond(Request::new(
+                Method::Get,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/{}/meta",
+                    created.message_token
+                ))
+                .unwrap(),
+            ))
+            .await
+            .unwrap();
+        let body: MessageMetaResponse = res.take_body().into_json().await.unwrap();
+        assert!(body.totp_required);
+
+        let res: Response = consume(&created.message_token, None).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        // half a million away from the current code, one in a million that it's a neighbour's
+        let res: Response = consume(
+            &created.message_token,
+            Some(current_code(&totp_secret, 500_000)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        let mut res: Response =
+            consume(&created.message_token, Some(current_code(&totp_secret, 0)))
+                .await
+                .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(*body.message_data, "SGVsbG8gd29ybGQ=");
+
+        // the secret of an existing authenticator isn't sent back, and a code isn't taken twice
+        let mut res: Response = create(MessageForm {
+            user_token: "test_token".to_string(),
+            message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+            totp_secret: Some("JBSWY3DPEHPK3PXP".to_string()).into(),
+            max_views: Some(2),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+        assert!(created.totp_secret.is_none());
+        let code = current_code("JBSWY3DPEHPK3PXP", 0);
+        let res: Response = consume(&created.message_token, Some(code.clone()))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let res: Response = consume(&created.message_token, Some(code)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+    }
+
+    #[async_std::test]
+    async fn test_pin_protected_message() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits("test_token", &UserLimits::default())
+            .unwrap();
+
+        let create = || {
+            let mut req = Request::new(
+                Method::Post,
+                Url::parse("http://localhost/api/v1/messages").unwrap(),
+            );
+            req.set_body(
+                Body::from_json(&MessageForm {
+                    user_token: "test_token".to_string(),
+                    message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                    pin: Some("4821".to_string()).into(),
+                    ..Default::default()
+                })
+                .unwrap(),
+            );
+            app.respond(req)
+        };
+        let consume = |message_token: &str, pin: Option<&str>| {
+            let mut req = Request::new(
+                Method::Post,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/{}/consume",
+                    message_token
+                ))
+                .unwrap(),
+            );
+            req.set_body(
+                Body::from_json(&ConsumeMessageRequest {
+                    pin: pin.map(str::to_string).into(),
+                    ..Default::default()
+                })
+                .unwrap(),
+            );
+            app.respond(req)
+        };
+
+        let mut res: Response = create().await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+        let mut res: Response = app
+            .respond(Request::new(
+                Method::Get,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/{}/meta",
+                    created.message_token
+                ))
+                .unwrap(),
+            ))
+            .await
+            .unwrap();
+        let body: MessageMetaResponse = res.take_body().into_json().await.unwrap();
+        assert!(body.pin_required);
+
+        // a missing PIN isn't a guess, two wrong ones leave the last attempt
+        for pin in [None, Some("0000"), Some("1234")] {
+            let res: Response = consume(&created.message_token, pin).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+        }
+        let mut res: Response = consume(&created.message_token, Some("4821")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(*body.message_data, "SGVsbG8gd29ybGQ=");
+
+        let mut res: Response = create().await.unwrap();
+        let created: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+        for _ in 1..MAX_PIN_ATTEMPTS {
+            let res: Response = consume(&created.message_token, Some("0000")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+        }
+        let res: Response = consume(&created.message_token, Some("0000")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Gone);
+        let res: Response = consume(&created.message_token, Some("4821")).await.unwrap();
+        assert_ne!(res.status(), StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn test_message_allowed_ips() {
+        let app_data = setup_test_data();
+        app_data.lock().unwrap().config.trusted_proxies = vec!["127.0.0.1".to_string()];
+        let app = crate::init_app(app_data.clone());
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits("test_token", &UserLimits::default())
+            .unwrap();
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages").unwrap(),
+        );
+        req.set_body(
+            Body::from_json(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                allowed_ips: Some(vec!["10.8.0.0/16".to_string()]),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+
+        let consume = |peer_addr: Option<&str>, forwarded_for: Option<&str>| {
+            let mut req = Request::new(
+                Method::Post,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/{}/consume",
+                    created.message_token
+                ))
+                .unwrap(),
+            );
+            req.set_peer_addr(peer_addr);
+            if let Some(forwarded_for) = forwarded_for {
+                req.insert_header("X-Forwarded-For", forwarded_for);
+            }
+            app.respond(req)
+        };
+        // the message is still there for the address it's meant for
+        for peer_addr in [Some("192.0.2.1:1000"), Some("10.9.0.1:1000"), None] {
+            let res: Response = consume(peer_addr, None).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Forbidden);
+        }
+        // an address the client put in front of the proxy's own entry doesn't count, nor does
+        // a header from a peer that isn't a trusted proxy
+        for (peer_addr, forwarded_for) in [
+            ("127.0.0.1:1000", "10.8.0.1, 192.0.2.1"),
+            ("127.0.0.1:1000", "10.8.0.1,192.0.2.1, 127.0.0.1"),
+            ("192.0.2.1:1000", "10.8.0.1"),
+        ] {
+            let res: Response = consume(Some(peer_addr), Some(forwarded_for)).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Forbidden);
+        }
+        let mut res: Response = consume(Some("127.0.0.1:1000"), Some("192.0.2.1, 10.8.3.4"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(*body.message_data, "SGVsbG8gd29ybGQ=");
+    }
+
+    #[async_std::test]
+    async fn test_message_for_recipients() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    monthly_byte_quota: 1024,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages").unwrap(),
+        );
+        req.set_body(
+            Body::from_json(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                recipients: Some(vec!["alice".to_string(), "bob".to_string()]),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+        let names: Vec<&str> = created
+            .recipients
+            .iter()
+            .map(|recipient| recipient.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["alice", "bob"]);
+        assert_eq!(created.message_token, created.recipients[0].message_token);
+        assert_ne!(
+            created.recipients[0].message_token,
+            created.recipients[1].message_token
+        );
+        assert_eq!(
+            created.recipients[1].url,
+            format!(
+                "http://localhost/shared/{}",
+                created.recipients[1].message_token
+            )
+        );
+
+        let consume = |message_token: &str| {
+            app.respond(Request::new(
+                Method::Post,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/{}/consume",
+                    message_token
+                ))
+                .unwrap(),
+            ))
+        };
+        let get_status = |message_token: &str| {
+            let mut req = Request::new(
+                Method::Get,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/{}/status",
+                    message_token
+                ))
+                .unwrap(),
+            );
+            req.insert_header("Authorization", format!("Bearer {}", created.delete_token));
+            app.respond(req)
+        };
+
+        // each link is one-time on its own
+        let res: Response = consume(&created.recipients[1].message_token).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let res: Response = consume(&created.recipients[1].message_token).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        // the creator sees who read it with the one delete token
+        let mut states = Vec::new();
+        for recipient in &created.recipients {
+            let mut res: Response = get_status(&recipient.message_token).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Ok);
+            let status: MessageStatusResponse = res.take_body().into_json().await.unwrap();
+            states.push(status.status);
+        }
+        assert_eq!(states, vec![MessageState::Pending, MessageState::Consumed]);
+
+        let res: Response = consume(&created.recipients[0].message_token).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        // both copies count against the quota
+        let database = app_data.lock().unwrap().database.clone();
+        let month = format_year_month(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        );
+        assert_eq!(
+            database
+                .lock()
+                .unwrap()
+                .get_monthly_usage("test_token", &month)
+                .unwrap(),
+            22
+        );
+    }
+
+    #[async_std::test]
+    async fn test_read_receipt() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        {
+            let mut data = app_data.lock().unwrap();
+            data.config.read_receipts.enabled = true;
+            data.config.read_receipts.record_client = true;
+            for message_token in ["with_receipt", "without_delete_token"] {
+                data.database
+                    .lock()
+                    .unwrap()
+                    .save_message(
+                        message_token,
+                        0,
+                        b"Hello world",
+                        &MessageOptions {
+                            delete_token: (message_token == "with_receipt")
+                                .then(|| "delete_token".to_string()),
+                            ..Default::default()
+                        },
+                    )
+                    .unwrap();
+            }
+        }
+
+        for message_token in ["with_receipt", "without_delete_token"] {
+            let mut req = Request::new(
+                Method::Post,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/{}/consume",
+                    message_token
+                ))
+                .unwrap(),
+            );
+            req.set_peer_addr(Some("203.0.113.7:4000"));
+            req.insert_header("User-Agent", "Mozilla/5.0 ".repeat(10));
+            let res: Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Ok);
+        }
+
+        let mut req = Request::new(
+            Method::Get,
+            Url::parse("http://localhost/api/v1/messages/with_receipt/status").unwrap(),
+        );
+        req.insert_header("Authorization", "Bearer delete_token");
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let status: MessageStatusResponse = res.take_body().into_json().await.unwrap();
+        let receipt = status.receipt.unwrap();
+        assert!(receipt.read_timestamp > 0);
+        assert_eq!(receipt.client_ip.as_deref(), Some("203.0.113.0"));
+        assert_eq!(receipt.user_agent.unwrap().chars().count(), 64);
+    }
+
+    #[async_std::test]
+    async fn test_read_receipt_in_privacy_mode() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        {
+            let mut data = app_data.lock().unwrap();
+            data.config.privacy_mode = true;
+            data.config.read_receipts.enabled = true;
+            data.config.read_receipts.record_client = true;
+            data.database
+                .lock()
+                .unwrap()
+                .save_message(
+                    "with_receipt",
+                    0,
+                    b"Hello world",
+                    &MessageOptions {
+                        delete_token: Some("delete_token".to_string()),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages/with_receipt/consume").unwrap(),
+        );
+        req.set_peer_addr(Some("203.0.113.7:4000"));
+        req.insert_header("User-Agent", "Mozilla/5.0");
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let mut req = Request::new(
+            Method::Get,
+            Url::parse("http://localhost/api/v1/messages/with_receipt/status").unwrap(),
+        );
+        req.insert_header("Authorization", "Bearer delete_token");
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let status: MessageStatusResponse = res.take_body().into_json().await.unwrap();
+        let receipt = status.receipt.unwrap();
+        assert!(receipt.read_timestamp > 0);
+        assert!(receipt.client_ip.is_none());
+        assert!(receipt.user_agent.is_none());
+    }
+
+    #[async_std::test]
+    async fn test_webhook_on_consumption() {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let webhook_url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        {
+            let mut data = app_data.lock().unwrap();
+            data.webhooks = Some(make_sender(1));
+            data.database
+                .lock()
+                .unwrap()
+                .save_message(
+                    "with_webhook",
+                    0,
+                    b"Hello world",
+                    &MessageOptions {
+                        webhook_url: Some(webhook_url),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+
+        let req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages/with_webhook/consume").unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let (database, webhooks) = {
+            let data = app_data.lock().unwrap();
+            (data.database.clone(), data.webhooks.clone().unwrap())
+        };
+        let (received, result) = futures_lite::future::zip(
+            receive_webhook(&listener, StatusCode::Ok),
+            webhooks.deliver_due_webhooks(&database),
+        )
+        .await;
+        result.unwrap();
+        let event: WebhookEvent = serde_json::from_slice(&received.body).unwrap();
+        assert_eq!(event.event, "message.consumed");
+        assert_eq!(event.token.as_deref(), Some("with_webhook"));
+        assert_eq!(event.status, MessageState::Consumed);
+        assert!(event.consumed_at.unwrap() > 0);
+    }
+
+    #[async_std::test]
+    async fn test_webhook_to_internal_address_is_refused() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        {
+            let mut data = app_data.lock().unwrap();
+            data.webhooks = Some(make_sender(1));
+            data.database
+                .lock()
+                .unwrap()
+                .set_user_limits("test_token", &UserLimits::default())
+                .unwrap();
+        }
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages").unwrap(),
+        );
+        req.set_body(
+            Body::from_json(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                webhook_url: Some("http://10.0.0.1:8080/hook".to_string()),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let body: ErrorResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.field.as_deref(), Some("webhook_url"));
+    }
+
+    #[async_std::test]
+    async fn test_email_on_consumption() {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        {
+            let mut data = app_data.lock().unwrap();
+            data.email = Some(crate::email::tests::make_sender(
+                listener.local_addr().unwrap().port(),
+            ));
+            let database = data.database.lock().unwrap();
+            database
+                .set_user_email("user1", Some("alice@example.com"))
+                .unwrap();
+            database
+                .save_message(
+                    "with_email",
+                    0,
+                    b"Hello world",
+                    &MessageOptions {
+                        user_token: Some("user1".to_string()),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+
+        let req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages/with_email/consume").unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let received = crate::email::tests::receive_email(&listener).await;
+        assert!(received
+            .commands
+            .contains(&"RCPT TO:<alice@example.com>".to_string()));
+        assert!(received.data.contains("Subject: Your message was read\r\n"));
+        assert!(received.data.contains(&hash_token("with_email")[..16]));
+    }
+
+    #[async_std::test]
+    async fn test_text_share_url() {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits("test_token", &UserLimits::default())
+            .unwrap();
+        let create = |end_to_end| {
+            let mut req = Request::new(
+                Method::Post,
+                Url::parse("https://localhost/api/v1/messages").unwrap(),
+            );
+            req.set_body(
+                Body::from_json(&MessageForm {
+                    user_token: "test_token".to_string(),
+                    message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                    end_to_end: Some(end_to_end),
+                    sms_to: Some("+15551234567".to_string()),
+                    ..Default::default()
+                })
+                .unwrap(),
+            );
+            app.respond(req)
+        };
+
+        let res: Response = create(false).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+
+        app_data.lock().unwrap().sms = Some(crate::sms::tests::make_gateway_sender(&format!(
+            "http://{}/sms",
+            listener.local_addr().unwrap()
+        )));
+        let res: Response = create(true).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+
+        let mut res: Response = create(false).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+        let received = crate::sms::tests::receive_text(&listener, StatusCode::Ok).await;
+        let text: serde_json::Value = serde_json::from_str(&received.body).unwrap();
+        assert_eq!(text["to"], "+15551234567");
+        assert!(text["text"].as_str().unwrap().ends_with(&body.url));
+    }
+
+    #[async_std::test]
+    async fn test_message_qr_code_does_not_read_the_message() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+        let mut database = OneTimeShareDb::connect(path).unwrap();
+        database.set_cipher(
+            MessageCipher::from_base64_key("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap(),
+        );
+        database
+            .save_message("token1", 0, b"Hello", &MessageOptions::default())
+            .unwrap();
+        // without the key the payload can't be read, the link is still known
+        let database = OneTimeShareDb::connect(path).unwrap();
+        assert!(database.get_message_info("token1").is_err());
+        app_data.lock().unwrap().database = Arc::new(Mutex::new(database));
+
+        let req = Request::new(
+            Method::Get,
+            Url::parse("http://localhost/api/v1/messages/token1/qr").unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res["Content-Type"], SVG_MEDIA_TYPE);
+    }
+
+    #[async_std::test]
+    async fn test_message_qr_code() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        let req = Request::new(
+            Method::Get,
+            Url::parse("http://localhost/api/v1/messages/unknown/qr").unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    retention_limit_minutes: 60,
+                    max_message_size_bytes: 1024,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("https://localhost/api/v1/messages").unwrap(),
+        );
+        req.set_body(
+            Body::from_json(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                retention: Some(60),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+        let qr_url = created.qr_url.unwrap();
+        assert_eq!(
+            qr_url,
+            format!(
+                "https://localhost/api/v1/messages/{}/qr",
+                created.message_token
+            )
+        );
+
+        let req = Request::new(Method::Get, Url::parse(&qr_url).unwrap());
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res["Content-Type"], SVG_MEDIA_TYPE);
+        assert!(res
+            .take_body()
+            .into_string()
+            .await
+            .unwrap()
+            .starts_with("<svg"));
+
+        let mut req = Request::new(Method::Get, Url::parse(&qr_url).unwrap());
+        req.insert_header("Accept", "image/png");
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res["Content-Type"], PNG_MEDIA_TYPE);
+        assert!(res
+            .take_body()
+            .into_bytes()
+            .await
+            .unwrap()
+            .starts_with(b"\x89PNG"));
+
+        let req = Request::new(
+            Method::Get,
+            Url::parse(&format!("{}?format=gif", qr_url)).unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+
+        // reading the code doesn't read the message
+        let req = Request::new(
+            Method::Post,
+            Url::parse(&format!(
+                "https://localhost/api/v1/messages/{}/consume",
+                created.message_token
+            ))
+            .unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn test_consume_message_with_passphrase() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    retention_limit_minutes: 60,
+                    max_message_size_bytes: 1024,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages").unwrap(),
+        );
+        req.set_body(
+            Body::from_json(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                retention: Some(60),
+                passphrase: Some("secret".to_string()).into(),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+
+        let meta_url = format!(
+            "http://localhost/api/v1/messages/{}/meta",
+            created.message_token
+        );
+        let req = Request::new(Method::Get, Url::parse(&meta_url).unwrap());
+        let mut res: Response = app.respond(req).await.unwrap();
+        let meta: MessageMetaResponse = res.take_body().into_json().await.unwrap();
+        assert!(meta.passphrase_required);
+
+        let consume_url = Url::parse(&format!(
+            "http://localhost/api/v1/messages/{}/consume",
+            created.message_token
+        ))
+        .unwrap();
+
+        let req = Request::new(Method::Post, consume_url.clone());
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let mut req = Request::new(Method::Post, consume_url.clone());
+        req.set_body(
+            Body::from_json(&ConsumeMessageRequest {
+                passphrase: Some("wrong".to_string()).into(),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let mut req = Request::new(Method::Post, consume_url);
+        req.set_body(
+            Body::from_json(&ConsumeMessageRequest {
+                passphrase: Some("secret".to_string()).into(),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(*body.message_data, "SGVsbG8gd29ybGQ=");
+    }
+
+    #[async_std::test]
+    async fn test_consume_message_passphrase_attempt_limit() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .save_message(
+                "message_token",
+                0,
+                b"Hello world",
+                &MessageOptions {
+                    passphrase_hash: Some(crate::passphrase::hash_passphrase("secret").unwrap()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let consume_url =
+            Url::parse("http://localhost/api/v1/messages/message_token/consume").unwrap();
+        let mut statuses = Vec::new();
+        for _ in 0..3 {
+            let mut req = Request::new(Method::Post, consume_url.clone());
+            req.set_body(
+                Body::from_json(&ConsumeMessageRequest {
+                    passphrase: Some("wrong".to_string()).into(),
+                    ..Default::default()
+                })
+                .unwrap(),
+            );
+            let res: Response = app.respond(req).await.unwrap();
+            statuses.push(res.status());
+        }
+        assert_eq!(
+            statuses,
+            vec![
+                StatusCode::Unauthorized,
+                StatusCode::Unauthorized,
+                StatusCode::Gone
+            ]
+        );
+
+        let mut req = Request::new(Method::Post, consume_url);
+        req.set_body(
+            Body::from_json(&ConsumeMessageRequest {
+                passphrase: Some("secret".to_string()).into(),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn test_create_end_to_end_message() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    retention_limit_minutes: 60,
+                    max_message_size_bytes: 1024,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("https://localhost/api/v1/messages").unwrap(),
+        );
+        req.set_body(
+            Body::from_json(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                retention: Some(60),
+                end_to_end: Some(true),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(
+            created.url,
+            format!("https://localhost/shared/{}#{{key}}", created.message_token)
+        );
+
+        let req = Request::new(
+            Method::Get,
+            Url::parse(&format!(
+                "http://localhost/api/v1/messages/{}/meta",
+                created.message_token
+            ))
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        let meta: MessageMetaResponse = res.take_body().into_json().await.unwrap();
+        assert!(meta.client_encrypted);
+    }
+}
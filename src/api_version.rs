@@ -0,0 +1,144 @@
+use tide::{Middleware, Next, Request};
+
+use crate::error::AppError;
+
+pub const API_VERSION_HEADER: &str = "Api-Version";
+// a breaking change adds a version here and routes under /api/v<version>/,
+// the old version is kept until its users moved on
+pub const SUPPORTED_API_VERSIONS: [u32; 1] = [1];
+// what the routes outside of /api/ behave like
+pub const LEGACY_API_VERSION: u32 = 1;
+
+fn supported_versions_text() -> String {
+    SUPPORTED_API_VERSIONS
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// "/api/v1/messages" gives Some(Ok(1)), a path outside of the API gives None
+fn path_version(path: &str) -> Option<Result<u32, ()>> {
+    let mut segments = path.trim_start_matches('/').split('/');
+    if segments.next() != Some("api") {
+        return None;
+    }
+    Some(
+        segments
+            .next()
+            .and_then(|segment| segment.strip_prefix('v'))
+            .and_then(|version| version.parse().ok())
+            .ok_or(()),
+    )
+}
+
+fn unsupported(version: &str) -> AppError {
+    AppError::NotFound(format!(
+        "API version {} is not supported, the supported versions are: {}",
+        version,
+        supported_versions_text()
+    ))
+}
+
+// the version is in the path, a client can also pin it with the Api-Version header, so it
+// finds out right away when it talks to a server that doesn't have that version any more
+fn resolve_version(path: &str, requested: Option<&str>) -> Result<Option<u32>, AppError> {
+    let requested = match requested {
+        Some(requested) => match requested.trim().trim_start_matches('v').parse::<u32>() {
+            Ok(version) if SUPPORTED_API_VERSIONS.contains(&version) => Some(version),
+            _ => return Err(unsupported(requested)),
+        },
+        None => None,
+    };
+    let version = match path_version(path) {
+        Some(Ok(version)) if SUPPORTED_API_VERSIONS.contains(&version) => version,
+        Some(Ok(version)) => return Err(unsupported(&version.to_string())),
+        // not a versioned route, the router answers
+        Some(Err(())) => return Ok(None),
+        None => match requested {
+            Some(_) => LEGACY_API_VERSION,
+            None => return Ok(None),
+        },
+    };
+    match requested {
+        Some(requested) if requested != version => Err(AppError::BadRequest(format!(
+            "{} header asks for version {}, but the path is of version {}",
+            API_VERSION_HEADER, requested, version
+        ))),
+        _ => Ok(Some(version)),
+    }
+}
+
+// tells every API response which version answered it
+pub struct ApiVersionMiddleware;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ApiVersionMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let requested = req
+            .header(API_VERSION_HEADER)
+            .map(|values| values.last().as_str().to_string());
+        let version = resolve_version(req.url().path(), requested.as_deref())
+            .map_err(AppError::into_error)?;
+
+        let mut res = next.run(req).await;
+        if let Some(version) = version {
+            res.insert_header(API_VERSION_HEADER, version.to_string());
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorResponseMiddleware;
+    use tide::http::{Method, Url};
+    use tide::StatusCode;
+
+    #[test]
+    fn test_resolve_version() {
+        assert_eq!(resolve_version("/api/v1/messages", None).unwrap(), Some(1));
+        assert_eq!(
+            resolve_version("/api/v1/messages", Some("1")).unwrap(),
+            Some(1)
+        );
+        assert_eq!(resolve_version("/save", None).unwrap(), None);
+        assert_eq!(resolve_version("/save", Some("v1")).unwrap(), Some(1));
+        assert_eq!(resolve_version("/api/docs", None).unwrap(), None);
+
+        let err = resolve_version("/api/v2/messages", None).unwrap_err();
+        assert_eq!(err.status(), StatusCode::NotFound);
+        assert_eq!(
+            err.message(),
+            "API version 2 is not supported, the supported versions are: 1"
+        );
+        assert_eq!(
+            resolve_version("/save", Some("3")).unwrap_err().status(),
+            StatusCode::NotFound
+        );
+    }
+
+    #[async_std::test]
+    async fn test_middleware_sets_version_header() {
+        let mut app = tide::new();
+        app.with(ErrorResponseMiddleware);
+        app.with(ApiVersionMiddleware);
+        app.at("/api/v1/messages").post(|_| async { Ok("created") });
+
+        let req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages").unwrap(),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res[API_VERSION_HEADER], "1");
+
+        let req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v2/messages").unwrap(),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+}
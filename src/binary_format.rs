@@ -0,0 +1,404 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::Value;
+use tide::{Middleware, Next, Request};
+
+// the fields that carry base64 in JSON, the binary formats send their bytes as they are
+const BYTES_FIELDS: [&str; 1] = ["message_data"];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BinaryFormat {
+    Cbor,
+    MessagePack,
+}
+
+impl BinaryFormat {
+    pub fn media_type(self) -> &'static str {
+        match self {
+            BinaryFormat::Cbor => "application/cbor",
+            BinaryFormat::MessagePack => "application/msgpack",
+        }
+    }
+
+    fn of(media_type: &str) -> Option<Self> {
+        match media_type {
+            "application/cbor" => Some(BinaryFormat::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(BinaryFormat::MessagePack)
+            }
+            _ => None,
+        }
+    }
+}
+
+// how much the client wants a media type, 0 when the Accept header doesn't list it
+pub(crate) fn accept_quality(accept: &str, wanted: &str) -> f32 {
+    accept
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            if !parts.next()?.eq_ignore_ascii_case(wanted) {
+                return None;
+            }
+            Some(
+                parts
+                    .filter_map(|param| param.strip_prefix("q="))
+                    .find_map(|quality| quality.parse::<f32>().ok())
+                    .unwrap_or(1.0),
+            )
+        })
+        .fold(0.0, f32::max)
+}
+
+// a binary format is used only when the client prefers it to JSON, so "*/*" keeps getting JSON
+pub fn preferred_binary_format(accept: &str) -> Option<BinaryFormat> {
+    let mut json_quality = 0.0;
+    let mut best: Option<(BinaryFormat, f32)> = None;
+    for entry in accept.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let media_type = parts.next().unwrap_or("").to_ascii_lowercase();
+        let quality = parts
+            .filter_map(|param| param.strip_prefix("q="))
+            .find_map(|quality| quality.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if media_type == "application/json" {
+            json_quality = f32::max(json_quality, quality);
+        } else if let Some(format) = BinaryFormat::of(&media_type) {
+            if best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                best = Some((format, quality));
+            }
+        }
+    }
+    best.filter(|(_, quality)| *quality > 0.0 && *quality > json_quality)
+        .map(|(format, _)| format)
+}
+
+pub fn encode(value: &Value, format: BinaryFormat) -> Vec<u8> {
+    let mut out = Vec::new();
+    match format {
+        BinaryFormat::Cbor => write_cbor(&mut out, value, false),
+        BinaryFormat::MessagePack => write_msgpack(&mut out, value, false),
+    }
+    out
+}
+
+// a base64 field that doesn't decode is sent as the string it is
+fn decoded_bytes(value: &Value, is_bytes_field: bool) -> Option<Vec<u8>> {
+    match value {
+        Value::String(text) if is_bytes_field => STANDARD.decode(text).ok(),
+        _ => None,
+    }
+}
+
+fn write_cbor_header(out: &mut Vec<u8>, major: u8, length: u64) {
+    let major = major << 5;
+    if length < 24 {
+        out.push(major | length as u8);
+    } else if length <= u8::MAX as u64 {
+        out.push(major | 24);
+        out.push(length as u8);
+    } else if length <= u16::MAX as u64 {
+        out.push(major | 25);
+        out.extend_from_slice(&(length as u16).to_be_bytes());
+    } else if length <= u32::MAX as u64 {
+        out.push(major | 26);
+        out.extend_from_slice(&(length as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&length.to_be_bytes());
+    }
+}
+
+// RFC 8949
+fn write_cbor(out: &mut Vec<u8>, value: &Value, is_bytes_field: bool) {
+    if let Some(bytes) = decoded_bytes(value, is_bytes_field) {
+        write_cbor_header(out, 2, bytes.len() as u64);
+        out.extend_from_slice(&bytes);
+        return;
+    }
+    match value {
+        Value::Null => out.push(0xf6),
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::Number(number) => {
+            if let Some(unsigned) = number.as_u64() {
+                write_cbor_header(out, 0, unsigned);
+            } else if let Some(signed) = number.as_i64() {
+                write_cbor_header(out, 1, (-1 - signed) as u64);
+            } else {
+                out.push(0xfb);
+                out.extend_from_slice(&number.as_f64().unwrap_or(0.0).to_be_bytes());
+            }
+        }
+        Value::String(text) => {
+            write_cbor_header(out, 3, text.len() as u64);
+            out.extend_from_slice(text.as_bytes());
+        }
+        Value::Array(items) => {
+            write_cbor_header(out, 4, items.len() as u64);
+            for item in items {
+                write_cbor(out, item, false);
+            }
+        }
+        Value::Object(fields) => {
+            write_cbor_header(out, 5, fields.len() as u64);
+            for (name, field) in fields {
+                write_cbor(out, &Value::String(name.clone()), false);
+                write_cbor(out, field, BYTES_FIELDS.contains(&name.as_str()));
+            }
+        }
+    }
+}
+
+// strings, arrays and maps have a short form for up to 31 or 15 items, bin has none
+fn write_msgpack_length(
+    out: &mut Vec<u8>,
+    length: usize,
+    fix: Option<(u8, usize)>,
+    markers: (Option<u8>, u8, u8),
+) {
+    let (marker8, marker16, marker32) = markers;
+    match (fix, marker8) {
+        (Some((prefix, max)), _) if length <= max => out.push(prefix | length as u8),
+        (_, Some(marker8)) if length <= u8::MAX as usize => {
+            out.extend_from_slice(&[marker8, length as u8])
+        }
+        _ if length <= u16::MAX as usize => {
+            out.push(marker16);
+            out.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(marker32);
+            out.extend_from_slice(&(length as u32).to_be_bytes());
+        }
+    }
+}
+
+// https://github.com/msgpack/msgpack/blob/master/spec.md
+fn write_msgpack(out: &mut Vec<u8>, value: &Value, is_bytes_field: bool) {
+    if let Some(bytes) = decoded_bytes(value, is_bytes_field) {
+        write_msgpack_length(out, bytes.len(), None, (Some(0xc4), 0xc5, 0xc6));
+        out.extend_from_slice(&bytes);
+        return;
+    }
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(number) => {
+            if let Some(unsigned) = number.as_u64() {
+                if unsigned < 0x80 {
+                    out.push(unsigned as u8);
+                } else if unsigned <= u8::MAX as u64 {
+                    out.extend_from_slice(&[0xcc, unsigned as u8]);
+                } else if unsigned <= u16::MAX as u64 {
+                    out.push(0xcd);
+                    out.extend_from_slice(&(unsigned as u16).to_be_bytes());
+                } else if unsigned <= u32::MAX as u64 {
+                    out.push(0xce);
+                    out.extend_from_slice(&(unsigned as u32).to_be_bytes());
+                } else {
+                    out.push(0xcf);
+                    out.extend_from_slice(&unsigned.to_be_bytes());
+                }
+            } else if let Some(signed) = number.as_i64() {
+                if signed >= -32 {
+                    out.push(signed as i8 as u8);
+                } else if signed >= i8::MIN as i64 {
+                    out.extend_from_slice(&[0xd0, signed as i8 as u8]);
+                } else if signed >= i16::MIN as i64 {
+                    out.push(0xd1);
+                    out.extend_from_slice(&(signed as i16).to_be_bytes());
+                } else if signed >= i32::MIN as i64 {
+                    out.push(0xd2);
+                    out.extend_from_slice(&(signed as i32).to_be_bytes());
+                } else {
+                    out.push(0xd3);
+                    out.extend_from_slice(&signed.to_be_bytes());
+                }
+            } else {
+                out.push(0xcb);
+                out.extend_from_slice(&number.as_f64().unwrap_or(0.0).to_be_bytes());
+            }
+        }
+        Value::String(text) => {
+            write_msgpack_length(out, text.len(), Some((0xa0, 31)), (Some(0xd9), 0xda, 0xdb));
+            out.extend_from_slice(text.as_bytes());
+        }
+        Value::Array(items) => {
+            write_msgpack_length(out, items.len(), Some((0x90, 15)), (None, 0xdc, 0xdd));
+            for item in items {
+                write_msgpack(out, item, false);
+            }
+        }
+        Value::Object(fields) => {
+            write_msgpack_length(out, fields.len(), Some((0x80, 15)), (None, 0xde, 0xdf));
+            for (name, field) in fields {
+                write_msgpack(out, &Value::String(name.clone()), false);
+                write_msgpack(out, field, BYTES_FIELDS.contains(&name.as_str()));
+            }
+        }
+    }
+}
+
+// re-encodes the JSON answers of the API for clients that ask for CBOR or MessagePack,
+// so binary messages don't pay the base64 overhead on the way back
+pub struct BinaryFormatMiddleware;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for BinaryFormatMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let is_api = req.url().path().starts_with("/api/");
+        let format = req
+            .header("Accept")
+            .filter(|_| is_api)
+            .and_then(|values| preferred_binary_format(values.last().as_str()));
+
+        let mut res = next.run(req).await;
+        if is_api {
+            res.append_header("Vary", "Accept");
+        }
+        let format = match format {
+            Some(format) => format,
+            None => return Ok(res),
+        };
+        // the content type is on the body until the response is sent
+        let body = res.take_body();
+        let is_json = res
+            .content_type()
+            .unwrap_or_else(|| body.mime().clone())
+            .essence()
+            == "application/json";
+        if !is_json {
+            res.set_body(body);
+            return Ok(res);
+        }
+
+        let body = body.into_bytes().await?;
+        match serde_json::from_slice::<Value>(&body) {
+            Ok(value) => {
+                res.set_body(encode(&value, format));
+                res.set_content_type(format.media_type());
+            }
+            // not ours to fix, passed on as it is
+            Err(_) => {
+                res.set_body(body);
+                res.set_content_type(tide::http::mime::JSON);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tide::http::{Method, Url};
+    use tide::StatusCode;
+
+    #[test]
+    fn test_preferred_binary_format() {
+        assert_eq!(
+            preferred_binary_format("application/cbor"),
+            Some(BinaryFormat::Cbor)
+        );
+        assert_eq!(
+            preferred_binary_format("application/json;q=0.5, application/x-msgpack"),
+            Some(BinaryFormat::MessagePack)
+        );
+        assert_eq!(
+            preferred_binary_format("application/json, application/cbor"),
+            None
+        );
+        assert_eq!(preferred_binary_format("*/*"), None);
+        assert_eq!(preferred_binary_format("application/cbor;q=0"), None);
+    }
+
+    #[test]
+    fn test_encode_cbor() {
+        // the examples of RFC 8949 appendix A
+        assert_eq!(encode(&json!(0), BinaryFormat::Cbor), [0x00]);
+        assert_eq!(encode(&json!(24), BinaryFormat::Cbor), [0x18, 0x18]);
+        assert_eq!(encode(&json!(1000), BinaryFormat::Cbor), [0x19, 0x03, 0xe8]);
+        assert_eq!(encode(&json!(-100), BinaryFormat::Cbor), [0x38, 0x63]);
+        assert_eq!(encode(&json!("a"), BinaryFormat::Cbor), [0x61, 0x61]);
+        assert_eq!(
+            encode(&json!([1, [2, 3]]), BinaryFormat::Cbor),
+            [0x82, 0x01, 0x82, 0x02, 0x03]
+        );
+        assert_eq!(
+            encode(&json!({"a": 1, "b": null}), BinaryFormat::Cbor),
+            [0xa2, 0x61, 0x61, 0x01, 0x61, 0x62, 0xf6]
+        );
+        assert_eq!(
+            encode(&json!({"message_data": "AQID"}), BinaryFormat::Cbor),
+            [
+                0xa1, 0x6c, b'm', b'e', b's', b's', b'a', b'g', b'e', b'_', b'd', b'a', b't', b'a',
+                0x43, 0x01, 0x02, 0x03
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_msgpack() {
+        assert_eq!(encode(&json!(127), BinaryFormat::MessagePack), [0x7f]);
+        assert_eq!(encode(&json!(200), BinaryFormat::MessagePack), [0xcc, 0xc8]);
+        assert_eq!(encode(&json!(-1), BinaryFormat::MessagePack), [0xff]);
+        assert_eq!(
+            encode(&json!(-100), BinaryFormat::MessagePack),
+            [0xd0, 0x9c]
+        );
+        assert_eq!(encode(&json!(true), BinaryFormat::MessagePack), [0xc3]);
+        assert_eq!(
+            encode(&json!("ab"), BinaryFormat::MessagePack),
+            [0xa2, b'a', b'b']
+        );
+        assert_eq!(encode(&json!([1]), BinaryFormat::MessagePack), [0x91, 0x01]);
+        assert_eq!(
+            encode(&json!({"message_data": "AQID"}), BinaryFormat::MessagePack)[14..],
+            [0xc4, 0x03, 0x01, 0x02, 0x03]
+        );
+        assert_eq!(
+            encode(&json!({"message_data": ""}), BinaryFormat::MessagePack)[14..],
+            [0xc4, 0x00]
+        );
+        let long_text = "x".repeat(300);
+        assert_eq!(
+            encode(&json!(long_text), BinaryFormat::MessagePack)[..3],
+            [0xda, 0x01, 0x2c]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_middleware() {
+        let mut app = tide::new();
+        app.with(BinaryFormatMiddleware);
+        app.at("/api/v1/messages/:token/consume")
+            .post(|_| async { Ok(json!({"message_data": "AQID", "expire_timestamp": 0})) });
+        app.at("/").get(|_| async { Ok(json!({"a": 1})) });
+
+        let mut req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages/abc/consume").unwrap(),
+        );
+        req.insert_header("Accept", "application/cbor");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res["Content-Type"], "application/cbor");
+        assert_eq!(
+            res.body_bytes().await.unwrap(),
+            encode(
+                &json!({"message_data": "AQID", "expire_timestamp": 0}),
+                BinaryFormat::Cbor
+            )
+        );
+
+        // the pages stay as they are
+        let mut req =
+            tide::http::Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+        req.insert_header("Accept", "application/cbor");
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert!(res["Content-Type"].as_str().starts_with("application/json"));
+    }
+}
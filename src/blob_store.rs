@@ -0,0 +1,30 @@
+use serde::{Deserialize, Serialize};
+
+use crate::file_blob_store::{FileBlobStore, FileBlobStoreConfig};
+use crate::s3::{S3BlobStore, S3Config};
+use crate::store::StoreResult;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BlobStorageConfig {
+    S3(S3Config),
+    Filesystem(FileBlobStoreConfig),
+}
+
+// storage for message payloads that are too big to be kept in the database
+pub trait BlobStore: Send + Sync {
+    fn put(&self, key: &str, data: &[u8]) -> StoreResult<()>;
+
+    fn get(&self, key: &str) -> StoreResult<Vec<u8>>;
+
+    fn delete(&self, key: &str) -> StoreResult<()>;
+}
+
+pub fn make_blob_store(config: &BlobStorageConfig) -> std::io::Result<Box<dyn BlobStore>> {
+    match config {
+        BlobStorageConfig::S3(s3_config) => Ok(Box::new(S3BlobStore::new(s3_config.clone())?)),
+        BlobStorageConfig::Filesystem(file_config) => {
+            Ok(Box::new(FileBlobStore::new(file_config)?))
+        }
+    }
+}
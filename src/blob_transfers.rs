@@ -0,0 +1,516 @@
+use async_std::task::{self, TaskId};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tide::{Middleware, Next, Request};
+use zeroize::Zeroizing;
+
+use crate::api::check_token_signature;
+use crate::blob_store::BlobStore;
+use crate::error::AppError;
+use crate::store::{Store, StoreError, StoreResult};
+use crate::StaticData;
+
+type Payload = Arc<Zeroizing<Vec<u8>>>;
+
+enum Transfer {
+    Upload(String),
+    Delete(String),
+}
+
+#[derive(Default)]
+struct Staged {
+    // saved under the locks, read from here until they are uploaded
+    uploads: HashMap<String, Payload>,
+    // fetched before the locks were taken, with the number of requests that fetched them
+    downloads: HashMap<String, (usize, Payload)>,
+    // in the order they were made, with the task that made them
+    queue: Vec<(Option<TaskId>, Transfer)>,
+}
+
+// the payloads cross to and from the blob store while no lock is held: an upload waits here
+// until the request that saved it is done with the locks, a download is fetched before the
+// request takes them, so a slow blob store doesn't hold up the other requests
+pub struct BlobTransfers {
+    blob_store: Box<dyn BlobStore>,
+    staged: Mutex<Staged>,
+}
+
+pub(crate) fn current_task_id() -> Option<TaskId> {
+    task::try_current().map(|task| task.id())
+}
+
+impl BlobTransfers {
+    pub fn new(blob_store: Box<dyn BlobStore>) -> Self {
+        BlobTransfers {
+            blob_store,
+            staged: Mutex::new(Staged::default()),
+        }
+    }
+
+    // for the maintenance jobs, they go to the blob store directly
+    pub fn blob_store(&self) -> &dyn BlobStore {
+        &*self.blob_store
+    }
+
+    pub fn put(&self, key: String, data: Zeroizing<Vec<u8>>) {
+        let mut staged = self.staged.lock().unwrap();
+        staged.uploads.insert(key.clone(), Arc::new(data));
+        staged
+            .queue
+            .push((current_task_id(), Transfer::Upload(key)));
+    }
+
+    // a payload that wasn't fetched beforehand is fetched right away, that only happens to
+    // the maintenance jobs and the readers that weren't known before the request ran
+    pub fn get(&self, key: &str) -> StoreResult<Zeroizing<Vec<u8>>> {
+        {
+            let staged = self.staged.lock().unwrap();
+            if let Some(data) = staged
+                .uploads
+                .get(key)
+                .or_else(|| staged.downloads.get(key).map(|(_, data)| data))
+            {
+                return Ok(Zeroizing::new(data.to_vec()));
+            }
+        }
+        self.blob_store.get(key).map(Zeroizing::new)
+    }
+
+    pub fn delete(&self, key: &str) {
+        let mut staged = self.staged.lock().unwrap();
+        staged.downloads.remove(key);
+        // an upload that didn't start yet is simply dropped
+        let queued_upload = staged.queue.iter().position(
+            |(_, transfer)| matches!(transfer, Transfer::Upload(upload) if upload == key),
+        );
+        match queued_upload {
+            Some(position) => {
+                staged.queue.remove(position);
+                staged.uploads.remove(key);
+            }
+            None => staged
+                .queue
+                .push((current_task_id(), Transfer::Delete(key.to_string()))),
+        }
+    }
+
+    // blocks until the payload is fetched, it's read from here until it's released
+    pub fn prefetch(&self, key: &str) -> StoreResult<()> {
+        if let Some((count, _)) = self.staged.lock().unwrap().downloads.get_mut(key) {
+            *count += 1;
+            return Ok(());
+        }
+        let data = Arc::new(Zeroizing::new(self.blob_store.get(key)?));
+        let mut staged = self.staged.lock().unwrap();
+        let (count, _) = staged.downloads.entry(key.to_string()).or_insert((0, data));
+        *count += 1;
+        Ok(())
+    }
+
+    pub fn release_prefetched(&self, key: &str) {
+        let mut staged = self.staged.lock().unwrap();
+        if let Some((count, _)) = staged.downloads.get_mut(key) {
+            *count -= 1;
+            if *count == 0 {
+                staged.downloads.remove(key);
+            }
+        }
+    }
+
+    // blocks until the queued transfers of the task, or all of them without one, are made,
+    // returns the keys of the payloads that couldn't be uploaded
+    pub fn run(&self, task_id: Option<TaskId>) -> Vec<String> {
+        let transfers: Vec<Transfer> = {
+            let mut staged = self.staged.lock().unwrap();
+            let (taken, kept) = std::mem::take(&mut staged.queue)
+                .into_iter()
+                .partition(|(owner, _)| task_id.is_none() || *owner == task_id);
+            staged.queue = kept;
+            taken.into_iter().map(|(_, transfer)| transfer).collect()
+        };
+
+        let mut failed_uploads = Vec::new();
+        for transfer in transfers {
+            match transfer {
+                Transfer::Upload(key) => {
+                    let data = match self.staged.lock().unwrap().uploads.get(&key) {
+                        Some(data) => data.clone(),
+                        None => continue,
+                    };
+                    if let Err(err) = self.blob_store.put(&key, &data) {
+                        log::error!("Failed to upload blob {}: {}", key, err);
+                        failed_uploads.push(key.clone());
+                    }
+                    self.staged.lock().unwrap().uploads.remove(&key);
+                }
+                // a failed delete only leaves an unreachable blob behind
+                Transfer::Delete(key) => {
+                    if let Err(err) = self.blob_store.delete(&key) {
+                        log::warn!("Failed to delete blob {}: {}", key, err);
+                    }
+                }
+            }
+        }
+        failed_uploads
+    }
+}
+
+// makes the queued transfers on a blocking thread and removes the messages whose payload
+// couldn't be uploaded, false when there were any
+pub async fn run_blob_transfers(
+    database: &Mutex<dyn Store>,
+    task_id: Option<TaskId>,
+) -> StoreResult<bool> {
+    let blob_transfers = match database.lock().unwrap().blob_transfers() {
+        Some(blob_transfers) => blob_transfers,
+        None => return Ok(true),
+    };
+    let failed_uploads = task::spawn_blocking(move || blob_transfers.run(task_id)).await;
+    if failed_uploads.is_empty() {
+        return Ok(true);
+    }
+    database
+        .lock()
+        .unwrap()
+        .remove_messages_of_blobs(&failed_uploads)?;
+    Ok(false)
+}
+
+// makes the transfers of the request once its handler let go of the locks, a message whose
+// payload couldn't be uploaded is removed and the request fails
+pub struct BlobTransfersMiddleware {
+    // None when there is no blob store
+    database: Option<Arc<Mutex<dyn Store>>>,
+}
+
+impl BlobTransfersMiddleware {
+    pub fn new(database: &Arc<Mutex<dyn Store>>) -> Self {
+        let has_blob_store = database.lock().unwrap().blob_transfers().is_some();
+        BlobTransfersMiddleware {
+            database: has_blob_store.then(|| database.clone()),
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl Middleware<Arc<Mutex<StaticData>>> for BlobTransfersMiddleware {
+    async fn handle(
+        &self,
+        req: Request<Arc<Mutex<StaticData>>>,
+        next: Next<'_, Arc<Mutex<StaticData>>>,
+    ) -> tide::Result {
+        let res = next.run(req).await;
+        if let Some(database) = &self.database {
+            if !run_blob_transfers(database, current_task_id()).await? {
+                return Err(
+                    AppError::from(StoreError::new("Failed to upload the message")).into_error(),
+                );
+            }
+        }
+        Ok(res)
+    }
+}
+
+// the message a route reads, None when there is nothing to fetch for it
+type MessageTokenOf = fn(&Request<Arc<Mutex<StaticData>>>, &StaticData) -> Option<String>;
+
+// fetches the payload of the message the route reads before its handler takes the locks
+pub struct BlobPrefetchMiddleware {
+    message_token_of: MessageTokenOf,
+}
+
+impl BlobPrefetchMiddleware {
+    pub fn new(message_token_of: MessageTokenOf) -> Self {
+        BlobPrefetchMiddleware { message_token_of }
+    }
+
+    // the message is named by the token of the route
+    pub fn by_token() -> Self {
+        Self::new(|req, _| req.param("token").ok().map(str::to_string))
+    }
+}
+
+#[tide::utils::async_trait]
+impl Middleware<Arc<Mutex<StaticData>>> for BlobPrefetchMiddleware {
+    async fn handle(
+        &self,
+        req: Request<Arc<Mutex<StaticData>>>,
+        next: Next<'_, Arc<Mutex<StaticData>>>,
+    ) -> tide::Result {
+        let prefetch = {
+            let data = req.state().lock().unwrap();
+            match (self.message_token_of)(&req, &data)
+                // a forged token is left to the handler, which turns it away
+                .filter(|message_token| check_token_signature(&data, message_token).is_ok())
+            {
+                Some(message_token) => {
+                    let database = data.database.lock().unwrap();
+                    match (
+                        database.blob_transfers(),
+                        database.get_message_blob_key(&message_token)?,
+                    ) {
+                        (Some(blob_transfers), Some(blob_key)) => Some((blob_transfers, blob_key)),
+                        _ => None,
+                    }
+                }
+                None => None,
+            }
+        };
+        let (blob_transfers, blob_key) = match prefetch {
+            Some(prefetch) => prefetch,
+            None => return Ok(next.run(req).await),
+        };
+
+        task::spawn_blocking({
+            let blob_transfers = blob_transfers.clone();
+            let blob_key = blob_key.clone();
+            move || blob_transfers.prefetch(&blob_key)
+        })
+        .await
+        .map_err(|err| AppError::from(err).into_error())?;
+        let res = next.run(req).await;
+        blob_transfers.release_prefetched(&blob_key);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ConsumeMessageResponse;
+    use crate::database::OneTimeShareDb;
+    use crate::store::{MessageOptions, UserLimits};
+    use crate::tests::setup_test_data;
+    use crate::MessageForm;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use tide::http::{Method, Request, Url};
+    use tide::{Body, Response, StatusCode};
+
+    // tells when a get started and answers it only when told to, like a blob store that
+    // stopped answering
+    #[derive(Clone, Default)]
+    struct SlowBlobStore {
+        blobs: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        started: Arc<Mutex<Option<mpsc::Sender<()>>>>,
+        answers: Arc<Mutex<Option<mpsc::Receiver<()>>>>,
+    }
+
+    impl BlobStore for SlowBlobStore {
+        fn put(&self, key: &str, data: &[u8]) -> StoreResult<()> {
+            self.blobs
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), data.to_vec());
+            Ok(())
+        }
+
+        fn get(&self, key: &str) -> StoreResult<Vec<u8>> {
+            if let Some(started) = &*self.started.lock().unwrap() {
+                started.send(()).unwrap();
+            }
+            if let Some(answers) = &*self.answers.lock().unwrap() {
+                answers.recv().unwrap();
+            }
+            self.blobs
+                .lock()
+                .unwrap()
+                .get(key)
+                .cloned()
+                .ok_or_else(|| StoreError::new("Blob not found"))
+        }
+
+        fn delete(&self, key: &str) -> StoreResult<()> {
+            self.blobs.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct FailingBlobStore;
+
+    impl BlobStore for FailingBlobStore {
+        fn put(&self, _key: &str, _data: &[u8]) -> StoreResult<()> {
+            Err(StoreError::new("Storage is down"))
+        }
+
+        fn get(&self, _key: &str) -> StoreResult<Vec<u8>> {
+            Err(StoreError::new("Storage is down"))
+        }
+
+        fn delete(&self, _key: &str) -> StoreResult<()> {
+            Err(StoreError::new("Storage is down"))
+        }
+    }
+
+    #[test]
+    fn test_staged_upload_is_read_until_it_is_made() {
+        let blob_store = SlowBlobStore::default();
+        let blob_transfers = BlobTransfers::new(Box::new(blob_store.clone()));
+
+        blob_transfers.put("blob1".to_string(), Zeroizing::new(b"Hello".to_vec()));
+        assert!(blob_store.blobs.lock().unwrap().is_empty());
+        assert_eq!(*blob_transfers.get("blob1").unwrap(), b"Hello");
+
+        assert!(blob_transfers.run(None).is_empty());
+        assert_eq!(blob_store.blobs.lock().unwrap()["blob1"], b"Hello");
+        assert!(blob_transfers.staged.lock().unwrap().uploads.is_empty());
+    }
+
+    #[test]
+    fn test_upload_deleted_before_it_is_made_is_never_made() {
+        let blob_store = SlowBlobStore::default();
+        let blob_transfers = BlobTransfers::new(Box::new(blob_store.clone()));
+
+        blob_transfers.put("blob1".to_string(), Zeroizing::new(b"Hello".to_vec()));
+        blob_transfers.delete("blob1");
+        assert!(blob_transfers.get("blob1").is_err());
+
+        assert!(blob_transfers.run(None).is_empty());
+        assert!(blob_store.blobs.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_failed_upload_is_reported() {
+        let blob_transfers = BlobTransfers::new(Box::new(FailingBlobStore));
+
+        blob_transfers.put("blob1".to_string(), Zeroizing::new(b"Hello".to_vec()));
+        assert_eq!(blob_transfers.run(None), vec!["blob1".to_string()]);
+        assert!(blob_transfers.get("blob1").is_err());
+    }
+
+    #[test]
+    fn test_prefetched_blob_is_kept_until_every_reader_released_it() {
+        let blob_store = SlowBlobStore::default();
+        blob_store.put("blob1", b"Hello").unwrap();
+        let blob_transfers = BlobTransfers::new(Box::new(blob_store.clone()));
+
+        blob_transfers.prefetch("blob1").unwrap();
+        blob_transfers.prefetch("blob1").unwrap();
+        blob_store.delete("blob1").unwrap();
+
+        blob_transfers.release_prefetched("blob1");
+        assert_eq!(*blob_transfers.get("blob1").unwrap(), b"Hello");
+        blob_transfers.release_prefetched("blob1");
+        assert!(blob_transfers.get("blob1").is_err());
+    }
+
+    fn use_blob_store(app_data: &Mutex<StaticData>, blob_store: Box<dyn BlobStore>) {
+        let mut database = OneTimeShareDb::connect_in_memory().unwrap();
+        database.set_blob_store(blob_store, 16);
+        database
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    retention_limit_minutes: 60,
+                    max_message_size_bytes: 1024,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        app_data.lock().unwrap().database = Arc::new(Mutex::new(database));
+    }
+
+    // the requests run on threads of their own, so a blocked one can't block the test
+    fn respond_in_thread(
+        app: &tide::Server<Arc<Mutex<StaticData>>>,
+        req: Request,
+    ) -> mpsc::Receiver<(StatusCode, Vec<u8>)> {
+        let app = app.clone();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            task::block_on(async {
+                let mut res: Response = app.respond(req).await.unwrap();
+                let body = res.take_body().into_bytes().await.unwrap();
+                sender.send((res.status(), body)).unwrap();
+            })
+        });
+        receiver
+    }
+
+    #[test]
+    fn test_slow_blob_store_does_not_hold_up_other_requests() {
+        let app_data = setup_test_data();
+        let blob_store = SlowBlobStore::default();
+        use_blob_store(&app_data, Box::new(blob_store.clone()));
+        {
+            let data = app_data.lock().unwrap();
+            let database = data.database.lock().unwrap();
+            database
+                .save_message("large", 0, &[b'a'; 100], &MessageOptions::default())
+                .unwrap();
+            database
+                .save_message("small", 0, b"Hi", &MessageOptions::default())
+                .unwrap();
+            assert!(database.blob_transfers().unwrap().run(None).is_empty());
+        }
+        let app = crate::init_app(app_data.clone());
+
+        let (started_sender, started) = mpsc::channel();
+        let (answer, answers) = mpsc::channel();
+        *blob_store.started.lock().unwrap() = Some(started_sender);
+        *blob_store.answers.lock().unwrap() = Some(answers);
+        let consume = respond_in_thread(
+            &app,
+            Request::new(
+                Method::Post,
+                Url::parse("http://localhost/api/v1/messages/large/consume").unwrap(),
+            ),
+        );
+        started.recv().unwrap();
+
+        // the consume waits for the blob store without holding any lock
+        let meta = respond_in_thread(
+            &app,
+            Request::new(
+                Method::Get,
+                Url::parse("http://localhost/api/v1/messages/small/meta").unwrap(),
+            ),
+        );
+        let (status, _) = meta
+            .recv_timeout(Duration::from_secs(5))
+            .expect("the request waited for the blob store");
+        assert_eq!(status, StatusCode::Ok);
+
+        answer.send(()).unwrap();
+        let (status, body) = consume.recv().unwrap();
+        assert_eq!(status, StatusCode::Ok);
+        let body: ConsumeMessageResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.message_data.len(), 136);
+        // deleted once the request was done with the locks
+        assert!(blob_store.blobs.lock().unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_message_that_was_not_uploaded_is_removed() {
+        let app_data = setup_test_data();
+        use_blob_store(&app_data, Box::new(FailingBlobStore));
+        let app = crate::init_app(app_data.clone());
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages").unwrap(),
+        );
+        req.set_body(
+            Body::from_json(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8sIHRoaXMgaXMgYSBsb25nIG1lc3NhZ2U="
+                    .to_string()
+                    .into(),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::InternalServerError);
+
+        let data = app_data.lock().unwrap();
+        let database = data.database.lock().unwrap();
+        assert_eq!(
+            database
+                .count_active_user_messages("test_token", 0)
+                .unwrap(),
+            0
+        );
+    }
+}
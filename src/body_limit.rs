@@ -0,0 +1,125 @@
+use async_std::io::{BufReader, Read};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tide::{Body, Middleware, Next, Request, Response, StatusCode};
+
+// fails the read as soon as more than `remaining` bytes come in, so an endless
+// chunked body never ends up in memory
+struct LimitedReader {
+    inner: Body,
+    remaining: u64,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl Read for LimitedReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(read)) if read as u64 > this.remaining => {
+                this.exceeded.store(true, Ordering::SeqCst);
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Request body is too large",
+                )))
+            }
+            Poll::Ready(Ok(read)) => {
+                this.remaining -= read as u64;
+                Poll::Ready(Ok(read))
+            }
+            other => other,
+        }
+    }
+}
+
+pub struct BodyLimitMiddleware {
+    max_body_bytes: u64,
+}
+
+impl BodyLimitMiddleware {
+    pub fn new(max_body_bytes: u64) -> Self {
+        BodyLimitMiddleware { max_body_bytes }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for BodyLimitMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        // an honest client says in advance how much it's going to send
+        if req
+            .len()
+            .is_some_and(|length| length as u64 > self.max_body_bytes)
+        {
+            return Ok(Response::new(StatusCode::PayloadTooLarge));
+        }
+
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let body = req.take_body();
+        let length = body.len();
+        let mime = body.mime().clone();
+        let mut limited_body = Body::from_reader(
+            BufReader::new(LimitedReader {
+                inner: body,
+                remaining: self.max_body_bytes,
+                exceeded: exceeded.clone(),
+            }),
+            length,
+        );
+        limited_body.set_mime(mime);
+        req.set_body(limited_body);
+
+        let res = next.run(req).await;
+        // the handler sees only a failed read, the real reason is known here
+        if exceeded.load(Ordering::SeqCst) {
+            return Ok(Response::new(StatusCode::PayloadTooLarge));
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tide::http::{Method, Url};
+
+    fn make_app() -> tide::Server<()> {
+        let mut app = tide::new();
+        app.with(BodyLimitMiddleware::new(10));
+        app.at("/").post(|mut req: Request<()>| async move {
+            let body = req.body_string().await?;
+            Ok(body)
+        });
+        app
+    }
+
+    async fn send(body: Body) -> tide::http::Response {
+        let mut req =
+            tide::http::Request::new(Method::Post, Url::parse("http://localhost/").unwrap());
+        req.set_body(body);
+        make_app().respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_body_within_limit() {
+        let mut res = send(Body::from_string("0123456789".to_string())).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.take_body().into_string().await.unwrap(), "0123456789");
+    }
+
+    #[async_std::test]
+    async fn test_body_over_limit() {
+        let res = send(Body::from_string("0123456789a".to_string())).await;
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+
+        // without a known length the body is cut off while it's read
+        let reader = BufReader::new(async_std::io::Cursor::new(vec![b'a'; 100]));
+        let res = send(Body::from_reader(reader, None)).await;
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+    }
+}
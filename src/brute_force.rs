@@ -0,0 +1,219 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tide::{Middleware, Next, Request, StatusCode};
+
+use crate::abuse_log::AbuseEvent;
+use crate::error::AppError;
+use crate::proxy::client_addr;
+use crate::rate_limit::{classify_request, client_ip, Budget};
+use crate::throttle::Throttled;
+
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct BruteForceConfig {
+    pub enabled: bool,
+    // lookups of tokens that don't exist, within the window
+    pub max_failures: u32,
+    pub window_seconds: u64,
+    pub ban_seconds: u64,
+}
+
+impl Default for BruteForceConfig {
+    fn default() -> Self {
+        BruteForceConfig {
+            enabled: true,
+            max_failures: 20,
+            window_seconds: 10 * 60,
+            ban_seconds: 15 * 60,
+        }
+    }
+}
+
+struct ClientFailures {
+    count: u32,
+    window_start: Instant,
+    banned_until: Option<Instant>,
+}
+
+// bans the addresses that look up many nonexistent tokens, so the tokens can't be enumerated
+pub struct BruteForceGuard {
+    config: BruteForceConfig,
+    clients: Mutex<HashMap<String, ClientFailures>>,
+}
+
+impl BruteForceGuard {
+    pub fn new(config: &BruteForceConfig) -> Self {
+        BruteForceGuard {
+            config: config.clone(),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.config.window_seconds)
+    }
+
+    // how long the client stays banned, if it is
+    pub fn ban_remaining(&self, ip: &str, now: Instant) -> Option<Duration> {
+        let clients = self.clients.lock().unwrap();
+        clients
+            .get(ip)
+            .and_then(|client| client.banned_until)
+            .filter(|banned_until| *banned_until > now)
+            .map(|banned_until| banned_until - now)
+    }
+
+    // returns true when this failure got the client banned
+    pub fn record_failure(&self, ip: &str, now: Instant) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_TRACKED_CLIENTS && !clients.contains_key(ip) {
+            let window = self.window();
+            clients.retain(|_, client| {
+                now.saturating_duration_since(client.window_start) < window
+                    || client.banned_until.is_some_and(|until| until > now)
+            });
+        }
+
+        let client = clients.entry(ip.to_string()).or_insert(ClientFailures {
+            count: 0,
+            window_start: now,
+            banned_until: None,
+        });
+        if now.saturating_duration_since(client.window_start) >= self.window() {
+            client.count = 0;
+            client.window_start = now;
+        }
+        client.count += 1;
+
+        if client.count >= self.config.max_failures
+            && client.banned_until.is_none_or(|until| until <= now)
+        {
+            client.banned_until = Some(now + Duration::from_secs(self.config.ban_seconds));
+            client.count = 0;
+            client.window_start = now;
+            return true;
+        }
+        false
+    }
+}
+
+pub struct BruteForceMiddleware {
+    guard: Option<BruteForceGuard>,
+    // the bans are only kept in memory, the log doesn't name the address
+    privacy_mode: bool,
+}
+
+impl BruteForceMiddleware {
+    pub fn new(config: &BruteForceConfig, privacy_mode: bool) -> Self {
+        BruteForceMiddleware {
+            guard: config.enabled.then(|| BruteForceGuard::new(config)),
+            privacy_mode,
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for BruteForceMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let guard = match &self.guard {
+            Some(guard) if classify_request(&req) == Some(Budget::Consume) => guard,
+            _ => return Ok(next.run(req).await),
+        };
+
+        let ip = client_ip(client_addr(&req).unwrap_or("-"));
+        if let Some(remaining) = guard.ban_remaining(&ip, Instant::now()) {
+            return Err(AppError::RateLimited(Throttled::new(
+                "Too many lookups of messages that don't exist",
+                remaining,
+            ))
+            .into_error());
+        }
+
+        let mut res = next.run(req).await;
+        if res.status() == StatusCode::NotFound && guard.record_failure(&ip, Instant::now()) {
+            res.insert_ext(AbuseEvent::Banned);
+            log::warn!(
+                "Banned {} for {} second(s) after {} lookups of nonexistent messages",
+                if self.privacy_mode { "a client" } else { &ip },
+                guard.config.ban_seconds,
+                guard.config.max_failures
+            );
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tide::http::{Method, Url};
+    use tide::Response;
+
+    fn make_config() -> BruteForceConfig {
+        BruteForceConfig {
+            enabled: true,
+            max_failures: 3,
+            window_seconds: 60,
+            ban_seconds: 300,
+        }
+    }
+
+    #[test]
+    fn test_ban_after_too_many_failures() {
+        let guard = BruteForceGuard::new(&make_config());
+        let start = Instant::now();
+
+        assert!(!guard.record_failure("a", start));
+        assert!(!guard.record_failure("a", start));
+        assert!(guard.ban_remaining("a", start).is_none());
+        assert!(guard.record_failure("a", start));
+        assert_eq!(
+            guard.ban_remaining("a", start + Duration::from_secs(100)),
+            Some(Duration::from_secs(200))
+        );
+        assert!(guard.ban_remaining("b", start).is_none());
+        assert!(guard
+            .ban_remaining("a", start + Duration::from_secs(300))
+            .is_none());
+    }
+
+    #[test]
+    fn test_failures_expire_with_the_window() {
+        let guard = BruteForceGuard::new(&make_config());
+        let start = Instant::now();
+
+        assert!(!guard.record_failure("a", start));
+        assert!(!guard.record_failure("a", start));
+        assert!(!guard.record_failure("a", start + Duration::from_secs(61)));
+        assert!(guard
+            .ban_remaining("a", start + Duration::from_secs(61))
+            .is_none());
+    }
+
+    #[async_std::test]
+    async fn test_middleware_bans_probing_client() {
+        let mut app = tide::new();
+        app.with(BruteForceMiddleware::new(&make_config(), false));
+        app.at("/shared/:token")
+            .post(|_| async { Ok(Response::new(StatusCode::NotFound)) });
+
+        for expected in [
+            StatusCode::NotFound,
+            StatusCode::NotFound,
+            StatusCode::NotFound,
+            StatusCode::TooManyRequests,
+        ] {
+            let mut req = tide::http::Request::new(
+                Method::Post,
+                Url::parse("http://localhost/shared/abc").unwrap(),
+            );
+            req.set_peer_addr(Some("10.0.0.1:1000"));
+            let res: tide::http::Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), expected);
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tide::{Body, Request, Response, StatusCode};
+use zeroize::Zeroizing;
+
+use crate::api::{
+    consume_protected_message, expires_in_seconds, parse_consume_request, ConsumeMessageResponse,
+};
+use crate::error::AppError;
+use crate::receipts::Reader;
+use crate::secret_requests::{find_request, not_found, seal_token, sign_token, unseal_token};
+use crate::store::SecretRequest;
+use crate::tokens::TokenConfig;
+use crate::{get_user_limits, make_bare_share_url, save_new_message, MessageForm, StaticData};
+
+// an exchange channel is a message for the other party and a secret request for their
+// reply that opens once the message is read, both found by the channel token
+#[derive(Serialize, Deserialize, Default)]
+pub struct ChannelForm {
+    pub user_token: String,
+    pub message_data: Zeroizing<String>,
+    // minutes the channel stays open and the reply is kept, no expiry when empty
+    pub retention: Option<u32>,
+    pub passphrase: Zeroizing<Option<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreatedChannel {
+    // for the other party, the secret is read and the reply is sent with it
+    pub channel_token: String,
+    // for the creator only, the reply is read with it like any message
+    pub reply_url: String,
+    pub reply_token: String,
+    pub expire_timestamp: u64,
+}
+
+pub async fn create_channel(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let form: ChannelForm = req
+        .body_json()
+        .await
+        .map_err(|_| AppError::BadRequest("Can't parse request body".to_string()).into_error())?;
+    let message_form = MessageForm {
+        user_token: form.user_token,
+        message_data: form.message_data,
+        retention: form.retention,
+        passphrase: form.passphrase,
+        ..Default::default()
+    };
+
+    let data = req.state().lock().unwrap();
+    // the limits of the creator apply to the secret and to the reply alike
+    let created = save_new_message(&data, &message_form)?;
+    let max_size_bytes = get_user_limits(&data, &message_form.user_token)?
+        .map_or(0, |user_limits| user_limits.max_message_size_bytes);
+
+    let channel_token = sign_token(&data, data.config.message_tokens.generate());
+    let reply_token = sign_token(&data, TokenConfig::default().generate());
+    data.database.lock().unwrap().save_secret_request(
+        &channel_token,
+        &SecretRequest {
+            note: None,
+            expire_timestamp: created.expire_timestamp as i64,
+            max_size_bytes,
+            retention_minutes: message_form.retention.unwrap_or(0),
+            sealed_response_token: seal_token(&channel_token, &reply_token)?,
+            sealed_message_token: Some(seal_token(&channel_token, &created.message_token)?),
+        },
+    )?;
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&CreatedChannel {
+            channel_token,
+            reply_url: make_bare_share_url(&req, &data.config, &reply_token),
+            reply_token,
+            expire_timestamp: created.expire_timestamp,
+        })?)
+        .build())
+}
+
+// the message of the channel, so its payload can be fetched before it's read
+pub(crate) fn message_token_of(
+    req: &Request<Arc<Mutex<StaticData>>>,
+    data: &StaticData,
+) -> Option<String> {
+    let channel_token = req.param("token").ok()?;
+    let sealed_message_token = find_request(data, channel_token)
+        .ok()?
+        .sealed_message_token?;
+    unseal_token(channel_token, &sealed_message_token).ok()
+}
+
+// reads the secret and lets the reply be sent, the channel token stays valid for that
+pub async fn consume_channel(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let body = Zeroizing::new(req.body_string().await?);
+    let consume_request = parse_consume_request(&body)?;
+
+    let data = req.state().lock().unwrap();
+    let channel_token = req.param("token")?;
+    let channel = find_request(&data, channel_token)?;
+    let message_token = match &channel.sealed_message_token {
+        Some(sealed_message_token) => unseal_token(channel_token, sealed_message_token)?,
+        // read before
+        None => return Err(not_found()),
+    };
+
+    let (message_data, expire_timestamp) =
+        consume_protected_message(&data, &message_token, &consume_request, &Reader::of(&req))?;
+    data.database
+        .lock()
+        .unwrap()
+        .open_secret_request(channel_token)?;
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&ConsumeMessageResponse {
+            message_data: STANDARD.encode(&message_data).into(),
+            expire_timestamp: expire_timestamp as u64,
+            expires_in_seconds: expires_in_seconds(expire_timestamp),
+            filename: None,
+            content_type: None,
+        })?)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret_requests::SecretAnswer;
+    use crate::store::UserLimits;
+    use crate::tests::setup_test_data;
+    use tide::http::{Method, Url};
+
+    fn post(url: &str, body: &impl Serialize) -> tide::http::Request {
+        let mut req = tide::http::Request::new(Method::Post, Url::parse(url).unwrap());
+        req.set_body(Body::from_json(body).unwrap());
+        req
+    }
+
+    #[async_std::test]
+    async fn test_exchange() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits("test_token", &UserLimits::default())
+            .unwrap();
+
+        let mut res: tide::http::Response = app
+            .respond(post(
+                "http://localhost/api/v1/channels",
+                &ChannelForm {
+                    user_token: "test_token".to_string(),
+                    // base64 of "hunter2"
+                    message_data: "aHVudGVyMg==".to_string().into(),
+                    retention: Some(60),
+                    passphrase: Some("pass".to_string()).into(),
+                },
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: CreatedChannel = res.take_body().into_json().await.unwrap();
+        assert_eq!(
+            created.reply_url,
+            format!("http://localhost/shared/{}", created.reply_token)
+        );
+
+        let consume_url = format!(
+            "http://localhost/api/v1/channels/{}/consume",
+            created.channel_token
+        );
+        let reply_url = format!(
+            "http://localhost/api/v1/channels/{}/reply",
+            created.channel_token
+        );
+        let reply = || {
+            post(
+                &reply_url,
+                &SecretAnswer {
+                    // base64 of "got it"
+                    message_data: "Z290IGl0".to_string().into(),
+                    ..Default::default()
+                },
+            )
+        };
+
+        // the reply comes after the secret is read
+        let res: tide::http::Response = app.respond(reply()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Conflict);
+        let res: tide::http::Response = app
+            .respond(post(&consume_url, &serde_json::json!({})))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        let mut res: tide::http::Response = app
+            .respond(post(
+                &consume_url,
+                &serde_json::json!({ "passphrase": "pass" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(*body.message_data, "aHVudGVyMg==");
+        let res: tide::http::Response = app
+            .respond(post(
+                &consume_url,
+                &serde_json::json!({ "passphrase": "pass" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let res: tide::http::Response = app.respond(reply()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        // exactly one reply
+        let res: tide::http::Response = app.respond(reply()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let consume_reply = || {
+            tide::http::Request::new(
+                Method::Post,
+                Url::parse(&format!(
+                    "http://localhost/api/v1/messages/{}/consume",
+                    created.reply_token
+                ))
+                .unwrap(),
+            )
+        };
+        let mut res: tide::http::Response = app.respond(consume_reply()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(*body.message_data, "Z290IGl0");
+        // nothing of the channel is left
+        let res: tide::http::Response = app.respond(consume_reply()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+        let res: tide::http::Response = app
+            .respond(post(&consume_url, &serde_json::json!({})))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+}
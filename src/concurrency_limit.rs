@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tide::{Middleware, Next, Request};
+
+use crate::error::AppError;
+use crate::throttle::Throttled;
+
+// how soon a client rejected because of the load is asked to come back
+const RETRY_AFTER: Duration = Duration::from_secs(1);
+
+// requests over the limit are rejected right away instead of queueing up
+// in front of the database
+pub struct ConcurrencyLimitMiddleware {
+    max_in_flight: usize,
+    in_flight: Arc<AtomicUsize>,
+}
+
+struct InFlightPermit(Arc<AtomicUsize>);
+
+impl Drop for InFlightPermit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ConcurrencyLimitMiddleware {
+    pub fn new(max_in_flight: usize) -> Self {
+        ConcurrencyLimitMiddleware {
+            max_in_flight,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn try_acquire(&self) -> Option<InFlightPermit> {
+        self.in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |in_flight| {
+                (in_flight < self.max_in_flight).then_some(in_flight + 1)
+            })
+            .ok()
+            .map(|_| InFlightPermit(self.in_flight.clone()))
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ConcurrencyLimitMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let _permit = match self.try_acquire() {
+            Some(permit) => permit,
+            None => {
+                return Err(
+                    AppError::Busy(Throttled::new("Server is busy", RETRY_AFTER)).into_error(),
+                )
+            }
+        };
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorResponseMiddleware;
+    use tide::StatusCode;
+
+    #[test]
+    fn test_permits_are_limited_and_released() {
+        let middleware = ConcurrencyLimitMiddleware::new(2);
+
+        let first = middleware.try_acquire();
+        let second = middleware.try_acquire();
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(middleware.try_acquire().is_none());
+
+        drop(first);
+        assert!(middleware.try_acquire().is_some());
+    }
+
+    #[async_std::test]
+    async fn test_saturated_server_answers_with_retry_after() {
+        let mut app = tide::new();
+        app.with(ErrorResponseMiddleware);
+        app.with(ConcurrencyLimitMiddleware::new(0));
+        app.at("/").get(|_| async { Ok("home") });
+
+        let req = tide::http::Request::new(
+            tide::http::Method::Get,
+            tide::http::Url::parse("http://localhost/").unwrap(),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        assert_eq!(res["Retry-After"], "1");
+    }
+}
@@ -0,0 +1,227 @@
+use serde::{Deserialize, Serialize};
+use tide::http::Method;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::api::{MESSAGE_EXPIRES_IN_HEADER, MESSAGE_SHA256_HEADER};
+use crate::api_version::API_VERSION_HEADER;
+use crate::request_id::REQUEST_ID_HEADER;
+use crate::tus;
+
+// the HTML pages and the form endpoints are used only by our own pages
+const API_PATH_PREFIX: &str = "/api/";
+const ANY_ORIGIN: &str = "*";
+
+// no origins means that cross-origin requests are not allowed
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CorsConfig {
+    // e.g. "https://tools.example.com", or "*" to allow any origin
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub max_age_seconds: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec![
+                "Content-Type".to_string(),
+                REQUEST_ID_HEADER.to_string(),
+                API_VERSION_HEADER.to_string(),
+            ],
+            max_age_seconds: 600,
+        }
+    }
+}
+
+pub struct CorsMiddleware {
+    config: CorsConfig,
+    allowed_methods: String,
+    allowed_headers: String,
+}
+
+impl CorsMiddleware {
+    pub fn new(config: &CorsConfig) -> Self {
+        CorsMiddleware {
+            config: config.clone(),
+            allowed_methods: config.allowed_methods.join(", "),
+            allowed_headers: config.allowed_headers.join(", "),
+        }
+    }
+
+    fn allows_any_origin(&self) -> bool {
+        self.config.allowed_origins.iter().any(|o| o == ANY_ORIGIN)
+    }
+
+    fn is_allowed_origin(&self, origin: &str) -> bool {
+        self.allows_any_origin() || self.config.allowed_origins.iter().any(|o| o == origin)
+    }
+
+    fn add_origin_headers(&self, res: &mut Response, origin: &str) {
+        if self.allows_any_origin() {
+            res.insert_header("Access-Control-Allow-Origin", ANY_ORIGIN);
+        } else {
+            res.insert_header("Access-Control-Allow-Origin", origin);
+            // the response depends on the origin, caches should know about it
+            res.append_header("Vary", "Origin");
+        }
+        res.insert_header(
+            "Access-Control-Expose-Headers",
+            format!(
+                "{}, {}, {}, {}",
+                REQUEST_ID_HEADER,
+                MESSAGE_SHA256_HEADER,
+                MESSAGE_EXPIRES_IN_HEADER,
+                tus::EXPOSED_HEADERS
+            ),
+        );
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for CorsMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let origin = req
+            .header("Origin")
+            .map(|values| values.last().as_str().to_string());
+        // same-origin requests may carry the header too, so unknown origins are not
+        // rejected here, the browser blocks them when the CORS headers are missing
+        let origin = match origin {
+            Some(origin)
+                if req.url().path().starts_with(API_PATH_PREFIX)
+                    && self.is_allowed_origin(&origin) =>
+            {
+                origin
+            }
+            _ => return Ok(next.run(req).await),
+        };
+
+        let is_preflight = req.method() == Method::Options
+            && req.header("Access-Control-Request-Method").is_some();
+        if is_preflight {
+            let mut res = Response::new(StatusCode::NoContent);
+            self.add_origin_headers(&mut res, &origin);
+            res.insert_header(
+                "Access-Control-Allow-Methods",
+                self.allowed_methods.as_str(),
+            );
+            res.insert_header(
+                "Access-Control-Allow-Headers",
+                self.allowed_headers.as_str(),
+            );
+            res.insert_header(
+                "Access-Control-Max-Age",
+                self.config.max_age_seconds.to_string(),
+            );
+            return Ok(res);
+        }
+
+        let mut res = next.run(req).await;
+        self.add_origin_headers(&mut res, &origin);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tide::http::Url;
+
+    fn make_app(allowed_origins: &[&str]) -> tide::Server<()> {
+        let mut app = tide::new();
+        app.with(CorsMiddleware::new(&CorsConfig {
+            allowed_origins: allowed_origins.iter().map(|o| o.to_string()).collect(),
+            ..Default::default()
+        }));
+        app.at("/api/v1/messages").post(|_| async { Ok("created") });
+        app.at("/save").post(|_| async { Ok("saved") });
+        app
+    }
+
+    async fn send(
+        app: &tide::Server<()>,
+        method: Method,
+        path: &str,
+        origin: &str,
+    ) -> tide::http::Response {
+        let mut req = tide::http::Request::new(
+            method,
+            Url::parse(&format!("http://localhost{}", path)).unwrap(),
+        );
+        req.insert_header("Origin", origin);
+        if method == Method::Options {
+            req.insert_header("Access-Control-Request-Method", "POST");
+        }
+        app.respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_preflight_for_allowed_origin() {
+        let app = make_app(&["https://tools.example.com"]);
+
+        let res = send(
+            &app,
+            Method::Options,
+            "/api/v1/messages",
+            "https://tools.example.com",
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NoContent);
+        assert_eq!(
+            res["Access-Control-Allow-Origin"],
+            "https://tools.example.com"
+        );
+        assert_eq!(res["Access-Control-Allow-Methods"], "GET, POST");
+        assert_eq!(
+            res["Access-Control-Allow-Headers"],
+            "Content-Type, X-Request-Id, Api-Version"
+        );
+        assert_eq!(res["Access-Control-Max-Age"], "600");
+        assert_eq!(res["Vary"], "Origin");
+    }
+
+    #[async_std::test]
+    async fn test_simple_request_headers() {
+        let app = make_app(&["*"]);
+
+        let res = send(&app, Method::Post, "/api/v1/messages", "https://a.example").await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res["Access-Control-Allow-Origin"], "*");
+        assert!(res["Access-Control-Expose-Headers"]
+            .as_str()
+            .starts_with("X-Request-Id, Message-Sha256, Message-Expires-In, Location"));
+        assert!(res.header("Vary").is_none());
+
+        // only the JSON API is exposed to other origins
+        let res = send(&app, Method::Post, "/save", "https://a.example").await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(res.header("Access-Control-Allow-Origin").is_none());
+    }
+
+    #[async_std::test]
+    async fn test_unknown_origin_gets_no_cors_headers() {
+        let app = make_app(&["https://tools.example.com"]);
+
+        let res = send(
+            &app,
+            Method::Post,
+            "/api/v1/messages",
+            "https://evil.example",
+        )
+        .await;
+        assert!(res.header("Access-Control-Allow-Origin").is_none());
+
+        let res = send(
+            &app,
+            Method::Options,
+            "/api/v1/messages",
+            "https://evil.example",
+        )
+        .await;
+        assert!(res.header("Access-Control-Allow-Origin").is_none());
+        assert_ne!(res.status(), StatusCode::NoContent);
+    }
+}
@@ -0,0 +1,215 @@
+use async_std::io::ReadExt;
+use serde::{Deserialize, Serialize};
+use tide::http::{mime, Url};
+use tide::{Request, Response, StatusCode};
+use uuid::Uuid;
+
+use crate::access_log::mask_message_tokens;
+use crate::error::AppError;
+
+pub const NONCE_PLACEHOLDER: &str = "{{.CspNonce}}";
+pub const CSP_REPORT_PATH: &str = "/csp-report";
+// reports are small, anything bigger is not worth reading
+const MAX_REPORT_SIZE_BYTES: u64 = 16 * 1024;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CspConfig {
+    pub enabled: bool,
+    // allowed on top of 'self' and the per-request nonce
+    pub script_sources: Vec<String>,
+    pub style_sources: Vec<String>,
+    // set to CSP_REPORT_PATH to log the violations on this server
+    pub report_uri: Option<String>,
+}
+
+impl Default for CspConfig {
+    fn default() -> Self {
+        CspConfig {
+            enabled: true,
+            script_sources: Vec::new(),
+            style_sources: Vec::new(),
+            report_uri: None,
+        }
+    }
+}
+
+pub fn generate_nonce() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+fn make_source_list(nonce: &str, extra_sources: &[String]) -> String {
+    let mut sources = vec!["'self'".to_string(), format!("'nonce-{}'", nonce)];
+    sources.extend(extra_sources.iter().cloned());
+    sources.join(" ")
+}
+
+pub fn make_csp_header(config: &CspConfig, nonce: &str) -> String {
+    let mut directives = vec![
+        "default-src 'self'".to_string(),
+        format!(
+            "script-src {}",
+            make_source_list(nonce, &config.script_sources)
+        ),
+        format!(
+            "style-src {}",
+            make_source_list(nonce, &config.style_sources)
+        ),
+        "img-src 'self' data:".to_string(),
+        "connect-src 'self'".to_string(),
+        "frame-ancestors 'none'".to_string(),
+        "base-uri 'none'".to_string(),
+        "form-action 'self'".to_string(),
+    ];
+    if let Some(report_uri) = &config.report_uri {
+        directives.push(format!("report-uri {}", report_uri));
+    }
+    directives.join("; ")
+}
+
+// every page gets a fresh nonce, so injected markup can't guess it
+pub fn html_response(config: &CspConfig, html: &str) -> Response {
+    let nonce = generate_nonce();
+    let mut res = Response::builder(StatusCode::Ok)
+        .body(html.replace(NONCE_PLACEHOLDER, &nonce))
+        .content_type(mime::HTML)
+        .build();
+    if config.enabled {
+        res.insert_header("Content-Security-Policy", make_csp_header(config, &nonce));
+    }
+    res
+}
+
+#[derive(Deserialize)]
+struct ViolationReport {
+    #[serde(rename = "csp-report")]
+    csp_report: ViolationDetails,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ViolationDetails {
+    #[serde(default)]
+    document_uri: String,
+    #[serde(default)]
+    violated_directive: String,
+    #[serde(default)]
+    blocked_uri: String,
+}
+
+// the page URL contains the message token, it should not end up in the logs
+fn mask_document_uri(document_uri: &str) -> String {
+    match Url::parse(document_uri) {
+        Ok(mut url) => {
+            let path = mask_message_tokens(url.path());
+            url.set_path(&path);
+            url.set_query(None);
+            url.set_fragment(None);
+            url.to_string()
+        }
+        Err(_) => "-".to_string(),
+    }
+}
+
+pub async fn report_violation<State: Clone + Send + Sync + 'static>(
+    mut req: Request<State>,
+) -> tide::Result {
+    let mut body = Vec::new();
+    req.take_body()
+        .take(MAX_REPORT_SIZE_BYTES)
+        .read_to_end(&mut body)
+        .await?;
+
+    let report: ViolationReport = match serde_json::from_slice(&body) {
+        Ok(report) => report,
+        Err(_) => return Err(AppError::BadRequest("Invalid CSP report".to_string()).into_error()),
+    };
+
+    let details = report.csp_report;
+    log::warn!(
+        "Content-Security-Policy violation on {}: {} blocked {}",
+        mask_document_uri(&details.document_uri),
+        details.violated_directive,
+        details.blocked_uri
+    );
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tide::http::Method;
+
+    #[test]
+    fn test_make_csp_header() {
+        let config = CspConfig {
+            script_sources: vec!["https://cdn.example.com".to_string()],
+            report_uri: Some(CSP_REPORT_PATH.to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            make_csp_header(&config, "abc"),
+            "default-src 'self'; script-src 'self' 'nonce-abc' https://cdn.example.com; \
+            style-src 'self' 'nonce-abc'; img-src 'self' data:; connect-src 'self'; \
+            frame-ancestors 'none'; base-uri 'none'; form-action 'self'; report-uri /csp-report"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_html_response_uses_the_same_nonce() {
+        let mut res = html_response(&CspConfig::default(), "<script nonce=\"{{.CspNonce}}\">");
+        let header = res["Content-Security-Policy"].as_str().to_string();
+        let nonce = header
+            .split("'nonce-")
+            .nth(1)
+            .and_then(|rest| rest.split('\'').next())
+            .unwrap();
+
+        assert_eq!(nonce.len(), 32);
+        assert_eq!(
+            res.take_body().into_string().await.unwrap(),
+            format!("<script nonce=\"{}\">", nonce)
+        );
+
+        let disabled = CspConfig {
+            enabled: false,
+            ..Default::default()
+        };
+        let res = html_response(&disabled, "<html>");
+        assert!(res.header("Content-Security-Policy").is_none());
+    }
+
+    #[test]
+    fn test_mask_document_uri() {
+        assert_eq!(
+            mask_document_uri("https://1ts.dev/shared/abc-123?x=1#key"),
+            "https://1ts.dev/shared/:token"
+        );
+        assert_eq!(mask_document_uri("not a url"), "-");
+    }
+
+    #[async_std::test]
+    async fn test_report_violation() {
+        let mut app = tide::new();
+        app.at(CSP_REPORT_PATH).post(report_violation);
+
+        let mut req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://localhost/csp-report").unwrap(),
+        );
+        req.set_body(
+            r#"{"csp-report": {"document-uri": "https://1ts.dev/", "violated-directive": "script-src", "blocked-uri": "inline"}}"#,
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NoContent);
+
+        let mut req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://localhost/csp-report").unwrap(),
+        );
+        req.set_body("not json");
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+    }
+}
@@ -1,24 +1,287 @@
-use rusqlite::{params, Connection, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rusqlite::types::{Type, Value};
+use rusqlite::{params, Connection, Error, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
 use std::sync::{Arc, Mutex};
+use zeroize::Zeroizing;
+
+use uuid::Uuid;
+
+use crate::blob_store::BlobStore;
+use crate::blob_transfers::BlobTransfers;
+use crate::encryption::{EncryptionError, MessageCipher};
+use crate::integrity::{IntegrityError, MessageSigner};
+use crate::store::{
+    AdminCredential, AdminCredentialStore, ApiKey, DeliveryState, DownloadStore, ExpiredMessage,
+    Invitation, InvitationStore, MessageInfo, MessageOptions, MessageState, MessageStatus,
+    MessageStore, PendingDownload, PortalStore, ReadReceipt, SecretRequest, SecretRequestStore,
+    SettingsStore, StoreError, StoreResult, UserLimits, UserStore, WebhookAttempt, WebhookDelivery,
+    WebhookStore,
+};
+use crate::tokens::hash_token;
+use crate::zstd;
 
 const MINIMAL_VERSION: &str = "0.1";
-const LATEST_VERSION: &str = "0.1";
+const LATEST_VERSION: &str = "0.29";
+// a creator can ask what became of their message for a week after it's gone
+const MESSAGE_STATUS_RETENTION_SECONDS: i64 = 7 * 24 * 60 * 60;
+// the key of `encryptionKey`, also the one of the messages stored before keys had ids
+pub const DEFAULT_KEY_ID: &str = "default";
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageMode {
+    // everything is lost when the server is stopped
+    Memory,
+    #[default]
+    File,
+}
 
 pub struct OneTimeShareDb {
     conn: Arc<Mutex<Connection>>,
+    // message data is encrypted with the last key before it is written to the database,
+    // the others are kept to read the messages stored with them
+    ciphers: Vec<(String, MessageCipher)>,
+    // when set, message data bigger than the threshold is kept outside of the database
+    blob_transfers: Option<Arc<BlobTransfers>>,
+    blob_threshold_bytes: usize,
+    // when set, message data bigger than the threshold is compressed with zstd
+    compression_threshold_bytes: Option<usize>,
+    // when set, every message is stored with a tag and refused when it doesn't match it
+    signer: Option<MessageSigner>,
 }
 
 impl OneTimeShareDb {
     pub fn connect(path: &str) -> Result<Self> {
-        //let conn = Connection::open(path)?;
-        let conn = Connection::open_in_memory()?;
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    // the whole file is encrypted with SQLCipher, the key has to be set before anything is read
+    pub fn connect_with_key(path: &str, key: &str) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "key", key)?;
+        // plain SQLite ignores the key, the file would be written unencrypted without a word
+        let cipher_version: Option<String> = conn
+            .query_row("PRAGMA cipher_version", [], |row| row.get(0))
+            .optional()?;
+        if cipher_version.is_none() {
+            return Err(Error::ToSqlConversionFailure(Box::new(StoreError::new(
+                "A database key is set but the server is built without SQLCipher, enable the sqlcipher feature",
+            ))));
+        }
+        Self::from_connection(conn)
+    }
+
+    pub fn connect_in_memory() -> Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(conn: Connection) -> Result<Self> {
         let db = OneTimeShareDb {
             conn: Arc::new(Mutex::new(conn)),
+            ciphers: Vec::new(),
+            blob_transfers: None,
+            blob_threshold_bytes: 0,
+            compression_threshold_bytes: None,
+            signer: None,
         };
         db.init()?;
         Ok(db)
     }
 
+    pub fn set_cipher(&mut self, cipher: MessageCipher) {
+        self.add_cipher(DEFAULT_KEY_ID, cipher);
+    }
+
+    // the key added last encrypts the new messages
+    pub fn add_cipher(&mut self, key_id: &str, cipher: MessageCipher) {
+        self.ciphers.retain(|(id, _)| id != key_id);
+        self.ciphers.push((key_id.to_string(), cipher));
+    }
+
+    fn current_cipher(&self) -> Option<&(String, MessageCipher)> {
+        self.ciphers.last()
+    }
+
+    fn find_cipher(&self, key_id: Option<&str>) -> Option<&MessageCipher> {
+        let key_id = key_id.unwrap_or(DEFAULT_KEY_ID);
+        self.ciphers
+            .iter()
+            .find(|(id, _)| id == key_id)
+            .map(|(_, cipher)| cipher)
+    }
+
+    pub fn set_blob_store(&mut self, blob_store: Box<dyn BlobStore>, threshold_bytes: usize) {
+        self.blob_transfers = Some(Arc::new(BlobTransfers::new(blob_store)));
+        self.blob_threshold_bytes = threshold_bytes;
+    }
+
+    pub fn set_compression_threshold(&mut self, threshold_bytes: usize) {
+        self.compression_threshold_bytes = Some(threshold_bytes);
+    }
+
+    pub fn set_signer(&mut self, signer: MessageSigner) {
+        self.signer = Some(signer);
+    }
+
+    // a message without a tag is refused too, otherwise removing the tag would get past the check
+    fn verify_message_data(
+        &self,
+        message_token: &str,
+        expire_timestamp: i64,
+        data: &[u8],
+        tag: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let signer = match &self.signer {
+            Some(signer) => signer,
+            None => return Ok(()),
+        };
+        let result = match tag {
+            Some(tag) => signer.verify(message_token, expire_timestamp, data, &tag),
+            None => Err(IntegrityError(format!(
+                "Message {} has no integrity tag",
+                &hash_token(message_token)[..16]
+            ))),
+        };
+        result.map_err(|err| {
+            log::warn!("{}", err);
+            Error::FromSqlConversionFailure(0, Type::Blob, Box::new(err))
+        })
+    }
+
+    // the data is kept as it is when compression doesn't make it smaller
+    fn compress_message_data(
+        &self,
+        data: &[u8],
+        options: &MessageOptions,
+    ) -> (Zeroizing<Vec<u8>>, bool) {
+        match self.compression_threshold_bytes {
+            // data encrypted by the client doesn't compress
+            Some(threshold_bytes)
+                if data.len() > threshold_bytes && !options.is_client_encrypted =>
+            {
+                let compressed = Zeroizing::new(zstd::compress(data));
+                if compressed.len() < data.len() {
+                    return (compressed, true);
+                }
+                (Zeroizing::new(data.to_vec()), false)
+            }
+            _ => (Zeroizing::new(data.to_vec()), false),
+        }
+    }
+
+    fn decompress_message_data(
+        data: Zeroizing<Vec<u8>>,
+        is_compressed: bool,
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        if !is_compressed {
+            return Ok(data);
+        }
+        zstd::decompress(&data)
+            .map(Zeroizing::new)
+            .map_err(|err| Error::FromSqlConversionFailure(0, Type::Blob, Box::new(err)))
+    }
+
+    // returns the data to keep in the database and the key of the blob if it was moved out
+    fn offload_message_data(
+        &self,
+        data: Zeroizing<Vec<u8>>,
+    ) -> Result<(Zeroizing<Vec<u8>>, Option<String>)> {
+        match &self.blob_transfers {
+            Some(blob_transfers) if data.len() > self.blob_threshold_bytes => {
+                // the key is unrelated to the message token, so it can't be guessed from a link
+                let blob_key = Uuid::new_v4().to_string();
+                blob_transfers.put(blob_key.clone(), data);
+                Ok((Zeroizing::default(), Some(blob_key)))
+            }
+            _ => Ok((data, None)),
+        }
+    }
+
+    // the data can be unencrypted, so it's zeroized like everything else read from a message
+    fn load_message_data(
+        &self,
+        data: Vec<u8>,
+        blob_key: Option<String>,
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        let blob_key = match blob_key {
+            Some(blob_key) => blob_key,
+            None => return Ok(Zeroizing::new(data)),
+        };
+        let blob_transfers = self.blob_transfers.as_ref().ok_or_else(|| {
+            Error::FromSqlConversionFailure(
+                0,
+                Type::Blob,
+                Box::new(StoreError::new(
+                    "Message is kept in a blob store but no blob store is configured",
+                )),
+            )
+        })?;
+        blob_transfers
+            .get(&blob_key)
+            .map_err(|err| Error::FromSqlConversionFailure(0, Type::Blob, Box::new(err)))
+    }
+
+    // the blobs are deleted once the locks are let go of
+    fn delete_blobs(&self, blob_keys: &[String]) {
+        if let Some(blob_transfers) = &self.blob_transfers {
+            for blob_key in blob_keys {
+                blob_transfers.delete(blob_key);
+            }
+        }
+    }
+
+    // a failed delete only leaves an unreachable blob behind, so it doesn't fail the caller
+    fn delete_blobs_now(blob_store: &dyn BlobStore, blob_keys: &[String]) {
+        for blob_key in blob_keys {
+            if let Err(err) = blob_store.delete(blob_key) {
+                log::warn!("Failed to delete blob {}: {}", blob_key, err);
+            }
+        }
+    }
+
+    // returns the id of the key the data was encrypted with
+    fn encrypt_message_data(
+        &self,
+        data: Zeroizing<Vec<u8>>,
+    ) -> Result<(Zeroizing<Vec<u8>>, Option<String>)> {
+        match self.current_cipher() {
+            Some((key_id, cipher)) => {
+                let encrypted = cipher
+                    .encrypt(&data)
+                    .map_err(|err| Error::ToSqlConversionFailure(Box::new(err)))?;
+                Ok((Zeroizing::new(encrypted), Some(key_id.clone())))
+            }
+            None => Ok((data, None)),
+        }
+    }
+
+    fn decrypt_message_data(
+        &self,
+        data: Zeroizing<Vec<u8>>,
+        is_encrypted: bool,
+        key_id: Option<&str>,
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        if !is_encrypted {
+            return Ok(data);
+        }
+        match self.find_cipher(key_id) {
+            Some(cipher) => cipher
+                .decrypt(&data)
+                .map(Zeroizing::new)
+                .map_err(|err| Error::FromSqlConversionFailure(0, Type::Blob, Box::new(err))),
+            None => Err(Error::FromSqlConversionFailure(
+                0,
+                Type::Blob,
+                Box::new(EncryptionError(format!(
+                    "Message is encrypted with key {} which is not configured",
+                    key_id.unwrap_or(DEFAULT_KEY_ID)
+                ))),
+            )),
+        }
+    }
+
     fn init(&self) -> Result<()> {
         let conn = self.conn.lock().unwrap();
 
@@ -38,7 +301,36 @@ impl OneTimeShareDb {
                 retention_limit_minutes INTEGER NOT NULL,
                 max_size_bytes INTEGER NOT NULL,
                 message_creation_limit_minutes INTEGER NOT NULL,
-                last_message_creation_timestamp INTEGER
+                message_creation_limit_count INTEGER NOT NULL DEFAULT 1,
+                active_message_limit INTEGER NOT NULL DEFAULT 0,
+                monthly_byte_quota INTEGER NOT NULL DEFAULT 0,
+                plan TEXT,
+                custom_slugs INTEGER NOT NULL DEFAULT 0,
+                webhook_url TEXT,
+                email TEXT,
+                account_id INTEGER,
+                key_name TEXT,
+                created_timestamp INTEGER
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS monthly_usage (
+                user_token TEXT NOT NULL,
+                month TEXT NOT NULL,
+                bytes INTEGER NOT NULL,
+                PRIMARY KEY (user_token, month)
+            )",
+            [],
+        )?;
+
+        // creation times within the limit window of each user
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS message_creations (
+                id INTEGER PRIMARY KEY,
+                user_token TEXT NOT NULL,
+                timestamp INTEGER NOT NULL
             )",
             [],
         )?;
@@ -48,16 +340,184 @@ impl OneTimeShareDb {
                 id INTEGER PRIMARY KEY,
                 message_token TEXT NOT NULL UNIQUE,
                 expire_timestamp INTEGER NOT NULL,
-                data TEXT NOT NULL
+                data BLOB NOT NULL,
+                passphrase_hash TEXT,
+                failed_passphrase_attempts INTEGER NOT NULL DEFAULT 0,
+                is_encrypted INTEGER NOT NULL DEFAULT 0,
+                is_client_encrypted INTEGER NOT NULL DEFAULT 0,
+                filename TEXT,
+                content_type TEXT,
+                blob_key TEXT,
+                user_token TEXT,
+                is_compressed INTEGER NOT NULL DEFAULT 0,
+                checksum TEXT,
+                integrity_tag BLOB,
+                encryption_key_id TEXT,
+                slug TEXT,
+                delete_token TEXT,
+                created_timestamp INTEGER,
+                webhook_url TEXT,
+                remaining_views INTEGER NOT NULL DEFAULT 1,
+                grace_minutes INTEGER NOT NULL DEFAULT 0,
+                grace_until INTEGER,
+                not_before INTEGER,
+                first_authorization_hash TEXT,
+                second_authorization_hash TEXT,
+                totp_secret BLOB,
+                totp_last_step INTEGER,
+                pin_hash TEXT,
+                failed_pin_attempts INTEGER NOT NULL DEFAULT 0,
+                allowed_ips TEXT
+            )",
+            [],
+        )?;
+
+        // what became of the messages that are gone, for their creators to ask about, kept
+        // only for the messages that had a delete token
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS message_statuses (
+                message_token TEXT NOT NULL UNIQUE,
+                slug TEXT,
+                delete_token TEXT NOT NULL,
+                state TEXT NOT NULL,
+                created_timestamp INTEGER,
+                expire_timestamp INTEGER NOT NULL,
+                removed_timestamp INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        // kept as long as the status of the message
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS message_receipts (
+                message_token TEXT NOT NULL UNIQUE,
+                read_timestamp INTEGER NOT NULL,
+                client_ip TEXT,
+                user_agent TEXT
+            )",
+            [],
+        )?;
+
+        // events on their way to webhooks, kept for a while after they are done
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                id INTEGER PRIMARY KEY,
+                url TEXT NOT NULL,
+                event TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                state TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                created_timestamp INTEGER NOT NULL,
+                next_attempt_timestamp INTEGER,
+                last_attempt_timestamp INTEGER,
+                last_status INTEGER,
+                last_error TEXT
+            )",
+            [],
+        )?;
+
+        // requests for secrets that nobody answered yet
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS secret_requests (
+                id INTEGER PRIMARY KEY,
+                request_token TEXT NOT NULL UNIQUE,
+                note TEXT,
+                expire_timestamp INTEGER NOT NULL,
+                max_size_bytes INTEGER NOT NULL,
+                retention_minutes INTEGER NOT NULL,
+                sealed_response_token BLOB NOT NULL,
+                sealed_message_token BLOB
+            )",
+            [],
+        )?;
+
+        // the consumed files whose downloads can still be resumed, never in the blob store, an
+        // upload that failed after the message was gone would lose the file
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS downloads (
+                id INTEGER PRIMARY KEY,
+                download_id TEXT NOT NULL UNIQUE,
+                data BLOB NOT NULL,
+                is_encrypted INTEGER NOT NULL,
+                encryption_key_id TEXT,
+                integrity_tag BLOB,
+                filename TEXT,
+                content_type TEXT,
+                checksum TEXT,
+                expire_timestamp INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        // the people who signed in to the portal through the IdP, their keys are in users
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS portal_accounts (
+                id INTEGER PRIMARY KEY,
+                issuer TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                email TEXT,
+                created_timestamp INTEGER NOT NULL,
+                last_sign_in_timestamp INTEGER NOT NULL,
+                UNIQUE (issuer, subject)
+            )",
+            [],
+        )?;
+
+        // registrations whose email address isn't confirmed yet
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS registrations (
+                token TEXT PRIMARY KEY,
+                email TEXT NOT NULL,
+                expire_timestamp INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        // links made by the admins that give a user token, until they are used
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS invitations (
+                id INTEGER PRIMARY KEY,
+                token TEXT NOT NULL UNIQUE,
+                plan TEXT,
+                note TEXT,
+                created_timestamp INTEGER NOT NULL,
+                expire_timestamp INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        // passkeys of the admins
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS admin_credentials (
+                id INTEGER PRIMARY KEY,
+                credential_id TEXT NOT NULL UNIQUE,
+                public_key BLOB NOT NULL,
+                sign_count INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                created_timestamp INTEGER NOT NULL,
+                last_used_timestamp INTEGER
             )",
             [],
         )?;
 
         conn.execute("CREATE INDEX IF NOT EXISTS token_index ON users(token)", [])?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS message_creation_user_index ON message_creations(user_token)",
+            [],
+        )?;
         conn.execute(
             "CREATE INDEX IF NOT EXISTS message_token_index ON messages(message_token)",
             [],
         )?;
+        // a database from before the slugs gets the column and the index when it's updated
+        let has_slug_column: bool = conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('messages') WHERE name='slug'",
+            [],
+            |row| row.get(0),
+        )?;
+        if has_slug_column {
+            create_slug_index(&conn)?;
+        }
 
         Ok(())
     }
@@ -81,236 +541,3724 @@ impl OneTimeShareDb {
         Ok(())
     }
 
-    pub fn set_user_limits(
-        &self,
-        token: &str,
-        retention_limit_minutes: i32,
-        max_size_bytes: i32,
-        message_creation_limit_minutes: i32,
-    ) -> Result<()> {
+    pub fn get_global_integer(&self, name: &str) -> Result<Option<i64>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT integer_value FROM global_vars WHERE name=?1")?;
+        let mut rows = stmt.query(params![name])?;
+        match rows.next()? {
+            Some(row) => row.get(0),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_global_integer(&self, name: &str, value: i64) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO global_vars (name, integer_value) VALUES (?1, ?2)
+            ON CONFLICT(name) DO UPDATE SET integer_value=?2",
+            params![name, value],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_global_var(&self, name: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM global_vars WHERE name=?1", params![name])?;
+        Ok(())
+    }
+
+    pub fn set_user_limits(&self, token: &str, limits: &UserLimits) -> Result<()> {
+        let token = hash_token(token);
         let conn = self.conn.lock().unwrap();
         conn.execute(
-            "INSERT INTO users (token, retention_limit_minutes, max_size_bytes, message_creation_limit_minutes) VALUES (?1, ?2, ?3, ?4)
-            ON CONFLICT(token) DO UPDATE SET retention_limit_minutes=?2, max_size_bytes=?3, message_creation_limit_minutes=?4",
-            params![token, retention_limit_minutes, max_size_bytes, message_creation_limit_minutes],
+            "INSERT INTO users (token, retention_limit_minutes, max_size_bytes, message_creation_limit_minutes, message_creation_limit_count, active_message_limit, monthly_byte_quota, custom_slugs) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            ON CONFLICT(token) DO UPDATE SET retention_limit_minutes=?2, max_size_bytes=?3, message_creation_limit_minutes=?4, message_creation_limit_count=?5, active_message_limit=?6, monthly_byte_quota=?7, custom_slugs=?8",
+            params![
+                token,
+                limits.retention_limit_minutes,
+                limits.max_message_size_bytes,
+                limits.message_creation_limit_minutes,
+                limits.message_creation_limit_count,
+                limits.active_message_limit,
+                limits.monthly_byte_quota,
+                limits.custom_slugs
+            ],
         )?;
         Ok(())
     }
 
-    pub fn get_user_limits(&self, token: &str) -> Result<(bool, u32, u32, u32)> {
+    pub fn get_user_limits(&self, token: &str) -> Result<Option<UserLimits>> {
+        let token = hash_token(token);
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT retention_limit_minutes, max_size_bytes, message_creation_limit_minutes FROM users WHERE token=?1")?;
+        let mut stmt = conn.prepare("SELECT retention_limit_minutes, max_size_bytes, message_creation_limit_minutes, message_creation_limit_count, active_message_limit, monthly_byte_quota, custom_slugs FROM users WHERE token=?1")?;
         let mut rows = stmt.query(params![token])?;
         if let Some(row) = rows.next()? {
-            Ok((true, row.get(0)?, row.get(1)?, row.get(2)?))
+            Ok(Some(UserLimits {
+                retention_limit_minutes: row.get(0)?,
+                max_message_size_bytes: row.get(1)?,
+                message_creation_limit_minutes: row.get(2)?,
+                message_creation_limit_count: row.get(3)?,
+                active_message_limit: row.get(4)?,
+                monthly_byte_quota: row.get(5)?,
+                custom_slugs: row.get(6)?,
+            }))
         } else {
-            Ok((false, 0, 0, 0))
+            Ok(None)
+        }
+    }
+
+    pub fn set_user_plan(&self, token: &str, plan: Option<&str>) -> Result<()> {
+        let token = hash_token(token);
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO users (token, retention_limit_minutes, max_size_bytes, message_creation_limit_minutes, plan) VALUES (?1, 0, 0, 0, ?2)
+            ON CONFLICT(token) DO UPDATE SET plan=?2",
+            params![token, plan],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_user_plan(&self, token: &str) -> Result<Option<String>> {
+        let token = hash_token(token);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT plan FROM users WHERE token=?1")?;
+        let mut rows = stmt.query(params![token])?;
+        match rows.next()? {
+            Some(row) => row.get(0),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_user_webhook_url(&self, token: &str, webhook_url: Option<&str>) -> Result<()> {
+        let token = hash_token(token);
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO users (token, retention_limit_minutes, max_size_bytes, message_creation_limit_minutes, webhook_url) VALUES (?1, 0, 0, 0, ?2)
+            ON CONFLICT(token) DO UPDATE SET webhook_url=?2",
+            params![token, webhook_url],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_user_webhook_url(&self, token: &str) -> Result<Option<String>> {
+        let token = hash_token(token);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT webhook_url FROM users WHERE token=?1")?;
+        let mut rows = stmt.query(params![token])?;
+        match rows.next()? {
+            Some(row) => row.get(0),
+            None => Ok(None),
         }
     }
 
-    pub fn set_user_last_message_creation_time(&self, token: &str, timestamp: i64) -> Result<()> {
+    pub fn set_user_email(&self, token: &str, email: Option<&str>) -> Result<()> {
+        let token = hash_token(token);
         let conn = self.conn.lock().unwrap();
         conn.execute(
-            "UPDATE users SET last_message_creation_timestamp=?1 WHERE token=?2",
-            params![timestamp, token],
+            "INSERT INTO users (token, retention_limit_minutes, max_size_bytes, message_creation_limit_minutes, email) VALUES (?1, 0, 0, 0, ?2)
+            ON CONFLICT(token) DO UPDATE SET email=?2",
+            params![token, email],
         )?;
         Ok(())
     }
 
-    pub fn get_user_last_message_creation_time(&self, token: &str) -> Result<i64> {
+    pub fn get_user_email(&self, token: &str) -> Result<Option<String>> {
+        let token = hash_token(token);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT email FROM users WHERE token=?1")?;
+        let mut rows = stmt.query(params![token])?;
+        match rows.next()? {
+            Some(row) => row.get(0),
+            None => Ok(None),
+        }
+    }
+
+    pub fn count_active_user_messages(&self, token: &str, timestamp: i64) -> Result<u32> {
+        let token = hash_token(token);
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT COUNT(*) FROM messages WHERE user_token=?1 AND (expire_timestamp=0 OR expire_timestamp>=?2)",
+            params![token, timestamp],
+            |row| row.get(0),
+        )
+    }
+
+    pub fn get_monthly_usage(&self, token: &str, month: &str) -> Result<u64> {
+        let token = hash_token(token);
         let conn = self.conn.lock().unwrap();
         let mut stmt =
-            conn.prepare("SELECT last_message_creation_timestamp FROM users WHERE token=?1")?;
-        let timestamp = stmt
-            .query_row(params![token], |row| row.get(0))
-            .unwrap_or(0);
-        Ok(timestamp)
+            conn.prepare("SELECT bytes FROM monthly_usage WHERE user_token=?1 AND month=?2")?;
+        let mut rows = stmt.query(params![token, month])?;
+        match rows.next()? {
+            Some(row) => row.get(0),
+            None => Ok(0),
+        }
+    }
+
+    pub fn add_monthly_usage(&self, token: &str, month: &str, bytes: u64) -> Result<()> {
+        let token = hash_token(token);
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO monthly_usage (user_token, month, bytes) VALUES (?1, ?2, ?3)
+            ON CONFLICT(user_token, month) DO UPDATE SET bytes=bytes+?3",
+            params![token, month, bytes],
+        )?;
+        Ok(())
+    }
+
+    // the check and the record happen under one lock, so concurrent requests can't both take
+    // the last free slot of the window
+    pub fn try_register_message_creation(
+        &self,
+        token: &str,
+        timestamp: i64,
+        window_seconds: i64,
+        max_count: u32,
+    ) -> Result<Option<i64>> {
+        let token = hash_token(token);
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM message_creations WHERE user_token=?1 AND timestamp<=?2",
+            params![token, timestamp - window_seconds],
+        )?;
+        let (count, oldest_timestamp): (u32, Option<i64>) = conn.query_row(
+            "SELECT COUNT(*), MIN(timestamp) FROM message_creations WHERE user_token=?1",
+            params![token],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        if count >= max_count {
+            let oldest_timestamp = oldest_timestamp.unwrap_or(timestamp);
+            return Ok(Some(oldest_timestamp + window_seconds - timestamp));
+        }
+        conn.execute(
+            "INSERT INTO message_creations (user_token, timestamp) VALUES (?1, ?2)",
+            params![token, timestamp],
+        )?;
+        Ok(None)
     }
 
     pub fn save_message(
         &self,
         message_token: &str,
         expire_timestamp: i64,
-        data: &str,
+        data: &[u8],
+        options: &MessageOptions,
     ) -> Result<()> {
+        // the tag is made for the name the message is handed out under
+        let name = options.slug.as_deref().unwrap_or(message_token);
+        let integrity_tag = self
+            .signer
+            .as_ref()
+            .map(|signer| signer.tag(name, expire_timestamp, data));
+        let (data, is_compressed) = self.compress_message_data(data, options);
+        let (data, encryption_key_id) = self.encrypt_message_data(data)?;
+        let (data, blob_key) = self.offload_message_data(data)?;
+        // with the key of the data, so it's re-encrypted along with it
+        let totp_secret = match &options.totp_secret {
+            Some(totp_secret) => Some(self.encrypt_message_data(totp_secret.clone())?.0),
+            None => None,
+        };
+        let user_token_hash = options.user_token.as_deref().map(hash_token);
         let conn = self.conn.lock().unwrap();
         conn.execute(
-            "INSERT INTO messages (message_token, expire_timestamp, data) VALUES (?1, ?2, ?3)",
-            params![message_token, expire_timestamp, data],
+            "INSERT INTO messages (message_token, expire_timestamp, data, passphrase_hash, is_encrypted, is_client_encrypted, filename, content_type, blob_key, user_token, is_compressed, checksum, integrity_tag, encryption_key_id, slug, delete_token, created_timestamp, webhook_url, remaining_views, grace_minutes, not_before, first_authorization_hash, second_authorization_hash, totp_secret, pin_hash, allowed_ips) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, CAST(strftime('%s', 'now') AS INTEGER), ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25)",
+            params![
+                hash_token(message_token),
+                expire_timestamp,
+                *data,
+                options.passphrase_hash,
+                encryption_key_id.is_some(),
+                options.is_client_encrypted,
+                options.filename,
+                options.content_type,
+                blob_key,
+                user_token_hash,
+                is_compressed,
+                options.checksum,
+                integrity_tag,
+                encryption_key_id,
+                options.slug.as_deref().map(hash_token),
+                options.delete_token.as_deref().map(hash_token),
+                options.webhook_url,
+                options.max_views.max(1),
+                options.grace_minutes,
+                options.not_before,
+                options
+                    .authorization_tokens
+                    .as_ref()
+                    .map(|tokens| hash_token(&tokens[0])),
+                options
+                    .authorization_tokens
+                    .as_ref()
+                    .map(|tokens| hash_token(&tokens[1])),
+                totp_secret.as_deref().map(|totp_secret| &totp_secret[..]),
+                options.pin_hash,
+                // the validated entries have no commas
+                (!options.allowed_ips.is_empty()).then(|| options.allowed_ips.join(","))
+            ],
         )?;
         Ok(())
     }
 
-    pub fn try_consume_message(&self, message_token: &str) -> Result<(Option<String>, i64)> {
+    pub fn try_consume_message(
+        &self,
+        message_token: &str,
+    ) -> Result<(Option<Zeroizing<Vec<u8>>>, i64)> {
+        let token_hash = hash_token(message_token);
         let conn = self.conn.lock().unwrap();
-        let mut stmt =
-            conn.prepare("SELECT id, data, expire_timestamp FROM messages WHERE message_token=?1")?;
-        let mut rows = stmt.query(params![message_token])?;
+        let mut stmt = conn.prepare(
+            "SELECT id, data, expire_timestamp, is_encrypted, blob_key, is_compressed, integrity_tag, encryption_key_id, remaining_views, grace_minutes, grace_until<CAST(strftime('%s', 'now') AS INTEGER) FROM messages WHERE (message_token=?1 OR slug=?1)",
+        )?;
+        let mut rows = stmt.query(params![token_hash])?;
         if let Some(row) = rows.next()? {
             let id: i32 = row.get(0)?;
-            let data: String = row.get(1)?;
+            let data: Vec<u8> = row.get(1)?;
             let expire_timestamp: i64 = row.get(2)?;
-            conn.execute("DELETE FROM messages WHERE id=?1", params![id])?;
+            let is_encrypted: bool = row.get(3)?;
+            let blob_key: Option<String> = row.get(4)?;
+            let is_compressed: bool = row.get(5)?;
+            let integrity_tag: Option<Vec<u8>> = row.get(6)?;
+            let encryption_key_id: Option<String> = row.get(7)?;
+            let remaining_views: u32 = row.get(8)?;
+            let grace_minutes: u32 = row.get(9)?;
+            // None until the grace window after the last view starts
+            let is_grace_over: Option<bool> = row.get(10)?;
+            if is_grace_over == Some(true) {
+                // read already, it's only waiting for the cleanup
+                record_message_status(
+                    &conn,
+                    "id=?2",
+                    params![MessageState::Consumed.as_str(), id],
+                )?;
+                conn.execute("DELETE FROM messages WHERE id=?1", params![id])?;
+                self.delete_blobs(blob_key.as_slice());
+                return Ok((None, 0));
+            }
+            // a view that isn't the last only takes one off, under the same lock as the read;
+            // no views are left in the grace window, the message is only read again until it ends
+            let is_last_view = is_grace_over.is_none()
+                && (remaining_views <= 1
+                    || conn.execute(
+                        "UPDATE messages SET remaining_views=remaining_views-1 WHERE id=?1 AND remaining_views>1",
+                        params![id],
+                    )? == 0);
+            if is_last_view && grace_minutes > 0 {
+                conn.execute(
+                    "UPDATE messages SET remaining_views=0, grace_until=CAST(strftime('%s', 'now') AS INTEGER)+grace_minutes*60 WHERE id=?1",
+                    params![id],
+                )?;
+            }
+            let data = if is_last_view && grace_minutes == 0 {
+                // a message that fails the check is gone as well, it can't be trusted later either
+                record_message_status(
+                    &conn,
+                    "id=?2",
+                    params![MessageState::Consumed.as_str(), id],
+                )?;
+                conn.execute("DELETE FROM messages WHERE id=?1", params![id])?;
+                let data = self.load_message_data(data, blob_key.clone())?;
+                self.delete_blobs(blob_key.as_slice());
+                data
+            } else {
+                self.load_message_data(data, blob_key)?
+            };
+            let data =
+                self.decrypt_message_data(data, is_encrypted, encryption_key_id.as_deref())?;
+            let data = Self::decompress_message_data(data, is_compressed)?;
+            self.verify_message_data(message_token, expire_timestamp, &data, integrity_tag)?;
             Ok((Some(data), expire_timestamp))
         } else {
             Ok((None, 0))
         }
     }
 
-    pub fn remove_user_by_token(&self, token: &str) -> Result<()> {
+    pub fn get_message_info(&self, message_token: &str) -> Result<Option<MessageInfo>> {
+        let token_hash = hash_token(message_token);
         let conn = self.conn.lock().unwrap();
-        conn.execute("DELETE FROM users WHERE token=?1", params![token])?;
-        Ok(())
+        let mut stmt = conn.prepare(
+            "SELECT data, expire_timestamp, passphrase_hash IS NOT NULL, is_encrypted, is_client_encrypted, filename, content_type, blob_key, is_compressed, checksum, integrity_tag, encryption_key_id, remaining_views, not_before, first_authorization_hash IS NOT NULL, totp_secret IS NOT NULL, pin_hash IS NOT NULL FROM messages WHERE (message_token=?1 OR slug=?1)",
+        )?;
+        let mut rows = stmt.query(params![token_hash])?;
+        if let Some(row) = rows.next()? {
+            let data = self.load_message_data(row.get(0)?, row.get(7)?)?;
+            let encryption_key_id: Option<String> = row.get(11)?;
+            let data =
+                self.decrypt_message_data(data, row.get(3)?, encryption_key_id.as_deref())?;
+            let data = Self::decompress_message_data(data, row.get(8)?)?;
+            let expire_timestamp = row.get(1)?;
+            self.verify_message_data(message_token, expire_timestamp, &data, row.get(10)?)?;
+            Ok(Some(MessageInfo {
+                data,
+                expire_timestamp,
+                has_passphrase: row.get(2)?,
+                is_client_encrypted: row.get(4)?,
+                filename: row.get(5)?,
+                content_type: row.get(6)?,
+                checksum: row.get(9)?,
+                remaining_views: row.get(12)?,
+                not_before: row.get(13)?,
+                is_dual_control: row.get(14)?,
+                is_totp_protected: row.get(15)?,
+                has_pin: row.get(16)?,
+            }))
+        } else {
+            Ok(None)
+        }
     }
 
-    pub fn clear_expired_messages(&self, limit_timestamp: i64) -> Result<()> {
+    // None when there is no such message
+    pub fn get_message_remaining_views(&self, message_token: &str) -> Result<Option<u32>> {
         let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "DELETE FROM messages WHERE expire_timestamp<?1",
-            params![limit_timestamp],
-        )?;
-        Ok(())
+        conn.query_row(
+            "SELECT remaining_views FROM messages WHERE (message_token=?1 OR slug=?1)",
+            params![hash_token(message_token)],
+            |row| row.get(0),
+        )
+        .optional()
     }
-}
 
-pub fn update_version(db: &OneTimeShareDb) -> Result<()> {
-    let current_version = db.get_database_version()?;
-    if current_version != LATEST_VERSION {
-        let updaters = make_updaters(&current_version, LATEST_VERSION);
-        for updater in updaters {
-            updater.update_db(db)?;
-        }
+    // reads neither the data nor the tag, the message isn't verified by it
+    pub fn get_message_expire_timestamp(&self, message_token: &str) -> Result<Option<i64>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT expire_timestamp FROM messages WHERE (message_token=?1 OR slug=?1)",
+            params![hash_token(message_token)],
+            |row| row.get(0),
+        )
+        .optional()
     }
-    db.set_database_version(LATEST_VERSION)?;
-    Ok(())
-}
 
-fn make_updaters(version_from: &str, version_to: &str) -> Vec<DbUpdater> {
-    let all_updaters = make_all_updaters();
-    let mut updaters = Vec::new();
-    let mut is_first_found = version_from == MINIMAL_VERSION;
+    // None when there is no such message or it's kept in the database
+    pub fn get_message_blob_key(&self, message_token: &str) -> Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT blob_key FROM messages WHERE (message_token=?1 OR slug=?1)",
+            params![hash_token(message_token)],
+            |row| row.get(0),
+        )
+        .optional()
+        .map(Option::flatten)
+    }
 
-    for updater in all_updaters {
-        if is_first_found {
-            updaters.push(updater.clone());
-            if updater.version == version_to {
-                break;
-            }
-        } else if updater.version == version_from {
-            is_first_found = true;
+    // the messages whose data never made it to the blob store
+    pub fn remove_messages_of_blobs(&self, blob_keys: &[String]) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        for blob_key in blob_keys {
+            conn.execute("DELETE FROM messages WHERE blob_key=?1", params![blob_key])?;
         }
+        Ok(())
     }
 
-    if !updaters.is_empty() && updaters.last().unwrap().version != version_to {
-        panic!(
-            "Last version updater not found. Expected: {} Found: {}",
+    pub fn blob_transfers(&self) -> Option<Arc<BlobTransfers>> {
+        self.blob_transfers.clone()
+    }
+
+    // the hashes of the two tokens that are needed together, None when one person can read it
+    pub fn get_message_authorization_hashes(
+        &self,
+        message_token: &str,
+    ) -> Result<Option<[String; 2]>> {
+        let conn = self.conn.lock().unwrap();
+        let hashes: Option<(Option<String>, Option<String>)> = conn
+            .query_row(
+                "SELECT first_authorization_hash, second_authorization_hash FROM messages WHERE (message_token=?1 OR slug=?1)",
+                params![hash_token(message_token)],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(match hashes {
+            Some((Some(first), Some(second))) => Some([first, second]),
+            _ => None,
+        })
+    }
+
+    // the decrypted authenticator secret, None when no code is needed to read the message
+    pub fn get_message_totp_secret(
+        &self,
+        message_token: &str,
+    ) -> Result<Option<Zeroizing<Vec<u8>>>> {
+        let conn = self.conn.lock().unwrap();
+        let row: Option<(Option<Vec<u8>>, bool, Option<String>)> = conn
+            .query_row(
+                "SELECT totp_secret, is_encrypted, encryption_key_id FROM messages WHERE (message_token=?1 OR slug=?1)",
+                params![hash_token(message_token)],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        drop(conn);
+        match row {
+            Some((Some(totp_secret), is_encrypted, key_id)) => {
+                Ok(Some(self.decrypt_message_data(
+                    Zeroizing::new(totp_secret),
+                    is_encrypted,
+                    key_id.as_deref(),
+                )?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // moves the last used step forward, a code of that step or an earlier one is refused after
+    pub fn use_message_totp_step(&self, message_token: &str, step: u64) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let changed = conn.execute(
+            "UPDATE messages SET totp_last_step=?2 WHERE (message_token=?1 OR slug=?1) AND IFNULL(totp_last_step, -1)<?2",
+            params![hash_token(message_token), step as i64],
+        )?;
+        Ok(changed > 0)
+    }
+
+    // None when there is no such message, like the expiry it's known without reading the data
+    pub fn is_message_client_encrypted(&self, message_token: &str) -> Result<Option<bool>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT is_client_encrypted FROM messages WHERE (message_token=?1 OR slug=?1)",
+            params![hash_token(message_token)],
+            |row| row.get(0),
+        )
+        .optional()
+    }
+
+    // None when there is no such message or it can be read at any time
+    pub fn get_message_not_before(&self, message_token: &str) -> Result<Option<i64>> {
+        let conn = self.conn.lock().unwrap();
+        let not_before = conn
+            .query_row(
+                "SELECT not_before FROM messages WHERE (message_token=?1 OR slug=?1)",
+                params![hash_token(message_token)],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(not_before.flatten())
+    }
+
+    pub fn get_message_passphrase_hash(&self, message_token: &str) -> Result<Option<String>> {
+        let token_hash = hash_token(message_token);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT passphrase_hash FROM messages WHERE (message_token=?1 OR slug=?1)")?;
+        let passphrase_hash = stmt
+            .query_row(params![token_hash], |row| row.get(0))
+            .unwrap_or(None);
+        Ok(passphrase_hash)
+    }
+
+    pub fn get_message_pin_hash(&self, message_token: &str) -> Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        let pin_hash: Option<Option<String>> = conn
+            .query_row(
+                "SELECT pin_hash FROM messages WHERE (message_token=?1 OR slug=?1)",
+                params![hash_token(message_token)],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(pin_hash.flatten())
+    }
+
+    pub fn get_message_allowed_ips(&self, message_token: &str) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let allowed_ips: Option<Option<String>> = conn
+            .query_row(
+                "SELECT allowed_ips FROM messages WHERE (message_token=?1 OR slug=?1)",
+                params![hash_token(message_token)],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(allowed_ips
+            .flatten()
+            .map(|allowed_ips| allowed_ips.split(',').map(str::to_string).collect())
+            .unwrap_or_default())
+    }
+
+    // the webhook of the message, or the one of its creator when it has none
+    pub fn get_message_webhook_url(&self, message_token: &str) -> Result<Option<String>> {
+        let token_hash = hash_token(message_token);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT COALESCE(messages.webhook_url, users.webhook_url) FROM messages
+            LEFT JOIN users ON users.token=messages.user_token
+            WHERE (messages.message_token=?1 OR messages.slug=?1)",
+        )?;
+        let mut rows = stmt.query(params![token_hash])?;
+        match rows.next()? {
+            Some(row) => row.get(0),
+            None => Ok(None),
+        }
+    }
+
+    // the email of the creator of the message, None for anonymous and unknown messages
+    pub fn get_message_creator_email(&self, message_token: &str) -> Result<Option<String>> {
+        let token_hash = hash_token(message_token);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT users.email FROM messages
+            JOIN users ON users.token=messages.user_token
+            WHERE (messages.message_token=?1 OR messages.slug=?1)",
+        )?;
+        let mut rows = stmt.query(params![token_hash])?;
+        match rows.next()? {
+            Some(row) => row.get(0),
+            None => Ok(None),
+        }
+    }
+
+    // a slug can't be the name of another message, be it a slug or a token
+    pub fn is_message_name_taken(&self, name: &str) -> Result<bool> {
+        let name_hash = hash_token(name);
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM messages WHERE message_token=?1 OR slug=?1)",
+            params![name_hash],
+            |row| row.get(0),
+        )
+    }
+
+    // removes the message if the delete token is the one it was created with, returns false
+    // when there is no such message or the delete token is wrong
+    pub fn delete_message(&self, message_token: &str, delete_token: &str) -> Result<bool> {
+        let token_hash = hash_token(message_token);
+        let delete_token_hash = hash_token(delete_token);
+        let conn = self.conn.lock().unwrap();
+        let blob_keys = select_blob_keys(
+            &conn,
+            "SELECT blob_key FROM messages WHERE (message_token=?1 OR slug=?1) AND delete_token=?2 AND blob_key IS NOT NULL",
+            params![token_hash, delete_token_hash],
+        )?;
+        record_message_status(
+            &conn,
+            "(message_token=?2 OR slug=?2) AND delete_token=?3",
+            params![
+                MessageState::Deleted.as_str(),
+                token_hash,
+                delete_token_hash
+            ],
+        )?;
+        let removed_count = conn.execute(
+            "DELETE FROM messages WHERE (message_token=?1 OR slug=?1) AND delete_token=?2",
+            params![token_hash, delete_token_hash],
+        )?;
+        self.delete_blobs(&blob_keys);
+        Ok(removed_count > 0)
+    }
+
+    // a message that is still there is pending, whether it has expired is up to the caller
+    pub fn get_message_status(
+        &self,
+        message_token: &str,
+        delete_token: &str,
+    ) -> Result<Option<MessageStatus>> {
+        let token_hash = hash_token(message_token);
+        let delete_token_hash = hash_token(delete_token);
+        let conn = self.conn.lock().unwrap();
+        let pending = conn
+            .query_row(
+                "SELECT created_timestamp, expire_timestamp FROM messages WHERE (message_token=?1 OR slug=?1) AND delete_token=?2",
+                params![token_hash, delete_token_hash],
+                |row| {
+                    Ok(MessageStatus {
+                        state: MessageState::Pending,
+                        created_timestamp: row.get(0)?,
+                        expire_timestamp: row.get(1)?,
+                        removed_timestamp: None,
+                        receipt: None,
+                    })
+                },
+            )
+            .optional()?;
+        if pending.is_some() {
+            return Ok(pending);
+        }
+        conn.query_row(
+            "SELECT state, created_timestamp, expire_timestamp, removed_timestamp, read_timestamp, client_ip, user_agent
+            FROM message_statuses LEFT JOIN message_receipts USING (message_token)
+            WHERE (message_token=?1 OR slug=?1) AND delete_token=?2",
+            params![token_hash, delete_token_hash],
+            |row| {
+                let state: String = row.get(0)?;
+                let read_timestamp: Option<i64> = row.get(4)?;
+                Ok(MessageStatus {
+                    state: MessageState::parse(&state).unwrap_or(MessageState::Destroyed),
+                    created_timestamp: row.get(1)?,
+                    expire_timestamp: row.get(2)?,
+                    removed_timestamp: row.get(3)?,
+                    receipt: match read_timestamp {
+                        Some(read_timestamp) => Some(ReadReceipt {
+                            read_timestamp,
+                            client_ip: row.get(5)?,
+                            user_agent: row.get(6)?,
+                        }),
+                        None => None,
+                    },
+                })
+            },
+        )
+        .optional()
+    }
+
+    // the slug of a message that was read can be taken again, the receipt goes to the
+    // message that was read last under the name
+    pub fn save_read_receipt(&self, message_token: &str, receipt: &ReadReceipt) -> Result<()> {
+        let token_hash = hash_token(message_token);
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO message_receipts (message_token, read_timestamp, client_ip, user_agent)
+            SELECT message_token, ?2, ?3, ?4 FROM message_statuses
+            WHERE (message_token=?1 OR slug=?1) AND state=?5
+            ORDER BY removed_timestamp DESC LIMIT 1",
+            params![
+                token_hash,
+                receipt.read_timestamp,
+                receipt.client_ip,
+                receipt.user_agent,
+                MessageState::Consumed.as_str()
+            ],
+        )?;
+        Ok(())
+    }
+
+    // returns true if the message was destroyed because the attempt limit was reached
+    pub fn register_failed_passphrase_attempt(
+        &self,
+        message_token: &str,
+        max_attempts: u32,
+    ) -> Result<bool> {
+        self.register_failed_attempt(message_token, "failed_passphrase_attempts", max_attempts)
+    }
+
+    // returns true if the message was destroyed because the attempt limit was reached
+    pub fn register_failed_pin_attempt(
+        &self,
+        message_token: &str,
+        max_attempts: u32,
+    ) -> Result<bool> {
+        self.register_failed_attempt(message_token, "failed_pin_attempts", max_attempts)
+    }
+
+    // the passphrase and the PIN count their attempts apart, `counter` is one of their columns
+    fn register_failed_attempt(
+        &self,
+        message_token: &str,
+        counter: &str,
+        max_attempts: u32,
+    ) -> Result<bool> {
+        let token_hash = hash_token(message_token);
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!(
+                "UPDATE messages SET {counter}={counter}+1 WHERE (message_token=?1 OR slug=?1)",
+                counter = counter
+            ),
+            params![token_hash],
+        )?;
+        if max_attempts == 0 {
+            return Ok(false);
+        }
+        let blob_keys = select_blob_keys(
+            &conn,
+            &format!(
+                "SELECT blob_key FROM messages WHERE (message_token=?1 OR slug=?1) AND {}>=?2 AND blob_key IS NOT NULL",
+                counter
+            ),
+            params![token_hash, max_attempts],
+        )?;
+        record_message_status(
+            &conn,
+            &format!("(message_token=?2 OR slug=?2) AND {}>=?3", counter),
+            params![MessageState::Destroyed.as_str(), token_hash, max_attempts],
+        )?;
+        let removed_count = conn.execute(
+            &format!(
+                "DELETE FROM messages WHERE (message_token=?1 OR slug=?1) AND {}>=?2",
+                counter
+            ),
+            params![token_hash, max_attempts],
+        )?;
+        self.delete_blobs(&blob_keys);
+        Ok(removed_count > 0)
+    }
+
+    pub fn remove_user_by_token(&self, token: &str) -> Result<()> {
+        let token = hash_token(token);
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM users WHERE token=?1", params![token])?;
+        conn.execute(
+            "DELETE FROM message_creations WHERE user_token=?1",
+            params![token],
+        )?;
+        conn.execute(
+            "DELETE FROM monthly_usage WHERE user_token=?1",
+            params![token],
+        )?;
+        Ok(())
+    }
+
+    // returns the number of removed messages, messages without expiry are never removed
+    pub fn clear_expired_messages(&self, limit_timestamp: i64) -> Result<Vec<ExpiredMessage>> {
+        let conn = self.conn.lock().unwrap();
+        // the messages read before they expired are not reported as expired unread
+        let read_blob_keys = select_blob_keys(
+            &conn,
+            "SELECT blob_key FROM messages WHERE grace_until<?1 AND blob_key IS NOT NULL",
+            params![limit_timestamp],
+        )?;
+        record_message_status(
+            &conn,
+            "grace_until<?2",
+            params![MessageState::Consumed.as_str(), limit_timestamp],
+        )?;
+        conn.execute(
+            "DELETE FROM messages WHERE grace_until<?1",
+            params![limit_timestamp],
+        )?;
+        self.delete_blobs(&read_blob_keys);
+        let blob_keys = select_blob_keys(
+            &conn,
+            "SELECT blob_key FROM messages WHERE expire_timestamp<?1 AND expire_timestamp!=0 AND blob_key IS NOT NULL",
+            params![limit_timestamp],
+        )?;
+        let expired_messages = {
+            let mut stmt = conn.prepare(
+                "SELECT COALESCE(messages.slug, messages.message_token), messages.expire_timestamp,
+                    COALESCE(messages.webhook_url, users.webhook_url), users.email
+                FROM messages LEFT JOIN users ON users.token=messages.user_token
+                WHERE messages.expire_timestamp<?1 AND messages.expire_timestamp!=0
+                    AND messages.grace_until IS NULL",
+            )?;
+            let rows = stmt.query_map(params![limit_timestamp], |row| {
+                Ok(ExpiredMessage {
+                    name_hash: row.get(0)?,
+                    expire_timestamp: row.get(1)?,
+                    webhook_url: row.get(2)?,
+                    creator_email: row.get(3)?,
+                })
+            })?;
+            rows.collect::<Result<Vec<_>>>()?
+        };
+        record_message_status(
+            &conn,
+            "expire_timestamp<?2 AND expire_timestamp!=0",
+            params![MessageState::Expired.as_str(), limit_timestamp],
+        )?;
+        conn.execute(
+            "DELETE FROM messages WHERE expire_timestamp<?1 AND expire_timestamp!=0",
+            params![limit_timestamp],
+        )?;
+        self.delete_blobs(&blob_keys);
+        conn.execute(
+            "DELETE FROM message_statuses WHERE removed_timestamp<?1",
+            params![limit_timestamp - MESSAGE_STATUS_RETENTION_SECONDS],
+        )?;
+        conn.execute(
+            "DELETE FROM message_receipts WHERE message_token NOT IN (SELECT message_token FROM message_statuses)",
+            [],
+        )?;
+        Ok(expired_messages)
+    }
+
+    pub fn enqueue_webhook(
+        &self,
+        url: &str,
+        event: &str,
+        payload: &str,
+        timestamp: i64,
+    ) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO webhook_deliveries (url, event, payload, state, created_timestamp, next_attempt_timestamp) VALUES (?1, ?2, ?3, ?4, ?5, ?5)",
+            params![url, event, payload, DeliveryState::Pending.as_str(), timestamp],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    fn select_webhook_deliveries(
+        conn: &Connection,
+        condition: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<WebhookDelivery>> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, url, event, payload, state, attempts, created_timestamp, next_attempt_timestamp, last_attempt_timestamp, last_status, last_error
+            FROM webhook_deliveries {}",
+            condition
+        ))?;
+        let rows = stmt.query_map(params, |row| {
+            let state: String = row.get(4)?;
+            Ok(WebhookDelivery {
+                id: row.get(0)?,
+                url: row.get(1)?,
+                event: row.get(2)?,
+                payload: row.get(3)?,
+                state: DeliveryState::parse(&state).unwrap_or(DeliveryState::Failed),
+                attempts: row.get(5)?,
+                created_timestamp: row.get(6)?,
+                next_attempt_timestamp: row.get(7)?,
+                last_attempt_timestamp: row.get(8)?,
+                last_status: row.get(9)?,
+                last_error: row.get(10)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn get_due_webhooks(&self, timestamp: i64, limit: usize) -> Result<Vec<WebhookDelivery>> {
+        let conn = self.conn.lock().unwrap();
+        Self::select_webhook_deliveries(
+            &conn,
+            "WHERE state=?1 AND next_attempt_timestamp<=?2 ORDER BY next_attempt_timestamp, id LIMIT ?3",
+            params![DeliveryState::Pending.as_str(), timestamp, limit as i64],
+        )
+    }
+
+    pub fn record_webhook_attempt(&self, id: i64, attempt: &WebhookAttempt) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE webhook_deliveries SET state=?2, attempts=attempts+1, last_attempt_timestamp=?3, last_status=?4, last_error=?5, next_attempt_timestamp=?6 WHERE id=?1",
+            params![
+                id,
+                attempt.state.as_str(),
+                attempt.timestamp,
+                attempt.status,
+                attempt.error,
+                attempt.next_attempt_timestamp
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_webhook_deliveries(
+        &self,
+        state: Option<DeliveryState>,
+        limit: usize,
+    ) -> Result<Vec<WebhookDelivery>> {
+        let conn = self.conn.lock().unwrap();
+        Self::select_webhook_deliveries(
+            &conn,
+            "WHERE ?1 IS NULL OR state=?1 ORDER BY id DESC LIMIT ?2",
+            params![state.map(|state| state.as_str()), limit as i64],
+        )
+    }
+
+    pub fn get_webhook_delivery(&self, id: i64) -> Result<Option<WebhookDelivery>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(Self::select_webhook_deliveries(&conn, "WHERE id=?1", params![id])?.pop())
+    }
+
+    pub fn retry_webhook_delivery(&self, id: i64, timestamp: i64) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let updated_count = conn.execute(
+            "UPDATE webhook_deliveries SET state=?2, attempts=0, next_attempt_timestamp=?3 WHERE id=?1 AND state=?4",
+            params![
+                id,
+                DeliveryState::Pending.as_str(),
+                timestamp,
+                DeliveryState::Failed.as_str()
+            ],
+        )?;
+        Ok(updated_count > 0)
+    }
+
+    pub fn clear_old_webhook_deliveries(&self, limit_timestamp: i64) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM webhook_deliveries WHERE state!=?1 AND created_timestamp<?2",
+            params![DeliveryState::Pending.as_str(), limit_timestamp],
+        )
+    }
+
+    pub fn save_secret_request(&self, request_token: &str, request: &SecretRequest) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO secret_requests (request_token, note, expire_timestamp, max_size_bytes, retention_minutes, sealed_response_token, sealed_message_token) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                hash_token(request_token),
+                request.note,
+                request.expire_timestamp,
+                request.max_size_bytes,
+                request.retention_minutes,
+                request.sealed_response_token,
+                request.sealed_message_token
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_secret_request(
+        &self,
+        request_token: &str,
+        timestamp: i64,
+    ) -> Result<Option<SecretRequest>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT note, expire_timestamp, max_size_bytes, retention_minutes, sealed_response_token, sealed_message_token FROM secret_requests
+            WHERE request_token=?1 AND (expire_timestamp=0 OR expire_timestamp>=?2)",
+        )?;
+        let mut rows = stmt.query(params![hash_token(request_token), timestamp])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(SecretRequest {
+                note: row.get(0)?,
+                expire_timestamp: row.get(1)?,
+                max_size_bytes: row.get(2)?,
+                retention_minutes: row.get(3)?,
+                sealed_response_token: row.get(4)?,
+                sealed_message_token: row.get(5)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    pub fn remove_secret_request(&self, request_token: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute(
+            "DELETE FROM secret_requests WHERE request_token=?1",
+            params![hash_token(request_token)],
+        )?;
+        Ok(removed > 0)
+    }
+
+    pub fn open_secret_request(&self, request_token: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let opened = conn.execute(
+            "UPDATE secret_requests SET sealed_message_token=NULL WHERE request_token=?1 AND sealed_message_token IS NOT NULL",
+            params![hash_token(request_token)],
+        )?;
+        Ok(opened > 0)
+    }
+
+    // a channel without retention stays like its message
+    pub fn clear_expired_secret_requests(&self, limit_timestamp: i64) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM secret_requests WHERE expire_timestamp!=0 AND expire_timestamp<?1",
+            params![limit_timestamp],
+        )
+    }
+
+    // encrypted like a message, the tag is made for the download id
+    pub fn save_download(&self, download_id: &str, download: &PendingDownload) -> Result<()> {
+        let integrity_tag = self
+            .signer
+            .as_ref()
+            .map(|signer| signer.tag(download_id, download.expire_timestamp, &download.data));
+        let (data, encryption_key_id) = self.encrypt_message_data(download.data.clone())?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO downloads (download_id, data, is_encrypted, encryption_key_id, integrity_tag, filename, content_type, checksum, expire_timestamp) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                hash_token(download_id),
+                *data,
+                encryption_key_id.is_some(),
+                encryption_key_id,
+                integrity_tag,
+                download.filename,
+                download.content_type,
+                download.checksum,
+                download.expire_timestamp
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_download(
+        &self,
+        download_id: &str,
+        timestamp: i64,
+    ) -> Result<Option<PendingDownload>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT data, is_encrypted, encryption_key_id, integrity_tag, filename, content_type, checksum, expire_timestamp FROM downloads
+            WHERE download_id=?1 AND expire_timestamp>=?2",
+        )?;
+        let mut rows = stmt.query(params![hash_token(download_id), timestamp])?;
+        match rows.next()? {
+            Some(row) => {
+                let encryption_key_id: Option<String> = row.get(2)?;
+                let data = self.decrypt_message_data(
+                    Zeroizing::new(row.get(0)?),
+                    row.get(1)?,
+                    encryption_key_id.as_deref(),
+                )?;
+                let expire_timestamp = row.get(7)?;
+                self.verify_message_data(download_id, expire_timestamp, &data, row.get(3)?)?;
+                Ok(Some(PendingDownload {
+                    data,
+                    filename: row.get(4)?,
+                    content_type: row.get(5)?,
+                    checksum: row.get(6)?,
+                    expire_timestamp,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn remove_download(&self, download_id: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute(
+            "DELETE FROM downloads WHERE download_id=?1",
+            params![hash_token(download_id)],
+        )?;
+        Ok(removed > 0)
+    }
+
+    pub fn count_pending_download_bytes(&self, timestamp: i64) -> Result<u64> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT COALESCE(SUM(LENGTH(data)), 0) FROM downloads WHERE expire_timestamp>=?1",
+            params![timestamp],
+            |row| row.get(0),
+        )
+    }
+
+    pub fn clear_expired_downloads(&self, limit_timestamp: i64) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM downloads WHERE expire_timestamp<?1",
+            params![limit_timestamp],
+        )
+    }
+
+    pub fn save_admin_credential(
+        &self,
+        credential_id: &str,
+        public_key: &[u8],
+        sign_count: u32,
+        name: &str,
+        timestamp: i64,
+    ) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO admin_credentials (credential_id, public_key, sign_count, name, created_timestamp) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![credential_id, public_key, sign_count, name, timestamp],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    fn read_admin_credential(row: &rusqlite::Row) -> Result<AdminCredential> {
+        Ok(AdminCredential {
+            id: row.get(0)?,
+            credential_id: row.get(1)?,
+            public_key: row.get(2)?,
+            sign_count: row.get(3)?,
+            name: row.get(4)?,
+            created_timestamp: row.get(5)?,
+            last_used_timestamp: row.get(6)?,
+        })
+    }
+
+    pub fn list_admin_credentials(&self) -> Result<Vec<AdminCredential>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, credential_id, public_key, sign_count, name, created_timestamp, last_used_timestamp FROM admin_credentials ORDER BY id",
+        )?;
+        let credentials = stmt
+            .query_map([], OneTimeShareDb::read_admin_credential)?
+            .collect();
+        credentials
+    }
+
+    pub fn get_admin_credential(&self, credential_id: &str) -> Result<Option<AdminCredential>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT id, credential_id, public_key, sign_count, name, created_timestamp, last_used_timestamp FROM admin_credentials WHERE credential_id=?1",
+            params![credential_id],
+            OneTimeShareDb::read_admin_credential,
+        )
+        .optional()
+    }
+
+    pub fn record_admin_credential_use(
+        &self,
+        id: i64,
+        sign_count: u32,
+        timestamp: i64,
+    ) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE admin_credentials SET sign_count=?2, last_used_timestamp=?3 WHERE id=?1",
+            params![id, sign_count, timestamp],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_admin_credential(&self, id: i64) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM admin_credentials WHERE id=?1", params![id])?;
+        Ok(removed > 0)
+    }
+
+    pub fn sign_in_portal_account(
+        &self,
+        issuer: &str,
+        subject: &str,
+        email: Option<&str>,
+        timestamp: i64,
+    ) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO portal_accounts (issuer, subject, email, created_timestamp, last_sign_in_timestamp) VALUES (?1, ?2, ?3, ?4, ?4)
+            ON CONFLICT(issuer, subject) DO UPDATE SET email=?3, last_sign_in_timestamp=?4",
+            params![issuer, subject, email, timestamp],
+        )?;
+        conn.query_row(
+            "SELECT id FROM portal_accounts WHERE issuer=?1 AND subject=?2",
+            params![issuer, subject],
+            |row| row.get(0),
+        )
+    }
+
+    pub fn create_api_key(
+        &self,
+        account_id: i64,
+        token: &str,
+        name: &str,
+        limits: &UserLimits,
+        plan: Option<&str>,
+        timestamp: i64,
+    ) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO users (token, retention_limit_minutes, max_size_bytes, message_creation_limit_minutes, message_creation_limit_count, active_message_limit, monthly_byte_quota, custom_slugs, plan, account_id, key_name, created_timestamp) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                hash_token(token),
+                limits.retention_limit_minutes,
+                limits.max_message_size_bytes,
+                limits.message_creation_limit_minutes,
+                limits.message_creation_limit_count,
+                limits.active_message_limit,
+                limits.monthly_byte_quota,
+                limits.custom_slugs,
+                plan,
+                account_id,
+                name,
+                timestamp
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn list_api_keys(&self, account_id: i64) -> Result<Vec<ApiKey>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, key_name, created_timestamp, plan FROM users WHERE account_id=?1 ORDER BY id",
+        )?;
+        let keys = stmt
+            .query_map(params![account_id], |row| {
+                Ok(ApiKey {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    created_timestamp: row.get(2)?,
+                    plan: row.get(3)?,
+                })
+            })?
+            .collect();
+        keys
+    }
+
+    // the usage of the key goes with it, like with remove_user_by_token
+    pub fn remove_api_key(&self, account_id: i64, id: i64) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let token: Option<String> = conn
+            .query_row(
+                "SELECT token FROM users WHERE id=?1 AND account_id=?2",
+                params![id, account_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let token = match token {
+            Some(token) => token,
+            None => return Ok(false),
+        };
+        conn.execute("DELETE FROM users WHERE token=?1", params![token])?;
+        conn.execute(
+            "DELETE FROM message_creations WHERE user_token=?1",
+            params![token],
+        )?;
+        conn.execute(
+            "DELETE FROM monthly_usage WHERE user_token=?1",
+            params![token],
+        )?;
+        Ok(true)
+    }
+
+    pub fn set_account_plan(&self, account_id: i64, plan: Option<&str>) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE users SET plan=?2 WHERE account_id=?1",
+            params![account_id, plan],
+        )?;
+        Ok(())
+    }
+
+    pub fn add_registration(
+        &self,
+        token: &str,
+        email: &str,
+        expire_timestamp: i64,
+        timestamp: i64,
+    ) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM registrations WHERE expire_timestamp<=?1 OR email=?2",
+            params![timestamp, email],
+        )?;
+        conn.execute(
+            "INSERT INTO registrations (token, email, expire_timestamp) VALUES (?1, ?2, ?3)",
+            params![hash_token(token), email, expire_timestamp],
+        )?;
+        Ok(())
+    }
+
+    pub fn take_registration(&self, token: &str, timestamp: i64) -> Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        let token_hash = hash_token(token);
+        let email = conn
+            .query_row(
+                "SELECT email FROM registrations WHERE token=?1 AND expire_timestamp>?2",
+                params![token_hash, timestamp],
+                |row| row.get(0),
+            )
+            .optional()?;
+        conn.execute(
+            "DELETE FROM registrations WHERE token=?1",
+            params![token_hash],
+        )?;
+        Ok(email)
+    }
+
+    pub fn create_invitation(
+        &self,
+        token: &str,
+        plan: Option<&str>,
+        note: Option<&str>,
+        expire_timestamp: i64,
+        timestamp: i64,
+    ) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM invitations WHERE expire_timestamp<=?1",
+            params![timestamp],
+        )?;
+        conn.execute(
+            "INSERT INTO invitations (token, plan, note, created_timestamp, expire_timestamp) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![hash_token(token), plan, note, timestamp, expire_timestamp],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    fn read_invitation(row: &rusqlite::Row) -> Result<Invitation> {
+        Ok(Invitation {
+            id: row.get(0)?,
+            plan: row.get(1)?,
+            note: row.get(2)?,
+            created_timestamp: row.get(3)?,
+            expire_timestamp: row.get(4)?,
+        })
+    }
+
+    pub fn list_invitations(&self, timestamp: i64) -> Result<Vec<Invitation>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, plan, note, created_timestamp, expire_timestamp FROM invitations WHERE expire_timestamp>?1 ORDER BY id",
+        )?;
+        let invitations = stmt
+            .query_map(params![timestamp], OneTimeShareDb::read_invitation)?
+            .collect();
+        invitations
+    }
+
+    pub fn take_invitation(&self, token: &str, timestamp: i64) -> Result<Option<Invitation>> {
+        let conn = self.conn.lock().unwrap();
+        let token_hash = hash_token(token);
+        let invitation = conn
+            .query_row(
+                "SELECT id, plan, note, created_timestamp, expire_timestamp FROM invitations WHERE token=?1 AND expire_timestamp>?2",
+                params![token_hash, timestamp],
+                OneTimeShareDb::read_invitation,
+            )
+            .optional()?;
+        conn.execute(
+            "DELETE FROM invitations WHERE token=?1",
+            params![token_hash],
+        )?;
+        Ok(invitation)
+    }
+
+    pub fn remove_invitation(&self, id: i64) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM invitations WHERE id=?1", params![id])?;
+        Ok(removed > 0)
+    }
+
+    // encrypts every message that isn't encrypted with the newest key yet with it, so the
+    // other keys can be removed from the config afterwards, returns the number of messages;
+    // a maintenance job, it goes to the blob store directly
+    pub fn reencrypt_messages(&self) -> Result<usize> {
+        let (current_key_id, current_cipher) = match self.current_cipher() {
+            Some(current) => current,
+            None => return Ok(0),
+        };
+        let blob_store = self
+            .blob_transfers
+            .as_ref()
+            .map(|blob_transfers| blob_transfers.blob_store());
+        let conn = self.conn.lock().unwrap();
+        let rows = {
+            let mut stmt = conn.prepare(
+                "SELECT id, data, is_encrypted, encryption_key_id, blob_key, totp_secret FROM messages
+                WHERE is_encrypted=0 OR IFNULL(encryption_key_id, ?1)!=?2",
+            )?;
+            let rows = stmt
+                .query_map(params![DEFAULT_KEY_ID, current_key_id], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, Vec<u8>>(1)?,
+                        row.get::<_, bool>(2)?,
+                        row.get::<_, Option<String>>(3)?,
+                        row.get::<_, Option<String>>(4)?,
+                        row.get::<_, Option<Vec<u8>>>(5)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>>>()?;
+            rows
+        };
+
+        let mut reencrypted_count = 0;
+        for (id, data, is_encrypted, key_id, blob_key, totp_secret) in rows {
+            if is_encrypted && self.find_cipher(key_id.as_deref()).is_none() {
+                log::warn!(
+                    "Message {} is encrypted with key {} which is not configured, it can't be re-encrypted",
+                    id,
+                    key_id.as_deref().unwrap_or(DEFAULT_KEY_ID)
+                );
+                continue;
+            }
+            let data = self.load_message_data(data, blob_key.clone())?;
+            let data = self.decrypt_message_data(data, is_encrypted, key_id.as_deref())?;
+            let encrypted = current_cipher
+                .encrypt(&data)
+                .map_err(|err| Error::ToSqlConversionFailure(Box::new(err)))?;
+            // the new data goes to a new blob, the old one stays readable until the row points
+            // away from it, so a crash in between doesn't lose the message
+            let (stored, new_blob_key) = match (&blob_key, blob_store) {
+                (Some(_), Some(blob_store)) => {
+                    let new_blob_key = Uuid::new_v4().to_string();
+                    blob_store
+                        .put(&new_blob_key, &encrypted)
+                        .map_err(|err| Error::ToSqlConversionFailure(Box::new(err)))?;
+                    (Vec::new(), Some(new_blob_key))
+                }
+                _ => (encrypted, None),
+            };
+            // the authenticator secret is encrypted with the key of the data
+            let totp_secret = match totp_secret {
+                Some(totp_secret) => {
+                    let totp_secret = self.decrypt_message_data(
+                        Zeroizing::new(totp_secret),
+                        is_encrypted,
+                        key_id.as_deref(),
+                    )?;
+                    Some(
+                        current_cipher
+                            .encrypt(&totp_secret)
+                            .map_err(|err| Error::ToSqlConversionFailure(Box::new(err)))?,
+                    )
+                }
+                None => None,
+            };
+            if let Err(err) = conn.execute(
+                "UPDATE messages SET data=?1, is_encrypted=1, encryption_key_id=?2, totp_secret=?3, blob_key=?4 WHERE id=?5",
+                params![stored, current_key_id, totp_secret, new_blob_key, id],
+            ) {
+                if let Some(blob_store) = blob_store {
+                    Self::delete_blobs_now(blob_store, new_blob_key.as_slice());
+                }
+                return Err(err);
+            }
+            if let (Some(_), Some(blob_store)) = (&new_blob_key, blob_store) {
+                Self::delete_blobs_now(blob_store, blob_key.as_slice());
+            }
+            reencrypted_count += 1;
+        }
+        Ok(reencrypted_count)
+    }
+
+    // swaps in an empty in-memory connection, so anything that still holds the database
+    // after shutdown gets an error instead of writing to the file
+    pub fn close(&self) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let file_conn = std::mem::replace(&mut *conn, Connection::open_in_memory()?);
+        file_conn.close().map_err(|(_, err)| err)
+    }
+}
+
+fn select_blob_keys(
+    conn: &Connection,
+    query: &str,
+    params: impl rusqlite::Params,
+) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(query)?;
+    let blob_keys = stmt
+        .query_map(params, |row| row.get(0))?
+        .collect::<Result<Vec<String>>>()?;
+    Ok(blob_keys)
+}
+
+impl UserStore for OneTimeShareDb {
+    fn set_user_limits(&self, token: &str, limits: &UserLimits) -> StoreResult<()> {
+        Ok(OneTimeShareDb::set_user_limits(self, token, limits)?)
+    }
+
+    fn get_user_limits(&self, token: &str) -> StoreResult<Option<UserLimits>> {
+        Ok(OneTimeShareDb::get_user_limits(self, token)?)
+    }
+
+    fn set_user_plan(&self, token: &str, plan: Option<&str>) -> StoreResult<()> {
+        Ok(OneTimeShareDb::set_user_plan(self, token, plan)?)
+    }
+
+    fn get_user_plan(&self, token: &str) -> StoreResult<Option<String>> {
+        Ok(OneTimeShareDb::get_user_plan(self, token)?)
+    }
+
+    fn set_user_webhook_url(&self, token: &str, webhook_url: Option<&str>) -> StoreResult<()> {
+        Ok(OneTimeShareDb::set_user_webhook_url(
+            self,
+            token,
+            webhook_url,
+        )?)
+    }
+
+    fn get_user_webhook_url(&self, token: &str) -> StoreResult<Option<String>> {
+        Ok(OneTimeShareDb::get_user_webhook_url(self, token)?)
+    }
+
+    fn set_user_email(&self, token: &str, email: Option<&str>) -> StoreResult<()> {
+        Ok(OneTimeShareDb::set_user_email(self, token, email)?)
+    }
+
+    fn get_user_email(&self, token: &str) -> StoreResult<Option<String>> {
+        Ok(OneTimeShareDb::get_user_email(self, token)?)
+    }
+
+    fn count_active_user_messages(&self, token: &str, timestamp: i64) -> StoreResult<u32> {
+        Ok(OneTimeShareDb::count_active_user_messages(
+            self, token, timestamp,
+        )?)
+    }
+
+    fn get_monthly_usage(&self, token: &str, month: &str) -> StoreResult<u64> {
+        Ok(OneTimeShareDb::get_monthly_usage(self, token, month)?)
+    }
+
+    fn add_monthly_usage(&self, token: &str, month: &str, bytes: u64) -> StoreResult<()> {
+        Ok(OneTimeShareDb::add_monthly_usage(
+            self, token, month, bytes,
+        )?)
+    }
+
+    fn try_register_message_creation(
+        &self,
+        token: &str,
+        timestamp: i64,
+        window_seconds: i64,
+        max_count: u32,
+    ) -> StoreResult<Option<i64>> {
+        Ok(OneTimeShareDb::try_register_message_creation(
+            self,
+            token,
+            timestamp,
+            window_seconds,
+            max_count,
+        )?)
+    }
+
+    fn remove_user_by_token(&self, token: &str) -> StoreResult<()> {
+        Ok(OneTimeShareDb::remove_user_by_token(self, token)?)
+    }
+}
+
+impl SettingsStore for OneTimeShareDb {
+    fn get_global_integer(&self, name: &str) -> StoreResult<Option<i64>> {
+        Ok(OneTimeShareDb::get_global_integer(self, name)?)
+    }
+
+    fn set_global_integer(&self, name: &str, value: i64) -> StoreResult<()> {
+        Ok(OneTimeShareDb::set_global_integer(self, name, value)?)
+    }
+
+    fn remove_global_var(&self, name: &str) -> StoreResult<()> {
+        Ok(OneTimeShareDb::remove_global_var(self, name)?)
+    }
+}
+
+impl SecretRequestStore for OneTimeShareDb {
+    fn save_secret_request(&self, request_token: &str, request: &SecretRequest) -> StoreResult<()> {
+        Ok(OneTimeShareDb::save_secret_request(
+            self,
+            request_token,
+            request,
+        )?)
+    }
+
+    fn get_secret_request(
+        &self,
+        request_token: &str,
+        timestamp: i64,
+    ) -> StoreResult<Option<SecretRequest>> {
+        Ok(OneTimeShareDb::get_secret_request(
+            self,
+            request_token,
+            timestamp,
+        )?)
+    }
+
+    fn remove_secret_request(&self, request_token: &str) -> StoreResult<bool> {
+        Ok(OneTimeShareDb::remove_secret_request(self, request_token)?)
+    }
+
+    fn open_secret_request(&self, request_token: &str) -> StoreResult<bool> {
+        Ok(OneTimeShareDb::open_secret_request(self, request_token)?)
+    }
+
+    fn clear_expired_secret_requests(&self, limit_timestamp: i64) -> StoreResult<usize> {
+        Ok(OneTimeShareDb::clear_expired_secret_requests(
+            self,
+            limit_timestamp,
+        )?)
+    }
+}
+
+impl DownloadStore for OneTimeShareDb {
+    fn save_download(&self, download_id: &str, download: &PendingDownload) -> StoreResult<()> {
+        Ok(OneTimeShareDb::save_download(self, download_id, download)?)
+    }
+
+    fn get_download(
+        &self,
+        download_id: &str,
+        timestamp: i64,
+    ) -> StoreResult<Option<PendingDownload>> {
+        Ok(OneTimeShareDb::get_download(self, download_id, timestamp)?)
+    }
+
+    fn remove_download(&self, download_id: &str) -> StoreResult<bool> {
+        Ok(OneTimeShareDb::remove_download(self, download_id)?)
+    }
+
+    fn count_pending_download_bytes(&self, timestamp: i64) -> StoreResult<u64> {
+        Ok(OneTimeShareDb::count_pending_download_bytes(
+            self, timestamp,
+        )?)
+    }
+
+    fn clear_expired_downloads(&self, limit_timestamp: i64) -> StoreResult<usize> {
+        Ok(OneTimeShareDb::clear_expired_downloads(
+            self,
+            limit_timestamp,
+        )?)
+    }
+}
+
+impl AdminCredentialStore for OneTimeShareDb {
+    fn save_admin_credential(
+        &self,
+        credential_id: &str,
+        public_key: &[u8],
+        sign_count: u32,
+        name: &str,
+        timestamp: i64,
+    ) -> StoreResult<i64> {
+        Ok(OneTimeShareDb::save_admin_credential(
+            self,
+            credential_id,
+            public_key,
+            sign_count,
+            name,
+            timestamp,
+        )?)
+    }
+
+    fn list_admin_credentials(&self) -> StoreResult<Vec<AdminCredential>> {
+        Ok(OneTimeShareDb::list_admin_credentials(self)?)
+    }
+
+    fn get_admin_credential(&self, credential_id: &str) -> StoreResult<Option<AdminCredential>> {
+        Ok(OneTimeShareDb::get_admin_credential(self, credential_id)?)
+    }
+
+    fn record_admin_credential_use(
+        &self,
+        id: i64,
+        sign_count: u32,
+        timestamp: i64,
+    ) -> StoreResult<()> {
+        Ok(OneTimeShareDb::record_admin_credential_use(
+            self, id, sign_count, timestamp,
+        )?)
+    }
+
+    fn remove_admin_credential(&self, id: i64) -> StoreResult<bool> {
+        Ok(OneTimeShareDb::remove_admin_credential(self, id)?)
+    }
+}
+
+impl PortalStore for OneTimeShareDb {
+    fn sign_in_portal_account(
+        &self,
+        issuer: &str,
+        subject: &str,
+        email: Option<&str>,
+        timestamp: i64,
+    ) -> StoreResult<i64> {
+        Ok(OneTimeShareDb::sign_in_portal_account(
+            self, issuer, subject, email, timestamp,
+        )?)
+    }
+
+    fn create_api_key(
+        &self,
+        account_id: i64,
+        token: &str,
+        name: &str,
+        limits: &UserLimits,
+        plan: Option<&str>,
+        timestamp: i64,
+    ) -> StoreResult<i64> {
+        Ok(OneTimeShareDb::create_api_key(
+            self, account_id, token, name, limits, plan, timestamp,
+        )?)
+    }
+
+    fn list_api_keys(&self, account_id: i64) -> StoreResult<Vec<ApiKey>> {
+        Ok(OneTimeShareDb::list_api_keys(self, account_id)?)
+    }
+
+    fn remove_api_key(&self, account_id: i64, id: i64) -> StoreResult<bool> {
+        Ok(OneTimeShareDb::remove_api_key(self, account_id, id)?)
+    }
+
+    fn set_account_plan(&self, account_id: i64, plan: Option<&str>) -> StoreResult<()> {
+        Ok(OneTimeShareDb::set_account_plan(self, account_id, plan)?)
+    }
+
+    fn add_registration(
+        &self,
+        token: &str,
+        email: &str,
+        expire_timestamp: i64,
+        timestamp: i64,
+    ) -> StoreResult<()> {
+        Ok(OneTimeShareDb::add_registration(
+            self,
+            token,
+            email,
+            expire_timestamp,
+            timestamp,
+        )?)
+    }
+
+    fn take_registration(&self, token: &str, timestamp: i64) -> StoreResult<Option<String>> {
+        Ok(OneTimeShareDb::take_registration(self, token, timestamp)?)
+    }
+}
+
+impl InvitationStore for OneTimeShareDb {
+    fn create_invitation(
+        &self,
+        token: &str,
+        plan: Option<&str>,
+        note: Option<&str>,
+        expire_timestamp: i64,
+        timestamp: i64,
+    ) -> StoreResult<i64> {
+        Ok(OneTimeShareDb::create_invitation(
+            self,
+            token,
+            plan,
+            note,
+            expire_timestamp,
+            timestamp,
+        )?)
+    }
+
+    fn list_invitations(&self, timestamp: i64) -> StoreResult<Vec<Invitation>> {
+        Ok(OneTimeShareDb::list_invitations(self, timestamp)?)
+    }
+
+    fn take_invitation(&self, token: &str, timestamp: i64) -> StoreResult<Option<Invitation>> {
+        Ok(OneTimeShareDb::take_invitation(self, token, timestamp)?)
+    }
+
+    fn remove_invitation(&self, id: i64) -> StoreResult<bool> {
+        Ok(OneTimeShareDb::remove_invitation(self, id)?)
+    }
+}
+
+impl WebhookStore for OneTimeShareDb {
+    fn enqueue_webhook(
+        &self,
+        url: &str,
+        event: &str,
+        payload: &str,
+        timestamp: i64,
+    ) -> StoreResult<i64> {
+        Ok(OneTimeShareDb::enqueue_webhook(
+            self, url, event, payload, timestamp,
+        )?)
+    }
+
+    fn get_due_webhooks(&self, timestamp: i64, limit: usize) -> StoreResult<Vec<WebhookDelivery>> {
+        Ok(OneTimeShareDb::get_due_webhooks(self, timestamp, limit)?)
+    }
+
+    fn record_webhook_attempt(&self, id: i64, attempt: &WebhookAttempt) -> StoreResult<()> {
+        Ok(OneTimeShareDb::record_webhook_attempt(self, id, attempt)?)
+    }
+
+    fn list_webhook_deliveries(
+        &self,
+        state: Option<DeliveryState>,
+        limit: usize,
+    ) -> StoreResult<Vec<WebhookDelivery>> {
+        Ok(OneTimeShareDb::list_webhook_deliveries(self, state, limit)?)
+    }
+
+    fn get_webhook_delivery(&self, id: i64) -> StoreResult<Option<WebhookDelivery>> {
+        Ok(OneTimeShareDb::get_webhook_delivery(self, id)?)
+    }
+
+    fn retry_webhook_delivery(&self, id: i64, timestamp: i64) -> StoreResult<bool> {
+        Ok(OneTimeShareDb::retry_webhook_delivery(self, id, timestamp)?)
+    }
+
+    fn clear_old_webhook_deliveries(&self, limit_timestamp: i64) -> StoreResult<usize> {
+        Ok(OneTimeShareDb::clear_old_webhook_deliveries(
+            self,
+            limit_timestamp,
+        )?)
+    }
+}
+
+impl MessageStore for OneTimeShareDb {
+    fn save_message(
+        &self,
+        message_token: &str,
+        expire_timestamp: i64,
+        data: &[u8],
+        options: &MessageOptions,
+    ) -> StoreResult<()> {
+        Ok(OneTimeShareDb::save_message(
+            self,
+            message_token,
+            expire_timestamp,
+            data,
+            options,
+        )?)
+    }
+
+    fn try_consume_message(
+        &self,
+        message_token: &str,
+    ) -> StoreResult<(Option<Zeroizing<Vec<u8>>>, i64)> {
+        Ok(OneTimeShareDb::try_consume_message(self, message_token)?)
+    }
+
+    fn get_message_info(&self, message_token: &str) -> StoreResult<Option<MessageInfo>> {
+        Ok(OneTimeShareDb::get_message_info(self, message_token)?)
+    }
+
+    fn get_message_webhook_url(&self, message_token: &str) -> StoreResult<Option<String>> {
+        Ok(OneTimeShareDb::get_message_webhook_url(
+            self,
+            message_token,
+        )?)
+    }
+
+    fn get_message_creator_email(&self, message_token: &str) -> StoreResult<Option<String>> {
+        Ok(OneTimeShareDb::get_message_creator_email(
+            self,
+            message_token,
+        )?)
+    }
+
+    fn is_message_name_taken(&self, name: &str) -> StoreResult<bool> {
+        Ok(OneTimeShareDb::is_message_name_taken(self, name)?)
+    }
+
+    fn get_message_status(
+        &self,
+        message_token: &str,
+        delete_token: &str,
+    ) -> StoreResult<Option<MessageStatus>> {
+        Ok(OneTimeShareDb::get_message_status(
+            self,
+            message_token,
+            delete_token,
+        )?)
+    }
+
+    fn save_read_receipt(&self, message_token: &str, receipt: &ReadReceipt) -> StoreResult<()> {
+        Ok(OneTimeShareDb::save_read_receipt(
+            self,
+            message_token,
+            receipt,
+        )?)
+    }
+
+    fn delete_message(&self, message_token: &str, delete_token: &str) -> StoreResult<bool> {
+        Ok(OneTimeShareDb::delete_message(
+            self,
+            message_token,
+            delete_token,
+        )?)
+    }
+
+    fn get_message_remaining_views(&self, message_token: &str) -> StoreResult<Option<u32>> {
+        Ok(OneTimeShareDb::get_message_remaining_views(
+            self,
+            message_token,
+        )?)
+    }
+
+    fn get_message_expire_timestamp(&self, message_token: &str) -> StoreResult<Option<i64>> {
+        Ok(OneTimeShareDb::get_message_expire_timestamp(
+            self,
+            message_token,
+        )?)
+    }
+
+    fn get_message_authorization_hashes(
+        &self,
+        message_token: &str,
+    ) -> StoreResult<Option<[String; 2]>> {
+        Ok(OneTimeShareDb::get_message_authorization_hashes(
+            self,
+            message_token,
+        )?)
+    }
+
+    fn get_message_totp_secret(
+        &self,
+        message_token: &str,
+    ) -> StoreResult<Option<Zeroizing<Vec<u8>>>> {
+        Ok(OneTimeShareDb::get_message_totp_secret(
+            self,
+            message_token,
+        )?)
+    }
+
+    fn use_message_totp_step(&self, message_token: &str, step: u64) -> StoreResult<bool> {
+        Ok(OneTimeShareDb::use_message_totp_step(
+            self,
+            message_token,
+            step,
+        )?)
+    }
+
+    fn is_message_client_encrypted(&self, message_token: &str) -> StoreResult<Option<bool>> {
+        Ok(OneTimeShareDb::is_message_client_encrypted(
+            self,
+            message_token,
+        )?)
+    }
+
+    fn get_message_not_before(&self, message_token: &str) -> StoreResult<Option<i64>> {
+        Ok(OneTimeShareDb::get_message_not_before(self, message_token)?)
+    }
+
+    fn get_message_passphrase_hash(&self, message_token: &str) -> StoreResult<Option<String>> {
+        Ok(OneTimeShareDb::get_message_passphrase_hash(
+            self,
+            message_token,
+        )?)
+    }
+
+    fn get_message_pin_hash(&self, message_token: &str) -> StoreResult<Option<String>> {
+        Ok(OneTimeShareDb::get_message_pin_hash(self, message_token)?)
+    }
+
+    fn get_message_allowed_ips(&self, message_token: &str) -> StoreResult<Vec<String>> {
+        Ok(OneTimeShareDb::get_message_allowed_ips(
+            self,
+            message_token,
+        )?)
+    }
+
+    fn register_failed_pin_attempt(
+        &self,
+        message_token: &str,
+        max_attempts: u32,
+    ) -> StoreResult<bool> {
+        Ok(OneTimeShareDb::register_failed_pin_attempt(
+            self,
+            message_token,
+            max_attempts,
+        )?)
+    }
+
+    fn register_failed_passphrase_attempt(
+        &self,
+        message_token: &str,
+        max_attempts: u32,
+    ) -> StoreResult<bool> {
+        Ok(OneTimeShareDb::register_failed_passphrase_attempt(
+            self,
+            message_token,
+            max_attempts,
+        )?)
+    }
+
+    fn clear_expired_messages(&self, limit_timestamp: i64) -> StoreResult<Vec<ExpiredMessage>> {
+        Ok(OneTimeShareDb::clear_expired_messages(
+            self,
+            limit_timestamp,
+        )?)
+    }
+
+    fn get_message_blob_key(&self, message_token: &str) -> StoreResult<Option<String>> {
+        Ok(OneTimeShareDb::get_message_blob_key(self, message_token)?)
+    }
+
+    fn remove_messages_of_blobs(&self, blob_keys: &[String]) -> StoreResult<()> {
+        Ok(OneTimeShareDb::remove_messages_of_blobs(self, blob_keys)?)
+    }
+
+    fn blob_transfers(&self) -> Option<Arc<BlobTransfers>> {
+        OneTimeShareDb::blob_transfers(self)
+    }
+
+    fn reencrypt_messages(&self) -> StoreResult<usize> {
+        Ok(OneTimeShareDb::reencrypt_messages(self)?)
+    }
+
+    fn close(&self) -> StoreResult<()> {
+        Ok(OneTimeShareDb::close(self)?)
+    }
+}
+
+pub fn update_version(db: &OneTimeShareDb) -> Result<()> {
+    let current_version = db.get_database_version()?;
+    if current_version != LATEST_VERSION {
+        let updaters = make_updaters(&current_version, LATEST_VERSION);
+        for updater in updaters {
+            updater.update_db(db)?;
+        }
+    }
+    db.set_database_version(LATEST_VERSION)?;
+    Ok(())
+}
+
+fn make_updaters(version_from: &str, version_to: &str) -> Vec<DbUpdater> {
+    let all_updaters = make_all_updaters();
+    let mut updaters = Vec::new();
+    let mut is_first_found = version_from == MINIMAL_VERSION;
+
+    for updater in all_updaters {
+        if is_first_found {
+            updaters.push(updater.clone());
+            if updater.version == version_to {
+                break;
+            }
+        } else if updater.version == version_from {
+            is_first_found = true;
+        }
+    }
+
+    if !updaters.is_empty() && updaters.last().unwrap().version != version_to {
+        panic!(
+            "Last version updater not found. Expected: {} Found: {}",
             version_to,
             updaters.last().unwrap().version
         );
     }
 
-    updaters
-}
+    updaters
+}
+
+fn make_all_updaters() -> Vec<DbUpdater> {
+    vec![
+        DbUpdater {
+            version: "0.2",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute("ALTER TABLE messages ADD COLUMN passphrase_hash TEXT", [])?;
+                conn.execute(
+                "ALTER TABLE messages ADD COLUMN failed_passphrase_attempts INTEGER NOT NULL DEFAULT 0",
+                [],
+            )?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.3",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute(
+                    "ALTER TABLE messages ADD COLUMN is_encrypted INTEGER NOT NULL DEFAULT 0",
+                    [],
+                )?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.4",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute(
+                    "ALTER TABLE messages ADD COLUMN is_client_encrypted INTEGER NOT NULL DEFAULT 0",
+                    [],
+                )?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.5",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute("ALTER TABLE messages ADD COLUMN filename TEXT", [])?;
+                conn.execute("ALTER TABLE messages ADD COLUMN content_type TEXT", [])?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.6",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute("ALTER TABLE messages ADD COLUMN blob_key TEXT", [])?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.7",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute(
+                    "ALTER TABLE users ADD COLUMN message_creation_limit_count INTEGER NOT NULL DEFAULT 1",
+                    [],
+                )?;
+                // the users who just created a message keep waiting for the rest of their window
+                conn.execute(
+                    "INSERT INTO message_creations (user_token, timestamp)
+                    SELECT token, last_message_creation_timestamp FROM users WHERE last_message_creation_timestamp>0",
+                    [],
+                )?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.8",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute(
+                    "ALTER TABLE users ADD COLUMN active_message_limit INTEGER NOT NULL DEFAULT 0",
+                    [],
+                )?;
+                // the messages created before don't count, their owner is unknown
+                conn.execute("ALTER TABLE messages ADD COLUMN user_token TEXT", [])?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.9",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute(
+                    "ALTER TABLE users ADD COLUMN monthly_byte_quota INTEGER NOT NULL DEFAULT 0",
+                    [],
+                )?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.10",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute("ALTER TABLE users ADD COLUMN plan TEXT", [])?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.11",
+            update_db: decode_stored_message_data,
+        },
+        DbUpdater {
+            version: "0.12",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute(
+                    "ALTER TABLE messages ADD COLUMN is_compressed INTEGER NOT NULL DEFAULT 0",
+                    [],
+                )?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.13",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                // the messages stored before are handed out without a checksum
+                conn.execute("ALTER TABLE messages ADD COLUMN checksum TEXT", [])?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.14",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute("ALTER TABLE messages ADD COLUMN integrity_tag BLOB", [])?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.15",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                // NULL stands for DEFAULT_KEY_ID, the only key there was before
+                conn.execute("ALTER TABLE messages ADD COLUMN encryption_key_id TEXT", [])?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.16",
+            update_db: hash_stored_tokens,
+        },
+        DbUpdater {
+            version: "0.17",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute("ALTER TABLE messages ADD COLUMN slug TEXT", [])?;
+                create_slug_index(&conn)?;
+                conn.execute(
+                    "ALTER TABLE users ADD COLUMN custom_slugs INTEGER NOT NULL DEFAULT 0",
+                    [],
+                )?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.18",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                // the messages stored before can't be removed by their creators
+                conn.execute("ALTER TABLE messages ADD COLUMN delete_token TEXT", [])?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.19",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                // NULL for the messages stored before, the time wasn't kept
+                conn.execute(
+                    "ALTER TABLE messages ADD COLUMN created_timestamp INTEGER",
+                    [],
+                )?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.20",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute("ALTER TABLE messages ADD COLUMN webhook_url TEXT", [])?;
+                conn.execute("ALTER TABLE users ADD COLUMN webhook_url TEXT", [])?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.21",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute("ALTER TABLE users ADD COLUMN email TEXT", [])?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.22",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute(
+                    "ALTER TABLE messages ADD COLUMN remaining_views INTEGER NOT NULL DEFAULT 1",
+                    [],
+                )?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.23",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute(
+                    "ALTER TABLE messages ADD COLUMN grace_minutes INTEGER NOT NULL DEFAULT 0",
+                    [],
+                )?;
+                conn.execute("ALTER TABLE messages ADD COLUMN grace_until INTEGER", [])?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.24",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute("ALTER TABLE messages ADD COLUMN not_before INTEGER", [])?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.25",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute(
+                    "ALTER TABLE messages ADD COLUMN first_authorization_hash TEXT",
+                    [],
+                )?;
+                conn.execute(
+                    "ALTER TABLE messages ADD COLUMN second_authorization_hash TEXT",
+                    [],
+                )?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.26",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute("ALTER TABLE messages ADD COLUMN totp_secret BLOB", [])?;
+                conn.execute("ALTER TABLE messages ADD COLUMN totp_last_step INTEGER", [])?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.27",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute("ALTER TABLE messages ADD COLUMN pin_hash TEXT", [])?;
+                conn.execute(
+                    "ALTER TABLE messages ADD COLUMN failed_pin_attempts INTEGER NOT NULL DEFAULT 0",
+                    [],
+                )?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.28",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute("ALTER TABLE messages ADD COLUMN allowed_ips TEXT", [])?;
+                Ok(())
+            },
+        },
+        DbUpdater {
+            version: "0.29",
+            update_db: |db| {
+                let conn = db.conn.lock().unwrap();
+                conn.execute("ALTER TABLE users ADD COLUMN account_id INTEGER", [])?;
+                conn.execute("ALTER TABLE users ADD COLUMN key_name TEXT", [])?;
+                conn.execute("ALTER TABLE users ADD COLUMN created_timestamp INTEGER", [])?;
+                Ok(())
+            },
+        },
+    ]
+}
+
+// copies what is known about the messages matched by `condition` to message_statuses before
+// they are removed, ?1 of the parameters is the state; a message that is past its expiry is
+// recorded as expired, whatever removes it
+fn record_message_status(
+    conn: &Connection,
+    condition: &str,
+    params: impl rusqlite::Params,
+) -> Result<()> {
+    conn.execute(
+        &format!(
+            "INSERT OR REPLACE INTO message_statuses (message_token, slug, delete_token, state, created_timestamp, expire_timestamp, removed_timestamp)
+            SELECT message_token, slug, delete_token,
+                CASE WHEN expire_timestamp!=0 AND expire_timestamp<=CAST(strftime('%s', 'now') AS INTEGER) THEN '{}' ELSE ?1 END,
+                created_timestamp, expire_timestamp, CAST(strftime('%s', 'now') AS INTEGER)
+            FROM messages WHERE delete_token IS NOT NULL AND {}",
+            MessageState::Expired.as_str(),
+            condition
+        ),
+        params,
+    )?;
+    Ok(())
+}
+
+// two messages can't have the same slug, the ones without a slug have NULL there
+fn create_slug_index(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS message_slug_index ON messages(slug)",
+        [],
+    )?;
+    Ok(())
+}
+
+// the tokens used to be stored as they are, now only their hashes are
+fn hash_stored_tokens(db: &OneTimeShareDb) -> Result<()> {
+    let conn = db.conn.lock().unwrap();
+    for (table, column) in [
+        ("users", "token"),
+        ("messages", "message_token"),
+        ("messages", "user_token"),
+        ("message_creations", "user_token"),
+        ("monthly_usage", "user_token"),
+    ] {
+        let tokens = {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT DISTINCT {column} FROM {table} WHERE {column} IS NOT NULL"
+            ))?;
+            let tokens = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>>>()?;
+            tokens
+        };
+        for token in tokens {
+            conn.execute(
+                &format!("UPDATE {table} SET {column}=?1 WHERE {column}=?2"),
+                params![hash_token(&token), token],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// messages used to be kept as the base64 text that came with the request, they are stored
+// as the decoded bytes now, encrypted ones are encrypted again
+fn decode_stored_message_data(db: &OneTimeShareDb) -> Result<()> {
+    let conn = db.conn.lock().unwrap();
+    let rows = {
+        let mut stmt = conn.prepare("SELECT id, data, is_encrypted, blob_key FROM messages")?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Value>(1)?,
+                    row.get::<_, bool>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        rows
+    };
+
+    for (id, data, is_encrypted, blob_key) in rows {
+        let text = match (&blob_key, data) {
+            (Some(blob_key), _) => match &db.blob_transfers {
+                Some(blob_transfers) => blob_transfers
+                    .blob_store()
+                    .get(blob_key)
+                    .map_err(|err| Error::FromSqlConversionFailure(0, Type::Blob, Box::new(err)))?,
+                None => {
+                    log::warn!("Message {} is kept in a blob store that isn't configured, it can't be converted", id);
+                    continue;
+                }
+            },
+            (None, Value::Text(text)) => text.into_bytes(),
+            // already converted
+            _ => continue,
+        };
+        let cipher = match (db.find_cipher(None), is_encrypted) {
+            (_, false) => None,
+            (Some(cipher), true) => Some(cipher),
+            (None, true) => {
+                log::warn!("Message {} is encrypted but no encryption key is configured, it can't be converted", id);
+                continue;
+            }
+        };
+        let base64_data = match cipher {
+            Some(cipher) => {
+                let encrypted = STANDARD
+                    .decode(&text)
+                    .map_err(|err| Error::FromSqlConversionFailure(0, Type::Text, Box::new(err)))?;
+                cipher
+                    .decrypt(&encrypted)
+                    .map_err(|err| Error::FromSqlConversionFailure(0, Type::Text, Box::new(err)))?
+            }
+            None => text,
+        };
+        // messages from before the data was validated are kept as they were sent
+        let decoded = STANDARD.decode(&base64_data).unwrap_or(base64_data);
+        let stored = match cipher {
+            Some(cipher) => cipher
+                .encrypt(&decoded)
+                .map_err(|err| Error::ToSqlConversionFailure(Box::new(err)))?,
+            None => decoded,
+        };
+
+        match (&blob_key, &db.blob_transfers) {
+            (Some(blob_key), Some(blob_transfers)) => {
+                blob_transfers
+                    .blob_store()
+                    .put(blob_key, &stored)
+                    .map_err(|err| Error::ToSqlConversionFailure(Box::new(err)))?;
+                conn.execute(
+                    "UPDATE messages SET data=?1 WHERE id=?2",
+                    params![Vec::<u8>::new(), id],
+                )?;
+            }
+            _ => {
+                conn.execute(
+                    "UPDATE messages SET data=?1 WHERE id=?2",
+                    params![stored, id],
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+struct DbUpdater {
+    version: &'static str,
+    update_db: fn(&OneTimeShareDb) -> Result<()>,
+}
+
+impl DbUpdater {
+    pub fn update_db(&self, db: &OneTimeShareDb) -> Result<()> {
+        (self.update_db)(db)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::ops::{Deref, DerefMut};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tempfile::NamedTempFile;
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    const TEST_KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    // keeps the database file alive for as long as the test uses the connection
+    struct TestDb {
+        db: OneTimeShareDb,
+        _temp_file: NamedTempFile,
+    }
+
+    impl Deref for TestDb {
+        type Target = OneTimeShareDb;
+
+        fn deref(&self) -> &OneTimeShareDb {
+            &self.db
+        }
+    }
+
+    impl DerefMut for TestDb {
+        fn deref_mut(&mut self) -> &mut OneTimeShareDb {
+            &mut self.db
+        }
+    }
+
+    fn transfer_blobs(db: &OneTimeShareDb) {
+        assert!(db.blob_transfers().unwrap().run(None).is_empty());
+    }
+
+    #[derive(Clone, Default)]
+    struct TestBlobStore {
+        blobs: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    }
+
+    impl BlobStore for TestBlobStore {
+        fn put(&self, key: &str, data: &[u8]) -> StoreResult<()> {
+            self.blobs
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), data.to_vec());
+            Ok(())
+        }
+
+        fn get(&self, key: &str) -> StoreResult<Vec<u8>> {
+            self.blobs
+                .lock()
+                .unwrap()
+                .get(key)
+                .cloned()
+                .ok_or_else(|| StoreError::new("Blob not found"))
+        }
+
+        fn delete(&self, key: &str) -> StoreResult<()> {
+            self.blobs.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    fn setup_db() -> TestDb {
+        let temp_file = NamedTempFile::new().unwrap();
+        TestDb {
+            db: OneTimeShareDb::connect(temp_file.path().to_str().unwrap()).unwrap(),
+            _temp_file: temp_file,
+        }
+    }
+
+    #[test]
+    fn test_get_and_set_database_version() {
+        let db = setup_db();
+        assert_eq!(db.get_database_version().unwrap(), LATEST_VERSION);
+
+        db.set_database_version("0.2").unwrap();
+        assert_eq!(db.get_database_version().unwrap(), "0.2");
+    }
+
+    #[test]
+    fn test_global_integers() {
+        let db = setup_db();
+        assert_eq!(db.get_global_integer("answer").unwrap(), None);
+
+        db.set_global_integer("answer", 41).unwrap();
+        db.set_global_integer("answer", 42).unwrap();
+        assert_eq!(db.get_global_integer("answer").unwrap(), Some(42));
+
+        db.remove_global_var("answer").unwrap();
+        assert_eq!(db.get_global_integer("answer").unwrap(), None);
+        assert_eq!(db.get_database_version().unwrap(), LATEST_VERSION);
+    }
+
+    #[test]
+    fn test_set_and_get_user_limits() {
+        let db = setup_db();
+        let limits = UserLimits {
+            retention_limit_minutes: 60,
+            max_message_size_bytes: 1024,
+            message_creation_limit_minutes: 5,
+            message_creation_limit_count: 2,
+            active_message_limit: 10,
+            monthly_byte_quota: 1_000_000,
+            custom_slugs: true,
+        };
+        db.set_user_limits("user1", &limits).unwrap();
+
+        assert_eq!(db.get_user_limits("user1").unwrap(), Some(limits));
+        assert_eq!(db.get_user_limits("user2").unwrap(), None);
+    }
+
+    #[test]
+    fn test_count_active_user_messages() {
+        let db = setup_db();
+        let options = MessageOptions {
+            user_token: Some("user1".to_string()),
+            ..Default::default()
+        };
+        db.save_message("token1", 0, b"a", &options).unwrap();
+        db.save_message("token2", 100, b"b", &options).unwrap();
+        db.save_message("token3", 0, b"c", &MessageOptions::default())
+            .unwrap();
+        assert_eq!(db.count_active_user_messages("user1", 50).unwrap(), 2);
+        // expired messages don't count even before they are removed
+        assert_eq!(db.count_active_user_messages("user1", 150).unwrap(), 1);
+
+        db.try_consume_message("token1").unwrap();
+        assert_eq!(db.count_active_user_messages("user1", 50).unwrap(), 1);
+        assert_eq!(db.count_active_user_messages("user2", 50).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_save_and_consume_message() {
+        let db = setup_db();
+        db.save_message(
+            "token1",
+            12345,
+            b"Hello, world!",
+            &MessageOptions::default(),
+        )
+        .unwrap();
+
+        let (data, expire) = db.try_consume_message("token1").unwrap();
+        assert_eq!(*data.unwrap(), b"Hello, world!");
+        assert_eq!(expire, 12345);
+
+        let (data, _expire) = db.try_consume_message("token1").unwrap();
+        assert!(data.is_none());
+    }
+
+    #[test]
+    fn test_get_message_info_does_not_consume() {
+        let db = setup_db();
+        db.save_message(
+            "token1",
+            12345,
+            b"Hello, world!",
+            &MessageOptions::default(),
+        )
+        .unwrap();
+
+        let info = db.get_message_info("token1").unwrap().unwrap();
+        assert_eq!(*info.data, b"Hello, world!");
+        assert_eq!(info.expire_timestamp, 12345);
+        assert!(!info.has_passphrase);
+        assert!(!info.is_client_encrypted);
+        assert!(info.filename.is_none());
+        assert!(info.content_type.is_none());
+
+        let (data, _expire) = db.try_consume_message("token1").unwrap();
+        assert_eq!(*data.unwrap(), b"Hello, world!");
+
+        assert!(db.get_message_info("token1").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_message_with_several_views() {
+        let db = setup_db();
+        db.save_message(
+            "token1",
+            0,
+            b"Hello, world!",
+            &MessageOptions {
+                delete_token: Some("delete1".to_string()),
+                max_views: 3,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        for remaining_views in [3, 2, 1] {
+            assert_eq!(
+                db.get_message_remaining_views("token1").unwrap(),
+                Some(remaining_views)
+            );
+            assert_eq!(
+                db.get_message_info("token1")
+                    .unwrap()
+                    .unwrap()
+                    .remaining_views,
+                remaining_views
+            );
+            let (data, _expire) = db.try_consume_message("token1").unwrap();
+            assert_eq!(*data.unwrap(), b"Hello, world!");
+        }
+        assert_eq!(db.get_message_remaining_views("token1").unwrap(), None);
+        let (data, _expire) = db.try_consume_message("token1").unwrap();
+        assert!(data.is_none());
+        assert_eq!(
+            db.get_message_status("token1", "delete1")
+                .unwrap()
+                .unwrap()
+                .state,
+            MessageState::Consumed
+        );
+    }
+
+    #[test]
+    fn test_message_with_grace_window() {
+        let db = setup_db();
+        let save = |token: &str| {
+            db.save_message(
+                token,
+                0,
+                b"Hello, world!",
+                &MessageOptions {
+                    delete_token: Some(format!("delete-{}", token)),
+                    grace_minutes: 5,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        save("token1");
+        save("token2");
+
+        for _ in 0..2 {
+            let (data, _expire) = db.try_consume_message("token1").unwrap();
+            assert_eq!(*data.unwrap(), b"Hello, world!");
+            assert_eq!(db.get_message_remaining_views("token1").unwrap(), Some(0));
+        }
+
+        // the window has ended
+        db.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE messages SET grace_until=grace_until-600 WHERE message_token=?1",
+                params![hash_token("token1")],
+            )
+            .unwrap();
+        let (data, _expire) = db.try_consume_message("token1").unwrap();
+        assert!(data.is_none());
+        assert_eq!(
+            db.get_message_status("token1", "delete-token1")
+                .unwrap()
+                .unwrap()
+                .state,
+            MessageState::Consumed
+        );
+
+        // the cleanup removes it without reporting it as expired
+        let (data, _expire) = db.try_consume_message("token2").unwrap();
+        assert_eq!(*data.unwrap(), b"Hello, world!");
+        assert!(db.clear_expired_messages(now() + 60).unwrap().is_empty());
+        assert!(db.get_message_remaining_views("token2").unwrap().is_some());
+        assert!(db
+            .clear_expired_messages(now() + 6 * 60)
+            .unwrap()
+            .is_empty());
+        assert!(db.get_message_remaining_views("token2").unwrap().is_none());
+        assert_eq!(
+            db.get_message_status("token2", "delete-token2")
+                .unwrap()
+                .unwrap()
+                .state,
+            MessageState::Consumed
+        );
+    }
+
+    #[test]
+    fn test_failed_passphrase_attempts_destroy_message() {
+        let db = setup_db();
+        db.save_message(
+            "token1",
+            0,
+            b"Hello, world!",
+            &MessageOptions {
+                passphrase_hash: Some("hash".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            db.get_message_passphrase_hash("token1").unwrap().as_deref(),
+            Some("hash")
+        );
+        assert!(
+            db.get_message_info("token1")
+                .unwrap()
+                .unwrap()
+                .has_passphrase
+        );
+
+        assert!(!db.register_failed_passphrase_attempt("token1", 2).unwrap());
+        assert!(db.get_message_info("token1").unwrap().is_some());
+
+        assert!(db.register_failed_passphrase_attempt("token1", 2).unwrap());
+        assert!(db.get_message_info("token1").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_failed_passphrase_attempts_without_limit() {
+        let db = setup_db();
+        db.save_message(
+            "token1",
+            0,
+            b"Hello, world!",
+            &MessageOptions {
+                passphrase_hash: Some("hash".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        for _ in 0..10 {
+            assert!(!db.register_failed_passphrase_attempt("token1", 0).unwrap());
+        }
+        assert!(db.get_message_info("token1").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_message_data_is_encrypted_at_rest() {
+        let mut db = setup_db();
+        db.set_cipher(MessageCipher::from_base64_key(TEST_KEY).unwrap());
+        db.save_message("token1", 0, b"Hello, world!", &MessageOptions::default())
+            .unwrap();
+
+        let stored_data: Vec<u8> = db
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT data FROM messages WHERE message_token=?1",
+                params![hash_token("token1")],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_ne!(stored_data, b"Hello, world!");
+
+        assert_eq!(
+            *db.get_message_info("token1").unwrap().unwrap().data,
+            b"Hello, world!"
+        );
+        let (data, _expire) = db.try_consume_message("token1").unwrap();
+        assert_eq!(*data.unwrap(), b"Hello, world!");
+    }
+
+    #[test]
+    fn test_message_totp_secret() {
+        let mut db = setup_db();
+        db.set_cipher(MessageCipher::from_base64_key(TEST_KEY).unwrap());
+        db.save_message(
+            "token1",
+            0,
+            b"Hello, world!",
+            &MessageOptions {
+                totp_secret: Some(Zeroizing::new(b"12345678901234567890".to_vec())),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        db.save_message("token2", 0, b"Hello, world!", &MessageOptions::default())
+            .unwrap();
+
+        let stored_secret: Vec<u8> = db
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT totp_secret FROM messages WHERE message_token=?1",
+                params![hash_token("token1")],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_ne!(stored_secret, b"12345678901234567890");
+        assert_eq!(
+            db.get_message_totp_secret("token1")
+                .unwrap()
+                .unwrap()
+                .to_vec(),
+            b"12345678901234567890"
+        );
+        assert!(
+            db.get_message_info("token1")
+                .unwrap()
+                .unwrap()
+                .is_totp_protected
+        );
+        assert!(db.get_message_totp_secret("token2").unwrap().is_none());
+        assert!(
+            !db.get_message_info("token2")
+                .unwrap()
+                .unwrap()
+                .is_totp_protected
+        );
+
+        // a step is used once, and an earlier one isn't taken after a later one
+        assert!(db.use_message_totp_step("token1", 100).unwrap());
+        assert!(!db.use_message_totp_step("token1", 100).unwrap());
+        assert!(!db.use_message_totp_step("token1", 99).unwrap());
+        assert!(db.use_message_totp_step("token1", 101).unwrap());
+    }
+
+    #[test]
+    fn test_unencrypted_messages_are_readable_after_enabling_encryption() {
+        let mut db = setup_db();
+        db.save_message("token1", 0, b"Hello, world!", &MessageOptions::default())
+            .unwrap();
+        db.set_cipher(MessageCipher::from_base64_key(TEST_KEY).unwrap());
+
+        let (data, _expire) = db.try_consume_message("token1").unwrap();
+        assert_eq!(*data.unwrap(), b"Hello, world!");
+    }
+
+    #[test]
+    fn test_client_encrypted_flag_is_stored() {
+        let db = setup_db();
+        db.save_message(
+            "token1",
+            0,
+            b"Hello, world!",
+            &MessageOptions {
+                is_client_encrypted: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(
+            db.get_message_info("token1")
+                .unwrap()
+                .unwrap()
+                .is_client_encrypted
+        );
+    }
+
+    #[test]
+    fn test_file_metadata_is_stored() {
+        let db = setup_db();
+        db.save_message(
+            "token1",
+            0,
+            b"SGVsbG8gd29ybGQ=",
+            &MessageOptions {
+                filename: Some("hello.txt".to_string()),
+                content_type: Some("text/plain".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let info = db.get_message_info("token1").unwrap().unwrap();
+        assert_eq!(info.filename.as_deref(), Some("hello.txt"));
+        assert_eq!(info.content_type.as_deref(), Some("text/plain"));
+    }
+
+    #[cfg(not(feature = "sqlcipher"))]
+    #[test]
+    fn test_database_key_needs_sqlcipher() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+        assert!(OneTimeShareDb::connect_with_key(path, "secret").is_err());
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn test_database_file_is_encrypted() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+        let db = OneTimeShareDb::connect_with_key(path, "secret").unwrap();
+        update_version(&db).unwrap();
+        db.set_user_limits("user_token_1", &UserLimits::default())
+            .unwrap();
+        db.close().unwrap();
+
+        let file = std::fs::read(path).unwrap();
+        assert!(!file.starts_with(b"SQLite format 3"));
+        assert!(!file
+            .windows(b"user_token_1".len())
+            .any(|window| window == b"user_token_1"));
+        assert!(OneTimeShareDb::connect_with_key(path, "wrong").is_err());
+        let db = OneTimeShareDb::connect_with_key(path, "secret").unwrap();
+        assert!(db.get_user_limits("user_token_1").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_message_is_found_by_slug() {
+        let mut db = setup_db();
+        db.set_signer(MessageSigner::from_base64_key(TEST_KEY).unwrap());
+        db.save_message(
+            "token1",
+            0,
+            b"Hello",
+            &MessageOptions {
+                slug: Some("db-password".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(db.is_message_name_taken("db-password").unwrap());
+        assert!(db.is_message_name_taken("token1").unwrap());
+        assert!(!db.is_message_name_taken("db-password-2").unwrap());
+        assert!(db
+            .get_message_passphrase_hash("db-password")
+            .unwrap()
+            .is_none());
+        let (data, _expire) = db.try_consume_message("db-password").unwrap();
+        assert_eq!(*data.unwrap(), b"Hello");
+        assert!(!db.is_message_name_taken("db-password").unwrap());
+    }
+
+    #[test]
+    fn test_delete_message() {
+        let db = setup_db();
+        db.save_message(
+            "token1",
+            0,
+            b"Hello",
+            &MessageOptions {
+                delete_token: Some("delete1".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        db.save_message("token2", 0, b"Hello", &MessageOptions::default())
+            .unwrap();
+
+        assert!(!db.delete_message("token1", "delete2").unwrap());
+        assert!(!db.delete_message("token2", "delete1").unwrap());
+        assert!(!db.delete_message("unknown", "delete1").unwrap());
+        assert!(db.is_message_name_taken("token1").unwrap());
+
+        assert!(db.delete_message("token1", "delete1").unwrap());
+        assert!(!db.is_message_name_taken("token1").unwrap());
+        assert!(!db.delete_message("token1", "delete1").unwrap());
+        // a message stored without a delete token can't be removed this way
+        assert!(!db.delete_message("token2", "").unwrap());
+    }
+
+    #[test]
+    fn test_message_status() {
+        let db = setup_db();
+        let save = |message_token: &str, expire_timestamp: i64| {
+            db.save_message(
+                message_token,
+                expire_timestamp,
+                b"Hello",
+                &MessageOptions {
+                    delete_token: Some(format!("delete-{}", message_token)),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        };
+        let state = |message_token: &str| {
+            db.get_message_status(message_token, &format!("delete-{}", message_token))
+                .unwrap()
+                .map(|status| status.state)
+        };
+        save("read", 0);
+        save("deleted", 0);
+        save("expired", 100);
+
+        let status = db
+            .get_message_status("read", "delete-read")
+            .unwrap()
+            .unwrap();
+        assert_eq!(status.state, MessageState::Pending);
+        assert!(status.created_timestamp.unwrap() > 0);
+        assert!(status.removed_timestamp.is_none());
+        assert!(db.get_message_status("read", "wrong").unwrap().is_none());
+
+        db.try_consume_message("read").unwrap();
+        db.save_read_receipt(
+            "read",
+            &ReadReceipt {
+                read_timestamp: 150,
+                client_ip: None,
+                user_agent: None,
+            },
+        )
+        .unwrap();
+        let status = db
+            .get_message_status("read", "delete-read")
+            .unwrap()
+            .unwrap();
+        assert_eq!(status.state, MessageState::Consumed);
+        assert_eq!(status.receipt.unwrap().read_timestamp, 150);
+        assert!(status.removed_timestamp.unwrap() >= status.created_timestamp.unwrap());
+        assert!(db.get_message_status("read", "wrong").unwrap().is_none());
+
+        db.delete_message("deleted", "delete-deleted").unwrap();
+        assert_eq!(state("deleted"), Some(MessageState::Deleted));
+
+        db.clear_expired_messages(200).unwrap();
+        assert_eq!(state("expired"), Some(MessageState::Expired));
+        assert_eq!(state("unknown"), None);
+
+        // and forgotten after a while
+        db.clear_expired_messages(i64::MAX / 2).unwrap();
+        assert_eq!(state("read"), None);
+        let conn = db.conn.lock().unwrap();
+        let receipt_count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM message_receipts", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(receipt_count, 0);
+    }
+
+    #[test]
+    fn test_tampered_messages_are_refused() {
+        let mut db = setup_db();
+        db.set_signer(MessageSigner::from_base64_key(TEST_KEY).unwrap());
+        for message_token in ["intact", "data", "expiry", "untagged"] {
+            db.save_message(message_token, 0, b"Hello", &MessageOptions::default())
+                .unwrap();
+        }
+        {
+            let conn = db.conn.lock().unwrap();
+            conn.execute(
+                "UPDATE messages SET data=X'4a656c6c6f' WHERE message_token=?1",
+                params![hash_token("data")],
+            )
+            .unwrap();
+            conn.execute(
+                "UPDATE messages SET expire_timestamp=100 WHERE message_token=?1",
+                params![hash_token("expiry")],
+            )
+            .unwrap();
+            conn.execute(
+                "UPDATE messages SET integrity_tag=NULL WHERE message_token=?1",
+                params![hash_token("untagged")],
+            )
+            .unwrap();
+        }
+
+        assert_eq!(
+            *db.get_message_info("intact").unwrap().unwrap().data,
+            b"Hello"
+        );
+        let (data, _expire) = db.try_consume_message("intact").unwrap();
+        assert_eq!(*data.unwrap(), b"Hello");
+        for message_token in ["data", "expiry", "untagged"] {
+            // the error gets logged, it names the message without its token
+            let err = db
+                .get_message_info(message_token)
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(err.contains(&hash_token(message_token)[..16]));
+            assert!(!err.contains(message_token));
+            assert!(db.try_consume_message(message_token).is_err());
+            // refused messages are removed on the first attempt to read them
+            let (data, _expire) = db.try_consume_message(message_token).unwrap();
+            assert!(data.is_none());
+        }
+    }
+
+    #[test]
+    fn test_checksum_is_stored() {
+        let db = setup_db();
+        db.save_message("token1", 0, b"Hello", &MessageOptions::default())
+            .unwrap();
+        db.save_message(
+            "token2",
+            0,
+            b"Hello",
+            &MessageOptions {
+                checksum: Some("abc123".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let info = db.get_message_info("token1").unwrap().unwrap();
+        assert!(info.checksum.is_none());
+        let info = db.get_message_info("token2").unwrap().unwrap();
+        assert_eq!(info.checksum.as_deref(), Some("abc123"));
+    }
+
+    #[test]
+    fn test_messages_are_moved_to_the_newest_key() {
+        let mut db = setup_db();
+        let blob_store = TestBlobStore::default();
+        db.set_blob_store(Box::new(blob_store.clone()), 64);
+        let large_data = b"Hello, world!".repeat(10);
+
+        db.save_message("plain", 0, b"Plain", &MessageOptions::default())
+            .unwrap();
+        db.set_cipher(MessageCipher::from_base64_key(TEST_KEY).unwrap());
+        db.save_message(
+            "old",
+            0,
+            b"Old",
+            &MessageOptions {
+                totp_secret: Some(Zeroizing::new(b"12345678901234567890".to_vec())),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        db.save_message("old_large", 0, &large_data, &MessageOptions::default())
+            .unwrap();
+        transfer_blobs(&db);
+        db.add_cipher(
+            "2024",
+            MessageCipher::from_base64_key("ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=").unwrap(),
+        );
+        db.save_message("new", 0, b"New", &MessageOptions::default())
+            .unwrap();
+
+        let key_id = |message_token: &str| -> Option<String> {
+            db.conn
+                .lock()
+                .unwrap()
+                .query_row(
+                    "SELECT encryption_key_id FROM messages WHERE message_token=?1",
+                    params![hash_token(message_token)],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        assert_eq!(key_id("plain"), None);
+        assert_eq!(key_id("old").as_deref(), Some(DEFAULT_KEY_ID));
+        assert_eq!(key_id("new").as_deref(), Some("2024"));
+
+        assert_eq!(db.reencrypt_messages().unwrap(), 3);
+        assert_eq!(db.reencrypt_messages().unwrap(), 0);
+        for message_token in ["plain", "old", "old_large", "new"] {
+            assert_eq!(key_id(message_token).as_deref(), Some("2024"));
+        }
+
+        // the old key can go now
+        db.ciphers.retain(|(key_id, _)| key_id == "2024");
+        assert_eq!(
+            db.get_message_totp_secret("old").unwrap().unwrap().to_vec(),
+            b"12345678901234567890"
+        );
+        let (data, _expire) = db.try_consume_message("plain").unwrap();
+        assert_eq!(*data.unwrap(), b"Plain");
+        let (data, _expire) = db.try_consume_message("old").unwrap();
+        assert_eq!(*data.unwrap(), b"Old");
+        let (data, _expire) = db.try_consume_message("old_large").unwrap();
+        assert_eq!(*data.unwrap(), large_data);
+        let (data, _expire) = db.try_consume_message("new").unwrap();
+        assert_eq!(*data.unwrap(), b"New");
+    }
+
+    #[test]
+    fn test_failed_reencryption_keeps_the_message() {
+        let mut db = setup_db();
+        let blob_store = TestBlobStore::default();
+        db.set_blob_store(Box::new(blob_store.clone()), 64);
+        db.set_cipher(MessageCipher::from_base64_key(TEST_KEY).unwrap());
+        let large_data = b"Hello, world!".repeat(10);
+        db.save_message("large", 0, &large_data, &MessageOptions::default())
+            .unwrap();
+        transfer_blobs(&db);
+        db.add_cipher(
+            "2024",
+            MessageCipher::from_base64_key("ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=").unwrap(),
+        );
+        let blobs_before = blob_store.blobs.lock().unwrap().clone();
 
-fn make_all_updaters() -> Vec<DbUpdater> {
-    vec![]
-}
+        db.conn
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "CREATE TRIGGER fail_update BEFORE UPDATE ON messages BEGIN SELECT RAISE(FAIL, 'failed'); END",
+            )
+            .unwrap();
+        assert!(db.reencrypt_messages().is_err());
+        // the old blob is untouched and the new one is gone again
+        assert_eq!(*blob_store.blobs.lock().unwrap(), blobs_before);
+        assert_eq!(
+            *db.get_message_info("large").unwrap().unwrap().data,
+            large_data
+        );
 
-#[derive(Clone)]
-struct DbUpdater {
-    version: &'static str,
-    update_db: fn(&OneTimeShareDb) -> Result<()>,
-}
+        db.conn
+            .lock()
+            .unwrap()
+            .execute_batch("DROP TRIGGER fail_update")
+            .unwrap();
+        assert_eq!(db.reencrypt_messages().unwrap(), 1);
+        let blobs_after = blob_store.blobs.lock().unwrap().clone();
+        assert_eq!(blobs_after.len(), 1);
+        assert!(blobs_after
+            .keys()
+            .all(|key| !blobs_before.contains_key(key)));
+        let (data, _expire) = db.try_consume_message("large").unwrap();
+        assert_eq!(*data.unwrap(), large_data);
+    }
 
-impl DbUpdater {
-    pub fn update_db(&self, db: &OneTimeShareDb) -> Result<()> {
-        (self.update_db)(db)
+    #[test]
+    fn test_large_message_is_kept_in_blob_store() {
+        let mut db = setup_db();
+        let blob_store = TestBlobStore::default();
+        db.set_cipher(MessageCipher::from_base64_key(TEST_KEY).unwrap());
+        db.set_blob_store(Box::new(blob_store.clone()), 64);
+        let large_data = b"Hello, world!".repeat(10);
+
+        db.save_message("small", 0, b"Hi", &MessageOptions::default())
+            .unwrap();
+        db.save_message("large", 0, &large_data, &MessageOptions::default())
+            .unwrap();
+        // read from memory until the upload is made
+        assert!(blob_store.blobs.lock().unwrap().is_empty());
+        assert_eq!(
+            *db.get_message_info("large").unwrap().unwrap().data,
+            large_data
+        );
+        transfer_blobs(&db);
+        assert_eq!(blob_store.blobs.lock().unwrap().len(), 1);
+        assert!(!blob_store
+            .blobs
+            .lock()
+            .unwrap()
+            .values()
+            .any(|blob| *blob == large_data));
+
+        let stored_data: Vec<u8> = db
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT data FROM messages WHERE message_token=?1",
+                params![hash_token("large")],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(stored_data.is_empty());
+
+        assert_eq!(
+            *db.get_message_info("large").unwrap().unwrap().data,
+            large_data
+        );
+        let (data, _expire) = db.try_consume_message("large").unwrap();
+        assert_eq!(*data.unwrap(), large_data);
+        transfer_blobs(&db);
+        assert!(blob_store.blobs.lock().unwrap().is_empty());
+
+        let (data, _expire) = db.try_consume_message("small").unwrap();
+        assert_eq!(*data.unwrap(), b"Hi");
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::NamedTempFile;
+    #[test]
+    fn test_message_data_is_compressed() {
+        let mut db = setup_db();
+        db.set_cipher(MessageCipher::from_base64_key(TEST_KEY).unwrap());
+        db.set_compression_threshold(64);
+        let large_data = b"Hello, world!".repeat(100);
 
-    fn setup_db() -> OneTimeShareDb {
-        let temp_file = NamedTempFile::new().unwrap();
-        OneTimeShareDb::connect(temp_file.path().to_str().unwrap()).unwrap()
+        db.save_message("small", 0, b"Hello, world!", &MessageOptions::default())
+            .unwrap();
+        db.save_message("large", 0, &large_data, &MessageOptions::default())
+            .unwrap();
+        db.save_message(
+            "client_encrypted",
+            0,
+            &large_data,
+            &MessageOptions {
+                is_client_encrypted: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let stored: Vec<(Vec<u8>, bool)> = {
+            let conn = db.conn.lock().unwrap();
+            let mut stmt = conn
+                .prepare("SELECT data, is_compressed FROM messages ORDER BY id")
+                .unwrap();
+            let rows = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            rows
+        };
+        assert!(!stored[0].1);
+        assert!(stored[1].1);
+        assert!(stored[1].0.len() < large_data.len() / 4);
+        assert!(!stored[2].1);
+
+        assert_eq!(
+            *db.get_message_info("large").unwrap().unwrap().data,
+            large_data
+        );
+        let (data, _expire) = db.try_consume_message("large").unwrap();
+        assert_eq!(*data.unwrap(), large_data);
+        let (data, _expire) = db.try_consume_message("small").unwrap();
+        assert_eq!(*data.unwrap(), b"Hello, world!");
+
+        let (data, _expire) = db.try_consume_message("client_encrypted").unwrap();
+        assert_eq!(*data.unwrap(), large_data);
+
+        // the flag of every message tells how to read it, whatever the setting is now
+        db.save_message("compressed", 0, &large_data, &MessageOptions::default())
+            .unwrap();
+        db.compression_threshold_bytes = None;
+        let (data, _expire) = db.try_consume_message("compressed").unwrap();
+        assert_eq!(*data.unwrap(), large_data);
     }
 
     #[test]
-    fn test_get_and_set_database_version() {
-        let db = setup_db();
+    fn test_expired_message_blobs_are_deleted() {
+        let mut db = setup_db();
+        let blob_store = TestBlobStore::default();
+        db.set_blob_store(Box::new(blob_store.clone()), 0);
+
+        db.save_message("token1", 100, b"Hello, world!", &MessageOptions::default())
+            .unwrap();
+        db.save_message("token2", 200, b"Hello, again!", &MessageOptions::default())
+            .unwrap();
+        transfer_blobs(&db);
+        assert_eq!(blob_store.blobs.lock().unwrap().len(), 2);
+
+        db.clear_expired_messages(160).unwrap();
+        transfer_blobs(&db);
+        assert_eq!(blob_store.blobs.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_database_is_updated_from_minimal_version() {
+        let db = OneTimeShareDb::connect_in_memory().unwrap();
+        {
+            let conn = db.conn.lock().unwrap();
+            conn.execute("DROP TABLE messages", []).unwrap();
+            conn.execute("DROP TABLE users", []).unwrap();
+            conn.execute(
+                "CREATE TABLE users (
+                    id INTEGER PRIMARY KEY,
+                    token TEXT NOT NULL UNIQUE,
+                    retention_limit_minutes INTEGER NOT NULL,
+                    max_size_bytes INTEGER NOT NULL,
+                    message_creation_limit_minutes INTEGER NOT NULL,
+                    last_message_creation_timestamp INTEGER
+                )",
+                [],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO users (token, retention_limit_minutes, max_size_bytes, message_creation_limit_minutes, last_message_creation_timestamp)
+                VALUES ('user1', 60, 1024, 5, 100)",
+                [],
+            )
+            .unwrap();
+            conn.execute(
+                "CREATE TABLE messages (
+                    id INTEGER PRIMARY KEY,
+                    message_token TEXT NOT NULL UNIQUE,
+                    expire_timestamp INTEGER NOT NULL,
+                    data TEXT NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        }
+        db.set_database_version(MINIMAL_VERSION).unwrap();
+
+        update_version(&db).unwrap();
         assert_eq!(db.get_database_version().unwrap(), LATEST_VERSION);
+        db.save_message("token1", 0, b"Hello, world!", &MessageOptions::default())
+            .unwrap();
+        let (data, _expire) = db.try_consume_message("token1").unwrap();
+        assert_eq!(*data.unwrap(), b"Hello, world!");
 
-        db.set_database_version("0.2").unwrap();
-        assert_eq!(db.get_database_version().unwrap(), "0.2");
+        // the last creation time is carried over into the window
+        let limits = db.get_user_limits("user1").unwrap().unwrap();
+        assert_eq!(limits.message_creation_limit_count, 1);
+        assert_eq!(limits.active_message_limit, 0);
+        assert_eq!(
+            db.try_register_message_creation("user1", 200, 300, 1)
+                .unwrap(),
+            Some(200)
+        );
     }
 
     #[test]
-    fn test_set_and_get_user_limits() {
-        let db = setup_db();
-        db.set_user_limits("user1", 60, 1024, 5).unwrap();
+    fn test_base64_messages_are_decoded_on_update() {
+        let mut db = setup_db();
+        let blob_store = TestBlobStore::default();
+        let cipher = MessageCipher::from_base64_key(TEST_KEY).unwrap();
+        let encrypted = STANDARD.encode(cipher.encrypt(b"SGVsbG8gd29ybGQ=").unwrap());
+        blob_store.put("blob1", b"SGVsbG8sIGJsb2Ih").unwrap();
+        db.set_cipher(cipher);
+        db.set_blob_store(Box::new(blob_store.clone()), 1024);
+        {
+            let conn = db.conn.lock().unwrap();
+            // the table as it was before the compression, the checksums, the tags, the key ids
+            // and the slugs
+            drop_slug_columns(&conn);
+            conn.execute("ALTER TABLE messages DROP COLUMN is_compressed", [])
+                .unwrap();
+            conn.execute("ALTER TABLE messages DROP COLUMN checksum", [])
+                .unwrap();
+            conn.execute("ALTER TABLE messages DROP COLUMN integrity_tag", [])
+                .unwrap();
+            conn.execute("ALTER TABLE messages DROP COLUMN encryption_key_id", [])
+                .unwrap();
+            conn.execute(
+                "INSERT INTO messages (message_token, expire_timestamp, data, is_encrypted, blob_key) VALUES
+                ('plain', 0, 'SGVsbG8gd29ybGQ=', 0, NULL),
+                ('encrypted', 0, ?1, 1, NULL),
+                ('offloaded', 0, '', 0, 'blob1')",
+                params![encrypted],
+            )
+            .unwrap();
+        }
+        db.set_database_version("0.10").unwrap();
 
-        let (found, retention, max_size, creation_limit) = db.get_user_limits("user1").unwrap();
-        assert!(found);
-        assert_eq!(retention, 60);
-        assert_eq!(max_size, 1024);
-        assert_eq!(creation_limit, 5);
+        update_version(&db).unwrap();
+        assert_eq!(
+            *db.get_message_info("plain").unwrap().unwrap().data,
+            b"Hello world"
+        );
+        let (data, _expire) = db.try_consume_message("plain").unwrap();
+        assert_eq!(*data.unwrap(), b"Hello world");
+        let (data, _expire) = db.try_consume_message("encrypted").unwrap();
+        assert_eq!(*data.unwrap(), b"Hello world");
+        let (data, _expire) = db.try_consume_message("offloaded").unwrap();
+        assert_eq!(*data.unwrap(), b"Hello, blob!");
+    }
+
+    // the columns of 0.17 and later
+    fn drop_slug_columns(conn: &Connection) {
+        conn.execute("ALTER TABLE users DROP COLUMN created_timestamp", [])
+            .unwrap();
+        conn.execute("ALTER TABLE users DROP COLUMN key_name", [])
+            .unwrap();
+        conn.execute("ALTER TABLE users DROP COLUMN account_id", [])
+            .unwrap();
+        conn.execute("ALTER TABLE messages DROP COLUMN allowed_ips", [])
+            .unwrap();
+        conn.execute("ALTER TABLE messages DROP COLUMN failed_pin_attempts", [])
+            .unwrap();
+        conn.execute("ALTER TABLE messages DROP COLUMN pin_hash", [])
+            .unwrap();
+        conn.execute("ALTER TABLE messages DROP COLUMN totp_last_step", [])
+            .unwrap();
+        conn.execute("ALTER TABLE messages DROP COLUMN totp_secret", [])
+            .unwrap();
+        conn.execute(
+            "ALTER TABLE messages DROP COLUMN second_authorization_hash",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "ALTER TABLE messages DROP COLUMN first_authorization_hash",
+            [],
+        )
+        .unwrap();
+        conn.execute("ALTER TABLE messages DROP COLUMN not_before", [])
+            .unwrap();
+        conn.execute("ALTER TABLE messages DROP COLUMN grace_until", [])
+            .unwrap();
+        conn.execute("ALTER TABLE messages DROP COLUMN grace_minutes", [])
+            .unwrap();
+        conn.execute("ALTER TABLE messages DROP COLUMN remaining_views", [])
+            .unwrap();
+        conn.execute("ALTER TABLE users DROP COLUMN email", [])
+            .unwrap();
+        conn.execute("ALTER TABLE messages DROP COLUMN webhook_url", [])
+            .unwrap();
+        conn.execute("ALTER TABLE users DROP COLUMN webhook_url", [])
+            .unwrap();
+        conn.execute("ALTER TABLE messages DROP COLUMN created_timestamp", [])
+            .unwrap();
+        conn.execute("ALTER TABLE messages DROP COLUMN delete_token", [])
+            .unwrap();
+        conn.execute("DROP INDEX message_slug_index", []).unwrap();
+        conn.execute("ALTER TABLE messages DROP COLUMN slug", [])
+            .unwrap();
+        conn.execute("ALTER TABLE users DROP COLUMN custom_slugs", [])
+            .unwrap();
     }
 
     #[test]
-    fn test_set_and_get_user_last_message_creation_time() {
+    fn test_tokens_are_hashed_on_update() {
         let db = setup_db();
-        db.set_user_limits("user1", 60, 1024, 5).unwrap();
+        {
+            let conn = db.conn.lock().unwrap();
+            drop_slug_columns(&conn);
+            conn.execute(
+                "INSERT INTO users (token, retention_limit_minutes, max_size_bytes, message_creation_limit_minutes) VALUES ('user1', 60, 1024, 0)",
+                [],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO messages (message_token, expire_timestamp, data, user_token) VALUES ('token1', 0, X'48656c6c6f', 'user1')",
+                [],
+            )
+            .unwrap();
+        }
+        db.set_database_version("0.15").unwrap();
+
+        update_version(&db).unwrap();
+        assert_eq!(
+            db.get_user_limits("user1")
+                .unwrap()
+                .unwrap()
+                .retention_limit_minutes,
+            60
+        );
+        assert_eq!(db.count_active_user_messages("user1", 0).unwrap(), 1);
+        let (data, _expire) = db.try_consume_message("token1").unwrap();
+        assert_eq!(*data.unwrap(), b"Hello");
 
-        db.set_user_last_message_creation_time("user1", 12345)
+        let conn = db.conn.lock().unwrap();
+        let stored_token: String = conn
+            .query_row("SELECT token FROM users", [], |row| row.get(0))
             .unwrap();
-        let timestamp = db.get_user_last_message_creation_time("user1").unwrap();
-        assert_eq!(timestamp, 12345);
+        assert_eq!(stored_token, hash_token("user1"));
     }
 
     #[test]
-    fn test_save_and_consume_message() {
-        let db = setup_db();
-        db.save_message("token1", 12345, "Hello, world!").unwrap();
+    fn test_data_persists_after_reconnect() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
 
-        let (data, expire) = db.try_consume_message("token1").unwrap();
-        assert_eq!(data.unwrap(), "Hello, world!");
-        assert_eq!(expire, 12345);
+        {
+            let db = OneTimeShareDb::connect(path).unwrap();
+            update_version(&db).unwrap();
+            db.set_user_limits("user1", &UserLimits::default()).unwrap();
+            db.save_message("token1", 0, b"Hello, world!", &MessageOptions::default())
+                .unwrap();
+        }
 
+        let db = OneTimeShareDb::connect(path).unwrap();
+        update_version(&db).unwrap();
+        assert!(db.get_user_limits("user1").unwrap().is_some());
         let (data, _expire) = db.try_consume_message("token1").unwrap();
-        assert!(data.is_none());
+        assert_eq!(*data.unwrap(), b"Hello, world!");
+    }
+
+    #[test]
+    fn test_closed_database_is_not_usable() {
+        let db = setup_db();
+        db.save_message("token1", 0, b"Hello, world!", &MessageOptions::default())
+            .unwrap();
+
+        db.close().unwrap();
+        assert!(db.get_message_info("token1").is_err());
+    }
+
+    #[test]
+    fn test_in_memory_data_does_not_persist() {
+        {
+            let db = OneTimeShareDb::connect_in_memory().unwrap();
+            db.save_message("token1", 0, b"Hello, world!", &MessageOptions::default())
+                .unwrap();
+        }
+
+        let db = OneTimeShareDb::connect_in_memory().unwrap();
+        assert!(db.get_message_info("token1").unwrap().is_none());
     }
 
     #[test]
     fn test_clear_expired_messages() {
         let db = setup_db();
-        db.save_message("token1", 100, "Hello, world!").unwrap();
-        db.save_message("token2", 200, "Hello, again!").unwrap();
+        db.save_message("token1", 100, b"Hello, world!", &MessageOptions::default())
+            .unwrap();
+        db.save_message("token2", 200, b"Hello, again!", &MessageOptions::default())
+            .unwrap();
+        db.save_message("token3", 0, b"Hello, forever!", &MessageOptions::default())
+            .unwrap();
+        db.save_message(
+            "token4",
+            150,
+            b"Hello, hook!",
+            &MessageOptions {
+                slug: Some("with-hook".to_string()),
+                webhook_url: Some("https://hooks.example.com/abc".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let expired_messages = db.clear_expired_messages(160).unwrap();
+        assert_eq!(
+            expired_messages,
+            vec![
+                ExpiredMessage {
+                    name_hash: hash_token("token1"),
+                    expire_timestamp: 100,
+                    webhook_url: None,
+                    creator_email: None,
+                },
+                ExpiredMessage {
+                    name_hash: hash_token("with-hook"),
+                    expire_timestamp: 150,
+                    webhook_url: Some("https://hooks.example.com/abc".to_string()),
+                    creator_email: None,
+                },
+            ]
+        );
+        let (data, _expire) = db.try_consume_message("token3").unwrap();
+        assert_eq!(*data.unwrap(), b"Hello, forever!");
 
-        db.clear_expired_messages(160).unwrap();
         let (data, _expire) = db.try_consume_message("token1").unwrap();
         assert!(data.is_none());
 
         let (data, _expire) = db.try_consume_message("token2").unwrap();
-        assert_eq!(data.unwrap(), "Hello, again!");
+        assert_eq!(*data.unwrap(), b"Hello, again!");
     }
 
     #[test]
     fn test_remove_user_limits() {
         let db = setup_db();
-        db.set_user_limits("user1", 60, 1024, 5).unwrap();
-        assert!(db.get_user_limits("user1").unwrap().0);
+        db.set_user_limits("user1", &UserLimits::default()).unwrap();
+        assert!(db.get_user_limits("user1").unwrap().is_some());
 
-        db.set_user_limits("user2", 60, 1024, 5).unwrap();
-        assert!(db.get_user_limits("user2").unwrap().0);
+        db.set_user_limits("user2", &UserLimits::default()).unwrap();
+        assert!(db.get_user_limits("user2").unwrap().is_some());
 
         db.remove_user_by_token("user1").unwrap();
-        assert!(!db.get_user_limits("user1").unwrap().0);
+        assert!(db.get_user_limits("user1").unwrap().is_none());
 
         db.remove_user_by_token("user2").unwrap();
-        assert!(!db.get_user_limits("user2").unwrap().0);
+        assert!(db.get_user_limits("user2").unwrap().is_none());
     }
 
     #[test]
@@ -318,62 +4266,481 @@ mod tests {
         let db = setup_db();
         let token = "321";
 
-        db.set_user_limits(token, 1, 2, 3).unwrap();
-        {
-            let (is_found, retention_limit_minutes, max_size_bytes, creation_limit_minutes) =
-                db.get_user_limits(token).unwrap();
-            assert!(is_found);
-            assert_eq!(retention_limit_minutes, 1);
-            assert_eq!(max_size_bytes, 2);
-            assert_eq!(creation_limit_minutes, 3);
-        }
+        let first_limits = UserLimits {
+            retention_limit_minutes: 1,
+            max_message_size_bytes: 2,
+            message_creation_limit_minutes: 3,
+            message_creation_limit_count: 4,
+            active_message_limit: 5,
+            monthly_byte_quota: 6,
+            custom_slugs: true,
+        };
+        db.set_user_limits(token, &first_limits).unwrap();
+        assert_eq!(db.get_user_limits(token).unwrap(), Some(first_limits));
 
-        db.set_user_limits(token, 4, 5, 6).unwrap();
-        {
-            let (is_found, retention_limit_minutes, max_size_bytes, creation_limit_minutes) =
-                db.get_user_limits(token).unwrap();
-            assert!(is_found);
-            assert_eq!(retention_limit_minutes, 4);
-            assert_eq!(max_size_bytes, 5);
-            assert_eq!(creation_limit_minutes, 6);
-        }
+        let second_limits = UserLimits {
+            retention_limit_minutes: 6,
+            max_message_size_bytes: 7,
+            message_creation_limit_minutes: 8,
+            message_creation_limit_count: 9,
+            active_message_limit: 10,
+            monthly_byte_quota: 11,
+            custom_slugs: false,
+        };
+        db.set_user_limits(token, &second_limits).unwrap();
+        assert_eq!(db.get_user_limits(token).unwrap(), Some(second_limits));
     }
 
     #[test]
-    fn test_user_last_message_creation_time() {
+    fn test_user_plan() {
         let db = setup_db();
-        let token = "123";
+        let limits = UserLimits {
+            retention_limit_minutes: 60,
+            ..Default::default()
+        };
+        db.set_user_limits("user1", &limits).unwrap();
+        assert_eq!(db.get_user_plan("user1").unwrap(), None);
 
-        db.set_user_limits(token, 1, 2, 3).unwrap();
+        db.set_user_plan("user1", Some("team")).unwrap();
+        assert_eq!(db.get_user_plan("user1").unwrap(), Some("team".to_string()));
+        // the own limits are kept for when the plan is removed again
+        assert_eq!(db.get_user_limits("user1").unwrap(), Some(limits));
+        db.set_user_plan("user1", None).unwrap();
+        assert_eq!(db.get_user_plan("user1").unwrap(), None);
 
-        {
-            let last_time = db.get_user_last_message_creation_time(token).unwrap();
-            assert_eq!(last_time, 0);
-        }
+        db.set_user_plan("user2", Some("free")).unwrap();
+        assert!(db.get_user_limits("user2").unwrap().is_some());
+        assert_eq!(db.get_user_plan("user2").unwrap(), Some("free".to_string()));
+    }
 
-        {
-            db.set_user_last_message_creation_time(token, 100).unwrap();
-            let last_time = db.get_user_last_message_creation_time(token).unwrap();
-            assert_eq!(last_time, 100);
+    #[test]
+    fn test_api_keys() {
+        let db = setup_db();
+        let account_id = db
+            .sign_in_portal_account("https://idp.example.com", "alice", None, 100)
+            .unwrap();
+        // the same person gets the same account on the next sign-in
+        assert_eq!(
+            db.sign_in_portal_account(
+                "https://idp.example.com",
+                "alice",
+                Some("alice@example.com"),
+                200
+            )
+            .unwrap(),
+            account_id
+        );
+        let other_id = db
+            .sign_in_portal_account("https://other.example.com", "alice", None, 100)
+            .unwrap();
+        assert_ne!(other_id, account_id);
+
+        let limits = UserLimits {
+            retention_limit_minutes: 60,
+            max_message_size_bytes: 1024,
+            ..Default::default()
+        };
+        let id = db
+            .create_api_key(account_id, "key1", "laptop", &limits, Some("team"), 300)
+            .unwrap();
+        assert_eq!(db.get_user_limits("key1").unwrap(), Some(limits));
+        assert_eq!(db.get_user_plan("key1").unwrap(), Some("team".to_string()));
+        assert_eq!(
+            db.list_api_keys(account_id).unwrap(),
+            vec![ApiKey {
+                id,
+                name: "laptop".to_string(),
+                created_timestamp: 300,
+                plan: Some("team".to_string()),
+            }]
+        );
+        assert!(db.list_api_keys(other_id).unwrap().is_empty());
+
+        db.set_account_plan(account_id, None).unwrap();
+        assert_eq!(db.get_user_plan("key1").unwrap(), None);
+
+        // only the account's own keys can be removed
+        assert!(!db.remove_api_key(other_id, id).unwrap());
+        assert!(db.remove_api_key(account_id, id).unwrap());
+        assert!(db.get_user_limits("key1").unwrap().is_none());
+        assert!(!db.remove_api_key(account_id, id).unwrap());
+    }
+
+    #[test]
+    fn test_registrations() {
+        let db = setup_db();
+        db.add_registration("token1", "alice@example.com", 200, 100)
+            .unwrap();
+        assert!(db.take_registration("token2", 150).unwrap().is_none());
+        assert_eq!(
+            db.take_registration("token1", 150).unwrap(),
+            Some("alice@example.com".to_string())
+        );
+        // a registration is confirmed once
+        assert!(db.take_registration("token1", 150).unwrap().is_none());
+
+        db.add_registration("token1", "alice@example.com", 200, 100)
+            .unwrap();
+        assert!(db.take_registration("token1", 200).unwrap().is_none());
+
+        // only the newest link of an address works
+        db.add_registration("token1", "alice@example.com", 200, 100)
+            .unwrap();
+        db.add_registration("token2", "alice@example.com", 300, 110)
+            .unwrap();
+        assert!(db.take_registration("token1", 150).unwrap().is_none());
+        assert!(db.take_registration("token2", 150).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_invitations() {
+        let db = setup_db();
+        let id = db
+            .create_invitation("token1", Some("team"), Some("for Bob"), 200, 100)
+            .unwrap();
+        db.create_invitation("token2", None, None, 150, 100)
+            .unwrap();
+        let invitation = Invitation {
+            id,
+            plan: Some("team".to_string()),
+            note: Some("for Bob".to_string()),
+            created_timestamp: 100,
+            expire_timestamp: 200,
+        };
+        assert_eq!(db.list_invitations(150).unwrap(), vec![invitation.clone()]);
+
+        // an invitation is used once and not after it expired
+        assert!(db.take_invitation("token2", 150).unwrap().is_none());
+        assert_eq!(db.take_invitation("token1", 120).unwrap(), Some(invitation));
+        assert!(db.take_invitation("token1", 120).unwrap().is_none());
+        assert!(db.list_invitations(120).unwrap().is_empty());
+
+        let id = db
+            .create_invitation("token3", None, None, 200, 100)
+            .unwrap();
+        assert!(db.remove_invitation(id).unwrap());
+        assert!(!db.remove_invitation(id).unwrap());
+        assert!(db.take_invitation("token3", 120).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_admin_credentials() {
+        let db = setup_db();
+        let id = db
+            .save_admin_credential("credential1", b"key", 5, "laptop", 100)
+            .unwrap();
+        assert!(db
+            .save_admin_credential("credential1", b"key", 0, "copy", 100)
+            .is_err());
+
+        db.record_admin_credential_use(id, 6, 200).unwrap();
+        let credential = db.get_admin_credential("credential1").unwrap().unwrap();
+        assert_eq!(credential.public_key, b"key");
+        assert_eq!(credential.sign_count, 6);
+        assert_eq!(credential.last_used_timestamp, Some(200));
+        assert_eq!(db.list_admin_credentials().unwrap(), vec![credential]);
+
+        assert!(db.remove_admin_credential(id).unwrap());
+        assert!(!db.remove_admin_credential(id).unwrap());
+        assert!(db.get_admin_credential("credential1").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_clear_old_webhook_deliveries() {
+        let db = setup_db();
+        let delivered_id = db
+            .enqueue_webhook("https://hooks.example.com/a", "message.consumed", "{}", 100)
+            .unwrap();
+        let pending_id = db
+            .enqueue_webhook("https://hooks.example.com/b", "message.consumed", "{}", 100)
+            .unwrap();
+        assert_eq!(db.get_due_webhooks(99, 10).unwrap().len(), 0);
+        assert_eq!(db.get_due_webhooks(100, 10).unwrap().len(), 2);
+        db.record_webhook_attempt(
+            delivered_id,
+            &WebhookAttempt {
+                timestamp: 101,
+                state: DeliveryState::Delivered,
+                status: Some(200),
+                error: None,
+                next_attempt_timestamp: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(db.get_due_webhooks(200, 10).unwrap()[0].id, pending_id);
+
+        // the pending ones stay until they are done
+        assert_eq!(db.clear_old_webhook_deliveries(200).unwrap(), 1);
+        assert!(db.get_webhook_delivery(delivered_id).unwrap().is_none());
+        assert!(db.get_webhook_delivery(pending_id).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_message_webhook_url() {
+        let db = setup_db();
+        db.set_user_webhook_url("user1", Some("https://hooks.example.com/user1"))
+            .unwrap();
+        assert_eq!(
+            db.get_user_webhook_url("user1").unwrap().as_deref(),
+            Some("https://hooks.example.com/user1")
+        );
+        for (message_token, user_token, webhook_url) in [
+            ("own", Some("user1"), Some("https://hooks.example.com/own")),
+            ("of_user", Some("user1"), None),
+            ("none", Some("user2"), None),
+            ("anonymous", None, None),
+        ] {
+            db.save_message(
+                message_token,
+                0,
+                b"Hello world",
+                &MessageOptions {
+                    user_token: user_token.map(str::to_string),
+                    webhook_url: webhook_url.map(str::to_string),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
         }
 
-        {
-            db.set_user_last_message_creation_time(token, 200).unwrap();
-            let last_time = db.get_user_last_message_creation_time(token).unwrap();
-            assert_eq!(last_time, 200);
+        let webhook_url = |message_token| db.get_message_webhook_url(message_token).unwrap();
+        assert_eq!(
+            webhook_url("own").as_deref(),
+            Some("https://hooks.example.com/own")
+        );
+        assert_eq!(
+            webhook_url("of_user").as_deref(),
+            Some("https://hooks.example.com/user1")
+        );
+        assert_eq!(webhook_url("none"), None);
+        assert_eq!(webhook_url("anonymous"), None);
+        assert_eq!(webhook_url("unknown"), None);
+
+        db.set_user_webhook_url("user1", None).unwrap();
+        assert_eq!(webhook_url("of_user"), None);
+    }
+
+    #[test]
+    fn test_message_creator_email() {
+        let db = setup_db();
+        db.set_user_email("user1", Some("alice@example.com"))
+            .unwrap();
+        assert_eq!(
+            db.get_user_email("user1").unwrap().as_deref(),
+            Some("alice@example.com")
+        );
+        for (message_token, user_token) in [("of_user", Some("user1")), ("anonymous", None)] {
+            db.save_message(
+                message_token,
+                100,
+                b"Hello world",
+                &MessageOptions {
+                    user_token: user_token.map(str::to_string),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
         }
+
+        assert_eq!(
+            db.get_message_creator_email("of_user").unwrap().as_deref(),
+            Some("alice@example.com")
+        );
+        assert_eq!(db.get_message_creator_email("anonymous").unwrap(), None);
+        assert_eq!(db.get_message_creator_email("unknown").unwrap(), None);
+
+        let mut creator_emails = db
+            .clear_expired_messages(200)
+            .unwrap()
+            .into_iter()
+            .map(|expired_message| expired_message.creator_email)
+            .collect::<Vec<_>>();
+        creator_emails.sort();
+        assert_eq!(
+            creator_emails,
+            vec![None, Some("alice@example.com".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_downloads() {
+        let mut db = setup_db();
+        db.set_cipher(MessageCipher::from_base64_key(TEST_KEY).unwrap());
+        db.set_signer(MessageSigner::from_base64_key(TEST_KEY).unwrap());
+        let download = || PendingDownload {
+            data: Zeroizing::new(b"Hello, world!".to_vec()),
+            filename: Some("hello.txt".to_string()),
+            content_type: None,
+            checksum: Some("abc".to_string()),
+            expire_timestamp: 200,
+        };
+        db.save_download("download1", &download()).unwrap();
+        db.save_download(
+            "download2",
+            &PendingDownload {
+                expire_timestamp: 100,
+                ..download()
+            },
+        )
+        .unwrap();
+
+        // encrypted at rest like the messages
+        let stored: Vec<u8> = db
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT data FROM downloads WHERE download_id=?1",
+                params![hash_token("download1")],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_ne!(stored, b"Hello, world!");
+
+        let found = db.get_download("download1", 150).unwrap().unwrap();
+        assert_eq!(*found.data, b"Hello, world!");
+        assert_eq!(found.filename.as_deref(), Some("hello.txt"));
+        assert_eq!(found.checksum.as_deref(), Some("abc"));
+        assert_eq!(found.expire_timestamp, 200);
+        assert!(db.get_download("download2", 150).unwrap().is_none());
+        assert!(db.get_download("unknown", 150).unwrap().is_none());
+        assert!(db.count_pending_download_bytes(150).unwrap() >= 13);
+        assert_eq!(db.count_pending_download_bytes(250).unwrap(), 0);
+
+        assert_eq!(db.clear_expired_downloads(150).unwrap(), 1);
+        assert!(db.remove_download("download1").unwrap());
+        assert!(!db.remove_download("download1").unwrap());
+        assert!(db.get_download("download1", 150).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_secret_requests() {
+        let db = setup_db();
+        let request = SecretRequest {
+            note: Some("the VPN password".to_string()),
+            expire_timestamp: 200,
+            max_size_bytes: 1024,
+            retention_minutes: 60,
+            sealed_response_token: vec![1, 2, 3],
+            sealed_message_token: None,
+        };
+        db.save_secret_request("request1", &request).unwrap();
+        db.save_secret_request(
+            "request2",
+            &SecretRequest {
+                expire_timestamp: 100,
+                ..request.clone()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            db.get_secret_request("request1", 150).unwrap(),
+            Some(request.clone())
+        );
+        assert_eq!(db.get_secret_request("request2", 150).unwrap(), None);
+        assert_eq!(db.get_secret_request("unknown", 150).unwrap(), None);
+
+        assert_eq!(db.clear_expired_secret_requests(150).unwrap(), 1);
+        assert!(db.remove_secret_request("request1").unwrap());
+        assert!(!db.remove_secret_request("request1").unwrap());
+        assert_eq!(db.get_secret_request("request1", 150).unwrap(), None);
+
+        // the request of a channel without retention doesn't expire
+        let channel = SecretRequest {
+            expire_timestamp: 0,
+            sealed_message_token: Some(vec![4, 5, 6]),
+            ..request
+        };
+        db.save_secret_request("channel1", &channel).unwrap();
+        assert_eq!(db.clear_expired_secret_requests(150).unwrap(), 0);
+        assert_eq!(
+            db.get_secret_request("channel1", 150).unwrap(),
+            Some(channel.clone())
+        );
+        assert!(db.open_secret_request("channel1").unwrap());
+        assert!(!db.open_secret_request("channel1").unwrap());
+        assert_eq!(
+            db.get_secret_request("channel1", 150).unwrap(),
+            Some(SecretRequest {
+                sealed_message_token: None,
+                ..channel
+            })
+        );
+    }
+
+    #[test]
+    fn test_monthly_usage() {
+        let db = setup_db();
+        assert_eq!(db.get_monthly_usage("user1", "2024-02").unwrap(), 0);
+
+        db.add_monthly_usage("user1", "2024-02", 100).unwrap();
+        db.add_monthly_usage("user1", "2024-02", 50).unwrap();
+        db.add_monthly_usage("user1", "2024-03", 10).unwrap();
+        db.add_monthly_usage("user2", "2024-02", 1).unwrap();
+        assert_eq!(db.get_monthly_usage("user1", "2024-02").unwrap(), 150);
+        assert_eq!(db.get_monthly_usage("user1", "2024-03").unwrap(), 10);
+
+        db.remove_user_by_token("user1").unwrap();
+        assert_eq!(db.get_monthly_usage("user1", "2024-02").unwrap(), 0);
+        assert_eq!(db.get_monthly_usage("user2", "2024-02").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_message_creation_sliding_window() {
+        let db = setup_db();
+        let token = "123";
+
+        assert_eq!(
+            db.try_register_message_creation(token, 100, 60, 2).unwrap(),
+            None
+        );
+        assert_eq!(
+            db.try_register_message_creation(token, 130, 60, 2).unwrap(),
+            None
+        );
+        // the first creation leaves the window at 160
+        assert_eq!(
+            db.try_register_message_creation(token, 140, 60, 2).unwrap(),
+            Some(20)
+        );
+        assert_eq!(
+            db.try_register_message_creation(token, 160, 60, 2).unwrap(),
+            None
+        );
+        assert_eq!(
+            db.try_register_message_creation(token, 170, 60, 2).unwrap(),
+            Some(20)
+        );
+        // other users have their own window
+        assert_eq!(
+            db.try_register_message_creation("456", 170, 60, 2).unwrap(),
+            None
+        );
     }
 
     #[test]
-    fn test_setting_limits_does_not_change_last_message_creation_time() {
+    fn test_setting_limits_does_not_reset_message_creations() {
         let db = setup_db();
         let token = "123";
 
-        db.set_user_limits(token, 0, 0, 0).unwrap();
-        db.set_user_last_message_creation_time(token, 100).unwrap();
-        assert_eq!(db.get_user_last_message_creation_time(token).unwrap(), 100);
+        db.set_user_limits(token, &UserLimits::default()).unwrap();
+        assert_eq!(
+            db.try_register_message_creation(token, 100, 60, 1).unwrap(),
+            None
+        );
 
-        db.set_user_limits(token, 1, 2, 3).unwrap();
-        assert_eq!(db.get_user_last_message_creation_time(token).unwrap(), 100);
+        db.set_user_limits(
+            token,
+            &UserLimits {
+                retention_limit_minutes: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            db.try_register_message_creation(token, 110, 60, 1).unwrap(),
+            Some(50)
+        );
     }
 }
@@ -0,0 +1,486 @@
+use async_std::io::{BufRead, Read};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tide::http::headers::CONTENT_TYPE;
+use tide::{Body, Request, Response, StatusCode};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::api::MESSAGE_SHA256_HEADER;
+use crate::error::AppError;
+use crate::store::{PendingDownload, Store, StoreResult};
+use crate::StaticData;
+
+// a consumed file stays in the store until a response that carries its last byte is complete,
+// so a broken download can be resumed with a Range request to its Content-Location
+pub const DOWNLOADS_PATH: &str = "/api/v1/downloads";
+const DOWNLOAD_GRACE_PERIOD: Duration = Duration::from_secs(10 * 60);
+// a file that doesn't fit next to the others is sent once and not kept for resuming
+const DEFAULT_MAX_PENDING_DOWNLOAD_BYTES: u64 = 256 * 1024 * 1024;
+pub(crate) const CONTENT_DISPOSITION: &str = "Content-Disposition";
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+pub(crate) struct FileInfo {
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub checksum: Option<String>,
+}
+
+fn now_seconds() -> tide::Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Whole,
+    // first and last byte, both included
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+// only a single range is served, a client that asks for several gets the whole file
+fn parse_range(header: Option<&str>, length: u64) -> ByteRange {
+    let spec = match header.and_then(|header| header.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Whole,
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Whole,
+    };
+    let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+        (Ok(first), Ok(last)) if first <= last => Some((first, last.min(length.saturating_sub(1)))),
+        (Ok(first), Err(_)) if last.is_empty() => Some((first, length.saturating_sub(1))),
+        // the last bytes of the file
+        (Err(_), Ok(suffix)) if first.is_empty() && suffix > 0 => {
+            Some((length.saturating_sub(suffix), length.saturating_sub(1)))
+        }
+        _ => return ByteRange::Whole,
+    };
+    match range {
+        Some((first, last)) if first < length && first <= last => ByteRange::Part(first, last),
+        _ => ByteRange::Unsatisfiable,
+    }
+}
+
+// hands out the bytes of a range to the connection as it asks for them, without reading
+// ahead, and forgets the download when it's dropped after the last byte of the file was taken
+struct RangeReader {
+    data: Zeroizing<Vec<u8>>,
+    position: usize,
+    end: usize,
+    on_complete: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl Read for RangeReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let count = buf.len().min(self.end - self.position);
+        let position = self.position;
+        buf[..count].copy_from_slice(&self.data[position..position + count]);
+        self.position += count;
+        Poll::Ready(Ok(count))
+    }
+}
+
+impl BufRead for RangeReader {
+    fn poll_fill_buf(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let reader = self.get_mut();
+        Poll::Ready(Ok(&reader.data[reader.position..reader.end]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amount: usize) {
+        self.position = (self.position + amount).min(self.end);
+    }
+}
+
+// the response is over, a broken one leaves the rest of the file to be resumed
+impl Drop for RangeReader {
+    fn drop(&mut self) {
+        if self.position == self.data.len() {
+            if let Some(on_complete) = self.on_complete.take() {
+                on_complete();
+            }
+        }
+    }
+}
+
+fn make_content_disposition(filename: Option<&str>) -> String {
+    match filename {
+        Some(filename) if !filename.is_empty() => {
+            // keep only characters that can't break out of the header value or the target directory
+            let sanitized: String = filename
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            format!("attachment; filename=\"{}\"", sanitized)
+        }
+        _ => "attachment".to_string(),
+    }
+}
+
+// without an id the download isn't kept, so the response doesn't offer to resume it
+fn download_response(
+    database: &Arc<Mutex<dyn Store>>,
+    download_id: Option<&str>,
+    download: PendingDownload,
+    range_header: Option<&str>,
+) -> Response {
+    let length = download.data.len() as u64;
+    let (status, first, last) = match parse_range(range_header, length) {
+        ByteRange::Whole => (StatusCode::Ok, 0, length.saturating_sub(1)),
+        ByteRange::Part(first, last) => (StatusCode::PartialContent, first, last),
+        ByteRange::Unsatisfiable => {
+            let mut res = Response::new(StatusCode::RequestedRangeNotSatisfiable);
+            res.insert_header("Content-Range", format!("bytes */{}", length));
+            if let Some(download_id) = download_id {
+                res.insert_header(
+                    "Content-Location",
+                    format!("{}/{}", DOWNLOADS_PATH, download_id),
+                );
+            }
+            return res;
+        }
+    };
+
+    let mut res = Response::new(status);
+    res.insert_header(
+        CONTENT_TYPE,
+        download
+            .content_type
+            .as_deref()
+            .unwrap_or(DEFAULT_CONTENT_TYPE),
+    );
+    res.insert_header(
+        CONTENT_DISPOSITION,
+        make_content_disposition(download.filename.as_deref()),
+    );
+    res.insert_header("X-Content-Type-Options", "nosniff");
+    res.insert_header("Cache-Control", "no-store");
+    if let Some(download_id) = download_id {
+        res.insert_header("Accept-Ranges", "bytes");
+        res.insert_header(
+            "Content-Location",
+            format!("{}/{}", DOWNLOADS_PATH, download_id),
+        );
+    }
+    // the checksum of the whole file, also on the answers that carry only a part of it
+    if let Some(checksum) = &download.checksum {
+        res.insert_header(MESSAGE_SHA256_HEADER, checksum.as_str());
+    }
+    if status == StatusCode::PartialContent {
+        res.insert_header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", first, last, length),
+        );
+    }
+
+    // an empty file has nothing to send
+    if length == 0 {
+        return res;
+    }
+    let on_complete = download_id.map(|download_id| {
+        let database = database.clone();
+        let download_id = download_id.to_string();
+        Box::new(move || {
+            if let Err(err) = database.lock().unwrap().remove_download(&download_id) {
+                log::error!("Failed to remove a complete download: {}", err);
+            }
+        }) as Box<dyn FnOnce() + Send + Sync>
+    });
+    let reader = RangeReader {
+        data: download.data,
+        position: first as usize,
+        end: last as usize + 1,
+        on_complete,
+    };
+    res.set_body(Body::from_reader(reader, Some((last - first + 1) as usize)));
+    res
+}
+
+// false when the file doesn't fit next to the ones already kept
+fn keep_download(
+    database: &dyn Store,
+    download_id: &str,
+    download: &PendingDownload,
+    max_pending_bytes: u64,
+    now: u64,
+) -> StoreResult<bool> {
+    let pending_bytes = database.count_pending_download_bytes(now as i64)?;
+    if pending_bytes + download.data.len() as u64 > max_pending_bytes {
+        return Ok(false);
+    }
+    database.save_download(download_id, download)?;
+    Ok(true)
+}
+
+// called with the data of a consumed file, the message itself is already gone from the store
+pub(crate) fn start_download(
+    data: &StaticData,
+    file_data: Zeroizing<Vec<u8>>,
+    info: FileInfo,
+    range_header: Option<&str>,
+) -> tide::Result<Response> {
+    let now = now_seconds()?;
+    let download_id = Uuid::new_v4().to_string();
+    let download = PendingDownload {
+        data: file_data,
+        filename: info.filename,
+        content_type: info.content_type,
+        checksum: info.checksum,
+        expire_timestamp: (now + DOWNLOAD_GRACE_PERIOD.as_secs()) as i64,
+    };
+    let max_pending_bytes = data
+        .config
+        .max_pending_download_bytes
+        .unwrap_or(DEFAULT_MAX_PENDING_DOWNLOAD_BYTES);
+    // the reader still gets the file when it can't be kept, only without the offer to resume
+    let is_kept = !download.data.is_empty()
+        && keep_download(
+            &*data.database.lock().unwrap(),
+            &download_id,
+            &download,
+            max_pending_bytes,
+            now,
+        )
+        .unwrap_or_else(|err| {
+            log::error!("Failed to keep a download: {}", err);
+            false
+        });
+    Ok(download_response(
+        &data.database,
+        is_kept.then_some(download_id.as_str()),
+        download,
+        range_header,
+    ))
+}
+
+pub async fn resume_download(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let download_id = req.param("download_id")?;
+    let range_header = req
+        .header("Range")
+        .map(|values| values.last().as_str().to_string());
+
+    let data = req.state().lock().unwrap();
+    let download = data
+        .database
+        .lock()
+        .unwrap()
+        .get_download(download_id, now_seconds()? as i64)?;
+    let download = match download {
+        Some(download) => download,
+        None => {
+            return Err(
+                AppError::NotFound("Download not found or already complete".to_string())
+                    .into_error(),
+            )
+        }
+    };
+    Ok(download_response(
+        &data.database,
+        Some(download_id),
+        download,
+        range_header.as_deref(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::CreateMessageResponse;
+    use crate::store::UserLimits;
+    use crate::tests::setup_test_data;
+    use async_std::io::ReadExt;
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use tide::http::{Method, Url};
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range(None, 10), ByteRange::Whole);
+        assert_eq!(parse_range(Some("bytes=0-4"), 10), ByteRange::Part(0, 4));
+        assert_eq!(parse_range(Some("bytes=5-"), 10), ByteRange::Part(5, 9));
+        assert_eq!(parse_range(Some("bytes=-3"), 10), ByteRange::Part(7, 9));
+        assert_eq!(parse_range(Some("bytes=5-100"), 10), ByteRange::Part(5, 9));
+        assert_eq!(parse_range(Some("bytes=10-"), 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=0-1,4-5"), 10), ByteRange::Whole);
+        assert_eq!(parse_range(Some("items=0-1"), 10), ByteRange::Whole);
+    }
+
+    #[test]
+    fn test_make_content_disposition() {
+        assert_eq!(
+            make_content_disposition(Some("key.pem")),
+            "attachment; filename=\"key.pem\""
+        );
+        assert_eq!(
+            make_content_disposition(Some("../\"evil\".sh")),
+            "attachment; filename=\"..__evil_.sh\""
+        );
+        assert_eq!(make_content_disposition(None), "attachment");
+    }
+
+    async fn setup_file(
+        max_pending_download_bytes: Option<u64>,
+    ) -> (tide::Server<Arc<Mutex<StaticData>>>, CreateMessageResponse) {
+        let app_data = setup_test_data();
+        app_data.lock().unwrap().config.max_pending_download_bytes = max_pending_download_bytes;
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    max_message_size_bytes: 1024,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let app = crate::init_app(app_data);
+
+        let mut req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages").unwrap(),
+        );
+        req.set_body(
+            tide::http::Body::from_json(&serde_json::json!({
+                "user_token": "test_token",
+                "message_data": STANDARD.encode("Hello, world!"),
+                "filename": "hello.txt",
+            }))
+            .unwrap(),
+        );
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        let created: CreateMessageResponse = res.body_json().await.unwrap();
+        (app, created)
+    }
+
+    fn consume_request(message_token: &str) -> tide::http::Request {
+        tide::http::Request::new(
+            Method::Post,
+            Url::parse(&format!(
+                "http://localhost/api/v1/files/{}/consume",
+                message_token
+            ))
+            .unwrap(),
+        )
+    }
+
+    #[async_std::test]
+    async fn test_resume_download() {
+        let (app, created) = setup_file(None).await;
+
+        let mut req = consume_request(&created.message_token);
+        req.insert_header("Range", "bytes=0-4");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PartialContent);
+        assert_eq!(res["Accept-Ranges"], "bytes");
+        assert_eq!(res["Content-Range"], "bytes 0-4/13");
+        assert_eq!(res.body_bytes().await.unwrap(), b"Hello");
+        let location = res["Content-Location"].as_str().to_string();
+
+        // the message is gone, only the one who read it knows where the rest is
+        let res: tide::http::Response = app
+            .respond(consume_request(&created.message_token))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let mut req = tide::http::Request::new(
+            Method::Get,
+            Url::parse(&format!("http://localhost{}", location)).unwrap(),
+        );
+        req.insert_header("Range", "bytes=5-");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PartialContent);
+        assert_eq!(res[MESSAGE_SHA256_HEADER], created.sha256.as_str());
+        assert_eq!(res.body_bytes().await.unwrap(), b", world!");
+
+        // the response with the last byte is complete, the download is forgotten
+        let req = tide::http::Request::new(
+            Method::Get,
+            Url::parse(&format!("http://localhost{}", location)).unwrap(),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    fn resume_request(location: &str, range: &str) -> tide::http::Request {
+        let mut req = tide::http::Request::new(
+            Method::Get,
+            Url::parse(&format!("http://localhost{}", location)).unwrap(),
+        );
+        req.insert_header("Range", range);
+        req
+    }
+
+    #[async_std::test]
+    async fn test_small_file_is_resumed_after_a_broken_transfer() {
+        let (app, created) = setup_file(None).await;
+
+        let mut res: tide::http::Response = app
+            .respond(consume_request(&created.message_token))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let location = res["Content-Location"].as_str().to_string();
+        // the connection breaks after the first bytes, the whole file fit in one buffer
+        let mut body = res.take_body();
+        let mut received = [0; 5];
+        body.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"Hello");
+        drop(body);
+        drop(res);
+
+        let mut res: tide::http::Response = app
+            .respond(resume_request(&location, "bytes=5-"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PartialContent);
+        assert_eq!(res.body_bytes().await.unwrap(), b", world!");
+
+        let res: tide::http::Response = app
+            .respond(resume_request(&location, "bytes=0-"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn test_download_over_the_budget_is_not_kept() {
+        let (app, created) = setup_file(Some(8)).await;
+
+        let mut req = consume_request(&created.message_token);
+        req.insert_header("Range", "bytes=0-4");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PartialContent);
+        assert!(res.header("Content-Location").is_none());
+        assert!(res.header("Accept-Ranges").is_none());
+        assert_eq!(res.body_bytes().await.unwrap(), b"Hello");
+
+        let data = app.state().lock().unwrap();
+        assert_eq!(
+            data.database
+                .lock()
+                .unwrap()
+                .count_pending_download_bytes(0)
+                .unwrap(),
+            0
+        );
+    }
+}
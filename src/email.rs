@@ -0,0 +1,467 @@
+use async_rustls::TlsConnector;
+use async_std::net::TcpStream;
+use async_std::{future, task};
+use futures_lite::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::http_client::load_tls_client_config;
+use crate::time_format::format_http_date;
+
+// the whole conversation with the server, a slow server doesn't hold a task forever
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+const MESSAGE_ID_PLACEHOLDER: &str = "{{.MessageId}}";
+const TIME_PLACEHOLDER: &str = "{{.Time}}";
+const LINK_PLACEHOLDER: &str = "{{.Link}}";
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    // TLS from the first byte, port 465 by default
+    Tls,
+    // upgraded after the greeting, port 587 by default
+    #[default]
+    Starttls,
+    // plain text, only for a relay on the same host, port 25 by default
+    None,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailTemplate {
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct EmailTemplates {
+    pub consumed: EmailTemplate,
+    pub expired: EmailTemplate,
+    // {{.Link}} is the confirmation link of a self-service registration, {{.Time}} when it expires
+    pub registration: EmailTemplate,
+}
+
+impl Default for EmailTemplates {
+    fn default() -> Self {
+        EmailTemplates {
+            consumed: EmailTemplate {
+                subject: "Your message was read".to_string(),
+                body: "Your message {{.MessageId}} was read on {{.Time}}.\n\nIt can't be read again.\n"
+                    .to_string(),
+            },
+            expired: EmailTemplate {
+                subject: "Your message expired unread".to_string(),
+                body: "Your message {{.MessageId}} expired on {{.Time}} without being read.\n\nIt has been removed.\n"
+                    .to_string(),
+            },
+            registration: EmailTemplate {
+                subject: "Confirm your email address".to_string(),
+                body: "Open this link to get your user token:\n\n{{.Link}}\n\nThe link works once, until {{.Time}}. If you didn't ask for it, ignore this email.\n"
+                    .to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SmtpConfig {
+    pub host: String,
+    // the default one of the security
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    // AUTH PLAIN is used when both are set
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // the address alone or "Name <address>"
+    pub from: String,
+    pub ca_bundle_path: Option<String>,
+    // {{.MessageId}} is replaced by the start of the hash of the token, {{.Time}} by the time
+    // the message was read or expired
+    #[serde(default)]
+    pub templates: EmailTemplates,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EmailEvent {
+    Consumed,
+    Expired,
+}
+
+// an address can't end a header or an SMTP command early
+pub fn is_valid_address(address: &str) -> bool {
+    !address.contains(['\r', '\n', '<', '>'])
+        && address
+            .split_once('@')
+            .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty())
+}
+
+// "Name <address>" gives "address"
+fn envelope_address(mailbox: &str) -> &str {
+    match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+fn render(template: &str, message_id: &str, timestamp: i64) -> String {
+    template
+        .replace(MESSAGE_ID_PLACEHOLDER, message_id)
+        .replace(TIME_PLACEHOLDER, &format_http_date(timestamp.max(0) as u64))
+}
+
+// the whole message as sent after DATA, lines end with CRLF and the ones starting with a dot
+// get another one
+fn format_email(from: &str, to: &str, subject: &str, body: &str, timestamp: i64) -> String {
+    let mut email = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+        from,
+        to,
+        subject.replace(['\r', '\n'], " "),
+        format_http_date(timestamp.max(0) as u64).replace("GMT", "+0000"),
+    );
+    for line in body.lines() {
+        if line.starts_with('.') {
+            email.push('.');
+        }
+        email.push_str(line);
+        email.push_str("\r\n");
+    }
+    email
+}
+
+struct SmtpConnection<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> SmtpConnection<S> {
+    fn new(stream: S) -> Self {
+        SmtpConnection {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    // the lines of a reply all start with the code, the last one has a space after it
+    async fn read_reply(&mut self, expected_code: &str) -> io::Result<()> {
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "SMTP server closed the connection",
+                ));
+            }
+            if !line.starts_with(expected_code) {
+                return Err(io::Error::other(format!(
+                    "Unexpected SMTP reply: {}",
+                    line.trim_end()
+                )));
+            }
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn command(&mut self, command: &str, expected_code: &str) -> io::Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await?;
+        self.read_reply(expected_code).await
+    }
+}
+
+#[derive(Clone)]
+pub struct EmailSender {
+    config: SmtpConfig,
+    tls_config: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl EmailSender {
+    pub fn new(config: &SmtpConfig) -> io::Result<Self> {
+        let tls_config = match config.security {
+            SmtpSecurity::None => None,
+            _ => Some(load_tls_client_config(config.ca_bundle_path.as_deref())?),
+        };
+        Ok(EmailSender {
+            config: config.clone(),
+            tls_config,
+        })
+    }
+
+    // sends in the background, a failure is logged and the email is not sent again
+    pub fn notify(&self, to: &str, event: EmailEvent, name_hash: &str, timestamp: i64) {
+        if !is_valid_address(to) {
+            log::warn!("Not sending a notification to an invalid email address");
+            return;
+        }
+        let template = match event {
+            EmailEvent::Consumed => &self.config.templates.consumed,
+            EmailEvent::Expired => &self.config.templates.expired,
+        };
+        let message_id = &name_hash[..16.min(name_hash.len())];
+        let email = format_email(
+            &self.config.from,
+            to,
+            &render(&template.subject, message_id, timestamp),
+            &render(&template.body, message_id, timestamp),
+            timestamp,
+        );
+        self.send_in_background(to, email, "an email notification");
+    }
+
+    // the link is the only way to finish the registration, so it goes to the address alone
+    pub fn send_registration_link(
+        &self,
+        to: &str,
+        link: &str,
+        expire_timestamp: i64,
+        timestamp: i64,
+    ) {
+        let template = &self.config.templates.registration;
+        let email = format_email(
+            &self.config.from,
+            to,
+            &render(&template.subject, "", expire_timestamp),
+            &render(&template.body, "", expire_timestamp).replace(LINK_PLACEHOLDER, link),
+            timestamp,
+        );
+        self.send_in_background(to, email, "a registration link");
+    }
+
+    fn send_in_background(&self, to: &str, email: String, what: &'static str) {
+        let sender = self.clone();
+        let to = to.to_string();
+        task::spawn(async move {
+            if let Err(err) = sender.send(&to, &email).await {
+                log::error!("Failed to send {}: {}", what, err);
+            }
+        });
+    }
+
+    pub async fn send(&self, to: &str, email: &str) -> io::Result<()> {
+        future::timeout(SEND_TIMEOUT, self.send_without_timeout(to, email))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "SMTP server timed out"))?
+    }
+
+    async fn send_without_timeout(&self, to: &str, email: &str) -> io::Result<()> {
+        let port = self.config.port.unwrap_or(match self.config.security {
+            SmtpSecurity::Tls => 465,
+            SmtpSecurity::Starttls => 587,
+            SmtpSecurity::None => 25,
+        });
+        let stream = TcpStream::connect((self.config.host.as_str(), port)).await?;
+        match self.config.security {
+            SmtpSecurity::Tls => {
+                let mut connection = SmtpConnection::new(self.connect_tls(stream).await?);
+                connection.read_reply("220").await?;
+                self.transmit(&mut connection, to, email).await
+            }
+            SmtpSecurity::Starttls => {
+                let mut connection = SmtpConnection::new(stream);
+                connection.read_reply("220").await?;
+                connection.command(&self.ehlo(), "250").await?;
+                connection.command("STARTTLS", "220").await?;
+                let stream = connection.stream.into_inner();
+                let mut connection = SmtpConnection::new(self.connect_tls(stream).await?);
+                self.transmit(&mut connection, to, email).await
+            }
+            SmtpSecurity::None => {
+                let mut connection = SmtpConnection::new(stream);
+                connection.read_reply("220").await?;
+                self.transmit(&mut connection, to, email).await
+            }
+        }
+    }
+
+    async fn connect_tls(
+        &self,
+        stream: TcpStream,
+    ) -> io::Result<async_rustls::client::TlsStream<TcpStream>> {
+        let domain = webpki::DNSNameRef::try_from_ascii_str(&self.config.host)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid SMTP host name"))?;
+        let tls_config = self.tls_config.clone().expect("TLS is configured");
+        TlsConnector::from(tls_config).connect(domain, stream).await
+    }
+
+    fn ehlo(&self) -> String {
+        let from = envelope_address(&self.config.from);
+        let domain = from
+            .rsplit_once('@')
+            .map_or("localhost", |(_, domain)| domain);
+        format!("EHLO {}", domain)
+    }
+
+    // everything after the greeting, over TLS unless the security is none
+    async fn transmit<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        connection: &mut SmtpConnection<S>,
+        to: &str,
+        email: &str,
+    ) -> io::Result<()> {
+        connection.command(&self.ehlo(), "250").await?;
+        if let (Some(username), Some(password)) = (&self.config.username, &self.config.password) {
+            let credentials = base64::Engine::encode(
+                &base64::engine::general_purpose::STANDARD,
+                format!("\0{}\0{}", username, password),
+            );
+            connection
+                .command(&format!("AUTH PLAIN {}", credentials), "235")
+                .await?;
+        }
+        connection
+            .command(
+                &format!("MAIL FROM:<{}>", envelope_address(&self.config.from)),
+                "250",
+            )
+            .await?;
+        connection
+            .command(&format!("RCPT TO:<{}>", to), "250")
+            .await?;
+        connection.command("DATA", "354").await?;
+        connection.command(&format!("{}.", email), "250").await?;
+        // the email is accepted, the answer to QUIT doesn't matter
+        let _ = connection.command("QUIT", "221").await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+
+    // the commands of the client and the data of the email
+    pub(crate) struct ReceivedEmail {
+        pub commands: Vec<String>,
+        pub data: String,
+    }
+
+    pub(crate) fn make_sender(port: u16) -> EmailSender {
+        EmailSender::new(&SmtpConfig {
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            security: SmtpSecurity::None,
+            username: Some("user".to_string()),
+            password: Some("secret".to_string()),
+            from: "One Time Share <noreply@share.example.com>".to_string(),
+            ca_bundle_path: None,
+            templates: EmailTemplates::default(),
+        })
+        .unwrap()
+    }
+
+    // accepts the next email sent to `listener`
+    pub(crate) async fn receive_email(listener: &TcpListener) -> ReceivedEmail {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(stream.clone());
+        let mut writer = stream;
+        writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+        let mut received = ReceivedEmail {
+            commands: Vec::new(),
+            data: String::new(),
+        };
+        let mut in_data = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await.unwrap() == 0 {
+                return received;
+            }
+            if in_data {
+                if line == ".\r\n" {
+                    in_data = false;
+                    writer.write_all(b"250 OK\r\n").await.unwrap();
+                } else {
+                    received.data.push_str(&line);
+                }
+                continue;
+            }
+            let command = line.trim_end().to_string();
+            let reply: &[u8] = match command.split(' ').next().unwrap() {
+                "EHLO" => b"250-localhost\r\n250 AUTH PLAIN\r\n",
+                "AUTH" => b"235 OK\r\n",
+                "DATA" => {
+                    in_data = true;
+                    b"354 Go ahead\r\n"
+                }
+                "QUIT" => b"221 Bye\r\n",
+                _ => b"250 OK\r\n",
+            };
+            received.commands.push(command);
+            writer.write_all(reply).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_addresses() {
+        assert!(is_valid_address("alice@example.com"));
+        assert!(!is_valid_address("alice"));
+        assert!(!is_valid_address("@example.com"));
+        assert!(!is_valid_address(
+            "alice@example.com\r\nBcc: eve@example.com"
+        ));
+        assert!(!is_valid_address("alice@example.com>"));
+
+        assert_eq!(
+            envelope_address("One Time Share <noreply@share.example.com>"),
+            "noreply@share.example.com"
+        );
+        assert_eq!(
+            envelope_address("noreply@share.example.com"),
+            "noreply@share.example.com"
+        );
+    }
+
+    #[test]
+    fn test_format_email() {
+        let email = format_email(
+            "noreply@share.example.com",
+            "alice@example.com",
+            "Read\r\nBcc: eve@example.com",
+            &render("{{.MessageId}} at {{.Time}}\n.hidden\n", "abcd", 784111777),
+            784111777,
+        );
+        assert!(email.contains("Subject: Read  Bcc: eve@example.com\r\n"));
+        assert!(email.contains("Date: Sun, 06 Nov 1994 08:49:37 +0000\r\n"));
+        assert!(email.ends_with("\r\n\r\nabcd at Sun, 06 Nov 1994 08:49:37 GMT\r\n..hidden\r\n"));
+    }
+
+    #[async_std::test]
+    async fn test_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sender = make_sender(listener.local_addr().unwrap().port());
+        let email = format_email(
+            "noreply@share.example.com",
+            "alice@example.com",
+            "Hi",
+            "Hello\n",
+            0,
+        );
+
+        let (received, result) = futures_lite::future::zip(
+            receive_email(&listener),
+            sender.send("alice@example.com", &email),
+        )
+        .await;
+        result.unwrap();
+        assert_eq!(
+            received.commands,
+            vec![
+                "EHLO share.example.com",
+                // base64 of "\0user\0secret"
+                "AUTH PLAIN AHVzZXIAc2VjcmV0",
+                "MAIL FROM:<noreply@share.example.com>",
+                "RCPT TO:<alice@example.com>",
+                "DATA",
+                "QUIT",
+            ]
+        );
+        assert_eq!(received.data, email);
+    }
+}
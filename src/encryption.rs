@@ -0,0 +1,144 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use zeroize::Zeroizing;
+
+const KEY_SIZE_BYTES: usize = 32;
+const NONCE_SIZE_BYTES: usize = 12;
+
+#[derive(Debug)]
+pub struct EncryptionError(pub String);
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for EncryptionError {}
+
+// one of the keys in `encryptionKeys`, the id is stored with every message encrypted with it
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EncryptionKeyConfig {
+    pub id: String,
+    pub key: Option<String>,
+    pub key_path: Option<String>,
+}
+
+impl EncryptionKeyConfig {
+    pub fn load(&self) -> Result<MessageCipher, EncryptionError> {
+        match (&self.key, &self.key_path) {
+            (Some(key), _) => MessageCipher::from_base64_key(key),
+            (None, Some(key_path)) => MessageCipher::from_key_file(key_path),
+            (None, None) => Err(EncryptionError(format!(
+                "Encryption key {} has neither key nor keyPath",
+                self.id
+            ))),
+        }
+    }
+}
+
+pub struct MessageCipher {
+    cipher: Aes256Gcm,
+}
+
+impl MessageCipher {
+    pub fn from_base64_key(key: &str) -> Result<Self, EncryptionError> {
+        let key = Zeroizing::new(STANDARD.decode(key.trim()).map_err(|err| {
+            EncryptionError(format!("Encryption key is not valid base64: {}", err))
+        })?);
+        Self::from_key(&key)
+    }
+
+    pub fn from_key(key: &[u8]) -> Result<Self, EncryptionError> {
+        if key.len() != KEY_SIZE_BYTES {
+            return Err(EncryptionError(format!(
+                "Encryption key should be {} bytes long, got {}",
+                KEY_SIZE_BYTES,
+                key.len()
+            )));
+        }
+        Ok(MessageCipher {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+        })
+    }
+
+    pub fn from_key_file(path: &str) -> Result<Self, EncryptionError> {
+        let key =
+            Zeroizing::new(fs::read_to_string(path).map_err(|err| {
+                EncryptionError(format!("Can't read encryption key file: {}", err))
+            })?);
+        Self::from_base64_key(&key)
+    }
+
+    // the result is the nonce followed by the ciphertext
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, data)
+            .map_err(|_| EncryptionError("Can't encrypt message".to_string()))?;
+
+        let mut result = nonce.to_vec();
+        result.extend_from_slice(&ciphertext);
+        Ok(result)
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        if data.len() < NONCE_SIZE_BYTES {
+            return Err(EncryptionError(
+                "Encrypted message is too short".to_string(),
+            ));
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE_BYTES);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| EncryptionError("Can't decrypt message".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        let cipher = MessageCipher::from_base64_key(TEST_KEY).unwrap();
+
+        let encrypted = cipher.encrypt(b"Hello, world!").unwrap();
+        assert_ne!(encrypted, b"Hello, world!");
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), b"Hello, world!");
+    }
+
+    #[test]
+    fn test_nonce_is_unique_per_message() {
+        let cipher = MessageCipher::from_base64_key(TEST_KEY).unwrap();
+        assert_ne!(
+            cipher.encrypt(b"Hello, world!").unwrap(),
+            cipher.encrypt(b"Hello, world!").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_decrypt_with_wrong_key_fails() {
+        let cipher = MessageCipher::from_base64_key(TEST_KEY).unwrap();
+        let other_cipher =
+            MessageCipher::from_base64_key("ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=").unwrap();
+
+        let encrypted = cipher.encrypt(b"Hello, world!").unwrap();
+        assert!(other_cipher.decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn test_invalid_key_size() {
+        assert!(MessageCipher::from_base64_key("c2hvcnQ=").is_err());
+    }
+}
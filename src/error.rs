@@ -0,0 +1,340 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tide::http::mime;
+use tide::{Body, Middleware, Next, Request, StatusCode};
+
+use crate::api::ErrorResponse;
+use crate::negotiate::{requested_format, Format};
+use crate::request_id::RequestId;
+use crate::store::StoreError;
+use crate::throttle::Throttled;
+
+// the errors the handlers report to the clients, the status code follows from the kind
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    // a field of the request that isn't valid, the client is told which one
+    Invalid {
+        field: &'static str,
+        message: String,
+    },
+    // a token or a passphrase that doesn't give access
+    BadToken(String),
+    // the request is understood, but not allowed from where it comes, e.g. an address a message
+    // can't be read from
+    Forbidden(String),
+    NotFound(String),
+    MethodNotAllowed,
+    Gone(String),
+    // the request doesn't fit the state of the resource, e.g. a chunk at the wrong offset
+    Conflict(String),
+    // the resource can't be used before the unix time it carries, e.g. a message not yet unlocked
+    TooEarly {
+        message: String,
+        not_before: i64,
+    },
+    TooLarge(String),
+    UnsupportedMediaType(String),
+    MisdirectedRequest(String),
+    RateLimited(Throttled),
+    Busy(Throttled),
+    // the details are logged, the client only learns that the storage failed
+    Storage(String),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) | AppError::Invalid { .. } => StatusCode::BadRequest,
+            AppError::BadToken(_) => StatusCode::Unauthorized,
+            AppError::Forbidden(_) => StatusCode::Forbidden,
+            AppError::NotFound(_) => StatusCode::NotFound,
+            AppError::MethodNotAllowed => StatusCode::MethodNotAllowed,
+            AppError::Gone(_) => StatusCode::Gone,
+            AppError::Conflict(_) => StatusCode::Conflict,
+            AppError::TooEarly { .. } => StatusCode::TooEarly,
+            AppError::TooLarge(_) => StatusCode::PayloadTooLarge,
+            AppError::UnsupportedMediaType(_) => StatusCode::UnsupportedMediaType,
+            AppError::MisdirectedRequest(_) => StatusCode::MisdirectedRequest,
+            AppError::RateLimited(_) => StatusCode::TooManyRequests,
+            AppError::Busy(_) => StatusCode::ServiceUnavailable,
+            AppError::Storage(_) => StatusCode::InternalServerError,
+        }
+    }
+
+    // what the client is shown
+    pub fn message(&self) -> &str {
+        match self {
+            AppError::BadRequest(message)
+            | AppError::BadToken(message)
+            | AppError::Forbidden(message)
+            | AppError::NotFound(message)
+            | AppError::Gone(message)
+            | AppError::Conflict(message)
+            | AppError::TooLarge(message)
+            | AppError::UnsupportedMediaType(message)
+            | AppError::MisdirectedRequest(message)
+            | AppError::Invalid { message, .. }
+            | AppError::TooEarly { message, .. } => message,
+            AppError::MethodNotAllowed => "Invalid request method",
+            AppError::RateLimited(throttled) | AppError::Busy(throttled) => &throttled.message,
+            AppError::Storage(_) => "Storage error, try again later",
+        }
+    }
+
+    pub fn retry_after_seconds(&self) -> Option<u64> {
+        match self {
+            AppError::RateLimited(throttled) | AppError::Busy(throttled) => {
+                Some(throttled.retry_after_seconds())
+            }
+            AppError::TooEarly { not_before, .. } => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |now| now.as_secs() as i64);
+                Some((not_before - now).max(1) as u64)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn not_before(&self) -> Option<u64> {
+        match self {
+            AppError::TooEarly { not_before, .. } => Some(*not_before as u64),
+            _ => None,
+        }
+    }
+
+    pub fn field(&self) -> Option<&'static str> {
+        match self {
+            AppError::Invalid { field, .. } => Some(field),
+            _ => None,
+        }
+    }
+
+    // keeps the status, so the middleware that look at it before the error is rendered see it
+    pub fn into_error(self) -> tide::Error {
+        tide::Error::new(self.status(), self)
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Storage(details) => write!(f, "Storage error: {}", details),
+            _ => f.write_str(self.message()),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<StoreError> for AppError {
+    fn from(err: StoreError) -> Self {
+        AppError::Storage(err.to_string())
+    }
+}
+
+fn describe_app_error(app_error: &AppError) -> (StatusCode, ErrorResponse) {
+    (
+        app_error.status(),
+        ErrorResponse {
+            error: app_error.message().to_string(),
+            field: app_error.field().map(str::to_string),
+            retry_after_seconds: app_error.retry_after_seconds(),
+            not_before: app_error.not_before(),
+            request_id: None,
+        },
+    )
+}
+
+fn describe_status(status: StatusCode) -> (StatusCode, ErrorResponse) {
+    (
+        status,
+        ErrorResponse {
+            error: status.canonical_reason().to_string(),
+            field: None,
+            retry_after_seconds: None,
+            not_before: None,
+            request_id: None,
+        },
+    )
+}
+
+fn describe_error(err: &tide::Error) -> (StatusCode, ErrorResponse) {
+    if let Some(app_error) = err.downcast_ref::<AppError>() {
+        return describe_app_error(app_error);
+    }
+    if let Some(store_error) = err.downcast_ref::<StoreError>() {
+        return describe_app_error(&AppError::Storage(store_error.to_string()));
+    }
+    // other errors can carry internal details, only the status is shown
+    describe_status(err.status())
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn render_html(status: StatusCode, message: &str, request_id: Option<&str>) -> String {
+    let request_id = request_id
+        .map(|request_id| {
+            format!(
+                "<p><small>Request ID: {}</small></p>\n",
+                escape_html(request_id)
+            )
+        })
+        .unwrap_or_default();
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{} {}</title>\n</head>\n<body>\n<h1>{}</h1>\n<p>{}</p>\n<p><a href=\"/\">Share a new message</a></p>\n{}</body>\n</html>\n",
+        status as u16,
+        status.canonical_reason(),
+        status.canonical_reason(),
+        escape_html(message),
+        request_id
+    )
+}
+
+// browsers that navigate to a page get HTML, the API and scripts get JSON
+fn prefers_html<State>(req: &Request<State>) -> bool {
+    !req.url().path().starts_with("/api/") && requested_format(req) == Some(Format::Html)
+}
+
+// renders the errors returned by the handlers and the middleware after this one
+pub struct ErrorResponseMiddleware;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ErrorResponseMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let is_html = prefers_html(&req);
+        let request_id = req
+            .ext::<RequestId>()
+            .map(|request_id| request_id.0.clone());
+
+        let mut res = next.run(req).await;
+
+        let (status, mut error_response) = match res.error() {
+            Some(err) => describe_error(err),
+            None if (res.status().is_client_error() || res.status().is_server_error())
+                && res.is_empty() == Some(true) =>
+            {
+                describe_status(res.status())
+            }
+            None => return Ok(res),
+        };
+
+        res.set_status(status);
+        if let Some(retry_after_seconds) = error_response.retry_after_seconds {
+            res.insert_header("Retry-After", retry_after_seconds.to_string());
+        }
+        if is_html {
+            res.set_body(render_html(
+                status,
+                &error_response.error,
+                request_id.as_deref(),
+            ));
+            res.set_content_type(mime::HTML);
+        } else {
+            error_response.request_id = request_id;
+            res.set_body(Body::from_json(&error_response)?);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tide::http::{Method, Url};
+    use tide::Response;
+
+    fn make_app() -> tide::Server<()> {
+        let mut app = tide::new();
+        app.with(ErrorResponseMiddleware);
+        app.at("/missing").get(|_| async {
+            Err::<Response, _>(AppError::NotFound("Message not found".to_string()).into_error())
+        });
+        app.at("/slow").get(|_| async {
+            Err::<Response, _>(
+                AppError::RateLimited(Throttled::new("Slow down", Duration::from_secs(30)))
+                    .into_error(),
+            )
+        });
+        app.at("/broken")
+            .get(|_| async { Err::<Response, _>(StoreError::new("disk <full>").into()) });
+        app
+    }
+
+    fn make_request(path: &str, accept: Option<&str>) -> tide::http::Request {
+        let mut req = tide::http::Request::new(
+            Method::Get,
+            Url::parse(&format!("http://localhost{}", path)).unwrap(),
+        );
+        if let Some(accept) = accept {
+            req.insert_header("Accept", accept);
+        }
+        req
+    }
+
+    #[async_std::test]
+    async fn test_json_error() {
+        let app = make_app();
+
+        let mut res: tide::http::Response = app
+            .respond(make_request("/missing", Some("application/json")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+        let body: ErrorResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.error, "Message not found");
+
+        let mut res: tide::http::Response = app.respond(make_request("/slow", None)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+        assert_eq!(res["Retry-After"], "30");
+        let body: ErrorResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.retry_after_seconds, Some(30));
+
+        // the details of storage errors stay in the logs
+        let mut res: tide::http::Response =
+            app.respond(make_request("/broken", None)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::InternalServerError);
+        let body: ErrorResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.error, "Storage error, try again later");
+    }
+
+    #[async_std::test]
+    async fn test_html_error_page() {
+        let app = make_app();
+        let mut res: tide::http::Response = app
+            .respond(make_request(
+                "/missing",
+                Some("text/html,application/xhtml+xml,*/*;q=0.8"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+        assert_eq!(res.content_type().unwrap().essence(), "text/html");
+        let body = res.take_body().into_string().await.unwrap();
+        assert!(body.contains("<h1>Not Found</h1>"));
+        assert!(body.contains("<p>Message not found</p>"));
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html("<script>\"a\" & 'b'</script>"),
+            "&lt;script&gt;&quot;a&quot; &amp; &#39;b&#39;&lt;/script&gt;"
+        );
+    }
+}
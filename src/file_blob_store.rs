@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use crate::blob_store::BlobStore;
+use crate::store::{StoreError, StoreResult};
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FileBlobStoreConfig {
+    pub path: String,
+}
+
+// keeps every payload in its own file, spread over subdirectories by the first
+// characters of the key so that no directory grows too big
+pub struct FileBlobStore {
+    root: PathBuf,
+}
+
+impl FileBlobStore {
+    pub fn new(config: &FileBlobStoreConfig) -> std::io::Result<Self> {
+        let root = PathBuf::from(&config.path);
+        fs::create_dir_all(&root)?;
+        Ok(FileBlobStore { root })
+    }
+
+    fn blob_path(&self, key: &str) -> StoreResult<PathBuf> {
+        // keys are generated by us, but never let one escape the storage directory
+        if key.len() < 4 || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(StoreError::new(format!("Invalid blob key: {}", key)));
+        }
+        Ok(self.root.join(&key[0..2]).join(&key[2..4]).join(key))
+    }
+}
+
+impl BlobStore for FileBlobStore {
+    fn put(&self, key: &str, data: &[u8]) -> StoreResult<()> {
+        let path = self.blob_path(key)?;
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        // write to a temporary file first, so a crash never leaves a partial payload
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, data)?;
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> StoreResult<Vec<u8>> {
+        Ok(fs::read(self.blob_path(key)?)?)
+    }
+
+    fn delete(&self, key: &str) -> StoreResult<()> {
+        match fs::remove_file(self.blob_path(key)?) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn setup_store() -> (FileBlobStore, TempDir) {
+        let temp_dir = TempDir::new().unwrap();
+        let store = FileBlobStore::new(&FileBlobStoreConfig {
+            path: temp_dir.path().to_str().unwrap().to_string(),
+        })
+        .unwrap();
+        (store, temp_dir)
+    }
+
+    #[test]
+    fn test_put_get_and_delete_blob() {
+        let (store, temp_dir) = setup_store();
+        let key = "0123abcd-0000-0000-0000-000000000000";
+
+        store.put(key, b"Hello, world!").unwrap();
+        assert!(temp_dir.path().join("01").join("23").join(key).exists());
+        assert_eq!(store.get(key).unwrap(), b"Hello, world!");
+
+        store.delete(key).unwrap();
+        assert!(store.get(key).is_err());
+        store.delete(key).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_keys_are_rejected() {
+        let (store, _temp_dir) = setup_store();
+
+        assert!(store.put("../../etc/passwd", b"data").is_err());
+        assert!(store.put("ab", b"data").is_err());
+        assert!(store.get("ab/cd/ef").is_err());
+    }
+}
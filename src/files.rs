@@ -0,0 +1,332 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::sync::{Arc, Mutex};
+use tide::http::headers::CONTENT_TYPE;
+use tide::{Body, Request, Response, StatusCode};
+use zeroize::Zeroizing;
+
+use crate::api::recipient_links;
+use crate::api::{
+    check_token_signature, consume_protected_message, insert_expires_in_header,
+    parse_consume_request, CreateMessageResponse,
+};
+use crate::downloads::{start_download, FileInfo};
+use crate::error::AppError;
+use crate::multipart::{boundary_from_content_type, parse_multipart};
+use crate::receipts::Reader;
+use crate::{
+    make_qr_url, make_share_url, save_new_message, text_share_url, MessageForm, StaticData,
+};
+
+fn is_valid_content_type(content_type: &str) -> bool {
+    !content_type.is_empty()
+        && content_type
+            .chars()
+            .all(|c| c.is_ascii_graphic() || c == ' ')
+}
+
+fn bad_request(message: &str) -> tide::Result {
+    Err(AppError::BadRequest(message.to_string()).into_error())
+}
+
+// the data comes as raw bytes in the "file" part, so it isn't inflated by base64 on the way,
+// "message_data" is taken too for clients that have the base64 at hand anyway
+pub(crate) async fn read_multipart_form(
+    req: &mut Request<Arc<Mutex<StaticData>>>,
+) -> tide::Result<MessageForm> {
+    let boundary = match req
+        .header(CONTENT_TYPE)
+        .and_then(|content_type| boundary_from_content_type(content_type.as_str()))
+    {
+        Some(boundary) => boundary,
+        None => {
+            return Err(
+                AppError::BadRequest("Expected a multipart/form-data body".to_string())
+                    .into_error(),
+            )
+        }
+    };
+
+    let body = Zeroizing::new(req.body_bytes().await?);
+    let fields =
+        parse_multipart(&body, &boundary).map_err(|err| AppError::BadRequest(err).into_error())?;
+
+    let mut form = MessageForm::default();
+    for field in fields {
+        let text = || String::from_utf8_lossy(&field.data).into_owned();
+        match field.name.as_str() {
+            "user_token" => form.user_token = text(),
+            "retention" => match text().parse() {
+                Ok(retention) => form.retention = Some(retention),
+                Err(_) => {
+                    return Err(
+                        AppError::BadRequest("Can't parse retention limit".to_string())
+                            .into_error(),
+                    )
+                }
+            },
+            "passphrase" => form.passphrase = Some(text()).into(),
+            "pin" => form.pin = Some(text()).into(),
+            // a field for each address, or several in one separated by commas
+            "allowed_ips" => form.allowed_ips.get_or_insert_with(Vec::new).extend(
+                text()
+                    .split(',')
+                    .map(|allowed_ip| allowed_ip.trim().to_string()),
+            ),
+            "max_views" => match text().parse() {
+                Ok(max_views) => form.max_views = Some(max_views),
+                Err(_) => {
+                    return Err(
+                        AppError::BadRequest("Can't parse max views".to_string()).into_error()
+                    )
+                }
+            },
+            "grace_minutes" => match text().parse() {
+                Ok(grace_minutes) => form.grace_minutes = Some(grace_minutes),
+                Err(_) => {
+                    return Err(
+                        AppError::BadRequest("Can't parse grace minutes".to_string()).into_error(),
+                    )
+                }
+            },
+            "not_before" => match text().parse() {
+                Ok(not_before) => form.not_before = Some(not_before),
+                Err(_) => {
+                    return Err(
+                        AppError::BadRequest("Can't parse not before time".to_string())
+                            .into_error(),
+                    )
+                }
+            },
+            "activate_in_minutes" => match text().parse() {
+                Ok(activate_in_minutes) => form.activate_in_minutes = Some(activate_in_minutes),
+                Err(_) => {
+                    return Err(
+                        AppError::BadRequest("Can't parse activation delay".to_string())
+                            .into_error(),
+                    )
+                }
+            },
+            "dual_control" => {
+                form.dual_control = Some(matches!(text().as_str(), "true" | "1" | "on"))
+            }
+            "totp_secret" => form.totp_secret = Some(text()).into(),
+            "totp" => form.totp = Some(matches!(text().as_str(), "true" | "1" | "on")),
+            "end_to_end" => form.end_to_end = Some(matches!(text().as_str(), "true" | "1" | "on")),
+            "slug" => form.slug = Some(text()),
+            "webhook_url" => form.webhook_url = Some(text()),
+            "sms_to" => form.sms_to = Some(text()),
+            "message_data" if form.message_data.is_empty() => form.message_data = text().into(),
+            "file" => {
+                form.message_data = STANDARD.encode(&field.data).into();
+                form.filename = field.filename;
+                form.content_type = field
+                    .content_type
+                    .filter(|content_type| is_valid_content_type(content_type));
+            }
+            _ => {}
+        }
+    }
+    Ok(form)
+}
+
+pub async fn upload_file(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let form = read_multipart_form(&mut req).await?;
+    if form.message_data.is_empty() {
+        return bad_request("File is missing");
+    }
+
+    let data = req.state().lock().unwrap();
+    let created = save_new_message(&data, &form)?;
+    let url = make_share_url(&req, &data.config, &created);
+    text_share_url(&data, &form, &url);
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&CreateMessageResponse {
+            url,
+            qr_url: make_qr_url(&req, &data.config, &created),
+            recipients: recipient_links(&req, &data.config, &created),
+            message_token: created.message_token,
+            expire_timestamp: created.expire_timestamp,
+            not_before: created.not_before,
+            sha256: created.checksum,
+            delete_token: created.delete_token,
+            authorization_tokens: created.authorization_tokens,
+            totp_secret: created.totp_secret,
+            totp_uri: created.totp_uri,
+        })?)
+        .build())
+}
+
+pub async fn consume_file(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let body = Zeroizing::new(req.body_string().await?);
+    let consume_request = parse_consume_request(&body)?;
+
+    let message_token = req.param("token")?;
+
+    let data = req.state().lock().unwrap();
+    // a forged token is turned away before it costs a storage lookup
+    check_token_signature(&data, message_token)?;
+    let message_info = match data
+        .database
+        .lock()
+        .unwrap()
+        .get_message_info(message_token)?
+    {
+        Some(message_info) => message_info,
+        None => return Err(AppError::NotFound("Message not found".to_string()).into_error()),
+    };
+
+    let (file_data, expire_timestamp) =
+        consume_protected_message(&data, message_token, &consume_request, &Reader::of(&req))?;
+
+    let range_header = req
+        .header("Range")
+        .map(|values| values.last().as_str().to_string());
+    let mut res = start_download(
+        &data,
+        file_data,
+        FileInfo {
+            filename: message_info.filename,
+            content_type: message_info.content_type,
+            checksum: message_info.checksum,
+        },
+        range_header.as_deref(),
+    )?;
+    insert_expires_in_header(&mut res, expire_timestamp);
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloads::CONTENT_DISPOSITION;
+    use crate::store::UserLimits;
+    use crate::tests::setup_test_data;
+    use tide::http::{Method, Request, Url};
+
+    fn make_multipart_request(url: &str, fields: &[(&str, Option<&str>, &[u8])]) -> Request {
+        let mut body = Vec::new();
+        for (name, filename, data) in fields {
+            body.extend_from_slice(b"--boundary\r\n");
+            match filename {
+                Some(filename) => body.extend_from_slice(
+                    format!(
+                        "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: text/plain\r\n\r\n",
+                        name, filename
+                    )
+                    .as_bytes(),
+                ),
+                None => body.extend_from_slice(
+                    format!("Content-Disposition: form-data; name=\"{}\"\r\n\r\n", name)
+                        .as_bytes(),
+                ),
+            }
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--boundary--\r\n");
+
+        let mut req = Request::new(Method::Post, Url::parse(url).unwrap());
+        req.insert_header(CONTENT_TYPE, "multipart/form-data; boundary=boundary");
+        req.set_body(body);
+        req
+    }
+
+    #[async_std::test]
+    async fn test_upload_and_consume_file() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    retention_limit_minutes: 60,
+                    max_message_size_bytes: 1024,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let req = make_multipart_request(
+            "http://localhost/api/v1/files",
+            &[
+                ("user_token", None, b"test_token"),
+                ("retention", None, b"60"),
+                ("file", Some("hello.txt"), b"Hello, world!"),
+            ],
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: CreateMessageResponse = res.take_body().into_json().await.unwrap();
+
+        let req = Request::new(
+            Method::Post,
+            Url::parse(&format!(
+                "http://localhost/api/v1/files/{}/consume",
+                created.message_token
+            ))
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res[CONTENT_TYPE].as_str(), "text/plain");
+        assert_eq!(
+            res[CONTENT_DISPOSITION].as_str(),
+            "attachment; filename=\"hello.txt\""
+        );
+        let body = res.take_body().into_bytes().await.unwrap();
+        assert_eq!(body, b"Hello, world!");
+    }
+
+    #[async_std::test]
+    async fn test_upload_file_too_big() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    retention_limit_minutes: 60,
+                    max_message_size_bytes: 4,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let req = make_multipart_request(
+            "http://localhost/api/v1/files",
+            &[
+                ("user_token", None, b"test_token"),
+                ("file", Some("hello.txt"), b"Hello, world!"),
+            ],
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+    }
+
+    #[async_std::test]
+    async fn test_upload_without_file() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        let req = make_multipart_request(
+            "http://localhost/api/v1/files",
+            &[("user_token", None, b"test_token")],
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+    }
+}
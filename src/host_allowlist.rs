@@ -0,0 +1,90 @@
+use tide::{Middleware, Next, Request};
+
+use crate::error::AppError;
+
+// strips the port, IPv6 addresses are kept in brackets
+pub(crate) fn host_name(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(index) if !host[index..].contains(']') => &host[..index],
+        _ => host,
+    }
+}
+
+// an empty allowlist accepts any host
+pub struct HostAllowlistMiddleware {
+    allowed_hosts: Vec<String>,
+}
+
+impl HostAllowlistMiddleware {
+    pub fn new(allowed_hosts: &[String]) -> Self {
+        HostAllowlistMiddleware {
+            allowed_hosts: allowed_hosts
+                .iter()
+                .map(|host| host.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    // an entry without a port matches the host on any port
+    fn is_allowed(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.allowed_hosts.is_empty()
+            || self
+                .allowed_hosts
+                .iter()
+                .any(|allowed| *allowed == host || allowed == host_name(&host))
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for HostAllowlistMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        // the host ends up in the generated links and redirects, so it can't be anything
+        // the client wants, this also stops DNS rebinding attacks
+        let is_allowed = req.host().is_some_and(|host| self.is_allowed(host));
+        if !is_allowed {
+            return Err(
+                AppError::MisdirectedRequest("Host is not allowed".to_string()).into_error(),
+            );
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tide::http::{Method, Url};
+    use tide::StatusCode;
+
+    #[test]
+    fn test_is_allowed() {
+        let middleware =
+            HostAllowlistMiddleware::new(&["1ts.dev".to_string(), "localhost:8080".to_string()]);
+
+        assert!(middleware.is_allowed("1ts.dev"));
+        assert!(middleware.is_allowed("1TS.dev:443"));
+        assert!(middleware.is_allowed("localhost:8080"));
+        assert!(!middleware.is_allowed("localhost:9090"));
+        assert!(!middleware.is_allowed("evil.example"));
+        assert!(!middleware.is_allowed("1ts.dev.evil.example"));
+
+        assert!(HostAllowlistMiddleware::new(&[]).is_allowed("anything"));
+    }
+
+    #[async_std::test]
+    async fn test_rejects_unknown_host() {
+        let mut app = tide::new();
+        app.with(HostAllowlistMiddleware::new(&["1ts.dev".to_string()]));
+        app.at("/").get(|_| async { Ok("home") });
+
+        let req = tide::http::Request::new(Method::Get, Url::parse("http://1ts.dev/").unwrap());
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let mut req = tide::http::Request::new(Method::Get, Url::parse("http://1ts.dev/").unwrap());
+        req.insert_header("Host", "evil.example");
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::MisdirectedRequest);
+    }
+}
@@ -0,0 +1,135 @@
+use async_rustls::TlsConnector;
+use async_std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use http_types::{Request, Response, StatusCode};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::proxy::IpNetwork;
+
+const DEFAULT_CA_BUNDLE_PATH: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+// minimal client for outgoing requests (object storage, webhooks and other integrations)
+#[derive(Clone)]
+pub struct HttpClient {
+    tls_config: Arc<rustls::ClientConfig>,
+    // set for the URLs that come from users, the server's own network is off limits to them
+    // except for these networks
+    allowed_internal_networks: Option<Arc<Vec<IpNetwork>>>,
+}
+
+// loopback, private, link-local (the cloud metadata endpoints among them), unique local and
+// unspecified addresses
+pub(crate) fn is_internal_address(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(v4) => {
+            v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_internal_address(IpAddr::V4(v4)),
+            None => {
+                v6.is_loopback()
+                    || v6.is_unspecified()
+                    || v6.is_unique_local()
+                    || v6.is_unicast_link_local()
+            }
+        },
+    }
+}
+
+fn forbidden_address(host: &str) -> http_types::Error {
+    http_types::Error::from_str(
+        StatusCode::Forbidden,
+        format!("{} is an internal address", host),
+    )
+}
+
+// trusts the certificates of the bundle, the one of the system by default
+pub(crate) fn load_tls_client_config(
+    ca_bundle_path: Option<&str>,
+) -> std::io::Result<Arc<rustls::ClientConfig>> {
+    let mut tls_config = rustls::ClientConfig::new();
+    let ca_bundle = File::open(ca_bundle_path.unwrap_or(DEFAULT_CA_BUNDLE_PATH))?;
+    tls_config
+        .root_store
+        .add_pem_file(&mut BufReader::new(ca_bundle))
+        .map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Can't parse CA bundle")
+        })?;
+    Ok(Arc::new(tls_config))
+}
+
+impl HttpClient {
+    pub fn new(ca_bundle_path: Option<&str>) -> std::io::Result<Self> {
+        Ok(HttpClient {
+            tls_config: load_tls_client_config(ca_bundle_path)?,
+            allowed_internal_networks: None,
+        })
+    }
+
+    // refuses hosts that resolve to an internal address outside of `allowed_internal_networks`
+    pub(crate) fn public_only(mut self, allowed_internal_networks: Vec<IpNetwork>) -> Self {
+        self.allowed_internal_networks = Some(Arc::new(allowed_internal_networks));
+        self
+    }
+
+    pub(crate) fn is_allowed_address(&self, address: IpAddr) -> bool {
+        match &self.allowed_internal_networks {
+            Some(allowed_internal_networks) => {
+                !is_internal_address(address)
+                    || allowed_internal_networks
+                        .iter()
+                        .any(|network| network.contains(address))
+            }
+            None => true,
+        }
+    }
+
+    // every address of the host is checked, and the connection goes to the checked ones,
+    // so a second lookup can't hand out another address
+    async fn resolve(&self, host: &str, port: u16) -> http_types::Result<Vec<SocketAddr>> {
+        // the brackets of an IPv6 URL host aren't part of the address
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addresses: Vec<SocketAddr> = (host, port).to_socket_addrs().await?.collect();
+        if addresses
+            .iter()
+            .any(|address| !self.is_allowed_address(address.ip()))
+        {
+            return Err(forbidden_address(host));
+        }
+        Ok(addresses)
+    }
+
+    pub async fn send(&self, req: Request) -> http_types::Result<Response> {
+        let url = req.url().clone();
+        let host = url
+            .host_str()
+            .ok_or_else(|| http_types::Error::from_str(StatusCode::BadRequest, "URL has no host"))?
+            .to_string();
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addresses = self.resolve(&host, port).await?;
+        let stream = TcpStream::connect(&addresses[..]).await?;
+
+        match url.scheme() {
+            "http" => async_h1::connect(stream, req).await,
+            "https" => {
+                let domain = webpki::DNSNameRef::try_from_ascii_str(&host).map_err(|_| {
+                    http_types::Error::from_str(StatusCode::BadRequest, "Invalid host name")
+                })?;
+                let tls_stream = TlsConnector::from(self.tls_config.clone())
+                    .connect(domain, stream)
+                    .await?;
+                async_h1::connect(tls_stream, req).await
+            }
+            scheme => Err(http_types::Error::from_str(
+                StatusCode::BadRequest,
+                format!("Unsupported URL scheme: {}", scheme),
+            )),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::fmt;
+use std::fs;
+use zeroize::Zeroizing;
+
+use crate::tokens::hash_token;
+
+// shorter keys would make the tags easier to forge than the hash is to break
+const MIN_KEY_SIZE_BYTES: usize = 32;
+
+#[derive(Debug)]
+pub struct IntegrityError(pub String);
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+// tags the stored messages, so a row changed outside of the server is refused
+pub struct MessageSigner {
+    key: Zeroizing<Vec<u8>>,
+}
+
+impl MessageSigner {
+    pub fn from_base64_key(key: &str) -> Result<Self, IntegrityError> {
+        let key = Zeroizing::new(STANDARD.decode(key.trim()).map_err(|err| {
+            IntegrityError(format!("Integrity key is not valid base64: {}", err))
+        })?);
+        if key.len() < MIN_KEY_SIZE_BYTES {
+            return Err(IntegrityError(format!(
+                "Integrity key should be at least {} bytes long, got {}",
+                MIN_KEY_SIZE_BYTES,
+                key.len()
+            )));
+        }
+        Ok(MessageSigner { key })
+    }
+
+    pub fn from_key_file(path: &str) -> Result<Self, IntegrityError> {
+        let key =
+            Zeroizing::new(fs::read_to_string(path).map_err(|err| {
+                IntegrityError(format!("Can't read integrity key file: {}", err))
+            })?);
+        Self::from_base64_key(&key)
+    }
+
+    fn mac(&self, message_token: &str, expire_timestamp: i64, data: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).expect("HMAC accepts keys of any size");
+        // the token is length-prefixed, so its end can't be moved into the other fields
+        mac.update(&(message_token.len() as u64).to_be_bytes());
+        mac.update(message_token.as_bytes());
+        mac.update(&expire_timestamp.to_be_bytes());
+        mac.update(data);
+        mac
+    }
+
+    pub fn tag(&self, message_token: &str, expire_timestamp: i64, data: &[u8]) -> Vec<u8> {
+        self.mac(message_token, expire_timestamp, data)
+            .finalize()
+            .into_bytes()
+            .to_vec()
+    }
+
+    // the comparison takes the same time wherever the tags differ, the error names the message
+    // by a prefix of its token hash, the token itself would give the message away in the logs
+    pub fn verify(
+        &self,
+        message_token: &str,
+        expire_timestamp: i64,
+        data: &[u8],
+        tag: &[u8],
+    ) -> Result<(), IntegrityError> {
+        self.mac(message_token, expire_timestamp, data)
+            .verify(tag)
+            .map_err(|_| {
+                IntegrityError(format!(
+                    "Message {} doesn't match its integrity tag",
+                    &hash_token(message_token)[..16]
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    #[test]
+    fn test_tag_and_verify() {
+        let signer = MessageSigner::from_base64_key(TEST_KEY).unwrap();
+
+        let tag = signer.tag("token1", 100, b"Hello, world!");
+        assert_eq!(tag.len(), 32);
+        assert!(signer.verify("token1", 100, b"Hello, world!", &tag).is_ok());
+        assert!(signer
+            .verify("token2", 100, b"Hello, world!", &tag)
+            .is_err());
+        assert!(signer.verify("token1", 0, b"Hello, world!", &tag).is_err());
+        assert!(signer
+            .verify("token1", 100, b"Hello, world?", &tag)
+            .is_err());
+        assert!(signer.verify("token1", 100, b"Hello, world!", &[]).is_err());
+    }
+
+    #[test]
+    fn test_error_does_not_contain_token() {
+        let signer = MessageSigner::from_base64_key(TEST_KEY).unwrap();
+
+        let err = signer
+            .verify("secret_token", 100, b"Hello, world!", &[])
+            .unwrap_err();
+        assert!(!err.to_string().contains("secret_token"));
+        assert!(err.to_string().contains(&hash_token("secret_token")[..16]));
+    }
+
+    #[test]
+    fn test_verify_with_wrong_key_fails() {
+        let signer = MessageSigner::from_base64_key(TEST_KEY).unwrap();
+        let other_signer =
+            MessageSigner::from_base64_key("ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=").unwrap();
+
+        let tag = signer.tag("token1", 100, b"Hello, world!");
+        assert!(other_signer
+            .verify("token1", 100, b"Hello, world!", &tag)
+            .is_err());
+    }
+
+    #[test]
+    fn test_short_key_is_refused() {
+        assert!(MessageSigner::from_base64_key("c2hvcnQ=").is_err());
+    }
+}
@@ -0,0 +1,380 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tide::{Body, Request, Response, StatusCode};
+use zeroize::Zeroizing;
+
+use crate::admin::check_admin_token;
+use crate::error::AppError;
+use crate::portal::{limits_of_plan, now_timestamp, RANDOM_BYTES};
+use crate::secret_requests::sign_token;
+use crate::store::{Invitation, UserLimits};
+use crate::tokens::random_base64url;
+use crate::{csp, make_base_url, StaticData};
+
+const DEFAULT_EXPIRES_IN_MINUTES: u64 = 7 * 24 * 60;
+const MAX_EXPIRES_IN_MINUTES: u64 = 30 * 24 * 60;
+const MAX_NOTE_LENGTH: usize = 200;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct CreateInvitationRequest {
+    // one of the plans of the config, the default limits when not set
+    pub plan: Option<String>,
+    pub note: Option<String>,
+    // a week by default
+    pub expires_in_minutes: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreatedInvitation {
+    #[serde(flatten)]
+    pub invitation: Invitation,
+    // the token is only in the URL, it can't be shown again
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InvitationsResponse {
+    pub invitations: Vec<Invitation>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RedeemInvitationRequest {
+    // the token from the invitation URL
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RedeemedInvitation {
+    pub plan: Option<String>,
+    pub limits: UserLimits,
+    // it can't be shown again
+    pub user_token: String,
+}
+
+fn not_found() -> tide::Error {
+    AppError::NotFound("The invitation has expired or was used already".to_string()).into_error()
+}
+
+pub async fn invitation_page(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    let html = String::from_utf8(data.invite_html.clone())?;
+    Ok(csp::html_response(
+        &data.config.content_security_policy,
+        &html,
+    ))
+}
+
+pub async fn create_invitation(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let create_request: Result<CreateInvitationRequest, _> = req.body_json().await;
+
+    let data = req.state().lock().unwrap();
+    check_admin_token(&req, &data)?;
+    let create_request = create_request
+        .map_err(|_| AppError::BadRequest("Can't parse request body".to_string()).into_error())?;
+    if let Some(plan) = &create_request.plan {
+        if !data.config.plans.contains_key(plan) {
+            return Err(AppError::Invalid {
+                field: "plan",
+                message: format!("There is no plan '{}'", plan),
+            }
+            .into_error());
+        }
+    }
+    let note = create_request
+        .note
+        .as_deref()
+        .map(str::trim)
+        .filter(|note| !note.is_empty());
+    if note.is_some_and(|note| note.chars().count() > MAX_NOTE_LENGTH) {
+        return Err(AppError::Invalid {
+            field: "note",
+            message: format!("Note should be at most {} characters long", MAX_NOTE_LENGTH),
+        }
+        .into_error());
+    }
+    let expires_in_minutes = create_request
+        .expires_in_minutes
+        .unwrap_or(DEFAULT_EXPIRES_IN_MINUTES);
+    if !(1..=MAX_EXPIRES_IN_MINUTES).contains(&expires_in_minutes) {
+        return Err(AppError::Invalid {
+            field: "expires_in_minutes",
+            message: format!(
+                "Expiration should be between 1 and {} minutes",
+                MAX_EXPIRES_IN_MINUTES
+            ),
+        }
+        .into_error());
+    }
+
+    let token = Zeroizing::new(random_base64url(RANDOM_BYTES));
+    let timestamp = now_timestamp()?;
+    let expire_timestamp = timestamp + (expires_in_minutes * 60) as i64;
+    let id = data.database.lock().unwrap().create_invitation(
+        &token,
+        create_request.plan.as_deref(),
+        note,
+        expire_timestamp,
+        timestamp,
+    )?;
+    log::info!("Invitation {} created through the admin API", id);
+    // the token is in the fragment, so it never reaches a server log, and the page asks
+    // before it's used, so a link preview doesn't use it up
+    let url = format!(
+        "{}/invite#token={}",
+        make_base_url(&req, &data.config),
+        sign_token(&data, token.to_string())
+    );
+    Ok(Response::builder(StatusCode::Created)
+        .body(Body::from_json(&CreatedInvitation {
+            invitation: Invitation {
+                id,
+                plan: create_request.plan,
+                note: note.map(str::to_string),
+                created_timestamp: timestamp,
+                expire_timestamp,
+            },
+            url,
+        })?)
+        .build())
+}
+
+pub async fn list_invitations(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    check_admin_token(&req, &data)?;
+    let invitations = data
+        .database
+        .lock()
+        .unwrap()
+        .list_invitations(now_timestamp()?)?;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&InvitationsResponse { invitations })?)
+        .build())
+}
+
+// the URL stops working right away
+pub async fn remove_invitation(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    check_admin_token(&req, &data)?;
+    let removed = match req.param("id")?.parse::<i64>() {
+        Ok(id) => data.database.lock().unwrap().remove_invitation(id)?,
+        Err(_) => false,
+    };
+    if !removed {
+        return Err(AppError::NotFound("Invitation not found".to_string()).into_error());
+    }
+    log::info!(
+        "Invitation {} removed through the admin API",
+        req.param("id")?
+    );
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+// the first one to use the invitation gets a new user token on its plan
+pub async fn redeem_invitation(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let redeem_request: Result<RedeemInvitationRequest, _> = req.body_json().await;
+
+    let data = req.state().lock().unwrap();
+    let redeem_request = redeem_request
+        .map_err(|_| AppError::BadRequest("Can't parse request body".to_string()).into_error())?;
+    // a forged token is answered like an unknown one
+    let token = match &data.token_signer {
+        Some(token_signer) => token_signer
+            .unsign(&redeem_request.token)
+            .ok_or_else(not_found)?,
+        None => redeem_request.token.as_str(),
+    };
+
+    let database = data.database.lock().unwrap();
+    let invitation = database
+        .take_invitation(token, now_timestamp()?)?
+        .ok_or_else(not_found)?;
+    let user_token = Zeroizing::new(random_base64url(RANDOM_BYTES));
+    // the own limits of the user are the defaults, the plan goes over them
+    database.set_user_limits(&user_token, &data.default_user_limits)?;
+    database.set_user_plan(&user_token, invitation.plan.as_deref())?;
+    log::info!("Invitation {} used", invitation.id);
+    Ok(Response::builder(StatusCode::Created)
+        .body(Body::from_json(&RedeemedInvitation {
+            limits: limits_of_plan(&data, invitation.plan.as_deref()),
+            plan: invitation.plan,
+            user_token: user_token.to_string(),
+        })?)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_test_data;
+    use crate::tokens::TokenSigner;
+    use tide::http::{Method, Url};
+
+    fn make_request(
+        method: Method,
+        path: &str,
+        admin_token: Option<&str>,
+        body: Option<serde_json::Value>,
+    ) -> tide::http::Request {
+        let mut req = tide::http::Request::new(
+            method,
+            Url::parse(&format!("http://localhost{}", path)).unwrap(),
+        );
+        if let Some(admin_token) = admin_token {
+            req.insert_header("Authorization", format!("Bearer {}", admin_token));
+        }
+        if let Some(body) = body {
+            req.set_body(Body::from_json(&body).unwrap());
+        }
+        req
+    }
+
+    fn setup_app() -> (tide::Server<Arc<Mutex<StaticData>>>, Arc<Mutex<StaticData>>) {
+        let app_data = setup_test_data();
+        {
+            let mut data = app_data.lock().unwrap();
+            data.config.admin_token = Some("admin".to_string());
+            data.config.plans.insert(
+                "team".to_string(),
+                UserLimits {
+                    retention_limit_minutes: 1440,
+                    ..Default::default()
+                },
+            );
+            data.token_signer = Some(Arc::new(TokenSigner::random()));
+        }
+        (crate::init_app(app_data.clone()), app_data)
+    }
+
+    async fn create(
+        app: &tide::Server<Arc<Mutex<StaticData>>>,
+        body: serde_json::Value,
+    ) -> tide::http::Response {
+        app.respond(make_request(
+            Method::Post,
+            "/api/v1/admin/invitations",
+            Some("admin"),
+            Some(body),
+        ))
+        .await
+        .unwrap()
+    }
+
+    async fn redeem(
+        app: &tide::Server<Arc<Mutex<StaticData>>>,
+        token: &str,
+    ) -> tide::http::Response {
+        app.respond(make_request(
+            Method::Post,
+            "/api/v1/invitations/redeem",
+            None,
+            Some(serde_json::json!({ "token": token })),
+        ))
+        .await
+        .unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_create_and_redeem_invitation() {
+        let (app, app_data) = setup_app();
+
+        let res = create(&app, serde_json::json!({ "plan": "free" })).await;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let res = create(&app, serde_json::json!({ "expires_in_minutes": 0 })).await;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let res: tide::http::Response = app
+            .respond(make_request(
+                Method::Post,
+                "/api/v1/admin/invitations",
+                None,
+                Some(serde_json::json!({})),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let mut res = create(
+            &app,
+            serde_json::json!({ "plan": "team", "note": " for Bob " }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::Created);
+        let created: CreatedInvitation = res.take_body().into_json().await.unwrap();
+        assert_eq!(created.invitation.note.as_deref(), Some("for Bob"));
+        let token = created
+            .url
+            .strip_prefix("http://localhost/invite#token=")
+            .unwrap()
+            .to_string();
+
+        let mut res: tide::http::Response = app
+            .respond(make_request(
+                Method::Get,
+                "/api/v1/admin/invitations",
+                Some("admin"),
+                None,
+            ))
+            .await
+            .unwrap();
+        let listed: InvitationsResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(listed.invitations, vec![created.invitation]);
+
+        // a token without the right signature is refused before the lookup
+        let res = redeem(&app, &format!("{}x", token)).await;
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let mut res = redeem(&app, &token).await;
+        assert_eq!(res.status(), StatusCode::Created);
+        let redeemed: RedeemedInvitation = res.take_body().into_json().await.unwrap();
+        assert_eq!(redeemed.plan.as_deref(), Some("team"));
+        assert_eq!(redeemed.limits.retention_limit_minutes, 1440);
+
+        // the new token is on the plan and works like any user token
+        let plan = app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .get_user_plan(&redeemed.user_token)
+            .unwrap();
+        assert_eq!(plan.as_deref(), Some("team"));
+        let res: tide::http::Response = app
+            .respond(make_request(
+                Method::Post,
+                "/api/v1/quota",
+                None,
+                Some(serde_json::json!({ "user_token": redeemed.user_token })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        // the invitation works once
+        let res = redeem(&app, &token).await;
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn test_removed_invitation_stops_working() {
+        let (app, _) = setup_app();
+        let mut res = create(&app, serde_json::json!({})).await;
+        let created: CreatedInvitation = res.take_body().into_json().await.unwrap();
+        assert_eq!(created.invitation.plan, None);
+
+        let path = format!("/api/v1/admin/invitations/{}", created.invitation.id);
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Delete, &path, Some("admin"), None))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NoContent);
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Delete, &path, Some("admin"), None))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let token = created.url.rsplit_once("#token=").unwrap().1;
+        let res = redeem(&app, token).await;
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+}
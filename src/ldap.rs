@@ -0,0 +1,879 @@
+use async_rustls::TlsConnector;
+use async_std::future;
+use async_std::net::TcpStream;
+use futures_lite::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tide::{Request, StatusCode};
+use zeroize::Zeroizing;
+
+use crate::error::AppError;
+use crate::http_client::load_tls_client_config;
+use crate::passkeys::signed_in_response;
+use crate::StaticData;
+
+// a simple bind and a search of LDAPv3 (RFC 4511) with just the BER they need: the admin is
+// looked up with the filters, then their own DN is bound with the password they typed in
+
+const USERNAME_PLACEHOLDER: &str = "{username}";
+const DEFAULT_USER_FILTER: &str = "(uid={username})";
+// the whole conversation with the directory, a slow one fails the sign-in
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// no answer to a bind or to a search for a single entry comes near this
+const MAX_MESSAGE_BYTES: usize = 1024 * 1024;
+const LDAP_VERSION: i64 = 3;
+// more than one entry is as good as none, the search stops at the second
+const SEARCH_SIZE_LIMIT: i64 = 2;
+const SEARCH_TIME_LIMIT_SECONDS: i64 = 10;
+const SCOPE_WHOLE_SUBTREE: i64 = 2;
+const NEVER_DEREF_ALIASES: i64 = 0;
+// "no attributes", only the DN of the entries is needed
+const NO_ATTRIBUTES: &str = "1.1";
+const RESULT_SUCCESS: i64 = 0;
+const RESULT_SIZE_LIMIT_EXCEEDED: i64 = 4;
+const RESULT_INVALID_CREDENTIALS: i64 = 49;
+
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_BIND_REQUEST: u8 = 0x60;
+const TAG_BIND_RESPONSE: u8 = 0x61;
+const TAG_UNBIND_REQUEST: u8 = 0x42;
+const TAG_SEARCH_REQUEST: u8 = 0x63;
+const TAG_SEARCH_RESULT_ENTRY: u8 = 0x64;
+const TAG_SEARCH_RESULT_DONE: u8 = 0x65;
+const TAG_SEARCH_RESULT_REFERENCE: u8 = 0x73;
+const TAG_SIMPLE_AUTHENTICATION: u8 = 0x80;
+// the choices of Filter
+const TAG_AND: u8 = 0xa0;
+const TAG_OR: u8 = 0xa1;
+const TAG_NOT: u8 = 0xa2;
+const TAG_EQUALITY_MATCH: u8 = 0xa3;
+const TAG_SUBSTRINGS: u8 = 0xa4;
+const TAG_GREATER_OR_EQUAL: u8 = 0xa5;
+const TAG_LESS_OR_EQUAL: u8 = 0xa6;
+const TAG_PRESENT: u8 = 0x87;
+const TAG_APPROX_MATCH: u8 = 0xa8;
+const TAG_EXTENSIBLE_MATCH: u8 = 0xa9;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LdapConfig {
+    // "ldaps://ad.example.com" (port 636 by default) or "ldap://..." (port 389, plain text,
+    // only for a directory on the same host)
+    pub url: String,
+    // the account the admins are looked up with, the search is anonymous when not set
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    // where the admins are looked up, e.g. "dc=example,dc=com"
+    pub base_dn: String,
+    // {username} is replaced with the escaped name that was typed in, "(uid={username})" by
+    // default, "(sAMAccountName={username})" for Active Directory
+    pub user_filter: Option<String>,
+    // the admins have to match it too, e.g. "(memberOf=cn=share-admins,ou=groups,dc=example,dc=com)"
+    pub group_filter: Option<String>,
+    pub ca_bundle_path: Option<String>,
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn encode_tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    if content.len() < 0x80 {
+        encoded.push(content.len() as u8);
+    } else {
+        let length = content.len().to_be_bytes();
+        let skip = length.iter().take_while(|&&byte| byte == 0).count();
+        encoded.push(0x80 | (length.len() - skip) as u8);
+        encoded.extend_from_slice(&length[skip..]);
+    }
+    encoded.extend_from_slice(content);
+    encoded
+}
+
+// the shortest two's complement, the values here are never negative
+fn encode_integer(tag: u8, value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let mut skip = 0;
+    while skip < bytes.len() - 1 && bytes[skip] == 0 && bytes[skip + 1] < 0x80 {
+        skip += 1;
+    }
+    encode_tlv(tag, &bytes[skip..])
+}
+
+fn encode_string(tag: u8, value: &[u8]) -> Vec<u8> {
+    encode_tlv(tag, value)
+}
+
+// splits the first element off, returns its tag, its content and what follows it
+fn read_tlv(data: &[u8]) -> io::Result<(u8, &[u8], &[u8])> {
+    let truncated = || invalid_data("Truncated LDAP message");
+    let (&tag, rest) = data.split_first().ok_or_else(truncated)?;
+    let (&first, mut rest) = rest.split_first().ok_or_else(truncated)?;
+    let length = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return Err(invalid_data("Unsupported BER length"));
+        }
+        let (length, after) = rest.split_at(count);
+        rest = after;
+        length
+            .iter()
+            .fold(0usize, |length, &byte| (length << 8) | byte as usize)
+    };
+    if rest.len() < length {
+        return Err(truncated());
+    }
+    let (content, rest) = rest.split_at(length);
+    Ok((tag, content, rest))
+}
+
+fn read_integer(content: &[u8]) -> io::Result<i64> {
+    if content.is_empty() || content.len() > 8 {
+        return Err(invalid_data("Invalid BER integer"));
+    }
+    let negative = content[0] & 0x80 != 0;
+    Ok(content
+        .iter()
+        .fold(if negative { -1 } else { 0 }, |value, &byte| {
+            (value << 8) | byte as i64
+        }))
+}
+
+// RFC 4515, so a name can't change the meaning of the filter it's put in
+pub fn escape_filter_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '*' | '(' | ')' | '\\' | '\0' => escaped.push_str(&format!("\\{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_filter_value(value: &str) -> Result<Vec<u8>, String> {
+    let bytes = value.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            let byte = value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("Invalid escape in filter value '{}'", value))?;
+            unescaped.push(byte);
+            i += 3;
+        } else {
+            unescaped.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(unescaped)
+}
+
+// the string form of a search filter (RFC 4515) in its BER form
+struct FilterParser<'a> {
+    filter: &'a str,
+    position: usize,
+}
+
+impl<'a> FilterParser<'a> {
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        if self.filter.as_bytes().get(self.position) != Some(&c) {
+            return Err(format!(
+                "Expected '{}' at {} in filter '{}'",
+                c as char, self.position, self.filter
+            ));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn parse_filter(&mut self) -> Result<Vec<u8>, String> {
+        self.expect(b'(')?;
+        let encoded = match self.filter.as_bytes().get(self.position) {
+            Some(b'&') => self.parse_set(TAG_AND)?,
+            Some(b'|') => self.parse_set(TAG_OR)?,
+            Some(b'!') => {
+                self.position += 1;
+                encode_tlv(TAG_NOT, &self.parse_filter()?)
+            }
+            _ => {
+                let length = self.filter[self.position..]
+                    .find(')')
+                    .ok_or_else(|| format!("Unclosed filter '{}'", self.filter))?;
+                let item = &self.filter[self.position..self.position + length];
+                self.position += length;
+                encode_item(item)?
+            }
+        };
+        self.expect(b')')?;
+        Ok(encoded)
+    }
+
+    fn parse_set(&mut self, tag: u8) -> Result<Vec<u8>, String> {
+        self.position += 1;
+        let mut filters = Vec::new();
+        while self.filter.as_bytes().get(self.position) == Some(&b'(') {
+            filters.extend(self.parse_filter()?);
+        }
+        if filters.is_empty() {
+            return Err(format!("Empty filter set in '{}'", self.filter));
+        }
+        Ok(encode_tlv(tag, &filters))
+    }
+}
+
+fn encode_attribute_value(tag: u8, attribute: &str, value: &str) -> Result<Vec<u8>, String> {
+    let mut content = encode_string(TAG_OCTET_STRING, attribute.as_bytes());
+    content.extend(encode_string(
+        TAG_OCTET_STRING,
+        &unescape_filter_value(value)?,
+    ));
+    Ok(encode_tlv(tag, &content))
+}
+
+// "attr=value", "attr>=value", "attr=*", "attr=ab*cd*" or "attr:dn:rule:=value"
+fn encode_item(item: &str) -> Result<Vec<u8>, String> {
+    let (left, value) = item
+        .split_once('=')
+        .ok_or_else(|| format!("Expected an operator in '{}'", item))?;
+    if let Some(attribute) = left.strip_suffix('>') {
+        return encode_attribute_value(TAG_GREATER_OR_EQUAL, attribute, value);
+    }
+    if let Some(attribute) = left.strip_suffix('<') {
+        return encode_attribute_value(TAG_LESS_OR_EQUAL, attribute, value);
+    }
+    if let Some(attribute) = left.strip_suffix('~') {
+        return encode_attribute_value(TAG_APPROX_MATCH, attribute, value);
+    }
+    if let Some(left) = left.strip_suffix(':') {
+        return encode_extensible_match(left, value);
+    }
+    if left.is_empty() {
+        return Err(format!("Expected an attribute in '{}'", item));
+    }
+    if value == "*" {
+        return Ok(encode_string(TAG_PRESENT, left.as_bytes()));
+    }
+    if !value.contains('*') {
+        return encode_attribute_value(TAG_EQUALITY_MATCH, left, value);
+    }
+
+    let parts: Vec<&str> = value.split('*').collect();
+    let mut substrings = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        if part.is_empty() {
+            continue;
+        }
+        let tag = match i {
+            0 => 0x80,
+            i if i == parts.len() - 1 => 0x82,
+            _ => 0x81,
+        };
+        substrings.extend(encode_string(tag, &unescape_filter_value(part)?));
+    }
+    if substrings.is_empty() {
+        return Err(format!("Empty substring filter '{}'", item));
+    }
+    let mut content = encode_string(TAG_OCTET_STRING, left.as_bytes());
+    content.extend(encode_tlv(TAG_SEQUENCE, &substrings));
+    Ok(encode_tlv(TAG_SUBSTRINGS, &content))
+}
+
+// e.g. "memberOf:1.2.840.113556.1.4.1941:" for the nested groups of Active Directory
+fn encode_extensible_match(left: &str, value: &str) -> Result<Vec<u8>, String> {
+    let mut parts = left.split(':');
+    let attribute = parts.next().filter(|attribute| !attribute.is_empty());
+    let mut dn_attributes = false;
+    let mut rule = None;
+    for part in parts {
+        match part {
+            "dn" if !dn_attributes && rule.is_none() => dn_attributes = true,
+            part if !part.is_empty() && rule.is_none() => rule = Some(part),
+            _ => return Err(format!("Invalid extensible match '{}:='", left)),
+        }
+    }
+    if attribute.is_none() && rule.is_none() {
+        return Err(format!("Invalid extensible match '{}:='", left));
+    }
+    let mut content = Vec::new();
+    if let Some(rule) = rule {
+        content.extend(encode_string(0x81, rule.as_bytes()));
+    }
+    if let Some(attribute) = attribute {
+        content.extend(encode_string(0x82, attribute.as_bytes()));
+    }
+    content.extend(encode_string(0x83, &unescape_filter_value(value)?));
+    if dn_attributes {
+        content.extend(encode_tlv(0x84, &[0xff]));
+    }
+    Ok(encode_tlv(TAG_EXTENSIBLE_MATCH, &content))
+}
+
+pub fn encode_filter(filter: &str) -> Result<Vec<u8>, String> {
+    let mut parser = FilterParser {
+        filter: filter.trim(),
+        position: 0,
+    };
+    let encoded = parser.parse_filter()?;
+    if parser.position != parser.filter.len() {
+        return Err(format!("Unexpected text after filter '{}'", filter));
+    }
+    Ok(encoded)
+}
+
+// the code and the diagnostic message of an LDAPResult
+fn read_result(content: &[u8]) -> io::Result<(i64, String)> {
+    let (_, code, rest) = read_tlv(content)?;
+    let (_, _matched_dn, rest) = read_tlv(rest)?;
+    let (_, message, _) = read_tlv(rest)?;
+    Ok((
+        read_integer(code)?,
+        String::from_utf8_lossy(message).into_owned(),
+    ))
+}
+
+struct LdapConnection<S> {
+    stream: S,
+    message_id: i64,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> LdapConnection<S> {
+    fn new(stream: S) -> Self {
+        LdapConnection {
+            stream,
+            message_id: 0,
+        }
+    }
+
+    async fn send(&mut self, operation: &[u8]) -> io::Result<()> {
+        self.message_id += 1;
+        let mut content = encode_integer(TAG_INTEGER, self.message_id);
+        content.extend_from_slice(operation);
+        self.stream
+            .write_all(&encode_tlv(TAG_SEQUENCE, &content))
+            .await?;
+        self.stream.flush().await
+    }
+
+    // the tag and the content of the operation in the answer to the last request
+    async fn receive(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let message = read_message(&mut self.stream).await?;
+        let (_, id, rest) = read_tlv(&message)?;
+        // 0 is a notice of disconnection, nothing else comes from the server unasked
+        if read_integer(id)? != self.message_id {
+            return Err(invalid_data("LDAP server answered another request"));
+        }
+        let (tag, operation, _) = read_tlv(rest)?;
+        Ok((tag, operation.to_vec()))
+    }
+
+    // the result code, 0 when the DN and the password match
+    async fn bind(&mut self, dn: &str, password: &str) -> io::Result<i64> {
+        let mut request = encode_integer(TAG_INTEGER, LDAP_VERSION);
+        request.extend(encode_string(TAG_OCTET_STRING, dn.as_bytes()));
+        request.extend(encode_string(
+            TAG_SIMPLE_AUTHENTICATION,
+            password.as_bytes(),
+        ));
+        self.send(&encode_tlv(TAG_BIND_REQUEST, &request)).await?;
+        match self.receive().await? {
+            (TAG_BIND_RESPONSE, content) => Ok(read_result(&content)?.0),
+            _ => Err(invalid_data("Expected an LDAP bind response")),
+        }
+    }
+
+    // the DNs of the entries that match
+    async fn search(&mut self, base_dn: &str, filter: &[u8]) -> io::Result<Vec<String>> {
+        let mut request = encode_string(TAG_OCTET_STRING, base_dn.as_bytes());
+        request.extend(encode_integer(TAG_ENUMERATED, SCOPE_WHOLE_SUBTREE));
+        request.extend(encode_integer(TAG_ENUMERATED, NEVER_DEREF_ALIASES));
+        request.extend(encode_integer(TAG_INTEGER, SEARCH_SIZE_LIMIT));
+        request.extend(encode_integer(TAG_INTEGER, SEARCH_TIME_LIMIT_SECONDS));
+        request.extend(encode_tlv(TAG_BOOLEAN, &[0]));
+        request.extend_from_slice(filter);
+        request.extend(encode_tlv(
+            TAG_SEQUENCE,
+            &encode_string(TAG_OCTET_STRING, NO_ATTRIBUTES.as_bytes()),
+        ));
+        self.send(&encode_tlv(TAG_SEARCH_REQUEST, &request)).await?;
+        let mut dns = Vec::new();
+        loop {
+            match self.receive().await? {
+                (TAG_SEARCH_RESULT_ENTRY, content) => {
+                    let (_, dn, _) = read_tlv(&content)?;
+                    dns.push(String::from_utf8_lossy(dn).into_owned());
+                }
+                // the entries in other directories aren't followed
+                (TAG_SEARCH_RESULT_REFERENCE, _) => {}
+                (TAG_SEARCH_RESULT_DONE, content) => {
+                    let (code, message) = read_result(&content)?;
+                    if code != RESULT_SUCCESS && code != RESULT_SIZE_LIMIT_EXCEEDED {
+                        return Err(io::Error::other(format!(
+                            "LDAP search failed with {}: {}",
+                            code, message
+                        )));
+                    }
+                    return Ok(dns);
+                }
+                _ => return Err(invalid_data("Expected an LDAP search result")),
+            }
+        }
+    }
+
+    // the server closes the connection, there is no answer to wait for
+    async fn unbind(&mut self) -> io::Result<()> {
+        self.send(&encode_tlv(TAG_UNBIND_REQUEST, &[])).await
+    }
+}
+
+// an LDAPMessage, the content of its outer sequence
+async fn read_message<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != TAG_SEQUENCE {
+        return Err(invalid_data("Expected an LDAP message"));
+    }
+    let length = if header[1] < 0x80 {
+        header[1] as usize
+    } else {
+        let count = (header[1] & 0x7f) as usize;
+        if count == 0 || count > 4 {
+            return Err(invalid_data("Unsupported BER length"));
+        }
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length[4 - count..]).await?;
+        u32::from_be_bytes(length) as usize
+    };
+    if length > MAX_MESSAGE_BYTES {
+        return Err(invalid_data("LDAP message is too big"));
+    }
+    let mut message = vec![0u8; length];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+#[derive(Clone)]
+pub struct LdapClient {
+    config: LdapConfig,
+    host: String,
+    port: u16,
+    // None for ldap://
+    tls_config: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl LdapClient {
+    // the filters are checked here, so a mistake in them stops the server from starting
+    pub fn new(config: &LdapConfig) -> io::Result<Self> {
+        let invalid_config = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let url = tide::http::Url::parse(&config.url)
+            .map_err(|_| invalid_config(format!("ldap url '{}' is not valid", config.url)))?;
+        let (tls_config, default_port) = match url.scheme() {
+            "ldaps" => (
+                Some(load_tls_client_config(config.ca_bundle_path.as_deref())?),
+                636,
+            ),
+            "ldap" => (None, 389),
+            _ => {
+                return Err(invalid_config(format!(
+                    "ldap url '{}' should start with ldaps:// or ldap://",
+                    config.url
+                )))
+            }
+        };
+        let host = url
+            .host_str()
+            .ok_or_else(|| invalid_config(format!("ldap url '{}' has no host", config.url)))?;
+        let client = LdapClient {
+            config: config.clone(),
+            host: host.to_string(),
+            port: url.port().unwrap_or(default_port),
+            tls_config,
+        };
+        encode_filter(&client.search_filter("admin")).map_err(invalid_config)?;
+        Ok(client)
+    }
+
+    fn search_filter(&self, username: &str) -> String {
+        let user_filter = self
+            .config
+            .user_filter
+            .as_deref()
+            .unwrap_or(DEFAULT_USER_FILTER);
+        let filter = match &self.config.group_filter {
+            Some(group_filter) => format!("(&{}{})", user_filter, group_filter),
+            None => user_filter.to_string(),
+        };
+        filter.replace(USERNAME_PLACEHOLDER, &escape_filter_value(username))
+    }
+
+    // the DN of the admin when the directory knows them, they are in the group and the
+    // password is theirs, None otherwise
+    pub async fn authenticate(&self, username: &str, password: &str) -> io::Result<Option<String>> {
+        // a bind without a password is an anonymous one and succeeds
+        if username.is_empty() || password.is_empty() {
+            return Ok(None);
+        }
+        future::timeout(REQUEST_TIMEOUT, async {
+            let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+            match &self.tls_config {
+                Some(tls_config) => {
+                    let domain =
+                        webpki::DNSNameRef::try_from_ascii_str(&self.host).map_err(|_| {
+                            io::Error::new(io::ErrorKind::InvalidInput, "Invalid LDAP host name")
+                        })?;
+                    let stream = TlsConnector::from(tls_config.clone())
+                        .connect(domain, stream)
+                        .await?;
+                    self.authenticate_over(LdapConnection::new(stream), username, password)
+                        .await
+                }
+                None => {
+                    self.authenticate_over(LdapConnection::new(stream), username, password)
+                        .await
+                }
+            }
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "LDAP server timed out"))?
+    }
+
+    async fn authenticate_over<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut connection: LdapConnection<S>,
+        username: &str,
+        password: &str,
+    ) -> io::Result<Option<String>> {
+        if let Some(bind_dn) = &self.config.bind_dn {
+            let bind_password = self.config.bind_password.as_deref().unwrap_or_default();
+            let code = connection.bind(bind_dn, bind_password).await?;
+            if code != RESULT_SUCCESS {
+                return Err(io::Error::other(format!(
+                    "LDAP bind of {} failed with {}",
+                    bind_dn, code
+                )));
+            }
+        }
+        let filter = encode_filter(&self.search_filter(username))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let dns = connection.search(&self.config.base_dn, &filter).await?;
+        let dn = match dns.as_slice() {
+            [dn] => dn.clone(),
+            // unknown, not in the group, or a filter that isn't specific enough
+            _ => {
+                let _ = connection.unbind().await;
+                return Ok(None);
+            }
+        };
+        let code = connection.bind(&dn, password).await?;
+        let _ = connection.unbind().await;
+        match code {
+            RESULT_SUCCESS => Ok(Some(dn)),
+            RESULT_INVALID_CREDENTIALS => Ok(None),
+            code => Err(io::Error::other(format!(
+                "LDAP bind of {} failed with {}",
+                dn, code
+            ))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LdapSignInRequest {
+    pub username: String,
+    pub password: Zeroizing<String>,
+}
+
+// an unknown name, a wrong password and a missing group get the same answer, the log tells
+// the admin which one it was
+pub async fn sign_in(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let sign_in_request: Result<LdapSignInRequest, _> = req.body_json().await;
+
+    let ldap = req
+        .state()
+        .lock()
+        .unwrap()
+        .ldap
+        .clone()
+        .ok_or_else(|| AppError::NotFound("Not found".to_string()).into_error())?;
+    let sign_in_request = sign_in_request
+        .map_err(|_| AppError::BadRequest("Can't parse request body".to_string()).into_error())?;
+    let username = sign_in_request.username.trim();
+    let dn = match ldap.authenticate(username, &sign_in_request.password).await {
+        Ok(Some(dn)) => dn,
+        Ok(None) => {
+            log::warn!("LDAP sign-in of '{}' refused", username);
+            return Err(AppError::BadToken("Sign-in failed".to_string()).into_error());
+        }
+        Err(err) => {
+            log::error!("LDAP sign-in of '{}' failed: {}", username, err);
+            return Err(tide::Error::from_str(
+                StatusCode::BadGateway,
+                "The directory can't be reached",
+            ));
+        }
+    };
+
+    let data = req.state().lock().unwrap();
+    log::info!("Admin {} signed in through LDAP", dn);
+    signed_in_response(&req, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passkeys::SignInResponse;
+    use crate::tests::setup_test_data;
+    use async_std::net::TcpListener;
+    use async_std::task;
+    use tide::http::{Method, Url};
+    use tide::Body;
+
+    const ADMIN_DN: &str = "uid=alice,ou=people,dc=example,dc=com";
+
+    fn make_config(port: u16) -> LdapConfig {
+        LdapConfig {
+            url: format!("ldap://127.0.0.1:{}", port),
+            bind_dn: Some("cn=reader,dc=example,dc=com".to_string()),
+            bind_password: Some("reader-secret".to_string()),
+            base_dn: "dc=example,dc=com".to_string(),
+            user_filter: None,
+            group_filter: Some("(memberOf=cn=admins,dc=example,dc=com)".to_string()),
+            ca_bundle_path: None,
+        }
+    }
+
+    async fn send_message<S: AsyncWrite + Unpin>(stream: &mut S, id: &[u8], operation: Vec<u8>) {
+        let mut content = encode_tlv(TAG_INTEGER, id);
+        content.extend(operation);
+        stream
+            .write_all(&encode_tlv(TAG_SEQUENCE, &content))
+            .await
+            .unwrap();
+    }
+
+    fn make_result(tag: u8, code: i64) -> Vec<u8> {
+        let mut result = encode_integer(TAG_ENUMERATED, code);
+        result.extend(encode_string(TAG_OCTET_STRING, b""));
+        result.extend(encode_string(TAG_OCTET_STRING, b""));
+        encode_tlv(tag, &result)
+    }
+
+    // a directory with the reader and alice, whose password is "secret", the search finds
+    // alice when her name is in the filter
+    async fn start_directory() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        task::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                task::spawn(async move {
+                    while let Ok(message) = read_message(&mut stream).await {
+                        let (_, id, rest) = read_tlv(&message).unwrap();
+                        let id = id.to_vec();
+                        let (tag, operation, _) = read_tlv(rest).unwrap();
+                        match tag {
+                            TAG_BIND_REQUEST => {
+                                let (_, _, rest) = read_tlv(operation).unwrap();
+                                let (_, dn, rest) = read_tlv(rest).unwrap();
+                                let (_, password, _) = read_tlv(rest).unwrap();
+                                let code = match (dn, password) {
+                                    (b"cn=reader,dc=example,dc=com", b"reader-secret") => 0,
+                                    (dn, b"secret") if dn == ADMIN_DN.as_bytes() => 0,
+                                    _ => RESULT_INVALID_CREDENTIALS,
+                                };
+                                send_message(
+                                    &mut stream,
+                                    &id,
+                                    make_result(TAG_BIND_RESPONSE, code),
+                                )
+                                .await;
+                            }
+                            TAG_SEARCH_REQUEST => {
+                                let filter = String::from_utf8_lossy(operation).into_owned();
+                                if filter.contains("alice") && filter.contains("cn=admins") {
+                                    let mut entry =
+                                        encode_string(TAG_OCTET_STRING, ADMIN_DN.as_bytes());
+                                    entry.extend(encode_tlv(TAG_SEQUENCE, &[]));
+                                    send_message(
+                                        &mut stream,
+                                        &id,
+                                        encode_tlv(TAG_SEARCH_RESULT_ENTRY, &entry),
+                                    )
+                                    .await;
+                                }
+                                send_message(
+                                    &mut stream,
+                                    &id,
+                                    make_result(TAG_SEARCH_RESULT_DONE, RESULT_SUCCESS),
+                                )
+                                .await;
+                            }
+                            _ => break,
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_encode_filter() {
+        assert_eq!(
+            encode_filter("(uid=alice)").unwrap(),
+            b"\xa3\x0c\x04\x03uid\x04\x05alice"
+        );
+        assert_eq!(
+            encode_filter("(objectClass=*)").unwrap(),
+            b"\x87\x0bobjectClass"
+        );
+        assert_eq!(
+            encode_filter("(&(cn=a\\2a)(!(sn>=b)))").unwrap(),
+            b"\xa0\x15\xa3\x08\x04\x02cn\x04\x02a*\xa2\x09\xa5\x07\x04\x02sn\x04\x01b"
+        );
+        assert_eq!(
+            encode_filter("(cn=ab*c*)").unwrap(),
+            b"\xa4\x0d\x04\x02cn\x30\x07\x80\x02ab\x81\x01c"
+        );
+        assert_eq!(
+            encode_filter("(memberOf:1.2:=g)").unwrap(),
+            b"\xa9\x12\x81\x031.2\x82\x08memberOf\x83\x01g"
+        );
+
+        for invalid in [
+            "uid=alice",
+            "(uid=alice",
+            "(&)",
+            "(=a)",
+            "(uid)",
+            "(cn=\\zz)",
+            "(a=b)c",
+        ] {
+            assert!(encode_filter(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_escaped_name_stays_a_value() {
+        let escaped = escape_filter_value("*)(uid=*");
+        assert_eq!(escaped, "\\2a\\29\\28uid=\\2a");
+        assert_eq!(
+            encode_filter(&format!("(uid={})", escaped)).unwrap(),
+            encode_attribute_value(TAG_EQUALITY_MATCH, "uid", "\\2a\\29\\28uid=\\2a").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_ber_lengths() {
+        let content = vec![7u8; 300];
+        let encoded = encode_tlv(TAG_OCTET_STRING, &content);
+        assert_eq!(&encoded[..4], &[TAG_OCTET_STRING, 0x82, 0x01, 0x2c]);
+        let (tag, decoded, rest) = read_tlv(&encoded).unwrap();
+        assert_eq!(
+            (tag, decoded, rest),
+            (TAG_OCTET_STRING, &content[..], &[][..])
+        );
+        assert!(read_tlv(&encoded[..100]).is_err());
+
+        assert_eq!(encode_integer(TAG_INTEGER, 0), [TAG_INTEGER, 1, 0]);
+        assert_eq!(encode_integer(TAG_INTEGER, 200), [TAG_INTEGER, 2, 0, 200]);
+        assert_eq!(read_integer(&[0, 200]).unwrap(), 200);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let mut config = make_config(389);
+        config.url = "https://ldap.example.com".to_string();
+        assert!(LdapClient::new(&config).is_err());
+        let mut config = make_config(389);
+        config.group_filter = Some("memberOf=admins".to_string());
+        assert!(LdapClient::new(&config).is_err());
+    }
+
+    #[async_std::test]
+    async fn test_authenticate() {
+        let port = start_directory().await;
+        let client = LdapClient::new(&make_config(port)).unwrap();
+        assert_eq!(
+            client.authenticate("alice", "secret").await.unwrap(),
+            Some(ADMIN_DN.to_string())
+        );
+        assert_eq!(client.authenticate("alice", "wrong").await.unwrap(), None);
+        assert_eq!(client.authenticate("bob", "secret").await.unwrap(), None);
+        assert_eq!(client.authenticate("alice", "").await.unwrap(), None);
+
+        // without the group the search finds nobody
+        let mut config = make_config(port);
+        config.group_filter = Some("(memberOf=cn=staff,dc=example,dc=com)".to_string());
+        let client = LdapClient::new(&config).unwrap();
+        assert_eq!(client.authenticate("alice", "secret").await.unwrap(), None);
+
+        let mut config = make_config(port);
+        config.bind_password = Some("wrong".to_string());
+        let client = LdapClient::new(&config).unwrap();
+        assert!(client.authenticate("alice", "secret").await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_sign_in_gives_admin_session() {
+        let port = start_directory().await;
+        let app_data = setup_test_data();
+        app_data.lock().unwrap().ldap = Some(LdapClient::new(&make_config(port)).unwrap());
+        let app = crate::init_app(app_data);
+
+        let sign_in = |password: &str| {
+            let mut req = tide::http::Request::new(
+                Method::Post,
+                Url::parse("http://localhost/api/v1/admin/sessions/ldap").unwrap(),
+            );
+            req.set_body(
+                Body::from_json(&serde_json::json!({ "username": "alice", "password": password }))
+                    .unwrap(),
+            );
+            req
+        };
+        let res: tide::http::Response = app.respond(sign_in("wrong")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let mut res: tide::http::Response = app.respond(sign_in("secret")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let response: SignInResponse = res.take_body().into_json().await.unwrap();
+
+        let mut req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://localhost/api/v1/admin/defaults").unwrap(),
+        );
+        req.insert_header(
+            "Authorization",
+            format!("Bearer {}", response.session_token),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn test_sign_in_is_disabled_by_default() {
+        let app = crate::init_app(setup_test_data());
+        let mut req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/admin/sessions/ldap").unwrap(),
+        );
+        req.set_body(
+            Body::from_json(&serde_json::json!({ "username": "alice", "password": "secret" }))
+                .unwrap(),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+}
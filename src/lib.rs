@@ -0,0 +1,2754 @@
+use async_rustls::TlsAcceptor;
+use async_std::task;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tide::{Request, Response, StatusCode};
+use zeroize::Zeroizing;
+
+pub mod abuse_log;
+pub mod access_log;
+pub mod admin;
+pub mod admin_auth;
+mod api;
+pub mod api_version;
+pub mod binary_format;
+pub mod blob_store;
+pub mod blob_transfers;
+pub mod body_limit;
+pub mod brute_force;
+pub mod channels;
+pub mod concurrency_limit;
+pub mod cors;
+pub mod csp;
+pub mod database;
+pub mod downloads;
+pub mod email;
+pub mod encryption;
+pub mod error;
+pub mod file_blob_store;
+mod files;
+pub mod host_allowlist;
+pub mod http_client;
+pub mod integrity;
+pub mod invitations;
+pub mod ldap;
+pub mod logging;
+mod multipart;
+pub mod negotiate;
+pub mod oidc;
+pub mod openapi;
+pub mod passkeys;
+mod passphrase;
+pub mod portal;
+pub mod proxy;
+pub mod proxy_protocol;
+mod qr;
+pub mod rate_limit;
+pub mod receipts;
+mod redirect;
+pub mod registration;
+pub mod request_id;
+pub mod response_compression;
+pub mod s3;
+pub mod secret_requests;
+pub mod security_headers;
+pub mod server;
+mod shamir;
+pub mod shares;
+pub mod short_links;
+pub mod sms;
+pub mod store;
+pub mod teams;
+pub mod telegram;
+pub mod throttle;
+mod time_format;
+pub mod timeout;
+pub mod tls;
+pub mod tokens;
+mod totp;
+pub mod tus;
+mod validation;
+mod webauthn;
+pub mod webhooks;
+mod zstd;
+use crate::abuse_log::AbuseLogMiddleware;
+use crate::access_log::{AccessLogFormat, AccessLogMiddleware};
+use crate::admin_auth::{AdminAuthConfig, AdminAuthMiddleware};
+use crate::api_version::ApiVersionMiddleware;
+use crate::binary_format::BinaryFormatMiddleware;
+use crate::blob_store::BlobStorageConfig;
+use crate::blob_transfers::{run_blob_transfers, BlobPrefetchMiddleware, BlobTransfersMiddleware};
+use crate::body_limit::BodyLimitMiddleware;
+use crate::brute_force::{BruteForceConfig, BruteForceMiddleware};
+use crate::concurrency_limit::ConcurrencyLimitMiddleware;
+use crate::cors::{CorsConfig, CorsMiddleware};
+use crate::csp::CspConfig;
+use crate::database::{OneTimeShareDb, StorageMode};
+use crate::email::{EmailEvent, EmailSender, SmtpConfig};
+use crate::encryption::{EncryptionKeyConfig, MessageCipher};
+use crate::error::{AppError, ErrorResponseMiddleware};
+use crate::host_allowlist::{host_name, HostAllowlistMiddleware};
+use crate::integrity::MessageSigner;
+use crate::ldap::{LdapClient, LdapConfig};
+use crate::logging::LogFormat;
+use crate::negotiate::{requested_format, Format};
+use crate::oidc::{OidcClient, OidcConfig};
+use crate::passkeys::{AdminSessions, DEFAULT_SESSION_IDLE_TIMEOUT};
+use crate::passphrase::hash_passphrase;
+use crate::portal::PortalSessions;
+use crate::proxy::ForwardedHeadersMiddleware;
+use crate::rate_limit::{IpRateLimitConfig, IpRateLimitMiddleware};
+use crate::receipts::ReadReceiptsConfig;
+use crate::registration::RegistrationConfig;
+use crate::request_id::RequestIdMiddleware;
+use crate::response_compression::ResponseCompressionMiddleware;
+use crate::security_headers::{SecurityHeadersConfig, SecurityHeadersMiddleware};
+use crate::server::{ListenAddr, ListenConfig};
+use crate::short_links::{short_link_host, ShortLinkHostMiddleware};
+use crate::sms::{SmsConfig, SmsSender};
+pub use crate::store::UserLimits;
+use crate::store::{MessageOptions, Store};
+use crate::teams::TeamsConfig;
+use crate::telegram::{TelegramBot, TelegramConfig};
+use crate::throttle::Throttled;
+use crate::time_format::format_year_month;
+use crate::timeout::TimeoutMiddleware;
+use crate::tls::TlsOptions;
+use crate::tokens::{TokenConfig, TokenSigner};
+use crate::totp::{decode_base32, encode_base32, generate_secret, otpauth_uri};
+use crate::tus::Uploads;
+use crate::validation::validate_message_form;
+use crate::webhooks::{WebhookConfig, WebhookEvent, WebhookSender};
+
+#[derive(Clone)]
+pub struct StaticData {
+    // the limit placeholders are filled in for every request, the defaults can change at runtime
+    pub index_html_template: String,
+    pub shared_html: Vec<u8>,
+    pub request_html: Vec<u8>,
+    pub combine_html: Vec<u8>,
+    pub admin_html: Vec<u8>,
+    pub portal_html: Vec<u8>,
+    pub register_html: Vec<u8>,
+    pub invite_html: Vec<u8>,
+    pub default_user_limits: UserLimits,
+    pub config: Config,
+    pub database: Arc<Mutex<dyn Store>>,
+    // resumable uploads that aren't complete yet, they are lost on restart
+    pub uploads: Arc<Mutex<Uploads>>,
+    // passkey challenges and the admin sessions started with them
+    pub admin_sessions: Arc<Mutex<AdminSessions>>,
+    // OpenID Connect sign-ins in progress and the portal sessions started with them
+    pub portal_sessions: Arc<Mutex<PortalSessions>>,
+    // when set, the message tokens carry a signature that is checked before any lookup
+    pub token_signer: Option<Arc<TokenSigner>>,
+    // None unless webhooks are configured
+    pub webhooks: Option<WebhookSender>,
+    // None unless an SMTP server is configured
+    pub email: Option<EmailSender>,
+    // None unless an SMS provider is configured
+    pub sms: Option<SmsSender>,
+    // None unless a Telegram bot is configured
+    pub telegram: Option<TelegramBot>,
+    // None unless OpenID Connect sign-in is configured
+    pub oidc: Option<OidcClient>,
+    // None unless admins sign in through LDAP
+    pub ldap: Option<LdapClient>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    pub port: String,
+    #[serde(default)]
+    pub storage_mode: StorageMode,
+    pub database_path: String,
+    pub force_unprotected_http: bool,
+    pub cert_path: String,
+    pub key_path: String,
+    #[serde(default)]
+    pub tls: TlsOptions,
+    // when TLS is enabled, plain HTTP requests to this port are redirected to HTTPS
+    pub http_redirect_port: Option<String>,
+    #[serde(default)]
+    pub security_headers: SecurityHeadersConfig,
+    #[serde(default)]
+    pub content_security_policy: CspConfig,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    // host names the service is reachable by, any host is accepted when empty
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    // e.g. "https://1ts.dev", used for the generated links instead of the request host
+    pub public_base_url: Option<String>,
+    // e.g. "https://1ts.link", a short host the links to the shared page are given out with,
+    // it serves nothing but that page
+    pub short_link_base: Option<String>,
+    // addresses or CIDR blocks of reverse proxies allowed to set the X-Forwarded-* headers
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    // replaces the listener on `port`, every entry can have TLS turned on or off
+    #[serde(default)]
+    pub listen_addrs: Vec<ListenConfig>,
+    // listen on this unix socket instead of the TCP port, TLS is not used then
+    pub unix_socket_path: Option<String>,
+    // permissions of the socket file in octal, e.g. "660"
+    pub unix_socket_mode: Option<String>,
+    // expect the HAProxy PROXY protocol header (v1 or v2) on every connection
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    pub default_retention_limit_minutes: u32,
+    pub default_max_message_size_bytes: u32,
+    pub default_message_creation_limit_minutes: u32,
+    // how many messages can be created within the creation limit window
+    pub default_message_creation_limit_count: Option<u32>,
+    // unconsumed messages a user can have at once, no limit by default
+    pub default_active_message_limit: Option<u32>,
+    // bytes a user can store per calendar month, no limit by default
+    pub default_monthly_byte_quota: Option<u64>,
+    // enables the admin API, sent as "Authorization: Bearer <token>"
+    pub admin_token: Option<String>,
+    // admins sign in to the admin page with passkeys, needs publicBaseUrl as the passkeys
+    // are bound to its host
+    #[serde(default)]
+    pub admin_passkeys: bool,
+    // admins sign in to the admin page with their directory account, e.g. Active Directory
+    pub ldap: Option<LdapConfig>,
+    // an admin session that isn't used for this long ends, 15 minutes by default
+    pub admin_session_idle_minutes: Option<u64>,
+    // basic auth and client certificates in front of the admin page and API
+    #[serde(default)]
+    pub admin_auth: AdminAuthConfig,
+    // named sets of limits that users can be assigned to
+    #[serde(default)]
+    pub plans: HashMap<String, UserLimits>,
+    pub max_passphrase_attempts: u32,
+    pub encryption_key: Option<String>,
+    pub encryption_key_path: Option<String>,
+    // more keys after the one above, the last one encrypts the new messages
+    #[serde(default)]
+    pub encryption_keys: Vec<EncryptionKeyConfig>,
+    // every stored message gets an HMAC-SHA256 tag with this key and is refused when it doesn't match
+    pub integrity_key: Option<String>,
+    pub integrity_key_path: Option<String>,
+    // encrypts the whole database file with SQLCipher, can also come from DATABASE_KEY_ENV
+    pub database_key: Option<String>,
+    pub database_key_path: Option<String>,
+    // payloads bigger than the threshold are moved out of the database when a blob storage is set
+    pub blob_storage: Option<BlobStorageConfig>,
+    pub blob_threshold_bytes: Option<u32>,
+    // payloads bigger than this are compressed with zstd before they are stored, off when not set
+    pub compression_threshold_bytes: Option<u32>,
+    // bigger requests are rejected with 413 before they are read into memory
+    pub max_request_body_bytes: Option<u64>,
+    // the biggest resumable upload, the user's message size limit applies too
+    pub max_upload_bytes: Option<u64>,
+    // the consumed files kept in the store for resuming their downloads, together
+    pub max_pending_download_bytes: Option<u64>,
+    // requests that take longer are answered with 503
+    pub request_timeout_seconds: Option<u64>,
+    // requests over this number are answered with 503 until others finish
+    pub max_concurrent_requests: Option<usize>,
+    // token buckets per client address, for creating and for reading messages
+    #[serde(default)]
+    pub ip_rate_limits: IpRateLimitConfig,
+    #[serde(default)]
+    pub brute_force_protection: BruteForceConfig,
+    #[serde(default)]
+    pub read_receipts: ReadReceiptsConfig,
+    // messages and users can have a webhook that is called when their messages are read
+    pub webhooks: Option<WebhookConfig>,
+    // the creators with an email address are told when their messages are read or expire
+    pub smtp: Option<SmtpConfig>,
+    // the link of a message can be texted to a phone number given when it is created
+    pub sms: Option<SmsConfig>,
+    // a Teams outgoing webhook can create links from a chat command
+    pub teams: Option<TeamsConfig>,
+    // a bot that answers the secrets sent to it with links, it polls Telegram for them
+    pub telegram: Option<TelegramConfig>,
+    // people of the organization sign in to /portal through its IdP and get their own user
+    // tokens, needs publicBaseUrl for the callback URL
+    pub oidc: Option<OidcConfig>,
+    // anyone with an email address can get a user token on /register, off for closed
+    // deployments, needs smtp, publicBaseUrl and a token signing key for the links
+    pub registration: Option<RegistrationConfig>,
+    pub abuse_log_path: Option<String>,
+    // one of "error", "warn", "info", "debug", "trace" or "off"
+    pub log_level: Option<String>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default)]
+    pub access_log_format: AccessLogFormat,
+    // message tokens are replaced with ":token" in the access log unless enabled
+    #[serde(default)]
+    pub log_message_tokens: bool,
+    // client addresses, user agents and referers are never logged nor stored when enabled
+    #[serde(default)]
+    pub privacy_mode: bool,
+    // how the message tokens in the links look, UUIDv4 by default
+    #[serde(default)]
+    pub message_tokens: TokenConfig,
+    // message tokens get an HMAC-SHA256 signature with this key, forged ones are refused early
+    pub token_signing_key: Option<String>,
+    pub token_signing_key_path: Option<String>,
+}
+
+const DEFAULT_BLOB_THRESHOLD_BYTES: u32 = 64 * 1024;
+// big enough for a file of a few megabytes encoded in base64
+const DEFAULT_MAX_REQUEST_BODY_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_REQUEST_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 256;
+const DEFAULT_MESSAGE_CREATION_LIMIT_COUNT: u32 = 1;
+const DEFAULT_LOG_LEVEL: &str = "info";
+// keeps the database key out of the config file, e.g. when it comes from a secret store
+const DATABASE_KEY_ENV: &str = "ONE_TIME_SHARE_DATABASE_KEY";
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Default)]
+struct MessageForm {
+    user_token: String,
+    message_data: Zeroizing<String>,
+    retention: Option<u32>,
+    passphrase: Zeroizing<Option<String>>,
+    end_to_end: Option<bool>,
+    filename: Option<String>,
+    content_type: Option<String>,
+    // e.g. "db-password-friday", the link is made with it instead of a random token
+    slug: Option<String>,
+    // gets a signed POST when the message is read, instead of the webhook of the user
+    webhook_url: Option<String>,
+    // e.g. "+15551234567", the link is texted there once the message is saved
+    sms_to: Option<String>,
+    // e.g. ["alice", "bob"], each of them gets their own one-time link to the message
+    recipients: Option<Vec<String>>,
+    // how many times the message can be read, once by default
+    max_views: Option<u32>,
+    // minutes the message can still be read after the last view, none by default
+    grace_minutes: Option<u32>,
+    // unix time, the link works right away but the message can't be read before it
+    not_before: Option<u64>,
+    // e.g. 60, the message is staged now and can be read that many minutes later, its
+    // retention counts from then
+    activate_in_minutes: Option<u32>,
+    // the message is read only with two authorization tokens, each handed to another person
+    dual_control: Option<bool>,
+    // e.g. "JBSWY3DPEHPK3PXP", the base32 secret of an authenticator the recipient has already,
+    // reading the message takes a code of it
+    totp_secret: Zeroizing<Option<String>>,
+    // the same with a new secret, which comes back in the answer for the recipient to add
+    totp: Option<bool>,
+    // e.g. "4821", sent to the recipient another way than the link, a few wrong ones destroy
+    // the message
+    pin: Zeroizing<Option<String>>,
+    // e.g. ["10.8.0.0/16"], the message can only be read from these addresses
+    allowed_ips: Option<Vec<String>>,
+}
+
+pub async fn read_config(file_path: impl AsRef<Path>) -> tide::Result<Config> {
+    let file_content = fs::read_to_string(file_path)?;
+    let config: Config = serde_json::from_str(&file_content)?;
+    Ok(config)
+}
+
+fn render_index_html(template: &str, default_user_limits: &UserLimits) -> String {
+    template
+        .replace(
+            "{{.MessageLimitBytes}}",
+            &default_user_limits.max_message_size_bytes.to_string(),
+        )
+        .replace(
+            "{{.RetentionLimitMinutes}}",
+            &default_user_limits.retention_limit_minutes.to_string(),
+        )
+}
+
+async fn home_page(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    // scripts get the limits the page shows
+    let mut res = if requested_format(&req) == Some(Format::Json) {
+        Response::builder(StatusCode::Ok)
+            .body(tide::Body::from_json(&api::LimitsResponse {
+                max_message_size_bytes: data.default_user_limits.max_message_size_bytes,
+                retention_limit_minutes: data.default_user_limits.retention_limit_minutes,
+            })?)
+            .build()
+    } else {
+        csp::html_response(
+            &data.config.content_security_policy,
+            &render_index_html(&data.index_html_template, &data.default_user_limits),
+        )
+    };
+    res.insert_header("Vary", "Accept");
+    Ok(res)
+}
+
+const KEY_PLACEHOLDER: &str = "{key}";
+// 12 bytes of AES-GCM nonce and 16 bytes of authentication tag
+pub(crate) const CLIENT_ENCRYPTION_OVERHEAD_BYTES: u32 = 28;
+
+pub(crate) struct CreatedMessage {
+    message_token: String,
+    expire_timestamp: u64,
+    // the unix time the message can be read from, for a locked or delayed message
+    not_before: Option<u64>,
+    is_client_encrypted: bool,
+    // hex SHA-256 of the data, so the recipient can check they got what was sent
+    checksum: String,
+    // lets the creator remove the message before it's read
+    delete_token: String,
+    // both are needed to read a dual control message, empty for the others
+    authorization_tokens: Vec<String>,
+    // a newly made authenticator secret in base32 and its otpauth:// link, not for a given one
+    totp_secret: Option<String>,
+    totp_uri: Option<String>,
+    // the links of a message for several recipients, the first one is `message_token`
+    recipients: Vec<RecipientToken>,
+}
+
+struct RecipientToken {
+    name: String,
+    message_token: String,
+}
+
+// a user on a plan gets the limits of the plan, so changing the plan in the config
+// changes them for all of its users
+pub(crate) fn get_user_limits(
+    data: &StaticData,
+    user_token: &str,
+) -> tide::Result<Option<UserLimits>> {
+    let database = data.database.lock().unwrap();
+    // both lookups are made for an unknown token too, so it isn't answered any sooner
+    let user_limits = database.get_user_limits(user_token)?;
+    let plan = database.get_user_plan(user_token)?;
+    let user_limits = match user_limits {
+        Some(user_limits) => user_limits,
+        None => return Ok(None),
+    };
+    match plan {
+        None => Ok(Some(user_limits)),
+        Some(plan) => match data.config.plans.get(&plan) {
+            Some(plan_limits) => Ok(Some(plan_limits.clone())),
+            None => Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!("Plan '{}' is not defined in the config", plan),
+            )),
+        },
+    }
+}
+
+fn save_new_message(data: &StaticData, form: &MessageForm) -> tide::Result<CreatedMessage> {
+    validate_message_form(form).map_err(AppError::into_error)?;
+    // the form is validated, so the data is valid base64
+    let message_data = Zeroizing::new(STANDARD.decode(&form.message_data)?);
+    save_new_payloads(data, form, std::slice::from_ref(&message_data))
+}
+
+// saves the message of a validated form with other data, the copy of every recipient gets
+// the payload at its position, or the only one when there is one
+pub(crate) fn save_new_payloads(
+    data: &StaticData,
+    form: &MessageForm,
+    payloads: &[Zeroizing<Vec<u8>>],
+) -> tide::Result<CreatedMessage> {
+    let retention_limit_minutes = form.retention.unwrap_or(0);
+
+    // an unknown user goes through the same checks with the default limits and is refused
+    // where a rate limited one would be, so the timing doesn't tell a valid token apart
+    let user_limits = get_user_limits(data, &form.user_token)?;
+    let is_known_user = user_limits.is_some();
+    let user_limits = user_limits.unwrap_or_else(|| data.default_user_limits.clone());
+    let max_size_bytes = user_limits.max_message_size_bytes;
+    let user_retention_limit_minutes = user_limits.retention_limit_minutes;
+
+    let is_client_encrypted = form.end_to_end.unwrap_or(false);
+    // client-side encryption adds a nonce and an authentication tag to the payload
+    let max_size_bytes = if is_client_encrypted && max_size_bytes > 0 {
+        max_size_bytes + CLIENT_ENCRYPTION_OVERHEAD_BYTES
+    } else {
+        max_size_bytes
+    };
+
+    if max_size_bytes > 0
+        && payloads
+            .iter()
+            .any(|payload| payload.len() as u64 > max_size_bytes as u64)
+    {
+        return Err(AppError::TooLarge("Message is too big".to_string()).into_error());
+    }
+    let mut checksums: Vec<String> = payloads
+        .iter()
+        .map(|payload| format!("{:x}", Sha256::digest(payload)))
+        .collect();
+    // every recipient gets a copy, they count against the limits like separate messages
+    let recipients = form.recipients.as_deref().unwrap_or_default();
+    let copies = recipients.len().max(1);
+    let payload_of = |copy: usize| copy % payloads.len();
+    let stored_bytes: u64 = (0..copies)
+        .map(|copy| payloads[payload_of(copy)].len() as u64)
+        .sum();
+
+    if retention_limit_minutes > 0
+        && user_retention_limit_minutes > 0
+        && retention_limit_minutes > user_retention_limit_minutes
+    {
+        return Err(AppError::BadRequest(
+            "Requested retention limit is bigger than allowed".to_string(),
+        )
+        .into_error());
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    // a delayed message is kept for its retention from the time it activates
+    let activate_timestamp = now as u64 + form.activate_in_minutes.unwrap_or(0) as u64 * 60;
+    let not_before = match form.activate_in_minutes {
+        Some(activate_in_minutes) if activate_in_minutes > 0 => Some(activate_timestamp),
+        _ => form.not_before,
+    };
+    let expire_timestamp = if retention_limit_minutes > 0 {
+        activate_timestamp + retention_limit_minutes as u64 * 60
+    } else {
+        0
+    };
+    // a message that expires before it's unlocked could never be read
+    if let Some(not_before) = not_before {
+        if expire_timestamp != 0 && not_before >= expire_timestamp {
+            return Err(AppError::Invalid {
+                field: "not_before",
+                message: "Message would expire before it can be read".to_string(),
+            }
+            .into_error());
+        }
+    }
+    if user_limits.active_message_limit > 0
+        && data
+            .database
+            .lock()
+            .unwrap()
+            .count_active_user_messages(&form.user_token, now)?
+            + copies as u32
+            > user_limits.active_message_limit
+    {
+        return Err(AppError::BadRequest(
+            "Too many unread messages. Wait until some of them are read or expire".to_string(),
+        )
+        .into_error());
+    }
+
+    let month = format_year_month(now as u64);
+    if user_limits.monthly_byte_quota > 0 {
+        let used_bytes = data
+            .database
+            .lock()
+            .unwrap()
+            .get_monthly_usage(&form.user_token, &month)?;
+        if used_bytes + stored_bytes > user_limits.monthly_byte_quota {
+            return Err(AppError::BadRequest(format!(
+                "Monthly quota exceeded, {} byte(s) left this month",
+                user_limits.monthly_byte_quota.saturating_sub(used_bytes)
+            ))
+            .into_error());
+        }
+    }
+
+    if !is_known_user {
+        return Err(AppError::NotFound("User not found".to_string()).into_error());
+    }
+
+    // a signed slug ends with its signature, so the forged ones are refused like forged tokens
+    let slug = match form.slug.as_deref().filter(|slug| !slug.is_empty()) {
+        Some(_) if !user_limits.custom_slugs => {
+            return Err(AppError::Invalid {
+                field: "slug",
+                message: "Custom slugs are not allowed for this user".to_string(),
+            }
+            .into_error())
+        }
+        Some(slug) => Some(match &data.token_signer {
+            Some(token_signer) => token_signer.sign(slug),
+            None => slug.to_string(),
+        }),
+        None => None,
+    };
+    let webhook_url = form.webhook_url.clone().filter(|url| !url.is_empty());
+    if let Some(webhook_url) = &webhook_url {
+        let webhooks = data.webhooks.as_ref().ok_or_else(|| {
+            AppError::Invalid {
+                field: "webhook_url",
+                message: "Webhooks are not enabled on this server".to_string(),
+            }
+            .into_error()
+        })?;
+        webhooks.check_url(webhook_url).map_err(|message| {
+            AppError::Invalid {
+                field: "webhook_url",
+                message,
+            }
+            .into_error()
+        })?;
+    }
+    if form
+        .sms_to
+        .as_deref()
+        .is_some_and(|sms_to| !sms_to.is_empty())
+    {
+        if data.sms.is_none() {
+            return Err(AppError::Invalid {
+                field: "sms_to",
+                message: "Texting links is not enabled on this server".to_string(),
+            }
+            .into_error());
+        }
+        // the server would text a link without the key
+        if is_client_encrypted {
+            return Err(AppError::Invalid {
+                field: "sms_to",
+                message: "The link of an end-to-end encrypted message can't be texted".to_string(),
+            }
+            .into_error());
+        }
+    }
+    if let Some(slug) = &slug {
+        if data.database.lock().unwrap().is_message_name_taken(slug)? {
+            return Err(AppError::Conflict("Slug is already taken".to_string()).into_error());
+        }
+    }
+
+    // checked last, so a rejected message doesn't take a slot of the window
+    register_message_creation(data, &form.user_token, &user_limits, now)?;
+
+    let mut message_tokens: Vec<String> = (0..copies)
+        .map(|_| {
+            let message_token = data.config.message_tokens.generate();
+            match &data.token_signer {
+                Some(token_signer) => token_signer.sign(&message_token),
+                None => message_token,
+            }
+        })
+        .collect();
+    // always a UUID, whatever style the message tokens have, it's never typed in
+    let delete_token = TokenConfig::default().generate();
+    let authorization_tokens = form
+        .dual_control
+        .unwrap_or(false)
+        .then(|| [(); 2].map(|_| TokenConfig::default().generate()));
+    // the form is validated, so a given secret is valid base32
+    let given_totp_secret = form
+        .totp_secret
+        .as_deref()
+        .filter(|totp_secret| !totp_secret.is_empty())
+        .and_then(decode_base32);
+    let generated_totp_secret =
+        (given_totp_secret.is_none() && form.totp.unwrap_or(false)).then(generate_secret);
+    let totp_secret = given_totp_secret.or_else(|| generated_totp_secret.clone());
+
+    let passphrase_hash = match form.passphrase.as_deref() {
+        Some(passphrase) if !passphrase.is_empty() => Some(hash_passphrase(passphrase)?),
+        _ => None,
+    };
+    let pin_hash = match form.pin.as_deref() {
+        Some(pin) if !pin.is_empty() => Some(hash_passphrase(pin)?),
+        _ => None,
+    };
+
+    // the copies share the delete token, so the creator can follow all of them with it
+    for (copy, message_token) in message_tokens.iter().enumerate() {
+        data.database.lock().unwrap().save_message(
+            message_token,
+            expire_timestamp as i64,
+            &payloads[payload_of(copy)],
+            &MessageOptions {
+                passphrase_hash: passphrase_hash.clone(),
+                is_client_encrypted,
+                filename: form.filename.clone(),
+                content_type: form.content_type.clone(),
+                user_token: Some(form.user_token.clone()),
+                checksum: Some(checksums[payload_of(copy)].clone()),
+                slug: slug.clone(),
+                delete_token: Some(delete_token.clone()),
+                webhook_url: webhook_url.clone(),
+                max_views: form.max_views.unwrap_or(1),
+                grace_minutes: form.grace_minutes.unwrap_or(0),
+                not_before: not_before.map(|not_before| not_before as i64),
+                authorization_tokens: authorization_tokens.clone(),
+                totp_secret: totp_secret.clone(),
+                pin_hash: pin_hash.clone(),
+                allowed_ips: form.allowed_ips.clone().unwrap_or_default(),
+            },
+        )?;
+    }
+    data.database
+        .lock()
+        .unwrap()
+        .add_monthly_usage(&form.user_token, &month, stored_bytes)?;
+
+    let recipients = recipients
+        .iter()
+        .zip(&message_tokens)
+        .map(|(name, message_token)| RecipientToken {
+            name: name.clone(),
+            message_token: message_token.clone(),
+        })
+        .collect();
+    Ok(CreatedMessage {
+        // the generated token isn't handed out when there is a slug
+        message_token: slug.unwrap_or_else(|| message_tokens.swap_remove(0)),
+        expire_timestamp,
+        not_before,
+        is_client_encrypted,
+        checksum: checksums.swap_remove(0),
+        delete_token,
+        authorization_tokens: authorization_tokens.map_or_else(Vec::new, Vec::from),
+        totp_secret: generated_totp_secret
+            .as_deref()
+            .map(|totp_secret| encode_base32(totp_secret)),
+        totp_uri: generated_totp_secret
+            .as_deref()
+            .map(|totp_secret| otpauth_uri(totp_secret)),
+        recipients,
+    })
+}
+
+// takes a slot of the creation window of the user, or fails when the window is full
+pub(crate) fn register_message_creation(
+    data: &StaticData,
+    user_token: &str,
+    user_limits: &UserLimits,
+    now: i64,
+) -> tide::Result<()> {
+    if user_limits.message_creation_limit_minutes == 0 {
+        return Ok(());
+    }
+    let seconds_left = data
+        .database
+        .lock()
+        .unwrap()
+        .try_register_message_creation(
+            user_token,
+            now,
+            user_limits.message_creation_limit_minutes as i64 * 60,
+            user_limits.message_creation_limit_count.max(1),
+        )?;
+    match seconds_left {
+        Some(seconds_left) => {
+            let seconds_left = seconds_left.max(1) as u64;
+            Err(AppError::RateLimited(Throttled::new(
+                format!(
+                    "Message creation limit reached. Wait for {} minute(s) and repeat",
+                    seconds_left.div_ceil(60)
+                ),
+                Duration::from_secs(seconds_left),
+            ))
+            .into_error())
+        }
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn make_base_url<State>(req: &Request<State>, config: &Config) -> String {
+    match &config.public_base_url {
+        Some(public_base_url) => public_base_url.trim_end_matches('/').to_string(),
+        // the scheme is https for TLS connections and for requests forwarded by a trusted proxy
+        None => format!("{}://{}", req.url().scheme(), req.host().unwrap()),
+    }
+}
+
+// the link without the key of an end-to-end encrypted message
+pub(crate) fn make_bare_share_url<State>(
+    req: &Request<State>,
+    config: &Config,
+    message_token: &str,
+) -> String {
+    let base_url = match &config.short_link_base {
+        Some(short_link_base) => short_link_base.trim_end_matches('/').to_string(),
+        None => make_base_url(req, config),
+    };
+    format!("{}/shared/{}", base_url, message_token)
+}
+
+// texts the link to the number the creator gave, if any
+pub(crate) fn text_share_url(data: &StaticData, form: &MessageForm, url: &str) {
+    if let (Some(sms), Some(sms_to)) = (&data.sms, form.sms_to.as_deref()) {
+        if !sms_to.is_empty() {
+            sms.send_link(sms_to, url);
+        }
+    }
+}
+
+// the link made outside of a request, e.g. by the Telegram bot, None without a configured
+// base URL
+pub(crate) fn make_configured_share_url(config: &Config, message_token: &str) -> Option<String> {
+    let base_url = config
+        .short_link_base
+        .as_ref()
+        .or(config.public_base_url.as_ref())?;
+    Some(format!(
+        "{}/shared/{}",
+        base_url.trim_end_matches('/'),
+        message_token
+    ))
+}
+
+fn make_share_url<State>(
+    req: &Request<State>,
+    config: &Config,
+    created: &CreatedMessage,
+) -> String {
+    make_token_share_url(req, config, created, &created.message_token)
+}
+
+// the link of one of the tokens of the message, e.g. of a recipient
+pub(crate) fn make_token_share_url<State>(
+    req: &Request<State>,
+    config: &Config,
+    created: &CreatedMessage,
+    message_token: &str,
+) -> String {
+    let url = make_bare_share_url(req, config, message_token);
+    if created.is_client_encrypted {
+        // the creator substitutes the placeholder with the key, so the server never sees it
+        format!("{}#{}", url, KEY_PLACEHOLDER)
+    } else {
+        url
+    }
+}
+
+// the server doesn't know the key of an end-to-end encrypted message, so it can't draw
+// a code that opens it
+fn make_qr_url<State>(
+    req: &Request<State>,
+    config: &Config,
+    created: &CreatedMessage,
+) -> Option<String> {
+    (!created.is_client_encrypted).then(|| {
+        format!(
+            "{}/api/v1/messages/{}/qr",
+            make_base_url(req, config),
+            created.message_token
+        )
+    })
+}
+
+async fn create_new_message(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    if req.method() != http_types::Method::Post {
+        return Err(AppError::MethodNotAllowed.into_error());
+    }
+
+    // the page sends a form, scripts tend to send the same fields as JSON,
+    // big messages come as multipart so they don't have to be base64 encoded
+    let essence = req.content_type().map(|mime| mime.essence().to_string());
+    let form: MessageForm = match essence.as_deref() {
+        Some("application/json") => req.body_json().await.map_err(|_| {
+            AppError::BadRequest("Can't parse request body as JSON".to_string()).into_error()
+        })?,
+        Some("multipart/form-data") => files::read_multipart_form(&mut req).await?,
+        _ => req.body_form().await.map_err(|_| {
+            AppError::BadRequest("Can't parse request body".to_string()).into_error()
+        })?,
+    };
+
+    let data = req.state().lock().unwrap();
+    let created = save_new_message(&data, &form)?;
+
+    let url_to_share = make_share_url(&req, &data.config, &created);
+    text_share_url(&data, &form, &url_to_share);
+    // the page expects the bare link, scripts can ask for the same answer as the API gives
+    if requested_format(&req) == Some(Format::Json) {
+        return Ok(Response::builder(StatusCode::Ok)
+            .body(tide::Body::from_json(&api::CreateMessageResponse {
+                url: url_to_share,
+                qr_url: make_qr_url(&req, &data.config, &created),
+                recipients: api::recipient_links(&req, &data.config, &created),
+                message_token: created.message_token,
+                expire_timestamp: created.expire_timestamp,
+                not_before: created.not_before,
+                sha256: created.checksum,
+                delete_token: created.delete_token,
+                authorization_tokens: created.authorization_tokens,
+                totp_secret: created.totp_secret,
+                totp_uri: created.totp_uri,
+            })?)
+            .build());
+    }
+    // the page reads the bare link from the body, the delete token comes next to it
+    Ok(Response::builder(StatusCode::Ok)
+        .header(api::MESSAGE_DELETE_TOKEN_HEADER, created.delete_token)
+        .body(url_to_share)
+        .build())
+}
+
+async fn shared_page(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    if req.method() != http_types::Method::Get {
+        return Err(AppError::MethodNotAllowed.into_error());
+    }
+
+    let token = req.url().path().trim_start_matches("/shared/");
+    if token.is_empty() {
+        return Err(AppError::BadRequest("Token is empty".to_string()).into_error());
+    }
+
+    let data = req.state().lock().unwrap();
+    // the page never touches the message, so neither does its JSON form
+    let mut res = if requested_format(&req) == Some(Format::Json) {
+        api::message_meta_response(&data, token)?
+    } else {
+        // the page counts down the lifetime left, null when it's unknown or unlimited
+        let expire_timestamp = match api::check_token_signature(&data, token) {
+            Ok(()) => data
+                .database
+                .lock()
+                .unwrap()
+                .get_message_expire_timestamp(token)?,
+            Err(_) => None,
+        };
+        let expires_in_seconds = expire_timestamp
+            .and_then(api::expires_in_seconds)
+            .map_or_else(|| "null".to_string(), |seconds| seconds.to_string());
+        let html_response = String::from_utf8(data.shared_html.clone())?
+            .replace("{{.MessageToken}}", token)
+            .replace("{{.ExpiresInSeconds}}", &expires_in_seconds);
+        csp::html_response(&data.config.content_security_policy, &html_response)
+    };
+    res.insert_header("Vary", "Accept");
+    Ok(res)
+}
+
+pub fn init_app(global_data: Arc<Mutex<StaticData>>) -> tide::Server<Arc<Mutex<StaticData>>> {
+    let config = global_data.lock().unwrap().config.clone();
+    let database = global_data.lock().unwrap().database.clone();
+
+    let mut app = tide::with_state(global_data);
+    // these go first, so the client address and the id are right for everything after them
+    app.with(ForwardedHeadersMiddleware::new(&config.trusted_proxies));
+    app.with(RequestIdMiddleware);
+    app.with(AccessLogMiddleware::new(
+        config.access_log_format,
+        config.log_message_tokens,
+        config.privacy_mode,
+    ));
+    // outside of the binary formats, which are already compact
+    app.with(ResponseCompressionMiddleware);
+    // outside of the error responses, so the errors come in the asked format too
+    app.with(BinaryFormatMiddleware);
+    // inside the access log, so the failures are still logged with their details
+    app.with(ErrorResponseMiddleware);
+    if let Some(abuse_log_path) = &config.abuse_log_path {
+        app.with(AbuseLogMiddleware::new(abuse_log_path, config.privacy_mode));
+    }
+    app.with(SecurityHeadersMiddleware::new(&config.security_headers));
+    app.with(ConcurrencyLimitMiddleware::new(
+        config
+            .max_concurrent_requests
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_REQUESTS),
+    ));
+    app.with(CorsMiddleware::new(&config.cors));
+    app.with(HostAllowlistMiddleware::new(&config.allowed_hosts));
+    app.with(ShortLinkHostMiddleware::new(
+        config.short_link_base.as_deref(),
+    ));
+    app.with(ApiVersionMiddleware);
+    app.with(IpRateLimitMiddleware::new(&config.ip_rate_limits));
+    app.with(BruteForceMiddleware::new(
+        &config.brute_force_protection,
+        config.privacy_mode,
+    ));
+    app.with(AdminAuthMiddleware::new(&config.admin_auth));
+    app.with(BodyLimitMiddleware::new(
+        config
+            .max_request_body_bytes
+            .unwrap_or(DEFAULT_MAX_REQUEST_BODY_BYTES),
+    ));
+    // outside of the timeout, the blob store has timeouts of its own
+    app.with(BlobTransfersMiddleware::new(&database));
+    app.with(TimeoutMiddleware::new(Duration::from_secs(
+        config
+            .request_timeout_seconds
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECONDS),
+    )));
+
+    app.at("/").get(home_page);
+    app.at("/shared/*")
+        .with(BlobPrefetchMiddleware::new(|req, _| {
+            // only the JSON form reads the message
+            (requested_format(req) == Some(Format::Json))
+                .then(|| req.url().path().trim_start_matches("/shared/").to_string())
+        }))
+        .get(shared_page);
+    app.at("/request/:token").get(secret_requests::request_page);
+    app.at("/combine").get(shares::combine_page);
+    app.at("/admin").get(passkeys::admin_page);
+    app.at("/portal").get(portal::portal_page);
+    app.at("/login").get(portal::login);
+    app.at(oidc::CALLBACK_PATH).get(portal::login_callback);
+    app.at("/register").get(registration::register_page);
+    app.at("/invite").get(invitations::invitation_page);
+    add_legacy_routes(&mut app);
+    add_api_v1_routes(&mut app);
+    app.at(csp::CSP_REPORT_PATH).post(csp::report_violation);
+    app.at(openapi::OPENAPI_PATH).get(openapi::openapi_document);
+    app.at(openapi::DOCS_PATH).get(openapi::docs_page);
+
+    app
+}
+
+// the routes the pages and the scripts written before the versioned API use, they stay
+// as they are and answer like version 1
+fn add_legacy_routes(app: &mut tide::Server<Arc<Mutex<StaticData>>>) {
+    app.at("/save").post(create_new_message);
+    // the page itself never touches the message, it is consumed only by an explicit POST
+    app.at("/shared/:token")
+        .with(BlobPrefetchMiddleware::by_token())
+        .post(api::consume_message);
+}
+
+// a breaking change goes into a new version with its own routes next to these
+fn add_api_v1_routes(app: &mut tide::Server<Arc<Mutex<StaticData>>>) {
+    app.at("/api/v1/messages").post(api::create_message);
+    app.at("/api/v1/messages/:token")
+        .delete(api::delete_message);
+    app.at("/api/v1/messages/:token/consume")
+        .with(BlobPrefetchMiddleware::by_token())
+        .post(api::consume_message);
+    app.at("/api/v1/files").post(files::upload_file);
+    app.at("/api/v1/files/:token/consume")
+        .with(BlobPrefetchMiddleware::by_token())
+        .post(files::consume_file);
+    app.at("/api/v1/messages/:token/meta")
+        .with(BlobPrefetchMiddleware::by_token())
+        .get(api::message_meta);
+    app.at("/api/v1/messages/:token/qr").get(api::message_qr);
+    app.at("/api/v1/messages/:token/status")
+        .get(api::message_status);
+    app.at(&format!("{}/:download_id", downloads::DOWNLOADS_PATH))
+        .get(downloads::resume_download);
+    app.at(tus::UPLOADS_PATH)
+        .options(tus::describe_server)
+        .post(tus::create_upload);
+    app.at(&format!("{}/:upload_id", tus::UPLOADS_PATH))
+        .head(tus::get_upload_offset)
+        .patch(tus::append_chunk)
+        .delete(tus::delete_upload);
+    // a POST, so the user token doesn't end up in URLs and logs
+    app.at("/api/v1/quota").post(api::user_quota);
+    app.at("/api/v1/requests")
+        .post(secret_requests::create_secret_request);
+    app.at("/api/v1/requests/:token")
+        .get(secret_requests::secret_request_meta)
+        .post(secret_requests::answer_secret_request);
+    app.at("/api/v1/channels").post(channels::create_channel);
+    app.at("/api/v1/channels/:token/consume")
+        .with(BlobPrefetchMiddleware::new(channels::message_token_of))
+        .post(channels::consume_channel);
+    app.at("/api/v1/channels/:token/reply")
+        .post(secret_requests::answer_secret_request);
+    app.at("/api/v1/shares").post(shares::split_secret);
+    app.at("/api/v1/shares/combine")
+        .post(shares::combine_shares);
+    app.at("/api/v1/integrations/teams")
+        .post(teams::create_link);
+    app.at("/api/v1/admin/defaults")
+        .get(admin::get_default_limits)
+        .put(admin::update_default_limits)
+        .delete(admin::reset_default_limits);
+    app.at("/api/v1/admin/reencrypt")
+        .post(admin::reencrypt_messages);
+    app.at("/api/v1/admin/webhooks")
+        .get(admin::list_webhook_deliveries);
+    app.at("/api/v1/admin/webhooks/:id")
+        .get(admin::get_webhook_delivery);
+    app.at("/api/v1/admin/webhooks/:id/retry")
+        .post(admin::retry_webhook_delivery);
+    app.at("/api/v1/admin/passkeys")
+        .get(passkeys::list_passkeys)
+        .post(passkeys::register_passkey);
+    app.at("/api/v1/admin/passkeys/options")
+        .post(passkeys::passkey_creation_options);
+    app.at("/api/v1/admin/passkeys/:id")
+        .delete(passkeys::remove_passkey);
+    // signing in needs nothing but the passkey
+    app.at("/api/v1/admin/sessions")
+        .post(passkeys::sign_in)
+        .delete(passkeys::sign_out);
+    app.at("/api/v1/admin/sessions/all")
+        .delete(passkeys::sign_out_everywhere);
+    app.at("/api/v1/admin/sessions/options")
+        .post(passkeys::sign_in_options);
+    app.at("/api/v1/admin/invitations")
+        .get(invitations::list_invitations)
+        .post(invitations::create_invitation);
+    app.at("/api/v1/admin/invitations/:id")
+        .delete(invitations::remove_invitation);
+    app.at("/api/v1/admin/sessions/ldap").post(ldap::sign_in);
+    app.at(admin_auth::BASIC_SIGN_IN_PATH)
+        .post(admin_auth::sign_in);
+    app.at("/api/v1/portal/account").get(portal::get_account);
+    app.at("/api/v1/portal/keys")
+        .get(portal::list_api_keys)
+        .post(portal::create_api_key);
+    app.at("/api/v1/portal/keys/:id")
+        .delete(portal::remove_api_key);
+    app.at("/api/v1/portal/session").delete(portal::sign_out);
+    app.at("/api/v1/registrations")
+        .post(registration::start_registration);
+    app.at("/api/v1/registrations/confirm")
+        .post(registration::confirm_registration);
+    app.at("/api/v1/invitations/redeem")
+        .post(invitations::redeem_invitation);
+}
+
+pub fn init_logging(config: &Config) -> tide::Result<()> {
+    let level = config
+        .log_level
+        .as_deref()
+        .unwrap_or(DEFAULT_LOG_LEVEL)
+        .parse()
+        .map_err(|_| {
+            tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!("Invalid log level: {:?}", config.log_level),
+            )
+        })?;
+    logging::init(level, config.log_format)?;
+    Ok(())
+}
+
+// secrets such as encryption keys are never logged
+fn log_config_summary(config: &Config) {
+    if let Some(unix_socket_path) = &config.unix_socket_path {
+        log::info!("Unix socket: {}, TLS: disabled", unix_socket_path);
+    }
+    // otherwise the listeners log their addresses when they start
+    if config.listen_addrs.is_empty() && config.unix_socket_path.is_none() {
+        if config.force_unprotected_http {
+            log::info!("Port: {}, TLS: disabled", config.port);
+        } else {
+            log::info!(
+                "Port: {}, TLS: enabled, minimal version {}",
+                config.port,
+                config.tls.min_version.as_deref().unwrap_or("1.2")
+            );
+        }
+    }
+    match config.storage_mode {
+        StorageMode::Memory => log::info!("Storage: in memory"),
+        StorageMode::File => log::info!("Storage: {}", config.database_path),
+    }
+    match config.encryption_keys.last() {
+        Some(key) => log::info!(
+            "Encryption at rest: enabled, {} key(s), new messages use key {}",
+            config.encryption_keys.len()
+                + usize::from(
+                    config.encryption_key.is_some() || config.encryption_key_path.is_some()
+                ),
+            key.id
+        ),
+        None => log::info!(
+            "Encryption at rest: {}",
+            if config.encryption_key.is_some() || config.encryption_key_path.is_some() {
+                "enabled"
+            } else {
+                "disabled"
+            }
+        ),
+    }
+    log::info!(
+        "Integrity tags: {}",
+        if config.integrity_key.is_some() || config.integrity_key_path.is_some() {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    log::info!(
+        "Message tokens: {:?}, {:.0} bits",
+        config.message_tokens.style,
+        config.message_tokens.strength_bits()
+    );
+    log::info!(
+        "Message token signatures: {}",
+        if config.token_signing_key.is_some() || config.token_signing_key_path.is_some() {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    if config.message_tokens.is_weak() {
+        log::warn!("Message tokens are short enough to be guessed, make them longer or use a bigger alphabet");
+    }
+    if let Some(blob_storage) = &config.blob_storage {
+        let blob_storage_type = match blob_storage {
+            BlobStorageConfig::S3(_) => "s3",
+            BlobStorageConfig::Filesystem(_) => "filesystem",
+        };
+        log::info!(
+            "Blob storage: {}, threshold: {} bytes",
+            blob_storage_type,
+            config
+                .blob_threshold_bytes
+                .unwrap_or(DEFAULT_BLOB_THRESHOLD_BYTES)
+        );
+    }
+    if let Some(threshold_bytes) = config.compression_threshold_bytes {
+        log::info!("Compression at rest: above {} bytes", threshold_bytes);
+    }
+    if let Some(public_base_url) = &config.public_base_url {
+        log::info!("Public base URL: {}", public_base_url);
+    }
+    if let Some(short_link_base) = &config.short_link_base {
+        log::info!("Short link base: {}", short_link_base);
+    }
+    if config.read_receipts.enabled {
+        log::info!(
+            "Read receipts: enabled{}",
+            if config.read_receipts.record_client && !config.privacy_mode {
+                ", with the reader's network and user agent"
+            } else {
+                ""
+            }
+        );
+    }
+    if config.webhooks.is_some() {
+        log::info!("Webhooks: enabled");
+    }
+    if let Some(smtp) = &config.smtp {
+        log::info!("Email notifications: through {}", smtp.host);
+    }
+    if config.sms.is_some() {
+        log::info!("SMS link delivery: enabled");
+    }
+    if config.teams.is_some() {
+        log::info!("Teams integration: enabled");
+    }
+    if config.telegram.is_some() {
+        log::info!("Telegram bot: enabled");
+    }
+    if let Some(ldap) = &config.ldap {
+        log::info!("LDAP admin sign-in: through {}", ldap.url);
+    }
+    if config.admin_auth.basic_auth_enabled() {
+        log::info!(
+            "Admin basic auth: {} user(s)",
+            config.admin_auth.basic_auth_users.len()
+        );
+    }
+    if config.admin_auth.require_client_cert {
+        log::info!("Admin client certificates: required");
+    }
+    if let Some(oidc) = &config.oidc {
+        log::info!("OpenID Connect portal: through {}", oidc.issuer);
+    }
+    if config.registration.is_some() {
+        log::info!("Self-service registration: enabled");
+    }
+    if config.privacy_mode {
+        log::info!("Privacy mode: client addresses, user agents and referers are not logged");
+    }
+    if !config.plans.is_empty() {
+        let mut plans: Vec<&str> = config.plans.keys().map(String::as_str).collect();
+        plans.sort();
+        log::info!("Plans: {}", plans.join(", "));
+    }
+    if !config.trusted_proxies.is_empty() {
+        log::info!("Trusted proxies: {}", config.trusted_proxies.join(", "));
+    }
+    if config.proxy_protocol {
+        log::info!("PROXY protocol: enabled");
+    }
+    if let Some(abuse_log_path) = &config.abuse_log_path {
+        log::info!("Abuse log: {}", abuse_log_path);
+    }
+    log::info!(
+        "Default limits: retention {} minute(s), message size {} bytes, {} message(s) every {} minute(s), {} passphrase attempt(s)",
+        config.default_retention_limit_minutes,
+        config.default_max_message_size_bytes,
+        default_message_creation_limit_count(config),
+        config.default_message_creation_limit_minutes,
+        config.max_passphrase_attempts
+    );
+}
+
+fn default_message_creation_limit_count(config: &Config) -> u32 {
+    config
+        .default_message_creation_limit_count
+        .unwrap_or(DEFAULT_MESSAGE_CREATION_LIMIT_COUNT)
+}
+
+pub(crate) fn make_default_user_limits(config: &Config) -> UserLimits {
+    UserLimits {
+        retention_limit_minutes: config.default_retention_limit_minutes,
+        max_message_size_bytes: config.default_max_message_size_bytes,
+        message_creation_limit_minutes: config.default_message_creation_limit_minutes,
+        message_creation_limit_count: default_message_creation_limit_count(config),
+        active_message_limit: config.default_active_message_limit.unwrap_or(0),
+        monthly_byte_quota: config.default_monthly_byte_quota.unwrap_or(0),
+        // only granted to users one by one or through a plan
+        custom_slugs: false,
+    }
+}
+
+// the creators learn about the messages that nobody read through their webhooks, when
+// webhooks are enabled, and by email when it is configured
+pub fn clear_expired_messages(
+    database: &Mutex<dyn Store>,
+    webhooks_enabled: bool,
+    email: Option<&EmailSender>,
+) {
+    let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(now) => now.as_secs() as i64,
+        Err(_) => return,
+    };
+    let expired_messages = match database.lock().unwrap().clear_expired_messages(now) {
+        Ok(expired_messages) => expired_messages,
+        Err(err) => {
+            log::error!("Failed to remove expired messages: {}", err);
+            return;
+        }
+    };
+    // the requests that nobody answered in time go with their links
+    match database.lock().unwrap().clear_expired_secret_requests(now) {
+        Ok(0) => {}
+        Ok(count) => log::info!("Removed {} expired secret request(s)", count),
+        Err(err) => log::error!("Failed to remove expired secret requests: {}", err),
+    }
+    // the downloads that weren't completed in time
+    match database.lock().unwrap().clear_expired_downloads(now) {
+        Ok(0) => {}
+        Ok(count) => log::info!("Removed {} unfinished download(s)", count),
+        Err(err) => log::error!("Failed to remove expired downloads: {}", err),
+    }
+    if !expired_messages.is_empty() {
+        log::info!("Removed {} expired message(s)", expired_messages.len());
+    }
+    for expired_message in expired_messages {
+        log::info!(
+            target: "audit",
+            "Message expired unread message={}",
+            &expired_message.name_hash[..16]
+        );
+        if let (Some(email), Some(creator_email)) = (email, &expired_message.creator_email) {
+            email.notify(
+                creator_email,
+                EmailEvent::Expired,
+                &expired_message.name_hash,
+                expired_message.expire_timestamp,
+            );
+        }
+        let webhook_url = match expired_message.webhook_url {
+            Some(webhook_url) if webhooks_enabled => webhook_url,
+            _ => continue,
+        };
+        let event =
+            WebhookEvent::expired(&expired_message.name_hash, expired_message.expire_timestamp);
+        if let Err(err) = webhooks::enqueue(&*database.lock().unwrap(), &webhook_url, &event) {
+            log::error!("Failed to queue the webhook of an expired message: {}", err);
+        }
+    }
+}
+
+// periodically removes the messages that expired without being consumed
+pub fn spawn_cleanup_task(
+    database: Arc<Mutex<dyn Store>>,
+    webhooks_enabled: bool,
+    email: Option<EmailSender>,
+) -> async_std::task::JoinHandle<()> {
+    async_std::task::spawn(async move {
+        loop {
+            async_std::task::sleep(CLEANUP_INTERVAL).await;
+            clear_expired_messages(&database, webhooks_enabled, email.as_ref());
+            // the deletes of the expired messages, and whatever a cancelled request left behind
+            if let Err(err) = run_blob_transfers(&database, None).await {
+                log::error!(
+                    "Failed to remove the messages that weren't uploaded: {}",
+                    err
+                );
+            }
+        }
+    })
+}
+
+fn database_key(config: &Config) -> tide::Result<Option<Zeroizing<String>>> {
+    if let Some(key) = &config.database_key {
+        return Ok(Some(Zeroizing::new(key.clone())));
+    }
+    if let Some(key_path) = &config.database_key_path {
+        let file_content = Zeroizing::new(fs::read_to_string(key_path)?);
+        return Ok(Some(Zeroizing::new(file_content.trim().to_string())));
+    }
+    Ok(std::env::var(DATABASE_KEY_ENV)
+        .ok()
+        .filter(|key| !key.is_empty())
+        .map(Zeroizing::new))
+}
+
+// opens the storage configured in `config` and brings it to the latest schema
+pub fn open_database(config: &Config) -> tide::Result<OneTimeShareDb> {
+    let mut database = match (config.storage_mode, database_key(config)?) {
+        (StorageMode::Memory, database_key) => {
+            if database_key.is_some() {
+                log::warn!("The database key is not used, the database is kept in memory");
+            }
+            OneTimeShareDb::connect_in_memory()?
+        }
+        (StorageMode::File, Some(database_key)) => {
+            log::info!("Database file encryption: enabled");
+            OneTimeShareDb::connect_with_key(&config.database_path, &database_key)?
+        }
+        (StorageMode::File, None) => OneTimeShareDb::connect(&config.database_path)?,
+    };
+
+    if let Some(key) = &config.encryption_key {
+        database.set_cipher(MessageCipher::from_base64_key(key)?);
+    } else if let Some(key_path) = &config.encryption_key_path {
+        database.set_cipher(MessageCipher::from_key_file(key_path)?);
+    }
+    for key in &config.encryption_keys {
+        database.add_cipher(&key.id, key.load()?);
+    }
+
+    if let Some(key) = &config.integrity_key {
+        database.set_signer(MessageSigner::from_base64_key(key)?);
+    } else if let Some(key_path) = &config.integrity_key_path {
+        database.set_signer(MessageSigner::from_key_file(key_path)?);
+    }
+
+    if let Some(blob_storage) = &config.blob_storage {
+        database.set_blob_store(
+            blob_store::make_blob_store(blob_storage)?,
+            config
+                .blob_threshold_bytes
+                .unwrap_or(DEFAULT_BLOB_THRESHOLD_BYTES) as usize,
+        );
+    }
+
+    if let Some(threshold_bytes) = config.compression_threshold_bytes {
+        database.set_compression_threshold(threshold_bytes as usize);
+    }
+
+    database::update_version(&database)?;
+
+    database.set_user_limits(
+        "default",
+        &admin::load_default_user_limits(&database, config)?,
+    )?;
+
+    Ok(database)
+}
+
+// loads the page templates from the working directory and opens the database
+pub fn load_static_data(config: Config) -> tide::Result<StaticData> {
+    let index_html_template = fs::read_to_string("index.html")?;
+
+    let shared_html = fs::read("shared.html")?;
+    let request_html = fs::read("request.html")?;
+    let combine_html = fs::read("combine.html")?;
+    let admin_html = fs::read("admin.html")?;
+    let portal_html = fs::read("portal.html")?;
+    let register_html = fs::read("register.html")?;
+    let invite_html = fs::read("invite.html")?;
+
+    let base_urls = [
+        ("publicBaseUrl", &config.public_base_url),
+        ("shortLinkBase", &config.short_link_base),
+    ];
+    for (name, base_url) in base_urls {
+        let base_url = match base_url {
+            Some(base_url) => base_url,
+            None => continue,
+        };
+        let is_valid = tide::http::Url::parse(base_url)
+            .is_ok_and(|url| (url.scheme() == "https" || url.scheme() == "http") && url.has_host());
+        if !is_valid {
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!("{} '{}' is not a valid http(s) URL", name, base_url),
+            ));
+        }
+    }
+    // otherwise the links would lead to a host the allowlist refuses
+    if let Some(short_link_host) = config.short_link_base.as_deref().and_then(short_link_host) {
+        let is_allowed = config.allowed_hosts.is_empty()
+            || config
+                .allowed_hosts
+                .iter()
+                .any(|host| host_name(host).eq_ignore_ascii_case(&short_link_host));
+        if !is_allowed {
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!(
+                    "shortLinkBase host '{}' is not in allowedHosts",
+                    short_link_host
+                ),
+            ));
+        }
+    }
+
+    if config.admin_session_idle_minutes == Some(0) {
+        return Err(tide::Error::from_str(
+            StatusCode::InternalServerError,
+            "adminSessionIdleMinutes should be at least 1",
+        ));
+    }
+
+    // the passkeys are bound to a host, the one of the request can't be trusted for that
+    if config.admin_passkeys && config.public_base_url.is_none() {
+        return Err(tide::Error::from_str(
+            StatusCode::InternalServerError,
+            "adminPasskeys needs publicBaseUrl",
+        ));
+    }
+
+    config
+        .admin_auth
+        .validate()
+        .map_err(|message| tide::Error::from_str(StatusCode::InternalServerError, message))?;
+    // rustls only asks for client certificates when it has the CAs to verify them with
+    if config.admin_auth.require_client_cert && config.tls.client_ca_path.is_none() {
+        return Err(tide::Error::from_str(
+            StatusCode::InternalServerError,
+            "adminAuth.requireClientCert needs tls.clientCaPath",
+        ));
+    }
+
+    if let Some(oidc) = &config.oidc {
+        // the IdP only sends the browser back to the redirect URL it knows
+        if config.public_base_url.is_none() {
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                "oidc needs publicBaseUrl",
+            ));
+        }
+        if let Some(plan) = oidc.plans().find(|plan| !config.plans.contains_key(*plan)) {
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!("oidc refers to plan '{}' which is not in plans", plan),
+            ));
+        }
+    }
+
+    if let Some(registration) = &config.registration {
+        // the links are emailed and signed, the Host header can't be trusted for them
+        let has_token_signing_key =
+            config.token_signing_key.is_some() || config.token_signing_key_path.is_some();
+        if config.smtp.is_none() || config.public_base_url.is_none() || !has_token_signing_key {
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                "registration needs smtp, publicBaseUrl and tokenSigningKey or tokenSigningKeyPath",
+            ));
+        }
+        if let Some(plan) = registration
+            .plan
+            .as_ref()
+            .filter(|plan| !config.plans.contains_key(*plan))
+        {
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!(
+                    "registration refers to plan '{}' which is not in plans",
+                    plan
+                ),
+            ));
+        }
+    }
+
+    // the bot has no request to take the host from
+    if config.telegram.is_some()
+        && config.public_base_url.is_none()
+        && config.short_link_base.is_none()
+    {
+        return Err(tide::Error::from_str(
+            StatusCode::InternalServerError,
+            "telegram needs publicBaseUrl or shortLinkBase",
+        ));
+    }
+
+    config
+        .message_tokens
+        .validate()
+        .map_err(|err| tide::Error::from_str(StatusCode::InternalServerError, err))?;
+
+    // a log that can't be written shouldn't go unnoticed until the first attack
+    if let Some(abuse_log_path) = &config.abuse_log_path {
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(abuse_log_path)
+            .map_err(|err| {
+                tide::Error::from_str(
+                    StatusCode::InternalServerError,
+                    format!("Can't open abuseLogPath '{}': {}", abuse_log_path, err),
+                )
+            })?;
+    }
+
+    log_config_summary(&config);
+    let database = open_database(&config)?;
+    let default_user_limits = admin::load_default_user_limits(&database, &config)?;
+    let token_signer = if let Some(key) = &config.token_signing_key {
+        Some(Arc::new(TokenSigner::from_base64_key(key)?))
+    } else if let Some(key_path) = &config.token_signing_key_path {
+        Some(Arc::new(TokenSigner::from_key_file(key_path)?))
+    } else {
+        None
+    };
+
+    let webhooks = match &config.webhooks {
+        Some(webhook_config) => Some(WebhookSender::new(webhook_config)?),
+        None => None,
+    };
+    let email = match &config.smtp {
+        Some(smtp_config) => Some(EmailSender::new(smtp_config)?),
+        None => None,
+    };
+    let sms = match &config.sms {
+        Some(sms_config) => Some(SmsSender::new(sms_config)?),
+        None => None,
+    };
+    let telegram = match &config.telegram {
+        Some(telegram_config) => Some(TelegramBot::new(telegram_config)?),
+        None => None,
+    };
+    let oidc = match (&config.oidc, &config.public_base_url) {
+        (Some(oidc_config), Some(public_base_url)) => {
+            Some(OidcClient::new(oidc_config, public_base_url)?)
+        }
+        _ => None,
+    };
+    let ldap = match &config.ldap {
+        Some(ldap_config) => Some(LdapClient::new(ldap_config)?),
+        None => None,
+    };
+    let admin_sessions = AdminSessions::new(
+        config
+            .admin_session_idle_minutes
+            .map_or(DEFAULT_SESSION_IDLE_TIMEOUT, |minutes| {
+                Duration::from_secs(minutes * 60)
+            }),
+    );
+
+    Ok(StaticData {
+        index_html_template,
+        shared_html,
+        request_html,
+        combine_html,
+        admin_html,
+        portal_html,
+        register_html,
+        invite_html,
+        default_user_limits,
+        config,
+        database: Arc::new(Mutex::new(database)),
+        uploads: Arc::new(Mutex::new(Uploads::default())),
+        admin_sessions: Arc::new(Mutex::new(admin_sessions)),
+        portal_sessions: Arc::new(Mutex::new(PortalSessions::default())),
+        token_signer,
+        webhooks,
+        email,
+        sms,
+        telegram,
+        oidc,
+        ldap,
+    })
+}
+
+// the permissions are given in octal, e.g. "660"
+fn parse_unix_socket_mode(config: &Config) -> tide::Result<Option<u32>> {
+    match &config.unix_socket_mode {
+        Some(mode) => match u32::from_str_radix(mode, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(Some(mode)),
+            _ => Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!("unixSocketMode '{}' is not a valid octal mode", mode),
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+// every listener with its TLS flag, the plain port is used when nothing else is configured
+fn make_listen_addrs(config: &Config) -> tide::Result<Vec<(ListenAddr, bool)>> {
+    let mut listen_addrs = Vec::new();
+    for listen_config in &config.listen_addrs {
+        if listen_config.addr.parse::<SocketAddr>().is_err() {
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!(
+                    "listenAddrs entry '{}' is not a valid address",
+                    listen_config.addr
+                ),
+            ));
+        }
+        listen_addrs.push((
+            ListenAddr::Tcp(listen_config.addr.clone()),
+            listen_config.tls.unwrap_or(!config.force_unprotected_http),
+        ));
+    }
+    // a unix socket is used only behind a reverse proxy that terminates TLS
+    if let Some(path) = &config.unix_socket_path {
+        listen_addrs.push((
+            ListenAddr::Unix {
+                path: PathBuf::from(path),
+                mode: parse_unix_socket_mode(config)?,
+            },
+            false,
+        ));
+    }
+    if listen_addrs.is_empty() {
+        listen_addrs.push((
+            ListenAddr::Tcp(format!("0.0.0.0:{}", config.port)),
+            !config.force_unprotected_http,
+        ));
+    }
+    Ok(listen_addrs)
+}
+
+// serves the app until `shutdown` completes and the requests in flight are finished
+pub async fn listen(
+    app: tide::Server<Arc<Mutex<StaticData>>>,
+    config: &Config,
+    shutdown: impl Future<Output = ()>,
+) -> tide::Result<()> {
+    let listen_addrs = make_listen_addrs(config)?;
+
+    let tls_acceptor = if listen_addrs.iter().any(|(_, is_tls)| *is_tls) {
+        let cert_resolver = Arc::new(tls::ReloadingCertResolver::new(
+            &config.cert_path,
+            &config.key_path,
+        )?);
+        tls::spawn_cert_reload_task(cert_resolver.clone());
+        let tls_config = tls::make_server_config(cert_resolver, &config.tls)?;
+        Some(TlsAcceptor::from(Arc::new(tls_config)))
+    } else {
+        None
+    };
+
+    // the redirect goes to the first HTTPS listener
+    let https_port = listen_addrs.iter().find_map(|(addr, is_tls)| match addr {
+        ListenAddr::Tcp(addr) if *is_tls => addr
+            .parse::<SocketAddr>()
+            .ok()
+            .map(|addr| addr.port().to_string()),
+        _ => None,
+    });
+    let redirect = match (https_port, &config.http_redirect_port) {
+        (Some(https_port), Some(redirect_port)) => Some((https_port, redirect_port.clone())),
+        _ => None,
+    };
+
+    // everything is bound before serving, so a port that is taken stops the startup
+    let mut listeners = Vec::new();
+    for (addr, is_tls) in &listen_addrs {
+        let listener = server::Listener::bind(addr).await?;
+        listeners.push((listener, if *is_tls { tls_acceptor.clone() } else { None }));
+    }
+    let redirect_listener = match &redirect {
+        Some((_, redirect_port)) => Some(
+            server::Listener::bind(&ListenAddr::Tcp(format!("0.0.0.0:{}", redirect_port))).await?,
+        ),
+        None => None,
+    };
+
+    // every listener stops when the channel is closed
+    let (stop_sender, stop_receiver) = async_std::channel::bounded::<()>(1);
+    let stopped = |receiver: async_std::channel::Receiver<()>| async move {
+        let _ = receiver.recv().await;
+    };
+
+    let mut servers = Vec::new();
+    for (listener, tls_acceptor) in listeners {
+        servers.push(task::spawn(server::serve(
+            app.clone(),
+            listener,
+            tls_acceptor,
+            config.proxy_protocol,
+            stopped(stop_receiver.clone()),
+        )));
+    }
+    if let (Some(listener), Some((https_port, _))) = (redirect_listener, &redirect) {
+        servers.push(task::spawn(server::serve(
+            redirect::make_redirect_app(https_port, &config.allowed_hosts),
+            listener,
+            None,
+            config.proxy_protocol,
+            stopped(stop_receiver.clone()),
+        )));
+    }
+
+    shutdown.await;
+    stop_sender.close();
+    for server in servers {
+        server.await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tide::http::{Method, Request, Url};
+
+    pub(crate) fn setup_test_data() -> Arc<Mutex<StaticData>> {
+        let config = Config {
+            port: "8080".to_string(),
+            storage_mode: StorageMode::Memory,
+            database_path: ":memory:".to_string(),
+            force_unprotected_http: true,
+            cert_path: "".to_string(),
+            key_path: "".to_string(),
+            tls: TlsOptions::default(),
+            http_redirect_port: None,
+            security_headers: SecurityHeadersConfig::default(),
+            content_security_policy: CspConfig::default(),
+            cors: CorsConfig::default(),
+            trusted_proxies: Vec::new(),
+            proxy_protocol: false,
+            listen_addrs: Vec::new(),
+            unix_socket_path: None,
+            unix_socket_mode: None,
+            public_base_url: None,
+            short_link_base: None,
+            allowed_hosts: Vec::new(),
+            default_retention_limit_minutes: 60,
+            default_max_message_size_bytes: 1024,
+            default_message_creation_limit_minutes: 5,
+            default_message_creation_limit_count: None,
+            default_active_message_limit: None,
+            default_monthly_byte_quota: None,
+            admin_token: None,
+            admin_passkeys: false,
+            ldap: None,
+            admin_session_idle_minutes: None,
+            admin_auth: AdminAuthConfig::default(),
+            plans: HashMap::new(),
+            max_passphrase_attempts: 3,
+            encryption_key: None,
+            encryption_key_path: None,
+            encryption_keys: Vec::new(),
+            integrity_key: None,
+            integrity_key_path: None,
+            database_key: None,
+            database_key_path: None,
+            blob_storage: None,
+            blob_threshold_bytes: None,
+            compression_threshold_bytes: None,
+            max_request_body_bytes: None,
+            max_upload_bytes: None,
+            request_timeout_seconds: None,
+            max_concurrent_requests: None,
+            max_pending_download_bytes: None,
+            ip_rate_limits: IpRateLimitConfig::default(),
+            brute_force_protection: BruteForceConfig::default(),
+            read_receipts: ReadReceiptsConfig::default(),
+            webhooks: None,
+            smtp: None,
+            sms: None,
+            teams: None,
+            telegram: None,
+            oidc: None,
+            registration: None,
+            abuse_log_path: None,
+            log_level: None,
+            log_format: LogFormat::Text,
+            access_log_format: AccessLogFormat::Combined,
+            log_message_tokens: false,
+            privacy_mode: false,
+            message_tokens: TokenConfig::default(),
+            token_signing_key: None,
+            token_signing_key_path: None,
+        };
+
+        let default_user_limits = make_default_user_limits(&config);
+
+        let index_html = "<html>Index Page</html>".to_string();
+        let shared_html = "<html>Shared Page with token {{.MessageToken}}</html>"
+            .as_bytes()
+            .to_vec();
+        let request_html = "<html>Request page with token {{.RequestToken}}</html>"
+            .as_bytes()
+            .to_vec();
+        let combine_html = b"<html>Combine page</html>".to_vec();
+        let admin_html = b"<html>Admin page</html>".to_vec();
+        let portal_html = b"<html>Portal page</html>".to_vec();
+        let register_html = b"<html>Register page</html>".to_vec();
+        let invite_html = b"<html>Invite page</html>".to_vec();
+
+        let database = OneTimeShareDb::connect_in_memory().unwrap();
+
+        Arc::new(Mutex::new(StaticData {
+            index_html_template: index_html,
+            shared_html,
+            request_html,
+            combine_html,
+            admin_html,
+            portal_html,
+            register_html,
+            invite_html,
+            default_user_limits,
+            config,
+            database: Arc::new(Mutex::new(database)),
+            uploads: Arc::new(Mutex::new(Uploads::default())),
+            admin_sessions: Arc::new(Mutex::new(AdminSessions::default())),
+            portal_sessions: Arc::new(Mutex::new(PortalSessions::default())),
+            token_signer: None,
+            webhooks: None,
+            email: None,
+            sms: None,
+            telegram: None,
+            oidc: None,
+            ldap: None,
+        }))
+    }
+
+    #[async_std::test]
+    async fn test_home_page() {
+        let app_data = setup_test_data();
+        let app = init_app(app_data.clone());
+
+        let req = Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+        let mut res: Response = app.respond(req).await.unwrap();
+        let body = res.take_body().into_string().await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(body, "<html>Index Page</html>");
+        assert_eq!(res.content_type(), Some(tide::http::mime::HTML));
+        assert!(res["Content-Security-Policy"]
+            .as_str()
+            .contains("script-src 'self' 'nonce-"));
+    }
+
+    #[async_std::test]
+    async fn test_create_new_message() {
+        let app_data = setup_test_data();
+        let app = init_app(app_data.clone());
+
+        // Insert a user into the database for testing
+        let user_token = "test_token";
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                user_token,
+                &UserLimits {
+                    retention_limit_minutes: 60,
+                    max_message_size_bytes: 1024,
+                    message_creation_limit_minutes: 5,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let mut req = Request::new(Method::Post, Url::parse("http://localhost/save").unwrap());
+        req.set_body(
+            tide::http::Body::from_form(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                retention: Some(60),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn test_message_creation_limit_window() {
+        let app_data = setup_test_data();
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    retention_limit_minutes: 60,
+                    max_message_size_bytes: 1024,
+                    message_creation_limit_minutes: 5,
+                    message_creation_limit_count: 2,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let app = init_app(app_data.clone());
+
+        let mut responses = Vec::new();
+        for _ in 0..3 {
+            let mut req = Request::new(Method::Post, Url::parse("http://localhost/save").unwrap());
+            req.set_body(
+                tide::http::Body::from_form(&MessageForm {
+                    user_token: "test_token".to_string(),
+                    message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                    ..Default::default()
+                })
+                .unwrap(),
+            );
+            let res: Response = app.respond(req).await.unwrap();
+            responses.push(res);
+        }
+
+        assert_eq!(responses[0].status(), StatusCode::Ok);
+        assert_eq!(responses[1].status(), StatusCode::Ok);
+        assert_eq!(responses[2].status(), StatusCode::TooManyRequests);
+        assert_eq!(responses[2]["Retry-After"], "300");
+        let body: api::ErrorResponse = responses[2].take_body().into_json().await.unwrap();
+        assert_eq!(
+            body.error,
+            "Message creation limit reached. Wait for 5 minute(s) and repeat"
+        );
+        assert_eq!(body.retry_after_seconds, Some(300));
+    }
+
+    #[async_std::test]
+    async fn test_content_negotiation() {
+        let app_data = setup_test_data();
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    max_message_size_bytes: 1024,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let app = init_app(app_data);
+
+        let mut req = Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+        req.insert_header("Accept", "application/json");
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(res["Vary"].iter().any(|value| value == "Accept"));
+        let body: api::LimitsResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.max_message_size_bytes, 1024);
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/save?format=json").unwrap(),
+        );
+        req.set_body(
+            tide::http::Body::from_form(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: api::CreateMessageResponse = res.take_body().into_json().await.unwrap();
+        assert!(body.url.ends_with(&body.message_token));
+
+        // the JSON form of the page tells about the message without consuming it
+        for _ in 0..2 {
+            let req = Request::new(
+                Method::Get,
+                Url::parse(&format!(
+                    "http://localhost/shared/{}?format=json",
+                    body.message_token
+                ))
+                .unwrap(),
+            );
+            let mut res: Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Ok);
+            let meta: api::MessageMetaResponse = res.take_body().into_json().await.unwrap();
+            assert_eq!(meta.size_bytes, 11);
+        }
+
+        let mut req = Request::new(
+            Method::Get,
+            Url::parse("http://localhost/shared/unknown").unwrap(),
+        );
+        req.insert_header("Accept", "application/json");
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn test_random_message_tokens() {
+        let app_data = setup_test_data();
+        app_data.lock().unwrap().config.message_tokens = TokenConfig {
+            style: tokens::TokenStyle::Random,
+            length: 12,
+            alphabet: "abcdefghjkmnpqrstuvwxyz23456789".to_string(),
+        };
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits("test_token", &UserLimits::default())
+            .unwrap();
+        let app = init_app(app_data);
+
+        let mut req = Request::new(Method::Post, Url::parse("http://localhost/save").unwrap());
+        req.set_body("user_token=test_token&message_data=SGVsbG8gd29ybGQ%3D");
+        req.set_content_type(tide::http::mime::FORM);
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let url = res.take_body().into_string().await.unwrap();
+        let message_token = url.rsplit('/').next().unwrap();
+        assert_eq!(message_token.len(), 12);
+        assert!(message_token
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()));
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse(&format!(
+                "http://localhost/api/v1/messages/{}/consume",
+                message_token
+            ))
+            .unwrap(),
+        );
+        req.set_body("{}");
+        req.set_content_type(tide::http::mime::JSON);
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: api::ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(*body.message_data, "SGVsbG8gd29ybGQ=");
+    }
+
+    #[async_std::test]
+    async fn test_custom_slug() {
+        let app_data = setup_test_data();
+        for (user_token, custom_slugs) in [("test_token", true), ("other_token", false)] {
+            app_data
+                .lock()
+                .unwrap()
+                .database
+                .lock()
+                .unwrap()
+                .set_user_limits(
+                    user_token,
+                    &UserLimits {
+                        custom_slugs,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+        let app = init_app(app_data);
+        let create = |user_token: &str| {
+            let mut req = Request::new(
+                Method::Post,
+                Url::parse("http://localhost/api/v1/messages").unwrap(),
+            );
+            req.set_body(format!(
+                "{{\"user_token\": \"{}\", \"message_data\": \"SGVsbG8gd29ybGQ=\", \"slug\": \"db-password-friday\"}}",
+                user_token
+            ));
+            req.set_content_type(tide::http::mime::JSON);
+            req
+        };
+
+        let mut res: Response = app.respond(create("test_token")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: api::CreateMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.message_token, "db-password-friday");
+        assert_eq!(body.url, "http://localhost/shared/db-password-friday");
+
+        let res: Response = app.respond(create("test_token")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Conflict);
+        let mut res: Response = app.respond(create("other_token")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let error: api::ErrorResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(error.field.as_deref(), Some("slug"));
+
+        let req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/messages/db-password-friday/consume").unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: api::ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(*body.message_data, "SGVsbG8gd29ybGQ=");
+    }
+
+    #[async_std::test]
+    async fn test_save_accepts_json() {
+        let app_data = setup_test_data();
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    max_message_size_bytes: 1024,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let app = init_app(app_data);
+
+        let mut req = Request::new(Method::Post, Url::parse("http://localhost/save").unwrap());
+        req.set_body(
+            tide::http::Body::from_json(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(res
+            .take_body()
+            .into_string()
+            .await
+            .unwrap()
+            .starts_with("http://localhost/shared/"));
+
+        let mut req = Request::new(Method::Post, Url::parse("http://localhost/save").unwrap());
+        req.set_body("{\"user_token\": ");
+        req.set_content_type(tide::http::mime::JSON);
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let body: api::ErrorResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.error, "Can't parse request body as JSON");
+    }
+
+    #[async_std::test]
+    async fn test_save_accepts_multipart() {
+        let app_data = setup_test_data();
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    max_message_size_bytes: 1024,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let app = init_app(app_data);
+
+        let mut req = Request::new(Method::Post, Url::parse("http://localhost/save").unwrap());
+        req.set_body(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"user_token\"\r\n\r\n\
+             test_token\r\n\
+             --boundary\r\n\
+             Content-Disposition: form-data; name=\"file\"; filename=\"hello.bin\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n\
+             \x00\x01binary\r\n\
+             --boundary--\r\n",
+        );
+        req.insert_header("Content-Type", "multipart/form-data; boundary=boundary");
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let url = res.take_body().into_string().await.unwrap();
+        let message_token = url.rsplit('/').next().unwrap();
+
+        let req = Request::new(
+            Method::Post,
+            Url::parse(&format!("http://localhost/shared/{}", message_token)).unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body = res.take_body().into_string().await.unwrap();
+        assert!(
+            body.contains(&STANDARD.encode(b"\x00\x01binary")),
+            "{}",
+            body
+        );
+    }
+
+    #[async_std::test]
+    async fn test_save_rejects_invalid_input() {
+        let app = init_app(setup_test_data());
+
+        for (form, field) in [
+            (
+                "user_token=test_token&message_data=not%20base64",
+                "message_data",
+            ),
+            (
+                "user_token=bad%20token&message_data=SGVsbG8gd29ybGQ%3D",
+                "user_token",
+            ),
+            (
+                "user_token=test_token&message_data=SGVsbG8gd29ybGQ%3D&retention=99999999",
+                "retention",
+            ),
+        ] {
+            let mut req = Request::new(Method::Post, Url::parse("http://localhost/save").unwrap());
+            req.set_body(form);
+            req.set_content_type(tide::http::mime::FORM);
+            let mut res: Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::BadRequest, "{}", form);
+            let body: api::ErrorResponse = res.take_body().into_json().await.unwrap();
+            assert_eq!(body.field.as_deref(), Some(field));
+        }
+
+        let mut req = Request::new(Method::Post, Url::parse("http://localhost/save").unwrap());
+        req.set_body("user_token=test_token&message_data=SGVsbG8gd29ybGQ%3D&retention=soon");
+        req.set_content_type(tide::http::mime::FORM);
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+    }
+
+    #[async_std::test]
+    async fn test_unknown_user_is_checked_like_a_known_one() {
+        let app_data = setup_test_data();
+        let limits = UserLimits {
+            max_message_size_bytes: 5,
+            ..Default::default()
+        };
+        app_data.lock().unwrap().default_user_limits = limits.clone();
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits("test_token", &limits)
+            .unwrap();
+        let app = init_app(app_data);
+
+        let mut answers = Vec::new();
+        for user_token in ["test_token", "unknown"] {
+            let mut req = Request::new(Method::Post, Url::parse("http://localhost/save").unwrap());
+            req.set_body(format!(
+                "user_token={}&message_data=SGVsbG8gd29ybGQ%3D",
+                user_token
+            ));
+            req.set_content_type(tide::http::mime::FORM);
+            let mut res: Response = app.respond(req).await.unwrap();
+            let body: api::ErrorResponse = res.take_body().into_json().await.unwrap();
+            answers.push((res.status(), body.error));
+        }
+        assert_eq!(answers[0].0, StatusCode::PayloadTooLarge);
+        assert_eq!(answers[0], answers[1]);
+    }
+
+    #[async_std::test]
+    async fn test_active_message_limit() {
+        let app_data = setup_test_data();
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    active_message_limit: 1,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let app = init_app(app_data.clone());
+
+        let save = || {
+            let mut req = Request::new(Method::Post, Url::parse("http://localhost/save").unwrap());
+            req.set_body(
+                tide::http::Body::from_form(&MessageForm {
+                    user_token: "test_token".to_string(),
+                    message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                    ..Default::default()
+                })
+                .unwrap(),
+            );
+            app.respond::<_, Response>(req)
+        };
+
+        assert_eq!(save().await.unwrap().status(), StatusCode::Ok);
+        let mut res = save().await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let body: api::ErrorResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(
+            body.error,
+            "Too many unread messages. Wait until some of them are read or expire"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_plan_limits_replace_user_limits() {
+        let app_data = setup_test_data();
+        {
+            let mut data = app_data.lock().unwrap();
+            data.config.plans.insert(
+                "tiny".to_string(),
+                UserLimits {
+                    max_message_size_bytes: 5,
+                    ..Default::default()
+                },
+            );
+            let database = data.database.lock().unwrap();
+            database
+                .set_user_limits("test_token", &UserLimits::default())
+                .unwrap();
+            database.set_user_plan("test_token", Some("tiny")).unwrap();
+            database.set_user_plan("lost_token", Some("gone")).unwrap();
+        }
+        let app = init_app(app_data.clone());
+
+        let save = |user_token: &str| {
+            let mut req = Request::new(Method::Post, Url::parse("http://localhost/save").unwrap());
+            req.set_body(
+                tide::http::Body::from_form(&MessageForm {
+                    user_token: user_token.to_string(),
+                    message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                    ..Default::default()
+                })
+                .unwrap(),
+            );
+            app.respond::<_, Response>(req)
+        };
+
+        let mut res = save("test_token").await.unwrap();
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+        let body: api::ErrorResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.error, "Message is too big");
+        assert_eq!(
+            save("lost_token").await.unwrap().status(),
+            StatusCode::InternalServerError
+        );
+    }
+
+    #[async_std::test]
+    async fn test_create_new_message_with_public_base_url() {
+        let app_data = setup_test_data();
+        {
+            let mut data = app_data.lock().unwrap();
+            data.config.public_base_url = Some("https://1ts.dev/".to_string());
+            data.database
+                .lock()
+                .unwrap()
+                .set_user_limits(
+                    "test_token",
+                    &UserLimits {
+                        retention_limit_minutes: 60,
+                        max_message_size_bytes: 1024,
+                        message_creation_limit_minutes: 5,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+        let app = init_app(app_data.clone());
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("http://10.0.0.1:8080/save").unwrap(),
+        );
+        req.set_body(
+            tide::http::Body::from_form(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                retention: Some(60),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(res.header(api::MESSAGE_DELETE_TOKEN_HEADER).is_some());
+        let body = res.take_body().into_string().await.unwrap();
+        assert!(body.starts_with("https://1ts.dev/shared/"));
+    }
+
+    #[async_std::test]
+    async fn test_short_link_base() {
+        let app_data = setup_test_data();
+        {
+            let mut data = app_data.lock().unwrap();
+            data.config.public_base_url = Some("https://share.corp.example".to_string());
+            data.config.short_link_base = Some("https://1ts.link/".to_string());
+            data.database
+                .lock()
+                .unwrap()
+                .set_user_limits(
+                    "test_token",
+                    &UserLimits {
+                        retention_limit_minutes: 60,
+                        max_message_size_bytes: 1024,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+        let app = init_app(app_data.clone());
+
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("https://share.corp.example/save?format=json").unwrap(),
+        );
+        req.set_body(
+            tide::http::Body::from_form(&MessageForm {
+                user_token: "test_token".to_string(),
+                message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+                retention: Some(60),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: api::CreateMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(
+            body.url,
+            format!("https://1ts.link/shared/{}", body.message_token)
+        );
+        // the code is drawn by the canonical host, it has the short link in it
+        assert!(body
+            .qr_url
+            .unwrap()
+            .starts_with("https://share.corp.example/api/v1/messages/"));
+
+        // the short host serves the shared page and nothing else
+        let req = Request::new(Method::Get, Url::parse(&body.url).unwrap());
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let req = Request::new(Method::Get, Url::parse("https://1ts.link/").unwrap());
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+        let req = Request::new(Method::Post, Url::parse("https://1ts.link/save").unwrap());
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let req = Request::new(
+            Method::Post,
+            Url::parse(&format!("https://1ts.link/shared/{}", body.message_token)).unwrap(),
+        );
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn test_shared_page() {
+        let app_data = setup_test_data();
+        let app = init_app(app_data.clone());
+
+        let token = "test_token";
+        let url = format!("http://localhost/shared/{}", token);
+
+        let req = Request::new(Method::Get, Url::parse(&url).unwrap());
+        let mut res: Response = app.respond(req).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body = res.take_body().into_string().await.unwrap();
+        assert!(body.contains(token));
+    }
+
+    #[async_std::test]
+    async fn test_shared_page_counts_down() {
+        let app_data = setup_test_data();
+        let app = init_app(app_data.clone());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        {
+            let mut data = app_data.lock().unwrap();
+            data.shared_html = b"const expiresInSeconds = {{.ExpiresInSeconds}};".to_vec();
+            data.database
+                .lock()
+                .unwrap()
+                .save_message(
+                    "expiring",
+                    now + 60,
+                    b"Hello world",
+                    &MessageOptions::default(),
+                )
+                .unwrap();
+        }
+        let page = |token: &str| {
+            app.respond(Request::new(
+                Method::Get,
+                Url::parse(&format!("http://localhost/shared/{}", token)).unwrap(),
+            ))
+        };
+
+        let mut res: Response = page("expiring").await.unwrap();
+        let body = res.take_body().into_string().await.unwrap();
+        let seconds: u64 = body
+            .trim_start_matches("const expiresInSeconds = ")
+            .trim_end_matches(';')
+            .parse()
+            .unwrap();
+        assert!((50..=60).contains(&seconds));
+
+        let mut res: Response = page("unknown").await.unwrap();
+        let body = res.take_body().into_string().await.unwrap();
+        assert_eq!(body, "const expiresInSeconds = null;");
+    }
+
+    #[async_std::test]
+    async fn test_shared_page_requires_post_to_consume() {
+        let app_data = setup_test_data();
+        let app = init_app(app_data.clone());
+
+        let token = "test_token";
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .save_message(token, 0, b"Hello world", &MessageOptions::default())
+            .unwrap();
+
+        let url = Url::parse(&format!("http://localhost/shared/{}", token)).unwrap();
+
+        // opening the link (e.g. by a link preview bot) must not burn the message
+        let req = Request::new(Method::Get, url.clone());
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .get_message_info(token)
+            .unwrap()
+            .is_some());
+
+        let req = Request::new(Method::Post, url.clone());
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let req = Request::new(Method::Post, url);
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[test]
+    fn test_make_listen_addrs() {
+        let app_data = setup_test_data();
+        let mut config = app_data.lock().unwrap().config.clone();
+
+        config.force_unprotected_http = false;
+        assert_eq!(
+            make_listen_addrs(&config).unwrap(),
+            vec![(ListenAddr::Tcp("0.0.0.0:8080".to_string()), true)]
+        );
+
+        config.listen_addrs = vec![
+            ListenConfig {
+                addr: "[::]:443".to_string(),
+                tls: None,
+            },
+            ListenConfig {
+                addr: "127.0.0.1:8080".to_string(),
+                tls: Some(false),
+            },
+        ];
+        assert_eq!(
+            make_listen_addrs(&config).unwrap(),
+            vec![
+                (ListenAddr::Tcp("[::]:443".to_string()), true),
+                (ListenAddr::Tcp("127.0.0.1:8080".to_string()), false)
+            ]
+        );
+
+        config.listen_addrs[0].addr = "localhost:443".to_string();
+        assert!(make_listen_addrs(&config).is_err());
+    }
+
+    #[async_std::test]
+    async fn test_webhook_on_expiry() {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let webhook_url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let database: Arc<Mutex<dyn Store>> =
+            Arc::new(Mutex::new(OneTimeShareDb::connect_in_memory().unwrap()));
+        database
+            .lock()
+            .unwrap()
+            .save_message(
+                "expired",
+                100,
+                b"Hello world",
+                &MessageOptions {
+                    webhook_url: Some(webhook_url),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        clear_expired_messages(&database, true, None);
+
+        let (received, result) = futures_lite::future::zip(
+            webhooks::tests::receive_webhook(&listener, StatusCode::Ok),
+            webhooks::tests::make_sender(1).deliver_due_webhooks(&database),
+        )
+        .await;
+        result.unwrap();
+        let event: WebhookEvent = serde_json::from_slice(&received.body).unwrap();
+        assert_eq!(
+            event,
+            WebhookEvent::expired(&tokens::hash_token("expired"), 100)
+        );
+    }
+
+    #[async_std::test]
+    async fn test_email_on_expiry() {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let database: Arc<Mutex<dyn Store>> =
+            Arc::new(Mutex::new(OneTimeShareDb::connect_in_memory().unwrap()));
+        {
+            let database = database.lock().unwrap();
+            database
+                .set_user_email("user1", Some("alice@example.com"))
+                .unwrap();
+            database
+                .save_message(
+                    "expired",
+                    100,
+                    b"Hello world",
+                    &MessageOptions {
+                        user_token: Some("user1".to_string()),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+
+        let sender = email::tests::make_sender(listener.local_addr().unwrap().port());
+        clear_expired_messages(&database, false, Some(&sender));
+
+        let received = email::tests::receive_email(&listener).await;
+        assert!(received
+            .commands
+            .contains(&"RCPT TO:<alice@example.com>".to_string()));
+        assert!(received
+            .data
+            .contains("Subject: Your message expired unread\r\n"));
+        assert!(received
+            .data
+            .contains("expired on Thu, 01 Jan 1970 00:01:40 GMT without being read"));
+    }
+}
@@ -0,0 +1,133 @@
+use log::kv::{Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::time_format::format_rfc3339_millis;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // one JSON object per line, for log collectors
+    Json,
+}
+
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+}
+
+// collects the structured key-value pairs attached to a record
+#[derive(Default)]
+struct KeyValues(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for KeyValues {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+fn format_record(record: &Record, format: LogFormat, timestamp: &str) -> String {
+    let mut key_values = KeyValues::default();
+    let _ = record.key_values().visit(&mut key_values);
+
+    match format {
+        LogFormat::Text => {
+            let mut line = format!(
+                "{} {:<5} {}: {}",
+                timestamp,
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            for (key, value) in key_values.0 {
+                line.push_str(&format!(" {}={}", key, value));
+            }
+            line
+        }
+        LogFormat::Json => {
+            let mut object = serde_json::Map::new();
+            object.insert("timestamp".to_string(), timestamp.into());
+            object.insert("level".to_string(), record.level().as_str().into());
+            object.insert("target".to_string(), record.target().into());
+            object.insert("message".to_string(), record.args().to_string().into());
+            for (key, value) in key_values.0 {
+                object.insert(key, value.into());
+            }
+            serde_json::Value::Object(object).to_string()
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let timestamp_millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0);
+        eprintln!(
+            "{}",
+            format_record(
+                record,
+                self.format,
+                &format_rfc3339_millis(timestamp_millis)
+            )
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+// installs the global logger, can be called only once per process
+pub fn init(level: LevelFilter, format: LogFormat) -> Result<(), SetLoggerError> {
+    log::set_boxed_logger(Box::new(Logger { level, format }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn test_format_text_record() {
+        let key_values = [("status", 200)];
+        let record = Record::builder()
+            .args(format_args!("Response sent"))
+            .level(Level::Info)
+            .target("one_time_share")
+            .key_values(&key_values)
+            .build();
+
+        assert_eq!(
+            format_record(&record, LogFormat::Text, "1970-01-01T00:00:00.000Z"),
+            "1970-01-01T00:00:00.000Z INFO  one_time_share: Response sent status=200"
+        );
+    }
+
+    #[test]
+    fn test_format_json_record() {
+        let record = Record::builder()
+            .args(format_args!("Cleanup \"done\""))
+            .level(Level::Warn)
+            .target("one_time_share")
+            .build();
+
+        let line = format_record(&record, LogFormat::Json, "1970-01-01T00:00:00.000Z");
+        let parsed: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed["level"], "WARN");
+        assert_eq!(parsed["message"], "Cleanup \"done\"");
+        assert_eq!(parsed["timestamp"], "1970-01-01T00:00:00.000Z");
+    }
+}
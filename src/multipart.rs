@@ -0,0 +1,153 @@
+use zeroize::Zeroizing;
+
+pub struct MultipartField {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    // the message or the passphrase
+    pub data: Zeroizing<Vec<u8>>,
+}
+
+pub fn boundary_from_content_type(content_type: &str) -> Option<String> {
+    let mut parts = content_type.split(';');
+    if parts.next()?.trim() != "multipart/form-data" {
+        return None;
+    }
+    parts
+        .filter_map(|part| part.trim().strip_prefix("boundary="))
+        .map(|boundary| boundary.trim_matches('"').to_string())
+        .find(|boundary| !boundary.is_empty())
+}
+
+fn find_subslice(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if from > haystack.len() {
+        return None;
+    }
+    haystack[from..]
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| position + from)
+}
+
+fn get_header_param(header_value: &str, param_name: &str) -> Option<String> {
+    header_value.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.trim().split_once('=')?;
+        if name.eq_ignore_ascii_case(param_name) {
+            Some(value.trim_matches('"').to_string())
+        } else {
+            None
+        }
+    })
+}
+
+fn parse_part(part: &[u8]) -> Result<MultipartField, String> {
+    let headers_end =
+        find_subslice(part, b"\r\n\r\n", 0).ok_or("Multipart headers are not terminated")?;
+    let headers = std::str::from_utf8(&part[..headers_end])
+        .map_err(|_| "Multipart headers are not valid UTF-8")?;
+
+    let mut name = None;
+    let mut filename = None;
+    let mut content_type = None;
+    for header in headers.split("\r\n") {
+        let (header_name, header_value) = match header.split_once(':') {
+            Some(header) => header,
+            None => continue,
+        };
+        if header_name.eq_ignore_ascii_case("Content-Disposition") {
+            name = get_header_param(header_value, "name");
+            filename = get_header_param(header_value, "filename");
+        } else if header_name.eq_ignore_ascii_case("Content-Type") {
+            content_type = Some(header_value.trim().to_string());
+        }
+    }
+
+    Ok(MultipartField {
+        name: name.ok_or("Multipart field has no name")?,
+        filename,
+        content_type,
+        data: Zeroizing::new(part[headers_end + 4..].to_vec()),
+    })
+}
+
+pub fn parse_multipart(body: &[u8], boundary: &str) -> Result<Vec<MultipartField>, String> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut fields = Vec::new();
+
+    let mut position =
+        find_subslice(body, &delimiter, 0).ok_or("Multipart boundary not found")? + delimiter.len();
+    loop {
+        if body[position..].starts_with(b"--") {
+            return Ok(fields);
+        }
+        if !body[position..].starts_with(b"\r\n") {
+            return Err("Malformed multipart boundary".to_string());
+        }
+        position += 2;
+
+        let mut next_delimiter = b"\r\n".to_vec();
+        next_delimiter.extend_from_slice(&delimiter);
+        let part_end = find_subslice(body, &next_delimiter, position)
+            .ok_or("Multipart body is not terminated")?;
+        fields.push(parse_part(&body[position..part_end])?);
+        position = part_end + next_delimiter.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boundary_from_content_type() {
+        assert_eq!(
+            boundary_from_content_type("multipart/form-data; boundary=abc123").as_deref(),
+            Some("abc123")
+        );
+        assert_eq!(
+            boundary_from_content_type("multipart/form-data; boundary=\"abc 123\"").as_deref(),
+            Some("abc 123")
+        );
+        assert!(boundary_from_content_type("application/json").is_none());
+        assert!(boundary_from_content_type("multipart/form-data").is_none());
+    }
+
+    #[test]
+    fn test_parse_multipart() {
+        let body = b"--xyz\r\n\
+            Content-Disposition: form-data; name=\"user_token\"\r\n\
+            \r\n\
+            token\r\n\
+            --xyz\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"key.pem\"\r\n\
+            Content-Type: application/x-pem-file\r\n\
+            \r\n\
+            line1\r\nline2\r\n\
+            --xyz--\r\n";
+
+        let fields = parse_multipart(body, "xyz").unwrap();
+        assert_eq!(fields.len(), 2);
+
+        assert_eq!(fields[0].name, "user_token");
+        assert!(fields[0].filename.is_none());
+        assert_eq!(*fields[0].data, b"token");
+
+        assert_eq!(fields[1].name, "file");
+        assert_eq!(fields[1].filename.as_deref(), Some("key.pem"));
+        assert_eq!(
+            fields[1].content_type.as_deref(),
+            Some("application/x-pem-file")
+        );
+        assert_eq!(*fields[1].data, b"line1\r\nline2");
+    }
+
+    #[test]
+    fn test_parse_malformed_multipart() {
+        assert!(parse_multipart(b"no boundary here", "xyz").is_err());
+        assert!(parse_multipart(
+            b"--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\ndata",
+            "xyz"
+        )
+        .is_err());
+    }
+}
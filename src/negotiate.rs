@@ -0,0 +1,92 @@
+use tide::Request;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Html,
+    Json,
+}
+
+// the media types each format answers to
+fn format_of(media_type: &str) -> Option<Format> {
+    match media_type {
+        "text/html" | "application/xhtml+xml" => Some(Format::Html),
+        "application/json" => Some(Format::Json),
+        _ => None,
+    }
+}
+
+// the format the client asks for in an Accept header, None when it has no preference
+// between the two, e.g. for "*/*"
+pub fn parse_accept(accept: &str) -> Option<Format> {
+    let mut html_quality = 0.0;
+    let mut json_quality = 0.0;
+    for entry in accept.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let media_type = parts.next().unwrap_or("").to_ascii_lowercase();
+        let quality = parts
+            .filter_map(|param| param.strip_prefix("q="))
+            .find_map(|quality| quality.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match format_of(&media_type) {
+            Some(Format::Html) if quality > html_quality => html_quality = quality,
+            Some(Format::Json) if quality > json_quality => json_quality = quality,
+            _ => {}
+        }
+    }
+    if html_quality > json_quality {
+        Some(Format::Html)
+    } else if json_quality > html_quality {
+        Some(Format::Json)
+    } else {
+        None
+    }
+}
+
+// "?format=json" or "?format=html" wins over the Accept header, so a format can be picked
+// from a plain link
+pub fn requested_format<State>(req: &Request<State>) -> Option<Format> {
+    let format_param = req
+        .url()
+        .query_pairs()
+        .find(|(name, _)| name == "format")
+        .map(|(_, value)| value.to_ascii_lowercase());
+    match format_param.as_deref() {
+        Some("json") => return Some(Format::Json),
+        Some("html") => return Some(Format::Html),
+        _ => {}
+    }
+    req.header("Accept")
+        .and_then(|values| parse_accept(values.last().as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tide::http::{Method, Url};
+
+    #[test]
+    fn test_parse_accept() {
+        assert_eq!(
+            parse_accept("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+            Some(Format::Html)
+        );
+        assert_eq!(parse_accept("application/json"), Some(Format::Json));
+        assert_eq!(
+            parse_accept("text/html;q=0.5, application/json"),
+            Some(Format::Json)
+        );
+        assert_eq!(parse_accept("*/*"), None);
+        assert_eq!(parse_accept("text/plain"), None);
+    }
+
+    #[test]
+    fn test_format_query_wins() {
+        let mut req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://localhost/?format=json").unwrap(),
+        );
+        req.insert_header("Accept", "text/html");
+        let req: Request<()> = req.into();
+        assert_eq!(requested_format(&req), Some(Format::Json));
+    }
+}
@@ -0,0 +1,594 @@
+use async_std::future;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use http_types::{Body, Method, Request, StatusCode, Url};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::http_client::HttpClient;
+
+// the authorization code flow of OpenID Connect with PKCE, the client authenticates with
+// its secret and the ID token is checked against the keys the IdP publishes
+
+const USER_AGENT: &str = "one-time-share-oidc";
+const DEFAULT_SCOPES: &str = "openid email profile";
+const DEFAULT_GROUPS_CLAIM: &str = "groups";
+pub const CALLBACK_PATH: &str = "/oidc/callback";
+const DISCOVERY_PATH: &str = "/.well-known/openid-configuration";
+// an IdP that doesn't answer in time fails the sign-in
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// for the clock of the IdP being a bit ahead or behind
+const CLOCK_SKEW_SECONDS: i64 = 60;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OidcConfig {
+    // e.g. "https://login.example.com/realms/corp", the rest is read from its discovery document
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    // "openid email profile" by default
+    pub scopes: Option<String>,
+    // only people with a verified address of these domains can sign in, anyone the IdP
+    // lets through when empty
+    #[serde(default)]
+    pub allowed_email_domains: Vec<String>,
+    // the plan of the keys of people without a group in groupPlans, the default limits
+    // when not set
+    pub default_plan: Option<String>,
+    // the claim of the ID token with the groups of the person, "groups" by default
+    pub groups_claim: Option<String>,
+    // e.g. {"engineering": "team"}, the first group in the claim that has a plan decides
+    #[serde(default)]
+    pub group_plans: HashMap<String, String>,
+    // the most keys a person can have at once, 10 by default
+    pub max_api_keys: Option<usize>,
+    pub ca_bundle_path: Option<String>,
+}
+
+impl OidcConfig {
+    pub fn plans(&self) -> impl Iterator<Item = &String> {
+        self.default_plan.iter().chain(self.group_plans.values())
+    }
+}
+
+#[derive(Deserialize, Clone)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct JsonWebKey {
+    kty: String,
+    kid: Option<String>,
+    // RSA
+    n: Option<String>,
+    e: Option<String>,
+    // EC
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct JsonWebKeySet {
+    keys: Vec<JsonWebKey>,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+// who signed in, from the claims of the ID token
+#[derive(Debug, PartialEq)]
+pub struct Identity {
+    pub subject: String,
+    // only when the IdP verified it
+    pub email: Option<String>,
+    pub groups: Vec<String>,
+}
+
+// what the ID token has to match
+pub struct ExpectedClaims<'a> {
+    pub issuer: &'a str,
+    pub client_id: &'a str,
+    pub nonce: &'a str,
+    pub groups_claim: &'a str,
+}
+
+fn decode_part(part: &str) -> Result<Vec<u8>, String> {
+    URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| "ID token is malformed".to_string())
+}
+
+fn verify_signature(
+    key: &JsonWebKey,
+    alg: &str,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), String> {
+    let param = |value: &Option<String>| {
+        value
+            .as_deref()
+            .ok_or("The signing key is malformed".to_string())
+            .and_then(decode_part)
+    };
+    let verified = match (alg, key.kty.as_str()) {
+        ("RS256", "RSA") => RsaPublicKeyComponents {
+            n: param(&key.n)?,
+            e: param(&key.e)?,
+        }
+        .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature),
+        ("ES256", "EC") if key.crv.as_deref() == Some("P-256") => {
+            let mut point = vec![0x04];
+            point.extend_from_slice(&param(&key.x)?);
+            point.extend_from_slice(&param(&key.y)?);
+            // JWS signatures are r and s next to each other, not ASN.1
+            UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                .verify(message, signature)
+        }
+        _ => return Err(format!("ID tokens signed with {} aren't supported", alg)),
+    };
+    verified.map_err(|_| "The signature of the ID token doesn't match".to_string())
+}
+
+pub fn verify_id_token(
+    id_token: &str,
+    keys: &JsonWebKeySet,
+    expected: &ExpectedClaims,
+    now: i64,
+) -> Result<Identity, String> {
+    let mut parts = id_token.split('.');
+    let (header, payload, signature) =
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(payload), Some(signature), None) => (header, payload, signature),
+            _ => return Err("ID token is malformed".to_string()),
+        };
+    let jwt_header: JwtHeader = serde_json::from_slice(&decode_part(header)?)
+        .map_err(|_| "ID token is malformed".to_string())?;
+    // without a key id any key of the right type is tried
+    let candidates = keys.keys.iter().filter(|key| match &jwt_header.kid {
+        Some(kid) => key.kid.as_ref() == Some(kid),
+        None => true,
+    });
+    let signed = format!("{}.{}", header, payload);
+    let signature = decode_part(signature)?;
+    let mut result = Err("No key of the IdP matches the ID token".to_string());
+    for key in candidates {
+        result = verify_signature(key, &jwt_header.alg, signed.as_bytes(), &signature);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result?;
+
+    let claims: Value = serde_json::from_slice(&decode_part(payload)?)
+        .map_err(|_| "ID token is malformed".to_string())?;
+    if claims["iss"].as_str() != Some(expected.issuer) {
+        return Err("ID token is from another issuer".to_string());
+    }
+    let audience_matches = match &claims["aud"] {
+        Value::String(audience) => audience == expected.client_id,
+        Value::Array(audiences) => audiences
+            .iter()
+            .any(|audience| audience.as_str() == Some(expected.client_id)),
+        _ => false,
+    };
+    if !audience_matches {
+        return Err("ID token is for another client".to_string());
+    }
+    match claims["exp"].as_i64() {
+        Some(expire) if expire + CLOCK_SKEW_SECONDS > now => {}
+        _ => return Err("ID token has expired".to_string()),
+    }
+    if claims["nonce"].as_str() != Some(expected.nonce) {
+        return Err("ID token is for another sign-in".to_string());
+    }
+    let subject = match claims["sub"].as_str() {
+        Some(subject) if !subject.is_empty() => subject.to_string(),
+        _ => return Err("ID token has no subject".to_string()),
+    };
+    // an address the IdP doesn't vouch for could be anybody's, a missing claim or a string
+    // doesn't count as vouching
+    let email = claims["email"]
+        .as_str()
+        .filter(|_| claims["email_verified"].as_bool() == Some(true))
+        .map(str::to_string);
+    let groups = claims[expected.groups_claim]
+        .as_array()
+        .map(|groups| {
+            groups
+                .iter()
+                .filter_map(|group| group.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(Identity {
+        subject,
+        email,
+        groups,
+    })
+}
+
+// the S256 code challenge of PKCE
+pub fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+#[derive(Clone)]
+pub struct OidcClient {
+    client: HttpClient,
+    config: OidcConfig,
+    redirect_url: String,
+    // read on the first sign-in, the endpoints of an IdP don't change while the server runs
+    discovery: Arc<Mutex<Option<Discovery>>>,
+}
+
+impl OidcClient {
+    pub fn new(config: &OidcConfig, public_base_url: &str) -> std::io::Result<Self> {
+        Ok(OidcClient {
+            client: HttpClient::new(config.ca_bundle_path.as_deref())?,
+            config: config.clone(),
+            redirect_url: format!("{}{}", public_base_url.trim_end_matches('/'), CALLBACK_PATH),
+            discovery: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    pub fn issuer(&self) -> &str {
+        self.config.issuer.trim_end_matches('/')
+    }
+
+    pub fn max_api_keys(&self) -> usize {
+        self.config.max_api_keys.unwrap_or(10)
+    }
+
+    async fn send(&self, mut req: Request) -> http_types::Result<http_types::Response> {
+        let host = match (req.url().host_str(), req.url().port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => String::new(),
+        };
+        req.insert_header("Host", host);
+        req.insert_header("User-Agent", USER_AGENT);
+        req.insert_header("Accept", "application/json");
+        let mut res = future::timeout(REQUEST_TIMEOUT, self.client.send(req))
+            .await
+            .map_err(|_| http_types::Error::from_str(StatusCode::GatewayTimeout, "Timed out"))??;
+        if !res.status().is_success() {
+            let body = res.body_string().await.unwrap_or_default();
+            return Err(http_types::Error::from_str(
+                res.status(),
+                format!("IdP answered {}: {}", res.status(), body),
+            ));
+        }
+        Ok(res)
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, url: &str) -> http_types::Result<T> {
+        self.send(Request::new(Method::Get, Url::parse(url)?))
+            .await?
+            .body_json()
+            .await
+    }
+
+    async fn discovery(&self) -> http_types::Result<Discovery> {
+        if let Some(discovery) = self.discovery.lock().unwrap().clone() {
+            return Ok(discovery);
+        }
+        let discovery: Discovery = self
+            .get_json(&format!("{}{}", self.issuer(), DISCOVERY_PATH))
+            .await?;
+        if discovery.issuer.trim_end_matches('/') != self.issuer() {
+            return Err(http_types::Error::from_str(
+                StatusCode::BadGateway,
+                format!("The IdP calls itself {}", discovery.issuer),
+            ));
+        }
+        *self.discovery.lock().unwrap() = Some(discovery.clone());
+        Ok(discovery)
+    }
+
+    // where the browser is sent to sign in
+    pub async fn authorization_url(
+        &self,
+        state: &str,
+        nonce: &str,
+        code_verifier: &str,
+    ) -> http_types::Result<String> {
+        let discovery = self.discovery().await?;
+        let mut url = Url::parse(&discovery.authorization_endpoint)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair(
+                "scope",
+                self.config.scopes.as_deref().unwrap_or(DEFAULT_SCOPES),
+            )
+            .append_pair("state", state)
+            .append_pair("nonce", nonce)
+            .append_pair("code_challenge", &code_challenge(code_verifier))
+            .append_pair("code_challenge_method", "S256");
+        Ok(url.to_string())
+    }
+
+    // trades the code the browser came back with for the ID token and checks it
+    pub async fn sign_in(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+        now: i64,
+    ) -> http_types::Result<Identity> {
+        let discovery = self.discovery().await?;
+        let mut req = Request::new(Method::Post, Url::parse(&discovery.token_endpoint)?);
+        req.insert_header(
+            "Authorization",
+            format!(
+                "Basic {}",
+                STANDARD.encode(format!(
+                    "{}:{}",
+                    self.config.client_id, self.config.client_secret
+                ))
+            ),
+        );
+        req.set_body(Body::from_form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.redirect_url),
+            ("code_verifier", code_verifier),
+        ])?);
+        let token_response: TokenResponse = self.send(req).await?.body_json().await?;
+        // fetched every time, so keys the IdP rotated in are picked up
+        let keys: JsonWebKeySet = self.get_json(&discovery.jwks_uri).await?;
+        verify_id_token(
+            &token_response.id_token,
+            &keys,
+            &ExpectedClaims {
+                issuer: &discovery.issuer,
+                client_id: &self.config.client_id,
+                nonce,
+                groups_claim: self
+                    .config
+                    .groups_claim
+                    .as_deref()
+                    .unwrap_or(DEFAULT_GROUPS_CLAIM),
+            },
+            now,
+        )
+        .map_err(|err| http_types::Error::from_str(StatusCode::Unauthorized, err))
+    }
+
+    pub fn is_allowed(&self, identity: &Identity) -> bool {
+        if self.config.allowed_email_domains.is_empty() {
+            return true;
+        }
+        let domain = identity
+            .email
+            .as_deref()
+            .and_then(|email| email.rsplit_once('@'))
+            .map(|(_, domain)| domain);
+        domain.is_some_and(|domain| {
+            self.config
+                .allowed_email_domains
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(domain))
+        })
+    }
+
+    pub fn plan_for(&self, identity: &Identity) -> Option<String> {
+        identity
+            .groups
+            .iter()
+            .find_map(|group| self.config.group_plans.get(group))
+            .or(self.config.default_plan.as_ref())
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair};
+
+    pub(crate) const CLIENT_ID: &str = "one-time-share";
+
+    // signs ID tokens like an IdP with a P-256 key
+    pub(crate) struct TestIssuer {
+        key_pair: EcdsaKeyPair,
+    }
+
+    impl TestIssuer {
+        pub(crate) fn new() -> TestIssuer {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(
+                &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+                &SystemRandom::new(),
+            )
+            .unwrap();
+            TestIssuer {
+                key_pair: EcdsaKeyPair::from_pkcs8(
+                    &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+                    pkcs8.as_ref(),
+                )
+                .unwrap(),
+            }
+        }
+
+        pub(crate) fn jwks(&self) -> Value {
+            let point = self.key_pair.public_key().as_ref();
+            serde_json::json!({
+                "keys": [{
+                    "kty": "EC",
+                    "kid": "key1",
+                    "crv": "P-256",
+                    "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                    "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+                }],
+            })
+        }
+
+        pub(crate) fn id_token(&self, claims: &Value) -> String {
+            let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"ES256","kid":"key1"}"#);
+            let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+            let signed = format!("{}.{}", header, payload);
+            let signature = self
+                .key_pair
+                .sign(&SystemRandom::new(), signed.as_bytes())
+                .unwrap();
+            format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signature.as_ref()))
+        }
+    }
+
+    fn claims(issuer: &str) -> Value {
+        serde_json::json!({
+            "iss": issuer,
+            "aud": CLIENT_ID,
+            "sub": "alice",
+            "exp": 1000,
+            "nonce": "nonce1",
+            "email": "alice@example.com",
+            "email_verified": true,
+            "groups": ["staff", "engineering"],
+        })
+    }
+
+    const EXPECTED: ExpectedClaims = ExpectedClaims {
+        issuer: "https://idp.example.com",
+        client_id: CLIENT_ID,
+        nonce: "nonce1",
+        groups_claim: "groups",
+    };
+
+    fn make_config() -> OidcConfig {
+        OidcConfig {
+            issuer: "https://idp.example.com/".to_string(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: "secret".to_string(),
+            scopes: None,
+            allowed_email_domains: Vec::new(),
+            default_plan: None,
+            groups_claim: None,
+            group_plans: HashMap::new(),
+            max_api_keys: None,
+            ca_bundle_path: None,
+        }
+    }
+
+    #[test]
+    fn test_verify_id_token() {
+        let issuer = TestIssuer::new();
+        let keys: JsonWebKeySet = serde_json::from_value(issuer.jwks()).unwrap();
+        let id_token = issuer.id_token(&claims(EXPECTED.issuer));
+        assert_eq!(
+            verify_id_token(&id_token, &keys, &EXPECTED, 900),
+            Ok(Identity {
+                subject: "alice".to_string(),
+                email: Some("alice@example.com".to_string()),
+                groups: vec!["staff".to_string(), "engineering".to_string()],
+            })
+        );
+
+        assert!(verify_id_token(&id_token, &keys, &EXPECTED, 1100).is_err());
+        let other_nonce = ExpectedClaims {
+            nonce: "nonce2",
+            ..EXPECTED
+        };
+        assert!(verify_id_token(&id_token, &keys, &other_nonce, 900).is_err());
+        let other_client = ExpectedClaims {
+            client_id: "other",
+            ..EXPECTED
+        };
+        assert!(verify_id_token(&id_token, &keys, &other_client, 900).is_err());
+        let other_issuer = issuer.id_token(&claims("https://evil.example.com"));
+        assert!(verify_id_token(&other_issuer, &keys, &EXPECTED, 900).is_err());
+
+        // a token signed by someone else, or changed after it was signed
+        let forged = TestIssuer::new().id_token(&claims(EXPECTED.issuer));
+        assert!(verify_id_token(&forged, &keys, &EXPECTED, 900).is_err());
+        let (signed, signature) = id_token.rsplit_once('.').unwrap();
+        let (header, _) = signed.split_once('.').unwrap();
+        let mut changed = claims(EXPECTED.issuer);
+        changed["sub"] = "mallory".into();
+        let tampered = format!(
+            "{}.{}.{}",
+            header,
+            URL_SAFE_NO_PAD.encode(changed.to_string()),
+            signature
+        );
+        assert!(verify_id_token(&tampered, &keys, &EXPECTED, 900).is_err());
+        assert!(verify_id_token("not.a-token", &keys, &EXPECTED, 900).is_err());
+    }
+
+    #[test]
+    fn test_unverified_email_is_ignored() {
+        let issuer = TestIssuer::new();
+        let keys: JsonWebKeySet = serde_json::from_value(issuer.jwks()).unwrap();
+        for email_verified in [
+            Value::Bool(false),
+            Value::from("false"),
+            Value::from("true"),
+        ] {
+            let mut unverified = claims(EXPECTED.issuer);
+            unverified["email_verified"] = email_verified;
+            let identity =
+                verify_id_token(&issuer.id_token(&unverified), &keys, &EXPECTED, 900).unwrap();
+            assert_eq!(identity.email, None);
+        }
+        let mut missing = claims(EXPECTED.issuer);
+        missing.as_object_mut().unwrap().remove("email_verified");
+        let identity = verify_id_token(&issuer.id_token(&missing), &keys, &EXPECTED, 900).unwrap();
+        assert_eq!(identity.email, None);
+    }
+
+    #[test]
+    fn test_allowed_domains_and_plans() {
+        let mut config = make_config();
+        config.allowed_email_domains = vec!["Example.com".to_string()];
+        config.default_plan = Some("free".to_string());
+        config.group_plans = HashMap::from([("engineering".to_string(), "team".to_string())]);
+        let client = OidcClient::new(&config, "https://1ts.dev/").unwrap();
+        assert_eq!(client.redirect_url, "https://1ts.dev/oidc/callback");
+
+        let mut identity = Identity {
+            subject: "alice".to_string(),
+            email: Some("alice@example.com".to_string()),
+            groups: vec!["staff".to_string(), "engineering".to_string()],
+        };
+        assert!(client.is_allowed(&identity));
+        assert_eq!(client.plan_for(&identity).as_deref(), Some("team"));
+
+        identity.groups.clear();
+        assert_eq!(client.plan_for(&identity).as_deref(), Some("free"));
+        identity.email = Some("alice@example.org".to_string());
+        assert!(!client.is_allowed(&identity));
+        identity.email = None;
+        assert!(!client.is_allowed(&identity));
+    }
+
+    #[test]
+    fn test_code_challenge() {
+        // RFC 7636 appendix B
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+}
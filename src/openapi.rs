@@ -0,0 +1,1424 @@
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::binary_format::BinaryFormat;
+use crate::csp;
+use crate::validation::MAX_RETENTION_MINUTES;
+use crate::{Config, StaticData};
+
+pub const OPENAPI_PATH: &str = "/openapi.json";
+pub const DOCS_PATH: &str = "/docs";
+// built into the binary, the page needs nothing but the document
+const DOCS_HTML: &str = include_str!("../docs.html");
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+// the answers come as CBOR or MessagePack too, with the base64 fields as raw bytes
+fn response_content(schema: Value) -> Value {
+    json!({
+        "application/json": { "schema": schema },
+        BinaryFormat::Cbor.media_type(): { "schema": schema },
+        BinaryFormat::MessagePack.media_type(): { "schema": schema },
+    })
+}
+
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": response_content(schema_ref("ErrorResponse")),
+    })
+}
+
+fn json_response(description: &str, schema_name: &str) -> Value {
+    json!({
+        "description": description,
+        "content": response_content(schema_ref(schema_name)),
+    })
+}
+
+fn token_parameter() -> Value {
+    json!({
+        "name": "token",
+        "in": "path",
+        "required": true,
+        "description": "The message token from the shared link",
+        "schema": { "type": "string" },
+    })
+}
+
+fn request_token_parameter() -> Value {
+    json!({
+        "name": "token",
+        "in": "path",
+        "required": true,
+        "description": "The request token from the request link",
+        "schema": { "type": "string" },
+    })
+}
+
+fn channel_token_parameter() -> Value {
+    json!({
+        "name": "token",
+        "in": "path",
+        "required": true,
+        "description": "The channel token the creator passed on",
+        "schema": { "type": "string" },
+    })
+}
+
+fn delivery_id_parameter() -> Value {
+    json!({
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "description": "The id of the webhook delivery",
+        "schema": { "type": "integer" },
+    })
+}
+
+fn schemas() -> Value {
+    let mut schemas = json!({
+        "CreateMessageRequest": {
+            "type": "object",
+            "required": ["user_token", "message_data"],
+            "properties": {
+                "user_token": { "type": "string", "maxLength": 128, "pattern": "^[A-Za-z0-9._-]+$" },
+                "message_data": { "type": "string", "format": "byte", "description": "The message, base64 encoded" },
+                "retention": { "type": "integer", "minimum": 0, "maximum": MAX_RETENTION_MINUTES, "description": "Minutes until the message expires, 0 keeps it until it's read" },
+                "passphrase": { "type": "string", "description": "Needed to read the message" },
+                "end_to_end": { "type": "boolean", "description": "The data was encrypted by the client, the link then carries a {key} placeholder" },
+                "max_views": { "type": "integer", "minimum": 1, "maximum": 100, "default": 1, "description": "How many times the message can be read before it's removed" },
+                "not_before": { "type": "integer", "description": "Unix time before which the message can't be read, the link can be handed out earlier; it must be before the expiry" },
+                "activate_in_minutes": { "type": "integer", "minimum": 0, "maximum": 525600, "description": "Minutes until the message can be read, e.g. when a rotated credential takes effect; the retention counts from then. Not with not_before" },
+                "dual_control": { "type": "boolean", "default": false, "description": "The message is read only with the two authorization tokens of the answer presented together, for secrets no single person may retrieve alone" },
+                "totp_secret": { "type": "string", "example": "JBSWY3DPEHPK3PXP", "description": "Base32 secret of an authenticator the recipient already has (10 to 64 bytes), reading the message takes a current 6-digit code of it" },
+                "totp": { "type": "boolean", "default": false, "description": "The same with a new secret, which comes back in totp_secret and totp_uri of the answer" },
+                "pin": { "type": "string", "pattern": "^[0-9]{4,8}$", "description": "A short PIN to send the recipient apart from the link; three wrong ones destroy the message" },
+                "allowed_ips": { "type": "array", "maxItems": 50, "items": { "type": "string" }, "example": ["10.8.0.0/16"], "description": "Addresses or CIDR blocks the message can be read from, e.g. the range of a VPN; other readers get 403 and the message stays" },
+                "grace_minutes": { "type": "integer", "minimum": 0, "maximum": 60, "default": 0, "description": "Minutes the message can still be read again after the last view, so a closed tab doesn't lose it" },
+                "filename": { "type": "string" },
+                "content_type": { "type": "string" },
+                "slug": { "type": "string", "minLength": 3, "maxLength": 64, "pattern": "^[a-z0-9]([a-z0-9-]*[a-z0-9])?$", "description": "The name of the link instead of a random token, for the users that are allowed to choose it" },
+                "webhook_url": { "type": "string", "format": "uri", "maxLength": 2048, "description": "Gets a POST signed in the Webhook-Signature header when the message is read or expires unread, instead of the webhook of the user" },
+                "sms_to": { "type": "string", "pattern": "^\\+[1-9][0-9]{7,14}$", "description": "A phone number in the international format the link is texted to, when the server has an SMS provider; not for end-to-end encrypted messages" },
+                "recipients": { "type": "array", "maxItems": 50, "items": { "type": "string", "minLength": 1, "maxLength": 100 }, "description": "Names of the recipients, each gets their own one-time link; not with a slug or sms_to" },
+            },
+        },
+        "CreateMessageResponse": {
+            "type": "object",
+            "required": ["url", "message_token", "expire_timestamp", "sha256", "delete_token"],
+            "properties": {
+                "url": { "type": "string", "format": "uri" },
+                "qr_url": { "type": "string", "format": "uri", "description": "A QR code of the link, missing for end-to-end encrypted messages" },
+                "recipients": { "type": "array", "items": schema_ref("RecipientLink"), "description": "Only for a message with recipients, the link of the first one is also `url`" },
+                "message_token": { "type": "string" },
+                "expire_timestamp": { "type": "integer", "description": "Unix time, 0 if the message doesn't expire" },
+                "not_before": { "type": "integer", "description": "Unix time the message can be read from, for a locked or delayed message" },
+                "sha256": { "type": "string", "description": "Hex SHA-256 of the message data, the consumption answer carries it in the Message-Sha256 header" },
+                "delete_token": { "type": "string", "description": "Removes the message before it's read, keep it to yourself; the same for the links of all recipients" },
+                "authorization_tokens": { "type": "array", "items": { "type": "string" }, "description": "The two tokens of a dual control message, each for another person; both are needed to read it" },
+                "totp_secret": { "type": "string", "description": "The new authenticator secret in base32, for the recipient only" },
+                "totp_uri": { "type": "string", "description": "The same as an otpauth:// link, authenticator apps take it from a QR code" },
+            },
+        },
+        "MessageStatusResponse": {
+            "type": "object",
+            "required": ["status", "expire_timestamp"],
+            "properties": {
+                "status": { "type": "string", "enum": ["pending", "consumed", "expired", "deleted", "destroyed"], "description": "destroyed after too many wrong passphrases" },
+                "created_timestamp": { "type": "integer", "description": "Unix time, missing for the messages stored before it was kept" },
+                "expire_timestamp": { "type": "integer", "description": "Unix time, 0 if the message doesn't expire" },
+                "consumed_timestamp": { "type": "integer", "description": "Unix time the message was read" },
+                "removed_timestamp": { "type": "integer", "description": "Unix time the message was removed, for any reason" },
+                "receipt": {
+                    "type": "object",
+                    "description": "Kept when read receipts are turned on",
+                    "properties": {
+                        "read_timestamp": { "type": "integer" },
+                        "client_ip": { "type": "string", "description": "The network of the reader, when the config asks for it" },
+                        "user_agent": { "type": "string", "description": "The first 64 characters, when the config asks for it" },
+                    },
+                },
+            },
+        },
+        "ConsumeMessageRequest": {
+            "type": "object",
+            "properties": {
+                "passphrase": { "type": "string" },
+                "authorization_tokens": { "type": "array", "items": { "type": "string" }, "description": "Both tokens of a dual control message, in any order" },
+                "totp_code": { "type": "string", "example": "287082", "description": "The current code of the authenticator the message is bound to, each code is taken once" },
+                "pin": { "type": "string", "description": "The PIN of the message, three wrong ones destroy it" },
+            },
+        },
+        "ConsumeMessageResponse": {
+            "type": "object",
+            "required": ["message_data", "expire_timestamp"],
+            "properties": {
+                "message_data": { "type": "string", "format": "byte" },
+                "expire_timestamp": { "type": "integer" },
+                "expires_in_seconds": { "type": "integer", "description": "Seconds left until the message expires, missing when it doesn't" },
+                "filename": { "type": "string", "nullable": true },
+                "content_type": { "type": "string", "nullable": true },
+            },
+        },
+        "MessageMetaResponse": {
+            "type": "object",
+            "required": ["size_bytes", "expire_timestamp", "passphrase_required", "client_encrypted"],
+            "properties": {
+                "size_bytes": { "type": "integer" },
+                "expire_timestamp": { "type": "integer" },
+                "expires_in_seconds": { "type": "integer", "description": "Seconds left until the message expires, missing when it doesn't" },
+                "passphrase_required": { "type": "boolean" },
+                "dual_control": { "type": "boolean", "description": "Two authorization tokens are needed to read it" },
+                "totp_required": { "type": "boolean", "description": "A code of an authenticator is needed to read it" },
+                "pin_required": { "type": "boolean", "description": "A PIN is needed to read it" },
+                "client_encrypted": { "type": "boolean" },
+                "filename": { "type": "string", "nullable": true },
+                "content_type": { "type": "string", "nullable": true },
+                "remaining_views": { "type": "integer", "description": "The reads left, the last one removes the message; 0 while the message is read again in its grace window" },
+                "not_before": { "type": "integer", "description": "Unix time the message can be read from, when it's set" },
+            },
+        },
+        "TeamsActivity": {
+            "type": "object",
+            "properties": {
+                "type": { "type": "string", "example": "message" },
+                "text": { "type": "string", "description": "The chat message with the mention of the bot, the rest of it is the secret", "example": "<at>Share</at>&nbsp;hunter2" },
+            },
+        },
+        "TeamsReply": {
+            "type": "object",
+            "required": ["type", "text"],
+            "properties": {
+                "type": { "type": "string", "enum": ["message"] },
+                "text": { "type": "string", "description": "The link, or why it couldn't be created" },
+            },
+        },
+        "CreateSecretRequestRequest": {
+            "type": "object",
+            "required": ["user_token"],
+            "properties": {
+                "user_token": { "type": "string" },
+                "retention": { "type": "integer", "minimum": 1, "maximum": MAX_RETENTION_MINUTES, "description": "Minutes the request stays open and the answer is kept, a week or the limit of the user by default" },
+                "note": { "type": "string", "maxLength": 500, "description": "Shown to whoever answers" },
+            },
+        },
+        "CreateSecretRequestResponse": {
+            "type": "object",
+            "required": ["url", "request_token", "response_url", "response_token", "expire_timestamp"],
+            "properties": {
+                "url": { "type": "string", "description": "The page to send to whoever should answer" },
+                "request_token": { "type": "string" },
+                "response_url": { "type": "string", "description": "Where the requester reads the answer, once" },
+                "response_token": { "type": "string", "description": "The message token of the answer, known only to the requester" },
+                "expire_timestamp": { "type": "integer" },
+            },
+        },
+        "SecretRequestMeta": {
+            "type": "object",
+            "required": ["expire_timestamp"],
+            "properties": {
+                "note": { "type": "string" },
+                "expire_timestamp": { "type": "integer" },
+                "max_size_bytes": { "type": "integer" },
+            },
+        },
+        "SecretAnswer": {
+            "type": "object",
+            "required": ["message_data"],
+            "properties": {
+                "message_data": { "type": "string", "format": "byte" },
+                "filename": { "type": "string" },
+                "content_type": { "type": "string" },
+            },
+        },
+        "SecretAnswerResponse": {
+            "type": "object",
+            "required": ["expire_timestamp"],
+            "properties": {
+                "expire_timestamp": { "type": "integer", "description": "When the answer expires unread, 0 for never" },
+            },
+        },
+        "CreateChannelRequest": {
+            "type": "object",
+            "required": ["user_token", "message_data"],
+            "properties": {
+                "user_token": { "type": "string" },
+                "message_data": { "type": "string", "format": "byte" },
+                "retention": { "type": "integer", "minimum": 1, "maximum": MAX_RETENTION_MINUTES, "description": "Minutes the channel stays open and the reply is kept" },
+                "passphrase": { "type": "string", "description": "Asked for when the secret is read" },
+            },
+        },
+        "CreateChannelResponse": {
+            "type": "object",
+            "required": ["channel_token", "reply_url", "reply_token", "expire_timestamp"],
+            "properties": {
+                "channel_token": { "type": "string", "description": "For the other party, to read the secret and send the reply" },
+                "reply_url": { "type": "string", "description": "Where the creator reads the reply, once" },
+                "reply_token": { "type": "string", "description": "The message token of the reply, known only to the creator" },
+                "expire_timestamp": { "type": "integer" },
+            },
+        },
+        "RecipientLink": {
+            "type": "object",
+            "required": ["name", "url", "message_token"],
+            "properties": {
+                "name": { "type": "string" },
+                "url": { "type": "string" },
+                "message_token": { "type": "string", "description": "Its status, with the delete token of the message, tells whether the recipient read it" },
+            },
+        },
+        "QuotaRequest": {
+            "type": "object",
+            "required": ["user_token"],
+            "properties": {
+                "user_token": { "type": "string" },
+            },
+        },
+        "QuotaResponse": {
+            "type": "object",
+            "required": ["month", "used_bytes"],
+            "properties": {
+                "month": { "type": "string", "example": "2024-02" },
+                "used_bytes": { "type": "integer" },
+                "quota_bytes": { "type": "integer", "nullable": true },
+                "remaining_bytes": { "type": "integer", "nullable": true },
+            },
+        },
+        "DefaultLimits": {
+            "type": "object",
+            "properties": {
+                "retention_limit_minutes": { "type": "integer", "nullable": true },
+                "max_message_size_bytes": { "type": "integer", "nullable": true },
+                "message_creation_limit_minutes": { "type": "integer", "nullable": true },
+                "message_creation_limit_count": { "type": "integer", "nullable": true },
+            },
+        },
+        "ReencryptResponse": {
+            "type": "object",
+            "required": ["reencrypted_messages"],
+            "properties": {
+                "reencrypted_messages": { "type": "integer" },
+            },
+        },
+        "WebhookDelivery": {
+            "type": "object",
+            "required": ["id", "url", "event", "payload", "state", "attempts", "created_timestamp"],
+            "properties": {
+                "id": { "type": "integer" },
+                "url": { "type": "string" },
+                "event": { "type": "string", "enum": ["message.consumed", "message.expired"] },
+                "payload": { "type": "string", "description": "The JSON body that is signed and sent" },
+                "state": { "type": "string", "enum": ["pending", "delivered", "failed"], "description": "failed once it's out of attempts" },
+                "attempts": { "type": "integer" },
+                "created_timestamp": { "type": "integer" },
+                "next_attempt_timestamp": { "type": "integer", "nullable": true },
+                "last_attempt_timestamp": { "type": "integer", "nullable": true },
+                "last_status": { "type": "integer", "nullable": true, "description": "The status the webhook answered the last attempt with" },
+                "last_error": { "type": "string", "nullable": true, "description": "Why the last attempt got no answer" },
+            },
+        },
+        "WebhookDeliveriesResponse": {
+            "type": "object",
+            "required": ["deliveries"],
+            "properties": {
+                "deliveries": { "type": "array", "items": { "$ref": "#/components/schemas/WebhookDelivery" } },
+            },
+        },
+        "AdminPasskey": {
+            "type": "object",
+            "required": ["id", "credential_id", "sign_count", "name", "created_timestamp"],
+            "properties": {
+                "id": { "type": "integer" },
+                "credential_id": { "type": "string", "description": "base64url, as the browser reports it" },
+                "sign_count": { "type": "integer" },
+                "name": { "type": "string" },
+                "created_timestamp": { "type": "integer" },
+                "last_used_timestamp": { "type": "integer", "nullable": true },
+            },
+        },
+        "PasskeysResponse": {
+            "type": "object",
+            "required": ["passkeys"],
+            "properties": {
+                "passkeys": { "type": "array", "items": { "$ref": "#/components/schemas/AdminPasskey" } },
+            },
+        },
+        "PasskeyCreationOptions": {
+            "type": "object",
+            "description": "The publicKey options of navigator.credentials.create(), the binary fields are base64url",
+            "required": ["challenge", "rp", "user", "pubKeyCredParams", "timeout", "attestation", "authenticatorSelection", "excludeCredentials"],
+            "properties": {
+                "challenge": { "type": "string" },
+                "rp": { "type": "object" },
+                "user": { "type": "object" },
+                "pubKeyCredParams": { "type": "array", "items": { "type": "object" } },
+                "timeout": { "type": "integer" },
+                "attestation": { "type": "string" },
+                "authenticatorSelection": { "type": "object" },
+                "excludeCredentials": { "type": "array", "items": { "type": "object" } },
+            },
+        },
+        "PasskeyRequestOptions": {
+            "type": "object",
+            "description": "The publicKey options of navigator.credentials.get(), the binary fields are base64url",
+            "required": ["challenge", "rpId", "timeout", "userVerification", "allowCredentials"],
+            "properties": {
+                "challenge": { "type": "string" },
+                "rpId": { "type": "string" },
+                "timeout": { "type": "integer" },
+                "userVerification": { "type": "string" },
+                "allowCredentials": { "type": "array", "items": { "type": "object" } },
+            },
+        },
+        "RegisterPasskeyRequest": {
+            "type": "object",
+            "required": ["name", "client_data_json", "attestation_object"],
+            "properties": {
+                "name": { "type": "string", "maxLength": 64 },
+                "client_data_json": { "type": "string", "description": "base64url" },
+                "attestation_object": { "type": "string", "description": "base64url" },
+            },
+        },
+        "SignInRequest": {
+            "type": "object",
+            "required": ["credential_id", "client_data_json", "authenticator_data", "signature"],
+            "properties": {
+                "credential_id": { "type": "string", "description": "base64url, the id of the credential" },
+                "client_data_json": { "type": "string", "description": "base64url" },
+                "authenticator_data": { "type": "string", "description": "base64url" },
+                "signature": { "type": "string", "description": "base64url" },
+            },
+        },
+        "LdapSignInRequest": {
+            "type": "object",
+            "required": ["username", "password"],
+            "properties": {
+                "username": { "type": "string", "description": "Put into userFilter in place of {username}" },
+                "password": { "type": "string" },
+            },
+        },
+        "SignInResponse": {
+            "type": "object",
+            "required": ["session_token", "expires_in_seconds", "idle_timeout_seconds"],
+            "properties": {
+                "session_token": { "type": "string", "description": "Sent as a bearer token in place of the admin token, the admin page uses the cookie that comes with it" },
+                "expires_in_seconds": { "type": "integer" },
+                "idle_timeout_seconds": { "type": "integer", "description": "The session ends sooner when it isn't used for this long" },
+            },
+        },
+        "ErrorResponse": {
+            "type": "object",
+            "required": ["error"],
+            "properties": {
+                "error": { "type": "string" },
+                "field": { "type": "string", "description": "The request field that failed the validation" },
+                "retry_after_seconds": { "type": "integer", "description": "Set for throttled requests, same as Retry-After" },
+                "not_before": { "type": "integer", "description": "Set when the message can't be read yet, the unix time it can be read from" },
+                "request_id": { "type": "string" },
+            },
+        },
+    });
+    if let (Value::Object(schemas), Value::Object(share_schemas)) = (&mut schemas, share_schemas())
+    {
+        schemas.extend(share_schemas);
+    }
+    if let (Value::Object(schemas), Value::Object(portal_schemas)) =
+        (&mut schemas, portal_schemas())
+    {
+        schemas.extend(portal_schemas);
+    }
+    if let (Value::Object(schemas), Value::Object(invitation_schemas)) =
+        (&mut schemas, invitation_schemas())
+    {
+        schemas.extend(invitation_schemas);
+    }
+    schemas
+}
+
+// apart from the others, a single json! with all of them goes past the recursion limit
+fn share_schemas() -> Value {
+    json!({
+        "SplitSecretRequest": {
+            "type": "object",
+            "required": ["user_token", "message_data", "threshold", "shares"],
+            "properties": {
+                "user_token": { "type": "string" },
+                "message_data": { "type": "string", "format": "byte" },
+                "retention": { "type": "integer", "minimum": 1, "maximum": MAX_RETENTION_MINUTES },
+                "passphrase": { "type": "string", "description": "Asked for when any of the shares is read" },
+                "threshold": { "type": "integer", "minimum": 2, "description": "How many shares give the secret back, fewer tell nothing about it" },
+                "shares": { "type": "integer", "minimum": 2, "maximum": 50, "description": "How many one-time links the secret is split into" },
+            },
+        },
+        "SplitSecretResponse": {
+            "type": "object",
+            "required": ["shares", "threshold", "expire_timestamp", "sha256", "delete_token"],
+            "properties": {
+                "shares": { "type": "array", "items": schema_ref("RecipientLink"), "description": "A one-time link for every share, each reads as text to paste on the combine page" },
+                "threshold": { "type": "integer" },
+                "expire_timestamp": { "type": "integer", "description": "Unix time, 0 if the shares don't expire" },
+                "sha256": { "type": "string", "description": "Hex SHA-256 of the whole secret" },
+                "delete_token": { "type": "string", "description": "Removes any of the shares that weren't read, the same for all of them" },
+            },
+        },
+        "CombineSharesRequest": {
+            "type": "object",
+            "required": ["shares"],
+            "properties": {
+                "shares": { "type": "array", "items": { "type": "string" }, "description": "The shares as they were read from their links" },
+            },
+        },
+        "CombineSharesResponse": {
+            "type": "object",
+            "required": ["message_data"],
+            "properties": {
+                "message_data": { "type": "string", "format": "byte" },
+            },
+        },
+    })
+}
+
+// messages stored before the checksums were kept are answered without it
+fn expires_in_header() -> Value {
+    json!({
+        "description": "Seconds left until the message expires, missing when it doesn't",
+        "schema": { "type": "integer" },
+    })
+}
+
+fn message_headers() -> Value {
+    json!({
+        "Message-Sha256": {
+            "description": "Hex SHA-256 of the message data as it was created",
+            "schema": { "type": "string" },
+        },
+        "Message-Expires-In": expires_in_header(),
+    })
+}
+
+fn portal_schemas() -> Value {
+    json!({
+        "ApiKey": {
+            "type": "object",
+            "required": ["id", "name", "created_timestamp"],
+            "properties": {
+                "id": { "type": "integer" },
+                "name": { "type": "string" },
+                "created_timestamp": { "type": "integer" },
+                "plan": { "type": "string", "nullable": true },
+            },
+        },
+        "ApiKeysResponse": {
+            "type": "object",
+            "required": ["keys"],
+            "properties": {
+                "keys": { "type": "array", "items": schema_ref("ApiKey") },
+            },
+        },
+        "CreateApiKeyRequest": {
+            "type": "object",
+            "required": ["name"],
+            "properties": {
+                "name": { "type": "string", "maxLength": 64 },
+            },
+        },
+        "CreatedApiKey": {
+            "allOf": [
+                schema_ref("ApiKey"),
+                {
+                    "type": "object",
+                    "required": ["user_token"],
+                    "properties": {
+                        "user_token": { "type": "string", "description": "Used as the user token of new messages, it can't be shown again" },
+                    },
+                },
+            ],
+        },
+        "PortalAccount": {
+            "type": "object",
+            "required": ["limits"],
+            "properties": {
+                "email": { "type": "string", "nullable": true },
+                "plan": { "type": "string", "nullable": true, "description": "The plan the groups of the account give, the default limits apply without one" },
+                "limits": { "type": "object", "description": "The limits of every key of the account" },
+                "new_api_key": { "type": "string", "nullable": true, "description": "The user token made on the first sign-in, only in the first answer after it" },
+            },
+        },
+        "RegistrationRequest": {
+            "type": "object",
+            "required": ["email"],
+            "properties": {
+                "email": { "type": "string", "format": "email" },
+            },
+        },
+        "ConfirmRegistrationRequest": {
+            "type": "object",
+            "required": ["token"],
+            "properties": {
+                "token": { "type": "string", "description": "The token after #confirm= in the emailed link" },
+            },
+        },
+        "RegisteredUser": {
+            "type": "object",
+            "required": ["email", "user_token"],
+            "properties": {
+                "email": { "type": "string" },
+                "plan": { "type": "string", "nullable": true, "description": "registration.plan, the default limits apply without one" },
+                "user_token": { "type": "string", "description": "Used as the user token of new messages, it can't be shown again" },
+            },
+        },
+    })
+}
+
+fn portal_paths() -> Value {
+    json!({
+        "/api/v1/portal/account": {
+            "get": {
+                "operationId": "getPortalAccount",
+                "summary": "Show the account that signed in to the portal",
+                "security": [{ "portalSession": [] }],
+                "responses": {
+                    "200": json_response("The account and its limits", "PortalAccount"),
+                    "401": error_response("The session has ended"),
+                    "404": error_response("OpenID Connect is not configured"),
+                },
+            },
+        },
+        "/api/v1/portal/keys": {
+            "get": {
+                "operationId": "listApiKeys",
+                "summary": "Show the keys of the account",
+                "security": [{ "portalSession": [] }],
+                "responses": {
+                    "200": json_response("The keys, the oldest first", "ApiKeysResponse"),
+                    "401": error_response("The session has ended"),
+                    "404": error_response("OpenID Connect is not configured"),
+                },
+            },
+            "post": {
+                "operationId": "createApiKey",
+                "summary": "Create a key with the limits of the plan of the account",
+                "security": [{ "portalSession": [] }],
+                "requestBody": { "required": true, "content": json_content(schema_ref("CreateApiKeyRequest")) },
+                "responses": {
+                    "201": json_response("The key and its user token", "CreatedApiKey"),
+                    "400": error_response("The name is not valid"),
+                    "401": error_response("The session has ended"),
+                    "404": error_response("OpenID Connect is not configured"),
+                    "409": error_response("The account has as many keys as it can have"),
+                },
+            },
+        },
+        "/api/v1/portal/keys/{id}": {
+            "delete": {
+                "operationId": "removeApiKey",
+                "summary": "Remove a key, its user token stops working",
+                "security": [{ "portalSession": [] }],
+                "parameters": [{ "name": "id", "in": "path", "required": true, "schema": { "type": "integer" } }],
+                "responses": {
+                    "204": { "description": "The key is removed" },
+                    "401": error_response("The session has ended"),
+                    "404": error_response("The account has no such key"),
+                },
+            },
+        },
+        "/api/v1/portal/session": {
+            "delete": {
+                "operationId": "signOutOfPortal",
+                "summary": "End the portal session of the bearer token",
+                "security": [{ "portalSession": [] }],
+                "responses": {
+                    "204": { "description": "The session is ended" },
+                    "401": error_response("There is no such session"),
+                    "404": error_response("OpenID Connect is not configured"),
+                },
+            },
+        },
+        "/api/v1/registrations": {
+            "post": {
+                "operationId": "startRegistration",
+                "summary": "Email a one-time confirmation link to the address",
+                "requestBody": { "required": true, "content": json_content(schema_ref("RegistrationRequest")) },
+                "responses": {
+                    "202": { "description": "The link is on its way, whether the address has a user token or not" },
+                    "400": error_response("The address is not valid"),
+                    "403": error_response("Addresses of this domain can't register"),
+                    "404": error_response("Registration is not enabled"),
+                    "429": error_response("Too many requests, see Retry-After"),
+                },
+            },
+        },
+        "/api/v1/registrations/confirm": {
+            "post": {
+                "operationId": "confirmRegistration",
+                "summary": "Use the confirmation link and get a user token",
+                "requestBody": { "required": true, "content": json_content(schema_ref("ConfirmRegistrationRequest")) },
+                "responses": {
+                    "201": json_response("The user token of the address", "RegisteredUser"),
+                    "404": error_response("The link has expired, was used already or registration is not enabled"),
+                    "409": error_response("The address has a user token already"),
+                    "429": error_response("Too many requests, see Retry-After"),
+                },
+            },
+        },
+    })
+}
+
+fn invitation_schemas() -> Value {
+    json!({
+        "Invitation": {
+            "type": "object",
+            "required": ["id", "created_timestamp", "expire_timestamp"],
+            "properties": {
+                "id": { "type": "integer" },
+                "plan": { "type": "string", "nullable": true },
+                "note": { "type": "string", "nullable": true },
+                "created_timestamp": { "type": "integer" },
+                "expire_timestamp": { "type": "integer" },
+            },
+        },
+        "InvitationsResponse": {
+            "type": "object",
+            "required": ["invitations"],
+            "properties": {
+                "invitations": { "type": "array", "items": schema_ref("Invitation") },
+            },
+        },
+        "CreateInvitationRequest": {
+            "type": "object",
+            "properties": {
+                "plan": { "type": "string", "description": "One of the plans of the config, the default limits apply without one" },
+                "note": { "type": "string", "maxLength": 200, "description": "Whom the invitation is for, only the admins see it" },
+                "expires_in_minutes": { "type": "integer", "minimum": 1, "maximum": 43200, "default": 10080 },
+            },
+        },
+        "CreatedInvitation": {
+            "allOf": [
+                schema_ref("Invitation"),
+                {
+                    "type": "object",
+                    "required": ["url"],
+                    "properties": {
+                        "url": { "type": "string", "description": "Works once, it can't be shown again" },
+                    },
+                },
+            ],
+        },
+        "RedeemInvitationRequest": {
+            "type": "object",
+            "required": ["token"],
+            "properties": {
+                "token": { "type": "string", "description": "The token after #token= in the invitation URL" },
+            },
+        },
+        "RedeemedInvitation": {
+            "type": "object",
+            "required": ["limits", "user_token"],
+            "properties": {
+                "plan": { "type": "string", "nullable": true },
+                "limits": { "type": "object", "description": "The limits of the new user token" },
+                "user_token": { "type": "string", "description": "Used as the user token of new messages, it can't be shown again" },
+            },
+        },
+    })
+}
+
+fn invitation_paths() -> Value {
+    json!({
+        "/api/v1/admin/invitations": {
+            "get": {
+                "operationId": "listInvitations",
+                "summary": "Show the invitations that weren't used and haven't expired",
+                "security": [{ "adminToken": [] }],
+                "responses": {
+                    "200": json_response("The invitations, the oldest first", "InvitationsResponse"),
+                    "401": error_response("The admin token is wrong"),
+                },
+            },
+            "post": {
+                "operationId": "createInvitation",
+                "summary": "Make a single-use URL that gives a new user token on the plan",
+                "security": [{ "adminToken": [] }],
+                "requestBody": { "required": true, "content": json_content(schema_ref("CreateInvitationRequest")) },
+                "responses": {
+                    "201": json_response("The invitation and its URL", "CreatedInvitation"),
+                    "400": error_response("The plan, the note or the expiration is not valid"),
+                    "401": error_response("The admin token is wrong"),
+                },
+            },
+        },
+        "/api/v1/admin/invitations/{id}": {
+            "delete": {
+                "operationId": "removeInvitation",
+                "summary": "Remove an invitation, its URL stops working",
+                "security": [{ "adminToken": [] }],
+                "parameters": [{ "name": "id", "in": "path", "required": true, "schema": { "type": "integer" } }],
+                "responses": {
+                    "204": { "description": "The invitation is removed" },
+                    "401": error_response("The admin token is wrong"),
+                    "404": error_response("There is no such invitation"),
+                },
+            },
+        },
+        "/api/v1/invitations/redeem": {
+            "post": {
+                "operationId": "redeemInvitation",
+                "summary": "Use an invitation and get a new user token",
+                "requestBody": { "required": true, "content": json_content(schema_ref("RedeemInvitationRequest")) },
+                "responses": {
+                    "201": json_response("The new user token and its limits", "RedeemedInvitation"),
+                    "404": error_response("The invitation has expired, was used already or was removed"),
+                    "429": error_response("Too many requests, see Retry-After"),
+                },
+            },
+        },
+    })
+}
+
+fn paths() -> Value {
+    let throttled = error_response("Too many requests, see Retry-After");
+    let mut consumed_content = response_content(schema_ref("ConsumeMessageResponse"));
+    consumed_content["application/octet-stream"] =
+        json!({ "schema": { "type": "string", "format": "binary" } });
+    json!({
+        "/api/v1/messages": {
+            "post": {
+                "operationId": "createMessage",
+                "summary": "Create a message that can be read once",
+                "requestBody": { "required": true, "content": json_content(schema_ref("CreateMessageRequest")) },
+                "responses": {
+                    "200": json_response("The message was created", "CreateMessageResponse"),
+                    "400": error_response("The request is not valid or a limit of the user is reached"),
+                    "404": error_response("The user token is unknown"),
+                    "409": error_response("The slug is already taken"),
+                    "413": error_response("The message is too big"),
+                    "429": throttled,
+                },
+            },
+        },
+        "/api/v1/messages/{token}/consume": {
+            "post": {
+                "operationId": "consumeMessage",
+                "summary": "Read the message and remove it",
+                "parameters": [token_parameter()],
+                "requestBody": { "required": false, "content": json_content(schema_ref("ConsumeMessageRequest")) },
+                "responses": {
+                    "200": {
+                        "description": "The message, it can't be read again. With `Accept: application/octet-stream` it comes as a download with its content type and file name",
+                        "headers": message_headers(),
+                        "content": consumed_content,
+                    },
+                    "401": error_response("The passphrase, the PIN, an authorization token of a dual control message or the TOTP code is missing or wrong"),
+                    "403": error_response("The message can't be read from the address of the request"),
+                    "404": error_response("The message doesn't exist or was already read"),
+                    "410": error_response("The message has expired or was destroyed, e.g. after too many wrong PINs"),
+                    "425": error_response("The message can't be read yet, `not_before` and Retry-After tell when it can"),
+                    "429": throttled,
+                },
+            },
+        },
+        "/api/v1/messages/{token}": {
+            "delete": {
+                "operationId": "deleteMessage",
+                "summary": "Remove the message before it's read, with the delete token given at creation",
+                "parameters": [token_parameter()],
+                "security": [{ "deleteToken": [] }],
+                "responses": {
+                    "204": { "description": "The message was removed" },
+                    "404": error_response("The message doesn't exist, was already read or the delete token is wrong"),
+                    "429": throttled,
+                },
+            },
+        },
+        "/api/v1/messages/{token}/status": {
+            "get": {
+                "operationId": "getMessageStatus",
+                "summary": "Tell the creator whether the message was read, with the delete token given at creation",
+                "parameters": [token_parameter()],
+                "security": [{ "deleteToken": [] }],
+                "responses": {
+                    "200": json_response("What became of the message", "MessageStatusResponse"),
+                    "404": error_response("The message doesn't exist, is gone for more than a week or the delete token is wrong"),
+                    "429": throttled,
+                },
+            },
+        },
+        "/api/v1/messages/{token}/meta": {
+            "get": {
+                "operationId": "getMessageMeta",
+                "summary": "Tell about the message without reading it",
+                "parameters": [token_parameter()],
+                "responses": {
+                    "200": {
+                        "description": "What is known about the message",
+                        "headers": { "Message-Expires-In": expires_in_header() },
+                        "content": response_content(schema_ref("MessageMetaResponse")),
+                    },
+                    "404": error_response("The message doesn't exist or was already read"),
+                    "410": error_response("The message has expired"),
+                    "429": throttled,
+                },
+            },
+        },
+        "/api/v1/messages/{token}/qr": {
+            "get": {
+                "operationId": "getMessageQrCode",
+                "summary": "Draw the link as a QR code, without reading the message",
+                "parameters": [
+                    token_parameter(),
+                    {
+                        "name": "format",
+                        "in": "query",
+                        "required": false,
+                        "description": "The picture format, otherwise taken from the Accept header",
+                        "schema": { "type": "string", "enum": ["svg", "png"], "default": "svg" },
+                    },
+                ],
+                "responses": {
+                    "200": {
+                        "description": "The QR code",
+                        "content": {
+                            "image/svg+xml": { "schema": { "type": "string" } },
+                            "image/png": { "schema": { "type": "string", "format": "binary" } },
+                        },
+                    },
+                    "400": error_response("The format is unknown or the message is end-to-end encrypted"),
+                    "404": error_response("The message doesn't exist or was already read"),
+                    "410": error_response("The message has expired"),
+                    "429": throttled,
+                },
+            },
+        },
+        "/api/v1/files": {
+            "post": {
+                "operationId": "uploadFile",
+                "summary": "Share a file that can be downloaded once",
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "multipart/form-data": {
+                            "schema": {
+                                "type": "object",
+                                "required": ["user_token", "file"],
+                                "properties": {
+                                    "user_token": { "type": "string" },
+                                    "retention": { "type": "integer" },
+                                    "passphrase": { "type": "string" },
+                                    "file": { "type": "string", "format": "binary" },
+                                },
+                            },
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("The file was stored", "CreateMessageResponse"),
+                    "400": error_response("The request is not valid or a limit of the user is reached"),
+                    "404": error_response("The user token is unknown"),
+                    "413": error_response("The file is too big"),
+                    "429": throttled,
+                },
+            },
+        },
+        "/api/v1/files/{token}/consume": {
+            "post": {
+                "operationId": "consumeFile",
+                "summary": "Download the file and remove it",
+                "parameters": [token_parameter()],
+                "requestBody": { "required": false, "content": json_content(schema_ref("ConsumeMessageRequest")) },
+                "responses": {
+                    "200": {
+                        "description": "The file with its content type, it can't be downloaded again",
+                        "headers": message_headers(),
+                        "content": { "application/octet-stream": { "schema": { "type": "string", "format": "binary" } } },
+                    },
+                    "401": error_response("The passphrase, the PIN, an authorization token of a dual control message or the TOTP code is missing or wrong"),
+                    "403": error_response("The message can't be read from the address of the request"),
+                    "404": error_response("The file doesn't exist or was already downloaded"),
+                    "410": error_response("The file has expired or was destroyed"),
+                    "425": error_response("The file can't be downloaded yet, `not_before` and Retry-After tell when it can"),
+                    "429": throttled,
+                },
+            },
+        },
+        "/api/v1/quota": {
+            "post": {
+                "operationId": "getQuota",
+                "summary": "Show the bytes the user stored this month",
+                "requestBody": { "required": true, "content": json_content(schema_ref("QuotaRequest")) },
+                "responses": {
+                    "200": json_response("The usage of the current month", "QuotaResponse"),
+                    "404": error_response("The user token is unknown"),
+                },
+            },
+        },
+        "/api/v1/requests": {
+            "post": {
+                "operationId": "createSecretRequest",
+                "summary": "Ask someone for a secret that only the requester can read",
+                "requestBody": { "required": true, "content": json_content(schema_ref("CreateSecretRequestRequest")) },
+                "responses": {
+                    "200": json_response("The request was created", "CreateSecretRequestResponse"),
+                    "400": error_response("The request is not valid or a limit of the user is reached"),
+                    "404": error_response("The user token is unknown"),
+                    "429": throttled,
+                },
+            },
+        },
+        "/api/v1/requests/{token}": {
+            "get": {
+                "operationId": "getSecretRequest",
+                "summary": "Tell about an open request",
+                "parameters": [request_token_parameter()],
+                "responses": {
+                    "200": json_response("The open request", "SecretRequestMeta"),
+                    "404": error_response("The request doesn't exist, was answered or expired"),
+                    "429": throttled,
+                },
+            },
+            "post": {
+                "operationId": "answerSecretRequest",
+                "summary": "Send the secret, a request is answered once",
+                "parameters": [request_token_parameter()],
+                "requestBody": { "required": true, "content": json_content(schema_ref("SecretAnswer")) },
+                "responses": {
+                    "200": json_response("The secret was stored for the requester", "SecretAnswerResponse"),
+                    "400": error_response("The request body is not valid"),
+                    "404": error_response("The request doesn't exist, was answered or expired"),
+                    "413": error_response("The secret is bigger than the requester allows"),
+                    "429": throttled,
+                },
+            },
+        },
+        "/api/v1/channels": {
+            "post": {
+                "operationId": "createChannel",
+                "summary": "Deposit a secret that can be read once and answered with one reply",
+                "requestBody": { "required": true, "content": json_content(schema_ref("CreateChannelRequest")) },
+                "responses": {
+                    "200": json_response("The channel was created", "CreateChannelResponse"),
+                    "400": error_response("The request is not valid or a limit of the user is reached"),
+                    "404": error_response("The user token is unknown"),
+                    "413": error_response("The secret is too big"),
+                    "429": throttled,
+                },
+            },
+        },
+        "/api/v1/channels/{token}/consume": {
+            "post": {
+                "operationId": "consumeChannel",
+                "summary": "Read the secret of the channel, after which the reply can be sent",
+                "parameters": [channel_token_parameter()],
+                "requestBody": { "required": false, "content": json_content(schema_ref("ConsumeMessageRequest")) },
+                "responses": {
+                    "200": json_response("The secret, it can't be read again", "ConsumeMessageResponse"),
+                    "401": error_response("The passphrase is missing or wrong"),
+                    "404": error_response("The channel doesn't exist, expired or its secret was already read"),
+                    "410": error_response("The secret has expired or was destroyed"),
+                    "429": throttled,
+                },
+            },
+        },
+        "/api/v1/channels/{token}/reply": {
+            "post": {
+                "operationId": "replyToChannel",
+                "summary": "Send the one reply, which closes the channel",
+                "parameters": [channel_token_parameter()],
+                "requestBody": { "required": true, "content": json_content(schema_ref("SecretAnswer")) },
+                "responses": {
+                    "200": json_response("The reply was stored for the creator", "SecretAnswerResponse"),
+                    "400": error_response("The request body is not valid"),
+                    "404": error_response("The channel doesn't exist, expired or was already replied to"),
+                    "409": error_response("The secret of the channel wasn't read yet"),
+                    "413": error_response("The reply is bigger than the creator allows"),
+                    "429": throttled,
+                },
+            },
+        },
+        "/api/v1/shares": {
+            "post": {
+                "operationId": "splitSecret",
+                "summary": "Split a secret into k-of-n shares, each of them a one-time link",
+                "requestBody": { "required": true, "content": json_content(schema_ref("SplitSecretRequest")) },
+                "responses": {
+                    "200": json_response("The secret was split and the shares were stored", "SplitSecretResponse"),
+                    "400": error_response("The request is not valid or a limit of the user is reached"),
+                    "404": error_response("The user token is unknown"),
+                    "413": error_response("The secret is too big"),
+                    "429": throttled,
+                },
+            },
+        },
+        "/api/v1/shares/combine": {
+            "post": {
+                "operationId": "combineShares",
+                "summary": "Put a secret back together from its shares, nothing is stored",
+                "requestBody": { "required": true, "content": json_content(schema_ref("CombineSharesRequest")) },
+                "responses": {
+                    "200": json_response("The secret", "CombineSharesResponse"),
+                    "400": error_response("The shares are not valid, of different secrets or too few"),
+                },
+            },
+        },
+        "/api/v1/integrations/teams": {
+            "post": {
+                "operationId": "createTeamsLink",
+                "summary": "Create a link from a Teams outgoing webhook",
+                "security": [{ "teamsSignature": [] }],
+                "requestBody": { "required": true, "content": json_content(schema_ref("TeamsActivity")) },
+                "responses": {
+                    "200": json_response("The answer shown in the channel", "TeamsReply"),
+                    "401": error_response("The signature is wrong"),
+                    "404": error_response("The Teams integration is not enabled"),
+                },
+            },
+        },
+        "/api/v1/admin/defaults": {
+            "get": {
+                "operationId": "getDefaultLimits",
+                "summary": "Show the limits of users without their own",
+                "security": [{ "adminToken": [] }],
+                "responses": {
+                    "200": json_response("The default limits", "DefaultLimits"),
+                    "401": error_response("The admin token is wrong"),
+                },
+            },
+            "put": {
+                "operationId": "updateDefaultLimits",
+                "summary": "Change the given default limits",
+                "security": [{ "adminToken": [] }],
+                "requestBody": { "required": true, "content": json_content(schema_ref("DefaultLimits")) },
+                "responses": {
+                    "200": json_response("The default limits after the change", "DefaultLimits"),
+                    "400": error_response("The request body is not valid"),
+                    "401": error_response("The admin token is wrong"),
+                },
+            },
+            "delete": {
+                "operationId": "resetDefaultLimits",
+                "summary": "Go back to the default limits from the config",
+                "security": [{ "adminToken": [] }],
+                "responses": {
+                    "200": json_response("The default limits from the config", "DefaultLimits"),
+                    "401": error_response("The admin token is wrong"),
+                },
+            },
+        },
+        "/api/v1/admin/reencrypt": {
+            "post": {
+                "operationId": "reencryptMessages",
+                "summary": "Encrypt the stored messages with the newest encryption key",
+                "security": [{ "adminToken": [] }],
+                "responses": {
+                    "200": json_response("The number of messages that were moved to the newest key", "ReencryptResponse"),
+                    "401": error_response("The admin token is wrong"),
+                },
+            },
+        },
+        "/api/v1/admin/webhooks": {
+            "get": {
+                "operationId": "listWebhookDeliveries",
+                "summary": "Show the newest webhook deliveries, of the last week",
+                "security": [{ "adminToken": [] }],
+                "parameters": [
+                    { "name": "state", "in": "query", "schema": { "type": "string", "enum": ["pending", "delivered", "failed"] } },
+                    { "name": "limit", "in": "query", "schema": { "type": "integer", "default": 100, "maximum": 1000 } },
+                ],
+                "responses": {
+                    "200": json_response("The deliveries, the newest first", "WebhookDeliveriesResponse"),
+                    "400": error_response("The state or the limit is invalid"),
+                    "401": error_response("The admin token is wrong"),
+                },
+            },
+        },
+        "/api/v1/admin/webhooks/{id}": {
+            "get": {
+                "operationId": "getWebhookDelivery",
+                "summary": "Show a webhook delivery",
+                "security": [{ "adminToken": [] }],
+                "parameters": [delivery_id_parameter()],
+                "responses": {
+                    "200": json_response("The delivery", "WebhookDelivery"),
+                    "401": error_response("The admin token is wrong"),
+                    "404": error_response("There is no such delivery"),
+                },
+            },
+        },
+        "/api/v1/admin/webhooks/{id}/retry": {
+            "post": {
+                "operationId": "retryWebhookDelivery",
+                "summary": "Give a failed webhook delivery another set of attempts",
+                "security": [{ "adminToken": [] }],
+                "parameters": [delivery_id_parameter()],
+                "responses": {
+                    "200": json_response("The delivery, pending again", "WebhookDelivery"),
+                    "401": error_response("The admin token is wrong"),
+                    "404": error_response("There is no such delivery"),
+                    "409": error_response("The delivery didn't fail"),
+                },
+            },
+        },
+        "/api/v1/admin/passkeys": {
+            "get": {
+                "operationId": "listAdminPasskeys",
+                "summary": "Show the passkeys that can sign in to the admin API",
+                "security": [{ "adminToken": [] }],
+                "responses": {
+                    "200": json_response("The passkeys, the oldest first", "PasskeysResponse"),
+                    "401": error_response("The admin token is wrong"),
+                    "404": error_response("Passkeys are not enabled"),
+                },
+            },
+            "post": {
+                "operationId": "registerAdminPasskey",
+                "summary": "Register a passkey with the answer to the creation options",
+                "security": [{ "adminToken": [] }],
+                "requestBody": { "required": true, "content": json_content(schema_ref("RegisterPasskeyRequest")) },
+                "responses": {
+                    "201": json_response("The registered passkey", "AdminPasskey"),
+                    "400": error_response("The answer of the authenticator is not valid"),
+                    "401": error_response("The admin token is wrong"),
+                    "404": error_response("Passkeys are not enabled"),
+                    "409": error_response("The passkey is registered already"),
+                },
+            },
+        },
+        "/api/v1/admin/passkeys/options": {
+            "post": {
+                "operationId": "getPasskeyCreationOptions",
+                "summary": "Start registering a passkey, the first one needs the admin token",
+                "security": [{ "adminToken": [] }],
+                "responses": {
+                    "200": json_response("The options for the browser", "PasskeyCreationOptions"),
+                    "401": error_response("The admin token is wrong"),
+                    "404": error_response("Passkeys are not enabled"),
+                },
+            },
+        },
+        "/api/v1/admin/passkeys/{id}": {
+            "delete": {
+                "operationId": "removeAdminPasskey",
+                "summary": "Remove a passkey",
+                "security": [{ "adminToken": [] }],
+                "parameters": [{ "name": "id", "in": "path", "required": true, "schema": { "type": "integer" } }],
+                "responses": {
+                    "204": { "description": "The passkey is removed" },
+                    "401": error_response("The admin token is wrong"),
+                    "404": error_response("There is no such passkey"),
+                },
+            },
+        },
+        "/api/v1/admin/sessions/options": {
+            "post": {
+                "operationId": "getPasskeyRequestOptions",
+                "summary": "Start signing in with a passkey",
+                "responses": {
+                    "200": json_response("The options for the browser", "PasskeyRequestOptions"),
+                    "404": error_response("Passkeys are not enabled"),
+                },
+            },
+        },
+        "/api/v1/admin/sessions/ldap": {
+            "post": {
+                "operationId": "signInWithLdap",
+                "summary": "Sign in with a directory account that matches the group filter",
+                "requestBody": { "required": true, "content": json_content(schema_ref("LdapSignInRequest")) },
+                "responses": {
+                    "200": json_response("A session token that lasts an hour", "SignInResponse"),
+                    "400": error_response("The request body is not valid"),
+                    "401": error_response("The account is unknown, not an admin or the password is wrong"),
+                    "404": error_response("LDAP is not configured"),
+                    "429": error_response("Too many requests, see Retry-After"),
+                    "502": error_response("The directory can't be reached"),
+                },
+            },
+        },
+        "/api/v1/admin/sessions/basic": {
+            "post": {
+                "operationId": "signInWithBasicAuth",
+                "summary": "Trade basic auth credentials for a session",
+                "security": [{ "adminBasicAuth": [] }],
+                "responses": {
+                    "200": json_response("A session token that lasts an hour", "SignInResponse"),
+                    "401": error_response("The user name or password is wrong, WWW-Authenticate asks for them"),
+                    "403": error_response("A client certificate is required and there is none"),
+                    "404": error_response("Basic auth is not configured"),
+                    "429": error_response("Too many requests, see Retry-After"),
+                },
+            },
+        },
+        "/api/v1/admin/sessions": {
+            "post": {
+                "operationId": "signInWithPasskey",
+                "summary": "Sign in with the answer to the request options",
+                "requestBody": { "required": true, "content": json_content(schema_ref("SignInRequest")) },
+                "responses": {
+                    "200": json_response("A session token that lasts an hour", "SignInResponse"),
+                    "400": error_response("The request body is not valid"),
+                    "401": error_response("The passkey or its signature is not accepted"),
+                    "404": error_response("Passkeys are not enabled"),
+                },
+            },
+            "delete": {
+                "operationId": "signOut",
+                "summary": "End the session of the bearer token or the cookie",
+                "security": [{ "adminToken": [] }, { "adminSession": [] }],
+                "responses": {
+                    "204": { "description": "The session is ended and the cookie removed" },
+                    "401": error_response("There is no such session"),
+                    "404": error_response("No sign-in is enabled"),
+                },
+            },
+        },
+        "/api/v1/admin/sessions/all": {
+            "delete": {
+                "operationId": "signOutEverywhere",
+                "summary": "End every admin session",
+                "security": [{ "adminToken": [] }, { "adminSession": [] }],
+                "responses": {
+                    "204": { "description": "The sessions are ended, the admin token still works" },
+                    "401": error_response("The admin token is wrong"),
+                    "404": error_response("No sign-in is enabled"),
+                },
+            },
+        },
+    })
+}
+
+// written by hand next to the handlers, the tests check it against the routes
+pub fn document(config: &Config) -> Value {
+    let mut document = json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "One Time Share",
+            "description": "Share messages and files that can be read only once",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths(),
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {
+                "adminBasicAuth": { "type": "http", "scheme": "basic", "description": "A user of adminAuth.basicAuthUsers, also taken wherever the admin token is" },
+                "adminToken": { "type": "http", "scheme": "bearer", "description": "The admin token or a session token from one of the sign-ins" },
+                "adminSession": { "type": "apiKey", "in": "cookie", "name": "admin_session", "description": "The signed HttpOnly cookie of a sign-in, taken wherever the admin token is" },
+                "deleteToken": { "type": "http", "scheme": "bearer", "description": "The delete token of the message" },
+                "portalSession": { "type": "http", "scheme": "bearer", "description": "The session token the portal gets after an OpenID Connect sign-in" },
+                "teamsSignature": { "type": "apiKey", "in": "header", "name": "Authorization", "description": "\"HMAC \" and the base64 HMAC-SHA256 of the body, keyed with the security token of the outgoing webhook" },
+            },
+        },
+    });
+    if let (Value::Object(paths), Value::Object(portal_paths)) =
+        (&mut document["paths"], portal_paths())
+    {
+        paths.extend(portal_paths);
+    }
+    if let (Value::Object(paths), Value::Object(invitation_paths)) =
+        (&mut document["paths"], invitation_paths())
+    {
+        paths.extend(invitation_paths);
+    }
+    if let Some(public_base_url) = &config.public_base_url {
+        document["servers"] = json!([{ "url": public_base_url.trim_end_matches('/') }]);
+    }
+    document
+}
+
+pub async fn openapi_document(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&document(&data.config))?)
+        .build())
+}
+
+// renders the operations of the document with forms to try them out
+pub async fn docs_page(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    Ok(csp::html_response(
+        &data.config.content_security_policy,
+        DOCS_HTML,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_test_data;
+    use tide::http::{Method, Url};
+
+    #[async_std::test]
+    async fn test_document_is_served() {
+        let app = crate::init_app(setup_test_data());
+        let req = tide::http::Request::new(
+            Method::Get,
+            Url::parse(&format!("http://localhost{}", OPENAPI_PATH)).unwrap(),
+        );
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let document: Value = res.take_body().into_json().await.unwrap();
+        assert_eq!(document["openapi"], "3.0.3");
+        assert!(document["paths"]["/api/v1/messages"]["post"].is_object());
+    }
+
+    #[async_std::test]
+    async fn test_docs_page_is_served() {
+        let app = crate::init_app(setup_test_data());
+        let req = tide::http::Request::new(
+            Method::Get,
+            Url::parse(&format!("http://localhost{}", DOCS_PATH)).unwrap(),
+        );
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body = res.take_body().into_string().await.unwrap();
+        assert!(body.contains(OPENAPI_PATH));
+        assert!(!body.contains("{{.CspNonce}}"));
+    }
+
+    // every documented operation has a route, a missing one would answer 404 without a body
+    #[async_std::test]
+    async fn test_documented_paths_are_routed() {
+        let app = crate::init_app(setup_test_data());
+        let document = document(&setup_test_data().lock().unwrap().config);
+        for (path, operations) in document["paths"].as_object().unwrap() {
+            for method in operations.as_object().unwrap().keys() {
+                let url = format!(
+                    "http://localhost{}",
+                    path.replace("{token}", "unknown").replace("{id}", "1")
+                );
+                let req = tide::http::Request::new(
+                    method.to_uppercase().parse::<Method>().unwrap(),
+                    Url::parse(&url).unwrap(),
+                );
+                let mut res: tide::http::Response = app.respond(req).await.unwrap();
+                let body: crate::api::ErrorResponse = match res.take_body().into_json().await {
+                    Ok(body) => body,
+                    Err(_) => continue,
+                };
+                assert_ne!(body.error, "Not Found", "{} {}", method, path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_references_are_defined() {
+        let document = document(&setup_test_data().lock().unwrap().config);
+        let text = document.to_string();
+        for reference in text.split("#/components/schemas/").skip(1) {
+            let name: String = reference
+                .chars()
+                .take_while(|c| c.is_alphanumeric())
+                .collect();
+            assert!(
+                document["components"]["schemas"][&name].is_object(),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,972 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tide::http::cookies::{Cookie, SameSite};
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::admin::{bearer_token, check_admin_token};
+use crate::error::AppError;
+use crate::store::AdminCredential;
+use crate::tokens::{hash_token, random_base64url, TokenSigner};
+use crate::webauthn::{read_client_data, verify_assertion, verify_registration};
+use crate::{csp, webauthn, Config, StaticData};
+
+// the time the browser gets to finish a ceremony
+const CHALLENGE_TTL: Duration = Duration::from_secs(5 * 60);
+pub(crate) const SESSION_TTL: Duration = Duration::from_secs(60 * 60);
+// a session that isn't used for this long ends before its TTL
+pub const DEFAULT_SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+pub(crate) const SESSION_COOKIE: &str = "admin_session";
+// anyone can ask for a sign-in challenge, the oldest ones make room for new ones
+const MAX_PENDING_CHALLENGES: usize = 1000;
+const MAX_PASSKEY_NAME_LENGTH: usize = 64;
+const RANDOM_BYTES: usize = 32;
+const RP_NAME: &str = "One Time Share";
+// there is a single admin account, its passkeys only need an id to be stored under
+const ADMIN_USER_NAME: &str = "admin";
+const PASSKEYS_ENABLED_PLACEHOLDER: &str = "{{.PasskeysEnabled}}";
+const LDAP_ENABLED_PLACEHOLDER: &str = "{{.LdapEnabled}}";
+const BASIC_AUTH_ENABLED_PLACEHOLDER: &str = "{{.BasicAuthEnabled}}";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Ceremony {
+    Registration,
+    SignIn,
+}
+
+impl Ceremony {
+    // the type in the client data
+    fn client_data_type(self) -> &'static str {
+        match self {
+            Ceremony::Registration => "webauthn.create",
+            Ceremony::SignIn => "webauthn.get",
+        }
+    }
+}
+
+struct Session {
+    expire: Instant,
+    last_used: Instant,
+}
+
+// challenges and sessions are kept in memory, a restart signs everybody out
+pub struct AdminSessions {
+    challenges: HashMap<String, (Ceremony, Instant)>,
+    // by the hash of the session token
+    sessions: HashMap<String, Session>,
+    idle_timeout: Duration,
+    // the cookies are only good for this process, like the sessions
+    cookie_signer: TokenSigner,
+}
+
+impl Default for AdminSessions {
+    fn default() -> Self {
+        AdminSessions::new(DEFAULT_SESSION_IDLE_TIMEOUT)
+    }
+}
+
+impl AdminSessions {
+    pub fn new(idle_timeout: Duration) -> Self {
+        AdminSessions {
+            challenges: HashMap::new(),
+            sessions: HashMap::new(),
+            idle_timeout,
+            cookie_signer: TokenSigner::random(),
+        }
+    }
+
+    fn issue_challenge(&mut self, ceremony: Ceremony, now: Instant) -> String {
+        self.challenges.retain(|_, (_, expire)| *expire > now);
+        if self.challenges.len() >= MAX_PENDING_CHALLENGES {
+            let oldest = self
+                .challenges
+                .iter()
+                .min_by_key(|(_, (_, expire))| *expire)
+                .map(|(challenge, _)| challenge.clone());
+            if let Some(oldest) = oldest {
+                self.challenges.remove(&oldest);
+            }
+        }
+        let challenge = random_base64url(RANDOM_BYTES);
+        self.challenges
+            .insert(challenge.clone(), (ceremony, now + CHALLENGE_TTL));
+        challenge
+    }
+
+    // a challenge is good for a single answer
+    fn take_challenge(&mut self, challenge: &str, ceremony: Ceremony, now: Instant) -> bool {
+        matches!(
+            self.challenges.remove(challenge),
+            Some((issued_for, expire)) if issued_for == ceremony && expire > now
+        )
+    }
+
+    fn is_live(session: &Session, idle_timeout: Duration, now: Instant) -> bool {
+        session.expire > now && now.saturating_duration_since(session.last_used) < idle_timeout
+    }
+
+    pub(crate) fn start_session(&mut self, now: Instant) -> String {
+        let idle_timeout = self.idle_timeout;
+        self.sessions
+            .retain(|_, session| Self::is_live(session, idle_timeout, now));
+        let session_token = random_base64url(RANDOM_BYTES);
+        self.sessions.insert(
+            hash_token(&session_token),
+            Session {
+                expire: now + SESSION_TTL,
+                last_used: now,
+            },
+        );
+        session_token
+    }
+
+    // a valid session counts as used, which keeps it from going idle
+    pub fn touch_session(&mut self, session_token: &str, now: Instant) -> bool {
+        let idle_timeout = self.idle_timeout;
+        match self.sessions.get_mut(&hash_token(session_token)) {
+            Some(session) if Self::is_live(session, idle_timeout, now) => {
+                session.last_used = now;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn end_session(&mut self, session_token: &str) -> bool {
+        self.sessions.remove(&hash_token(session_token)).is_some()
+    }
+
+    // e.g. after a lost laptop, everybody signs in again
+    fn end_all_sessions(&mut self) {
+        self.sessions.clear();
+    }
+
+    // the page gets the session as a cookie that scripts can't read and other sites can't send
+    fn session_cookie(&self, session_token: &str, secure: bool) -> Cookie<'static> {
+        Cookie::build(SESSION_COOKIE, self.cookie_signer.sign(session_token))
+            .path("/")
+            .http_only(true)
+            .secure(secure)
+            .same_site(SameSite::Strict)
+            .finish()
+    }
+
+    // a forged cookie is refused before the sessions are looked at
+    pub(crate) fn cookie_session_token<'a>(&self, cookie_value: &'a str) -> Option<&'a str> {
+        self.cookie_signer.unsign(cookie_value)
+    }
+}
+
+// the session token from the bearer or, for the admin page, from the cookie
+pub(crate) fn session_token(
+    req: &Request<Arc<Mutex<StaticData>>>,
+    sessions: &AdminSessions,
+) -> Option<String> {
+    if let Some(bearer_token) = bearer_token(req) {
+        return Some(bearer_token.to_string());
+    }
+    let cookie = req.cookie(SESSION_COOKIE)?;
+    sessions
+        .cookie_session_token(cookie.value())
+        .map(str::to_string)
+}
+
+fn removal_cookie() -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, "").path("/").finish()
+}
+
+// every sign-in ends here, the page keeps the cookie and API clients the bearer token
+pub(crate) fn signed_in_response(
+    req: &Request<Arc<Mutex<StaticData>>>,
+    data: &StaticData,
+) -> tide::Result<Response> {
+    let mut sessions = data.admin_sessions.lock().unwrap();
+    let session_token = sessions.start_session(Instant::now());
+    let cookie = sessions.session_cookie(&session_token, req.url().scheme() == "https");
+    let mut res = Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&SignInResponse {
+            session_token,
+            expires_in_seconds: SESSION_TTL.as_secs(),
+            idle_timeout_seconds: sessions.idle_timeout.as_secs(),
+        })?)
+        .build();
+    res.insert_cookie(cookie);
+    Ok(res)
+}
+
+// the origin is the public base URL and the relying party id its host, the passkeys work
+// only on that host
+pub fn relying_party(config: &Config) -> Option<(String, String)> {
+    if !config.admin_passkeys {
+        return None;
+    }
+    let url = tide::http::Url::parse(config.public_base_url.as_deref()?).ok()?;
+    let rp_id = url.host_str()?.to_string();
+    Some((url.origin().ascii_serialization(), rp_id))
+}
+
+// passkeys, LDAP and basic auth sign in to the same sessions
+pub(crate) fn admin_sessions_enabled(data: &StaticData) -> bool {
+    relying_party(&data.config).is_some()
+        || data.ldap.is_some()
+        || data.config.admin_auth.basic_auth_enabled()
+}
+
+fn require_relying_party(config: &Config) -> tide::Result<(String, String)> {
+    relying_party(config).ok_or_else(|| AppError::NotFound("Not found".to_string()).into_error())
+}
+
+fn decode_field(field: &'static str, value: &str) -> tide::Result<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|_| {
+            AppError::Invalid {
+                field,
+                message: format!("{} should be base64url", field),
+            }
+            .into_error()
+        })
+}
+
+fn now_timestamp() -> tide::Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RelyingPartyEntity {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEntity {
+    pub id: String,
+    pub name: String,
+    pub display_name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CredentialParameters {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub alg: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    pub resident_key: String,
+    pub user_verification: String,
+}
+
+// the options of `navigator.credentials.create()`, the binary fields are base64url
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationOptions {
+    pub challenge: String,
+    pub rp: RelyingPartyEntity,
+    pub user: UserEntity,
+    pub pub_key_cred_params: Vec<CredentialParameters>,
+    pub timeout: u64,
+    pub attestation: String,
+    pub authenticator_selection: AuthenticatorSelection,
+    pub exclude_credentials: Vec<CredentialDescriptor>,
+}
+
+// the options of `navigator.credentials.get()`, the binary fields are base64url
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    pub timeout: u64,
+    pub user_verification: String,
+    pub allow_credentials: Vec<CredentialDescriptor>,
+}
+
+// the fields of the browser's answer, base64url encoded
+#[derive(Serialize, Deserialize)]
+pub struct RegisterPasskeyRequest {
+    pub name: String,
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SignInRequest {
+    pub credential_id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SignInResponse {
+    // sent as "Authorization: Bearer <token>" like the admin token
+    pub session_token: String,
+    pub expires_in_seconds: u64,
+    pub idle_timeout_seconds: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasskeysResponse {
+    pub passkeys: Vec<AdminCredential>,
+}
+
+fn credential_descriptors(credentials: &[AdminCredential]) -> Vec<CredentialDescriptor> {
+    credentials
+        .iter()
+        .map(|credential| CredentialDescriptor {
+            kind: "public-key".to_string(),
+            id: credential.credential_id.clone(),
+        })
+        .collect()
+}
+
+async fn read_json<T: serde::de::DeserializeOwned>(
+    req: &mut Request<Arc<Mutex<StaticData>>>,
+) -> tide::Result<T> {
+    req.body_json()
+        .await
+        .map_err(|_| AppError::BadRequest("Can't parse request body".to_string()).into_error())
+}
+
+// the page offers the sign-in methods that are configured
+pub async fn admin_page(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    if !admin_sessions_enabled(&data) {
+        return Err(AppError::NotFound("Not found".to_string()).into_error());
+    }
+    let html = String::from_utf8(data.admin_html.clone())?
+        .replace(
+            PASSKEYS_ENABLED_PLACEHOLDER,
+            &relying_party(&data.config).is_some().to_string(),
+        )
+        .replace(LDAP_ENABLED_PLACEHOLDER, &data.ldap.is_some().to_string())
+        .replace(
+            BASIC_AUTH_ENABLED_PLACEHOLDER,
+            &data.config.admin_auth.basic_auth_enabled().to_string(),
+        );
+    Ok(csp::html_response(
+        &data.config.content_security_policy,
+        &html,
+    ))
+}
+
+// the first passkey is registered with the admin token, the next ones with either
+pub async fn passkey_creation_options(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    let (_, rp_id) = require_relying_party(&data.config)?;
+    check_admin_token(&req, &data)?;
+    let credentials = data.database.lock().unwrap().list_admin_credentials()?;
+    let challenge = data
+        .admin_sessions
+        .lock()
+        .unwrap()
+        .issue_challenge(Ceremony::Registration, Instant::now());
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&CreationOptions {
+            challenge,
+            rp: RelyingPartyEntity {
+                id: rp_id,
+                name: RP_NAME.to_string(),
+            },
+            user: UserEntity {
+                id: URL_SAFE_NO_PAD.encode(ADMIN_USER_NAME),
+                name: ADMIN_USER_NAME.to_string(),
+                display_name: RP_NAME.to_string(),
+            },
+            pub_key_cred_params: webauthn::SUPPORTED_ALGORITHMS
+                .iter()
+                .map(|&alg| CredentialParameters {
+                    kind: "public-key".to_string(),
+                    alg,
+                })
+                .collect(),
+            timeout: CHALLENGE_TTL.as_millis() as u64,
+            attestation: "none".to_string(),
+            authenticator_selection: AuthenticatorSelection {
+                resident_key: "preferred".to_string(),
+                user_verification: "required".to_string(),
+            },
+            exclude_credentials: credential_descriptors(&credentials),
+        })?)
+        .build())
+}
+
+pub async fn register_passkey(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let register_request: Result<RegisterPasskeyRequest, _> = read_json(&mut req).await;
+
+    let data = req.state().lock().unwrap();
+    let (origin, rp_id) = require_relying_party(&data.config)?;
+    check_admin_token(&req, &data)?;
+    let register_request = register_request?;
+
+    let name = register_request.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_PASSKEY_NAME_LENGTH {
+        return Err(AppError::Invalid {
+            field: "name",
+            message: format!(
+                "Name should be 1 to {} characters long",
+                MAX_PASSKEY_NAME_LENGTH
+            ),
+        }
+        .into_error());
+    }
+    let client_data_json = decode_field("client_data_json", &register_request.client_data_json)?;
+    let attestation_object =
+        decode_field("attestation_object", &register_request.attestation_object)?;
+
+    let rejected = |err: String| AppError::BadRequest(err).into_error();
+    let challenge = read_client_data(
+        &client_data_json,
+        Ceremony::Registration.client_data_type(),
+        &origin,
+    )
+    .map_err(rejected)?;
+    if !data.admin_sessions.lock().unwrap().take_challenge(
+        &challenge,
+        Ceremony::Registration,
+        Instant::now(),
+    ) {
+        return Err(rejected("The challenge is unknown or expired".to_string()));
+    }
+    let credential = verify_registration(&rp_id, &attestation_object).map_err(rejected)?;
+
+    let database = data.database.lock().unwrap();
+    if database
+        .get_admin_credential(&credential.credential_id)?
+        .is_some()
+    {
+        return Err(
+            AppError::Conflict("The passkey is registered already".to_string()).into_error(),
+        );
+    }
+    database.save_admin_credential(
+        &credential.credential_id,
+        &credential.public_key,
+        credential.sign_count,
+        name,
+        now_timestamp()?,
+    )?;
+    let saved = database
+        .get_admin_credential(&credential.credential_id)?
+        .ok_or_else(|| AppError::Storage("The passkey wasn't saved".to_string()).into_error())?;
+    log::info!("Admin passkey {} '{}' registered", saved.id, saved.name);
+    Ok(Response::builder(StatusCode::Created)
+        .body(Body::from_json(&saved)?)
+        .build())
+}
+
+pub async fn list_passkeys(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    require_relying_party(&data.config)?;
+    check_admin_token(&req, &data)?;
+    let passkeys = data.database.lock().unwrap().list_admin_credentials()?;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&PasskeysResponse { passkeys })?)
+        .build())
+}
+
+// the sessions that were started with it stay until they expire
+pub async fn remove_passkey(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    require_relying_party(&data.config)?;
+    check_admin_token(&req, &data)?;
+    let removed = match req.param("id")?.parse::<i64>() {
+        Ok(id) => data.database.lock().unwrap().remove_admin_credential(id)?,
+        Err(_) => false,
+    };
+    if !removed {
+        return Err(AppError::NotFound("Passkey not found".to_string()).into_error());
+    }
+    log::info!("Admin passkey {} removed", req.param("id")?);
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+pub async fn sign_in_options(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    let (_, rp_id) = require_relying_party(&data.config)?;
+    let credentials = data.database.lock().unwrap().list_admin_credentials()?;
+    let challenge = data
+        .admin_sessions
+        .lock()
+        .unwrap()
+        .issue_challenge(Ceremony::SignIn, Instant::now());
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&RequestOptions {
+            challenge,
+            rp_id,
+            timeout: CHALLENGE_TTL.as_millis() as u64,
+            user_verification: "required".to_string(),
+            allow_credentials: credential_descriptors(&credentials),
+        })?)
+        .build())
+}
+
+// every failure gets the same answer, the log tells the admin what went wrong
+pub async fn sign_in(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let sign_in_request: Result<SignInRequest, _> = read_json(&mut req).await;
+
+    let data = req.state().lock().unwrap();
+    let (origin, rp_id) = require_relying_party(&data.config)?;
+    let sign_in_request = sign_in_request?;
+    let client_data_json = decode_field("client_data_json", &sign_in_request.client_data_json)?;
+    let authenticator_data =
+        decode_field("authenticator_data", &sign_in_request.authenticator_data)?;
+    let signature = decode_field("signature", &sign_in_request.signature)?;
+
+    let now = Instant::now();
+    let database = data.database.lock().unwrap();
+    let verified = read_client_data(
+        &client_data_json,
+        Ceremony::SignIn.client_data_type(),
+        &origin,
+    )
+    .and_then(|challenge| {
+        if data
+            .admin_sessions
+            .lock()
+            .unwrap()
+            .take_challenge(&challenge, Ceremony::SignIn, now)
+        {
+            Ok(())
+        } else {
+            Err("The challenge is unknown or expired".to_string())
+        }
+    })
+    .and_then(|_| {
+        database
+            .get_admin_credential(&sign_in_request.credential_id)
+            .map_err(|err| err.to_string())?
+            .ok_or_else(|| "The passkey isn't registered".to_string())
+    })
+    .and_then(|credential| {
+        verify_assertion(
+            &rp_id,
+            &credential.public_key,
+            credential.sign_count,
+            &client_data_json,
+            &authenticator_data,
+            &signature,
+        )
+        .map(|sign_count| (credential, sign_count))
+    });
+    let (credential, sign_count) = match verified {
+        Ok(verified) => verified,
+        Err(err) => {
+            log::warn!("Admin passkey sign-in refused: {}", err);
+            return Err(AppError::BadToken("Passkey sign-in failed".to_string()).into_error());
+        }
+    };
+    database.record_admin_credential_use(credential.id, sign_count, now_timestamp()?)?;
+
+    log::info!("Admin signed in with passkey {}", credential.id);
+    signed_in_response(&req, &data)
+}
+
+pub async fn sign_out(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    if !admin_sessions_enabled(&data) {
+        return Err(AppError::NotFound("Not found".to_string()).into_error());
+    }
+    let mut sessions = data.admin_sessions.lock().unwrap();
+    let ended = session_token(&req, &sessions)
+        .is_some_and(|session_token| sessions.end_session(&session_token));
+    if !ended {
+        return Err(AppError::BadToken("Invalid session token".to_string()).into_error());
+    }
+    let mut res = Response::new(StatusCode::NoContent);
+    res.remove_cookie(removal_cookie());
+    Ok(res)
+}
+
+// revokes every session, the admin token and the other credentials stay as they are
+pub async fn sign_out_everywhere(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    if !admin_sessions_enabled(&data) {
+        return Err(AppError::NotFound("Not found".to_string()).into_error());
+    }
+    check_admin_token(&req, &data)?;
+    data.admin_sessions.lock().unwrap().end_all_sessions();
+    log::info!("Ended all admin sessions");
+    let mut res = Response::new(StatusCode::NoContent);
+    res.remove_cookie(removal_cookie());
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_test_data;
+    use crate::webauthn::tests::{TestAuthenticator, ORIGIN};
+    use tide::http::{Method, Url};
+
+    fn setup_passkeys(admin_token: Option<&str>) -> Arc<Mutex<StaticData>> {
+        let app_data = setup_test_data();
+        {
+            let mut data = app_data.lock().unwrap();
+            data.config.admin_passkeys = true;
+            data.config.public_base_url = Some(ORIGIN.to_string());
+            data.config.admin_token = admin_token.map(str::to_string);
+        }
+        app_data
+    }
+
+    fn make_request(method: Method, path: &str, bearer: Option<&str>) -> tide::http::Request {
+        let mut req = tide::http::Request::new(
+            method,
+            Url::parse(&format!("http://localhost/api/v1/admin{}", path)).unwrap(),
+        );
+        if let Some(bearer) = bearer {
+            req.insert_header("Authorization", format!("Bearer {}", bearer));
+        }
+        req
+    }
+
+    async fn register(
+        app: &tide::Server<Arc<Mutex<StaticData>>>,
+        authenticator: &TestAuthenticator,
+        bearer: &str,
+    ) -> tide::http::Response {
+        let mut res: tide::http::Response = app
+            .respond(make_request(
+                Method::Post,
+                "/passkeys/options",
+                Some(bearer),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let options: CreationOptions = res.take_body().into_json().await.unwrap();
+        assert_eq!(options.rp.id, "localhost");
+
+        let mut req = make_request(Method::Post, "/passkeys", Some(bearer));
+        req.set_body(
+            Body::from_json(&RegisterPasskeyRequest {
+                name: "laptop".to_string(),
+                client_data_json: URL_SAFE_NO_PAD.encode(TestAuthenticator::client_data(
+                    "webauthn.create",
+                    &options.challenge,
+                )),
+                attestation_object: URL_SAFE_NO_PAD.encode(authenticator.attestation_object()),
+            })
+            .unwrap(),
+        );
+        app.respond(req).await.unwrap()
+    }
+
+    async fn sign_in_with(
+        app: &tide::Server<Arc<Mutex<StaticData>>>,
+        authenticator: &mut TestAuthenticator,
+    ) -> tide::http::Response {
+        let mut res: tide::http::Response = app
+            .respond(make_request(Method::Post, "/sessions/options", None))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let options: RequestOptions = res.take_body().into_json().await.unwrap();
+
+        let client_data = TestAuthenticator::client_data("webauthn.get", &options.challenge);
+        let (authenticator_data, signature) = authenticator.sign(&client_data);
+        let mut req = make_request(Method::Post, "/sessions", None);
+        req.set_body(
+            Body::from_json(&SignInRequest {
+                credential_id: URL_SAFE_NO_PAD.encode(&authenticator.credential_id),
+                client_data_json: URL_SAFE_NO_PAD.encode(client_data),
+                authenticator_data: URL_SAFE_NO_PAD.encode(authenticator_data),
+                signature: URL_SAFE_NO_PAD.encode(signature),
+            })
+            .unwrap(),
+        );
+        app.respond(req).await.unwrap()
+    }
+
+    #[test]
+    fn test_challenges_are_single_use() {
+        let mut sessions = AdminSessions::default();
+        let now = Instant::now();
+        let challenge = sessions.issue_challenge(Ceremony::SignIn, now);
+        assert!(!sessions.take_challenge(&challenge, Ceremony::Registration, now));
+        let challenge = sessions.issue_challenge(Ceremony::SignIn, now);
+        assert!(sessions.take_challenge(&challenge, Ceremony::SignIn, now));
+        assert!(!sessions.take_challenge(&challenge, Ceremony::SignIn, now));
+
+        let challenge = sessions.issue_challenge(Ceremony::SignIn, now);
+        assert!(!sessions.take_challenge(&challenge, Ceremony::SignIn, now + CHALLENGE_TTL));
+
+        let session_token = sessions.start_session(now);
+        assert!(sessions.touch_session(&session_token, now));
+        assert!(sessions.end_session(&session_token));
+        assert!(!sessions.touch_session(&session_token, now));
+    }
+
+    #[test]
+    fn test_sessions_expire_when_idle() {
+        let mut sessions = AdminSessions::new(Duration::from_secs(10 * 60));
+        let now = Instant::now();
+        let session_token = sessions.start_session(now);
+
+        // every use pushes the idle timeout back, but not past the TTL
+        let mut used = now;
+        while used + Duration::from_secs(9 * 60) < now + SESSION_TTL {
+            used += Duration::from_secs(9 * 60);
+            assert!(sessions.touch_session(&session_token, used));
+        }
+        assert!(!sessions.touch_session(&session_token, now + SESSION_TTL));
+
+        let session_token = sessions.start_session(now);
+        assert!(!sessions.touch_session(&session_token, now + Duration::from_secs(10 * 60)));
+
+        let session_token = sessions.start_session(now);
+        sessions.end_all_sessions();
+        assert!(!sessions.touch_session(&session_token, now));
+    }
+
+    #[test]
+    fn test_session_cookie() {
+        let sessions = AdminSessions::default();
+        let cookie = sessions.session_cookie("token", true);
+        assert_eq!(cookie.name(), SESSION_COOKIE);
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+        assert_eq!(sessions.cookie_session_token(cookie.value()), Some("token"));
+        assert_eq!(sessions.cookie_session_token("token"), None);
+        assert_eq!(
+            AdminSessions::default().cookie_session_token(cookie.value()),
+            None
+        );
+    }
+
+    #[async_std::test]
+    async fn test_passkeys_are_disabled_by_default() {
+        let app = crate::init_app(setup_test_data());
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Post, "/sessions/options", None))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn test_register_and_sign_in_with_passkey() {
+        let app_data = setup_passkeys(Some("admin"));
+        let app = crate::init_app(app_data.clone());
+        let mut authenticator = TestAuthenticator::new();
+
+        // the first passkey needs the admin token
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Post, "/passkeys/options", None))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        let mut res = register(&app, &authenticator, "admin").await;
+        assert_eq!(res.status(), StatusCode::Created);
+        let passkey: AdminCredential = res.take_body().into_json().await.unwrap();
+        assert_eq!(passkey.name, "laptop");
+        assert_eq!(
+            register(&app, &authenticator, "admin").await.status(),
+            StatusCode::Conflict
+        );
+
+        // the admin token can go once there is a passkey
+        app_data.lock().unwrap().config.admin_token = None;
+        let mut res = sign_in_with(&app, &mut authenticator).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let session: SignInResponse = res.take_body().into_json().await.unwrap();
+
+        let mut res: tide::http::Response = app
+            .respond(make_request(
+                Method::Get,
+                "/passkeys",
+                Some(&session.session_token),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: PasskeysResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(body.passkeys.len(), 1);
+        assert_eq!(body.passkeys[0].sign_count, 1);
+        assert!(body.passkeys[0].last_used_timestamp.is_some());
+        let res: tide::http::Response = app
+            .respond(make_request(
+                Method::Get,
+                "/defaults",
+                Some(&session.session_token),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let res: tide::http::Response = app
+            .respond(make_request(
+                Method::Delete,
+                "/sessions",
+                Some(&session.session_token),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NoContent);
+        let res: tide::http::Response = app
+            .respond(make_request(
+                Method::Get,
+                "/defaults",
+                Some(&session.session_token),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        // a removed passkey can't sign in anymore
+        let mut res = sign_in_with(&app, &mut authenticator).await;
+        let session: SignInResponse = res.take_body().into_json().await.unwrap();
+        let res: tide::http::Response = app
+            .respond(make_request(
+                Method::Delete,
+                &format!("/passkeys/{}", passkey.id),
+                Some(&session.session_token),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NoContent);
+        assert_eq!(
+            sign_in_with(&app, &mut authenticator).await.status(),
+            StatusCode::Unauthorized
+        );
+    }
+
+    #[async_std::test]
+    async fn test_session_cookie_signs_in_the_page() {
+        let app = crate::init_app(setup_passkeys(Some("admin")));
+        let mut authenticator = TestAuthenticator::new();
+        assert_eq!(
+            register(&app, &authenticator, "admin").await.status(),
+            StatusCode::Created
+        );
+        let res = sign_in_with(&app, &mut authenticator).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let set_cookie = res["Set-Cookie"].as_str();
+        assert!(set_cookie.contains("HttpOnly"));
+        assert!(set_cookie.contains("SameSite=Strict"));
+        let cookie = set_cookie.split(';').next().unwrap().to_string();
+
+        let with_cookie = |method: Method, path: &str, cookie: &str| {
+            let mut req = make_request(method, path, None);
+            req.insert_header("Cookie", cookie);
+            req
+        };
+        let res: tide::http::Response = app
+            .respond(with_cookie(Method::Get, "/defaults", &cookie))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let forged = format!("{}=token.0123456789abcdef", SESSION_COOKIE);
+        let res: tide::http::Response = app
+            .respond(with_cookie(Method::Get, "/defaults", &forged))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let res: tide::http::Response = app
+            .respond(with_cookie(Method::Delete, "/sessions", &cookie))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NoContent);
+        assert!(res["Set-Cookie"].as_str().starts_with(SESSION_COOKIE));
+        let res: tide::http::Response = app
+            .respond(with_cookie(Method::Get, "/defaults", &cookie))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+    }
+
+    #[async_std::test]
+    async fn test_sign_out_everywhere() {
+        let app = crate::init_app(setup_passkeys(Some("admin")));
+        let mut authenticator = TestAuthenticator::new();
+        register(&app, &authenticator, "admin").await;
+        let mut res = sign_in_with(&app, &mut authenticator).await;
+        let first: SignInResponse = res.take_body().into_json().await.unwrap();
+        let mut res = sign_in_with(&app, &mut authenticator).await;
+        let second: SignInResponse = res.take_body().into_json().await.unwrap();
+
+        let res: tide::http::Response = app
+            .respond(make_request(
+                Method::Delete,
+                "/sessions/all",
+                Some(&first.session_token),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NoContent);
+        for session in [&first, &second] {
+            let res: tide::http::Response = app
+                .respond(make_request(
+                    Method::Get,
+                    "/defaults",
+                    Some(&session.session_token),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+        }
+        // the admin token isn't a session
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Get, "/defaults", Some("admin")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn test_sign_in_is_refused() {
+        let app_data = setup_passkeys(Some("admin"));
+        let app = crate::init_app(app_data);
+        let mut authenticator = TestAuthenticator::new();
+        assert_eq!(
+            register(&app, &authenticator, "admin").await.status(),
+            StatusCode::Created
+        );
+
+        // another authenticator with the same credential id
+        let mut impostor = TestAuthenticator::new();
+        assert_eq!(
+            sign_in_with(&app, &mut impostor).await.status(),
+            StatusCode::Unauthorized
+        );
+
+        // an answer to a challenge that was never handed out
+        let client_data = TestAuthenticator::client_data("webauthn.get", "made-up");
+        let (authenticator_data, signature) = authenticator.sign(&client_data);
+        let mut req = make_request(Method::Post, "/sessions", None);
+        req.set_body(
+            Body::from_json(&SignInRequest {
+                credential_id: URL_SAFE_NO_PAD.encode(&authenticator.credential_id),
+                client_data_json: URL_SAFE_NO_PAD.encode(client_data),
+                authenticator_data: URL_SAFE_NO_PAD.encode(authenticator_data),
+                signature: URL_SAFE_NO_PAD.encode(signature),
+            })
+            .unwrap(),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        assert_eq!(
+            sign_in_with(&app, &mut authenticator).await.status(),
+            StatusCode::Ok
+        );
+    }
+}
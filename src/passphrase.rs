@@ -0,0 +1,38 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+
+pub fn hash_passphrase(passphrase: &str) -> tide::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(passphrase.as_bytes(), &salt)
+        .map_err(|err| tide::Error::from_str(tide::StatusCode::InternalServerError, err))?;
+    Ok(hash.to_string())
+}
+
+pub fn verify_passphrase(passphrase: &str, passphrase_hash: &str) -> bool {
+    match PasswordHash::new(passphrase_hash) {
+        Ok(parsed_hash) => Argon2::default()
+            .verify_password(passphrase.as_bytes(), &parsed_hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_and_verify_passphrase() {
+        let hash = hash_passphrase("correct horse").unwrap();
+        assert_ne!(hash, "correct horse");
+        assert!(verify_passphrase("correct horse", &hash));
+        assert!(!verify_passphrase("battery staple", &hash));
+    }
+
+    #[test]
+    fn test_verify_passphrase_with_malformed_hash() {
+        assert!(!verify_passphrase("correct horse", "not a hash"));
+    }
+}
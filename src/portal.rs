@@ -0,0 +1,812 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tide::http::cookies::{Cookie, SameSite};
+use tide::{Body, Request, Response, StatusCode};
+use zeroize::Zeroizing;
+
+use crate::admin::bearer_token;
+use crate::error::AppError;
+use crate::oidc::{self, OidcClient};
+use crate::store::{ApiKey, UserLimits};
+use crate::tokens::{hash_token, is_same_token, random_base64url};
+use crate::{csp, StaticData};
+
+// the time the IdP gets to send the browser back
+const LOGIN_TTL: Duration = Duration::from_secs(10 * 60);
+const SESSION_TTL: Duration = Duration::from_secs(8 * 60 * 60);
+// anyone can start a sign-in, the oldest ones make room for new ones
+const MAX_PENDING_LOGINS: usize = 1000;
+// the browser that started a sign-in keeps its state and nonce, so a callback link made
+// for another browser can't sign it in
+const LOGIN_COOKIE: &str = "oidc_login";
+const MAX_KEY_NAME_LENGTH: usize = 64;
+pub(crate) const RANDOM_BYTES: usize = 32;
+pub(crate) const FIRST_KEY_NAME: &str = "default";
+
+struct PendingLogin {
+    nonce: String,
+    code_verifier: Zeroizing<String>,
+    expire: Instant,
+}
+
+struct PortalSession {
+    account_id: i64,
+    email: Option<String>,
+    plan: Option<String>,
+    expire: Instant,
+    // the key made on the first sign-in, shown once
+    new_api_key: Option<Zeroizing<String>>,
+}
+
+// sign-ins and sessions are kept in memory, a restart signs everybody out
+#[derive(Default)]
+pub struct PortalSessions {
+    // by the state sent to the IdP
+    logins: HashMap<String, PendingLogin>,
+    // by the hash of the session token
+    sessions: HashMap<String, PortalSession>,
+}
+
+impl PortalSessions {
+    fn start_login(&mut self, now: Instant) -> (String, String, Zeroizing<String>) {
+        self.logins.retain(|_, login| login.expire > now);
+        if self.logins.len() >= MAX_PENDING_LOGINS {
+            let oldest = self
+                .logins
+                .iter()
+                .min_by_key(|(_, login)| login.expire)
+                .map(|(state, _)| state.clone());
+            if let Some(oldest) = oldest {
+                self.logins.remove(&oldest);
+            }
+        }
+        let state = random_base64url(RANDOM_BYTES);
+        let nonce = random_base64url(RANDOM_BYTES);
+        let code_verifier = Zeroizing::new(random_base64url(RANDOM_BYTES));
+        self.logins.insert(
+            state.clone(),
+            PendingLogin {
+                nonce: nonce.clone(),
+                code_verifier: code_verifier.clone(),
+                expire: now + LOGIN_TTL,
+            },
+        );
+        (state, nonce, code_verifier)
+    }
+
+    // a state is good for a single callback
+    fn finish_login(&mut self, state: &str, now: Instant) -> Option<PendingLogin> {
+        self.logins.remove(state).filter(|login| login.expire > now)
+    }
+
+    fn start_session(
+        &mut self,
+        account_id: i64,
+        email: Option<String>,
+        plan: Option<String>,
+        new_api_key: Option<Zeroizing<String>>,
+        now: Instant,
+    ) -> String {
+        self.sessions.retain(|_, session| session.expire > now);
+        let session_token = random_base64url(RANDOM_BYTES);
+        self.sessions.insert(
+            hash_token(&session_token),
+            PortalSession {
+                account_id,
+                email,
+                plan,
+                expire: now + SESSION_TTL,
+                new_api_key,
+            },
+        );
+        session_token
+    }
+
+    fn get_session(&mut self, session_token: &str, now: Instant) -> Option<&mut PortalSession> {
+        self.sessions
+            .get_mut(&hash_token(session_token))
+            .filter(|session| session.expire > now)
+    }
+
+    fn end_session(&mut self, session_token: &str) -> bool {
+        self.sessions.remove(&hash_token(session_token)).is_some()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AccountResponse {
+    pub email: Option<String>,
+    pub plan: Option<String>,
+    // the limits every key of the account has
+    pub limits: UserLimits,
+    // the key made on the first sign-in, only in the first answer after it
+    pub new_api_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ApiKeysResponse {
+    pub keys: Vec<ApiKey>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub key: ApiKey,
+    // sent as the user token, it can't be shown again
+    pub user_token: String,
+}
+
+fn require_oidc(data: &StaticData) -> tide::Result<OidcClient> {
+    data.oidc
+        .clone()
+        .ok_or_else(|| AppError::NotFound("Not found".to_string()).into_error())
+}
+
+pub(crate) fn now_timestamp() -> tide::Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+}
+
+// the limits of the plan from the config, the defaults without one
+pub(crate) fn limits_of_plan(data: &StaticData, plan: Option<&str>) -> UserLimits {
+    plan.and_then(|plan| data.config.plans.get(plan))
+        .unwrap_or(&data.default_user_limits)
+        .clone()
+}
+
+// the account of the bearer's session
+fn check_session(
+    req: &Request<Arc<Mutex<StaticData>>>,
+    data: &StaticData,
+) -> tide::Result<(i64, Option<String>)> {
+    require_oidc(data)?;
+    let mut sessions = data.portal_sessions.lock().unwrap();
+    bearer_token(req)
+        .and_then(|session_token| sessions.get_session(session_token, Instant::now()))
+        .map(|session| (session.account_id, session.plan.clone()))
+        .ok_or_else(|| AppError::BadToken("Sign in again".to_string()).into_error())
+}
+
+fn found(location: &str) -> Response {
+    Response::builder(StatusCode::Found)
+        .header("Location", location)
+        .build()
+}
+
+// Lax, the IdP sends the browser back with a top-level navigation from its own site
+fn login_cookie(state: &str, nonce: &str, secure: bool) -> Cookie<'static> {
+    Cookie::build(LOGIN_COOKIE, format!("{}.{}", state, nonce))
+        .path(oidc::CALLBACK_PATH)
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(LOGIN_TTL.as_secs() as i64))
+        .finish()
+}
+
+fn login_removal_cookie() -> Cookie<'static> {
+    Cookie::build(LOGIN_COOKIE, "")
+        .path(oidc::CALLBACK_PATH)
+        .finish()
+}
+
+fn identity_provider_error(err: http_types::Error) -> tide::Error {
+    log::error!("OpenID Connect sign-in failed: {}", err);
+    tide::Error::from_str(
+        StatusCode::BadGateway,
+        "The identity provider can't be reached",
+    )
+}
+
+pub async fn portal_page(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    require_oidc(&data)?;
+    let html = String::from_utf8(data.portal_html.clone())?;
+    Ok(csp::html_response(
+        &data.config.content_security_policy,
+        &html,
+    ))
+}
+
+// sends the browser to the IdP
+pub async fn login(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let (oidc, (state, nonce, code_verifier)) = {
+        let data = req.state().lock().unwrap();
+        let oidc = require_oidc(&data)?;
+        let login = data
+            .portal_sessions
+            .lock()
+            .unwrap()
+            .start_login(Instant::now());
+        (oidc, login)
+    };
+    let url = oidc
+        .authorization_url(&state, &nonce, &code_verifier)
+        .await
+        .map_err(identity_provider_error)?;
+    let mut res = found(&url);
+    res.insert_cookie(login_cookie(&state, &nonce, req.url().scheme() == "https"));
+    Ok(res)
+}
+
+// the IdP sends the browser back here, it goes on to the portal with the session token in
+// the fragment, so the token never reaches a server log
+pub async fn login_callback(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let mut code = None;
+    let mut state = None;
+    for (name, value) in req.url().query_pairs() {
+        match name.as_ref() {
+            "code" => code = Some(value.into_owned()),
+            "state" => state = Some(value.into_owned()),
+            "error" => {
+                log::info!("The IdP refused a sign-in: {}", value);
+                return Err(AppError::BadToken("The sign-in was refused".to_string()).into_error());
+            }
+            _ => {}
+        }
+    }
+    let (code, state) = match (code, state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return Err(AppError::BadRequest("Expected code and state".to_string()).into_error()),
+    };
+    let started_here = || {
+        AppError::BadToken("The sign-in was started in another browser, start again".to_string())
+            .into_error()
+    };
+    let (cookie_state, cookie_nonce) = req
+        .cookie(LOGIN_COOKIE)
+        .and_then(|cookie| {
+            cookie
+                .value()
+                .split_once('.')
+                .map(|(state, nonce)| (state.to_string(), nonce.to_string()))
+        })
+        .ok_or_else(started_here)?;
+    if !is_same_token(&cookie_state, &state) {
+        return Err(started_here());
+    }
+
+    let (oidc, login) = {
+        let data = req.state().lock().unwrap();
+        let oidc = require_oidc(&data)?;
+        let login = data
+            .portal_sessions
+            .lock()
+            .unwrap()
+            .finish_login(&state, Instant::now());
+        (oidc, login)
+    };
+    let login = login.ok_or_else(|| {
+        AppError::BadToken("The sign-in has expired, start again".to_string()).into_error()
+    })?;
+    if !is_same_token(&cookie_nonce, &login.nonce) {
+        return Err(started_here());
+    }
+    let identity = match oidc
+        .sign_in(&code, &login.code_verifier, &login.nonce, now_timestamp()?)
+        .await
+    {
+        Ok(identity) => identity,
+        Err(err) if err.status() == StatusCode::Unauthorized => {
+            log::warn!("OpenID Connect ID token refused: {}", err);
+            return Err(AppError::BadToken("The sign-in was refused".to_string()).into_error());
+        }
+        Err(err) => return Err(identity_provider_error(err)),
+    };
+    if !oidc.is_allowed(&identity) {
+        log::info!(
+            "OpenID Connect sign-in of {} refused by allowedEmailDomains",
+            identity.subject
+        );
+        return Err(
+            AppError::Forbidden("Your account can't use this service".to_string()).into_error(),
+        );
+    }
+
+    let plan = oidc.plan_for(&identity);
+    let data = req.state().lock().unwrap();
+    let limits = limits_of_plan(&data, plan.as_deref());
+    let timestamp = now_timestamp()?;
+    let (account_id, new_api_key) = {
+        let database = data.database.lock().unwrap();
+        let account_id = database.sign_in_portal_account(
+            oidc.issuer(),
+            &identity.subject,
+            identity.email.as_deref(),
+            timestamp,
+        )?;
+        // the groups may have changed since the last sign-in
+        database.set_account_plan(account_id, plan.as_deref())?;
+        let new_api_key = if database.list_api_keys(account_id)?.is_empty() {
+            let token = Zeroizing::new(random_base64url(RANDOM_BYTES));
+            database.create_api_key(
+                account_id,
+                &token,
+                FIRST_KEY_NAME,
+                &limits,
+                plan.as_deref(),
+                timestamp,
+            )?;
+            log::info!("Portal account {} got its first key", account_id);
+            Some(token)
+        } else {
+            None
+        };
+        (account_id, new_api_key)
+    };
+    let session_token = data.portal_sessions.lock().unwrap().start_session(
+        account_id,
+        identity.email,
+        plan,
+        new_api_key,
+        Instant::now(),
+    );
+    let mut res = found(&format!("/portal#session={}", session_token));
+    res.remove_cookie(login_removal_cookie());
+    Ok(res)
+}
+
+pub async fn get_account(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    check_session(&req, &data)?;
+    let (email, plan, new_api_key) = {
+        let mut sessions = data.portal_sessions.lock().unwrap();
+        let session = bearer_token(&req)
+            .and_then(|session_token| sessions.get_session(session_token, Instant::now()))
+            .ok_or_else(|| AppError::BadToken("Sign in again".to_string()).into_error())?;
+        (
+            session.email.clone(),
+            session.plan.clone(),
+            session.new_api_key.take(),
+        )
+    };
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&AccountResponse {
+            email,
+            limits: limits_of_plan(&data, plan.as_deref()),
+            plan,
+            new_api_key: new_api_key.map(|token| token.to_string()),
+        })?)
+        .build())
+}
+
+pub async fn list_api_keys(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    let (account_id, _) = check_session(&req, &data)?;
+    let keys = data.database.lock().unwrap().list_api_keys(account_id)?;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&ApiKeysResponse { keys })?)
+        .build())
+}
+
+pub async fn create_api_key(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let create_request: Result<CreateApiKeyRequest, _> = req.body_json().await;
+
+    let data = req.state().lock().unwrap();
+    let (account_id, plan) = check_session(&req, &data)?;
+    let create_request = create_request
+        .map_err(|_| AppError::BadRequest("Can't parse request body".to_string()).into_error())?;
+    let name = create_request.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_KEY_NAME_LENGTH {
+        return Err(AppError::Invalid {
+            field: "name",
+            message: format!(
+                "Name should be 1 to {} characters long",
+                MAX_KEY_NAME_LENGTH
+            ),
+        }
+        .into_error());
+    }
+    let max_api_keys = require_oidc(&data)?.max_api_keys();
+
+    let database = data.database.lock().unwrap();
+    if database.list_api_keys(account_id)?.len() >= max_api_keys {
+        return Err(AppError::Conflict(format!(
+            "You can have {} keys at most, remove one first",
+            max_api_keys
+        ))
+        .into_error());
+    }
+    let token = Zeroizing::new(random_base64url(RANDOM_BYTES));
+    let timestamp = now_timestamp()?;
+    let id = database.create_api_key(
+        account_id,
+        &token,
+        name,
+        &limits_of_plan(&data, plan.as_deref()),
+        plan.as_deref(),
+        timestamp,
+    )?;
+    log::info!("Portal account {} created key {}", account_id, id);
+    Ok(Response::builder(StatusCode::Created)
+        .body(Body::from_json(&CreatedApiKey {
+            key: ApiKey {
+                id,
+                name: name.to_string(),
+                created_timestamp: timestamp,
+                plan,
+            },
+            user_token: token.to_string(),
+        })?)
+        .build())
+}
+
+// the messages created with the key stay until they are read or expire
+pub async fn remove_api_key(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    let (account_id, _) = check_session(&req, &data)?;
+    let removed = match req.param("id")?.parse::<i64>() {
+        Ok(id) => data
+            .database
+            .lock()
+            .unwrap()
+            .remove_api_key(account_id, id)?,
+        Err(_) => false,
+    };
+    if !removed {
+        return Err(AppError::NotFound("Key not found".to_string()).into_error());
+    }
+    log::info!(
+        "Portal account {} removed key {}",
+        account_id,
+        req.param("id")?
+    );
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+pub async fn sign_out(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    require_oidc(&data)?;
+    let ended = bearer_token(&req).is_some_and(|session_token| {
+        data.portal_sessions
+            .lock()
+            .unwrap()
+            .end_session(session_token)
+    });
+    if !ended {
+        return Err(AppError::BadToken("Sign in again".to_string()).into_error());
+    }
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oidc::tests::{TestIssuer, CLIENT_ID};
+    use crate::oidc::{code_challenge, OidcConfig};
+    use crate::tests::setup_test_data;
+    use async_std::net::TcpListener;
+    use async_std::task;
+    use tide::http::{Method, Url};
+
+    // answers discovery, token and key requests like an IdP, the token carries the nonce
+    // of the last authorization URL
+    async fn start_identity_provider(
+        groups: Vec<&'static str>,
+    ) -> (String, Arc<Mutex<Option<(String, String)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer_url = format!("http://{}", listener.local_addr().unwrap());
+        // the nonce and code challenge of the pending sign-in
+        let pending: Arc<Mutex<Option<(String, String)>>> = Arc::new(Mutex::new(None));
+        let issuer = Arc::new(TestIssuer::new());
+        let (served_issuer, served_pending) = (issuer_url.clone(), pending.clone());
+        task::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (issuer_url, pending, issuer, groups) = (
+                    served_issuer.clone(),
+                    served_pending.clone(),
+                    issuer.clone(),
+                    groups.clone(),
+                );
+                task::spawn(async move {
+                    let _ = async_h1::accept(stream, |mut req| {
+                        let (issuer_url, pending, issuer, groups) = (
+                            issuer_url.clone(),
+                            pending.clone(),
+                            issuer.clone(),
+                            groups.clone(),
+                        );
+                        async move {
+                            let body = match req.url().path() {
+                                "/.well-known/openid-configuration" => serde_json::json!({
+                                    "issuer": issuer_url,
+                                    "authorization_endpoint": format!("{}/authorize", issuer_url),
+                                    "token_endpoint": format!("{}/token", issuer_url),
+                                    "jwks_uri": format!("{}/keys", issuer_url),
+                                }),
+                                "/keys" => issuer.jwks(),
+                                _ => {
+                                    let form = req.body_string().await?;
+                                    let (nonce, challenge) =
+                                        pending.lock().unwrap().clone().unwrap();
+                                    let verifier = form
+                                        .split('&')
+                                        .find_map(|pair| pair.strip_prefix("code_verifier="))
+                                        .unwrap_or_default()
+                                        .to_string();
+                                    if code_challenge(&verifier) != challenge {
+                                        return Ok(http_types::Response::new(400));
+                                    }
+                                    serde_json::json!({
+                                        "id_token": issuer.id_token(&serde_json::json!({
+                                            "iss": issuer_url,
+                                            "aud": CLIENT_ID,
+                                            "sub": "alice",
+                                            "exp": i64::MAX / 2,
+                                            "nonce": nonce,
+                                            "email": "alice@example.com",
+                                            "email_verified": true,
+                                            "groups": groups,
+                                        })),
+                                    })
+                                }
+                            };
+                            let mut res = http_types::Response::new(200);
+                            res.set_body(http_types::Body::from_json(&body)?);
+                            Ok(res)
+                        }
+                    })
+                    .await;
+                });
+            }
+        });
+        (issuer_url, pending)
+    }
+
+    async fn setup_portal(
+        groups: Vec<&'static str>,
+    ) -> (
+        tide::Server<Arc<Mutex<StaticData>>>,
+        Arc<Mutex<Option<(String, String)>>>,
+    ) {
+        let (issuer_url, pending) = start_identity_provider(groups).await;
+        let app_data = setup_test_data();
+        {
+            let mut data = app_data.lock().unwrap();
+            let config = OidcConfig {
+                issuer: issuer_url,
+                client_id: CLIENT_ID.to_string(),
+                client_secret: "secret".to_string(),
+                scopes: None,
+                allowed_email_domains: Vec::new(),
+                default_plan: None,
+                groups_claim: None,
+                group_plans: HashMap::from([("engineering".to_string(), "team".to_string())]),
+                max_api_keys: Some(2),
+                ca_bundle_path: None,
+            };
+            data.config.plans.insert(
+                "team".to_string(),
+                UserLimits {
+                    retention_limit_minutes: 1440,
+                    ..Default::default()
+                },
+            );
+            data.oidc = Some(OidcClient::new(&config, "http://localhost").unwrap());
+        }
+        (crate::init_app(app_data), pending)
+    }
+
+    // starts a sign-in, returns the query of the authorization URL and the login cookie
+    async fn start_login(
+        app: &tide::Server<Arc<Mutex<StaticData>>>,
+        pending: &Arc<Mutex<Option<(String, String)>>>,
+    ) -> (HashMap<String, String>, String) {
+        let req =
+            tide::http::Request::new(Method::Get, Url::parse("http://localhost/login").unwrap());
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Found);
+        let authorization_url = Url::parse(res["Location"].as_str()).unwrap();
+        let query: HashMap<String, String> = authorization_url.query_pairs().into_owned().collect();
+        assert_eq!(query["client_id"], CLIENT_ID);
+        assert_eq!(query["redirect_uri"], "http://localhost/oidc/callback");
+        *pending.lock().unwrap() = Some((query["nonce"].clone(), query["code_challenge"].clone()));
+        let set_cookie = res["Set-Cookie"].as_str();
+        assert!(set_cookie.contains("HttpOnly"));
+        let cookie = set_cookie.split(';').next().unwrap().to_string();
+        (query, cookie)
+    }
+
+    fn callback_request(state: &str, cookie: Option<&str>) -> tide::http::Request {
+        let mut req = tide::http::Request::new(
+            Method::Get,
+            Url::parse(&format!(
+                "http://localhost/oidc/callback?code=code1&state={}",
+                state
+            ))
+            .unwrap(),
+        );
+        if let Some(cookie) = cookie {
+            req.insert_header("Cookie", cookie);
+        }
+        req
+    }
+
+    // goes through the IdP and returns the session token
+    async fn sign_in(
+        app: &tide::Server<Arc<Mutex<StaticData>>>,
+        pending: &Arc<Mutex<Option<(String, String)>>>,
+    ) -> String {
+        let (query, cookie) = start_login(app, pending).await;
+        let res: tide::http::Response = app
+            .respond(callback_request(&query["state"], Some(&cookie)))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Found);
+        res["Location"]
+            .as_str()
+            .strip_prefix("/portal#session=")
+            .unwrap()
+            .to_string()
+    }
+
+    fn make_request(method: Method, path: &str, session_token: &str) -> tide::http::Request {
+        let mut req = tide::http::Request::new(
+            method,
+            Url::parse(&format!("http://localhost/api/v1/portal{}", path)).unwrap(),
+        );
+        req.insert_header("Authorization", format!("Bearer {}", session_token));
+        req
+    }
+
+    #[test]
+    fn test_logins_are_single_use() {
+        let mut sessions = PortalSessions::default();
+        let now = Instant::now();
+        let (state, nonce, _) = sessions.start_login(now);
+        assert_eq!(sessions.finish_login(&state, now).unwrap().nonce, nonce);
+        assert!(sessions.finish_login(&state, now).is_none());
+        let (state, _, _) = sessions.start_login(now);
+        assert!(sessions.finish_login(&state, now + LOGIN_TTL).is_none());
+    }
+
+    #[async_std::test]
+    async fn test_portal_is_disabled_by_default() {
+        let app = crate::init_app(setup_test_data());
+        let req =
+            tide::http::Request::new(Method::Get, Url::parse("http://localhost/login").unwrap());
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn test_sign_in_and_manage_keys() {
+        let (app, pending) = setup_portal(vec!["engineering"]).await;
+        let session_token = sign_in(&app, &pending).await;
+
+        // the first key is shown once
+        let mut res: tide::http::Response = app
+            .respond(make_request(Method::Get, "/account", &session_token))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let account: AccountResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(account.email.as_deref(), Some("alice@example.com"));
+        assert_eq!(account.plan.as_deref(), Some("team"));
+        assert_eq!(account.limits.retention_limit_minutes, 1440);
+        let first_key = account.new_api_key.unwrap();
+        let mut res: tide::http::Response = app
+            .respond(make_request(Method::Get, "/account", &session_token))
+            .await
+            .unwrap();
+        let account: AccountResponse = res.take_body().into_json().await.unwrap();
+        assert!(account.new_api_key.is_none());
+
+        // the key works like any user token
+        let mut req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://localhost/api/v1/quota").unwrap(),
+        );
+        req.set_body(Body::from_json(&serde_json::json!({ "user_token": first_key })).unwrap());
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let mut req = make_request(Method::Post, "/keys", &session_token);
+        req.set_body(
+            Body::from_json(&CreateApiKeyRequest {
+                name: "ci".to_string(),
+            })
+            .unwrap(),
+        );
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Created);
+        let created: CreatedApiKey = res.take_body().into_json().await.unwrap();
+        assert_eq!(created.key.plan.as_deref(), Some("team"));
+        assert_ne!(created.user_token, first_key);
+
+        let mut req = make_request(Method::Post, "/keys", &session_token);
+        req.set_body(
+            Body::from_json(&CreateApiKeyRequest {
+                name: "one more".to_string(),
+            })
+            .unwrap(),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Conflict);
+
+        let mut res: tide::http::Response = app
+            .respond(make_request(Method::Get, "/keys", &session_token))
+            .await
+            .unwrap();
+        let keys: ApiKeysResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(keys.keys.len(), 2);
+        assert_eq!(keys.keys[0].name, "default");
+
+        let res: tide::http::Response = app
+            .respond(make_request(
+                Method::Delete,
+                &format!("/keys/{}", created.key.id),
+                &session_token,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NoContent);
+
+        // a second sign-in finds the account and doesn't make another first key
+        let session_token = sign_in(&app, &pending).await;
+        let mut res: tide::http::Response = app
+            .respond(make_request(Method::Get, "/account", &session_token))
+            .await
+            .unwrap();
+        let account: AccountResponse = res.take_body().into_json().await.unwrap();
+        assert!(account.new_api_key.is_none());
+
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Delete, "/session", &session_token))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NoContent);
+        let res: tide::http::Response = app
+            .respond(make_request(Method::Get, "/keys", &session_token))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+    }
+
+    #[async_std::test]
+    async fn test_callback_needs_the_browser_that_started_the_login() {
+        let (app, pending) = setup_portal(Vec::new()).await;
+        let (_, victim_cookie) = start_login(&app, &pending).await;
+        let (query, attacker_cookie) = start_login(&app, &pending).await;
+
+        // the attacker's callback link opened in the victim's browser, or without a cookie
+        for cookie in [Some(victim_cookie.as_str()), None] {
+            let res: tide::http::Response = app
+                .respond(callback_request(&query["state"], cookie))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+        }
+        // the login is still there for the browser that started it
+        let res: tide::http::Response = app
+            .respond(callback_request(&query["state"], Some(&attacker_cookie)))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Found);
+    }
+
+    #[async_std::test]
+    async fn test_callback_needs_a_pending_login() {
+        let (app, _) = setup_portal(Vec::new()).await;
+        let req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://localhost/oidc/callback?code=code1&state=made-up").unwrap(),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://localhost/oidc/callback?error=access_denied&state=made-up").unwrap(),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+    }
+}
@@ -0,0 +1,353 @@
+use std::net::{IpAddr, SocketAddr};
+use tide::{Middleware, Next, Request};
+
+use crate::server::UNIX_PEER_ADDR;
+
+const FORWARDED_HEADERS: [&str; 4] = [
+    "Forwarded",
+    "X-Forwarded-For",
+    "X-Forwarded-Host",
+    "X-Forwarded-Proto",
+];
+
+// the address of the client as far as the trusted proxies tell it, the peer address when
+// the request didn't come through one of them
+#[derive(Clone)]
+pub struct ClientAddr(pub String);
+
+// every middleware and handler takes the client address from here, tide's remote() takes the
+// leftmost forwarded hop, which the client can write itself
+pub fn client_addr<State>(req: &Request<State>) -> Option<&str> {
+    req.ext::<ClientAddr>()
+        .map(|client_addr| client_addr.0.as_str())
+        .or_else(|| req.peer_addr())
+}
+
+// a single address or a CIDR block, e.g. "10.0.0.0/8"
+#[derive(Debug, PartialEq)]
+pub(crate) struct IpNetwork {
+    address: IpAddr,
+    prefix_length: u32,
+}
+
+impl IpNetwork {
+    pub(crate) fn parse(value: &str) -> Option<IpNetwork> {
+        let (address, prefix_length) = match value.split_once('/') {
+            Some((address, prefix_length)) => (address, Some(prefix_length)),
+            None => (value, None),
+        };
+        let address: IpAddr = address.trim().parse().ok()?;
+        let max_prefix_length = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match prefix_length {
+            Some(prefix_length) => prefix_length.trim().parse().ok()?,
+            None => max_prefix_length,
+        };
+        if prefix_length > max_prefix_length {
+            return None;
+        }
+        Some(IpNetwork {
+            address,
+            prefix_length,
+        })
+    }
+
+    pub(crate) fn contains(&self, address: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack socket show up as IPv4-mapped IPv6 addresses
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+            IpAddr::V4(_) => address,
+        };
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_length).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_length).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// the Forwarded and X-Forwarded-* headers are honored only when the connection
+// comes from one of the trusted proxies, otherwise any client could spoof them
+pub struct ForwardedHeadersMiddleware {
+    trusted_proxies: Vec<IpNetwork>,
+    // "unix" in the list trusts everything connected over the unix socket
+    trust_unix_socket: bool,
+}
+
+impl ForwardedHeadersMiddleware {
+    pub fn new(trusted_proxies: &[String]) -> Self {
+        let trust_unix_socket = trusted_proxies.iter().any(|value| value == UNIX_PEER_ADDR);
+        let trusted_proxies = trusted_proxies
+            .iter()
+            .filter(|value| *value != UNIX_PEER_ADDR)
+            .filter_map(|value| {
+                let network = IpNetwork::parse(value);
+                if network.is_none() {
+                    log::warn!("Ignoring invalid trusted proxy address '{}'", value);
+                }
+                network
+            })
+            .collect();
+        ForwardedHeadersMiddleware {
+            trusted_proxies,
+            trust_unix_socket,
+        }
+    }
+
+    fn is_trusted_ip(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|network| network.contains(ip))
+    }
+
+    // the rightmost hop that isn't a trusted proxy, every proxy appends the address it got
+    // the request from, so only the ones left of the first untrusted hop can be made up
+    fn resolve_client(&self, hops: &[String]) -> Option<String> {
+        hops.iter()
+            .rev()
+            .find(|hop| !forwarded_ip(hop).is_some_and(|ip| self.is_trusted_ip(ip)))
+            .or_else(|| hops.first())
+            .map(|hop| match forwarded_ip(hop) {
+                Some(ip) => ip.to_string(),
+                None => hop.clone(),
+            })
+    }
+
+    fn is_trusted(&self, peer_addr: Option<&str>) -> bool {
+        if peer_addr == Some(UNIX_PEER_ADDR) {
+            return self.trust_unix_socket;
+        }
+        peer_addr
+            .and_then(|peer_addr| peer_addr.parse::<SocketAddr>().ok())
+            .is_some_and(|peer_addr| self.is_trusted_ip(peer_addr.ip()))
+    }
+}
+
+// e.g. "192.0.2.1", "192.0.2.1:4711", "[2001:db8::1]:4711" or a quoted one of them
+fn forwarded_ip(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    if let Some(rest) = hop.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    hop.parse::<IpAddr>()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+// the "for" of every Forwarded element or else every X-Forwarded-For entry, in order
+fn forwarded_hops<State>(req: &Request<State>) -> Vec<String> {
+    let from_forwarded: Vec<String> = req
+        .header("Forwarded")
+        .into_iter()
+        .flat_map(|values| values.iter())
+        .flat_map(|value| value.as_str().split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| value.trim().trim_matches('"').to_string())
+            })
+        })
+        .collect();
+    if !from_forwarded.is_empty() {
+        return from_forwarded;
+    }
+    req.header("X-Forwarded-For")
+        .into_iter()
+        .flat_map(|values| values.iter())
+        .flat_map(|value| value.as_str().split(','))
+        .map(|hop| hop.trim().to_string())
+        .filter(|hop| !hop.is_empty())
+        .collect()
+}
+
+fn forwarded_proto<State>(req: &Request<State>) -> Option<String> {
+    let from_forwarded = req.header("Forwarded").and_then(|values| {
+        values.last().as_str().split([';', ',']).find_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            key.trim()
+                .eq_ignore_ascii_case("proto")
+                .then(|| value.trim().trim_matches('"').to_string())
+        })
+    });
+    from_forwarded
+        .or_else(|| {
+            req.header("X-Forwarded-Proto")
+                .and_then(|values| values.last().as_str().split(',').next())
+                .map(|proto| proto.trim().to_string())
+        })
+        .map(|proto| proto.to_ascii_lowercase())
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ForwardedHeadersMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let peer_addr = req.peer_addr().map(str::to_string);
+        if self.is_trusted(peer_addr.as_deref()) {
+            if let Some(client) = self.resolve_client(&forwarded_hops(&req)) {
+                req.set_ext(ClientAddr(client));
+            } else if let Some(peer_addr) = peer_addr {
+                req.set_ext(ClientAddr(peer_addr));
+            }
+            if let Some(proto) = forwarded_proto(&req) {
+                if proto == "http" || proto == "https" {
+                    let http_req: &mut tide::http::Request = req.as_mut();
+                    let _ = http_req.url_mut().set_scheme(&proto);
+                }
+            }
+        } else {
+            // tide uses them in host() and remote(), drop them before anything reads them
+            for name in FORWARDED_HEADERS {
+                req.remove_header(name);
+            }
+            if let Some(peer_addr) = peer_addr {
+                req.set_ext(ClientAddr(peer_addr));
+            }
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tide::http::{Method, Url};
+
+    #[test]
+    fn test_ip_network() {
+        let network = IpNetwork::parse("10.1.0.0/16").unwrap();
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("10.2.0.1".parse().unwrap()));
+
+        let network = IpNetwork::parse("127.0.0.1").unwrap();
+        assert!(network.contains("127.0.0.1".parse().unwrap()));
+        assert!(!network.contains("127.0.0.2".parse().unwrap()));
+
+        let network = IpNetwork::parse("fd00::/8").unwrap();
+        assert!(network.contains("fd12::1".parse().unwrap()));
+        assert!(!network.contains("fe80::1".parse().unwrap()));
+
+        assert!(IpNetwork::parse("0.0.0.0/0")
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap()));
+        assert_eq!(IpNetwork::parse("10.0.0.0/33"), None);
+        assert_eq!(IpNetwork::parse("localhost"), None);
+    }
+
+    async fn describe_request_with(peer_addr: &str, headers: &[(&str, &str)]) -> String {
+        let mut app = tide::new();
+        app.with(ForwardedHeadersMiddleware::new(&["10.0.0.0/8".to_string()]));
+        app.at("/").get(|req: Request<()>| async move {
+            Ok(client_addr(&req).unwrap_or("-").to_string())
+        });
+
+        let mut req = tide::http::Request::new(Method::Get, Url::parse("http://1ts.dev/").unwrap());
+        req.set_peer_addr(Some(peer_addr));
+        for (name, value) in headers {
+            req.append_header(*name, *value);
+        }
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        res.take_body().into_string().await.unwrap()
+    }
+
+    async fn describe_request(peer_addr: &str) -> String {
+        let mut app = tide::new();
+        app.with(ForwardedHeadersMiddleware::new(&["10.0.0.0/8".to_string()]));
+        app.at("/").get(|req: Request<()>| async move {
+            Ok(format!(
+                "{}://{} {}",
+                req.url().scheme(),
+                req.host().unwrap_or("-"),
+                client_addr(&req).unwrap_or("-")
+            ))
+        });
+
+        let mut req = tide::http::Request::new(Method::Get, Url::parse("http://1ts.dev/").unwrap());
+        req.set_peer_addr(Some(peer_addr));
+        req.insert_header("X-Forwarded-Host", "share.example.com");
+        req.insert_header("X-Forwarded-Proto", "https");
+        req.insert_header("X-Forwarded-For", "203.0.113.7");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        res.take_body().into_string().await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_forwarded_headers_from_trusted_proxy() {
+        assert_eq!(
+            describe_request("10.0.0.5:40000").await,
+            "https://share.example.com 203.0.113.7"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_client_address_skips_only_trusted_hops() {
+        // the client wrote the first entry itself, the proxy appended the real address
+        assert_eq!(
+            describe_request_with(
+                "10.0.0.5:40000",
+                &[("X-Forwarded-For", "10.8.0.1, 203.0.113.7")]
+            )
+            .await,
+            "203.0.113.7"
+        );
+        // a chain of trusted proxies is walked through
+        assert_eq!(
+            describe_request_with(
+                "10.0.0.5:40000",
+                &[
+                    ("X-Forwarded-For", "1.2.3.4, 203.0.113.7"),
+                    ("X-Forwarded-For", "10.0.0.9")
+                ]
+            )
+            .await,
+            "203.0.113.7"
+        );
+        assert_eq!(
+            describe_request_with(
+                "10.0.0.5:40000",
+                &[(
+                    "Forwarded",
+                    "for=10.8.0.1, for=\"[2001:db8::7]:4711\";proto=https"
+                )]
+            )
+            .await,
+            "2001:db8::7"
+        );
+        assert_eq!(
+            describe_request_with("10.0.0.5:40000", &[]).await,
+            "10.0.0.5:40000"
+        );
+        // an untrusted peer is the client, whatever it sends
+        assert_eq!(
+            describe_request_with("198.51.100.1:40000", &[("X-Forwarded-For", "10.8.0.1")]).await,
+            "198.51.100.1:40000"
+        );
+    }
+
+    #[test]
+    fn test_is_trusted() {
+        let middleware = ForwardedHeadersMiddleware::new(&["unix".to_string()]);
+        assert!(middleware.is_trusted(Some(UNIX_PEER_ADDR)));
+        assert!(!middleware.is_trusted(Some("10.0.0.5:40000")));
+        assert!(!middleware.is_trusted(None));
+
+        let middleware = ForwardedHeadersMiddleware::new(&["10.0.0.5".to_string()]);
+        assert!(!middleware.is_trusted(Some(UNIX_PEER_ADDR)));
+        assert!(middleware.is_trusted(Some("10.0.0.5:40000")));
+    }
+
+    #[async_std::test]
+    async fn test_forwarded_headers_from_untrusted_client() {
+        assert_eq!(
+            describe_request("198.51.100.1:40000").await,
+            "http://1ts.dev 198.51.100.1:40000"
+        );
+    }
+}
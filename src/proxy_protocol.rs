@@ -0,0 +1,161 @@
+use async_std::io::{Read, ReadExt};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V2_COMMAND_LOCAL: u8 = 0x0;
+const V2_COMMAND_PROXY: u8 = 0x1;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+
+fn invalid_header(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "TCP4" | "TCP6", source_ip, _, source_port, _] => {
+            let ip: IpAddr = source_ip
+                .parse()
+                .map_err(|_| invalid_header("Invalid source address in PROXY header"))?;
+            let port: u16 = source_port
+                .parse()
+                .map_err(|_| invalid_header("Invalid source port in PROXY header"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        // the proxy doesn't know the client, e.g. for its own health checks
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        _ => Err(invalid_header("Invalid PROXY header")),
+    }
+}
+
+fn parse_v2_addresses(family: u8, addresses: &[u8]) -> Option<SocketAddr> {
+    match family {
+        V2_FAMILY_TCP4 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        V2_FAMILY_TCP6 if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+        _ => None,
+    }
+}
+
+// reads exactly the PROXY header (v1 or v2) and nothing after it, returns the address
+// of the client if the proxy provided one
+pub async fn read_header<R: Read + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut first_byte = [0u8; 1];
+    stream.read_exact(&mut first_byte).await?;
+
+    if first_byte[0] == V1_PREFIX[0] {
+        let mut line = first_byte.to_vec();
+        // the line is short, reading it byte by byte keeps the rest of the stream intact
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(invalid_header("PROXY header is too long"));
+            }
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).await?;
+            line.push(byte[0]);
+        }
+        if !line.starts_with(V1_PREFIX) {
+            return Err(invalid_header("Invalid PROXY header"));
+        }
+        let line = std::str::from_utf8(&line[..line.len() - 2])
+            .map_err(|_| invalid_header("Invalid PROXY header"))?;
+        return parse_v1(line);
+    }
+
+    if first_byte[0] == V2_SIGNATURE[0] {
+        let mut header = [0u8; 16];
+        header[0] = first_byte[0];
+        stream.read_exact(&mut header[1..]).await?;
+        if header[..12] != V2_SIGNATURE || header[12] >> 4 != 2 {
+            return Err(invalid_header("Invalid PROXY header"));
+        }
+        let command = header[12] & 0x0f;
+        let family = header[13];
+        let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+        // the addresses may be followed by TLVs that we don't need, but still have to consume
+        let mut addresses = vec![0u8; length];
+        stream.read_exact(&mut addresses).await?;
+        return match command {
+            V2_COMMAND_LOCAL => Ok(None),
+            V2_COMMAND_PROXY => Ok(parse_v2_addresses(family, &addresses)),
+            _ => Err(invalid_header("Unsupported PROXY command")),
+        };
+    }
+
+    Err(invalid_header(
+        "Connection didn't start with a PROXY header",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::Cursor;
+
+    async fn read_with_rest(data: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = Cursor::new(data.to_vec());
+        let result = read_header(&mut stream).await;
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        (result, rest)
+    }
+
+    #[async_std::test]
+    async fn test_read_v1_header() {
+        let (result, rest) =
+            read_with_rest(b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\nGET / HTTP/1.1\r\n")
+                .await;
+        assert_eq!(result.unwrap(), Some("203.0.113.7:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let (result, _) = read_with_rest(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n").await;
+        assert_eq!(result.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        let (result, _) = read_with_rest(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[async_std::test]
+    async fn test_read_v2_header() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, V2_FAMILY_TCP4, 0, 15]);
+        data.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        // a TLV that should be skipped
+        data.extend_from_slice(&[0x04, 0, 0]);
+        data.extend_from_slice(b"GET /");
+
+        let (result, rest) = read_with_rest(&data).await;
+        assert_eq!(result.unwrap(), Some("203.0.113.7:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x20, 0, 0, 0]);
+        let (result, _) = read_with_rest(&data).await;
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[async_std::test]
+    async fn test_rejects_connections_without_header() {
+        let (result, _) = read_with_rest(b"GET / HTTP/1.1\r\n").await;
+        assert!(result.is_err());
+
+        let (result, _) = read_with_rest(b"PROXY TCP4 not-an-ip 10.0.0.1 1 2\r\n").await;
+        assert!(result.is_err());
+
+        let (result, _) = read_with_rest(&[b'P'; 200]).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use png::{BitDepth, ColorType, Encoder};
+use qrcode::{Color, EcLevel};
+
+// the modules of light border the readers need around the code
+const QUIET_ZONE: usize = 4;
+
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+}
+
+impl QrCode {
+    // None when the data doesn't fit into the largest version; level M reads fine from a
+    // screen and still fits a long link into a version 10 or so code
+    pub fn encode(data: &[u8]) -> Option<QrCode> {
+        let code = qrcode::QrCode::with_error_correction_level(data, EcLevel::M).ok()?;
+        Some(QrCode {
+            size: code.width(),
+            modules: code
+                .to_colors()
+                .into_iter()
+                .map(|color| color == Color::Dark)
+                .collect(),
+        })
+    }
+
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    // one unit per module, so the picture scales to any size without blurring
+    pub fn to_svg(&self) -> String {
+        let full_size = self.size + QUIET_ZONE * 2;
+        let mut path = String::new();
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if self.is_dark(x, y) {
+                    path.push_str(&format!("M{},{}h1v1h-1z", x + QUIET_ZONE, y + QUIET_ZONE));
+                }
+            }
+        }
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {0} {0}\" shape-rendering=\"crispEdges\">\
+            <rect width=\"{0}\" height=\"{0}\" fill=\"#fff\"/><path d=\"{1}\" fill=\"#000\"/></svg>\n",
+            full_size, path
+        )
+    }
+
+    // a 1 bit grayscale picture with `scale` pixels per module
+    pub fn to_png(&self, scale: usize) -> Vec<u8> {
+        let width = (self.size + QUIET_ZONE * 2) * scale;
+        let row_bytes = width.div_ceil(8);
+        let mut pixels = vec![0u8; row_bytes * width];
+        for y in 0..width {
+            for x in 0..width {
+                if !self.is_dark_pixel(x / scale, y / scale) {
+                    pixels[y * row_bytes + x / 8] |= 0x80 >> (x % 8);
+                }
+            }
+        }
+
+        let mut png = Vec::new();
+        let mut encoder = Encoder::new(&mut png, width as u32, width as u32);
+        encoder.set_color(ColorType::Grayscale);
+        encoder.set_depth(BitDepth::One);
+        // the sizes match the header and the picture goes to memory, so neither step fails
+        let mut writer = encoder.write_header().expect("the PNG header is valid");
+        writer
+            .write_image_data(&pixels)
+            .expect("the pixels fill the PNG");
+        writer.finish().expect("the PNG is complete");
+        png
+    }
+
+    // in the coordinates of the picture with the quiet zone around the code
+    fn is_dark_pixel(&self, x: usize, y: usize) -> bool {
+        let inside = QUIET_ZONE..QUIET_ZONE + self.size;
+        inside.contains(&x) && inside.contains(&y) && self.is_dark(x - QUIET_ZONE, y - QUIET_ZONE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(QrCode::encode(b"hello").unwrap().size, 21);
+        assert_eq!(QrCode::encode(&[b'a'; 14]).unwrap().size, 21);
+        assert_eq!(QrCode::encode(&[b'a'; 15]).unwrap().size, 25);
+        assert!(QrCode::encode(&[b'a'; 3000]).is_none());
+
+        let qr = QrCode::encode(b"https://localhost/shared/0f4c5ad2-5e31-4b4e-b7a6-1f3e7c2a9d10")
+            .unwrap();
+        // the finder pattern corners and the always dark module
+        assert!(qr.is_dark(0, 0) && qr.is_dark(qr.size - 1, 0) && qr.is_dark(0, qr.size - 1));
+        assert!(!qr.is_dark(7, 7));
+        assert!(qr.is_dark(8, qr.size - 8));
+    }
+
+    #[test]
+    fn test_images() {
+        let qr = QrCode::encode(b"hello").unwrap();
+        let svg = qr.to_svg();
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 29 29\""));
+        assert!(svg.contains("M4,4h1v1h-1z"));
+
+        let png = qr.to_png(2);
+        let mut reader = png::Decoder::new(&png[..]).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((frame.width, frame.height), (58, 58));
+        assert_eq!(
+            (frame.color_type, frame.bit_depth),
+            (ColorType::Grayscale, BitDepth::One)
+        );
+        let is_white =
+            |x: usize, y: usize| pixels[y * frame.line_size + x / 8] & (0x80 >> (x % 8)) != 0;
+        // the quiet zone, then the corner of the finder pattern over two pixels
+        assert!(is_white(7, 7));
+        assert!(!is_white(8, 8) && !is_white(9, 9));
+    }
+}
@@ -0,0 +1,325 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tide::http::Method;
+use tide::{Middleware, Next, Request};
+
+use crate::error::AppError;
+use crate::negotiate::{requested_format, Format};
+use crate::proxy::client_addr;
+use crate::throttle::Throttled;
+
+// buckets that are full again carry no information and are dropped after this many clients
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitConfig {
+    // requests that can be made at once
+    pub burst: u32,
+    // how fast the budget refills
+    pub per_minute: u32,
+}
+
+// no value means no limit
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct IpRateLimitConfig {
+    pub create: Option<RateLimitConfig>,
+    pub consume: Option<RateLimitConfig>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct TokenBucketLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl TokenBucketLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        TokenBucketLimiter {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn refill_per_second(&self) -> f64 {
+        self.config.per_minute as f64 / 60.0
+    }
+
+    fn refilled_tokens(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.refill_per_second()).min(self.config.burst as f64)
+    }
+
+    // takes one token for `key`, or tells how long to wait for the next one
+    pub fn check(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(key) {
+            let burst = self.config.burst as f64;
+            buckets.retain(|_, bucket| self.refilled_tokens(bucket, now) < burst);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.config.burst as f64,
+            updated: now,
+        });
+        bucket.tokens = self.refilled_tokens(bucket, now);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let refill_per_second = self.refill_per_second();
+        if refill_per_second <= 0.0 {
+            return Err(Duration::from_secs(60));
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - bucket.tokens) / refill_per_second,
+        ))
+    }
+}
+
+// the port changes with every connection, only the address identifies the client
+pub fn client_ip(remote: &str) -> String {
+    if let Ok(addr) = remote.parse::<SocketAddr>() {
+        return addr.ip().to_string();
+    }
+    match remote.parse::<IpAddr>() {
+        Ok(ip) => ip.to_string(),
+        Err(_) => remote.to_string(),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Budget {
+    Create,
+    Consume,
+}
+
+pub(crate) fn classify(method: Method, path: &str) -> Option<Budget> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        (
+            Method::Post,
+            ["save"]
+            | ["api", "v1", "messages"]
+            | ["api", "v1", "files"]
+            | ["api", "v1", "uploads"]
+            | ["api", "v1", "requests"]
+            | ["api", "v1", "channels"]
+            | ["api", "v1", "shares"]
+            // every registration sends an email
+            | ["api", "v1", "registrations"],
+        ) => Some(Budget::Create),
+        (Method::Post, ["shared", _])
+        | (Method::Post, ["api", "v1", "messages" | "files", _, "consume"])
+        | (Method::Get, ["api", "v1", "messages", _, "meta" | "qr" | "status"])
+        | (Method::Delete, ["api", "v1", "messages", _])
+        | (Method::Get | Method::Post, ["api", "v1", "requests", _])
+        | (Method::Post, ["api", "v1", "channels", _, "consume" | "reply"])
+        | (Method::Get, ["request", _])
+        // every try is a password guess
+        | (Method::Post, ["api", "v1", "admin", "sessions", "ldap" | "basic"])
+        | (Method::Post, ["api", "v1", "registrations", "confirm"])
+        | (Method::Post, ["api", "v1", "invitations", "redeem"]) => {
+            Some(Budget::Consume)
+        }
+        _ => None,
+    }
+}
+
+// the JSON form of the shared page tells whether a message exists, so it's budgeted
+// like the metadata endpoint
+pub(crate) fn classify_request<State>(req: &Request<State>) -> Option<Budget> {
+    let path = req.url().path();
+    match classify(req.method(), path) {
+        None if req.method() == Method::Get
+            && path.starts_with("/shared/")
+            && requested_format(req) == Some(Format::Json) =>
+        {
+            Some(Budget::Consume)
+        }
+        budget => budget,
+    }
+}
+
+pub struct IpRateLimitMiddleware {
+    create: Option<TokenBucketLimiter>,
+    consume: Option<TokenBucketLimiter>,
+}
+
+impl IpRateLimitMiddleware {
+    pub fn new(config: &IpRateLimitConfig) -> Self {
+        IpRateLimitMiddleware {
+            create: config.create.map(TokenBucketLimiter::new),
+            consume: config.consume.map(TokenBucketLimiter::new),
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for IpRateLimitMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let limiter = match classify_request(&req) {
+            Some(Budget::Create) => self.create.as_ref(),
+            Some(Budget::Consume) => self.consume.as_ref(),
+            None => None,
+        };
+        if let Some(limiter) = limiter {
+            let ip = client_ip(client_addr(&req).unwrap_or("-"));
+            if let Err(retry_after) = limiter.check(&ip, Instant::now()) {
+                return Err(AppError::RateLimited(Throttled::new(
+                    "Too many requests",
+                    retry_after,
+                ))
+                .into_error());
+            }
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorResponseMiddleware;
+    use tide::StatusCode;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = TokenBucketLimiter::new(RateLimitConfig {
+            burst: 2,
+            per_minute: 6,
+        });
+        let start = Instant::now();
+
+        assert!(limiter.check("a", start).is_ok());
+        assert!(limiter.check("a", start).is_ok());
+        assert_eq!(limiter.check("a", start), Err(Duration::from_secs(10)));
+        // other clients have their own budget
+        assert!(limiter.check("b", start).is_ok());
+
+        assert!(limiter.check("a", start + Duration::from_secs(10)).is_ok());
+        assert!(limiter.check("a", start + Duration::from_secs(10)).is_err());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        assert_eq!(client_ip("203.0.113.7:40000"), "203.0.113.7");
+        assert_eq!(client_ip("[2001:db8::1]:40000"), "2001:db8::1");
+        assert_eq!(client_ip("203.0.113.7"), "203.0.113.7");
+        assert_eq!(client_ip("unix"), "unix");
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(Method::Post, "/save"), Some(Budget::Create));
+        assert_eq!(
+            classify(Method::Post, "/api/v1/files"),
+            Some(Budget::Create)
+        );
+        assert_eq!(classify(Method::Post, "/shared/abc"), Some(Budget::Consume));
+        assert_eq!(
+            classify(Method::Post, "/api/v1/messages/abc/consume"),
+            Some(Budget::Consume)
+        );
+        assert_eq!(
+            classify(Method::Get, "/api/v1/messages/abc/meta"),
+            Some(Budget::Consume)
+        );
+        assert_eq!(
+            classify(Method::Delete, "/api/v1/messages/abc"),
+            Some(Budget::Consume)
+        );
+        assert_eq!(
+            classify(Method::Post, "/api/v1/requests"),
+            Some(Budget::Create)
+        );
+        assert_eq!(
+            classify(Method::Post, "/api/v1/requests/abc"),
+            Some(Budget::Consume)
+        );
+        assert_eq!(classify(Method::Get, "/request/abc"), Some(Budget::Consume));
+        assert_eq!(
+            classify(Method::Post, "/api/v1/channels/abc/reply"),
+            Some(Budget::Consume)
+        );
+        assert_eq!(
+            classify(Method::Post, "/api/v1/shares"),
+            Some(Budget::Create)
+        );
+        assert_eq!(classify(Method::Post, "/api/v1/shares/combine"), None);
+        assert_eq!(
+            classify(Method::Post, "/api/v1/registrations"),
+            Some(Budget::Create)
+        );
+        assert_eq!(
+            classify(Method::Post, "/api/v1/registrations/confirm"),
+            Some(Budget::Consume)
+        );
+        assert_eq!(
+            classify(Method::Post, "/api/v1/invitations/redeem"),
+            Some(Budget::Consume)
+        );
+        assert_eq!(classify(Method::Get, "/shared/abc"), None);
+        assert_eq!(classify(Method::Get, "/"), None);
+    }
+
+    #[test]
+    fn test_classify_request() {
+        let make_request = |url: &str| -> Request<()> {
+            tide::http::Request::new(Method::Get, tide::http::Url::parse(url).unwrap()).into()
+        };
+        assert_eq!(
+            classify_request(&make_request("http://localhost/shared/abc")),
+            None
+        );
+        assert_eq!(
+            classify_request(&make_request("http://localhost/shared/abc?format=json")),
+            Some(Budget::Consume)
+        );
+    }
+
+    #[async_std::test]
+    async fn test_middleware_limits_by_ip() {
+        let mut app = tide::new();
+        app.with(ErrorResponseMiddleware);
+        app.with(IpRateLimitMiddleware::new(&IpRateLimitConfig {
+            create: Some(RateLimitConfig {
+                burst: 1,
+                per_minute: 1,
+            }),
+            consume: None,
+        }));
+        app.at("/save").post(|_| async { Ok("saved") });
+
+        let send = |peer_addr: &str| {
+            let mut req = tide::http::Request::new(
+                Method::Post,
+                tide::http::Url::parse("http://localhost/save").unwrap(),
+            );
+            req.set_peer_addr(Some(peer_addr));
+            app.respond::<_, tide::http::Response>(req)
+        };
+
+        assert_eq!(
+            send("10.0.0.1:1000").await.unwrap().status(),
+            StatusCode::Ok
+        );
+        let res = send("10.0.0.1:1001").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+        assert_eq!(res["Retry-After"], "60");
+        assert_eq!(
+            send("10.0.0.2:1000").await.unwrap().status(),
+            StatusCode::Ok
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use tide::Request;
+
+use crate::proxy::client_addr;
+use crate::rate_limit::client_ip;
+
+// enough to tell one browser from another, not enough to fingerprint it
+const MAX_USER_AGENT_CHARS: usize = 64;
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ReadReceiptsConfig {
+    // keeps the time a message with a delete token was read, for its creator
+    pub enabled: bool,
+    // adds the network of the reader and the start of their user agent
+    pub record_client: bool,
+}
+
+// who read a message, as far as the receipt tells it
+pub struct Reader {
+    pub ip: String,
+    pub user_agent: Option<String>,
+}
+
+impl Reader {
+    pub fn of<State>(req: &Request<State>) -> Self {
+        Reader {
+            ip: client_ip(client_addr(req).unwrap_or("-")),
+            user_agent: req
+                .header("User-Agent")
+                .map(|values| values.last().as_str().to_string()),
+        }
+    }
+}
+
+// the /24 of an IPv4 address and the /48 of an IPv6 one, the network tells the creator
+// enough about who read the message
+pub fn truncate_ip(ip: &str) -> Option<String> {
+    match ip.parse::<IpAddr>().ok()? {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Some(format!("{}.{}.{}.0", a, b, c))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            Some(format!(
+                "{:x}:{:x}:{:x}::",
+                segments[0], segments[1], segments[2]
+            ))
+        }
+    }
+}
+
+pub fn truncate_user_agent(user_agent: &str) -> String {
+    user_agent.chars().take(MAX_USER_AGENT_CHARS).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate_ip("203.0.113.7").unwrap(), "203.0.113.0");
+        assert_eq!(
+            truncate_ip("2001:db8:1234:5678::1").unwrap(),
+            "2001:db8:1234::"
+        );
+        assert!(truncate_ip("-").is_none());
+
+        assert_eq!(truncate_user_agent("curl/8.0"), "curl/8.0");
+        assert_eq!(truncate_user_agent(&"ä".repeat(100)).chars().count(), 64);
+    }
+}
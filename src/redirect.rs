@@ -0,0 +1,91 @@
+use tide::{Request, Response, StatusCode};
+
+use crate::host_allowlist::{host_name, HostAllowlistMiddleware};
+
+const DEFAULT_HTTPS_PORT: &str = "443";
+
+#[derive(Clone)]
+pub struct RedirectState {
+    https_port: String,
+}
+
+fn make_https_url(host: &str, https_port: &str, path_and_query: &str) -> String {
+    // drop the port of the plain HTTP listener
+    let host_name = host_name(host);
+    if https_port == DEFAULT_HTTPS_PORT {
+        format!("https://{}{}", host_name, path_and_query)
+    } else {
+        format!("https://{}:{}{}", host_name, https_port, path_and_query)
+    }
+}
+
+async fn redirect_to_https(req: Request<RedirectState>) -> tide::Result {
+    let host = match req.host() {
+        Some(host) => host.to_string(),
+        None => {
+            return Ok(Response::builder(StatusCode::BadRequest)
+                .body("Host header is missing")
+                .build())
+        }
+    };
+    let path_and_query = match req.url().query() {
+        Some(query) => format!("{}?{}", req.url().path(), query),
+        None => req.url().path().to_string(),
+    };
+
+    Ok(Response::builder(StatusCode::MovedPermanently)
+        .header(
+            "Location",
+            make_https_url(&host, &req.state().https_port, &path_and_query),
+        )
+        .build())
+}
+
+// a plain HTTP app that sends every request to the HTTPS origin
+pub fn make_redirect_app(
+    https_port: &str,
+    allowed_hosts: &[String],
+) -> tide::Server<RedirectState> {
+    let mut app = tide::with_state(RedirectState {
+        https_port: https_port.to_string(),
+    });
+    // otherwise the redirect could send the client to any host
+    app.with(HostAllowlistMiddleware::new(allowed_hosts));
+    app.at("/").all(redirect_to_https);
+    app.at("*").all(redirect_to_https);
+    app
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tide::http::{Method, Url};
+
+    #[test]
+    fn test_make_https_url() {
+        assert_eq!(
+            make_https_url("1ts.dev", "443", "/shared/abc"),
+            "https://1ts.dev/shared/abc"
+        );
+        assert_eq!(
+            make_https_url("1ts.dev:8081", "8443", "/?a=b"),
+            "https://1ts.dev:8443/?a=b"
+        );
+        assert_eq!(make_https_url("[::1]:8081", "443", "/"), "https://[::1]/");
+        assert_eq!(make_https_url("[::1]", "443", "/"), "https://[::1]/");
+    }
+
+    #[async_std::test]
+    async fn test_redirect_to_https() {
+        let app = make_redirect_app("8443", &[]);
+
+        let req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://localhost:8080/shared/abc?x=1").unwrap(),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::MovedPermanently);
+        assert_eq!(res["Location"], "https://localhost:8443/shared/abc?x=1");
+    }
+}
@@ -0,0 +1,339 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tide::{Body, Request, Response, StatusCode};
+use zeroize::Zeroizing;
+
+use crate::email::is_valid_address;
+use crate::error::AppError;
+use crate::portal::{limits_of_plan, now_timestamp, FIRST_KEY_NAME, RANDOM_BYTES};
+use crate::tokens::random_base64url;
+use crate::{csp, StaticData};
+
+// the accounts of the registered people are portal accounts of this issuer, by their address
+pub const REGISTRATION_ISSUER: &str = "registration";
+const DEFAULT_LINK_TTL_MINUTES: u64 = 24 * 60;
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationConfig {
+    // e.g. ["example.com"], anyone with an email address can register when empty
+    #[serde(default)]
+    pub allowed_email_domains: Vec<String>,
+    // the plan of the registered people's tokens, the default limits when not set
+    pub plan: Option<String>,
+    // how long the confirmation link works, 24 hours by default
+    pub link_ttl_minutes: Option<u64>,
+}
+
+impl RegistrationConfig {
+    pub fn is_allowed(&self, email: &str) -> bool {
+        if self.allowed_email_domains.is_empty() {
+            return true;
+        }
+        email.rsplit_once('@').is_some_and(|(_, domain)| {
+            self.allowed_email_domains
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(domain))
+        })
+    }
+
+    fn link_ttl_seconds(&self) -> i64 {
+        (self.link_ttl_minutes.unwrap_or(DEFAULT_LINK_TTL_MINUTES) * 60) as i64
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RegistrationRequest {
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConfirmRegistrationRequest {
+    // the token from the confirmation link
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RegisteredUser {
+    pub email: String,
+    pub plan: Option<String>,
+    // it can't be shown again
+    pub user_token: String,
+}
+
+fn require_registration(data: &StaticData) -> tide::Result<RegistrationConfig> {
+    data.config
+        .registration
+        .clone()
+        .ok_or_else(|| AppError::NotFound("Not found".to_string()).into_error())
+}
+
+fn invalid_link() -> tide::Error {
+    AppError::NotFound("The link has expired or was used already, register again".to_string())
+        .into_error()
+}
+
+pub async fn register_page(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    require_registration(&data)?;
+    let html = String::from_utf8(data.register_html.clone())?;
+    Ok(csp::html_response(
+        &data.config.content_security_policy,
+        &html,
+    ))
+}
+
+// emails the confirmation link, the answer is the same whether the address has an account
+// or not
+pub async fn start_registration(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let form: Result<RegistrationRequest, _> = req.body_json().await;
+
+    let data = req.state().lock().unwrap();
+    let config = require_registration(&data)?;
+    let form = form
+        .map_err(|_| AppError::BadRequest("Can't parse request body".to_string()).into_error())?;
+    let email = form.email.trim().to_lowercase();
+    if !is_valid_address(&email) {
+        return Err(AppError::Invalid {
+            field: "email",
+            message: "Expected an email address".to_string(),
+        }
+        .into_error());
+    }
+    if !config.is_allowed(&email) {
+        return Err(
+            AppError::Forbidden("Addresses of this domain can't register".to_string()).into_error(),
+        );
+    }
+    // the startup checks make sure these are set when registration is
+    let (email_sender, token_signer, public_base_url) = match (
+        &data.email,
+        &data.token_signer,
+        &data.config.public_base_url,
+    ) {
+        (Some(email_sender), Some(token_signer), Some(public_base_url)) => {
+            (email_sender, token_signer, public_base_url)
+        }
+        _ => return Err(AppError::NotFound("Not found".to_string()).into_error()),
+    };
+
+    let token = Zeroizing::new(random_base64url(RANDOM_BYTES));
+    let timestamp = now_timestamp()?;
+    let expire_timestamp = timestamp + config.link_ttl_seconds();
+    data.database
+        .lock()
+        .unwrap()
+        .add_registration(&token, &email, expire_timestamp, timestamp)?;
+    // the token is in the fragment, so it never reaches a server log, and the page asks
+    // before it's used, so a mail scanner that opens the link doesn't use it up; the base
+    // URL comes from the config, a Host header could send the link elsewhere
+    let link = format!(
+        "{}/register#confirm={}",
+        public_base_url.trim_end_matches('/'),
+        token_signer.sign(&token)
+    );
+    email_sender.send_registration_link(&email, &link, expire_timestamp, timestamp);
+    Ok(Response::new(StatusCode::Accepted))
+}
+
+// the link gives the first token of the address, the same address can't register twice
+pub async fn confirm_registration(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let form: Result<ConfirmRegistrationRequest, _> = req.body_json().await;
+
+    let data = req.state().lock().unwrap();
+    let config = require_registration(&data)?;
+    let form = form
+        .map_err(|_| AppError::BadRequest("Can't parse request body".to_string()).into_error())?;
+    // a forged token is answered like an unknown one
+    let token = data
+        .token_signer
+        .as_ref()
+        .and_then(|token_signer| token_signer.unsign(&form.token))
+        .ok_or_else(invalid_link)?;
+
+    let timestamp = now_timestamp()?;
+    let database = data.database.lock().unwrap();
+    let email = database
+        .take_registration(token, timestamp)?
+        .ok_or_else(invalid_link)?;
+    let account_id =
+        database.sign_in_portal_account(REGISTRATION_ISSUER, &email, Some(&email), timestamp)?;
+    if !database.list_api_keys(account_id)?.is_empty() {
+        return Err(
+            AppError::Conflict("This address has a user token already".to_string()).into_error(),
+        );
+    }
+    let user_token = Zeroizing::new(random_base64url(RANDOM_BYTES));
+    let plan = config.plan;
+    database.create_api_key(
+        account_id,
+        &user_token,
+        FIRST_KEY_NAME,
+        &limits_of_plan(&data, plan.as_deref()),
+        plan.as_deref(),
+        timestamp,
+    )?;
+    log::info!("Registered account {} got its user token", account_id);
+    Ok(Response::builder(StatusCode::Created)
+        .body(Body::from_json(&RegisteredUser {
+            email,
+            plan,
+            user_token: user_token.to_string(),
+        })?)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::tests::{make_sender, receive_email};
+    use crate::tests::setup_test_data;
+    use crate::tokens::TokenSigner;
+    use async_std::net::TcpListener;
+    use tide::http::{Method, Url};
+
+    fn make_config() -> RegistrationConfig {
+        RegistrationConfig {
+            allowed_email_domains: vec!["Example.com".to_string()],
+            plan: None,
+            link_ttl_minutes: None,
+        }
+    }
+
+    fn make_request(path: &str, body: serde_json::Value) -> tide::http::Request {
+        let mut req = tide::http::Request::new(
+            Method::Post,
+            Url::parse(&format!("http://localhost{}", path)).unwrap(),
+        );
+        req.set_body(Body::from_json(&body).unwrap());
+        req
+    }
+
+    async fn setup_registration(listener: &TcpListener) -> tide::Server<Arc<Mutex<StaticData>>> {
+        let app_data = setup_test_data();
+        {
+            let mut data = app_data.lock().unwrap();
+            data.config.registration = Some(make_config());
+            data.config.public_base_url = Some("https://1ts.dev/".to_string());
+            data.token_signer = Some(Arc::new(TokenSigner::random()));
+            data.email = Some(make_sender(listener.local_addr().unwrap().port()));
+        }
+        crate::init_app(app_data)
+    }
+
+    async fn confirm(
+        app: &tide::Server<Arc<Mutex<StaticData>>>,
+        token: &str,
+    ) -> tide::http::Response {
+        app.respond(make_request(
+            "/api/v1/registrations/confirm",
+            serde_json::json!({ "token": token }),
+        ))
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn test_allowed_domains() {
+        let config = make_config();
+        assert!(config.is_allowed("alice@example.com"));
+        assert!(!config.is_allowed("alice@example.com.evil.test"));
+        assert!(RegistrationConfig::default().is_allowed("alice@anything.test"));
+    }
+
+    #[async_std::test]
+    async fn test_registration_is_disabled_by_default() {
+        let app = crate::init_app(setup_test_data());
+        let req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://localhost/register").unwrap(),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+        let res: tide::http::Response = app
+            .respond(make_request(
+                "/api/v1/registrations",
+                serde_json::json!({ "email": "alice@example.com" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn test_register_and_confirm() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let app = setup_registration(&listener).await;
+
+        let res: tide::http::Response = app
+            .respond(make_request(
+                "/api/v1/registrations",
+                serde_json::json!({ "email": "alice@other.test" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Forbidden);
+
+        let res: tide::http::Response = app
+            .respond(make_request(
+                "/api/v1/registrations",
+                serde_json::json!({ "email": " Alice@Example.com " }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Accepted);
+        let received = receive_email(&listener).await;
+        assert!(received
+            .commands
+            .contains(&"RCPT TO:<alice@example.com>".to_string()));
+        let token = received
+            .data
+            .lines()
+            .find_map(|line| line.strip_prefix("https://1ts.dev/register#confirm="))
+            .unwrap()
+            .to_string();
+
+        // a token without the right signature is refused before the lookup
+        let res = confirm(&app, &format!("{}x", token)).await;
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let mut res = confirm(&app, &token).await;
+        assert_eq!(res.status(), StatusCode::Created);
+        let registered: RegisteredUser = res.take_body().into_json().await.unwrap();
+        assert_eq!(registered.email, "alice@example.com");
+        assert_eq!(registered.plan, None);
+
+        // the token works like any user token
+        let res: tide::http::Response = app
+            .respond(make_request(
+                "/api/v1/quota",
+                serde_json::json!({ "user_token": registered.user_token }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        // the link works once
+        let res = confirm(&app, &token).await;
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        // and the address gets no second token
+        let res: tide::http::Response = app
+            .respond(make_request(
+                "/api/v1/registrations",
+                serde_json::json!({ "email": "alice@example.com" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Accepted);
+        let received = receive_email(&listener).await;
+        let token = received
+            .data
+            .lines()
+            .find_map(|line| line.strip_prefix("https://1ts.dev/register#confirm="))
+            .unwrap()
+            .to_string();
+        let res = confirm(&app, &token).await;
+        assert_eq!(res.status(), StatusCode::Conflict);
+    }
+}
@@ -0,0 +1,85 @@
+use tide::{Body, Middleware, Next, Request};
+use uuid::Uuid;
+
+use crate::api::ErrorResponse;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+// stored in the request extensions, so handlers and other middleware can use it
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+// an incoming id ends up in the logs, so it's accepted only if it can't break the log format
+fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LENGTH
+        && request_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+pub struct RequestIdMiddleware;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RequestIdMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let request_id = req
+            .header(REQUEST_ID_HEADER)
+            .map(|values| values.last().as_str().to_string())
+            .filter(|request_id| is_valid_request_id(request_id))
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        req.set_ext(RequestId(request_id.clone()));
+
+        let mut res = next.run(req).await;
+
+        if res.status().is_client_error() || res.status().is_server_error() {
+            let error_response = if res.is_empty() == Some(true) {
+                // errors returned from handlers come without a body
+                Some(ErrorResponse {
+                    error: res.status().canonical_reason().to_string(),
+                    field: None,
+                    retry_after_seconds: None,
+                    not_before: None,
+                    request_id: None,
+                })
+            } else if res
+                .content_type()
+                .is_some_and(|mime| mime.essence() == "application/json")
+            {
+                let body = res.take_body().into_bytes().await?;
+                match serde_json::from_slice::<ErrorResponse>(&body) {
+                    Ok(error_response) => Some(error_response),
+                    Err(_) => {
+                        res.set_body(body);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            if let Some(mut error_response) = error_response {
+                error_response.request_id = Some(request_id.clone());
+                res.set_body(Body::from_json(&error_response)?);
+            }
+        }
+
+        res.insert_header(REQUEST_ID_HEADER, request_id);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_request_id() {
+        assert!(is_valid_request_id("3f2b6c1e-8d4a-4c1b-9a7e-2b5d8f0c1a2e"));
+        assert!(is_valid_request_id("req_42.retry"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("id with spaces"));
+        assert!(!is_valid_request_id("id\"injected"));
+        assert!(!is_valid_request_id(&"a".repeat(65)));
+    }
+}
@@ -0,0 +1,243 @@
+use brotli::CompressorWriter;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{self, Write};
+use tide::http::Mime;
+use tide::{Middleware, Next, Request};
+
+use crate::binary_format::accept_quality;
+
+// below this the headers of the stream and the lost bytes of the last block eat most of the gain
+const MIN_COMPRESSED_BODY_BYTES: usize = 1024;
+// the answers are compressed for each request, the higher levels take much longer for little gain
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_BITS: u32 = 22;
+const BROTLI_BUFFER_BYTES: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut writer = CompressorWriter::new(
+                    Vec::new(),
+                    BROTLI_BUFFER_BYTES,
+                    BROTLI_QUALITY,
+                    BROTLI_WINDOW_BITS,
+                );
+                writer.write_all(data)?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+// a coding the client doesn't list gets the quality of "*"
+fn coding_quality(accept_encoding: &str, coding: &str) -> f32 {
+    let listed = accept_encoding.split(',').any(|entry| {
+        entry
+            .split(';')
+            .next()
+            .is_some_and(|listed_coding| listed_coding.trim().eq_ignore_ascii_case(coding))
+    });
+    accept_quality(accept_encoding, if listed { coding } else { "*" })
+}
+
+// brotli makes the smaller answers, so it wins when the client likes both the same
+fn negotiate_encoding(accept_encoding: &str) -> Option<Encoding> {
+    [Encoding::Brotli, Encoding::Gzip]
+        .into_iter()
+        .map(|encoding| (encoding, coding_quality(accept_encoding, encoding.as_str())))
+        .filter(|(_, quality)| *quality > 0.0)
+        .fold(None, |best, (encoding, quality)| match best {
+            Some((_, best_quality)) if best_quality >= quality => best,
+            _ => Some((encoding, quality)),
+        })
+        .map(|(encoding, _)| encoding)
+}
+
+// the pages and the API answers, the downloads are sent as they were uploaded
+fn is_compressible(mime: &Mime) -> bool {
+    let essence = mime.essence();
+    mime.basetype() == "text"
+        || mime.subtype().ends_with("+json")
+        || matches!(
+            essence,
+            "application/json" | "application/javascript" | "application/xml" | "image/svg+xml"
+        )
+}
+
+pub struct ResponseCompressionMiddleware;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ResponseCompressionMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let encoding = req
+            .header("Accept-Encoding")
+            .and_then(|values| negotiate_encoding(values.last().as_str()));
+
+        let mut res = next.run(req).await;
+        // the content type is on the body until the response is sent
+        let body = res.take_body();
+        let mime = res.content_type().unwrap_or_else(|| body.mime().clone());
+        let is_download = res.header("Content-Disposition").is_some()
+            || res.header("Content-Range").is_some()
+            || res.header("Content-Encoding").is_some();
+        if !is_compressible(&mime) || is_download {
+            res.set_body(body);
+            return Ok(res);
+        }
+        res.append_header("Vary", "Accept-Encoding");
+        let encoding = match encoding {
+            Some(encoding)
+                if body
+                    .len()
+                    .is_some_and(|len| len >= MIN_COMPRESSED_BODY_BYTES) =>
+            {
+                encoding
+            }
+            _ => {
+                res.set_body(body);
+                return Ok(res);
+            }
+        };
+
+        let body = body.into_bytes().await?;
+        let compressed = encoding.compress(&body)?;
+        if compressed.len() < body.len() {
+            res.set_body(compressed);
+            res.insert_header("Content-Encoding", encoding.as_str());
+        } else {
+            res.set_body(body);
+        }
+        res.set_content_type(mime);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use brotli::Decompressor;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use tide::http::{Method, Url};
+    use tide::Response;
+
+    fn page() -> String {
+        "<p>Hello world</p>\n".repeat(100)
+    }
+
+    async fn respond_to(path: &str, accept_encoding: Option<&str>) -> tide::http::Response {
+        let mut app = tide::new();
+        app.with(ResponseCompressionMiddleware);
+        app.at("/page").get(|_| async {
+            Ok(Response::builder(200)
+                .body(page())
+                .content_type(tide::http::mime::HTML)
+                .build())
+        });
+        app.at("/short").get(|_| async { Ok("Hello") });
+        app.at("/file").get(|_| async {
+            Ok(Response::builder(200)
+                .body(page())
+                .content_type(tide::http::mime::PLAIN)
+                .header("Content-Disposition", "attachment; filename=\"page.txt\"")
+                .build())
+        });
+        let mut req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://localhost/").unwrap().join(path).unwrap(),
+        );
+        if let Some(accept_encoding) = accept_encoding {
+            req.insert_header("Accept-Encoding", accept_encoding);
+        }
+        app.respond(req).await.unwrap()
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        assert_eq!(
+            negotiate_encoding("gzip, deflate, br"),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            negotiate_encoding("br;q=0.5, GZIP;q=1.0"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate_encoding("gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate_encoding("*"), Some(Encoding::Brotli));
+        assert_eq!(negotiate_encoding("br;q=0, *"), Some(Encoding::Gzip));
+        assert_eq!(negotiate_encoding("deflate, identity"), None);
+        assert_eq!(negotiate_encoding("*;q=0"), None);
+    }
+
+    #[async_std::test]
+    async fn test_page_is_compressed_with_gzip() {
+        let mut res = respond_to("page", Some("gzip, deflate")).await;
+
+        assert_eq!(res["Content-Encoding"], "gzip");
+        assert_eq!(res["Vary"], "Accept-Encoding");
+        assert_eq!(res.content_type().unwrap().essence(), "text/html");
+        let body = res.body_bytes().await.unwrap();
+        assert!(body.len() < page().len());
+        let mut decoded = String::new();
+        GzDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, page());
+    }
+
+    #[async_std::test]
+    async fn test_page_is_compressed_with_brotli() {
+        let mut res = respond_to("page", Some("gzip, br")).await;
+
+        assert_eq!(res["Content-Encoding"], "br");
+        assert_eq!(res.content_type().unwrap().essence(), "text/html");
+        let body = res.body_bytes().await.unwrap();
+        assert!(body.len() < page().len());
+        let mut decoded = String::new();
+        Decompressor::new(&body[..], BROTLI_BUFFER_BYTES)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, page());
+    }
+
+    #[async_std::test]
+    async fn test_page_is_not_compressed_without_accept_encoding() {
+        let mut res = respond_to("page", None).await;
+
+        assert!(res.header("Content-Encoding").is_none());
+        assert_eq!(res["Vary"], "Accept-Encoding");
+        assert_eq!(res.body_string().await.unwrap(), page());
+    }
+
+    #[async_std::test]
+    async fn test_short_answers_and_downloads_are_not_compressed() {
+        let mut res = respond_to("short", Some("gzip")).await;
+        assert!(res.header("Content-Encoding").is_none());
+        assert_eq!(res.body_string().await.unwrap(), "Hello");
+
+        let mut res = respond_to("file", Some("gzip")).await;
+        assert!(res.header("Content-Encoding").is_none());
+        assert!(res.header("Vary").is_none());
+        assert_eq!(res.body_string().await.unwrap(), page());
+    }
+}
@@ -0,0 +1,437 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tide::{Body, Request, Response, StatusCode};
+use zeroize::Zeroizing;
+
+use crate::csp;
+use crate::encryption::MessageCipher;
+use crate::error::AppError;
+use crate::store::{MessageOptions, SecretRequest};
+use crate::tokens::TokenConfig;
+use crate::validation::MAX_RETENTION_MINUTES;
+use crate::{
+    get_user_limits, make_bare_share_url, make_base_url, register_message_creation, StaticData,
+};
+
+// a week, or the retention limit of the user when it's shorter
+const DEFAULT_RETENTION_MINUTES: u32 = 7 * 24 * 60;
+const MAX_NOTE_CHARS: usize = 500;
+const REQUEST_TOKEN_PLACEHOLDER: &str = "{{.RequestToken}}";
+// keeps the key of the sealed response token apart from the hash the request is found by
+const SEAL_KEY_CONTEXT: &str = "one-time-share secret request:";
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct SecretRequestForm {
+    pub user_token: String,
+    // minutes the request stays open, and the answer is kept after that
+    pub retention: Option<u32>,
+    // shown to whoever answers, e.g. "the VPN password"
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreatedSecretRequest {
+    // for whoever should send the secret
+    pub url: String,
+    pub request_token: String,
+    // for the requester only, the answer is read with it like any message
+    pub response_url: String,
+    pub response_token: String,
+    pub expire_timestamp: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SecretRequestMeta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    pub expire_timestamp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size_bytes: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct SecretAnswer {
+    pub message_data: Zeroizing<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AnsweredSecretRequest {
+    pub expire_timestamp: i64,
+}
+
+fn cipher_for(request_token: &str) -> tide::Result<MessageCipher> {
+    let key = Sha256::digest(format!("{}{}", SEAL_KEY_CONTEXT, request_token).as_bytes());
+    Ok(MessageCipher::from_key(&key)?)
+}
+
+// the request token is the key, the database only keeps its hash
+pub(crate) fn seal_token(request_token: &str, token: &str) -> tide::Result<Vec<u8>> {
+    Ok(cipher_for(request_token)?.encrypt(token.as_bytes())?)
+}
+
+pub(crate) fn unseal_token(request_token: &str, sealed: &[u8]) -> tide::Result<String> {
+    let token = cipher_for(request_token)?.decrypt(sealed)?;
+    Ok(String::from_utf8(token)?)
+}
+
+pub(crate) fn now() -> tide::Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+}
+
+pub(crate) fn not_found() -> tide::Error {
+    AppError::NotFound("Request not found".to_string()).into_error()
+}
+
+// a forged token is answered like an unknown one
+pub(crate) fn find_request(data: &StaticData, request_token: &str) -> tide::Result<SecretRequest> {
+    if let Some(token_signer) = &data.token_signer {
+        if !token_signer.verify(request_token) {
+            return Err(not_found());
+        }
+    }
+    data.database
+        .lock()
+        .unwrap()
+        .get_secret_request(request_token, now()?)?
+        .ok_or_else(not_found)
+}
+
+pub(crate) fn sign_token(data: &StaticData, token: String) -> String {
+    match &data.token_signer {
+        Some(token_signer) => token_signer.sign(&token),
+        None => token,
+    }
+}
+
+pub async fn create_secret_request(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let form: SecretRequestForm = req
+        .body_json()
+        .await
+        .map_err(|_| AppError::BadRequest("Can't parse request body".to_string()).into_error())?;
+    let note = form.note.filter(|note| !note.is_empty());
+    if note
+        .as_ref()
+        .is_some_and(|note| note.chars().count() > MAX_NOTE_CHARS)
+    {
+        return Err(AppError::Invalid {
+            field: "note",
+            message: format!("Note can't be longer than {} characters", MAX_NOTE_CHARS),
+        }
+        .into_error());
+    }
+
+    let data = req.state().lock().unwrap();
+    let user_limits = get_user_limits(&data, &form.user_token)?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()).into_error())?;
+    let user_retention_limit_minutes = user_limits.retention_limit_minutes;
+    let retention = match form.retention {
+        Some(retention) if retention > 0 => retention,
+        _ if user_retention_limit_minutes > 0 => {
+            DEFAULT_RETENTION_MINUTES.min(user_retention_limit_minutes)
+        }
+        _ => DEFAULT_RETENTION_MINUTES,
+    };
+    if retention > MAX_RETENTION_MINUTES
+        || (user_retention_limit_minutes > 0 && retention > user_retention_limit_minutes)
+    {
+        return Err(AppError::BadRequest(
+            "Requested retention limit is bigger than allowed".to_string(),
+        )
+        .into_error());
+    }
+
+    // a request takes a slot of the creation window like a message does
+    let now = now()?;
+    register_message_creation(&data, &form.user_token, &user_limits, now)?;
+
+    let request_token = sign_token(&data, data.config.message_tokens.generate());
+    // always a UUID, it's never typed in
+    let response_token = sign_token(&data, TokenConfig::default().generate());
+    let expire_timestamp = now + retention as i64 * 60;
+    data.database.lock().unwrap().save_secret_request(
+        &request_token,
+        &SecretRequest {
+            note,
+            expire_timestamp,
+            max_size_bytes: user_limits.max_message_size_bytes,
+            retention_minutes: retention,
+            sealed_response_token: seal_token(&request_token, &response_token)?,
+            sealed_message_token: None,
+        },
+    )?;
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&CreatedSecretRequest {
+            url: format!(
+                "{}/request/{}",
+                make_base_url(&req, &data.config),
+                request_token
+            ),
+            request_token,
+            response_url: make_bare_share_url(&req, &data.config, &response_token),
+            response_token,
+            expire_timestamp,
+        })?)
+        .build())
+}
+
+pub async fn secret_request_meta(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    let request = find_request(&data, req.param("token")?)?;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&SecretRequestMeta {
+            note: request.note,
+            expire_timestamp: request.expire_timestamp,
+            max_size_bytes: (request.max_size_bytes > 0).then_some(request.max_size_bytes),
+        })?)
+        .build())
+}
+
+pub async fn answer_secret_request(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let answer: SecretAnswer = req
+        .body_json()
+        .await
+        .map_err(|_| AppError::BadRequest("Can't parse request body".to_string()).into_error())?;
+    let message_data = match STANDARD.decode(answer.message_data.as_bytes()) {
+        Ok(message_data) if !message_data.is_empty() => Zeroizing::new(message_data),
+        _ => {
+            return Err(AppError::Invalid {
+                field: "message_data",
+                message: "Message should be non-empty base64".to_string(),
+            }
+            .into_error())
+        }
+    };
+
+    let data = req.state().lock().unwrap();
+    let request_token = req.param("token")?;
+    let request = find_request(&data, request_token)?;
+    if request.sealed_message_token.is_some() {
+        return Err(AppError::Conflict(
+            "The secret of the channel has to be read before the reply".to_string(),
+        )
+        .into_error());
+    }
+    if request.max_size_bytes > 0 && message_data.len() > request.max_size_bytes as usize {
+        return Err(AppError::TooLarge("Message is too big".to_string()).into_error());
+    }
+    let response_token = unseal_token(request_token, &request.sealed_response_token)?;
+    // whoever removes the request answers it, a second answer finds it gone
+    if !data
+        .database
+        .lock()
+        .unwrap()
+        .remove_secret_request(request_token)?
+    {
+        return Err(not_found());
+    }
+
+    let expire_timestamp = if request.retention_minutes > 0 {
+        now()? + request.retention_minutes as i64 * 60
+    } else {
+        0
+    };
+    data.database.lock().unwrap().save_message(
+        &response_token,
+        expire_timestamp,
+        &message_data,
+        &MessageOptions {
+            filename: answer.filename.clone(),
+            content_type: answer.content_type.clone(),
+            checksum: Some(format!("{:x}", Sha256::digest(&message_data))),
+            ..Default::default()
+        },
+    )?;
+    log::info!(
+        target: "audit",
+        "Secret request answered message={}",
+        &crate::tokens::hash_token(&response_token)[..16]
+    );
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&AnsweredSecretRequest {
+            expire_timestamp,
+        })?)
+        .build())
+}
+
+// the page only exists for open requests, so the token put in it is a known one
+pub async fn request_page(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    let request_token = req.param("token")?;
+    find_request(&data, request_token)?;
+    let html = String::from_utf8(data.request_html.clone())?
+        .replace(REQUEST_TOKEN_PLACEHOLDER, request_token);
+    Ok(csp::html_response(
+        &data.config.content_security_policy,
+        &html,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ConsumeMessageResponse;
+    use crate::store::UserLimits;
+    use crate::tests::setup_test_data;
+    use tide::http::{Method, Url};
+
+    fn json_request(method: Method, url: &str, body: &impl Serialize) -> tide::http::Request {
+        let mut req = tide::http::Request::new(method, Url::parse(url).unwrap());
+        req.set_body(Body::from_json(body).unwrap());
+        req
+    }
+
+    #[test]
+    fn test_seal_token() {
+        let sealed = seal_token("request1", "response1").unwrap();
+        assert_eq!(unseal_token("request1", &sealed).unwrap(), "response1");
+        assert!(unseal_token("request2", &sealed).is_err());
+    }
+
+    #[async_std::test]
+    async fn test_secret_request() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    max_message_size_bytes: 16,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let mut res: tide::http::Response = app
+            .respond(json_request(
+                Method::Post,
+                "http://localhost/api/v1/requests",
+                &SecretRequestForm {
+                    user_token: "test_token".to_string(),
+                    note: Some("the VPN password".to_string()),
+                    ..Default::default()
+                },
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: CreatedSecretRequest = res.take_body().into_json().await.unwrap();
+        assert_eq!(
+            created.url,
+            format!("http://localhost/request/{}", created.request_token)
+        );
+        assert_eq!(
+            created.response_url,
+            format!("http://localhost/shared/{}", created.response_token)
+        );
+
+        // the answer isn't there before it is sent
+        let consume_url = format!(
+            "http://localhost/api/v1/messages/{}/consume",
+            created.response_token
+        );
+        let consume = || tide::http::Request::new(Method::Post, Url::parse(&consume_url).unwrap());
+        let res: tide::http::Response = app.respond(consume()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let request_url = format!("http://localhost/api/v1/requests/{}", created.request_token);
+        let mut res: tide::http::Response = app
+            .respond(tide::http::Request::new(
+                Method::Get,
+                Url::parse(&request_url).unwrap(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let meta: SecretRequestMeta = res.take_body().into_json().await.unwrap();
+        assert_eq!(meta.note.as_deref(), Some("the VPN password"));
+        assert_eq!(meta.max_size_bytes, Some(16));
+
+        let answer = |message_data: &str| {
+            json_request(
+                Method::Post,
+                &request_url,
+                &SecretAnswer {
+                    message_data: message_data.to_string().into(),
+                    ..Default::default()
+                },
+            )
+        };
+        // 17 bytes
+        let res: tide::http::Response = app
+            .respond(answer("SGVsbG8gd29ybGQsIGFnYWluIQ=="))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+        let res: tide::http::Response = app.respond(answer("aHVudGVyMg==")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        // a request is answered only once
+        let res: tide::http::Response = app.respond(answer("aHVudGVyMg==")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let mut res: tide::http::Response = app.respond(consume()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(*body.message_data, "aHVudGVyMg==");
+    }
+
+    #[async_std::test]
+    async fn test_request_page() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        let sealed = seal_token("request1", "response1").unwrap();
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .save_secret_request(
+                "request1",
+                &SecretRequest {
+                    note: None,
+                    expire_timestamp: now().unwrap() + 60,
+                    max_size_bytes: 0,
+                    retention_minutes: 0,
+                    sealed_response_token: sealed,
+                    sealed_message_token: None,
+                },
+            )
+            .unwrap();
+
+        let mut res: tide::http::Response = app
+            .respond(tide::http::Request::new(
+                Method::Get,
+                Url::parse("http://localhost/request/request1").unwrap(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(res
+            .body_string()
+            .await
+            .unwrap()
+            .contains("Request page with token request1"));
+
+        let res: tide::http::Response = app
+            .respond(tide::http::Request::new(
+                Method::Get,
+                Url::parse("http://localhost/request/unknown").unwrap(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+}
@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+use tide::{Middleware, Next, Request};
+
+// an empty value disables the corresponding header
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SecurityHeadersConfig {
+    // 0 disables Strict-Transport-Security
+    pub hsts_max_age_seconds: u64,
+    pub hsts_include_subdomains: bool,
+    pub frame_options: String,
+    pub referrer_policy: String,
+    pub content_type_options: String,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        SecurityHeadersConfig {
+            hsts_max_age_seconds: 365 * 24 * 60 * 60,
+            hsts_include_subdomains: false,
+            frame_options: "DENY".to_string(),
+            // the link contains the message token, so it must never leak to other sites
+            referrer_policy: "no-referrer".to_string(),
+            content_type_options: "nosniff".to_string(),
+        }
+    }
+}
+
+pub struct SecurityHeadersMiddleware {
+    headers: Vec<(&'static str, String)>,
+}
+
+impl SecurityHeadersMiddleware {
+    pub fn new(config: &SecurityHeadersConfig) -> Self {
+        let hsts = if config.hsts_max_age_seconds == 0 {
+            String::new()
+        } else if config.hsts_include_subdomains {
+            format!("max-age={}; includeSubDomains", config.hsts_max_age_seconds)
+        } else {
+            format!("max-age={}", config.hsts_max_age_seconds)
+        };
+
+        let headers = vec![
+            ("Strict-Transport-Security", hsts),
+            ("X-Frame-Options", config.frame_options.clone()),
+            ("Referrer-Policy", config.referrer_policy.clone()),
+            (
+                "X-Content-Type-Options",
+                config.content_type_options.clone(),
+            ),
+        ];
+        SecurityHeadersMiddleware {
+            headers: headers
+                .into_iter()
+                .filter(|(_, value)| !value.is_empty())
+                .collect(),
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for SecurityHeadersMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let mut res = next.run(req).await;
+        // handlers can still set their own values
+        for (name, value) in &self.headers {
+            if res.header(*name).is_none() {
+                res.insert_header(*name, value.as_str());
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tide::http::{Method, Url};
+
+    async fn respond_with(config: &SecurityHeadersConfig) -> tide::http::Response {
+        let mut app = tide::new();
+        app.with(SecurityHeadersMiddleware::new(config));
+        app.at("/").get(|_| async { Ok("Hello") });
+        let req = tide::http::Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+        app.respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_default_headers_are_set() {
+        let res = respond_with(&SecurityHeadersConfig::default()).await;
+
+        assert_eq!(res["Strict-Transport-Security"], "max-age=31536000");
+        assert_eq!(res["X-Frame-Options"], "DENY");
+        assert_eq!(res["Referrer-Policy"], "no-referrer");
+        assert_eq!(res["X-Content-Type-Options"], "nosniff");
+    }
+
+    #[async_std::test]
+    async fn test_headers_can_be_disabled() {
+        let res = respond_with(&SecurityHeadersConfig {
+            hsts_max_age_seconds: 0,
+            frame_options: String::new(),
+            ..Default::default()
+        })
+        .await;
+
+        assert!(res.header("Strict-Transport-Security").is_none());
+        assert!(res.header("X-Frame-Options").is_none());
+        assert_eq!(res["Referrer-Policy"], "no-referrer");
+    }
+}
@@ -0,0 +1,353 @@
+use async_dup::{Arc as DupArc, Mutex as DupMutex};
+use async_rustls::server::TlsStream;
+use async_rustls::TlsAcceptor;
+use async_signal::{Signal, Signals};
+use async_std::io::{Read, Write};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use async_std::task;
+use futures_lite::{future, StreamExt};
+use rustls::Session;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::proxy_protocol;
+
+// how long in-flight requests are given to finish after a shutdown signal
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// a load balancer sends the PROXY header right away, don't wait for slow clients
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// reported as the peer address of unix socket connections, see ForwardedHeadersMiddleware
+pub const UNIX_PEER_ADDR: &str = "unix";
+
+// the DER of the certificate the client presented in the TLS handshake, rustls verified it
+// against tls.clientCaPath
+#[derive(Clone)]
+pub struct ClientCertificate(pub Vec<u8>);
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ListenConfig {
+    // e.g. "0.0.0.0:443" or "[::]:443"
+    pub addr: String,
+    // defaults to the opposite of forceUnprotectedHttp
+    pub tls: Option<bool>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddr {
+    Tcp(String),
+    // an existing socket file is replaced, `mode` sets its permissions, e.g. 0o660
+    Unix { path: PathBuf, mode: Option<u32> },
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub async fn bind(addr: &ListenAddr) -> io::Result<Listener> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            ListenAddr::Unix { path, mode } => {
+                // a socket file left after a crash would make the bind fail
+                match fs::remove_file(path) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+                let listener = UnixListener::bind(path).await?;
+                if let Some(mode) = mode {
+                    fs::set_permissions(path, fs::Permissions::from_mode(*mode))?;
+                }
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+        }
+    }
+
+    async fn accept(&self) -> io::Result<Accepted> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept().await?;
+                Ok(Accepted::Tcp(Connection {
+                    local_addr: stream.local_addr().ok().map(|addr| addr.to_string()),
+                    peer_addr: Some(peer_addr.to_string()),
+                    stream,
+                }))
+            }
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(Accepted::Unix(Connection {
+                    stream,
+                    local_addr: Some(format!("unix:{}", path.display())),
+                    peer_addr: Some(UNIX_PEER_ADDR.to_string()),
+                }))
+            }
+        }
+    }
+}
+
+enum Accepted {
+    Tcp(Connection<TcpStream>),
+    Unix(Connection<UnixStream>),
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// async-h1 needs a stream that can be cloned
+struct TlsStreamWrapper<S>(DupArc<DupMutex<TlsStream<S>>>);
+
+impl<S> Clone for TlsStreamWrapper<S> {
+    fn clone(&self) -> Self {
+        TlsStreamWrapper(self.0.clone())
+    }
+}
+
+impl<S: Read + Write + Unpin> Read for TlsStreamWrapper<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut &*self.0).poll_read(cx, buf)
+    }
+}
+
+impl<S: Read + Write + Unpin> Write for TlsStreamWrapper<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut &*self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut &*self.0).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut &*self.0).poll_close(cx)
+    }
+}
+
+// tracks the requests that are being handled, so shutdown can wait for them
+#[derive(Clone, Default)]
+struct InFlightRequests(Arc<AtomicUsize>);
+
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl InFlightRequests {
+    fn start(&self) -> InFlightGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self.0.clone())
+    }
+
+    fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub async fn wait_for_shutdown_signal() -> io::Result<()> {
+    let mut signals = Signals::new([Signal::Term, Signal::Int])?;
+    if let Some(signal) = signals.next().await {
+        log::info!("Received {:?}, shutting down", signal?);
+    }
+    Ok(())
+}
+
+struct Connection<S> {
+    stream: S,
+    local_addr: Option<String>,
+    peer_addr: Option<String>,
+}
+
+// shared by all connections of a listener
+#[derive(Clone)]
+struct ConnectionContext<State> {
+    app: tide::Server<State>,
+    tls_acceptor: Option<TlsAcceptor>,
+    proxy_protocol: bool,
+    in_flight: InFlightRequests,
+    is_shutting_down: Arc<AtomicBool>,
+}
+
+async fn handle_connection<State, S>(context: ConnectionContext<State>, connection: Connection<S>)
+where
+    State: Clone + Send + Sync + 'static,
+    S: Read + Write + Clone + Send + Sync + Unpin + 'static,
+{
+    let ConnectionContext {
+        app,
+        tls_acceptor,
+        proxy_protocol,
+        in_flight,
+        is_shutting_down,
+    } = context;
+    let Connection {
+        mut stream,
+        local_addr,
+        mut peer_addr,
+    } = connection;
+    if proxy_protocol {
+        let header = async_std::io::timeout(
+            PROXY_HEADER_TIMEOUT,
+            proxy_protocol::read_header(&mut stream),
+        )
+        .await;
+        match header {
+            // the proxy may connect on its own behalf, e.g. for health checks
+            Ok(client_addr) => peer_addr = client_addr.map(|addr| addr.to_string()).or(peer_addr),
+            Err(err) => {
+                log::debug!("Failed to read the PROXY header: {}", err);
+                return;
+            }
+        }
+    }
+    let tls_stream = match tls_acceptor {
+        Some(tls_acceptor) => match tls_acceptor.accept(stream.clone()).await {
+            Ok(tls_stream) => Some(tls_stream),
+            Err(err) => {
+                log::debug!("TLS handshake failed: {}", err);
+                return;
+            }
+        },
+        None => None,
+    };
+    let is_tls = tls_stream.is_some();
+    let client_cert = tls_stream
+        .as_ref()
+        .and_then(|tls_stream| tls_stream.get_ref().1.get_peer_certificates())
+        .and_then(|certs| certs.into_iter().next())
+        .map(|cert| ClientCertificate(cert.0));
+
+    let endpoint = |mut req: http_types::Request| {
+        let app = app.clone();
+        let in_flight = in_flight.clone();
+        let is_shutting_down = is_shutting_down.clone();
+        let local_addr = local_addr.clone();
+        let peer_addr = peer_addr.clone();
+        let client_cert = client_cert.clone();
+        async move {
+            let _guard = in_flight.start();
+            if is_tls {
+                let _ = req.url_mut().set_scheme("https");
+            }
+            req.set_local_addr(local_addr);
+            req.set_peer_addr(peer_addr);
+            if let Some(client_cert) = client_cert {
+                req.ext_mut().insert(client_cert);
+            }
+            let mut res: http_types::Response = app.respond(req).await?;
+            // don't keep idle connections around when the server is about to stop
+            if is_shutting_down.load(Ordering::SeqCst) {
+                res.insert_header("Connection", "close");
+            }
+            Ok(res)
+        }
+    };
+
+    let result = match tls_stream {
+        Some(tls_stream) => {
+            let stream = TlsStreamWrapper(DupArc::new(DupMutex::new(tls_stream)));
+            async_h1::accept(stream, endpoint).await
+        }
+        None => async_h1::accept(stream, endpoint).await,
+    };
+
+    if let Err(err) = result {
+        log::debug!("Connection error: {}", err);
+    }
+}
+
+// serves `app` until `shutdown` completes, then waits for the requests in flight
+pub async fn serve<State: Clone + Send + Sync + 'static>(
+    app: tide::Server<State>,
+    listener: Listener,
+    tls_acceptor: Option<TlsAcceptor>,
+    proxy_protocol: bool,
+    shutdown: impl Future<Output = ()>,
+) {
+    match &listener {
+        Listener::Tcp(tcp_listener) => log::info!(
+            "Listening on {}://{}",
+            if tls_acceptor.is_some() {
+                "https"
+            } else {
+                "http"
+            },
+            tcp_listener
+                .local_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default()
+        ),
+        Listener::Unix(_, path) => log::info!("Listening on unix:{}", path.display()),
+    }
+
+    let in_flight = InFlightRequests::default();
+    let is_shutting_down = Arc::new(AtomicBool::new(false));
+    let context = ConnectionContext {
+        app,
+        tls_acceptor,
+        proxy_protocol,
+        in_flight: in_flight.clone(),
+        is_shutting_down: is_shutting_down.clone(),
+    };
+    futures_lite::pin!(shutdown);
+
+    loop {
+        let accepted = future::or(async { Some(listener.accept().await) }, async {
+            shutdown.as_mut().await;
+            None
+        })
+        .await;
+
+        match accepted {
+            Some(Ok(Accepted::Tcp(connection))) => {
+                task::spawn(handle_connection(context.clone(), connection));
+            }
+            Some(Ok(Accepted::Unix(connection))) => {
+                task::spawn(handle_connection(context.clone(), connection));
+            }
+            Some(Err(err)) => log::warn!("Failed to accept a connection: {}", err),
+            None => break,
+        }
+    }
+
+    drop(listener);
+    is_shutting_down.store(true, Ordering::SeqCst);
+
+    let drain_start = Instant::now();
+    while in_flight.count() > 0 && drain_start.elapsed() < DRAIN_TIMEOUT {
+        task::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+    if in_flight.count() > 0 {
+        log::warn!(
+            "Stopped with {} request(s) still in flight",
+            in_flight.count()
+        );
+    }
+}
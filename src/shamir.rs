@@ -0,0 +1,150 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use zeroize::Zeroizing;
+
+// Shamir's secret sharing over GF(2^8) with the AES polynomial, byte by byte: each byte of
+// the secret is the constant of a random polynomial of degree threshold - 1 and a share is
+// the value of all of them at its index; fewer shares than the threshold tell nothing
+
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        // multiplying by x, reduced by x^8 + x^4 + x^3 + x + 1
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+        b >>= 1;
+    }
+    product
+}
+
+// a^254 is the inverse of a, the multiplicative group has 255 elements
+fn inverse(a: u8) -> u8 {
+    let mut result = 1;
+    let mut power = a;
+    let mut exponent = 254;
+    while exponent != 0 {
+        if exponent & 1 != 0 {
+            result = mul(result, power);
+        }
+        power = mul(power, power);
+        exponent >>= 1;
+    }
+    result
+}
+
+pub struct Share {
+    // the point the polynomials are evaluated at, never 0, which is where the secret is
+    pub index: u8,
+    pub data: Zeroizing<Vec<u8>>,
+}
+
+// any `threshold` of the `count` shares give the secret back, 2 <= threshold <= count
+pub fn split(secret: &[u8], threshold: u8, count: u8) -> Vec<Share> {
+    let degree = threshold as usize - 1;
+    let mut coefficients = Zeroizing::new(vec![0u8; secret.len() * degree]);
+    OsRng.fill_bytes(&mut coefficients);
+
+    (1..=count)
+        .map(|index| {
+            let data = secret
+                .iter()
+                .zip(coefficients.chunks(degree))
+                .map(|(byte, coefficients)| {
+                    // Horner's rule from the highest coefficient down to the secret byte
+                    let value = coefficients
+                        .iter()
+                        .rev()
+                        .fold(0, |value, coefficient| mul(value, index) ^ coefficient);
+                    mul(value, index) ^ byte
+                })
+                .collect();
+            Share {
+                index,
+                data: Zeroizing::new(data),
+            }
+        })
+        .collect()
+}
+
+// the secret at 0 by Lagrange interpolation, wrong or too few shares give a wrong secret
+// that can't be told apart, the caller checks what it can
+pub fn combine(shares: &[Share]) -> Result<Zeroizing<Vec<u8>>, String> {
+    let len = match shares.first() {
+        Some(share) => share.data.len(),
+        None => return Err("No shares".to_string()),
+    };
+    if shares.iter().any(|share| share.data.len() != len) {
+        return Err("The shares have different lengths".to_string());
+    }
+    for (i, share) in shares.iter().enumerate() {
+        if share.index == 0 || shares[..i].iter().any(|other| other.index == share.index) {
+            return Err("The share indexes should be distinct and not 0".to_string());
+        }
+    }
+
+    let mut secret = Zeroizing::new(vec![0u8; len]);
+    for share in shares {
+        // in GF(2^8) subtracting is xor, so this is the basis polynomial of the share at 0
+        let basis = shares
+            .iter()
+            .filter(|other| other.index != share.index)
+            .fold(1, |basis, other| {
+                mul(basis, mul(other.index, inverse(other.index ^ share.index)))
+            });
+        for (byte, value) in secret.iter_mut().zip(share.data.iter()) {
+            *byte ^= mul(*value, basis);
+        }
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_inverse() {
+        for a in 1..=255 {
+            assert_eq!(mul(a, inverse(a)), 1);
+        }
+    }
+
+    #[test]
+    fn test_any_threshold_of_shares_combine() {
+        let secret = b"correct horse battery staple";
+        let shares = split(secret, 3, 5);
+        assert_eq!(shares.len(), 5);
+        for share in &shares {
+            assert_ne!(&share.data[..], &secret[..]);
+        }
+
+        let pick = |indexes: &[usize]| -> Vec<Share> {
+            indexes
+                .iter()
+                .map(|&i| Share {
+                    index: shares[i].index,
+                    data: shares[i].data.clone(),
+                })
+                .collect()
+        };
+        for indexes in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            assert_eq!(&combine(&pick(&indexes)).unwrap()[..], &secret[..]);
+        }
+        // all of them work as well, two don't
+        assert_eq!(&combine(&pick(&[0, 1, 2, 3, 4])).unwrap()[..], &secret[..]);
+        assert_ne!(&combine(&pick(&[0, 1])).unwrap()[..], &secret[..]);
+    }
+
+    #[test]
+    fn test_combine_rejects_bad_shares() {
+        assert!(combine(&[]).is_err());
+        let share = |index: u8, data: &[u8]| Share {
+            index,
+            data: Zeroizing::new(data.to_vec()),
+        };
+        assert!(combine(&[share(1, b"ab"), share(1, b"cd")]).is_err());
+        assert!(combine(&[share(1, b"ab"), share(2, b"c")]).is_err());
+        assert!(combine(&[share(0, b"ab"), share(2, b"cd")]).is_err());
+    }
+}
@@ -0,0 +1,348 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+use tide::{Body, Request, Response, StatusCode};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::api::{recipient_links, RecipientLink};
+use crate::csp;
+use crate::error::AppError;
+use crate::shamir::{combine, split, Share};
+use crate::validation::validate_message_form;
+use crate::{save_new_payloads, MessageForm, StaticData};
+
+// each share is a message of its own, so the recipient limit of a message applies
+const MAX_SHARES: u8 = 50;
+// a share is read as text on the shared page and pasted back on the combine page
+const SHARE_PREFIX: &str = "one-time-share";
+
+// a secret split into shares, each of them is a one-time link and any `threshold` of them
+// give the secret back, fewer tell nothing about it
+#[derive(Serialize, Deserialize, Default)]
+pub struct SplitSecretForm {
+    pub user_token: String,
+    pub message_data: Zeroizing<String>,
+    pub retention: Option<u32>,
+    // protects every share, the recipients are told it separately
+    pub passphrase: Zeroizing<Option<String>>,
+    pub threshold: u8,
+    pub shares: u8,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SplitSecretResponse {
+    pub shares: Vec<RecipientLink>,
+    pub threshold: u8,
+    pub expire_timestamp: u64,
+    // hex SHA-256 of the whole secret, so it can be checked once it's put together
+    pub sha256: String,
+    // the same for all the shares, like for the links of a message with recipients
+    pub delete_token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CombineSharesRequest {
+    // as the recipients read them, one per item
+    pub shares: Vec<Zeroizing<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CombineSharesResponse {
+    pub message_data: Zeroizing<String>,
+}
+
+fn invalid(field: &'static str, message: &str) -> tide::Error {
+    AppError::Invalid {
+        field,
+        message: message.to_string(),
+    }
+    .into_error()
+}
+
+// e.g. "one-time-share:1f2e3d4c:2:1:<base64>", the split id keeps the shares of different
+// secrets from being mixed up
+fn encode_share(split_id: &str, threshold: u8, share: &Share) -> Zeroizing<Vec<u8>> {
+    Zeroizing::new(
+        format!(
+            "{}:{}:{}:{}:{}",
+            SHARE_PREFIX,
+            split_id,
+            threshold,
+            share.index,
+            STANDARD.encode(&*share.data)
+        )
+        .into_bytes(),
+    )
+}
+
+struct ParsedShare {
+    split_id: String,
+    threshold: u8,
+    share: Share,
+}
+
+fn parse_share(text: &str) -> Option<ParsedShare> {
+    let mut parts = text.trim().split(':');
+    if parts.next() != Some(SHARE_PREFIX) {
+        return None;
+    }
+    let split_id = parts.next()?.to_string();
+    let threshold = parts.next()?.parse().ok()?;
+    let index = parts.next()?.parse().ok()?;
+    let data = STANDARD.decode(parts.next()?).ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(ParsedShare {
+        split_id,
+        threshold,
+        share: Share {
+            index,
+            data: Zeroizing::new(data),
+        },
+    })
+}
+
+pub async fn split_secret(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let form: SplitSecretForm = req
+        .body_json()
+        .await
+        .map_err(|_| AppError::BadRequest("Can't parse request body".to_string()).into_error())?;
+    if form.shares < 2 || form.shares > MAX_SHARES {
+        return Err(invalid(
+            "shares",
+            &format!("Shares should be from 2 to {}", MAX_SHARES),
+        ));
+    }
+    if form.threshold < 2 || form.threshold > form.shares {
+        return Err(invalid(
+            "threshold",
+            "Threshold should be from 2 to the number of shares",
+        ));
+    }
+
+    // the shares are saved like a message with a recipient for each of them
+    let message_form = MessageForm {
+        user_token: form.user_token,
+        message_data: form.message_data,
+        retention: form.retention,
+        passphrase: form.passphrase,
+        recipients: Some(
+            (1..=form.shares)
+                .map(|index| format!("share {}", index))
+                .collect(),
+        ),
+        ..Default::default()
+    };
+    validate_message_form(&message_form).map_err(AppError::into_error)?;
+    // the form is validated, so the data is valid base64
+    let secret = Zeroizing::new(STANDARD.decode(&message_form.message_data)?);
+    let split_id = Uuid::new_v4().simple().to_string()[..8].to_string();
+    let payloads: Vec<Zeroizing<Vec<u8>>> = split(&secret, form.threshold, form.shares)
+        .iter()
+        .map(|share| encode_share(&split_id, form.threshold, share))
+        .collect();
+
+    let data = req.state().lock().unwrap();
+    let created = save_new_payloads(&data, &message_form, &payloads)?;
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&SplitSecretResponse {
+            shares: recipient_links(&req, &data.config, &created),
+            threshold: form.threshold,
+            expire_timestamp: created.expire_timestamp,
+            sha256: format!("{:x}", Sha256::digest(&secret)),
+            delete_token: created.delete_token,
+        })?)
+        .build())
+}
+
+// nothing is stored, the server only does the arithmetic for the page
+pub async fn combine_shares(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let form: CombineSharesRequest = req
+        .body_json()
+        .await
+        .map_err(|_| AppError::BadRequest("Can't parse request body".to_string()).into_error())?;
+
+    let mut parsed_shares = Vec::with_capacity(form.shares.len());
+    for text in &form.shares {
+        match parse_share(text) {
+            Some(parsed_share) => parsed_shares.push(parsed_share),
+            None => return Err(invalid("shares", "Not a share of a secret")),
+        }
+    }
+    let (split_id, threshold) = match parsed_shares.first() {
+        Some(first) => (first.split_id.clone(), first.threshold),
+        None => return Err(invalid("shares", "No shares")),
+    };
+    if parsed_shares
+        .iter()
+        .any(|parsed_share| parsed_share.split_id != split_id)
+    {
+        return Err(invalid("shares", "The shares are of different secrets"));
+    }
+    if parsed_shares.len() < threshold as usize {
+        return Err(invalid(
+            "shares",
+            &format!("{} shares are needed", threshold),
+        ));
+    }
+
+    let shares: Vec<Share> = parsed_shares
+        .into_iter()
+        .map(|parsed_share| parsed_share.share)
+        .collect();
+    let secret = combine(&shares).map_err(|err| invalid("shares", &err))?;
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&CombineSharesResponse {
+            message_data: STANDARD.encode(&*secret).into(),
+        })?)
+        .build())
+}
+
+pub async fn combine_page(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    let html = String::from_utf8(data.combine_html.clone())?;
+    Ok(csp::html_response(
+        &data.config.content_security_policy,
+        &html,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ConsumeMessageResponse;
+    use crate::store::UserLimits;
+    use crate::tests::setup_test_data;
+    use tide::http::{Method, Url};
+
+    fn post(url: &str, body: &impl Serialize) -> tide::http::Request {
+        let mut req = tide::http::Request::new(Method::Post, Url::parse(url).unwrap());
+        req.set_body(Body::from_json(body).unwrap());
+        req
+    }
+
+    #[async_std::test]
+    async fn test_split_and_combine() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits("test_token", &UserLimits::default())
+            .unwrap();
+
+        let mut res: tide::http::Response = app
+            .respond(post(
+                "http://localhost/api/v1/shares",
+                &SplitSecretForm {
+                    user_token: "test_token".to_string(),
+                    // base64 of "hunter2"
+                    message_data: "aHVudGVyMg==".to_string().into(),
+                    threshold: 2,
+                    shares: 3,
+                    ..Default::default()
+                },
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let created: SplitSecretResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(created.shares.len(), 3);
+        assert_eq!(created.sha256, format!("{:x}", Sha256::digest(b"hunter2")));
+
+        let mut shares = Vec::new();
+        for share in &created.shares {
+            let mut res: tide::http::Response = app
+                .respond(tide::http::Request::new(
+                    Method::Post,
+                    Url::parse(&format!(
+                        "http://localhost/api/v1/messages/{}/consume",
+                        share.message_token
+                    ))
+                    .unwrap(),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::Ok);
+            let body: ConsumeMessageResponse = res.take_body().into_json().await.unwrap();
+            let text = String::from_utf8(STANDARD.decode(&*body.message_data).unwrap()).unwrap();
+            assert!(!text.contains("aHVudGVyMg=="));
+            shares.push(Zeroizing::new(text));
+        }
+
+        let combine_url = "http://localhost/api/v1/shares/combine";
+        // one share isn't enough
+        let res: tide::http::Response = app
+            .respond(post(
+                combine_url,
+                &CombineSharesRequest {
+                    shares: shares[..1].to_vec(),
+                },
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+
+        let mut res: tide::http::Response = app
+            .respond(post(
+                combine_url,
+                &CombineSharesRequest {
+                    shares: shares[1..].to_vec(),
+                },
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: CombineSharesResponse = res.take_body().into_json().await.unwrap();
+        assert_eq!(*body.message_data, "aHVudGVyMg==");
+    }
+
+    #[async_std::test]
+    async fn test_split_bounds() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+
+        for (threshold, shares) in [(1, 3), (4, 3), (2, MAX_SHARES + 1)] {
+            let res: tide::http::Response = app
+                .respond(post(
+                    "http://localhost/api/v1/shares",
+                    &SplitSecretForm {
+                        user_token: "test_token".to_string(),
+                        message_data: "aHVudGVyMg==".to_string().into(),
+                        threshold,
+                        shares,
+                        ..Default::default()
+                    },
+                ))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn test_parse_share() {
+        let share = |split_id: &str| {
+            let share = Share {
+                index: 1,
+                data: Zeroizing::new(b"ab".to_vec()),
+            };
+            String::from_utf8(encode_share(split_id, 2, &share).to_vec()).unwrap()
+        };
+        let parsed = parse_share(&share("1f2e3d4c")).unwrap();
+        assert_eq!(parsed.split_id, "1f2e3d4c");
+        assert_eq!(parsed.threshold, 2);
+        assert_eq!(parsed.share.index, 1);
+        assert!(parse_share("hunter2").is_none());
+        assert!(parse_share(&format!("{}:extra", share("1f2e3d4c"))).is_none());
+    }
+}
@@ -0,0 +1,86 @@
+use tide::http::Url;
+use tide::{Middleware, Next, Request};
+
+use crate::error::AppError;
+use crate::host_allowlist::host_name;
+
+const SHARED_PATH_PREFIX: &str = "/shared/";
+const MESSAGES_PATH_PREFIX: &str = "/api/v1/messages/";
+// the shared page asks about the message before it is read
+const META_PATH_SUFFIX: &str = "/meta";
+
+// the host name of a base URL like "https://1ts.link", None when it has none
+pub(crate) fn short_link_host(short_link_base: &str) -> Option<String> {
+    Url::parse(short_link_base)
+        .ok()?
+        .host_str()
+        .map(str::to_ascii_lowercase)
+}
+
+fn is_shared_page_path(path: &str) -> bool {
+    if path.starts_with(SHARED_PATH_PREFIX) {
+        return true;
+    }
+    path.strip_prefix(MESSAGES_PATH_PREFIX)
+        .and_then(|rest| rest.strip_suffix(META_PATH_SUFFIX))
+        .is_some_and(|token| !token.is_empty() && !token.contains('/'))
+}
+
+// the short host of the generated links only serves the shared page, everything else stays
+// on the canonical host
+pub struct ShortLinkHostMiddleware {
+    short_link_host: Option<String>,
+}
+
+impl ShortLinkHostMiddleware {
+    pub fn new(short_link_base: Option<&str>) -> Self {
+        ShortLinkHostMiddleware {
+            short_link_host: short_link_base.and_then(short_link_host),
+        }
+    }
+
+    fn is_routed(&self, host: &str, path: &str) -> bool {
+        match &self.short_link_host {
+            Some(short_link_host) if *short_link_host == host_name(host).to_ascii_lowercase() => {
+                is_shared_page_path(path)
+            }
+            _ => true,
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ShortLinkHostMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let is_routed = req
+            .host()
+            .is_none_or(|host| self.is_routed(host, req.url().path()));
+        if !is_routed {
+            return Err(AppError::NotFound("Not found".to_string()).into_error());
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_routed() {
+        let middleware = ShortLinkHostMiddleware::new(Some("https://1TS.link/"));
+
+        assert!(middleware.is_routed("1ts.link", "/shared/abc"));
+        assert!(middleware.is_routed("1ts.link:443", "/shared/abc"));
+        assert!(middleware.is_routed("1ts.link", "/api/v1/messages/abc/meta"));
+        assert!(!middleware.is_routed("1ts.link", "/"));
+        assert!(!middleware.is_routed("1ts.link", "/save"));
+        assert!(!middleware.is_routed("1ts.link", "/api/v1/messages"));
+        assert!(!middleware.is_routed("1ts.link", "/api/v1/messages/abc/consume"));
+        assert!(!middleware.is_routed("1ts.link", "/api/v1/messages/a/b/meta"));
+        assert!(middleware.is_routed("share.corp.example", "/"));
+        assert!(middleware.is_routed("share.corp.example", "/save"));
+
+        assert!(ShortLinkHostMiddleware::new(None).is_routed("1ts.link", "/"));
+    }
+}
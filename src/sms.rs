@@ -0,0 +1,277 @@
+use async_std::{future, task};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use http_types::{Body, Method, Request, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::http_client::HttpClient;
+
+const USER_AGENT: &str = "one-time-share-sms";
+const LINK_PLACEHOLDER: &str = "{{.Link}}";
+const DEFAULT_TEXT: &str = "You have been sent a one-time message, it can be read once: {{.Link}}";
+const DEFAULT_TWILIO_API_BASE_URL: &str = "https://api.twilio.com";
+// a gateway that doesn't answer in time counts as a failure
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(tag = "provider", rename_all = "camelCase")]
+pub enum SmsProvider {
+    #[serde(rename_all = "camelCase")]
+    Twilio {
+        account_sid: String,
+        auth_token: String,
+        // the number or messaging service the texts come from
+        from: String,
+        // only for tests and regional endpoints
+        api_base_url: Option<String>,
+    },
+    // gets a POST of {"to": "+15551234567", "text": "..."}
+    #[serde(rename_all = "camelCase")]
+    Gateway {
+        url: String,
+        // sent as is in the Authorization header, e.g. "Bearer ..."
+        authorization: Option<String>,
+    },
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SmsConfig {
+    #[serde(flatten)]
+    pub provider: SmsProvider,
+    // {{.Link}} is replaced by the link of the message
+    pub text: Option<String>,
+    pub ca_bundle_path: Option<String>,
+}
+
+// the international format, e.g. "+15551234567"
+pub fn is_valid_phone_number(phone_number: &str) -> bool {
+    match phone_number.strip_prefix('+') {
+        Some(digits) => {
+            (8..=15).contains(&digits.len())
+                && !digits.starts_with('0')
+                && digits.bytes().all(|byte| byte.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+#[derive(Clone)]
+pub struct SmsSender {
+    client: HttpClient,
+    config: SmsConfig,
+}
+
+impl SmsSender {
+    pub fn new(config: &SmsConfig) -> std::io::Result<Self> {
+        Ok(SmsSender {
+            client: HttpClient::new(config.ca_bundle_path.as_deref())?,
+            config: config.clone(),
+        })
+    }
+
+    // sends in the background, a failure is logged and the text is not sent again
+    pub fn send_link(&self, to: &str, link: &str) {
+        let text = self
+            .config
+            .text
+            .as_deref()
+            .unwrap_or(DEFAULT_TEXT)
+            .replace(LINK_PLACEHOLDER, link);
+        let sender = self.clone();
+        let to = to.to_string();
+        task::spawn(async move {
+            if let Err(err) = sender.send(&to, &text).await {
+                log::error!("Failed to text a link: {}", err);
+            }
+        });
+    }
+
+    pub async fn send(&self, to: &str, text: &str) -> http_types::Result<()> {
+        let req = self.make_request(to, text)?;
+        let res = future::timeout(SEND_TIMEOUT, self.client.send(req))
+            .await
+            .map_err(|_| http_types::Error::from_str(StatusCode::GatewayTimeout, "Timed out"))??;
+        if !res.status().is_success() {
+            return Err(http_types::Error::from_str(
+                res.status(),
+                format!("SMS gateway answered {}", res.status()),
+            ));
+        }
+        Ok(())
+    }
+
+    fn make_request(&self, to: &str, text: &str) -> http_types::Result<Request> {
+        let mut req = match &self.config.provider {
+            SmsProvider::Twilio {
+                account_sid,
+                auth_token,
+                from,
+                api_base_url,
+            } => {
+                let url = Url::parse(&format!(
+                    "{}/2010-04-01/Accounts/{}/Messages.json",
+                    api_base_url
+                        .as_deref()
+                        .unwrap_or(DEFAULT_TWILIO_API_BASE_URL)
+                        .trim_end_matches('/'),
+                    account_sid
+                ))?;
+                let mut req = Request::new(Method::Post, url);
+                req.insert_header(
+                    "Authorization",
+                    format!(
+                        "Basic {}",
+                        STANDARD.encode(format!("{}:{}", account_sid, auth_token))
+                    ),
+                );
+                req.set_body(Body::from_form(&[
+                    ("To", to),
+                    ("From", from),
+                    ("Body", text),
+                ])?);
+                req
+            }
+            SmsProvider::Gateway { url, authorization } => {
+                let mut req = Request::new(Method::Post, Url::parse(url)?);
+                if let Some(authorization) = authorization {
+                    req.insert_header("Authorization", authorization.as_str());
+                }
+                req.set_body(Body::from_json(
+                    &serde_json::json!({ "to": to, "text": text }),
+                )?);
+                req
+            }
+        };
+        let host = match (req.url().host_str(), req.url().port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => String::new(),
+        };
+        req.insert_header("Host", host);
+        req.insert_header("User-Agent", USER_AGENT);
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+
+    pub(crate) struct ReceivedText {
+        pub path: String,
+        pub authorization: Option<String>,
+        pub content_type: String,
+        pub body: String,
+    }
+
+    pub(crate) fn make_gateway_sender(url: &str) -> SmsSender {
+        SmsSender::new(&SmsConfig {
+            provider: SmsProvider::Gateway {
+                url: url.to_string(),
+                authorization: Some("Bearer secret".to_string()),
+            },
+            text: None,
+            ca_bundle_path: None,
+        })
+        .unwrap()
+    }
+
+    // answers the next request on `listener` with `status`
+    pub(crate) async fn receive_text(listener: &TcpListener, status: StatusCode) -> ReceivedText {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (sender, receiver) = async_std::channel::bounded(1);
+        async_h1::accept(stream, |mut req| {
+            let sender = sender.clone();
+            async move {
+                let received = ReceivedText {
+                    path: req.url().path().to_string(),
+                    authorization: req
+                        .header("Authorization")
+                        .map(|values| values.as_str().to_string()),
+                    content_type: req.header("Content-Type").unwrap().as_str().to_string(),
+                    body: req.body_string().await?,
+                };
+                sender.send(received).await?;
+                Ok(http_types::Response::new(status))
+            }
+        })
+        .await
+        .unwrap();
+        receiver.recv().await.unwrap()
+    }
+
+    #[test]
+    fn test_phone_numbers() {
+        assert!(is_valid_phone_number("+15551234567"));
+        assert!(is_valid_phone_number("+4915112345678"));
+        assert!(!is_valid_phone_number("15551234567"));
+        assert!(!is_valid_phone_number("+1555"));
+        assert!(!is_valid_phone_number("+1 555 123 4567"));
+        assert!(!is_valid_phone_number("+05551234567"));
+        assert!(!is_valid_phone_number("+1555123456789012"));
+    }
+
+    #[async_std::test]
+    async fn test_send_through_twilio() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sender = SmsSender::new(&SmsConfig {
+            provider: SmsProvider::Twilio {
+                account_sid: "AC123".to_string(),
+                auth_token: "token".to_string(),
+                from: "+15550000000".to_string(),
+                api_base_url: Some(format!("http://{}", listener.local_addr().unwrap())),
+            },
+            text: None,
+            ca_bundle_path: None,
+        })
+        .unwrap();
+
+        let (received, result) = futures_lite::future::zip(
+            receive_text(&listener, StatusCode::Created),
+            sender.send("+15551234567", "Hello & bye"),
+        )
+        .await;
+        result.unwrap();
+        assert_eq!(received.path, "/2010-04-01/Accounts/AC123/Messages.json");
+        // base64 of "AC123:token"
+        assert_eq!(
+            received.authorization.as_deref(),
+            Some("Basic QUMxMjM6dG9rZW4=")
+        );
+        assert_eq!(received.content_type, "application/x-www-form-urlencoded");
+        assert_eq!(
+            received.body,
+            "To=%2B15551234567&From=%2B15550000000&Body=Hello+%26+bye"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_send_through_gateway() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sender = make_gateway_sender(&format!("http://{}/sms", listener.local_addr().unwrap()));
+
+        let (received, result) = futures_lite::future::zip(
+            receive_text(&listener, StatusCode::Ok),
+            sender.send("+15551234567", "Hello"),
+        )
+        .await;
+        result.unwrap();
+        assert_eq!(received.path, "/sms");
+        assert_eq!(received.authorization.as_deref(), Some("Bearer secret"));
+        let body: serde_json::Value = serde_json::from_str(&received.body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "to": "+15551234567", "text": "Hello" })
+        );
+
+        let (_, result) = futures_lite::future::zip(
+            receive_text(&listener, StatusCode::BadRequest),
+            sender.send("+15551234567", "Hello"),
+        )
+        .await;
+        assert!(result.is_err());
+    }
+}
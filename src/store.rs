@@ -0,0 +1,679 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+use crate::blob_transfers::BlobTransfers;
+
+// storage-agnostic error, backends wrap their own errors into it
+#[derive(Debug)]
+pub struct StoreError(Box<dyn std::error::Error + Send + Sync>);
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl StoreError {
+    pub fn new(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        StoreError(err.into())
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(err: std::io::Error) -> Self {
+        StoreError(Box::new(err))
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        StoreError(Box::new(err))
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct UserLimits {
+    pub retention_limit_minutes: u32,
+    pub max_message_size_bytes: u32,
+    pub message_creation_limit_minutes: u32,
+    // messages that can be created within the creation limit window
+    pub message_creation_limit_count: u32,
+    // unconsumed messages the user can have at once, 0 means no limit
+    pub active_message_limit: u32,
+    // bytes the user can store per calendar month, 0 means no limit
+    pub monthly_byte_quota: u64,
+    // the user can choose the name of the link instead of getting a random token
+    pub custom_slugs: bool,
+}
+
+// optional properties of a message that are set at creation time
+#[derive(Default)]
+pub struct MessageOptions {
+    pub passphrase_hash: Option<String>,
+    // the data was encrypted by the creator and the key is never sent to the server
+    pub is_client_encrypted: bool,
+    // original name and MIME type of an uploaded file
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    // the user who created the message, counted against their active message limit
+    pub user_token: Option<String>,
+    // hex SHA-256 of the data as it was uploaded
+    pub checksum: Option<String>,
+    // the name chosen by the creator, the message is looked up by it instead of the token
+    pub slug: Option<String>,
+    // a second secret the creator can remove the message with before it's read
+    pub delete_token: Option<String>,
+    // gets a signed POST when the message is read
+    pub webhook_url: Option<String>,
+    // how many times it can be read before it's removed, 0 is the same as 1
+    pub max_views: u32,
+    // it can still be read for this long after the last view, e.g. after a browser crash
+    pub grace_minutes: u32,
+    // the unix time before which it can't be read, the link can be handed out earlier
+    pub not_before: Option<i64>,
+    // two distinct tokens that have to be presented together to read it, e.g. for a root
+    // password no single person may retrieve alone
+    pub authorization_tokens: Option<[String; 2]>,
+    // the raw secret of an authenticator, reading it takes a code made with it
+    pub totp_secret: Option<Zeroizing<Vec<u8>>>,
+    // a short numeric code sent apart from the link, a few wrong ones destroy the message
+    pub pin_hash: Option<String>,
+    // addresses or CIDR blocks it can be read from, from anywhere when empty
+    pub allowed_ips: Vec<String>,
+}
+
+pub struct MessageInfo {
+    // the decoded payload, the API hands it out as base64
+    pub data: Zeroizing<Vec<u8>>,
+    pub expire_timestamp: i64,
+    pub has_passphrase: bool,
+    pub is_client_encrypted: bool,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    // None for the messages stored before checksums were kept
+    pub checksum: Option<String>,
+    // the reads left, the last one removes the message, 0 in its grace window
+    pub remaining_views: u32,
+    pub not_before: Option<i64>,
+    // it takes two authorization tokens to read it
+    pub is_dual_control: bool,
+    // it takes a code of an authenticator app to read it
+    pub is_totp_protected: bool,
+    pub has_pin: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageState {
+    // not read yet
+    Pending,
+    Consumed,
+    Expired,
+    // removed by the creator with the delete token
+    Deleted,
+    // removed after too many wrong passphrases
+    Destroyed,
+}
+
+impl MessageState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageState::Pending => "pending",
+            MessageState::Consumed => "consumed",
+            MessageState::Expired => "expired",
+            MessageState::Deleted => "deleted",
+            MessageState::Destroyed => "destroyed",
+        }
+    }
+
+    pub fn parse(state: &str) -> Option<MessageState> {
+        [
+            MessageState::Pending,
+            MessageState::Consumed,
+            MessageState::Expired,
+            MessageState::Deleted,
+            MessageState::Destroyed,
+        ]
+        .into_iter()
+        .find(|candidate| candidate.as_str() == state)
+    }
+}
+
+// what the creator of a message can learn about it with the delete token
+pub struct MessageStatus {
+    pub state: MessageState,
+    // None for the messages stored before the creation time was kept
+    pub created_timestamp: Option<i64>,
+    pub expire_timestamp: i64,
+    // set once the message is gone
+    pub removed_timestamp: Option<i64>,
+    // only when read receipts are turned on
+    pub receipt: Option<ReadReceipt>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ReadReceipt {
+    pub read_timestamp: i64,
+    // truncated to the network, only when the config asks for it
+    pub client_ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+// a message the cleanup removed before anyone read it
+#[derive(Clone, PartialEq, Debug)]
+pub struct ExpiredMessage {
+    // the hash of the name the message was handed out under, the slug when it has one
+    pub name_hash: String,
+    pub expire_timestamp: i64,
+    // the webhook of the message or else the one of its creator
+    pub webhook_url: Option<String>,
+    pub creator_email: Option<String>,
+}
+
+// a request for a secret, whoever opens its link can answer it once
+#[derive(Clone, PartialEq, Debug)]
+pub struct SecretRequest {
+    // shown to whoever answers it, e.g. "the VPN password"
+    pub note: Option<String>,
+    pub expire_timestamp: i64,
+    // 0 for no limit
+    pub max_size_bytes: u32,
+    // how long the answer is kept for the requester, 0 for no expiry
+    pub retention_minutes: u32,
+    // the token the answer is saved under, sealed with the request token so the database
+    // alone doesn't give it away
+    pub sealed_response_token: Vec<u8>,
+    // the token of the secret of an exchange channel, sealed the same way, the request
+    // can be answered only once it's read and this is cleared
+    pub sealed_message_token: Option<Vec<u8>>,
+}
+
+// a passkey that can sign in to the admin API
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct AdminCredential {
+    pub id: i64,
+    // base64url, the way browsers report it
+    pub credential_id: String,
+    // the COSE key of the authenticator
+    #[serde(skip)]
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+    // to tell the passkeys apart in the list, e.g. "laptop"
+    pub name: String,
+    pub created_timestamp: i64,
+    pub last_used_timestamp: Option<i64>,
+}
+
+// a user token created through the self-service portal
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub created_timestamp: i64,
+    pub plan: Option<String>,
+}
+
+// a single-use link an admin made, whoever opens it gets a user token on the plan
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Invitation {
+    pub id: i64,
+    pub plan: Option<String>,
+    // e.g. "for Bob", so the admins know whom it was made for
+    pub note: Option<String>,
+    pub created_timestamp: i64,
+    pub expire_timestamp: i64,
+}
+
+// the data of a consumed file, kept until its download is complete so a broken one can be
+// resumed
+pub struct PendingDownload {
+    pub data: Zeroizing<Vec<u8>>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub checksum: Option<String>,
+    pub expire_timestamp: i64,
+}
+
+pub trait UserStore {
+    fn set_user_limits(&self, token: &str, limits: &UserLimits) -> StoreResult<()>;
+
+    // returns None for unknown users
+    fn get_user_limits(&self, token: &str) -> StoreResult<Option<UserLimits>>;
+
+    // a user on a plan gets the limits of the plan from the config instead of their own,
+    // setting a plan creates the user if needed
+    fn set_user_plan(&self, token: &str, plan: Option<&str>) -> StoreResult<()>;
+
+    fn get_user_plan(&self, token: &str) -> StoreResult<Option<String>>;
+
+    // the messages of the user without a webhook of their own use this one, setting it
+    // creates the user if needed
+    fn set_user_webhook_url(&self, token: &str, webhook_url: Option<&str>) -> StoreResult<()>;
+
+    fn get_user_webhook_url(&self, token: &str) -> StoreResult<Option<String>>;
+
+    // the creator is told by email when their messages are read or expire, setting it
+    // creates the user if needed
+    fn set_user_email(&self, token: &str, email: Option<&str>) -> StoreResult<()>;
+
+    fn get_user_email(&self, token: &str) -> StoreResult<Option<String>>;
+
+    // messages of the user that are neither consumed nor expired at `timestamp`
+    fn count_active_user_messages(&self, token: &str, timestamp: i64) -> StoreResult<u32>;
+
+    // the month is given as "2024-02"
+    fn get_monthly_usage(&self, token: &str, month: &str) -> StoreResult<u64>;
+
+    fn add_monthly_usage(&self, token: &str, month: &str, bytes: u64) -> StoreResult<()>;
+
+    // records a message creation unless the user already created `max_count` messages within
+    // the last `window_seconds`, then returns the seconds until the oldest of them leaves the window
+    fn try_register_message_creation(
+        &self,
+        token: &str,
+        timestamp: i64,
+        window_seconds: i64,
+        max_count: u32,
+    ) -> StoreResult<Option<i64>>;
+
+    fn remove_user_by_token(&self, token: &str) -> StoreResult<()>;
+}
+
+pub trait MessageStore {
+    fn save_message(
+        &self,
+        message_token: &str,
+        expire_timestamp: i64,
+        data: &[u8],
+        options: &MessageOptions,
+    ) -> StoreResult<()>;
+
+    // returns the data and expire timestamp of the message, and removes it on its last view
+    fn try_consume_message(
+        &self,
+        message_token: &str,
+    ) -> StoreResult<(Option<Zeroizing<Vec<u8>>>, i64)>;
+
+    fn get_message_info(&self, message_token: &str) -> StoreResult<Option<MessageInfo>>;
+
+    // the webhook of the message or else the one of its creator, None for unknown messages
+    fn get_message_webhook_url(&self, message_token: &str) -> StoreResult<Option<String>>;
+
+    fn get_message_creator_email(&self, message_token: &str) -> StoreResult<Option<String>>;
+
+    // true when a message has this token or slug
+    fn is_message_name_taken(&self, name: &str) -> StoreResult<bool>;
+
+    // removes the message if the delete token matches, returns false otherwise
+    fn delete_message(&self, message_token: &str, delete_token: &str) -> StoreResult<bool>;
+
+    // keeps the receipt of a message that was just read, messages without a delete token
+    // have no status and get no receipt either
+    fn save_read_receipt(&self, message_token: &str, receipt: &ReadReceipt) -> StoreResult<()>;
+
+    // None when there is no such message, it's gone for too long or the delete token is wrong
+    fn get_message_status(
+        &self,
+        message_token: &str,
+        delete_token: &str,
+    ) -> StoreResult<Option<MessageStatus>>;
+
+    fn get_message_remaining_views(&self, message_token: &str) -> StoreResult<Option<u32>>;
+
+    // None when there is no such message, 0 when it doesn't expire
+    fn get_message_expire_timestamp(&self, message_token: &str) -> StoreResult<Option<i64>>;
+
+    // None when the message doesn't need two authorization tokens
+    fn get_message_authorization_hashes(
+        &self,
+        message_token: &str,
+    ) -> StoreResult<Option<[String; 2]>>;
+
+    // None when the message can be read without an authenticator code
+    fn get_message_totp_secret(
+        &self,
+        message_token: &str,
+    ) -> StoreResult<Option<Zeroizing<Vec<u8>>>>;
+
+    // false when a code of this step or a later one was used already, so none is used twice
+    fn use_message_totp_step(&self, message_token: &str, step: u64) -> StoreResult<bool>;
+
+    // None when there is no such message
+    fn is_message_client_encrypted(&self, message_token: &str) -> StoreResult<Option<bool>>;
+
+    // None when the message can be read at any time
+    fn get_message_not_before(&self, message_token: &str) -> StoreResult<Option<i64>>;
+
+    fn get_message_passphrase_hash(&self, message_token: &str) -> StoreResult<Option<String>>;
+
+    fn get_message_pin_hash(&self, message_token: &str) -> StoreResult<Option<String>>;
+
+    // empty when the message can be read from any address
+    fn get_message_allowed_ips(&self, message_token: &str) -> StoreResult<Vec<String>>;
+
+    // counted apart from the passphrase attempts, returns true if the message was destroyed
+    fn register_failed_pin_attempt(
+        &self,
+        message_token: &str,
+        max_attempts: u32,
+    ) -> StoreResult<bool>;
+
+    // returns true if the message was destroyed because the attempt limit was reached
+    fn register_failed_passphrase_attempt(
+        &self,
+        message_token: &str,
+        max_attempts: u32,
+    ) -> StoreResult<bool>;
+
+    // returns the removed messages
+    fn clear_expired_messages(&self, limit_timestamp: i64) -> StoreResult<Vec<ExpiredMessage>>;
+
+    // None when there is no such message or it's kept in the store itself
+    fn get_message_blob_key(&self, message_token: &str) -> StoreResult<Option<String>>;
+
+    // the messages whose data couldn't be uploaded to the blob store
+    fn remove_messages_of_blobs(&self, blob_keys: &[String]) -> StoreResult<()>;
+
+    // the transfers to and from the blob store, they are made without the store's lock held
+    fn blob_transfers(&self) -> Option<Arc<BlobTransfers>>;
+
+    // moves every message to the newest encryption key, returns the number of moved messages
+    fn reencrypt_messages(&self) -> StoreResult<usize>;
+
+    // flushes and releases the storage, it can't be used afterwards
+    fn close(&self) -> StoreResult<()>;
+}
+
+// settings that can be changed at runtime and outlive a restart
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryState {
+    // waiting for its next attempt
+    Pending,
+    Delivered,
+    // out of attempts
+    Failed,
+}
+
+impl DeliveryState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryState::Pending => "pending",
+            DeliveryState::Delivered => "delivered",
+            DeliveryState::Failed => "failed",
+        }
+    }
+
+    pub fn parse(state: &str) -> Option<DeliveryState> {
+        [
+            DeliveryState::Pending,
+            DeliveryState::Delivered,
+            DeliveryState::Failed,
+        ]
+        .into_iter()
+        .find(|candidate| candidate.as_str() == state)
+    }
+}
+
+// an event on its way to a webhook
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub url: String,
+    // e.g. "message.consumed"
+    pub event: String,
+    // the JSON body that is signed and sent
+    pub payload: String,
+    pub state: DeliveryState,
+    pub attempts: u32,
+    pub created_timestamp: i64,
+    // None once the delivery is delivered or failed
+    pub next_attempt_timestamp: Option<i64>,
+    pub last_attempt_timestamp: Option<i64>,
+    // the status the webhook answered the last attempt with
+    pub last_status: Option<u16>,
+    // why the last attempt didn't get an answer
+    pub last_error: Option<String>,
+}
+
+// how an attempt to deliver went
+pub struct WebhookAttempt<'a> {
+    pub timestamp: i64,
+    pub state: DeliveryState,
+    pub status: Option<u16>,
+    pub error: Option<&'a str>,
+    // None when there won't be another attempt
+    pub next_attempt_timestamp: Option<i64>,
+}
+
+pub trait WebhookStore {
+    // queues a delivery that is due at `timestamp`, returns its id
+    fn enqueue_webhook(
+        &self,
+        url: &str,
+        event: &str,
+        payload: &str,
+        timestamp: i64,
+    ) -> StoreResult<i64>;
+
+    // the pending deliveries that are due at `timestamp`, the oldest first
+    fn get_due_webhooks(&self, timestamp: i64, limit: usize) -> StoreResult<Vec<WebhookDelivery>>;
+
+    fn record_webhook_attempt(&self, id: i64, attempt: &WebhookAttempt) -> StoreResult<()>;
+
+    // the newest deliveries first, all of them without a state
+    fn list_webhook_deliveries(
+        &self,
+        state: Option<DeliveryState>,
+        limit: usize,
+    ) -> StoreResult<Vec<WebhookDelivery>>;
+
+    fn get_webhook_delivery(&self, id: i64) -> StoreResult<Option<WebhookDelivery>>;
+
+    // makes a failed delivery pending again with a fresh set of attempts, returns false when
+    // there is no such failed delivery
+    fn retry_webhook_delivery(&self, id: i64, timestamp: i64) -> StoreResult<bool>;
+
+    // removes the delivered and failed deliveries created before `limit_timestamp`
+    fn clear_old_webhook_deliveries(&self, limit_timestamp: i64) -> StoreResult<usize>;
+}
+
+pub trait SecretRequestStore {
+    fn save_secret_request(&self, request_token: &str, request: &SecretRequest) -> StoreResult<()>;
+
+    // None for unknown requests and the ones that expired before `timestamp`
+    fn get_secret_request(
+        &self,
+        request_token: &str,
+        timestamp: i64,
+    ) -> StoreResult<Option<SecretRequest>>;
+
+    // true when this call removed it, so a request is answered only once
+    fn remove_secret_request(&self, request_token: &str) -> StoreResult<bool>;
+
+    // clears the sealed message token once the secret of the channel is read, false when
+    // it already was
+    fn open_secret_request(&self, request_token: &str) -> StoreResult<bool>;
+
+    // returns the number of removed requests
+    fn clear_expired_secret_requests(&self, limit_timestamp: i64) -> StoreResult<usize>;
+}
+
+pub trait DownloadStore {
+    fn save_download(&self, download_id: &str, download: &PendingDownload) -> StoreResult<()>;
+
+    // None for unknown downloads and the ones that expired before `timestamp`
+    fn get_download(
+        &self,
+        download_id: &str,
+        timestamp: i64,
+    ) -> StoreResult<Option<PendingDownload>>;
+
+    // true when this call removed it
+    fn remove_download(&self, download_id: &str) -> StoreResult<bool>;
+
+    // the bytes of the files whose downloads weren't over at `timestamp`
+    fn count_pending_download_bytes(&self, timestamp: i64) -> StoreResult<u64>;
+
+    // returns the number of removed downloads
+    fn clear_expired_downloads(&self, limit_timestamp: i64) -> StoreResult<usize>;
+}
+
+pub trait AdminCredentialStore {
+    // returns the id of the credential
+    fn save_admin_credential(
+        &self,
+        credential_id: &str,
+        public_key: &[u8],
+        sign_count: u32,
+        name: &str,
+        timestamp: i64,
+    ) -> StoreResult<i64>;
+
+    // the oldest first
+    fn list_admin_credentials(&self) -> StoreResult<Vec<AdminCredential>>;
+
+    fn get_admin_credential(&self, credential_id: &str) -> StoreResult<Option<AdminCredential>>;
+
+    fn record_admin_credential_use(
+        &self,
+        id: i64,
+        sign_count: u32,
+        timestamp: i64,
+    ) -> StoreResult<()>;
+
+    // false when there is no such credential
+    fn remove_admin_credential(&self, id: i64) -> StoreResult<bool>;
+}
+
+pub trait PortalStore {
+    // creates the account on the first sign-in through the IdP, returns its id
+    fn sign_in_portal_account(
+        &self,
+        issuer: &str,
+        subject: &str,
+        email: Option<&str>,
+        timestamp: i64,
+    ) -> StoreResult<i64>;
+
+    // the key is a user token like any other, stored as its hash
+    fn create_api_key(
+        &self,
+        account_id: i64,
+        token: &str,
+        name: &str,
+        limits: &UserLimits,
+        plan: Option<&str>,
+        timestamp: i64,
+    ) -> StoreResult<i64>;
+
+    // the oldest first
+    fn list_api_keys(&self, account_id: i64) -> StoreResult<Vec<ApiKey>>;
+
+    // false when the account has no such key
+    fn remove_api_key(&self, account_id: i64, id: i64) -> StoreResult<bool>;
+
+    // puts every key of the account on the plan, e.g. when the groups of its owner changed
+    fn set_account_plan(&self, account_id: i64, plan: Option<&str>) -> StoreResult<()>;
+
+    // a self-service registration waiting for its email address to be confirmed, stored by
+    // the hash of the token, a newer one for the same address replaces it
+    fn add_registration(
+        &self,
+        token: &str,
+        email: &str,
+        expire_timestamp: i64,
+        timestamp: i64,
+    ) -> StoreResult<()>;
+
+    // the address of the registration, which can be confirmed only once
+    fn take_registration(&self, token: &str, timestamp: i64) -> StoreResult<Option<String>>;
+}
+
+pub trait InvitationStore {
+    // stored by the hash of the token, returns the id
+    fn create_invitation(
+        &self,
+        token: &str,
+        plan: Option<&str>,
+        note: Option<&str>,
+        expire_timestamp: i64,
+        timestamp: i64,
+    ) -> StoreResult<i64>;
+
+    // the ones that weren't used and haven't expired, the oldest first
+    fn list_invitations(&self, timestamp: i64) -> StoreResult<Vec<Invitation>>;
+
+    // an invitation can be used only once
+    fn take_invitation(&self, token: &str, timestamp: i64) -> StoreResult<Option<Invitation>>;
+
+    // false when there is no such invitation
+    fn remove_invitation(&self, id: i64) -> StoreResult<bool>;
+}
+
+pub trait SettingsStore {
+    fn get_global_integer(&self, name: &str) -> StoreResult<Option<i64>>;
+
+    fn set_global_integer(&self, name: &str, value: i64) -> StoreResult<()>;
+
+    fn remove_global_var(&self, name: &str) -> StoreResult<()>;
+}
+
+// everything the server needs from a storage backend
+pub trait Store:
+    UserStore
+    + MessageStore
+    + SettingsStore
+    + WebhookStore
+    + SecretRequestStore
+    + DownloadStore
+    + AdminCredentialStore
+    + PortalStore
+    + InvitationStore
+    + Send
+{
+}
+
+impl<
+        T: UserStore
+            + MessageStore
+            + SettingsStore
+            + WebhookStore
+            + SecretRequestStore
+            + DownloadStore
+            + AdminCredentialStore
+            + PortalStore
+            + InvitationStore
+            + Send,
+    > Store for T
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::OneTimeShareDb;
+
+    #[test]
+    fn test_sqlite_database_can_be_used_as_store() {
+        let store: Box<dyn Store> = Box::new(OneTimeShareDb::connect_in_memory().unwrap());
+
+        store
+            .set_user_limits("user1", &UserLimits::default())
+            .unwrap();
+        assert!(store.get_user_limits("user1").unwrap().is_some());
+
+        store
+            .save_message("token1", 0, b"Hello, world!", &MessageOptions::default())
+            .unwrap();
+        let (data, _expire) = store.try_consume_message("token1").unwrap();
+        assert_eq!(*data.unwrap(), b"Hello, world!");
+    }
+}
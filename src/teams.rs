@@ -0,0 +1,259 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::{Arc, Mutex};
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::error::AppError;
+use crate::{make_share_url, save_new_message, MessageForm, StaticData};
+
+// a day, unless the config says otherwise
+const DEFAULT_RETENTION_MINUTES: u32 = 24 * 60;
+const USAGE_TEXT: &str = "Mention me with the secret, e.g. \"@Share hunter2\", and I answer with a link that can be opened once.";
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TeamsConfig {
+    // the base64 security token Teams shows when the outgoing webhook is created
+    pub security_token: String,
+    // the links are created for this user, with their limits
+    pub user_token: String,
+    // minutes, a day by default
+    pub retention: Option<u32>,
+}
+
+// the part of a Teams activity that is used
+#[derive(Deserialize)]
+struct Activity {
+    #[serde(default)]
+    text: String,
+}
+
+// Teams expects the answer as an activity
+#[derive(Serialize, Deserialize)]
+pub struct Reply {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub text: String,
+}
+
+fn reply(text: String) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&Reply {
+            kind: "message".to_string(),
+            text,
+        })?)
+        .build())
+}
+
+// the Authorization header is "HMAC " and the base64 HMAC-SHA256 of the body, keyed with
+// the decoded security token
+fn verify_signature(security_token: &str, authorization: Option<&str>, body: &[u8]) -> bool {
+    let signature = match authorization
+        .and_then(|authorization| authorization.strip_prefix("HMAC "))
+        .and_then(|signature| STANDARD.decode(signature.trim()).ok())
+    {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let key = match STANDARD.decode(security_token) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let mut mac = Hmac::<Sha256>::new_varkey(&key).expect("HMAC can take a key of any size");
+    mac.update(body);
+    mac.verify(&signature).is_ok()
+}
+
+// Teams sends the text as HTML with the mention of the bot in it, e.g.
+// "<at>Share</at>&nbsp;hunter2"
+fn secret_text(text: &str) -> String {
+    let mut rest = text;
+    let mut without_mentions = String::new();
+    while let Some(start) = rest.find("<at>") {
+        without_mentions.push_str(&rest[..start]);
+        rest = match rest[start..].find("</at>") {
+            Some(end) => &rest[start + end + "</at>".len()..],
+            None => "",
+        };
+    }
+    without_mentions.push_str(rest);
+
+    let mut plain = String::new();
+    let mut in_tag = false;
+    for c in without_mentions.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => plain.push(c),
+            _ => {}
+        }
+    }
+    plain
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+        .trim()
+        .to_string()
+}
+
+pub async fn create_link(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let teams_config = match &req.state().lock().unwrap().config.teams {
+        Some(teams_config) => teams_config.clone(),
+        None => return Err(AppError::NotFound("Not found".to_string()).into_error()),
+    };
+    let body = req.body_bytes().await?;
+    let authorization = req
+        .header("Authorization")
+        .map(|values| values.last().as_str().to_string());
+    if !verify_signature(
+        &teams_config.security_token,
+        authorization.as_deref(),
+        &body,
+    ) {
+        return Err(AppError::BadToken("Invalid signature".to_string()).into_error());
+    }
+    let activity: Activity = serde_json::from_slice(&body).map_err(|_| {
+        AppError::BadRequest("Can't parse request body as JSON".to_string()).into_error()
+    })?;
+
+    let secret = secret_text(&activity.text);
+    if secret.is_empty() {
+        return reply(USAGE_TEXT.to_string());
+    }
+    let form = MessageForm {
+        user_token: teams_config.user_token.clone(),
+        message_data: STANDARD.encode(secret.as_bytes()).into(),
+        retention: Some(teams_config.retention.unwrap_or(DEFAULT_RETENTION_MINUTES)),
+        ..Default::default()
+    };
+
+    let data = req.state().lock().unwrap();
+    // Teams shows the answer in the channel, an error answer only as a generic failure
+    match save_new_message(&data, &form) {
+        Ok(created) => reply(format!(
+            "One-time link, it can be opened once: {}",
+            make_share_url(&req, &data.config, &created)
+        )),
+        Err(err) => reply(format!(
+            "Can't create the link: {}",
+            err.downcast_ref::<AppError>()
+                .map_or("Something went wrong", AppError::message)
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::UserLimits;
+    use crate::tests::setup_test_data;
+    use tide::http::{Method, Url};
+
+    // base64 of "secret"
+    const SECURITY_TOKEN: &str = "c2VjcmV0";
+
+    fn sign(body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(b"secret").unwrap();
+        mac.update(body.as_bytes());
+        format!("HMAC {}", STANDARD.encode(mac.finalize().into_bytes()))
+    }
+
+    #[test]
+    fn test_secret_text() {
+        assert_eq!(secret_text("<at>Share</at>&nbsp;hunter2\n"), "hunter2");
+        assert_eq!(
+            secret_text("<at>Share</at> <p>a &lt;b&gt; &amp;amp;</p>"),
+            "a <b> &amp;"
+        );
+        assert_eq!(secret_text("<at>Share</at>"), "");
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let body = r#"{"text":"hi"}"#;
+        assert!(verify_signature(
+            SECURITY_TOKEN,
+            Some(&sign(body)),
+            body.as_bytes()
+        ));
+        assert!(!verify_signature(SECURITY_TOKEN, Some(&sign(body)), b"{}"));
+        assert!(!verify_signature(
+            "b3RoZXI=",
+            Some(&sign(body)),
+            body.as_bytes()
+        ));
+        assert!(!verify_signature(SECURITY_TOKEN, None, body.as_bytes()));
+        assert!(!verify_signature(
+            SECURITY_TOKEN,
+            Some("HMAC !!"),
+            body.as_bytes()
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_create_link() {
+        let app_data = setup_test_data();
+        let app = crate::init_app(app_data.clone());
+        let make_request = |body: &str, authorization: &str| {
+            let mut req = tide::http::Request::new(
+                Method::Post,
+                Url::parse("https://localhost/api/v1/integrations/teams").unwrap(),
+            );
+            req.insert_header("Authorization", authorization);
+            req.set_body(body);
+            req
+        };
+        let body = r#"{"type":"message","text":"<at>Share</at>&nbsp;hunter2"}"#;
+
+        let res: tide::http::Response = app.respond(make_request(body, &sign(body))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        {
+            let mut data = app_data.lock().unwrap();
+            data.config.teams = Some(TeamsConfig {
+                security_token: SECURITY_TOKEN.to_string(),
+                user_token: "teams".to_string(),
+                retention: Some(60),
+            });
+            data.database
+                .lock()
+                .unwrap()
+                .set_user_limits("teams", &UserLimits::default())
+                .unwrap();
+        }
+
+        let res: tide::http::Response = app
+            .respond(make_request(body, "HMAC c2lnbmF0dXJl"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let mut res: tide::http::Response =
+            app.respond(make_request(body, &sign(body))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let reply: Reply = res.take_body().into_json().await.unwrap();
+        assert_eq!(reply.kind, "message");
+        let url = reply.text.rsplit(' ').next().unwrap();
+        assert!(url.starts_with("https://localhost/shared/"));
+
+        let token = url.rsplit('/').next().unwrap();
+        let req = tide::http::Request::new(
+            Method::Post,
+            Url::parse(&format!(
+                "https://localhost/api/v1/messages/{}/consume",
+                token
+            ))
+            .unwrap(),
+        );
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        // base64 of "hunter2"
+        assert!(res.body_string().await.unwrap().contains("aHVudGVyMg=="));
+    }
+}
@@ -0,0 +1,363 @@
+use async_std::{future, task};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use http_types::{Body, Method, Request, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::blob_transfers::{current_task_id, run_blob_transfers};
+use crate::error::AppError;
+use crate::http_client::HttpClient;
+use crate::{make_configured_share_url, save_new_message, MessageForm, StaticData};
+
+const DEFAULT_API_BASE_URL: &str = "https://api.telegram.org";
+// Telegram holds a poll open for this long when there are no updates
+const POLL_TIMEOUT_SECONDS: u64 = 30;
+// a little more than the poll, so a held poll doesn't count as a failure
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(POLL_TIMEOUT_SECONDS + 10);
+// after a failed poll, so an outage of the API isn't hammered
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+// a day, unless the config says otherwise
+const DEFAULT_RETENTION_MINUTES: u32 = 24 * 60;
+const USAGE_TEXT: &str = "Send me a secret and I answer with a link that can be opened once. I remove your message from the chat after that.";
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TelegramConfig {
+    // the token BotFather gives
+    pub bot_token: String,
+    // the links are created for this user, with their limits
+    pub user_token: String,
+    // minutes, a day by default
+    pub retention: Option<u32>,
+    // the Telegram user IDs that can use the bot, anyone when empty
+    #[serde(default)]
+    pub allowed_user_ids: Vec<i64>,
+    // only for tests and local Bot API servers
+    pub api_base_url: Option<String>,
+    pub ca_bundle_path: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiResponse<T> {
+    ok: bool,
+    result: Option<T>,
+    description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Update {
+    update_id: i64,
+    message: Option<ChatMessage>,
+}
+
+#[derive(Deserialize)]
+struct ChatMessage {
+    message_id: i64,
+    chat: Chat,
+    from: Option<User>,
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Chat {
+    id: i64,
+}
+
+#[derive(Deserialize)]
+struct User {
+    id: i64,
+}
+
+struct Answer {
+    text: String,
+    // the message held a secret that is now behind a link
+    remove_message: bool,
+}
+
+impl Answer {
+    fn text(text: &str) -> Option<Answer> {
+        Some(Answer {
+            text: text.to_string(),
+            remove_message: false,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct TelegramBot {
+    client: HttpClient,
+    config: TelegramConfig,
+}
+
+impl TelegramBot {
+    pub fn new(config: &TelegramConfig) -> std::io::Result<Self> {
+        Ok(TelegramBot {
+            client: HttpClient::new(config.ca_bundle_path.as_deref())?,
+            config: config.clone(),
+        })
+    }
+
+    fn method_url(&self, method: &str) -> http_types::Result<Url> {
+        Ok(Url::parse(&format!(
+            "{}/bot{}/{}",
+            self.config
+                .api_base_url
+                .as_deref()
+                .unwrap_or(DEFAULT_API_BASE_URL)
+                .trim_end_matches('/'),
+            self.config.bot_token,
+            method
+        ))?)
+    }
+
+    async fn call<T: for<'de> Deserialize<'de>>(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> http_types::Result<T> {
+        let url = self.method_url(method)?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => String::new(),
+        };
+        let mut req = Request::new(Method::Post, url);
+        req.insert_header("Host", host);
+        req.set_body(Body::from_json(&params)?);
+        let mut res = future::timeout(REQUEST_TIMEOUT, self.client.send(req))
+            .await
+            .map_err(|_| http_types::Error::from_str(StatusCode::GatewayTimeout, "Timed out"))??;
+        // the errors are described in the body too
+        let response: ApiResponse<T> = res.body_json().await?;
+        match response.result {
+            Some(result) if response.ok => Ok(result),
+            _ => Err(http_types::Error::from_str(
+                res.status(),
+                format!(
+                    "Telegram {} failed: {}",
+                    method,
+                    response.description.unwrap_or_default()
+                ),
+            )),
+        }
+    }
+
+    async fn send_message(&self, chat_id: i64, text: &str) -> http_types::Result<()> {
+        self.call::<serde_json::Value>(
+            "sendMessage",
+            serde_json::json!({ "chat_id": chat_id, "text": text }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    // the answer to a message, None when it gets none
+    fn answer(&self, data: &Mutex<StaticData>, message: &ChatMessage) -> Option<Answer> {
+        let text = message.text.as_deref()?.trim();
+        let is_allowed = self.config.allowed_user_ids.is_empty()
+            || message
+                .from
+                .as_ref()
+                .is_some_and(|user| self.config.allowed_user_ids.contains(&user.id));
+        if !is_allowed {
+            return Answer::text("You are not allowed to use this bot.");
+        }
+        if text.is_empty() || text.starts_with('/') {
+            return Answer::text(USAGE_TEXT);
+        }
+
+        let form = MessageForm {
+            user_token: self.config.user_token.clone(),
+            message_data: STANDARD.encode(text.as_bytes()).into(),
+            retention: Some(self.config.retention.unwrap_or(DEFAULT_RETENTION_MINUTES)),
+            ..Default::default()
+        };
+        let data = data.lock().unwrap();
+        match save_new_message(&data, &form) {
+            Ok(created) => match make_configured_share_url(&data.config, &created.message_token) {
+                Some(url) => Some(Answer {
+                    text: format!("One-time link, it can be opened once: {}", url),
+                    remove_message: true,
+                }),
+                None => Answer::text("The server has no public base URL to make links with."),
+            },
+            Err(err) => Answer::text(&format!(
+                "Can't create the link: {}",
+                err.downcast_ref::<AppError>()
+                    .map_or("Something went wrong", AppError::message)
+            )),
+        }
+    }
+
+    // answers the updates after `offset` and returns the offset of the next poll
+    async fn poll(&self, data: &Mutex<StaticData>, offset: i64) -> http_types::Result<i64> {
+        let updates: Vec<Update> = self
+            .call(
+                "getUpdates",
+                serde_json::json!({
+                    "offset": offset,
+                    "timeout": POLL_TIMEOUT_SECONDS,
+                    "allowed_updates": ["message"],
+                }),
+            )
+            .await?;
+        let mut offset = offset;
+        for update in updates {
+            offset = offset.max(update.update_id + 1);
+            let message = match update.message {
+                Some(message) => message,
+                None => continue,
+            };
+            let answer = match self.answer(data, &message) {
+                Some(answer) => answer,
+                None => continue,
+            };
+            // the link is sent only once its message is stored for good
+            let database = data.lock().unwrap().database.clone();
+            let answer = match run_blob_transfers(&database, current_task_id()).await {
+                Ok(true) => answer,
+                _ => Answer {
+                    text: "Can't create the link: Storage error, try again later".to_string(),
+                    remove_message: false,
+                },
+            };
+            if let Err(err) = self.send_message(message.chat.id, &answer.text).await {
+                log::error!("Failed to answer on Telegram: {}", err);
+                continue;
+            }
+            if !answer.remove_message {
+                continue;
+            }
+            // the secret shouldn't stay in the chat history, bots can only remove recent
+            // messages so this may fail
+            if let Err(err) = self
+                .call::<bool>(
+                    "deleteMessage",
+                    serde_json::json!({
+                        "chat_id": message.chat.id,
+                        "message_id": message.message_id,
+                    }),
+                )
+                .await
+            {
+                log::debug!("Can't remove a message on Telegram: {}", err);
+            }
+        }
+        Ok(offset)
+    }
+}
+
+// answers the messages sent to the bot until the task is cancelled
+pub fn spawn_telegram_bot(data: Arc<Mutex<StaticData>>, bot: TelegramBot) -> task::JoinHandle<()> {
+    task::spawn(async move {
+        let mut offset = 0;
+        loop {
+            match bot.poll(&data, offset).await {
+                Ok(next_offset) => offset = next_offset,
+                Err(err) => {
+                    log::error!("Failed to poll Telegram: {}", err);
+                    task::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::UserLimits;
+    use crate::tests::setup_test_data;
+    use async_std::net::TcpListener;
+
+    // answers the calls of the bot like the Bot API would, and reports them
+    async fn serve_api(listener: TcpListener, calls: async_std::channel::Sender<(String, String)>) {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let calls = calls.clone();
+            async_h1::accept(stream, |mut req| {
+                let calls = calls.clone();
+                async move {
+                    let method = req.url().path().rsplit('/').next().unwrap().to_string();
+                    let body = req.body_string().await?;
+                    let result = match method.as_str() {
+                        "getUpdates" => serde_json::json!([
+                            { "update_id": 7, "message": { "message_id": 1, "chat": { "id": 42 }, "from": { "id": 100 }, "text": "hunter2" } },
+                            { "update_id": 8, "message": { "message_id": 2, "chat": { "id": 43 }, "from": { "id": 101 }, "text": "other" } },
+                        ]),
+                        "sendMessage" => serde_json::json!({ "message_id": 3 }),
+                        _ => serde_json::json!(true),
+                    };
+                    calls.send((method, body)).await?;
+                    let mut res = http_types::Response::new(StatusCode::Ok);
+                    res.set_body(Body::from_json(&serde_json::json!({ "ok": true, "result": result }))?);
+                    Ok(res)
+                }
+            })
+            .await
+            .unwrap();
+        }
+    }
+
+    #[async_std::test]
+    async fn test_poll() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bot = TelegramBot::new(&TelegramConfig {
+            bot_token: "123:abc".to_string(),
+            user_token: "telegram".to_string(),
+            retention: Some(60),
+            allowed_user_ids: vec![100],
+            api_base_url: Some(format!("http://{}", listener.local_addr().unwrap())),
+            ca_bundle_path: None,
+        })
+        .unwrap();
+        let (sender, receiver) = async_std::channel::unbounded();
+        let server = task::spawn(serve_api(listener, sender));
+
+        let app_data = setup_test_data();
+        {
+            let mut data = app_data.lock().unwrap();
+            data.config.public_base_url = Some("https://share.example.com/".to_string());
+            data.database
+                .lock()
+                .unwrap()
+                .set_user_limits("telegram", &UserLimits::default())
+                .unwrap();
+        }
+
+        assert_eq!(bot.poll(&app_data, 0).await.unwrap(), 9);
+        server.cancel().await;
+
+        let calls: Vec<(String, serde_json::Value)> =
+            std::iter::from_fn(|| receiver.try_recv().ok())
+                .map(|(method, body)| (method, serde_json::from_str(&body).unwrap()))
+                .collect();
+        let methods: Vec<&str> = calls.iter().map(|(method, _)| method.as_str()).collect();
+        assert_eq!(
+            methods,
+            vec!["getUpdates", "sendMessage", "deleteMessage", "sendMessage"]
+        );
+        assert_eq!(calls[1].1["chat_id"], 42);
+        let link = calls[1].1["text"]
+            .as_str()
+            .unwrap()
+            .rsplit(' ')
+            .next()
+            .unwrap();
+        assert!(link.starts_with("https://share.example.com/shared/"));
+        assert_eq!(
+            calls[2].1,
+            serde_json::json!({ "chat_id": 42, "message_id": 1 })
+        );
+        // the other user isn't allowed, their message is left alone
+        assert_eq!(calls[3].1["chat_id"], 43);
+        assert_eq!(calls[3].1["text"], "You are not allowed to use this bot.");
+
+        let token = link.rsplit('/').next().unwrap();
+        let database = app_data.lock().unwrap().database.clone();
+        let (message_data, _) = database.lock().unwrap().try_consume_message(token).unwrap();
+        assert_eq!(message_data.unwrap().as_slice(), b"hunter2");
+    }
+}
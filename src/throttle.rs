@@ -0,0 +1,48 @@
+use std::fmt;
+use std::time::Duration;
+
+// the client is over a limit and can try again after `retry_after`
+#[derive(Debug)]
+pub struct Throttled {
+    pub message: String,
+    pub retry_after: Duration,
+}
+
+impl Throttled {
+    pub fn new(message: impl Into<String>, retry_after: Duration) -> Self {
+        Throttled {
+            message: message.into(),
+            retry_after,
+        }
+    }
+
+    // rounded up, so a client that waits exactly this long isn't refused again
+    pub fn retry_after_seconds(&self) -> u64 {
+        (self.retry_after.as_secs_f64().ceil() as u64).max(1)
+    }
+}
+
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Throttled {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_after_seconds() {
+        assert_eq!(
+            Throttled::new("Slow down", Duration::from_millis(1500)).retry_after_seconds(),
+            2
+        );
+        assert_eq!(
+            Throttled::new("Slow down", Duration::ZERO).retry_after_seconds(),
+            1
+        );
+    }
+}
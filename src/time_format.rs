@@ -0,0 +1,115 @@
+// converts days since the unix epoch to (year, month, day)
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u32, day as u32)
+}
+
+// returns the UTC time in the "2024-02-29T23:59:59.123Z" format
+pub fn format_rfc3339_millis(unix_timestamp_millis: u64) -> String {
+    let unix_timestamp = unix_timestamp_millis / 1000;
+    let (year, month, day) = civil_from_days((unix_timestamp / 86400) as i64);
+    let seconds_of_day = unix_timestamp % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        (seconds_of_day / 60) % 60,
+        seconds_of_day % 60,
+        unix_timestamp_millis % 1000
+    )
+}
+
+// returns the UTC calendar month in the "2024-02" format
+pub fn format_year_month(unix_timestamp: u64) -> String {
+    let (year, month, _) = civil_from_days((unix_timestamp / 86400) as i64);
+    format!("{:04}-{:02}", year, month)
+}
+
+const MONTH_NAMES: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// returns the UTC time in the "10/Oct/2000:13:55:36 +0000" format of the common log format
+pub fn format_common_log_date(unix_timestamp: u64) -> String {
+    let (year, month, day) = civil_from_days((unix_timestamp / 86400) as i64);
+    let seconds_of_day = unix_timestamp % 86400;
+    format!(
+        "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTH_NAMES[month as usize - 1],
+        year,
+        seconds_of_day / 3600,
+        (seconds_of_day / 60) % 60,
+        seconds_of_day % 60
+    )
+}
+
+const WEEKDAY_NAMES: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+// returns the UTC time in the "Sun, 06 Nov 1994 08:49:37 GMT" format of the HTTP headers
+pub fn format_http_date(unix_timestamp: u64) -> String {
+    let days = (unix_timestamp / 86400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let seconds_of_day = unix_timestamp % 86400;
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        // the epoch was a Thursday
+        WEEKDAY_NAMES[((days + 4) % 7) as usize],
+        day,
+        MONTH_NAMES[month as usize - 1],
+        year,
+        seconds_of_day / 3600,
+        (seconds_of_day / 60) % 60,
+        seconds_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_rfc3339_millis() {
+        assert_eq!(format_rfc3339_millis(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_rfc3339_millis(1709251199123),
+            "2024-02-29T23:59:59.123Z"
+        );
+    }
+
+    #[test]
+    fn test_format_year_month() {
+        assert_eq!(format_year_month(0), "1970-01");
+        assert_eq!(format_year_month(1709251199), "2024-02");
+        assert_eq!(format_year_month(1709251200), "2024-03");
+    }
+
+    #[test]
+    fn test_format_common_log_date() {
+        assert_eq!(
+            format_common_log_date(971186136),
+            "10/Oct/2000:13:55:36 +0000"
+        );
+    }
+
+    #[test]
+    fn test_format_http_date() {
+        assert_eq!(format_http_date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format_http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+}
@@ -0,0 +1,61 @@
+use std::time::Duration;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+// the handler is dropped at its next await point once the time is up, work that blocks
+// the thread (e.g. waiting for an SQLite lock) is bounded by the database busy timeout
+pub struct TimeoutMiddleware {
+    timeout: Duration,
+}
+
+impl TimeoutMiddleware {
+    pub fn new(timeout: Duration) -> Self {
+        TimeoutMiddleware { timeout }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for TimeoutMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let method = req.method();
+        match async_std::future::timeout(self.timeout, next.run(req)).await {
+            Ok(res) => Ok(res),
+            Err(_) => {
+                log::warn!(
+                    "{} request timed out after {} second(s)",
+                    method,
+                    self.timeout.as_secs()
+                );
+                let mut res = Response::new(StatusCode::ServiceUnavailable);
+                res.insert_header("Retry-After", self.timeout.as_secs().to_string());
+                Ok(res)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tide::http::{Method, Url};
+
+    #[async_std::test]
+    async fn test_slow_request_times_out() {
+        let mut app = tide::new();
+        app.with(TimeoutMiddleware::new(Duration::from_millis(50)));
+        app.at("/fast").get(|_| async { Ok("done") });
+        app.at("/slow").get(|_| async {
+            async_std::task::sleep(Duration::from_secs(5)).await;
+            Ok("done")
+        });
+
+        let req =
+            tide::http::Request::new(Method::Get, Url::parse("http://localhost/fast").unwrap());
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let req =
+            tide::http::Request::new(Method::Get, Url::parse("http://localhost/slow").unwrap());
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+    }
+}
@@ -0,0 +1,326 @@
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, Certificate, ClientHello, NoClientAuth, PrivateKey,
+    ProtocolVersion, ResolvesServerCert, RootCertStore, ServerConfig, SupportedCipherSuite,
+    ALL_CIPHERSUITES,
+};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Seek, SeekFrom};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+// how often the certificate files are checked for changes
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// async-h1 speaks only HTTP/1.x
+const SUPPORTED_ALPN_PROTOCOLS: [&str; 2] = ["http/1.1", "http/1.0"];
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsOptions {
+    // "1.2" (default) or "1.3"
+    pub min_version: Option<String>,
+    // rustls names such as "TLS13_AES_256_GCM_SHA384", all supported suites are enabled when empty
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    #[serde(default)]
+    pub alpn_protocols: Vec<String>,
+    // PEM file with the CAs that client certificates are verified against, clients without
+    // a certificate can still connect, see adminAuth.requireClientCert
+    pub client_ca_path: Option<String>,
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn parse_versions(min_version: Option<&str>) -> io::Result<Vec<ProtocolVersion>> {
+    match min_version {
+        None | Some("1.2") => Ok(vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]),
+        Some("1.3") => Ok(vec![ProtocolVersion::TLSv1_3]),
+        Some(version) => Err(invalid_input(format!(
+            "Unsupported minimal TLS version: {}",
+            version
+        ))),
+    }
+}
+
+fn parse_cipher_suites(
+    names: &[String],
+    versions: &[ProtocolVersion],
+) -> io::Result<Vec<&'static SupportedCipherSuite>> {
+    let suites = if names.is_empty() {
+        ALL_CIPHERSUITES.to_vec()
+    } else {
+        names
+            .iter()
+            .map(|name| {
+                ALL_CIPHERSUITES
+                    .iter()
+                    .find(|suite| format!("{:?}", suite.suite) == *name)
+                    .copied()
+                    .ok_or_else(|| invalid_input(format!("Unknown cipher suite: {}", name)))
+            })
+            .collect::<io::Result<Vec<_>>>()?
+    };
+
+    let suites: Vec<_> = suites
+        .into_iter()
+        .filter(|suite| versions.iter().any(|v| suite.usable_for_version(*v)))
+        .collect();
+    if suites.is_empty() {
+        return Err(invalid_input(
+            "None of the cipher suites can be used with the enabled TLS versions".to_string(),
+        ));
+    }
+    Ok(suites)
+}
+
+fn parse_alpn_protocols(protocols: &[String]) -> io::Result<Vec<Vec<u8>>> {
+    protocols
+        .iter()
+        .map(|protocol| {
+            if SUPPORTED_ALPN_PROTOCOLS.contains(&protocol.as_str()) {
+                Ok(protocol.as_bytes().to_vec())
+            } else {
+                Err(invalid_input(format!(
+                    "Unsupported ALPN protocol: {}",
+                    protocol
+                )))
+            }
+        })
+        .collect()
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    match certs(&mut BufReader::new(File::open(path)?)) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Invalid certificate",
+        )),
+    }
+}
+
+fn load_client_cas(path: &str) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(&cert)
+            .map_err(|err| invalid_input(format!("Invalid client CA certificate: {:?}", err)))?;
+    }
+    Ok(roots)
+}
+
+// accepts both PKCS#8 and RSA private keys
+fn load_private_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if let Ok(mut keys) = pkcs8_private_keys(&mut reader) {
+        if !keys.is_empty() {
+            return Ok(keys.remove(0));
+        }
+    }
+
+    reader.seek(SeekFrom::Start(0))?;
+    if let Ok(mut keys) = rsa_private_keys(&mut reader) {
+        if !keys.is_empty() {
+            return Ok(keys.remove(0));
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Invalid private key",
+    ))
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> io::Result<CertifiedKey> {
+    let signing_key = sign::any_supported_type(&load_private_key(key_path)?)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Unsupported private key type"))?;
+    Ok(CertifiedKey::new(
+        load_certs(cert_path)?,
+        Arc::new(signing_key),
+    ))
+}
+
+// the latest modification time of the two files
+fn files_modified_time(cert_path: &str, key_path: &str) -> Option<SystemTime> {
+    let cert_modified = fs::metadata(cert_path).and_then(|m| m.modified()).ok()?;
+    let key_modified = fs::metadata(key_path).and_then(|m| m.modified()).ok()?;
+    Some(cert_modified.max(key_modified))
+}
+
+// serves the certificate from disk and picks up renewed files without a restart
+pub struct ReloadingCertResolver {
+    cert_path: String,
+    key_path: String,
+    current: RwLock<(CertifiedKey, Option<SystemTime>)>,
+}
+
+impl ReloadingCertResolver {
+    pub fn new(cert_path: &str, key_path: &str) -> io::Result<Self> {
+        let modified_time = files_modified_time(cert_path, key_path);
+        Ok(ReloadingCertResolver {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            current: RwLock::new((load_certified_key(cert_path, key_path)?, modified_time)),
+        })
+    }
+
+    // returns true if the files changed and the new certificate was loaded,
+    // on error the previous certificate stays in use
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let modified_time = files_modified_time(&self.cert_path, &self.key_path);
+        if modified_time == self.current.read().unwrap().1 {
+            return Ok(false);
+        }
+        let certified_key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = (certified_key, modified_time);
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current.read().unwrap().0.clone())
+    }
+}
+
+pub fn spawn_cert_reload_task(resolver: Arc<ReloadingCertResolver>) {
+    async_std::task::spawn(async move {
+        loop {
+            async_std::task::sleep(CERT_CHECK_INTERVAL).await;
+            match resolver.reload_if_changed() {
+                Ok(true) => log::info!("Reloaded TLS certificate from {}", resolver.cert_path),
+                Ok(false) => {}
+                Err(err) => log::warn!("Failed to reload TLS certificate: {}", err),
+            }
+        }
+    });
+}
+
+pub fn make_server_config(
+    cert_resolver: Arc<ReloadingCertResolver>,
+    options: &TlsOptions,
+) -> io::Result<ServerConfig> {
+    let client_auth = match &options.client_ca_path {
+        Some(client_ca_path) => {
+            AllowAnyAnonymousOrAuthenticatedClient::new(load_client_cas(client_ca_path)?)
+        }
+        None => NoClientAuth::new(),
+    };
+    let mut config = ServerConfig::new(client_auth);
+    config.cert_resolver = cert_resolver;
+    config.versions = parse_versions(options.min_version.as_deref())?;
+    config.ciphersuites = parse_cipher_suites(&options.cipher_suites, &config.versions)?;
+    config.set_protocols(&parse_alpn_protocols(&options.alpn_protocols)?);
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const TEST_CERT_PATH: &str = "testdata/localhost-cert.pem";
+    const TEST_KEY_PATH: &str = "testdata/localhost-key.pem";
+
+    #[test]
+    fn test_parse_versions() {
+        assert_eq!(parse_versions(None).unwrap().len(), 2);
+        assert_eq!(
+            parse_versions(Some("1.3")).unwrap(),
+            vec![ProtocolVersion::TLSv1_3]
+        );
+        assert!(parse_versions(Some("1.1")).is_err());
+    }
+
+    #[test]
+    fn test_parse_cipher_suites() {
+        let all_versions = parse_versions(None).unwrap();
+        assert_eq!(
+            parse_cipher_suites(&[], &all_versions).unwrap().len(),
+            ALL_CIPHERSUITES.len()
+        );
+
+        let names = vec![
+            "TLS13_AES_256_GCM_SHA384".to_string(),
+            "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384".to_string(),
+        ];
+        assert_eq!(parse_cipher_suites(&names, &all_versions).unwrap().len(), 2);
+
+        // TLS 1.2 suites are dropped when only TLS 1.3 is enabled
+        let tls13_only = parse_versions(Some("1.3")).unwrap();
+        assert_eq!(parse_cipher_suites(&names, &tls13_only).unwrap().len(), 1);
+        assert!(parse_cipher_suites(&names[1..], &tls13_only).is_err());
+
+        assert!(parse_cipher_suites(&["TLS_NULL".to_string()], &all_versions).is_err());
+    }
+
+    #[test]
+    fn test_parse_alpn_protocols() {
+        assert_eq!(
+            parse_alpn_protocols(&["http/1.1".to_string()]).unwrap(),
+            vec![b"http/1.1".to_vec()]
+        );
+        assert!(parse_alpn_protocols(&["h2".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_client_ca_is_loaded() {
+        let roots = load_client_cas(TEST_CERT_PATH).unwrap();
+        assert_eq!(roots.len(), 1);
+        assert!(load_client_cas(TEST_KEY_PATH).is_err());
+
+        let resolver = Arc::new(ReloadingCertResolver::new(TEST_CERT_PATH, TEST_KEY_PATH).unwrap());
+        let options = TlsOptions {
+            client_ca_path: Some(TEST_CERT_PATH.to_string()),
+            ..TlsOptions::default()
+        };
+        assert!(make_server_config(resolver, &options).is_ok());
+    }
+
+    #[test]
+    fn test_certificate_is_reloaded_when_files_change() {
+        let temp_dir = TempDir::new().unwrap();
+        let cert_path = temp_dir.path().join("cert.pem");
+        let key_path = temp_dir.path().join("key.pem");
+        fs::copy(TEST_CERT_PATH, &cert_path).unwrap();
+        fs::copy(TEST_KEY_PATH, &key_path).unwrap();
+        let cert_path = cert_path.to_str().unwrap();
+        let key_path = key_path.to_str().unwrap();
+
+        let resolver = ReloadingCertResolver::new(cert_path, key_path).unwrap();
+        assert!(!resolver.reload_if_changed().unwrap());
+
+        File::options()
+            .write(true)
+            .open(cert_path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert!(resolver.reload_if_changed().unwrap());
+        assert!(!resolver.reload_if_changed().unwrap());
+    }
+
+    #[test]
+    fn test_broken_certificate_keeps_the_previous_one() {
+        let temp_dir = TempDir::new().unwrap();
+        let cert_path = temp_dir.path().join("cert.pem");
+        fs::copy(TEST_CERT_PATH, &cert_path).unwrap();
+        let cert_path = cert_path.to_str().unwrap();
+
+        let resolver = ReloadingCertResolver::new(cert_path, TEST_KEY_PATH).unwrap();
+        fs::write(cert_path, "not a certificate").unwrap();
+        File::options()
+            .write(true)
+            .open(cert_path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+
+        assert!(resolver.reload_if_changed().is_err());
+        assert!(!resolver.current.read().unwrap().0.cert.is_empty());
+    }
+}
@@ -0,0 +1,317 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+const DEFAULT_ALPHABET: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+// as many bits as a version 4 UUID carries
+const DEFAULT_LENGTH: usize = 22;
+const MAX_LENGTH: usize = 128;
+// below this a token could be found by trying, even with the brute force protection
+const MIN_RECOMMENDED_BITS: f64 = 64.0;
+const MIN_SIGNING_KEY_SIZE_BYTES: usize = 32;
+// 64 bits of the HMAC, a forgery is found by trying no sooner than a token itself
+const SIGNATURE_SIZE_BYTES: usize = 8;
+const SIGNATURE_SEPARATOR: char = '.';
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenStyle {
+    #[default]
+    Uuid4,
+    // starts with the creation time, so the tokens of new messages sort after the old ones
+    Uuid7,
+    // `length` characters picked from `alphabet`
+    Random,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TokenConfig {
+    pub style: TokenStyle,
+    pub length: usize,
+    pub alphabet: String,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        TokenConfig {
+            style: TokenStyle::default(),
+            length: DEFAULT_LENGTH,
+            alphabet: DEFAULT_ALPHABET.to_string(),
+        }
+    }
+}
+
+impl TokenConfig {
+    // the tokens end up in the links, so only the characters that need no escaping are allowed
+    pub fn validate(&self) -> Result<(), String> {
+        if self.style != TokenStyle::Random {
+            return Ok(());
+        }
+        if self.length == 0 || self.length > MAX_LENGTH {
+            return Err(format!(
+                "messageTokens.length should be between 1 and {}",
+                MAX_LENGTH
+            ));
+        }
+        if let Some(c) = self
+            .alphabet
+            .chars()
+            .find(|c| !c.is_ascii_alphanumeric() && !"-._~".contains(*c))
+        {
+            return Err(format!(
+                "messageTokens.alphabet can't contain '{}', only letters, digits and \"-._~\" are allowed",
+                c
+            ));
+        }
+        let mut chars: Vec<char> = self.alphabet.chars().collect();
+        chars.sort_unstable();
+        chars.dedup();
+        if chars.len() != self.alphabet.len() || chars.len() < 2 {
+            return Err(
+                "messageTokens.alphabet should have at least 2 characters, each of them once"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    // bits an attacker has to guess
+    pub fn strength_bits(&self) -> f64 {
+        match self.style {
+            TokenStyle::Uuid4 => 122.0,
+            // the 74 random bits, the time can be guessed
+            TokenStyle::Uuid7 => 74.0,
+            TokenStyle::Random => self.length as f64 * (self.alphabet.len() as f64).log2(),
+        }
+    }
+
+    pub fn is_weak(&self) -> bool {
+        self.strength_bits() < MIN_RECOMMENDED_BITS
+    }
+
+    pub fn generate(&self) -> String {
+        match self.style {
+            TokenStyle::Uuid4 => Uuid::new_v4().to_string(),
+            TokenStyle::Uuid7 => Uuid::now_v7().to_string(),
+            TokenStyle::Random => random_string(self.length, self.alphabet.as_bytes()),
+        }
+    }
+}
+
+// bytes that would make some characters more likely than others are skipped
+fn random_string(length: usize, alphabet: &[u8]) -> String {
+    let limit = 256 - 256 % alphabet.len();
+    let mut token = String::with_capacity(length);
+    let mut bytes = [0u8; 64];
+    while token.len() < length {
+        OsRng.fill_bytes(&mut bytes);
+        for byte in bytes.iter().map(|byte| *byte as usize) {
+            if byte < limit && token.len() < length {
+                token.push(alphabet[byte % alphabet.len()] as char);
+            }
+        }
+    }
+    token
+}
+
+// `byte_count` random bytes, for the tokens the server hands out by itself such as sessions
+pub(crate) fn random_base64url(byte_count: usize) -> String {
+    let mut bytes = vec![0u8; byte_count];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[derive(Debug)]
+pub struct TokenSigningError(pub String);
+
+impl fmt::Display for TokenSigningError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for TokenSigningError {}
+
+// appends an HMAC of the token to it, so a made-up token is refused before the database
+// is asked about it
+pub struct TokenSigner {
+    key: Zeroizing<Vec<u8>>,
+}
+
+impl TokenSigner {
+    pub fn from_base64_key(key: &str) -> Result<Self, TokenSigningError> {
+        let key = Zeroizing::new(STANDARD.decode(key.trim()).map_err(|err| {
+            TokenSigningError(format!("Token signing key is not valid base64: {}", err))
+        })?);
+        if key.len() < MIN_SIGNING_KEY_SIZE_BYTES {
+            return Err(TokenSigningError(format!(
+                "Token signing key should be at least {} bytes long, got {}",
+                MIN_SIGNING_KEY_SIZE_BYTES,
+                key.len()
+            )));
+        }
+        Ok(TokenSigner { key })
+    }
+
+    // for what is only kept in memory, such as the admin sessions, the key dies with the process
+    pub(crate) fn random() -> Self {
+        let mut key = Zeroizing::new(vec![0u8; MIN_SIGNING_KEY_SIZE_BYTES]);
+        OsRng.fill_bytes(key.as_mut_slice());
+        TokenSigner { key }
+    }
+
+    pub fn from_key_file(path: &str) -> Result<Self, TokenSigningError> {
+        let key = Zeroizing::new(fs::read_to_string(path).map_err(|err| {
+            TokenSigningError(format!("Can't read token signing key file: {}", err))
+        })?);
+        Self::from_base64_key(&key)
+    }
+
+    fn signature(&self, token: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(token.as_bytes());
+        mac.finalize().into_bytes()[..SIGNATURE_SIZE_BYTES]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    pub fn sign(&self, token: &str) -> String {
+        format!("{}{}{}", token, SIGNATURE_SEPARATOR, self.signature(token))
+    }
+
+    // the signature has a fixed length, so the comparison takes the same time for any of them
+    pub fn verify(&self, signed_token: &str) -> bool {
+        match signed_token.rsplit_once(SIGNATURE_SEPARATOR) {
+            Some((token, signature)) if signature.len() == SIGNATURE_SIZE_BYTES * 2 => {
+                self.signature(token)
+                    .bytes()
+                    .zip(signature.bytes())
+                    .fold(0, |diff, (l, r)| diff | (l ^ r))
+                    == 0
+            }
+            _ => false,
+        }
+    }
+
+    // the token without its signature, if the signature is right
+    pub fn unsign<'a>(&self, signed_token: &'a str) -> Option<&'a str> {
+        if !self.verify(signed_token) {
+            return None;
+        }
+        signed_token
+            .rsplit_once(SIGNATURE_SEPARATOR)
+            .map(|(token, _)| token)
+    }
+}
+
+// the database is searched by the hash, so the time an index lookup takes says nothing
+// about how much of a guessed token is right
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// compares the digests in constant time, so neither the length of the token nor the
+// position of the first wrong byte shows in the timing
+pub fn is_same_token(left: &str, right: &str) -> bool {
+    Sha256::digest(left.as_bytes())
+        .iter()
+        .zip(Sha256::digest(right.as_bytes()).iter())
+        .fold(0, |diff, (l, r)| diff | (l ^ r))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_token() {
+        assert_eq!(
+            hash_token("test_token"),
+            "cc0af97287543b65da2c7e1476426021826cab166f1e063ed012b855ff819656"
+        );
+        assert_ne!(hash_token("test_token"), hash_token("test_tokeN"));
+    }
+
+    #[test]
+    fn test_is_same_token() {
+        assert!(is_same_token("secret", "secret"));
+        assert!(!is_same_token("secret", "secreT"));
+        assert!(!is_same_token("secret", "secret2"));
+        assert!(!is_same_token("", "secret"));
+    }
+
+    #[test]
+    fn test_generate() {
+        let uuid4 = TokenConfig::default().generate();
+        assert_eq!(Uuid::parse_str(&uuid4).unwrap().get_version_num(), 4);
+
+        let uuid7 = TokenConfig {
+            style: TokenStyle::Uuid7,
+            ..Default::default()
+        }
+        .generate();
+        assert_eq!(Uuid::parse_str(&uuid7).unwrap().get_version_num(), 7);
+
+        let config = TokenConfig {
+            style: TokenStyle::Random,
+            length: 30,
+            alphabet: "abc".to_string(),
+        };
+        let token = config.generate();
+        assert_eq!(token.len(), 30);
+        assert!(token.chars().all(|c| "abc".contains(c)));
+        assert_ne!(config.generate(), config.generate());
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer =
+            TokenSigner::from_base64_key("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+        let other_signer =
+            TokenSigner::from_base64_key("ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=").unwrap();
+
+        let signed = signer.sign("token1");
+        assert!(signed.starts_with("token1."));
+        assert_eq!(signed.len(), "token1.".len() + 16);
+        assert!(signer.verify(&signed));
+        assert!(!other_signer.verify(&signed));
+        assert!(!signer.verify("token1"));
+        assert!(!signer.verify(&signed.replace("token1", "token2")));
+        assert!(!signer.verify(&signed[..signed.len() - 1]));
+        assert!(TokenSigner::from_base64_key("c2hvcnQ=").is_err());
+
+        assert_eq!(signer.unsign(&signed), Some("token1"));
+        assert_eq!(other_signer.unsign(&signed), None);
+        assert_eq!(TokenSigner::random().unsign(&signed), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let random = |length: usize, alphabet: &str| TokenConfig {
+            style: TokenStyle::Random,
+            length,
+            alphabet: alphabet.to_string(),
+        };
+        assert!(TokenConfig::default().validate().is_ok());
+        assert!(random(12, "0123456789abcdef-_").validate().is_ok());
+        assert!(random(0, "abc").validate().is_err());
+        assert!(random(200, "abc").validate().is_err());
+        assert!(random(12, "a").validate().is_err());
+        assert!(random(12, "aab").validate().is_err());
+        assert!(random(12, "ab/").validate().is_err());
+        assert!(random(12, "abä").validate().is_err());
+
+        assert!(!random(22, DEFAULT_ALPHABET).is_weak());
+        assert!(random(8, DEFAULT_ALPHABET).is_weak());
+    }
+}
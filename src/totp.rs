@@ -0,0 +1,277 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use zeroize::Zeroizing;
+
+// time-based one-time codes of RFC 6238 as authenticator apps make them: HMAC-SHA1, 6 digits
+// and 30 second steps, the apps don't agree on anything else; SHA-1 is written out here as
+// none of the dependencies has it, it's only used inside the HMAC
+
+const DIGITS: u32 = 6;
+pub(crate) const STEP_SECONDS: u64 = 30;
+// a step either way, for a phone clock that is a bit off or a code typed in at the last second
+const ALLOWED_DRIFT_STEPS: u64 = 1;
+// 160 bits, as RFC 4226 recommends
+const SECRET_BYTES: usize = 20;
+// 80 bits, the shortest secrets authenticator apps still hand out
+pub const MIN_SECRET_BYTES: usize = 10;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+const ISSUER: &str = "One%20Time%20Share";
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    // the padded copy holds the key of the HMAC
+    let mut message = Zeroizing::new(data.to_vec());
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, added) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, value) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    // a key longer than a block is hashed first
+    let mut block_key = Zeroizing::new(vec![0u8; 64]);
+    if key.len() > 64 {
+        block_key[..20].copy_from_slice(&sha1(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Zeroizing::new(block_key.iter().map(|byte| byte ^ 0x36).collect::<Vec<_>>());
+    inner.extend_from_slice(message);
+    let mut outer = Zeroizing::new(block_key.iter().map(|byte| byte ^ 0x5c).collect::<Vec<_>>());
+    outer.extend_from_slice(&sha1(&inner));
+    sha1(&outer)
+}
+
+// the code of HOTP (RFC 4226) for the counter, TOTP counts the steps since the epoch
+pub(crate) fn code_at(secret: &[u8], step: u64) -> u32 {
+    let digest = hmac_sha1(secret, &step.to_be_bytes());
+    let offset = (digest[19] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    truncated % 10u32.pow(DIGITS)
+}
+
+// the step the code was made for, when it's one of the steps around `now`; the caller makes
+// sure a step isn't accepted twice
+pub fn verify_code(secret: &[u8], code: &str, now: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current_step = now / STEP_SECONDS;
+    (current_step.saturating_sub(ALLOWED_DRIFT_STEPS)..=current_step + ALLOWED_DRIFT_STEPS)
+        .find(|&step| code_at(secret, step) == code)
+}
+
+pub fn generate_secret() -> Zeroizing<Vec<u8>> {
+    let mut secret = Zeroizing::new(vec![0u8; SECRET_BYTES]);
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+// RFC 4648 without padding, the way authenticator apps show secrets
+pub fn encode_base32(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[(buffer >> bits) as usize & 0x1f] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 0x1f] as char);
+    }
+    encoded
+}
+
+// takes secrets as people copy them: any case, with spaces or dashes between the groups and
+// with or without padding
+pub fn decode_base32(text: &str) -> Option<Zeroizing<Vec<u8>>> {
+    let mut decoded = Zeroizing::new(Vec::with_capacity(text.len() * 5 / 8));
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        if c == b' ' || c == b'-' {
+            continue;
+        }
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&letter| letter == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+// for the QR code or the "add account" link of an authenticator app
+pub fn otpauth_uri(secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{issuer}?secret={}&issuer={issuer}&algorithm=SHA1&digits={}&period={}",
+        encode_base32(secret),
+        DIGITS,
+        STEP_SECONDS,
+        issuer = ISSUER
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(&sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        // two blocks after the padding
+        assert_eq!(
+            hex(&sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn test_hmac_sha1() {
+        // RFC 2202, the second one has a key longer than a block
+        assert_eq!(
+            hex(&hmac_sha1(b"Jefe", b"what do ya want for nothing?")),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+        assert_eq!(
+            hex(&hmac_sha1(
+                &[0xaa; 80],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "aa4ae5e15272d00e95705637ce8a3b55ed402112"
+        );
+    }
+
+    #[test]
+    fn test_rfc_6238_codes() {
+        // the SHA-1 vectors of the RFC, cut to 6 digits
+        let secret = b"12345678901234567890";
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(verify_code(secret, code, time), Some(time / STEP_SECONDS));
+        }
+    }
+
+    #[test]
+    fn test_verify_code_window() {
+        let secret = generate_secret();
+        let now = 1_700_000_000;
+        let step = now / STEP_SECONDS;
+        let code = |step: u64| format!("{:06}", code_at(&secret, step));
+
+        assert_eq!(verify_code(&secret, &code(step - 1), now), Some(step - 1));
+        assert_eq!(verify_code(&secret, &code(step + 1), now), Some(step + 1));
+        assert_eq!(
+            verify_code(&secret, &format!(" {} ", code(step)), now),
+            Some(step)
+        );
+        // the codes of other steps could match by chance, one in a million
+        if ![step - 1, step, step + 1]
+            .iter()
+            .any(|&near| code(near) == code(step - 2))
+        {
+            assert_eq!(verify_code(&secret, &code(step - 2), now), None);
+        }
+        assert_eq!(verify_code(&secret, "12345", now), None);
+        assert_eq!(verify_code(&secret, "12345a", now), None);
+    }
+
+    #[test]
+    fn test_base32() {
+        // RFC 4648
+        for (data, encoded) in [
+            (&b""[..], ""),
+            (b"f", "MY"),
+            (b"fo", "MZXQ"),
+            (b"foo", "MZXW6"),
+            (b"foob", "MZXW6YQ"),
+            (b"fooba", "MZXW6YTB"),
+            (b"foobar", "MZXW6YTBOI"),
+        ] {
+            assert_eq!(encode_base32(data), encoded);
+            assert_eq!(&decode_base32(encoded).unwrap()[..], data);
+        }
+        assert_eq!(&decode_base32("mzxw 6ytb-oi======").unwrap()[..], b"foobar");
+        assert!(decode_base32("MZXW1").is_none());
+
+        let secret = generate_secret();
+        assert_eq!(
+            &decode_base32(&encode_base32(&secret)).unwrap()[..],
+            &secret[..]
+        );
+        assert!(otpauth_uri(&secret).contains(&encode_base32(&secret)));
+    }
+}
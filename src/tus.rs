@@ -0,0 +1,599 @@
+use async_std::io::ReadExt;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tide::http::headers::CONTENT_TYPE;
+use tide::{Request, Response, StatusCode};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::api::{MESSAGE_DELETE_TOKEN_HEADER, MESSAGE_SHA256_HEADER};
+use crate::error::AppError;
+use crate::throttle::Throttled;
+use crate::time_format::format_http_date;
+use crate::{
+    get_user_limits, make_share_url, save_new_message, text_share_url, MessageForm, StaticData,
+    CLIENT_ENCRYPTION_OVERHEAD_BYTES,
+};
+
+// the resumable upload protocol, https://tus.io/protocols/resumable-upload
+pub const UPLOADS_PATH: &str = "/api/v1/uploads";
+pub const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,termination";
+const TUS_RESUMABLE: &str = "Tus-Resumable";
+const UPLOAD_LENGTH: &str = "Upload-Length";
+const UPLOAD_OFFSET: &str = "Upload-Offset";
+const UPLOAD_METADATA: &str = "Upload-Metadata";
+const UPLOAD_EXPIRES: &str = "Upload-Expires";
+const CHUNK_CONTENT_TYPE: &str = "application/offset+octet-stream";
+// the finished upload tells where its message is
+const MESSAGE_URL: &str = "Message-Url";
+const MESSAGE_TOKEN: &str = "Message-Token";
+// what browser clients on other origins have to be able to read
+pub const EXPOSED_HEADERS: &str =
+    "Location, Tus-Resumable, Upload-Offset, Upload-Length, Upload-Expires, Message-Url, Message-Token, Message-Delete-Token";
+
+pub const DEFAULT_MAX_UPLOAD_BYTES: u64 = 100 * 1024 * 1024;
+const UPLOAD_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+// the uploads are kept in memory until they are complete
+const MAX_PENDING_UPLOADS: usize = 1000;
+const MAX_PENDING_BYTES: u64 = 1024 * 1024 * 1024;
+
+struct Upload {
+    // everything but the data, which comes in chunks
+    form: MessageForm,
+    length: u64,
+    data: Zeroizing<Vec<u8>>,
+    expire_timestamp: u64,
+}
+
+#[derive(Default)]
+pub struct Uploads {
+    pending: HashMap<String, Upload>,
+}
+
+impl Uploads {
+    fn remove_expired(&mut self, now: u64) {
+        self.pending
+            .retain(|_, upload| upload.expire_timestamp > now);
+    }
+}
+
+fn now_seconds() -> tide::Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+fn tus_response(status: StatusCode) -> Response {
+    let mut res = Response::new(status);
+    res.insert_header(TUS_RESUMABLE, TUS_VERSION);
+    // the offsets change with every chunk
+    res.insert_header("Cache-Control", "no-store");
+    res
+}
+
+// None when the client speaks a version this server knows
+fn unsupported_version<State>(req: &Request<State>) -> Option<Response> {
+    let version = req
+        .header(TUS_RESUMABLE)
+        .map(|values| values.last().as_str().to_string());
+    if version.as_deref() == Some(TUS_VERSION) {
+        return None;
+    }
+    let mut res = tus_response(StatusCode::PreconditionFailed);
+    res.insert_header("Tus-Version", TUS_VERSION);
+    Some(res)
+}
+
+fn parse_number_header<State>(req: &Request<State>, name: &str) -> Result<u64, AppError> {
+    req.header(name)
+        .and_then(|values| values.last().as_str().trim().parse().ok())
+        .ok_or_else(|| AppError::BadRequest(format!("{} header is missing or invalid", name)))
+}
+
+// "user_token dGVzdA==,filename aGVsbG8udHh0", the values are base64 and can be left out
+fn parse_metadata(metadata: &str) -> Result<HashMap<String, String>, AppError> {
+    let invalid = || AppError::BadRequest(format!("{} header is invalid", UPLOAD_METADATA));
+    let mut values = HashMap::new();
+    for pair in metadata
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let (key, value) = match pair.split_once(' ') {
+            Some((key, value)) => {
+                let value = STANDARD.decode(value.trim()).map_err(|_| invalid())?;
+                (key, String::from_utf8(value).map_err(|_| invalid())?)
+            }
+            None => (pair, String::new()),
+        };
+        values.insert(key.to_string(), value);
+    }
+    Ok(values)
+}
+
+// the same fields as the other ways of creating a message, tus clients name the file
+// "filename" and its type "filetype"
+fn form_from_metadata(metadata: &HashMap<String, String>) -> Result<MessageForm, AppError> {
+    let value = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| metadata.get(*name))
+            .filter(|value| !value.is_empty())
+            .cloned()
+    };
+    let retention = match value(&["retention"]) {
+        Some(retention) => Some(retention.parse().map_err(|_| AppError::Invalid {
+            field: "retention",
+            message: "Can't parse retention limit".to_string(),
+        })?),
+        None => None,
+    };
+    let max_views = match value(&["max_views"]) {
+        Some(max_views) => Some(max_views.parse().map_err(|_| AppError::Invalid {
+            field: "max_views",
+            message: "Can't parse max views".to_string(),
+        })?),
+        None => None,
+    };
+    let not_before = match value(&["not_before"]) {
+        Some(not_before) => Some(not_before.parse().map_err(|_| AppError::Invalid {
+            field: "not_before",
+            message: "Can't parse not before time".to_string(),
+        })?),
+        None => None,
+    };
+    let activate_in_minutes = match value(&["activate_in_minutes"]) {
+        Some(activate_in_minutes) => {
+            Some(activate_in_minutes.parse().map_err(|_| AppError::Invalid {
+                field: "activate_in_minutes",
+                message: "Can't parse activation delay".to_string(),
+            })?)
+        }
+        None => None,
+    };
+    let grace_minutes = match value(&["grace_minutes"]) {
+        Some(grace_minutes) => Some(grace_minutes.parse().map_err(|_| AppError::Invalid {
+            field: "grace_minutes",
+            message: "Can't parse grace minutes".to_string(),
+        })?),
+        None => None,
+    };
+    Ok(MessageForm {
+        user_token: value(&["user_token"]).unwrap_or_default(),
+        message_data: Zeroizing::default(),
+        retention,
+        passphrase: value(&["passphrase"]).into(),
+        pin: value(&["pin"]).into(),
+        // comma separated, like the other lists of the metadata would be
+        allowed_ips: value(&["allowed_ips"]).map(|allowed_ips| {
+            allowed_ips
+                .split(',')
+                .map(|allowed_ip| allowed_ip.trim().to_string())
+                .collect()
+        }),
+        // a key without a value turns it on
+        end_to_end: metadata
+            .get("end_to_end")
+            .map(|value| value.is_empty() || value == "true"),
+        filename: value(&["filename", "name"]),
+        content_type: value(&["filetype", "content_type"]),
+        slug: value(&["slug"]),
+        webhook_url: value(&["webhook_url"]),
+        sms_to: value(&["sms_to"]),
+        // the upload answers with a single link
+        recipients: None,
+        max_views,
+        grace_minutes,
+        not_before,
+        activate_in_minutes,
+        // the authorization tokens would have no header to come back in
+        dual_control: None,
+        totp_secret: value(&["totp_secret"]).into(),
+        // and neither would a new authenticator secret, a given one works
+        totp: None,
+    })
+}
+
+pub async fn describe_server(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    let data = req.state().lock().unwrap();
+    let mut res = tus_response(StatusCode::NoContent);
+    res.insert_header("Tus-Version", TUS_VERSION);
+    res.insert_header("Tus-Extension", TUS_EXTENSIONS);
+    res.insert_header(
+        "Tus-Max-Size",
+        data.config
+            .max_upload_bytes
+            .unwrap_or(DEFAULT_MAX_UPLOAD_BYTES)
+            .to_string(),
+    );
+    Ok(res)
+}
+
+pub async fn create_upload(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    if let Some(res) = unsupported_version(&req) {
+        return Ok(res);
+    }
+    let length = parse_number_header(&req, UPLOAD_LENGTH).map_err(AppError::into_error)?;
+    let metadata = match req.header(UPLOAD_METADATA) {
+        Some(values) => parse_metadata(values.last().as_str()),
+        None => Ok(HashMap::new()),
+    }
+    .map_err(AppError::into_error)?;
+    let form = form_from_metadata(&metadata).map_err(AppError::into_error)?;
+    if length == 0 {
+        return Err(AppError::BadRequest("Upload is empty".to_string()).into_error());
+    }
+
+    let data = req.state().lock().unwrap();
+    // checked up front, so nobody uploads a file only to learn it's too big
+    let user_limits = match get_user_limits(&data, &form.user_token)? {
+        Some(user_limits) => user_limits,
+        None => return Err(AppError::NotFound("User not found".to_string()).into_error()),
+    };
+    let max_size_bytes = match user_limits.max_message_size_bytes as u64 {
+        0 => u64::MAX,
+        max_size_bytes if form.end_to_end == Some(true) => {
+            max_size_bytes + CLIENT_ENCRYPTION_OVERHEAD_BYTES as u64
+        }
+        max_size_bytes => max_size_bytes,
+    };
+    let max_upload_bytes = data
+        .config
+        .max_upload_bytes
+        .unwrap_or(DEFAULT_MAX_UPLOAD_BYTES);
+    if length > max_size_bytes.min(max_upload_bytes) {
+        return Err(AppError::TooLarge("Message is too big".to_string()).into_error());
+    }
+
+    let now = now_seconds()?;
+    let mut uploads = data.uploads.lock().unwrap();
+    uploads.remove_expired(now);
+    let pending_bytes: u64 = uploads.pending.values().map(|upload| upload.length).sum();
+    if uploads.pending.len() >= MAX_PENDING_UPLOADS || pending_bytes + length > MAX_PENDING_BYTES {
+        return Err(AppError::Busy(Throttled::new(
+            "Too many uploads in progress, try again later".to_string(),
+            Duration::from_secs(60),
+        ))
+        .into_error());
+    }
+
+    let upload_id = Uuid::new_v4().to_string();
+    let expire_timestamp = now + UPLOAD_LIFETIME.as_secs();
+    uploads.pending.insert(
+        upload_id.clone(),
+        Upload {
+            form,
+            length,
+            // reserved at once, a buffer that grows leaves copies of the data behind
+            data: Zeroizing::new(Vec::with_capacity(length as usize)),
+            expire_timestamp,
+        },
+    );
+
+    let mut res = tus_response(StatusCode::Created);
+    res.insert_header("Location", format!("{}/{}", UPLOADS_PATH, upload_id));
+    res.insert_header(UPLOAD_EXPIRES, format_http_date(expire_timestamp));
+    Ok(res)
+}
+
+pub async fn get_upload_offset(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    if let Some(res) = unsupported_version(&req) {
+        return Ok(res);
+    }
+    let upload_id = req.param("upload_id")?;
+
+    let data = req.state().lock().unwrap();
+    let mut uploads = data.uploads.lock().unwrap();
+    uploads.remove_expired(now_seconds()?);
+    let upload = match uploads.pending.get(upload_id) {
+        Some(upload) => upload,
+        None => return Err(AppError::NotFound("Upload not found".to_string()).into_error()),
+    };
+
+    let mut res = tus_response(StatusCode::Ok);
+    res.insert_header(UPLOAD_OFFSET, upload.data.len().to_string());
+    res.insert_header(UPLOAD_LENGTH, upload.length.to_string());
+    res.insert_header(UPLOAD_EXPIRES, format_http_date(upload.expire_timestamp));
+    Ok(res)
+}
+
+// the chunk that completes the upload turns it into a message; if that fails, e.g. because
+// the creation limit is reached, an empty chunk at the final offset tries again
+// into a buffer of the announced size, for the same reason as the upload itself; the body
+// limit caps the announced size too
+async fn read_chunk(req: &mut Request<Arc<Mutex<StaticData>>>) -> tide::Result<Zeroizing<Vec<u8>>> {
+    let length = match req.len() {
+        Some(length) => length,
+        None => {
+            return Err(
+                AppError::BadRequest("Chunks need a Content-Length".to_string()).into_error(),
+            )
+        }
+    };
+    let mut chunk = Zeroizing::new(vec![0; length]);
+    req.read_exact(&mut chunk).await?;
+    Ok(chunk)
+}
+
+pub async fn append_chunk(mut req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    if let Some(res) = unsupported_version(&req) {
+        return Ok(res);
+    }
+    let is_chunk = req
+        .header(CONTENT_TYPE)
+        .is_some_and(|values| values.last().as_str() == CHUNK_CONTENT_TYPE);
+    if !is_chunk {
+        return Err(AppError::UnsupportedMediaType(format!(
+            "Chunks are sent as {}",
+            CHUNK_CONTENT_TYPE
+        ))
+        .into_error());
+    }
+    let offset = parse_number_header(&req, UPLOAD_OFFSET).map_err(AppError::into_error)?;
+    let chunk = read_chunk(&mut req).await?;
+    let upload_id = req.param("upload_id")?.to_string();
+
+    let data = req.state().lock().unwrap();
+    let mut uploads = data.uploads.lock().unwrap();
+    uploads.remove_expired(now_seconds()?);
+    let upload = match uploads.pending.get_mut(&upload_id) {
+        Some(upload) => upload,
+        None => return Err(AppError::NotFound("Upload not found".to_string()).into_error()),
+    };
+    if offset != upload.data.len() as u64 {
+        return Err(AppError::Conflict(format!(
+            "{} is {}, but the upload is at {}",
+            UPLOAD_OFFSET,
+            offset,
+            upload.data.len()
+        ))
+        .into_error());
+    }
+    if offset + chunk.len() as u64 > upload.length {
+        return Err(AppError::BadRequest(format!(
+            "Chunk goes past {} of {}",
+            UPLOAD_LENGTH, upload.length
+        ))
+        .into_error());
+    }
+    upload.data.extend_from_slice(&chunk);
+
+    let mut res = tus_response(StatusCode::NoContent);
+    res.insert_header(UPLOAD_OFFSET, upload.data.len().to_string());
+    if (upload.data.len() as u64) < upload.length {
+        return Ok(res);
+    }
+
+    let mut upload = uploads.pending.remove(&upload_id).unwrap();
+    upload.form.message_data = STANDARD.encode(&upload.data).into();
+    match save_new_message(&data, &upload.form) {
+        Ok(created) => {
+            let url = make_share_url(&req, &data.config, &created);
+            text_share_url(&data, &upload.form, &url);
+            res.insert_header(MESSAGE_URL, url);
+            res.insert_header(MESSAGE_TOKEN, created.message_token);
+            res.insert_header(MESSAGE_DELETE_TOKEN_HEADER, created.delete_token);
+            res.insert_header(MESSAGE_SHA256_HEADER, created.checksum);
+            Ok(res)
+        }
+        Err(err) => {
+            upload.form.message_data = Zeroizing::default();
+            uploads.pending.insert(upload_id, upload);
+            Err(err)
+        }
+    }
+}
+
+pub async fn delete_upload(req: Request<Arc<Mutex<StaticData>>>) -> tide::Result {
+    if let Some(res) = unsupported_version(&req) {
+        return Ok(res);
+    }
+    let upload_id = req.param("upload_id")?;
+
+    let data = req.state().lock().unwrap();
+    let removed = data.uploads.lock().unwrap().pending.remove(upload_id);
+    match removed {
+        Some(_) => Ok(tus_response(StatusCode::NoContent)),
+        None => Err(AppError::NotFound("Upload not found".to_string()).into_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init_app;
+    use crate::store::UserLimits;
+    use crate::tests::setup_test_data;
+    use tide::http::{Method, Url};
+
+    fn setup_app() -> tide::Server<Arc<Mutex<StaticData>>> {
+        let app_data = setup_test_data();
+        app_data
+            .lock()
+            .unwrap()
+            .database
+            .lock()
+            .unwrap()
+            .set_user_limits(
+                "test_token",
+                &UserLimits {
+                    max_message_size_bytes: 1024,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        init_app(app_data)
+    }
+
+    fn tus_request(method: Method, path: &str) -> tide::http::Request {
+        let mut req = tide::http::Request::new(
+            method,
+            Url::parse(&format!("http://localhost{}", path)).unwrap(),
+        );
+        req.insert_header(TUS_RESUMABLE, TUS_VERSION);
+        req
+    }
+
+    fn chunk_request(location: &str, offset: usize, chunk: &[u8]) -> tide::http::Request {
+        let mut req = tus_request(Method::Patch, location);
+        req.insert_header(UPLOAD_OFFSET, offset.to_string());
+        req.insert_header(CONTENT_TYPE, CHUNK_CONTENT_TYPE);
+        req.set_body(chunk.to_vec());
+        req
+    }
+
+    #[test]
+    fn test_parse_metadata() {
+        let metadata =
+            parse_metadata("user_token dGVzdA==, filename aGVsbG8udHh0,end_to_end").unwrap();
+        assert_eq!(metadata["user_token"], "test");
+        assert_eq!(metadata["filename"], "hello.txt");
+        assert_eq!(metadata["end_to_end"], "");
+        assert!(parse_metadata("user_token not-base64!").is_err());
+
+        let form = form_from_metadata(&metadata).unwrap();
+        assert_eq!(form.user_token, "test");
+        assert_eq!(form.end_to_end, Some(true));
+    }
+
+    #[async_std::test]
+    async fn test_resumable_upload() {
+        let app = setup_app();
+
+        let req = tus_request(Method::Options, UPLOADS_PATH);
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NoContent);
+        assert_eq!(res["Tus-Extension"], TUS_EXTENSIONS);
+
+        let mut req = tus_request(Method::Post, UPLOADS_PATH);
+        req.insert_header(UPLOAD_LENGTH, "11");
+        req.insert_header(
+            UPLOAD_METADATA,
+            format!("user_token {}", STANDARD.encode("test_token")),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Created);
+        let location = res["Location"].as_str().to_string();
+
+        let res: tide::http::Response = app
+            .respond(chunk_request(&location, 0, b"Hello"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NoContent);
+        assert_eq!(res[UPLOAD_OFFSET], "5");
+
+        // the connection broke, the client asks where to go on from
+        let res: tide::http::Response = app
+            .respond(tus_request(Method::Head, &location))
+            .await
+            .unwrap();
+        assert_eq!(res[UPLOAD_OFFSET], "5");
+        assert_eq!(res[UPLOAD_LENGTH], "11");
+
+        let res: tide::http::Response = app
+            .respond(chunk_request(&location, 0, b"Hello"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Conflict);
+
+        let res: tide::http::Response = app
+            .respond(chunk_request(&location, 5, b" world"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NoContent);
+        assert_eq!(res[UPLOAD_OFFSET], "11");
+        let message_token = res[MESSAGE_TOKEN].as_str().to_string();
+        assert!(res[MESSAGE_URL].as_str().ends_with(&message_token));
+
+        let res: tide::http::Response = app
+            .respond(tus_request(Method::Head, &location))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let req = tide::http::Request::new(
+            Method::Post,
+            Url::parse(&format!(
+                "http://localhost/api/v1/messages/{}/consume",
+                message_token
+            ))
+            .unwrap(),
+        );
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        let body: crate::api::ConsumeMessageResponse = res.body_json().await.unwrap();
+        assert_eq!(*body.message_data, STANDARD.encode("Hello world"));
+    }
+
+    #[async_std::test]
+    async fn test_create_upload_checks_limits() {
+        let app = setup_app();
+
+        let req = tide::http::Request::new(
+            Method::Post,
+            Url::parse(&format!("http://localhost{}", UPLOADS_PATH)).unwrap(),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PreconditionFailed);
+        assert_eq!(res["Tus-Version"], TUS_VERSION);
+
+        let mut req = tus_request(Method::Post, UPLOADS_PATH);
+        req.insert_header(UPLOAD_LENGTH, "11");
+        req.insert_header(
+            UPLOAD_METADATA,
+            format!("user_token {}", STANDARD.encode("nobody")),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let mut req = tus_request(Method::Post, UPLOADS_PATH);
+        req.insert_header(UPLOAD_LENGTH, "1000000");
+        req.insert_header(
+            UPLOAD_METADATA,
+            format!("user_token {}", STANDARD.encode("test_token")),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+    }
+
+    #[async_std::test]
+    async fn test_upload_buffer_does_not_grow() {
+        let app = setup_app();
+        let mut req = tus_request(Method::Post, UPLOADS_PATH);
+        req.insert_header(UPLOAD_LENGTH, "11");
+        req.insert_header(
+            UPLOAD_METADATA,
+            format!("user_token {}", STANDARD.encode("test_token")),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        let location = res["Location"].as_str().to_string();
+        let buffer = || {
+            let data = app.state().lock().unwrap();
+            let uploads = data.uploads.lock().unwrap();
+            let upload = uploads.pending.values().next().unwrap();
+            (upload.data.as_ptr(), upload.data.capacity())
+        };
+        let (pointer, capacity) = buffer();
+        assert_eq!(capacity, 11);
+
+        let res: tide::http::Response = app
+            .respond(chunk_request(&location, 0, b"Hello"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NoContent);
+        assert_eq!(buffer(), (pointer, capacity));
+
+        // a chunk of unknown size can't be read into a buffer made for it
+        let mut req = tus_request(Method::Patch, &location);
+        req.insert_header(UPLOAD_OFFSET, "5");
+        req.insert_header(CONTENT_TYPE, CHUNK_CONTENT_TYPE);
+        req.set_body(tide::http::Body::from_reader(
+            async_std::io::Cursor::new(b" world".to_vec()),
+            None,
+        ));
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        assert_eq!(buffer(), (pointer, capacity));
+    }
+}
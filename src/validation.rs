@@ -0,0 +1,572 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use http_types::Url;
+
+use crate::error::AppError;
+use crate::proxy::IpNetwork;
+use crate::sms::is_valid_phone_number;
+use crate::totp::{decode_base32, MIN_SECRET_BYTES};
+use crate::MessageForm;
+
+const MAX_USER_TOKEN_LENGTH: usize = 128;
+const MIN_SLUG_LENGTH: usize = 3;
+const MAX_SLUG_LENGTH: usize = 64;
+const MAX_WEBHOOK_URL_LENGTH: usize = 2048;
+const MAX_RECIPIENTS: usize = 50;
+const MAX_RECIPIENT_NAME_LENGTH: usize = 100;
+const MAX_VIEWS: u32 = 100;
+// long enough to reopen a crashed browser, short enough not to undo the one-time link
+const MAX_GRACE_MINUTES: u32 = 60;
+// a year ahead covers the rotation schedules the delayed messages are staged for
+const MAX_ACTIVATION_MINUTES: u32 = 365 * 24 * 60;
+// authenticator secrets are 10 to 20 bytes, some apps use the 64 of a SHA-512 key
+const MAX_TOTP_SECRET_BYTES: usize = 64;
+// short enough to be read out over the phone, the attempt limit makes up for it
+const MIN_PIN_LENGTH: usize = 4;
+const MAX_PIN_LENGTH: usize = 8;
+const MAX_ALLOWED_IPS: usize = 50;
+// ten years, the expiry timestamps stay far from any overflow
+pub(crate) const MAX_RETENTION_MINUTES: u32 = 10 * 365 * 24 * 60;
+
+fn invalid(field: &'static str, message: &str) -> AppError {
+    AppError::Invalid {
+        field,
+        message: message.to_string(),
+    }
+}
+
+// the token is looked up in the database and can end up in the logs, so it's kept simple
+fn validate_user_token(user_token: &str) -> Result<(), AppError> {
+    if user_token.is_empty() {
+        return Err(invalid("user_token", "User token is empty"));
+    }
+    if user_token.len() > MAX_USER_TOKEN_LENGTH
+        || !user_token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        return Err(invalid("user_token", "User token has an invalid format"));
+    }
+    Ok(())
+}
+
+// the slug is a path segment of the link, so it's limited to what reads well there
+fn validate_slug(slug: Option<&str>) -> Result<(), AppError> {
+    let slug = match slug {
+        Some(slug) if !slug.is_empty() => slug,
+        _ => return Ok(()),
+    };
+    if slug.len() < MIN_SLUG_LENGTH || slug.len() > MAX_SLUG_LENGTH {
+        return Err(invalid(
+            "slug",
+            &format!(
+                "Slug should be {} to {} characters long",
+                MIN_SLUG_LENGTH, MAX_SLUG_LENGTH
+            ),
+        ));
+    }
+    if !slug
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        || slug.starts_with('-')
+        || slug.ends_with('-')
+    {
+        return Err(invalid(
+            "slug",
+            "Slug can only have lowercase letters, digits and dashes between them",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_webhook_url(webhook_url: Option<&str>) -> Result<(), AppError> {
+    let webhook_url = match webhook_url {
+        Some(webhook_url) if !webhook_url.is_empty() => webhook_url,
+        _ => return Ok(()),
+    };
+    if webhook_url.len() > MAX_WEBHOOK_URL_LENGTH {
+        return Err(invalid("webhook_url", "Webhook URL is too long"));
+    }
+    match Url::parse(webhook_url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.host_str().is_some() => Ok(()),
+        _ => Err(invalid(
+            "webhook_url",
+            "Webhook URL should be an http or https URL",
+        )),
+    }
+}
+
+fn validate_sms_to(sms_to: Option<&str>) -> Result<(), AppError> {
+    match sms_to {
+        Some(sms_to) if !sms_to.is_empty() && !is_valid_phone_number(sms_to) => Err(invalid(
+            "sms_to",
+            "Phone number should be in the international format, e.g. +15551234567",
+        )),
+        _ => Ok(()),
+    }
+}
+
+// the names only tell the links apart for the creator, they aren't stored
+fn validate_recipients(form: &MessageForm) -> Result<(), AppError> {
+    let recipients = match &form.recipients {
+        Some(recipients) if !recipients.is_empty() => recipients,
+        _ => return Ok(()),
+    };
+    if recipients.len() > MAX_RECIPIENTS {
+        return Err(invalid(
+            "recipients",
+            &format!("A message can have up to {} recipients", MAX_RECIPIENTS),
+        ));
+    }
+    if recipients
+        .iter()
+        .any(|name| name.trim().is_empty() || name.chars().count() > MAX_RECIPIENT_NAME_LENGTH)
+    {
+        return Err(invalid(
+            "recipients",
+            &format!(
+                "Recipient names should be 1 to {} characters long",
+                MAX_RECIPIENT_NAME_LENGTH
+            ),
+        ));
+    }
+    if recipients
+        .iter()
+        .enumerate()
+        .any(|(i, name)| recipients[..i].contains(name))
+    {
+        return Err(invalid("recipients", "Recipient names should be unique"));
+    }
+    // a slug names a single link, and a texted link would reach only one of them
+    if form.slug.as_deref().is_some_and(|slug| !slug.is_empty()) {
+        return Err(invalid(
+            "recipients",
+            "A message for several recipients can't have a slug",
+        ));
+    }
+    if form
+        .sms_to
+        .as_deref()
+        .is_some_and(|sms_to| !sms_to.is_empty())
+    {
+        return Err(invalid(
+            "recipients",
+            "The links of a message for several recipients can't be texted",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_max_views(max_views: Option<u32>) -> Result<(), AppError> {
+    match max_views {
+        Some(max_views) if max_views == 0 || max_views > MAX_VIEWS => Err(invalid(
+            "max_views",
+            &format!("Max views should be from 1 to {}", MAX_VIEWS),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn validate_grace_minutes(grace_minutes: Option<u32>) -> Result<(), AppError> {
+    match grace_minutes {
+        Some(grace_minutes) if grace_minutes > MAX_GRACE_MINUTES => Err(invalid(
+            "grace_minutes",
+            &format!("Grace can't be longer than {} minutes", MAX_GRACE_MINUTES),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn validate_activation(form: &MessageForm) -> Result<(), AppError> {
+    match form.activate_in_minutes {
+        Some(activate_in_minutes) if activate_in_minutes > MAX_ACTIVATION_MINUTES => Err(invalid(
+            "activate_in_minutes",
+            &format!(
+                "Activation can't be later than {} minutes",
+                MAX_ACTIVATION_MINUTES
+            ),
+        )),
+        // both tell when the message can be read
+        Some(_) if form.not_before.is_some() => Err(invalid(
+            "activate_in_minutes",
+            "Activation delay can't be combined with not_before",
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn validate_totp_secret(totp_secret: Option<&str>) -> Result<(), AppError> {
+    let totp_secret = match totp_secret {
+        Some(totp_secret) if !totp_secret.is_empty() => totp_secret,
+        _ => return Ok(()),
+    };
+    match decode_base32(totp_secret) {
+        None => Err(invalid("totp_secret", "TOTP secret is not valid base32")),
+        Some(secret) if secret.len() < MIN_SECRET_BYTES || secret.len() > MAX_TOTP_SECRET_BYTES => {
+            Err(invalid(
+                "totp_secret",
+                &format!(
+                    "TOTP secret should be {} to {} bytes long",
+                    MIN_SECRET_BYTES, MAX_TOTP_SECRET_BYTES
+                ),
+            ))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+fn validate_pin(pin: Option<&str>) -> Result<(), AppError> {
+    match pin {
+        Some(pin)
+            if !pin.is_empty()
+                && (pin.len() < MIN_PIN_LENGTH
+                    || pin.len() > MAX_PIN_LENGTH
+                    || !pin.bytes().all(|byte| byte.is_ascii_digit())) =>
+        {
+            Err(invalid(
+                "pin",
+                &format!(
+                    "PIN should be {} to {} digits",
+                    MIN_PIN_LENGTH, MAX_PIN_LENGTH
+                ),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_allowed_ips(allowed_ips: Option<&[String]>) -> Result<(), AppError> {
+    let allowed_ips = allowed_ips.unwrap_or_default();
+    if allowed_ips.len() > MAX_ALLOWED_IPS {
+        return Err(invalid(
+            "allowed_ips",
+            &format!("Up to {} addresses can be allowed", MAX_ALLOWED_IPS),
+        ));
+    }
+    match allowed_ips
+        .iter()
+        .find(|allowed_ip| IpNetwork::parse(allowed_ip).is_none())
+    {
+        Some(allowed_ip) => Err(invalid(
+            "allowed_ips",
+            &format!("'{}' is not an address or a CIDR block", allowed_ip),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn validate_message_data(message_data: &str) -> Result<(), AppError> {
+    if message_data.is_empty() {
+        return Err(invalid("message_data", "Message is empty"));
+    }
+    if STANDARD.decode(message_data).is_err() {
+        return Err(invalid("message_data", "Message is not valid base64"));
+    }
+    Ok(())
+}
+
+fn validate_retention(retention: Option<u32>) -> Result<(), AppError> {
+    match retention {
+        Some(retention) if retention > MAX_RETENTION_MINUTES => Err(invalid(
+            "retention",
+            &format!(
+                "Retention can't be longer than {} minutes",
+                MAX_RETENTION_MINUTES
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+// checks what can be checked without the database, before any limit is looked up
+pub(crate) fn validate_message_form(form: &MessageForm) -> Result<(), AppError> {
+    validate_user_token(&form.user_token)?;
+    validate_message_data(&form.message_data)?;
+    validate_retention(form.retention)?;
+    validate_slug(form.slug.as_deref())?;
+    validate_webhook_url(form.webhook_url.as_deref())?;
+    validate_sms_to(form.sms_to.as_deref())?;
+    validate_recipients(form)?;
+    validate_max_views(form.max_views)?;
+    validate_grace_minutes(form.grace_minutes)?;
+    validate_activation(form)?;
+    validate_totp_secret(form.totp_secret.as_deref())?;
+    validate_pin(form.pin.as_deref())?;
+    validate_allowed_ips(form.allowed_ips.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_form() -> MessageForm {
+        MessageForm {
+            user_token: "test_token".to_string(),
+            message_data: "SGVsbG8gd29ybGQ=".to_string().into(),
+            retention: Some(60),
+            ..Default::default()
+        }
+    }
+
+    fn invalid_field(form: &MessageForm) -> Option<&'static str> {
+        validate_message_form(form)
+            .err()
+            .and_then(|err| err.field())
+    }
+
+    #[test]
+    fn test_valid_form() {
+        assert!(validate_message_form(&make_form()).is_ok());
+        assert!(validate_message_form(&MessageForm {
+            retention: None,
+            ..make_form()
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn test_invalid_user_token() {
+        for user_token in ["", "has spaces", "quote\"d", &"a".repeat(129)] {
+            let form = MessageForm {
+                user_token: user_token.to_string(),
+                ..make_form()
+            };
+            assert_eq!(invalid_field(&form), Some("user_token"), "{}", user_token);
+        }
+    }
+
+    #[test]
+    fn test_invalid_message_data() {
+        for message_data in ["", "not base64!", "SGVsbG8"] {
+            let form = MessageForm {
+                message_data: message_data.to_string().into(),
+                ..make_form()
+            };
+            assert_eq!(
+                invalid_field(&form),
+                Some("message_data"),
+                "{}",
+                message_data
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_recipients() {
+        let recipients = |names: &[&str]| Some(names.iter().map(|name| name.to_string()).collect());
+        let valid = MessageForm {
+            recipients: recipients(&["alice", "bob"]),
+            ..make_form()
+        };
+        assert!(validate_message_form(&valid).is_ok());
+        let too_many: Vec<String> = (0..=MAX_RECIPIENTS).map(|i| i.to_string()).collect();
+        for form in [
+            MessageForm {
+                recipients: Some(too_many),
+                ..make_form()
+            },
+            MessageForm {
+                recipients: recipients(&["alice", " "]),
+                ..make_form()
+            },
+            MessageForm {
+                recipients: recipients(&["alice", "alice"]),
+                ..make_form()
+            },
+            MessageForm {
+                slug: Some("db-password".to_string()),
+                ..valid
+            },
+        ] {
+            assert_eq!(invalid_field(&form), Some("recipients"));
+        }
+    }
+
+    #[test]
+    fn test_max_views_out_of_bounds() {
+        for max_views in [0, MAX_VIEWS + 1] {
+            let form = MessageForm {
+                max_views: Some(max_views),
+                ..make_form()
+            };
+            assert_eq!(invalid_field(&form), Some("max_views"), "{}", max_views);
+        }
+        assert!(validate_message_form(&MessageForm {
+            max_views: Some(MAX_VIEWS),
+            ..make_form()
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn test_grace_out_of_bounds() {
+        let form = MessageForm {
+            grace_minutes: Some(MAX_GRACE_MINUTES + 1),
+            ..make_form()
+        };
+        assert_eq!(invalid_field(&form), Some("grace_minutes"));
+        assert!(validate_message_form(&MessageForm {
+            grace_minutes: Some(MAX_GRACE_MINUTES),
+            ..make_form()
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn test_activation() {
+        let form = MessageForm {
+            activate_in_minutes: Some(MAX_ACTIVATION_MINUTES + 1),
+            ..make_form()
+        };
+        assert_eq!(invalid_field(&form), Some("activate_in_minutes"));
+        let form = MessageForm {
+            activate_in_minutes: Some(60),
+            not_before: Some(1_700_000_000),
+            ..make_form()
+        };
+        assert_eq!(invalid_field(&form), Some("activate_in_minutes"));
+        assert!(validate_message_form(&MessageForm {
+            activate_in_minutes: Some(MAX_ACTIVATION_MINUTES),
+            ..make_form()
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn test_totp_secret() {
+        for totp_secret in ["JBSWY3DPEHPK3PXP", "jbsw y3dp ehpk 3pxp", ""] {
+            let form = MessageForm {
+                totp_secret: Some(totp_secret.to_string()).into(),
+                ..make_form()
+            };
+            assert!(validate_message_form(&form).is_ok(), "{}", totp_secret);
+        }
+        // not base32, and 5 bytes
+        for totp_secret in ["JBSWY3DPEHPK3PX1", "JBSWY3DP"] {
+            let form = MessageForm {
+                totp_secret: Some(totp_secret.to_string()).into(),
+                ..make_form()
+            };
+            assert_eq!(invalid_field(&form), Some("totp_secret"), "{}", totp_secret);
+        }
+    }
+
+    #[test]
+    fn test_pin() {
+        for pin in ["0000", "48210517", ""] {
+            let form = MessageForm {
+                pin: Some(pin.to_string()).into(),
+                ..make_form()
+            };
+            assert!(validate_message_form(&form).is_ok(), "{}", pin);
+        }
+        for pin in ["123", "123456789", "12a4", " 1234"] {
+            let form = MessageForm {
+                pin: Some(pin.to_string()).into(),
+                ..make_form()
+            };
+            assert_eq!(invalid_field(&form), Some("pin"), "{}", pin);
+        }
+    }
+
+    #[test]
+    fn test_allowed_ips() {
+        let form = MessageForm {
+            allowed_ips: Some(vec![
+                "10.8.0.0/16".to_string(),
+                "203.0.113.7".to_string(),
+                "2001:db8::/32".to_string(),
+            ]),
+            ..make_form()
+        };
+        assert!(validate_message_form(&form).is_ok());
+        for allowed_ip in ["10.8.0.0/33", "10.8.0.0,10.9.0.0", "vpn"] {
+            let form = MessageForm {
+                allowed_ips: Some(vec![allowed_ip.to_string()]),
+                ..make_form()
+            };
+            assert_eq!(invalid_field(&form), Some("allowed_ips"), "{}", allowed_ip);
+        }
+        let form = MessageForm {
+            allowed_ips: Some(vec!["10.0.0.1".to_string(); MAX_ALLOWED_IPS + 1]),
+            ..make_form()
+        };
+        assert_eq!(invalid_field(&form), Some("allowed_ips"));
+    }
+
+    #[test]
+    fn test_retention_out_of_bounds() {
+        let form = MessageForm {
+            retention: Some(MAX_RETENTION_MINUTES + 1),
+            ..make_form()
+        };
+        assert_eq!(invalid_field(&form), Some("retention"));
+    }
+
+    #[test]
+    fn test_slug() {
+        for slug in ["db-password-friday", "abc", "2024-q3"] {
+            let form = MessageForm {
+                slug: Some(slug.to_string()),
+                ..make_form()
+            };
+            assert!(validate_message_form(&form).is_ok(), "{}", slug);
+        }
+        for slug in [
+            "ab",
+            "Upper",
+            "-dash",
+            "dash-",
+            "has space",
+            "dot.ted",
+            &"a".repeat(65),
+        ] {
+            let form = MessageForm {
+                slug: Some(slug.to_string()),
+                ..make_form()
+            };
+            assert_eq!(invalid_field(&form), Some("slug"), "{}", slug);
+        }
+    }
+
+    #[test]
+    fn test_webhook_url() {
+        for webhook_url in ["", "https://hooks.example.com/abc"] {
+            let form = MessageForm {
+                webhook_url: Some(webhook_url.to_string()),
+                ..make_form()
+            };
+            assert!(validate_message_form(&form).is_ok(), "{}", webhook_url);
+        }
+        for webhook_url in [
+            "hooks.example.com",
+            "ftp://hooks.example.com/abc",
+            "file:///etc/passwd",
+            &format!("https://example.com/{}", "a".repeat(2048)),
+        ] {
+            let form = MessageForm {
+                webhook_url: Some(webhook_url.to_string()),
+                ..make_form()
+            };
+            assert_eq!(invalid_field(&form), Some("webhook_url"), "{}", webhook_url);
+        }
+    }
+
+    #[test]
+    fn test_sms_to() {
+        for sms_to in ["", "+15551234567"] {
+            let form = MessageForm {
+                sms_to: Some(sms_to.to_string()),
+                ..make_form()
+            };
+            assert!(validate_message_form(&form).is_ok(), "{}", sms_to);
+        }
+        for sms_to in ["5551234567", "+1 555 123 4567", "+1555"] {
+            let form = MessageForm {
+                sms_to: Some(sms_to.to_string()),
+                ..make_form()
+            };
+            assert_eq!(invalid_field(&form), Some("sms_to"), "{}", sms_to);
+        }
+    }
+}
@@ -0,0 +1,631 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+// the relying party side of WebAuthn (https://www.w3.org/TR/webauthn-2/): reading what the
+// authenticators send back during the registration and sign-in ceremonies and checking their
+// signatures; attestation isn't checked, any authenticator the admin has at hand is fine
+
+// COSE algorithm ids, the ones browsers offer by default
+pub const ES256: i64 = -7;
+pub const EDDSA: i64 = -8;
+pub const RS256: i64 = -257;
+pub const SUPPORTED_ALGORITHMS: [i64; 3] = [ES256, EDDSA, RS256];
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+// rpIdHash, flags and signCount
+const AUTHENTICATOR_DATA_HEADER_LEN: usize = 37;
+// aaguid and the length of the credential id
+const ATTESTED_CREDENTIAL_HEADER_LEN: usize = 18;
+// authenticator data doesn't nest deeper than this
+const MAX_CBOR_DEPTH: usize = 8;
+
+#[derive(Debug, PartialEq)]
+enum Cbor {
+    Integer(i128),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Cbor>),
+    Map(Vec<(Cbor, Cbor)>),
+    // booleans, null and floats, nothing here needs their value
+    Simple,
+}
+
+impl Cbor {
+    fn get(&self, key: &Cbor) -> Option<&Cbor> {
+        match self {
+            Cbor::Map(entries) => entries
+                .iter()
+                .find(|(entry_key, _)| entry_key == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn get_text_key(&self, key: &str) -> Option<&Cbor> {
+        self.get(&Cbor::Text(key.to_string()))
+    }
+
+    fn get_integer_key(&self, key: i128) -> Option<&Cbor> {
+        self.get(&Cbor::Integer(key))
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Cbor::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    fn as_integer(&self) -> Option<i128> {
+        match self {
+            Cbor::Integer(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+// one item of RFC 8949 and the bytes after it, authenticators only use definite lengths
+fn decode_cbor(data: &[u8], depth: usize) -> Option<(Cbor, &[u8])> {
+    if depth > MAX_CBOR_DEPTH {
+        return None;
+    }
+    let (&initial, rest) = data.split_first()?;
+    let major_type = initial >> 5;
+    let additional_info = initial & 0x1f;
+    let (argument, rest) = match additional_info {
+        0..=23 => (additional_info as u64, rest),
+        24..=27 => {
+            let len = 1 << (additional_info - 24);
+            if rest.len() < len {
+                return None;
+            }
+            let (bytes, rest) = rest.split_at(len);
+            let argument = bytes
+                .iter()
+                .fold(0u64, |value, &byte| (value << 8) | byte as u64);
+            (argument, rest)
+        }
+        _ => return None,
+    };
+
+    match major_type {
+        0 => Some((Cbor::Integer(argument as i128), rest)),
+        1 => Some((Cbor::Integer(-1 - argument as i128), rest)),
+        2 | 3 => {
+            let len = usize::try_from(argument).ok()?;
+            if rest.len() < len {
+                return None;
+            }
+            let (bytes, rest) = rest.split_at(len);
+            let item = if major_type == 2 {
+                Cbor::Bytes(bytes.to_vec())
+            } else {
+                Cbor::Text(String::from_utf8(bytes.to_vec()).ok()?)
+            };
+            Some((item, rest))
+        }
+        4 => {
+            // every item takes at least a byte, so a made up count runs out of data quickly
+            let mut rest = rest;
+            let mut items = Vec::new();
+            for _ in 0..argument {
+                let (item, after) = decode_cbor(rest, depth + 1)?;
+                items.push(item);
+                rest = after;
+            }
+            Some((Cbor::Array(items), rest))
+        }
+        5 => {
+            let mut rest = rest;
+            let mut entries = Vec::new();
+            for _ in 0..argument {
+                let (key, after) = decode_cbor(rest, depth + 1)?;
+                let (value, after) = decode_cbor(after, depth + 1)?;
+                entries.push((key, value));
+                rest = after;
+            }
+            Some((Cbor::Map(entries), rest))
+        }
+        // a tag only tells how to read the item after it
+        6 => decode_cbor(rest, depth + 1),
+        _ => Some((Cbor::Simple, rest)),
+    }
+}
+
+#[derive(Debug)]
+pub struct AuthenticatorData {
+    pub flags: u8,
+    pub sign_count: u32,
+    // only in the data of a registration: the credential id and its COSE key
+    pub attested_credential: Option<(Vec<u8>, Vec<u8>)>,
+    rp_id_hash: Vec<u8>,
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, String> {
+    let malformed = || "Authenticator data is malformed".to_string();
+    if data.len() < AUTHENTICATOR_DATA_HEADER_LEN {
+        return Err(malformed());
+    }
+    let flags = data[32];
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+    let attested_credential = if flags & FLAG_ATTESTED_CREDENTIAL_DATA != 0 {
+        let rest = &data[AUTHENTICATOR_DATA_HEADER_LEN..];
+        if rest.len() < ATTESTED_CREDENTIAL_HEADER_LEN {
+            return Err(malformed());
+        }
+        let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        let rest = &rest[ATTESTED_CREDENTIAL_HEADER_LEN..];
+        if rest.len() < id_len {
+            return Err(malformed());
+        }
+        let (credential_id, rest) = rest.split_at(id_len);
+        // the key is kept as it came, it's read again for every sign-in
+        let (_, after_key) = decode_cbor(rest, 0).ok_or_else(malformed)?;
+        let public_key = rest[..rest.len() - after_key.len()].to_vec();
+        Some((credential_id.to_vec(), public_key))
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData {
+        flags,
+        sign_count,
+        attested_credential,
+        rp_id_hash: data[..32].to_vec(),
+    })
+}
+
+enum PublicKey {
+    // the uncompressed P-256 point
+    Es256(Vec<u8>),
+    Ed25519(Vec<u8>),
+    Rs256 { n: Vec<u8>, e: Vec<u8> },
+}
+
+fn read_public_key(cose_key: &[u8]) -> Result<PublicKey, String> {
+    let (key, _) = decode_cbor(cose_key, 0).ok_or("The public key is malformed")?;
+    let bytes_param = |label| {
+        key.get_integer_key(label)
+            .and_then(Cbor::as_bytes)
+            .ok_or("The public key is malformed")
+    };
+    let curve = key.get_integer_key(-1).and_then(Cbor::as_integer);
+    match key
+        .get_integer_key(3)
+        .and_then(Cbor::as_integer)
+        .map(|algorithm| algorithm as i64)
+    {
+        // EC2 keys with the P-256 curve
+        Some(ES256) if curve == Some(1) => {
+            let (x, y) = (bytes_param(-2)?, bytes_param(-3)?);
+            if x.len() != 32 || y.len() != 32 {
+                return Err("The public key is malformed".to_string());
+            }
+            let mut point = vec![0x04];
+            point.extend_from_slice(x);
+            point.extend_from_slice(y);
+            Ok(PublicKey::Es256(point))
+        }
+        // OKP keys with the Ed25519 curve
+        Some(EDDSA) if curve == Some(6) => Ok(PublicKey::Ed25519(bytes_param(-2)?.to_vec())),
+        Some(RS256) => Ok(PublicKey::Rs256 {
+            n: bytes_param(-1)?.to_vec(),
+            e: bytes_param(-2)?.to_vec(),
+        }),
+        _ => Err("The algorithm of the public key isn't supported".to_string()),
+    }
+}
+
+fn verify_signature(cose_key: &[u8], message: &[u8], signature: &[u8]) -> Result<(), String> {
+    let verified = match read_public_key(cose_key)? {
+        PublicKey::Es256(point) => {
+            UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point)
+                .verify(message, signature)
+        }
+        PublicKey::Ed25519(key) => {
+            UnparsedPublicKey::new(&signature::ED25519, key).verify(message, signature)
+        }
+        PublicKey::Rs256 { n, e } => RsaPublicKeyComponents { n, e }.verify(
+            &signature::RSA_PKCS1_2048_8192_SHA256,
+            message,
+            signature,
+        ),
+    };
+    verified.map_err(|_| "The signature doesn't match".to_string())
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony: String,
+    challenge: String,
+    origin: String,
+}
+
+// the challenge the browser signed, after checking the ceremony and the page it was made on;
+// the caller checks the challenge is one it handed out
+pub fn read_client_data(
+    client_data_json: &[u8],
+    ceremony: &str,
+    origin: &str,
+) -> Result<String, String> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)
+        .map_err(|_| "Client data is malformed".to_string())?;
+    if client_data.ceremony != ceremony {
+        return Err(format!("Expected a {} ceremony", ceremony));
+    }
+    if client_data.origin != origin {
+        return Err("The ceremony was made on another site".to_string());
+    }
+    Ok(client_data.challenge)
+}
+
+fn check_authenticator_data(
+    authenticator_data: &AuthenticatorData,
+    rp_id: &str,
+) -> Result<(), String> {
+    if authenticator_data.rp_id_hash[..] != Sha256::digest(rp_id.as_bytes())[..] {
+        return Err("The credential belongs to another site".to_string());
+    }
+    // admin access is worth the PIN or fingerprint of the authenticator
+    let required_flags = FLAG_USER_PRESENT | FLAG_USER_VERIFIED;
+    if authenticator_data.flags & required_flags != required_flags {
+        return Err("The user wasn't verified by the authenticator".to_string());
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct NewCredential {
+    // base64url, the way browsers report it in `id`
+    pub credential_id: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+// the attestation object of `navigator.credentials.create()`, its client data is read first
+pub fn verify_registration(
+    rp_id: &str,
+    attestation_object: &[u8],
+) -> Result<NewCredential, String> {
+    let (attestation, _) =
+        decode_cbor(attestation_object, 0).ok_or("Attestation object is malformed")?;
+    let authenticator_data = attestation
+        .get_text_key("authData")
+        .and_then(Cbor::as_bytes)
+        .ok_or("Attestation object has no authenticator data")?;
+    let authenticator_data = parse_authenticator_data(authenticator_data)?;
+    check_authenticator_data(&authenticator_data, rp_id)?;
+
+    let (credential_id, public_key) = authenticator_data
+        .attested_credential
+        .ok_or("Authenticator data has no credential")?;
+    // a key that can't be used later is better turned down now
+    read_public_key(&public_key)?;
+    Ok(NewCredential {
+        credential_id: URL_SAFE_NO_PAD.encode(credential_id),
+        public_key,
+        sign_count: authenticator_data.sign_count,
+    })
+}
+
+// the new signature counter of the credential when `navigator.credentials.get()` was answered
+// by it; authenticators that don't count always send 0
+pub fn verify_assertion(
+    rp_id: &str,
+    public_key: &[u8],
+    stored_sign_count: u32,
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+) -> Result<u32, String> {
+    let parsed = parse_authenticator_data(authenticator_data)?;
+    check_authenticator_data(&parsed, rp_id)?;
+
+    let mut signed = authenticator_data.to_vec();
+    signed.extend_from_slice(&Sha256::digest(client_data_json));
+    verify_signature(public_key, &signed, signature)?;
+
+    // a counter that doesn't move forward means the authenticator was cloned
+    if (parsed.sign_count != 0 || stored_sign_count != 0) && parsed.sign_count <= stored_sign_count
+    {
+        return Err("The signature counter of the authenticator went back".to_string());
+    }
+    Ok(parsed.sign_count)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair};
+
+    pub(crate) const RP_ID: &str = "localhost";
+    pub(crate) const ORIGIN: &str = "http://localhost";
+
+    fn cbor_head(major_type: u8, argument: u64) -> Vec<u8> {
+        let mut head = vec![];
+        if argument < 24 {
+            head.push((major_type << 5) | argument as u8);
+        } else if argument < 0x100 {
+            head.push((major_type << 5) | 24);
+            head.push(argument as u8);
+        } else {
+            head.push((major_type << 5) | 25);
+            head.extend_from_slice(&(argument as u16).to_be_bytes());
+        }
+        head
+    }
+
+    fn cbor_integer(value: i64) -> Vec<u8> {
+        if value >= 0 {
+            cbor_head(0, value as u64)
+        } else {
+            cbor_head(1, (-1 - value) as u64)
+        }
+    }
+
+    fn cbor_bytes(major_type: u8, bytes: &[u8]) -> Vec<u8> {
+        let mut item = cbor_head(major_type, bytes.len() as u64);
+        item.extend_from_slice(bytes);
+        item
+    }
+
+    fn cbor_map(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut item = cbor_head(5, entries.len() as u64);
+        for (key, value) in entries {
+            item.extend_from_slice(key);
+            item.extend_from_slice(value);
+        }
+        item
+    }
+
+    // an authenticator with a P-256 key, as most security keys and phones have
+    pub(crate) struct TestAuthenticator {
+        key_pair: EcdsaKeyPair,
+        pub(crate) credential_id: Vec<u8>,
+        pub(crate) sign_count: u32,
+    }
+
+    impl TestAuthenticator {
+        pub(crate) fn new() -> TestAuthenticator {
+            let rng = SystemRandom::new();
+            let pkcs8 =
+                EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+                    .unwrap();
+            TestAuthenticator {
+                key_pair: EcdsaKeyPair::from_pkcs8(
+                    &signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+                    pkcs8.as_ref(),
+                )
+                .unwrap(),
+                credential_id: vec![7; 16],
+                sign_count: 0,
+            }
+        }
+
+        pub(crate) fn cose_key(&self) -> Vec<u8> {
+            let point = self.key_pair.public_key().as_ref();
+            cbor_map(&[
+                (cbor_integer(1), cbor_integer(2)),
+                (cbor_integer(3), cbor_integer(ES256)),
+                (cbor_integer(-1), cbor_integer(1)),
+                (cbor_integer(-2), cbor_bytes(2, &point[1..33])),
+                (cbor_integer(-3), cbor_bytes(2, &point[33..])),
+            ])
+        }
+
+        fn authenticator_data(&self, rp_id: &str, flags: u8, with_credential: bool) -> Vec<u8> {
+            let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+            data.push(
+                flags
+                    | if with_credential {
+                        FLAG_ATTESTED_CREDENTIAL_DATA
+                    } else {
+                        0
+                    },
+            );
+            data.extend_from_slice(&self.sign_count.to_be_bytes());
+            if with_credential {
+                data.extend_from_slice(&[0; 16]);
+                data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+                data.extend_from_slice(&self.credential_id);
+                data.extend_from_slice(&self.cose_key());
+            }
+            data
+        }
+
+        pub(crate) fn client_data(ceremony: &str, challenge: &str) -> Vec<u8> {
+            serde_json::json!({
+                "type": ceremony,
+                "challenge": challenge,
+                "origin": ORIGIN,
+                "crossOrigin": false,
+            })
+            .to_string()
+            .into_bytes()
+        }
+
+        pub(crate) fn attestation_object(&self) -> Vec<u8> {
+            cbor_map(&[
+                (cbor_bytes(3, b"fmt"), cbor_bytes(3, b"none")),
+                (cbor_bytes(3, b"attStmt"), cbor_map(&[])),
+                (
+                    cbor_bytes(3, b"authData"),
+                    cbor_bytes(
+                        2,
+                        &self.authenticator_data(
+                            RP_ID,
+                            FLAG_USER_PRESENT | FLAG_USER_VERIFIED,
+                            true,
+                        ),
+                    ),
+                ),
+            ])
+        }
+
+        // the authenticator data and the signature over it and the client data
+        pub(crate) fn sign(&mut self, client_data_json: &[u8]) -> (Vec<u8>, Vec<u8>) {
+            self.sign_count += 1;
+            let authenticator_data =
+                self.authenticator_data(RP_ID, FLAG_USER_PRESENT | FLAG_USER_VERIFIED, false);
+            let mut signed = authenticator_data.clone();
+            signed.extend_from_slice(&Sha256::digest(client_data_json));
+            let signature = self
+                .key_pair
+                .sign(&SystemRandom::new(), &signed)
+                .unwrap()
+                .as_ref()
+                .to_vec();
+            (authenticator_data, signature)
+        }
+    }
+
+    #[test]
+    fn test_decode_cbor() {
+        // RFC 8949 appendix A
+        for (encoded, decoded) in [
+            (&[0x00][..], Cbor::Integer(0)),
+            (&[0x18, 0x64], Cbor::Integer(100)),
+            (&[0x39, 0x01, 0x00], Cbor::Integer(-257)),
+            (
+                &[0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+                Cbor::Integer(u64::MAX as i128),
+            ),
+            (&[0x44, 1, 2, 3, 4], Cbor::Bytes(vec![1, 2, 3, 4])),
+            (&[0x62, 0x22, 0x5c], Cbor::Text("\"\\".to_string())),
+            (
+                &[0x82, 0x01, 0x82, 0x02, 0x03],
+                Cbor::Array(vec![
+                    Cbor::Integer(1),
+                    Cbor::Array(vec![Cbor::Integer(2), Cbor::Integer(3)]),
+                ]),
+            ),
+            (
+                &[0xa1, 0x61, 0x61, 0x01],
+                Cbor::Map(vec![(Cbor::Text("a".to_string()), Cbor::Integer(1))]),
+            ),
+            (&[0xf5], Cbor::Simple),
+            (
+                &[0xc1, 0x1a, 0x51, 0x4b, 0x67, 0xb0],
+                Cbor::Integer(1363896240),
+            ),
+        ] {
+            assert_eq!(decode_cbor(encoded, 0), Some((decoded, &[][..])));
+        }
+
+        assert_eq!(
+            decode_cbor(&[0x01, 0x02], 0),
+            Some((Cbor::Integer(1), &[0x02][..]))
+        );
+        // cut short, indefinite length, too deep and a huge made up count
+        assert_eq!(decode_cbor(&[0x44, 1, 2], 0), None);
+        assert_eq!(decode_cbor(&[0x5f, 0x41, 1, 0xff], 0), None);
+        assert_eq!(decode_cbor(&[0x81; 20], 0), None);
+        assert_eq!(
+            decode_cbor(
+                &[0x9b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+                0
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_read_client_data() {
+        let client_data = TestAuthenticator::client_data("webauthn.get", "abc");
+        assert_eq!(
+            read_client_data(&client_data, "webauthn.get", ORIGIN),
+            Ok("abc".to_string())
+        );
+        assert!(read_client_data(&client_data, "webauthn.create", ORIGIN).is_err());
+        assert!(read_client_data(&client_data, "webauthn.get", "https://example.com").is_err());
+        assert!(read_client_data(b"{}", "webauthn.get", ORIGIN).is_err());
+    }
+
+    #[test]
+    fn test_registration_and_assertion() {
+        let mut authenticator = TestAuthenticator::new();
+        let credential = verify_registration(RP_ID, &authenticator.attestation_object()).unwrap();
+        assert_eq!(
+            credential.credential_id,
+            URL_SAFE_NO_PAD.encode(&authenticator.credential_id)
+        );
+        assert_eq!(credential.public_key, authenticator.cose_key());
+        assert_eq!(credential.sign_count, 0);
+        assert!(verify_registration("example.com", &authenticator.attestation_object()).is_err());
+
+        let client_data = TestAuthenticator::client_data("webauthn.get", "abc");
+        let (authenticator_data, signature) = authenticator.sign(&client_data);
+        assert_eq!(
+            verify_assertion(
+                RP_ID,
+                &credential.public_key,
+                0,
+                &client_data,
+                &authenticator_data,
+                &signature
+            ),
+            Ok(1)
+        );
+        // the same signature doesn't do for other client data
+        let other_client_data = TestAuthenticator::client_data("webauthn.get", "abd");
+        assert!(verify_assertion(
+            RP_ID,
+            &credential.public_key,
+            0,
+            &other_client_data,
+            &authenticator_data,
+            &signature
+        )
+        .is_err());
+        // nor a second time once the counter has moved
+        assert!(verify_assertion(
+            RP_ID,
+            &credential.public_key,
+            1,
+            &client_data,
+            &authenticator_data,
+            &signature
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_user_verification_is_required() {
+        let authenticator = TestAuthenticator::new();
+        let data = authenticator.authenticator_data(RP_ID, FLAG_USER_PRESENT, true);
+        let parsed = parse_authenticator_data(&data).unwrap();
+        assert!(parsed.attested_credential.is_some());
+        assert!(check_authenticator_data(&parsed, RP_ID).is_err());
+        assert!(parse_authenticator_data(&data[..40]).is_err());
+    }
+
+    #[test]
+    fn test_ed25519_signature() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let cose_key = cbor_map(&[
+            (cbor_integer(1), cbor_integer(1)),
+            (cbor_integer(3), cbor_integer(EDDSA)),
+            (cbor_integer(-1), cbor_integer(6)),
+            (
+                cbor_integer(-2),
+                cbor_bytes(2, key_pair.public_key().as_ref()),
+            ),
+        ]);
+        let signature = key_pair.sign(b"signed data");
+        assert!(verify_signature(&cose_key, b"signed data", signature.as_ref()).is_ok());
+        assert!(verify_signature(&cose_key, b"other data", signature.as_ref()).is_err());
+
+        // an unknown algorithm
+        let cose_key = cbor_map(&[(cbor_integer(3), cbor_integer(-36))]);
+        assert!(read_public_key(&cose_key).is_err());
+    }
+}
@@ -0,0 +1,538 @@
+use async_std::{future, task};
+use hmac::{Hmac, Mac, NewMac};
+use http_types::url::Host;
+use http_types::{Method, Request, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::http_client::HttpClient;
+use crate::proxy::IpNetwork;
+use crate::store::{
+    DeliveryState, MessageState, Store, StoreResult, WebhookAttempt, WebhookDelivery, WebhookStore,
+};
+use crate::tokens::hash_token;
+
+// "sha256=" and the hex HMAC-SHA256 of the timestamp, a dot and the body
+pub const SIGNATURE_HEADER: &str = "Webhook-Signature";
+// seconds since the epoch, receivers can refuse the old deliveries
+pub const TIMESTAMP_HEADER: &str = "Webhook-Timestamp";
+const USER_AGENT: &str = "one-time-share-webhooks";
+const DEFAULT_MAX_ATTEMPTS: u32 = 8;
+// doubled after every failed attempt, up to the maximum
+const FIRST_RETRY_DELAY_SECONDS: i64 = 30;
+const MAX_RETRY_DELAY_SECONDS: i64 = 6 * 60 * 60;
+// a receiver that doesn't answer in time counts as a failed attempt
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(5);
+// deliveries taken from the queue at once
+const DELIVERY_BATCH_SIZE: usize = 100;
+// the delivered and failed ones stay for the admin API to show for a week
+const DELIVERY_RETENTION_SECONDS: i64 = 7 * 24 * 60 * 60;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookConfig {
+    // every delivery is signed with it, the receivers check the signature with the same secret
+    pub signing_secret: String,
+    pub ca_bundle_path: Option<String>,
+    // attempts before a delivery is given up, 8 by default
+    pub max_attempts: Option<u32>,
+    // the webhook URLs come from the users, so the server's own network can only be reached
+    // in these addresses or CIDR blocks, e.g. ["10.20.0.0/16"] for an internal ticket system
+    #[serde(default)]
+    pub allowed_internal_networks: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct WebhookEvent {
+    // "message.consumed" or "message.expired"
+    pub event: String,
+    // the token or slug the message was looked up by, only the hash of it is kept for
+    // the messages that nobody read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    // hex SHA-256 of the token or slug
+    pub token_sha256: String,
+    pub status: MessageState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consumed_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expired_at: Option<i64>,
+}
+
+impl WebhookEvent {
+    pub fn consumed(message_token: &str, consumed_at: i64) -> Self {
+        WebhookEvent {
+            event: "message.consumed".to_string(),
+            token: Some(message_token.to_string()),
+            token_sha256: hash_token(message_token),
+            status: MessageState::Consumed,
+            consumed_at: Some(consumed_at),
+            expired_at: None,
+        }
+    }
+
+    pub fn expired(token_sha256: &str, expired_at: i64) -> Self {
+        WebhookEvent {
+            event: "message.expired".to_string(),
+            token: None,
+            token_sha256: token_sha256.to_string(),
+            status: MessageState::Expired,
+            consumed_at: None,
+            expired_at: Some(expired_at),
+        }
+    }
+}
+
+pub fn sign(signing_secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(signing_secret.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+// the seconds until the next attempt after `attempts` failed ones
+fn retry_delay_seconds(attempts: u32) -> i64 {
+    FIRST_RETRY_DELAY_SECONDS
+        .saturating_mul(1 << attempts.saturating_sub(1).min(20))
+        .min(MAX_RETRY_DELAY_SECONDS)
+}
+
+fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// every event goes through the queue, so a receiver that is down gets it later
+pub fn enqueue<S: WebhookStore + ?Sized>(
+    store: &S,
+    url: &str,
+    event: &WebhookEvent,
+) -> StoreResult<()> {
+    let payload = serde_json::to_string(event).expect("events serialize to JSON");
+    store.enqueue_webhook(url, &event.event, &payload, unix_timestamp())?;
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct WebhookSender {
+    client: HttpClient,
+    signing_secret: String,
+    max_attempts: u32,
+}
+
+impl WebhookSender {
+    pub fn new(config: &WebhookConfig) -> std::io::Result<Self> {
+        let allowed_internal_networks = config
+            .allowed_internal_networks
+            .iter()
+            .map(|value| {
+                IpNetwork::parse(value).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Invalid network in allowedInternalNetworks: {}", value),
+                    )
+                })
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Ok(WebhookSender {
+            client: HttpClient::new(config.ca_bundle_path.as_deref())?
+                .public_only(allowed_internal_networks),
+            signing_secret: config.signing_secret.clone(),
+            max_attempts: config.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1),
+        })
+    }
+
+    // refuses a URL whose host is an internal address or localhost, names are looked up only
+    // when a delivery is made, which checks every address again
+    pub fn check_url(&self, url: &str) -> Result<(), String> {
+        let url = Url::parse(url).map_err(|err| err.to_string())?;
+        let is_allowed = match url.host() {
+            Some(Host::Ipv4(address)) => self.client.is_allowed_address(address.into()),
+            Some(Host::Ipv6(address)) => self.client.is_allowed_address(address.into()),
+            Some(Host::Domain(domain)) => {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                domain != "localhost" && !domain.ends_with(".localhost")
+            }
+            None => false,
+        };
+        if is_allowed {
+            Ok(())
+        } else {
+            Err("Webhook URL can't point to an internal address".to_string())
+        }
+    }
+
+    fn make_request(&self, url: Url, body: &[u8], timestamp: i64) -> Request {
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => String::new(),
+        };
+        let signature = sign(&self.signing_secret, timestamp, body);
+
+        let mut req = Request::new(Method::Post, url);
+        req.insert_header("Host", host);
+        req.insert_header("Content-Type", "application/json");
+        req.insert_header("User-Agent", USER_AGENT);
+        req.insert_header(TIMESTAMP_HEADER, timestamp.to_string());
+        req.insert_header(SIGNATURE_HEADER, format!("sha256={}", signature));
+        req.set_body(body.to_vec());
+        req
+    }
+
+    // signed at the time of the attempt, so a retry gets a fresh timestamp
+    pub async fn deliver(&self, url: &str, body: &[u8]) -> http_types::Result<StatusCode> {
+        let url = Url::parse(url)?;
+        let req = self.make_request(url, body, unix_timestamp());
+        let res = future::timeout(DELIVERY_TIMEOUT, self.client.send(req)).await??;
+        Ok(res.status())
+    }
+
+    fn make_attempt<'a>(
+        &self,
+        delivery: &WebhookDelivery,
+        result: &'a Result<StatusCode, String>,
+        timestamp: i64,
+    ) -> WebhookAttempt<'a> {
+        let attempts = delivery.attempts + 1;
+        let (state, next_attempt_timestamp) = match result {
+            Ok(status) if status.is_success() => (DeliveryState::Delivered, None),
+            _ if attempts >= self.max_attempts => (DeliveryState::Failed, None),
+            _ => (
+                DeliveryState::Pending,
+                Some(timestamp + retry_delay_seconds(attempts)),
+            ),
+        };
+        WebhookAttempt {
+            timestamp,
+            state,
+            status: result.as_ref().ok().map(|status| *status as u16),
+            error: result.as_ref().err().map(String::as_str),
+            next_attempt_timestamp,
+        }
+    }
+
+    // makes an attempt at every delivery that is due, the URL can carry a secret of the
+    // receiver, so only its host is logged
+    pub async fn deliver_due_webhooks(&self, database: &Mutex<dyn Store>) -> StoreResult<()> {
+        let deliveries = database
+            .lock()
+            .unwrap()
+            .get_due_webhooks(unix_timestamp(), DELIVERY_BATCH_SIZE)?;
+        for delivery in deliveries {
+            let result = self
+                .deliver(&delivery.url, delivery.payload.as_bytes())
+                .await
+                .map_err(|err| err.to_string());
+            let attempt = self.make_attempt(&delivery, &result, unix_timestamp());
+            if attempt.state != DeliveryState::Delivered {
+                let host = Url::parse(&delivery.url)
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_string))
+                    .unwrap_or_default();
+                let outcome = match &result {
+                    Ok(status) => format!("answered with status {}", *status as u16),
+                    Err(err) => format!("failed: {}", err),
+                };
+                log::warn!(
+                    "Webhook {} {} to {} {}{}",
+                    delivery.event,
+                    delivery.id,
+                    host,
+                    outcome,
+                    if attempt.state == DeliveryState::Failed {
+                        ", giving up"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            database
+                .lock()
+                .unwrap()
+                .record_webhook_attempt(delivery.id, &attempt)?;
+        }
+        Ok(())
+    }
+}
+
+// delivers the queued events and forgets the old ones
+pub fn spawn_webhook_task(
+    database: Arc<Mutex<dyn Store>>,
+    sender: WebhookSender,
+) -> task::JoinHandle<()> {
+    task::spawn(async move {
+        loop {
+            if let Err(err) = sender.deliver_due_webhooks(&database).await {
+                log::error!("Failed to deliver webhooks: {}", err);
+            }
+            if let Err(err) = database
+                .lock()
+                .unwrap()
+                .clear_old_webhook_deliveries(unix_timestamp() - DELIVERY_RETENTION_SECONDS)
+            {
+                log::error!("Failed to remove old webhook deliveries: {}", err);
+            }
+            task::sleep(DELIVERY_INTERVAL).await;
+        }
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::database::OneTimeShareDb;
+    use async_std::net::TcpListener;
+    use futures_lite::future;
+
+    pub(crate) struct ReceivedWebhook {
+        pub timestamp: String,
+        pub signature: String,
+        pub body: Vec<u8>,
+    }
+
+    pub(crate) fn make_sender(max_attempts: u32) -> WebhookSender {
+        WebhookSender::new(&WebhookConfig {
+            signing_secret: "secret".to_string(),
+            ca_bundle_path: None,
+            max_attempts: Some(max_attempts),
+            // the test receivers listen on the loopback
+            allowed_internal_networks: vec!["127.0.0.1".to_string()],
+        })
+        .unwrap()
+    }
+
+    // answers the next delivery on `listener` with `status`
+    pub(crate) async fn receive_webhook(
+        listener: &TcpListener,
+        status: StatusCode,
+    ) -> ReceivedWebhook {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (sender, receiver) = async_std::channel::bounded(1);
+        async_h1::accept(stream, |mut req| {
+            let sender = sender.clone();
+            async move {
+                let header = |name: &str| req.header(name).unwrap().as_str().to_string();
+                let received = ReceivedWebhook {
+                    timestamp: header(TIMESTAMP_HEADER),
+                    signature: header(SIGNATURE_HEADER),
+                    body: req.body_bytes().await?,
+                };
+                sender.send(received).await?;
+                Ok(http_types::Response::new(status))
+            }
+        })
+        .await
+        .unwrap();
+        receiver.recv().await.unwrap()
+    }
+
+    #[test]
+    fn test_sign() {
+        // echo -n '1700000000.{}' | openssl dgst -sha256 -hmac secret
+        assert_eq!(
+            sign("secret", 1700000000, b"{}"),
+            "b8569b78799ff9e3cbff0fc2d63a33a2b57f3282abd07c37ae5e8e7d79a5f163"
+        );
+        assert_ne!(
+            sign("secret", 1700000001, b"{}"),
+            sign("secret", 1700000000, b"{}")
+        );
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay_seconds(1), 30);
+        assert_eq!(retry_delay_seconds(2), 60);
+        assert_eq!(retry_delay_seconds(5), 480);
+        assert_eq!(retry_delay_seconds(20), MAX_RETRY_DELAY_SECONDS);
+        assert_eq!(retry_delay_seconds(u32::MAX), MAX_RETRY_DELAY_SECONDS);
+    }
+
+    #[test]
+    fn test_internal_urls_are_refused() {
+        let make = |allowed_internal_networks: Vec<&str>| {
+            WebhookSender::new(&WebhookConfig {
+                signing_secret: "secret".to_string(),
+                ca_bundle_path: None,
+                max_attempts: None,
+                allowed_internal_networks: allowed_internal_networks
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect(),
+            })
+            .unwrap()
+        };
+        let sender = make(Vec::new());
+        assert!(sender.check_url("https://hooks.example.com/abc").is_ok());
+        assert!(sender.check_url("http://203.0.113.7/hook").is_ok());
+        for url in [
+            "http://10.0.0.1:8080/hook",
+            "http://127.0.0.1/hook",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://192.168.1.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[fe80::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://localhost:8080/",
+            "http://api.localhost/",
+        ] {
+            assert!(sender.check_url(url).is_err(), "{}", url);
+        }
+
+        // internal receivers are an explicit opt-in
+        let sender = make(vec!["10.0.0.0/8"]);
+        assert!(sender.check_url("http://10.0.0.1:8080/hook").is_ok());
+        assert!(sender.check_url("http://192.168.1.1/").is_err());
+        assert!(WebhookSender::new(&WebhookConfig {
+            signing_secret: "secret".to_string(),
+            ca_bundle_path: None,
+            max_attempts: None,
+            allowed_internal_networks: vec!["not a network".to_string()],
+        })
+        .is_err());
+    }
+
+    #[async_std::test]
+    async fn test_delivery_to_internal_address_is_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sender = WebhookSender::new(&WebhookConfig {
+            signing_secret: "secret".to_string(),
+            ca_bundle_path: None,
+            max_attempts: None,
+            allowed_internal_networks: Vec::new(),
+        })
+        .unwrap();
+        // the name is checked by the addresses it resolves to, before anything connects
+        for url in [
+            format!("http://127.0.0.1:{}/hook", port),
+            format!("http://localhost:{}/hook", port),
+        ] {
+            let err = sender.deliver(&url, b"{}").await.unwrap_err();
+            assert_eq!(err.status(), StatusCode::Forbidden, "{}", url);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_deliver_due_webhooks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hooks/abc", listener.local_addr().unwrap());
+        let database: Arc<Mutex<dyn Store>> =
+            Arc::new(Mutex::new(OneTimeShareDb::connect_in_memory().unwrap()));
+        let event = WebhookEvent::consumed("token1", 1700000000);
+        enqueue(&*database.lock().unwrap(), &url, &event).unwrap();
+        let sender = make_sender(3);
+
+        // the first attempt fails and is retried later
+        let (_, result) = future::zip(
+            receive_webhook(&listener, StatusCode::ServiceUnavailable),
+            sender.deliver_due_webhooks(&database),
+        )
+        .await;
+        result.unwrap();
+        let delivery = database
+            .lock()
+            .unwrap()
+            .list_webhook_deliveries(None, 10)
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_eq!(delivery.state, DeliveryState::Pending);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.last_status, Some(503));
+        assert!(delivery.next_attempt_timestamp.unwrap() >= unix_timestamp() + 29);
+
+        // the retry is made once it's due
+        database
+            .lock()
+            .unwrap()
+            .record_webhook_attempt(
+                delivery.id,
+                &WebhookAttempt {
+                    timestamp: unix_timestamp(),
+                    state: DeliveryState::Pending,
+                    status: Some(503),
+                    error: None,
+                    next_attempt_timestamp: Some(0),
+                },
+            )
+            .unwrap();
+        let (received, result) = future::zip(
+            receive_webhook(&listener, StatusCode::NoContent),
+            sender.deliver_due_webhooks(&database),
+        )
+        .await;
+        result.unwrap();
+        assert_eq!(
+            received.signature,
+            format!(
+                "sha256={}",
+                sign(
+                    "secret",
+                    received.timestamp.parse().unwrap(),
+                    &received.body
+                )
+            )
+        );
+        let delivered: WebhookEvent = serde_json::from_slice(&received.body).unwrap();
+        assert_eq!(delivered, event);
+        assert_eq!(
+            String::from_utf8(received.body).unwrap(),
+            format!(
+                r#"{{"event":"message.consumed","token":"token1","token_sha256":"{}","status":"consumed","consumed_at":1700000000}}"#,
+                hash_token("token1")
+            )
+        );
+        let delivery = database
+            .lock()
+            .unwrap()
+            .get_webhook_delivery(delivery.id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(delivery.state, DeliveryState::Delivered);
+        assert_eq!(delivery.next_attempt_timestamp, None);
+    }
+
+    #[async_std::test]
+    async fn test_delivery_gives_up() {
+        // nothing listens there once the listener is gone
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("http://{}/hook", listener.local_addr().unwrap())
+        };
+        let database: Arc<Mutex<dyn Store>> =
+            Arc::new(Mutex::new(OneTimeShareDb::connect_in_memory().unwrap()));
+        enqueue(
+            &*database.lock().unwrap(),
+            &url,
+            &WebhookEvent::expired("abc", 1700000000),
+        )
+        .unwrap();
+
+        make_sender(1)
+            .deliver_due_webhooks(&database)
+            .await
+            .unwrap();
+        let delivery = database
+            .lock()
+            .unwrap()
+            .list_webhook_deliveries(Some(DeliveryState::Failed), 10)
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_eq!(delivery.event, "message.expired");
+        assert_eq!(delivery.attempts, 1);
+        assert!(delivery.last_error.is_some());
+        assert_eq!(delivery.next_attempt_timestamp, None);
+    }
+}
@@ -0,0 +1,141 @@
+use ruzstd::decoding::errors::FrameDecoderError;
+use ruzstd::decoding::StreamingDecoder;
+use ruzstd::encoding::{compress_to_vec, CompressionLevel};
+use std::fmt;
+use std::io::Read;
+
+// the zstd codec of the message data at rest, any zstd frame without a dictionary is read
+const MAGIC_NUMBER: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+// a frame from a tampered row can't make the server allocate more than this
+const MAX_DECOMPRESSED_SIZE: u64 = 1 << 30;
+
+#[derive(Debug)]
+pub struct ZstdError(pub String);
+
+impl fmt::Display for ZstdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ZstdError {}
+
+fn corrupted() -> ZstdError {
+    ZstdError("Compressed data is corrupted".to_string())
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    compress_to_vec(data, CompressionLevel::Fastest)
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, ZstdError> {
+    decompress_with_limit(data, MAX_DECOMPRESSED_SIZE)
+}
+
+fn decompress_with_limit(data: &[u8], max_size: u64) -> Result<Vec<u8>, ZstdError> {
+    if !data.starts_with(&MAGIC_NUMBER) {
+        return Err(ZstdError("Data is not zstd compressed".to_string()));
+    }
+    let too_big = || ZstdError(format!("Compressed data is bigger than {} bytes", max_size));
+    let mut source = data;
+    let mut decoder = StreamingDecoder::new(&mut source).map_err(|err| match err {
+        FrameDecoderError::DictNotProvided { .. } => {
+            ZstdError("Compressed data needs a dictionary".to_string())
+        }
+        _ => corrupted(),
+    })?;
+    // 0 when the frame doesn't tell, otherwise it only guides the first allocation
+    let content_size = decoder.decoder.content_size();
+    if content_size > max_size {
+        return Err(too_big());
+    }
+    let mut out = Vec::with_capacity(content_size.min(data.len() as u64 * 4) as usize);
+    decoder
+        .by_ref()
+        .take(max_size + 1)
+        .read_to_end(&mut out)
+        .map_err(|_| corrupted())?;
+    if out.len() as u64 > max_size {
+        return Err(too_big());
+    }
+    if content_size != 0 && content_size != out.len() as u64 {
+        return Err(corrupted());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_text(size: usize) -> Vec<u8> {
+        let mut text = Vec::new();
+        let mut line = 0;
+        while text.len() < size {
+            text.extend_from_slice(
+                format!("server.{}.host = 10.0.{}.{}\n", line, line % 7, line % 251).as_bytes(),
+            );
+            line += 1;
+        }
+        text.truncate(size);
+        text
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut noise = Vec::new();
+        let mut seed: u32 = 1;
+        for _ in 0..1000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            noise.push((seed >> 16) as u8);
+        }
+        let inputs = [
+            Vec::new(),
+            b"a".to_vec(),
+            b"Hello, world!".to_vec(),
+            vec![0; 300_000],
+            noise,
+            make_text(1000),
+            make_text(70_000),
+            make_text(300_000),
+        ];
+        for input in inputs {
+            let compressed = compress(&input);
+            assert_eq!(decompress(&compressed).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn test_text_is_compressed() {
+        let text = make_text(100_000);
+        assert!(compress(&text).len() < text.len() / 3);
+    }
+
+    #[test]
+    fn test_decompress_frame_of_another_encoder() {
+        // `printf 'hi!' | zstd`: a raw block with a checksum and without a content size
+        let frame = [
+            0x28, 0xB5, 0x2F, 0xFD, 0x04, 0x58, 0x19, 0x00, 0x00, b'h', b'i', b'!', 0x9F, 0x84,
+            0xE9, 0xDA,
+        ];
+        assert_eq!(decompress(&frame).unwrap(), b"hi!");
+    }
+
+    #[test]
+    fn test_decompress_rejects_bad_data() {
+        assert!(decompress(b"Hello, world!").is_err());
+        let mut frame = compress(&make_text(1000));
+        frame.truncate(frame.len() - 10);
+        assert!(decompress(&frame).is_err());
+    }
+
+    #[test]
+    fn test_decompress_stops_at_the_limit() {
+        let frame = compress(&vec![0; 100_000]);
+        assert!(decompress_with_limit(&frame, 99_999).is_err());
+        assert_eq!(
+            decompress_with_limit(&frame, 100_000).unwrap().len(),
+            100_000
+        );
+    }
+}
@@ -0,0 +1 @@
+head -c 32 /dev/urandom | base64 > encryption.key